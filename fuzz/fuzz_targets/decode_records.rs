@@ -0,0 +1,20 @@
+#![no_main]
+// whatever bytes are stored in place of a record are decoded with an error, never a panic,
+// and the record decoded is encoded back to the same one
+use libfuzzer_sys::fuzz_target;
+use migchat_core::proto::{Chat, Post, User};
+use prost::Message;
+
+fn round_trip<M: Message + Default + PartialEq>(data: &[u8]) {
+    if let Ok(record) = M::decode(data) {
+        let mut encoded = Vec::with_capacity(record.encoded_len());
+        record.encode(&mut encoded).unwrap();
+        assert!(M::decode(encoded.as_slice()).ok() == Some(record));
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    round_trip::<User>(data);
+    round_trip::<Chat>(data);
+    round_trip::<Post>(data);
+});
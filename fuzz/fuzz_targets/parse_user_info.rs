@@ -0,0 +1,12 @@
+#![no_main]
+// the user info given on the command line is parsed with an error, never a panic,
+// and its parts come trimmed
+use libfuzzer_sys::fuzz_target;
+use migchat_core::proto::UserInfo;
+
+fuzz_target!(|text: &str| {
+    if let Ok(info) = text.parse::<UserInfo>() {
+        assert_eq!(info.short_name.trim(), info.short_name);
+        assert_eq!(info.name.trim(), info.name);
+    }
+});
@@ -0,0 +1,17 @@
+#![no_main]
+// the text of the post is normalized once and for all, and checked by its normalized size
+use libfuzzer_sys::fuzz_target;
+use migchat_core::post_limits::{normalize_line_endings, PostError, PostLimits};
+
+fuzz_target!(|text: &str| {
+    let normalized = normalize_line_endings(text);
+    assert!(!normalized.contains('\r'));
+    assert_eq!(normalize_line_endings(&normalized), normalized);
+    let limits = PostLimits::default();
+    match limits.check(&normalized, 0) {
+        Ok(()) => assert!(normalized.len() <= limits.max_bytes),
+        Err(PostError::TooLarge(bytes, _)) => assert_eq!(bytes, normalized.len()),
+        Err(PostError::Empty) => assert!(normalized.trim().is_empty()),
+        Err(e) => panic!("unexpected {}", e),
+    }
+});
@@ -0,0 +1,15 @@
+use std::env;
+
+// vendored along with the changes of the protocol made by the server and its clients
+const PROTO_DIR: &str = "proto";
+const PROTO: &str = "proto/migchat.proto";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // the code of the enabled features only is generated
+    tonic_build::configure()
+        .build_client(env::var_os("CARGO_FEATURE_CLIENT").is_some())
+        .build_server(env::var_os("CARGO_FEATURE_SERVER").is_some())
+        .compile(&[PROTO], &[PROTO_DIR])?;
+    println!("cargo:rerun-if-changed={}", PROTO);
+    Ok(())
+}
@@ -0,0 +1,596 @@
+//! The one-line syntax of the chat to create, as typed by the users.
+
+use crate::proto::{self, UserId};
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+/// Chat to create written in one line, e.g.
+/// `rust-talk +perm +public #rust #ru @u2 @u5 slow=30`:
+/// - the description goes first, it is quoted if contains spaces, `"a \"b\" c"`
+/// - `+perm` / `-perm`, `+auto` / `-auto`, `+public` / `-public`, `+export` / `-export`
+///   switch flags
+/// - `#tag` adds a tag, `@login` invites the user
+/// - `slow=N` allows one post per N seconds, `writers=u2,u5` restricts who posts
+#[derive(Debug, PartialEq, Clone)]
+pub struct ChatSpec {
+    /// Normalized, the inner whitespaces of the quoted one are kept.
+    pub description: String,
+    /// `+perm`, the chat outlives its last member.
+    pub permanent: bool,
+    /// `+auto`, the creator enters the chat.
+    pub auto_enter: bool,
+    /// `+public`, anyone can find the chat.
+    pub public: bool,
+    /// `-export`, the clients refuse exporting the history; advisory, the members read
+    /// the posts anyway.
+    pub allow_export: bool,
+    /// `#tag`, without the `#`.
+    pub tags: Vec<String>,
+    /// `@login`, the logins to be resolved.
+    pub users: Vec<String>,
+    /// `slow=N`, the seconds between the posts of a member.
+    pub slow_secs: Option<u32>,
+    /// `writers=u2,u5`, the logins of those allowed to post.
+    pub writers: Vec<String>,
+}
+
+impl Default for ChatSpec {
+    fn default() -> Self {
+        ChatSpec {
+            description: String::new(),
+            permanent: true,
+            auto_enter: true,
+            public: false,
+            allow_export: true,
+            tags: Vec::new(),
+            users: Vec::new(),
+            slow_secs: None,
+            writers: Vec::new(),
+        }
+    }
+}
+
+/// The spec is invalid or cannot be resolved.
+#[derive(Debug, PartialEq)]
+pub struct ChatSpecError {
+    /// 1-based, zero if the token is not from the parsed text.
+    pub column: usize,
+    /// The offending token.
+    pub token: String,
+    /// What is wrong with the token.
+    pub text: String,
+}
+
+impl ChatSpecError {
+    fn new(token: &Token, text: &str) -> Self {
+        ChatSpecError {
+            column: token.column,
+            token: token.text.clone(),
+            text: text.to_string(),
+        }
+    }
+}
+
+impl Error for ChatSpecError {}
+
+impl Display for ChatSpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.column > 0 {
+            write!(f, "{} at {}: '{}'", self.text, self.column, self.token)
+        } else {
+            write!(f, "{}: '{}'", self.text, self.token)
+        }
+    }
+}
+
+struct Token {
+    text: String,
+    column: usize,
+    quoted: bool,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, ChatSpecError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().enumerate().peekable();
+    while let Some(&(idx, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let column = idx + 1;
+        let mut text = String::new();
+        if c == '"' {
+            chars.next();
+            let mut closed = false;
+            while let Some((_, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        closed = true;
+                        break;
+                    }
+                    '\\' => {
+                        if let Some((_, escaped)) = chars.next() {
+                            text.push(escaped);
+                        }
+                    }
+                    _ => text.push(c),
+                }
+            }
+            if !closed {
+                return Err(ChatSpecError {
+                    column,
+                    token: text,
+                    text: String::from("unterminated quote"),
+                });
+            }
+            tokens.push(Token {
+                text,
+                column,
+                quoted: true,
+            });
+        } else {
+            while let Some(&(_, c)) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                text.push(c);
+                chars.next();
+            }
+            tokens.push(Token {
+                text,
+                column,
+                quoted: false,
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+fn is_option(token: &Token) -> bool {
+    !token.quoted && (token.text.starts_with(&['+', '-', '#', '@'][..]) || token.text.contains('='))
+}
+
+// options are not repeated, the opposite flag is a repetition as well
+fn check_repeated(seen: &mut Vec<String>, key: &str, token: &Token) -> Result<(), ChatSpecError> {
+    if seen.iter().any(|k| k == key) {
+        return Err(ChatSpecError::new(token, "repeated option"));
+    }
+    seen.push(key.to_string());
+    Ok(())
+}
+
+fn parse_flag(token: &Token) -> Result<(&str, bool), ChatSpecError> {
+    let (sign, name) = token.text.split_at(1);
+    match name {
+        "perm" | "auto" | "public" | "export" => Ok((name, sign == "+")),
+        _ => Err(ChatSpecError::new(token, "unknown flag")),
+    }
+}
+
+fn parse_name<'a>(token: &'a Token, what: &str) -> Result<&'a str, ChatSpecError> {
+    let name = &token.text[1..];
+    if name.is_empty() {
+        Err(ChatSpecError::new(token, &format!("empty {}", what)))
+    } else {
+        Ok(name)
+    }
+}
+
+impl FromStr for ChatSpec {
+    type Err = ChatSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut spec = ChatSpec::default();
+        let mut words: Vec<Token> = Vec::new();
+        let mut seen: Vec<String> = Vec::new();
+        for token in tokenize(s)? {
+            if !is_option(&token) {
+                if !seen.is_empty() {
+                    return Err(ChatSpecError::new(
+                        &token,
+                        "the description goes first, quote it to use spaces",
+                    ));
+                }
+                if words.last().map_or(false, |w| w.quoted) || (token.quoted && !words.is_empty()) {
+                    return Err(ChatSpecError::new(
+                        &token,
+                        "the description is already given",
+                    ));
+                }
+                words.push(token);
+                continue;
+            }
+            if token.text.starts_with(&['+', '-'][..]) {
+                let (name, on) = parse_flag(&token)?;
+                check_repeated(&mut seen, name, &token)?;
+                match name {
+                    "perm" => spec.permanent = on,
+                    "auto" => spec.auto_enter = on,
+                    "export" => spec.allow_export = on,
+                    _ => spec.public = on,
+                }
+            } else if token.text.starts_with('#') {
+                let tag = parse_name(&token, "tag")?;
+                check_repeated(&mut seen, &token.text, &token)?;
+                spec.tags.push(tag.to_string());
+            } else if token.text.starts_with('@') {
+                let login = parse_name(&token, "login")?;
+                check_repeated(&mut seen, &token.text, &token)?;
+                spec.users.push(login.to_string());
+            } else if let Some(value) = token.text.strip_prefix("slow=") {
+                check_repeated(&mut seen, "slow=", &token)?;
+                match value.parse::<u32>() {
+                    Ok(secs) if secs > 0 => spec.slow_secs = Some(secs),
+                    Ok(_) => return Err(ChatSpecError::new(&token, "slow mode must be positive")),
+                    Err(_) => return Err(ChatSpecError::new(&token, "bad number of seconds")),
+                }
+            } else if let Some(value) = token.text.strip_prefix("writers=") {
+                check_repeated(&mut seen, "writers=", &token)?;
+                for login in value.split(',') {
+                    let login = login.strip_prefix('@').unwrap_or(login);
+                    if login.is_empty() {
+                        return Err(ChatSpecError::new(&token, "empty login"));
+                    }
+                    if spec.writers.iter().any(|w| w == login) {
+                        return Err(ChatSpecError::new(&token, "repeated writer"));
+                    }
+                    spec.writers.push(login.to_string());
+                }
+            } else {
+                return Err(ChatSpecError::new(&token, "unknown option"));
+            }
+        }
+        spec.description = words
+            .into_iter()
+            .map(|w| w.text)
+            .collect::<Vec<_>>()
+            .join(" ");
+        if spec.description.is_empty() {
+            return Err(ChatSpecError {
+                column: 0,
+                token: s.to_string(),
+                text: String::from("no chat description"),
+            });
+        }
+        Ok(spec)
+    }
+}
+
+impl Display for ChatSpec {
+    // only the options differing from the defaults are written
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bare = !self.description.is_empty()
+            && !self
+                .description
+                .contains(|c: char| c.is_whitespace() || c == '"' || c == '\\')
+            && !is_option(&Token {
+                text: self.description.clone(),
+                column: 0,
+                quoted: false,
+            });
+        if bare {
+            write!(f, "{}", self.description)?;
+        } else {
+            write!(
+                f,
+                "\"{}\"",
+                self.description.replace('\\', "\\\\").replace('"', "\\\"")
+            )?;
+        }
+        if !self.permanent {
+            write!(f, " -perm")?;
+        }
+        if !self.auto_enter {
+            write!(f, " -auto")?;
+        }
+        if self.public {
+            write!(f, " +public")?;
+        }
+        if !self.allow_export {
+            write!(f, " -export")?;
+        }
+        for tag in &self.tags {
+            write!(f, " #{}", tag)?;
+        }
+        for login in &self.users {
+            write!(f, " @{}", login)?;
+        }
+        if let Some(secs) = self.slow_secs {
+            write!(f, " slow={}", secs)?;
+        }
+        if !self.writers.is_empty() {
+            write!(f, " writers={}", self.writers.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+impl ChatSpec {
+    /// Makes the request to create the chat, the logins are looked up by `find_user`.
+    pub fn resolve<F: Fn(&str) -> Option<UserId>>(
+        &self,
+        user_id: UserId,
+        find_user: F,
+    ) -> Result<proto::ChatInfo, ChatSpecError> {
+        // the server does not keep these yet
+        let unsupported = if self.public {
+            Some(String::from("+public"))
+        } else if let Some(tag) = self.tags.first() {
+            Some(format!("#{}", tag))
+        } else if let Some(secs) = self.slow_secs {
+            Some(format!("slow={}", secs))
+        } else if !self.writers.is_empty() {
+            Some(format!("writers={}", self.writers.join(",")))
+        } else {
+            None
+        };
+        if let Some(token) = unsupported {
+            return Err(ChatSpecError {
+                column: 0,
+                token,
+                text: String::from("not supported by the server"),
+            });
+        }
+        let mut desired_users = Vec::with_capacity(self.users.len());
+        for login in &self.users {
+            match find_user(login) {
+                Some(id) => desired_users.push(id),
+                None => {
+                    return Err(ChatSpecError {
+                        column: 0,
+                        token: format!("@{}", login),
+                        text: String::from("unknown user"),
+                    })
+                }
+            }
+        }
+        Ok(proto::ChatInfo {
+            user_id,
+            permanent: self.permanent,
+            auto_enter: self.auto_enter,
+            description: self.description.clone(),
+            desired_users,
+            export_discouraged: !self.allow_export,
+        })
+    }
+
+    /// The spec of the existing chat to edit, only its description, `perm` and `export`
+    /// change.
+    pub fn of_chat(chat: &proto::Chat) -> Self {
+        ChatSpec {
+            description: chat.description.clone(),
+            permanent: chat.permanent,
+            allow_export: !chat.export_discouraged,
+            ..Default::default()
+        }
+    }
+
+    /// Makes the request to update the chat, the other options are not accepted.
+    pub fn update(
+        &self,
+        user_id: UserId,
+        chat_id: proto::ChatId,
+    ) -> Result<proto::ChatInfoUpdate, ChatSpecError> {
+        let editable = ChatSpec {
+            description: self.description.clone(),
+            permanent: self.permanent,
+            allow_export: self.allow_export,
+            ..Default::default()
+        };
+        if *self != editable {
+            return Err(ChatSpecError {
+                column: 0,
+                token: format!("{}", self),
+                text: String::from(
+                    "only the description, +perm / -perm and +export / -export change",
+                ),
+            });
+        }
+        Ok(proto::ChatInfoUpdate {
+            chat_id,
+            user_id,
+            description: self.description.clone(),
+            permanent: self.permanent,
+            export_discouraged: !self.allow_export,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> ChatSpec {
+        s.parse::<ChatSpec>().unwrap()
+    }
+
+    fn error(s: &str) -> ChatSpecError {
+        s.parse::<ChatSpec>().unwrap_err()
+    }
+
+    #[test]
+    fn valid_specs() {
+        assert_eq!(
+            parse("rust-talk"),
+            ChatSpec {
+                description: String::from("rust-talk"),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            parse("rust-talk +perm +public #rust #ru @u2 @u5 slow=30"),
+            ChatSpec {
+                description: String::from("rust-talk"),
+                permanent: true,
+                public: true,
+                tags: vec![String::from("rust"), String::from("ru")],
+                users: vec![String::from("u2"), String::from("u5")],
+                slow_secs: Some(30),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            parse("  about  rust   -perm -auto writers=u2,@u5 "),
+            ChatSpec {
+                description: String::from("about rust"),
+                permanent: false,
+                auto_enter: false,
+                writers: vec![String::from("u2"), String::from("u5")],
+                ..Default::default()
+            }
+        );
+        // the options go in any order
+        assert_eq!(parse("chat @u2 -perm #tag"), parse("chat #tag -perm @u2"));
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(parse(r#""about  rust" @u2"#).description, "about  rust");
+        assert_eq!(
+            parse(r#""say \"hi\" \\ bye""#).description,
+            r#"say "hi" \ bye"#
+        );
+        assert_eq!(parse(r#""+perm""#).description, "+perm");
+        assert_eq!(error(r#""""#).text, "no chat description");
+        let e = error(r#"chat "unterminated"#);
+        assert_eq!((e.column, e.text.as_str()), (6, "unterminated quote"));
+        assert_eq!(error(r#""one" "two""#).column, 7);
+        assert_eq!(error(r#"one "two""#).column, 5);
+        assert_eq!(error(r#""one" two"#).column, 7);
+    }
+
+    #[test]
+    fn invalid_specs() {
+        let check = |s: &str, column: usize, token: &str| {
+            let e = error(s);
+            assert_eq!((e.column, e.token.as_str()), (column, token), "{}", e);
+        };
+        // unknown options
+        check("chat +perm +fast", 12, "+fast");
+        check("chat -x", 6, "-x");
+        check("chat mode=on", 6, "mode=on");
+        // repeated options
+        check("chat +perm -perm", 12, "-perm");
+        check("chat +public +public", 14, "+public");
+        check("chat #rust #rust", 12, "#rust");
+        check("chat @u2 @u2", 10, "@u2");
+        check("chat slow=1 slow=2", 13, "slow=2");
+        check("chat writers=a writers=b", 16, "writers=b");
+        check("chat writers=a,a", 6, "writers=a,a");
+        // bad values
+        check("chat slow=", 6, "slow=");
+        check("chat slow=x", 6, "slow=x");
+        check("chat slow=-1", 6, "slow=-1");
+        check("chat slow=0", 6, "slow=0");
+        check("chat slow=99999999999", 6, "slow=99999999999");
+        check("chat #", 6, "#");
+        check("chat @", 6, "@");
+        check("chat writers=", 6, "writers=");
+        check("chat writers=a,,b", 6, "writers=a,,b");
+        // the description
+        check("chat +perm talk", 12, "talk");
+        check("+perm", 0, "+perm");
+        check("   ", 0, "   ");
+        assert_eq!(
+            format!("{}", error("chat +fast")),
+            "unknown flag at 6: '+fast'"
+        );
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for s in &[
+            "rust-talk",
+            "rust-talk -perm -auto +public -export #rust #ru @u2 @u5 slow=30 writers=u2,u5",
+            r#""about  rust" @u2"#,
+            r#""say \"hi\" \\ bye" #q"#,
+            r#""+perm" -perm"#,
+            r#""a=b""#,
+        ] {
+            let spec = parse(s);
+            let text = format!("{}", spec);
+            assert_eq!(&text, s);
+            assert_eq!(parse(&text), spec);
+        }
+        // defaults are not written
+        assert_eq!(
+            format!("{}", parse("chat +perm +auto -public +export")),
+            "chat"
+        );
+        assert_eq!(format!("{}", parse("about rust")), r#""about rust""#);
+    }
+
+    #[test]
+    fn resolution() {
+        let find_user = |login: &str| match login {
+            "u2" => Some(2),
+            "u5" => Some(5),
+            _ => None,
+        };
+        assert_eq!(
+            parse("chat -auto @u5 @u2").resolve(1, find_user),
+            Ok(proto::ChatInfo {
+                user_id: 1,
+                permanent: true,
+                auto_enter: false,
+                description: String::from("chat"),
+                desired_users: vec![5, 2],
+                export_discouraged: false,
+            })
+        );
+        let info = parse("chat -export").resolve(1, find_user).unwrap();
+        assert!(info.export_discouraged);
+        let e = parse("chat @u2 @u3").resolve(1, find_user).unwrap_err();
+        assert_eq!((e.token.as_str(), e.text.as_str()), ("@u3", "unknown user"));
+        assert_eq!(format!("{}", e), "unknown user: '@u3'");
+        for s in &[
+            "chat +public",
+            "chat #rust",
+            "chat slow=5",
+            "chat writers=u2",
+        ] {
+            let e = parse(s).resolve(1, find_user).unwrap_err();
+            assert_eq!(e.text, "not supported by the server");
+        }
+    }
+
+    #[test]
+    fn chat_update() {
+        let chat = proto::Chat {
+            id: 10,
+            permanent: false,
+            description: String::from("about rust"),
+            ..Default::default()
+        };
+        let text = format!("{}", ChatSpec::of_chat(&chat));
+        assert_eq!(text, r#""about rust" -perm"#);
+        assert_eq!(
+            parse("rust +perm").update(1, 10),
+            Ok(proto::ChatInfoUpdate {
+                chat_id: 10,
+                user_id: 1,
+                description: String::from("rust"),
+                permanent: true,
+                export_discouraged: false,
+            })
+        );
+        assert_eq!(parse(&text).update(1, 10).map(|u| u.permanent), Ok(false));
+        // the export is allowed unless the chat discourages it
+        let chat = proto::Chat {
+            export_discouraged: true,
+            ..chat
+        };
+        let text = format!("{}", ChatSpec::of_chat(&chat));
+        assert_eq!(text, r#""about rust" -perm -export"#);
+        let update = parse(&text).update(1, 10).unwrap();
+        assert!(update.export_discouraged);
+        let update = parse("rust +export").update(1, 10).unwrap();
+        assert!(!update.export_discouraged);
+        for s in &["rust -auto", "rust @u2", "rust #tag", "rust slow=5"] {
+            assert!(parse(s).update(1, 10).is_err(), "{}", s);
+        }
+    }
+}
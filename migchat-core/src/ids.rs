@@ -0,0 +1,66 @@
+//! The ids the server derives from the names, everyone gets the same ones for the same names.
+
+use crate::proto::{ChatId, UserId, UserInfo};
+use fxhash::FxHasher64;
+use std::hash::Hasher;
+
+/// The id the user registering with the names got before the ids became random,
+/// the server still finds such users by it.
+pub fn user_id(user: &UserInfo) -> UserId {
+    let mut hasher = FxHasher64::default();
+    hasher.write(user.name.as_bytes());
+    hasher.write(user.short_name.as_bytes());
+    hasher.finish()
+}
+
+/// The id of the chat by its description if it is not empty, otherwise by its members
+/// in the given order, the server sorts them. `None` for no description and no members,
+/// such a chat gets a random id.
+///
+/// The description is expected to be normalized, see [`normalize_description`].
+pub fn chat_id(description: &str, users: &[UserId]) -> Option<ChatId> {
+    chat_id_probe(description, users, 0)
+}
+
+/// The id of the chat like [`chat_id`] salted by the probe, the next probe is tried while
+/// the id is taken by a chat of another description or members. Probe 0 is [`chat_id`].
+pub fn chat_id_probe(description: &str, users: &[UserId], probe: u32) -> Option<ChatId> {
+    let mut hasher = FxHasher64::default();
+    if !description.is_empty() {
+        hasher.write(description.as_bytes());
+    } else if !users.is_empty() {
+        for id in users {
+            hasher.write(&id.to_le_bytes());
+        }
+    } else {
+        return None;
+    }
+    if probe > 0 {
+        hasher.write_u32(probe);
+    }
+    Some(hasher.finish())
+}
+
+/// Trims the description and collapses its inner whitespaces.
+pub fn normalize_description(description: &str) -> String {
+    description
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_ids() {
+        assert_eq!(chat_id("chat", &[1, 2]), chat_id("chat", &[]));
+        assert_ne!(chat_id("", &[1, 2]), chat_id("", &[2, 1]));
+        assert_eq!(chat_id("", &[]), None);
+        assert_eq!(chat_id_probe("chat", &[], 0), chat_id("chat", &[]));
+        assert_ne!(chat_id_probe("chat", &[], 1), chat_id("chat", &[]));
+        assert_ne!(chat_id_probe("", &[1, 2], 1), chat_id_probe("", &[1, 2], 2));
+        assert_eq!(normalize_description("  about \t rust "), "about rust");
+    }
+}
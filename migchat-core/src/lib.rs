@@ -0,0 +1,11 @@
+//! The protocol of the migchat server and the types its clients share with it.
+//!
+//! A bot talks to the server by the generated client, enabled by the `client` feature:
+//! it registers with [`proto::UserInfo`] to get its [`proto::UserId`] and, in the
+//! [`proto::SESSION_TOKEN_KEY`] metadata, the session token it presents by
+//! [`proto::bearer_value`] in the [`proto::AUTHORIZATION_KEY`] metadata of every later call. The `server` feature generates the service to implement instead.
+
+pub mod chat_spec;
+pub mod ids;
+pub mod post_limits;
+pub mod proto;
@@ -0,0 +1,169 @@
+//! The posts the server accepts, the clients check them before sending to tell the user at once.
+
+use std::{
+    borrow::Cow,
+    error::Error,
+    fmt::{self, Display},
+};
+
+/// The default of [`PostLimits::max_bytes`].
+pub const DEF_MAX_POST_BYTES: usize = 16 * 1024;
+/// The default of [`PostLimits::max_attachments`].
+pub const DEF_MAX_ATTACHMENTS: usize = 8;
+
+/// The limits of a single post.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PostLimits {
+    /// The size of the text in bytes, its line endings normalized.
+    pub max_bytes: usize,
+    /// The count of the files attached.
+    pub max_attachments: usize,
+}
+
+impl Default for PostLimits {
+    fn default() -> Self {
+        PostLimits {
+            max_bytes: DEF_MAX_POST_BYTES,
+            max_attachments: DEF_MAX_ATTACHMENTS,
+        }
+    }
+}
+
+/// The post is refused by the limits.
+#[derive(Debug, PartialEq)]
+pub enum PostError {
+    /// Nothing but whitespaces and nothing attached.
+    Empty,
+    /// The size of the text and the limit, in bytes.
+    TooLarge(usize, usize),
+    /// The count of the attachments and the limit.
+    TooManyAttachments(usize, usize),
+}
+
+impl Display for PostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PostError::Empty => write!(f, "post is empty"),
+            PostError::TooLarge(bytes, max) => {
+                write!(f, "post of {} bytes exceeds {} bytes", bytes, max)
+            }
+            PostError::TooManyAttachments(count, max) => {
+                write!(f, "{} attachments exceed {}", count, max)
+            }
+        }
+    }
+}
+
+impl Error for PostError {}
+
+/// The text with `\r\n` and the lone `\r` turned into `\n`.
+pub fn normalize_line_endings(text: &str) -> Cow<'_, str> {
+    if text.contains('\r') {
+        Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+impl PostLimits {
+    /// Checks the text of the post, its line endings are to be normalized before;
+    /// the post of the attachments only may have no text.
+    pub fn check(&self, text: &str, attachments: usize) -> Result<(), PostError> {
+        if attachments > self.max_attachments {
+            return Err(PostError::TooManyAttachments(
+                attachments,
+                self.max_attachments,
+            ));
+        }
+        if text.len() > self.max_bytes {
+            return Err(PostError::TooLarge(text.len(), self.max_bytes));
+        }
+        if attachments == 0 && text.trim().is_empty() {
+            return Err(PostError::Empty);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn line_endings_normalized() {
+        assert_eq!(normalize_line_endings("a\r\nb\rc\n"), "a\nb\nc\n");
+        assert!(matches!(normalize_line_endings("a\nb"), Cow::Borrowed(_)));
+        // the size is checked as normalized
+        let limits = PostLimits {
+            max_bytes: 3,
+            max_attachments: 1,
+        };
+        assert_eq!(limits.check(&normalize_line_endings("a\r\nb"), 0), Ok(()));
+    }
+
+    #[test]
+    fn boundaries_checked() {
+        let limits = PostLimits {
+            max_bytes: 4,
+            max_attachments: 2,
+        };
+        assert_eq!(limits.check("1234", 0), Ok(()));
+        assert_eq!(limits.check("12345", 0), Err(PostError::TooLarge(5, 4)));
+        // the bytes are counted, not the chars
+        assert_eq!(limits.check("жж", 0), Ok(()));
+        assert_eq!(limits.check("жжж", 0), Err(PostError::TooLarge(6, 4)));
+        assert_eq!(limits.check("x", 2), Ok(()));
+        assert_eq!(
+            limits.check("x", 3),
+            Err(PostError::TooManyAttachments(3, 2))
+        );
+        assert_eq!(limits.check("", 0), Err(PostError::Empty));
+        assert_eq!(limits.check(" \n\t", 0), Err(PostError::Empty));
+        assert_eq!(limits.check("", 1), Ok(()));
+    }
+
+    proptest! {
+        #[test]
+        fn normalized_once(text in "(\\PC|\r|\n)*") {
+            let normalized = normalize_line_endings(&text);
+            prop_assert!(!normalized.contains('\r'));
+            prop_assert_eq!(normalize_line_endings(&normalized), normalized.clone());
+            // every line ending is kept as a single one
+            prop_assert!(normalized.len() <= text.len());
+            prop_assert_eq!(
+                normalized.matches('\n').count(),
+                text.replace("\r\n", "\n").matches(|c| c == '\r' || c == '\n').count()
+            );
+        }
+
+        #[test]
+        fn checked_as_sized(
+            text in "(\\PC|\r|\n)*",
+            max_bytes in 0..64usize,
+            attachments in 0..4usize,
+        ) {
+            let limits = PostLimits {
+                max_bytes,
+                max_attachments: 2,
+            };
+            let text = normalize_line_endings(&text);
+            match limits.check(&text, attachments) {
+                Ok(()) => {
+                    prop_assert!(text.len() <= max_bytes && attachments <= 2);
+                    prop_assert!(attachments > 0 || !text.trim().is_empty());
+                }
+                Err(PostError::TooManyAttachments(count, max)) => {
+                    prop_assert_eq!((count, max), (attachments, 2));
+                }
+                Err(PostError::TooLarge(bytes, max)) => {
+                    prop_assert_eq!((bytes, max), (text.len(), max_bytes));
+                    prop_assert!(bytes > max);
+                }
+                Err(PostError::Empty) => {
+                    prop_assert!(attachments == 0 && text.trim().is_empty());
+                }
+            }
+        }
+    }
+}
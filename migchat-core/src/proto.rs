@@ -0,0 +1,240 @@
+//! The messages and the services of the chat room generated from the proto,
+//! along with the ids and the metadata keys the server and its clients agree on.
+
+#[cfg(test)]
+use proptest::prelude::*;
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+tonic::include_proto!("migchat"); // The string specified here must match the proto package name
+
+/// Id of the chat, derived from its description or its members.
+pub type ChatId = u64;
+/// Id of the user, random and assigned by the first registration.
+pub type UserId = u64;
+/// Id of the post, random and unique across all chats.
+pub type PostId = u64;
+/// Id of the uploaded attachment, random and unique across all chats.
+pub type AttachmentId = u64;
+
+/// No user, e.g. the sender of a system notice.
+pub const NOT_USER_ID: UserId = 0;
+/// No chat, e.g. the chat of the heartbeat.
+pub const NOT_CHAT_ID: ChatId = 0;
+/// No post, the id of the post yet to be created.
+pub const NOT_POST_ID: PostId = 0;
+
+/// create_chat() response metadata telling whether the chat was just created or found existing.
+pub const CHAT_STATUS_KEY: &str = "chat-status";
+/// The chat has been created by the request.
+pub const CHAT_STATUS_CREATED: &str = "created";
+/// The chat has existed before the request.
+pub const CHAT_STATUS_FOUND: &str = "found_existing";
+/// The existing chat was found and its flags were reconciled with the request.
+pub const CHAT_STATUS_FLAGS_ADJUSTED: &str = "flags_adjusted";
+
+/// create_post() response metadata carrying id of the accepted post.
+pub const POST_ID_KEY: &str = "post-id";
+
+/// create_post() and invite_user() error metadata, the seconds to wait before the rate limit
+/// of the user lets the call through.
+pub const RETRY_AFTER_KEY: &str = "retry-after";
+
+/// register() response metadata carrying the session token.
+pub const SESSION_TOKEN_KEY: &str = "session-token";
+/// register() request metadata presenting the user id assigned by the previous registration.
+pub const USER_ID_KEY: &str = "user-id";
+/// register() metadata, the secret of the new user in the response, given only once,
+/// and presented along with [`USER_ID_KEY`] by every following registration.
+pub const USER_SECRET_KEY: &str = "user-secret";
+/// Request metadata presenting the session token to every call after register().
+pub const AUTHORIZATION_KEY: &str = "authorization";
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// get_posts() request metadata, the id of the newest post the client has got,
+/// only the posts after it are replayed before the new ones.
+pub const SINCE_POST_ID_KEY: &str = "since-post-id";
+/// get_posts() request metadata, the creation time of the newest post the client has got.
+pub const SINCE_CREATED_KEY: &str = "since-created";
+
+/// list_users() and list_chats() request metadata, the number of the entries to skip.
+pub const LIST_OFFSET_KEY: &str = "list-offset";
+/// list_users() and list_chats() request metadata, the most entries to return, all if missing.
+pub const LIST_LIMIT_KEY: &str = "list-limit";
+
+/// The value of [`AUTHORIZATION_KEY`] presenting the session token.
+pub fn bearer_value(token: &str) -> String {
+    format!("{}{}", BEARER_PREFIX, token)
+}
+
+/// The session token presented by the value of [`AUTHORIZATION_KEY`], if any.
+pub fn bearer_token(value: &str) -> Option<&str> {
+    value.strip_prefix(BEARER_PREFIX)
+}
+
+/// History of no posts, the idle client asks for it to stay online.
+pub fn heartbeat() -> HistoryParams {
+    HistoryParams {
+        chat_id: NOT_CHAT_ID,
+        idx_from: 0,
+        count: 0,
+        before_seq: 0,
+    }
+}
+
+impl Display for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let has_name = !self.name.is_empty();
+        let has_short = !self.short_name.is_empty();
+        if has_name {
+            if has_short {
+                write!(f, "{} ({})", self.short_name, self.name)
+            } else {
+                write!(f, "{}", self.name)
+            }
+        } else if has_short {
+            write!(f, "{}", self.short_name)
+        } else {
+            write!(f, "<not set>")
+        }
+    }
+}
+
+impl Display for UserInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let has_name = !self.name.is_empty();
+        let has_short = !self.short_name.is_empty();
+        if has_name {
+            if has_short {
+                write!(f, "{} ({})", self.short_name, self.name)
+            } else {
+                write!(f, "{}", self.name)
+            }
+        } else if has_short {
+            write!(f, "{}", self.short_name)
+        } else {
+            write!(f, "<not set>")
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ParseUserError {
+    text: String,
+}
+
+impl Error for ParseUserError {}
+
+impl Display for ParseUserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl From<User> for UserInfo {
+    fn from(user: User) -> Self {
+        UserInfo {
+            name: user.name,
+            short_name: user.short_name,
+        }
+    }
+}
+
+impl FromStr for UserInfo {
+    // the error must be owned as well
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(&[',', ':', ';'][..]).collect();
+        if parts.len() == 2 {
+            Ok(UserInfo {
+                short_name: parts[0].trim().to_string(),
+                name: parts[1].trim().to_string(),
+            })
+        } else {
+            Err(Box::new(ParseUserError {
+                text: s.to_string(),
+            }))
+        }
+    }
+}
+
+#[test]
+fn test_parse_user() {
+    let input = "login, User Name";
+    assert_eq!(
+        input.parse::<UserInfo>().unwrap(),
+        UserInfo {
+            name: String::from("User Name"),
+            short_name: String::from("login")
+        }
+    );
+}
+
+#[test]
+fn test_display_user() {
+    assert_eq!(
+        format!(
+            "{}",
+            UserInfo {
+                name: String::new(),
+                short_name: String::new()
+            }
+        ),
+        "<not set>"
+    );
+    assert_eq!(
+        format!(
+            "{}",
+            UserInfo {
+                name: String::from("Only Name"),
+                short_name: String::new()
+            }
+        ),
+        "Only Name"
+    );
+    assert_eq!(
+        format!(
+            "{}",
+            UserInfo {
+                name: String::new(),
+                short_name: String::from("Login")
+            }
+        ),
+        "Login"
+    );
+    assert_eq!(
+        format!(
+            "{}",
+            UserInfo {
+                name: String::from("User Name"),
+                short_name: String::from("Login")
+            }
+        ),
+        "Login (User Name)"
+    );
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn parse_user_never_panics(s in "\\PC*") {
+        let _ = s.parse::<UserInfo>();
+    }
+
+    #[test]
+    fn parse_user_trims_both_parts(
+        short_name in "[a-zA-Z0-9_]{1,16}",
+        name in "[a-zA-Z]([a-zA-Z ]{0,30}[a-zA-Z])?",
+        sep in "[,:;]",
+    ) {
+        let input = format!("  {} {}  {}  ", short_name, sep, name);
+        let info = input.parse::<UserInfo>().unwrap();
+        prop_assert_eq!(&info.short_name, &short_name);
+        prop_assert_eq!(&info.name, &name);
+        prop_assert_eq!(format!("{}", info), format!("{} ({})", short_name, name));
+    }
+}
@@ -0,0 +1,54 @@
+// uses the crate as a bot does, by the public API only
+use migchat_core::chat_spec::ChatSpec;
+use migchat_core::ids;
+use migchat_core::proto::{
+    self, chat_room_service_client::ChatRoomServiceClient, ChatInfo, UserInfo, NOT_USER_ID,
+};
+use tonic::transport::Channel;
+
+#[test]
+fn bot_predicts_ids() {
+    let bot: UserInfo = "ci-bot, Build Bot".parse().unwrap();
+    assert_eq!(format!("{}", bot), "ci-bot (Build Bot)");
+    let bot_id = ids::user_id(&bot);
+    assert_ne!(bot_id, NOT_USER_ID);
+    assert_eq!(ids::user_id(&bot.clone()), bot_id);
+
+    let spec: ChatSpec = r#""nightly   builds" -auto @alice"#.parse().unwrap();
+    let info = spec
+        .resolve(
+            bot_id,
+            |login| if login == "alice" { Some(7) } else { None },
+        )
+        .unwrap();
+    assert_eq!(
+        info,
+        ChatInfo {
+            user_id: bot_id,
+            permanent: true,
+            auto_enter: false,
+            description: String::from("nightly   builds"),
+            desired_users: vec![7],
+        }
+    );
+    // the server normalizes the description the id is derived from
+    let description = ids::normalize_description(&info.description);
+    assert_eq!(
+        ids::chat_id(&description, &[]),
+        ids::chat_id("nightly builds", &[bot_id, 7])
+    );
+}
+
+#[test]
+fn bot_authorizes() {
+    let value = proto::bearer_value("token");
+    assert_eq!(proto::bearer_token(&value), Some("token"));
+    assert_eq!(proto::bearer_token("token"), None);
+    assert_eq!(proto::heartbeat().chat_id, proto::NOT_CHAT_ID);
+}
+
+// the client is generated by default
+#[allow(dead_code)]
+fn client(channel: Channel) -> ChatRoomServiceClient<Channel> {
+    ChatRoomServiceClient::new(channel)
+}
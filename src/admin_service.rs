@@ -0,0 +1,364 @@
+use log::{debug, info, warn};
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+
+use super::proto::chat_room_admin_server::ChatRoomAdmin;
+use super::proto::{
+    bearer_token, AdminChat, AdminChats, AdminRequest, AdminUser, AdminUsers, ChatReference,
+    Registration, Result as RpcResult, AUTHORIZATION_KEY,
+};
+use super::server_service::{blocking, member_name, post_system_notice, remove_member};
+use super::storage::ChatStorage;
+use super::{ChatChanged, ChatRoomImpl};
+
+// the tokens are compared in full whatever they start with
+fn same_token(presented: &str, token: &str) -> bool {
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// the calls are made with the admin token of the config instead of a session token
+pub fn check_token(token: &str, request: Request<()>) -> Result<Request<()>, Status> {
+    let presented = request
+        .metadata()
+        .get(AUTHORIZATION_KEY)
+        .and_then(|value| value.to_str().ok())
+        .and_then(bearer_token);
+    match presented {
+        Some(presented) if same_token(presented, token) => Ok(request),
+        Some(_) => {
+            warn!("admin call with a wrong token");
+            Err(Status::unauthenticated("wrong admin token"))
+        }
+        None => Err(Status::unauthenticated("admin token required")),
+    }
+}
+
+fn done(description: String) -> Response<RpcResult> {
+    info!("admin: {}", description);
+    Response::new(RpcResult {
+        ok: true,
+        description,
+    })
+}
+
+// the changes are seen by the connected clients as if made by the users
+#[tonic::async_trait]
+impl<S: ChatStorage> ChatRoomAdmin for Arc<ChatRoomImpl<S>> {
+    #[doc = " Lists all users with their online status"]
+    async fn list_all_users(
+        &self,
+        _: Request<AdminRequest>,
+    ) -> Result<Response<AdminUsers>, Status> {
+        debug!("list_all_users()");
+        blocking(self, |chat_room| {
+            let mut users = chat_room
+                .storage
+                .read_all_users()
+                .map_err(|e| Status::internal(format!("failed read users, {}", e)))?;
+            users.sort_by_key(|u| u.id);
+            let users = users
+                .into_iter()
+                .map(|user| AdminUser {
+                    online: chat_room.presence.is_online(user.id),
+                    user: Some(user),
+                })
+                .collect();
+            Ok(Response::new(AdminUsers { users }))
+        })
+        .await
+    }
+
+    #[doc = " Lists all chats with the counts of their members and posts"]
+    async fn list_all_chats(
+        &self,
+        _: Request<AdminRequest>,
+    ) -> Result<Response<AdminChats>, Status> {
+        debug!("list_all_chats()");
+        blocking(self, |chat_room| {
+            let storage = &chat_room.storage;
+            let mut chats = storage
+                .read_all_chats()
+                .map_err(|e| Status::internal(format!("failed read chats, {}", e)))?;
+            chats.sort_by_key(|c| c.id);
+            let chats = chats
+                .into_iter()
+                .map(|chat| AdminChat {
+                    members: chat.users.len() as u32,
+                    posts: storage.chat_posts_count(chat.id).unwrap_or_default() as u64,
+                    chat: Some(chat),
+                })
+                .collect();
+            Ok(Response::new(AdminChats { chats }))
+        })
+        .await
+    }
+
+    #[doc = " Ends all sessions of the user, the user is to register again"]
+    async fn logout_user(
+        &self,
+        request: Request<Registration>,
+    ) -> Result<Response<RpcResult>, Status> {
+        debug!("logout_user(): {:?}", request.get_ref());
+        let user_id = request.into_inner().user_id;
+        let ended = self.end_user_sessions(user_id);
+        Ok(done(format!(
+            "{} session(s) of user {} ended",
+            ended, user_id
+        )))
+    }
+
+    #[doc = " Deletes the chat with its posts, the user id is not used"]
+    async fn delete_chat(
+        &self,
+        request: Request<ChatReference>,
+    ) -> Result<Response<RpcResult>, Status> {
+        debug!("delete_chat(): {:?}", request.get_ref());
+        let chat_id = request.into_inner().chat_id;
+        blocking(self, move |chat_room| {
+            let storage = &chat_room.storage;
+            match storage.read_chat(chat_id) {
+                Ok(Some(_)) => {}
+                Ok(None) => return Err(Status::not_found("chat does not exist")),
+                Err(e) => return Err(Status::internal(format!("failed read chats, {}", e))),
+            }
+            storage
+                .remove_chat(chat_id)
+                .map_err(|e| Status::internal(format!("failed remove chat, {}", e)))?;
+            chat_room.notify_chat_changed(ChatChanged::Closed(chat_id));
+            Ok(done(format!("chat {} deleted", chat_id)))
+        })
+        .await
+    }
+
+    #[doc = " Deletes the user, its sessions end and it leaves its chats"]
+    async fn delete_user(
+        &self,
+        request: Request<Registration>,
+    ) -> Result<Response<RpcResult>, Status> {
+        debug!("delete_user(): {:?}", request.get_ref());
+        let user_id = request.into_inner().user_id;
+        blocking(self, move |chat_room| {
+            let storage = &chat_room.storage;
+            match storage.read_user(user_id) {
+                Ok(Some(_)) => {}
+                Ok(None) => return Err(Status::not_found("user does not exist")),
+                Err(e) => return Err(Status::internal(format!("failed read users, {}", e))),
+            }
+            let ended = chat_room.end_user_sessions(user_id);
+            let chat_ids = storage
+                .read_user_chats(user_id)
+                .map_err(|e| Status::internal(format!("failed read user chats, {}", e)))?;
+            // the members see the user leave, the chats left by everyone are closed
+            let name = member_name(storage, user_id);
+            for chat_id in &chat_ids {
+                if remove_member(chat_room, *chat_id, user_id, |_| Ok(()))? {
+                    post_system_notice(chat_room, *chat_id, format!("{} left the chat", name));
+                }
+            }
+            storage
+                .remove_user(user_id)
+                .map_err(|e| Status::internal(format!("failed remove user, {}", e)))?;
+            storage
+                .remove_user_secret(user_id)
+                .map_err(|e| Status::internal(format!("failed remove user, {}", e)))?;
+            Ok(done(format!(
+                "user {} deleted, {} session(s) ended, {} chat(s) left",
+                user_id,
+                ended,
+                chat_ids.len()
+            )))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::chat_room_admin_client::ChatRoomAdminClient;
+    use crate::proto::chat_room_service_client::ChatRoomServiceClient;
+    use crate::proto::{
+        bearer_value, UpdateChats, UpdateUsers, UserInfo, NOT_USER_ID, SESSION_TOKEN_KEY,
+    };
+    use crate::proxy::{ProxyConfig, ProxyFilter};
+    use crate::storage::memory::InMemoryStorage;
+    use crate::{Chat, Limits, Listener, UserId};
+    use std::time::Duration;
+    use tonic::{transport::Channel, Code, Streaming};
+
+    const ADMIN_TOKEN: &str = "admin-secret";
+
+    fn with_token<T>(message: T, token: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert(AUTHORIZATION_KEY, bearer_value(token).parse().unwrap());
+        request
+    }
+
+    // the id of the registered user and the token of its session
+    async fn register(
+        client: &mut ChatRoomServiceClient<Channel>,
+        short_name: &str,
+    ) -> (UserId, String) {
+        let registered = client
+            .register(Request::new(UserInfo {
+                name: short_name.to_uppercase(),
+                short_name: String::from(short_name),
+            }))
+            .await
+            .unwrap();
+        let token = registered
+            .metadata()
+            .get(SESSION_TOKEN_KEY)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+            .unwrap();
+        (registered.into_inner().registration.unwrap().user_id, token)
+    }
+
+    // the first update meeting the condition, the ones before it are skipped
+    async fn wait_for<T, F: Fn(&T) -> bool>(stream: &mut Streaming<T>, condition: F) -> T {
+        let update = async {
+            loop {
+                let update = stream.message().await.unwrap().expect("stream goes on");
+                if condition(&update) {
+                    return update;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), update)
+            .await
+            .expect("update in time")
+    }
+
+    #[tokio::test]
+    async fn forced_changes_observed() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        // take a free port
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (tx_stop, rx_stop) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(crate::serve(
+            chat_room.clone(),
+            Listener::Bind(addr),
+            None,
+            Arc::new(ProxyFilter::new(ProxyConfig::default())),
+            Some(String::from(ADMIN_TOKEN)),
+            async {
+                let _ = rx_stop.await;
+            },
+        ));
+        let url = format!("http://{}", addr);
+        let mut client = None;
+        for _ in 0..50 {
+            match ChatRoomServiceClient::connect(url.clone()).await {
+                Ok(connected) => {
+                    client = Some(connected);
+                    break;
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+        let mut client = client.expect("server is started");
+        let mut admin = ChatRoomAdminClient::connect(url).await.unwrap();
+        let (alice, alice_token) = register(&mut client, "alice").await;
+        let (bob, bob_token) = register(&mut client, "bob").await;
+        let chat = Chat {
+            id: 10,
+            permanent: true,
+            description: String::from("ops"),
+            users: vec![alice, bob],
+            owner_id: alice,
+            ..Default::default()
+        };
+        chat_room.storage.write_chat(chat.id, &chat).unwrap();
+        let bob_request = || with_token(Registration { user_id: bob }, &bob_token);
+        let mut users = client.get_users(bob_request()).await.unwrap().into_inner();
+        let mut chats = client.get_chats(bob_request()).await.unwrap().into_inner();
+
+        // the session tokens are not the admin one
+        let err = admin
+            .list_all_users(with_token(AdminRequest {}, &bob_token))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+        let err = admin
+            .list_all_users(Request::new(AdminRequest {}))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+
+        let listed = admin
+            .list_all_users(with_token(AdminRequest {}, ADMIN_TOKEN))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(listed.users.len(), 2);
+        assert!(listed.users.iter().all(|u| u.online));
+        let listed = admin
+            .list_all_chats(with_token(AdminRequest {}, ADMIN_TOKEN))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(listed.chats.len(), 1);
+        assert_eq!((listed.chats[0].members, listed.chats[0].posts), (2, 0));
+
+        // the logged out user goes offline for the others, its token expires
+        admin
+            .logout_user(with_token(Registration { user_id: alice }, ADMIN_TOKEN))
+            .await
+            .unwrap();
+        wait_for(&mut users, |u: &UpdateUsers| u.offline.contains(&alice)).await;
+        let err = client
+            .list_chats(with_token(Registration { user_id: alice }, &alice_token))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+
+        // the deleted user leaves the chat
+        admin
+            .delete_user(with_token(Registration { user_id: alice }, ADMIN_TOKEN))
+            .await
+            .unwrap();
+        wait_for(&mut chats, |u: &UpdateChats| {
+            u.updated
+                .iter()
+                .filter_map(|update| update.chat.as_ref())
+                .any(|c| c.id == chat.id && c.users == vec![bob])
+        })
+        .await;
+        assert!(chat_room.storage.read_user(alice).unwrap().is_none());
+
+        // the deleted chat is gone
+        let chat_ref = |chat_id| ChatReference {
+            user_id: NOT_USER_ID,
+            chat_id,
+        };
+        admin
+            .delete_chat(with_token(chat_ref(chat.id), ADMIN_TOKEN))
+            .await
+            .unwrap();
+        wait_for(&mut chats, |u: &UpdateChats| u.gone.contains(&chat.id)).await;
+        let err = admin
+            .delete_chat(with_token(chat_ref(chat.id), ADMIN_TOKEN))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+
+        tx_stop.send(()).unwrap();
+        drop(client);
+        drop(admin);
+        server.await.unwrap().unwrap();
+    }
+}
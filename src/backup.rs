@@ -0,0 +1,435 @@
+use crate::proto::{AttachmentInfo, ForwardedFrom, PostKind, PostReaction};
+use crate::storage::ChatStorage;
+use crate::{Chat, ChatId, InternalError, Post, PostId, User, UserId};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+// the layout of the document, the older ones are converted on import once it changes
+pub const BACKUP_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BackupUser {
+    pub id: UserId,
+    pub name: String,
+    pub short_name: String,
+    pub created: u64,
+    // the digest of the secret the user registers again with, the user is locked out without it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub secret_digest: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BackupChat {
+    pub id: ChatId,
+    pub permanent: bool,
+    pub description: String,
+    pub users: Vec<UserId>,
+    pub created: u64,
+    pub owner_id: UserId,
+    #[serde(default)]
+    pub export_discouraged: bool,
+}
+
+// the content of the attachments is not kept, the references are
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BackupAttachment {
+    pub id: u64,
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BackupReaction {
+    pub emoji: String,
+    pub user_ids: Vec<UserId>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BackupOrigin {
+    pub chat_id: ChatId,
+    pub post_id: PostId,
+    pub user_id: UserId,
+    pub author_name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BackupPost {
+    pub id: PostId,
+    pub chat_id: ChatId,
+    pub user_id: UserId,
+    pub text: String,
+    #[serde(default)]
+    pub attachments: Vec<BackupAttachment>,
+    pub created: u64,
+    pub author_name: String,
+    #[serde(default)]
+    pub reactions: Vec<BackupReaction>,
+    pub reply_to_post_id: PostId,
+    pub forwarded_from: Option<BackupOrigin>,
+    // the notice of the server, e.g. who entered the chat
+    #[serde(default)]
+    pub system: bool,
+}
+
+// the posts of every chat follow in the order of writing
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Backup {
+    pub version: u32,
+    pub users: Vec<BackupUser>,
+    pub chats: Vec<BackupChat>,
+    pub posts: Vec<BackupPost>,
+}
+
+// what has been restored, the skipped posts have been reported
+#[derive(Debug, Default, PartialEq)]
+pub struct Restored {
+    pub users: usize,
+    pub chats: usize,
+    pub posts: usize,
+    pub skipped_posts: usize,
+}
+
+impl From<&User> for BackupUser {
+    fn from(user: &User) -> Self {
+        BackupUser {
+            id: user.id,
+            name: user.name.clone(),
+            short_name: user.short_name.clone(),
+            created: user.created,
+            secret_digest: Vec::new(),
+        }
+    }
+}
+
+impl From<BackupUser> for User {
+    fn from(user: BackupUser) -> Self {
+        User {
+            id: user.id,
+            name: user.name,
+            short_name: user.short_name,
+            created: user.created,
+        }
+    }
+}
+
+impl From<&Chat> for BackupChat {
+    fn from(chat: &Chat) -> Self {
+        BackupChat {
+            id: chat.id,
+            permanent: chat.permanent,
+            description: chat.description.clone(),
+            users: chat.users.clone(),
+            created: chat.created,
+            owner_id: chat.owner_id,
+            export_discouraged: chat.export_discouraged,
+        }
+    }
+}
+
+impl From<BackupChat> for Chat {
+    fn from(chat: BackupChat) -> Self {
+        Chat {
+            id: chat.id,
+            permanent: chat.permanent,
+            description: chat.description,
+            users: chat.users,
+            created: chat.created,
+            owner_id: chat.owner_id,
+            export_discouraged: chat.export_discouraged,
+        }
+    }
+}
+
+impl From<&Post> for BackupPost {
+    fn from(post: &Post) -> Self {
+        BackupPost {
+            id: post.id,
+            chat_id: post.chat_id,
+            user_id: post.user_id,
+            text: post.text.clone(),
+            attachments: post
+                .attachments
+                .iter()
+                .map(|a| BackupAttachment {
+                    id: a.id,
+                    name: a.name.clone(),
+                    size: a.size,
+                })
+                .collect(),
+            created: post.created,
+            author_name: post.author_name.clone(),
+            reactions: post
+                .reactions
+                .iter()
+                .map(|r| BackupReaction {
+                    emoji: r.emoji.clone(),
+                    user_ids: r.user_ids.clone(),
+                })
+                .collect(),
+            reply_to_post_id: post.reply_to_post_id,
+            forwarded_from: post.forwarded_from.as_ref().map(|origin| BackupOrigin {
+                chat_id: origin.chat_id,
+                post_id: origin.post_id,
+                user_id: origin.user_id,
+                author_name: origin.author_name.clone(),
+            }),
+            system: post.kind() == PostKind::System,
+        }
+    }
+}
+
+impl From<BackupPost> for Post {
+    fn from(post: BackupPost) -> Self {
+        Post {
+            id: post.id,
+            chat_id: post.chat_id,
+            user_id: post.user_id,
+            text: post.text,
+            attachments: post
+                .attachments
+                .into_iter()
+                .map(|a| AttachmentInfo {
+                    id: a.id,
+                    name: a.name,
+                    size: a.size,
+                })
+                .collect(),
+            created: post.created,
+            author_name: post.author_name,
+            reactions: post
+                .reactions
+                .into_iter()
+                .map(|r| PostReaction {
+                    emoji: r.emoji,
+                    user_ids: r.user_ids,
+                })
+                .collect(),
+            reply_to_post_id: post.reply_to_post_id,
+            forwarded_from: post.forwarded_from.map(|origin| ForwardedFrom {
+                chat_id: origin.chat_id,
+                post_id: origin.post_id,
+                user_id: origin.user_id,
+                author_name: origin.author_name,
+            }),
+            idempotency_key: String::new(),
+            seq: 0,
+            kind: if post.system {
+                PostKind::System
+            } else {
+                PostKind::Regular
+            } as i32,
+        }
+    }
+}
+
+// the users, the chats and their posts as the storage has them
+pub fn export<S: ChatStorage>(storage: &S) -> Result<Backup, InternalError> {
+    let mut users = Vec::new();
+    for user in storage.read_all_users()? {
+        let mut backup_user = BackupUser::from(&user);
+        backup_user.secret_digest = storage.read_user_secret(user.id)?.unwrap_or_default();
+        users.push(backup_user);
+    }
+    let chats = storage.read_all_chats()?;
+    let mut posts = Vec::new();
+    for chat in &chats {
+        let count = storage.chat_posts_count(chat.id)?;
+        posts.extend(
+            storage
+                .read_chat_posts(chat.id, 0, count)?
+                .iter()
+                .map(BackupPost::from),
+        );
+    }
+    Ok(Backup {
+        version: BACKUP_VERSION,
+        users,
+        chats: chats.iter().map(BackupChat::from).collect(),
+        posts,
+    })
+}
+
+// fills the empty storage, the posts of the chats missing from the backup are skipped
+pub fn import<S: ChatStorage>(storage: &S, backup: Backup) -> Result<Restored, InternalError> {
+    if backup.version != BACKUP_VERSION {
+        return Err(format!(
+            "backup version {} is not supported, expected {}",
+            backup.version, BACKUP_VERSION
+        )
+        .into());
+    }
+    if !storage.read_all_users()?.is_empty() || !storage.read_all_chats()?.is_empty() {
+        return Err("the database is not empty".into());
+    }
+    let mut restored = Restored::default();
+    let user_ids: HashSet<UserId> = backup.users.iter().map(|u| u.id).collect();
+    for user in backup.users {
+        if !user.secret_digest.is_empty() {
+            storage.write_user_secret(user.id, &user.secret_digest)?;
+        }
+        storage.write_user(user.id, &user.into())?;
+        restored.users += 1;
+    }
+    let mut chat_ids = HashSet::new();
+    for chat in backup.chats {
+        let unknown = chat
+            .users
+            .iter()
+            .filter(|id| !user_ids.contains(id))
+            .count();
+        if unknown > 0 {
+            warn!("chat {} has unknown members: {}", chat.id, unknown);
+        }
+        chat_ids.insert(chat.id);
+        storage.write_chat(chat.id, &chat.into())?;
+        restored.chats += 1;
+    }
+    for post in backup.posts {
+        if !chat_ids.contains(&post.chat_id) {
+            warn!(
+                "post {} refers to missing chat {}, skipped",
+                post.id, post.chat_id
+            );
+            restored.skipped_posts += 1;
+            continue;
+        }
+        let post_id = post.id;
+        if storage.write_post(&post.into())? {
+            restored.posts += 1;
+        } else {
+            warn!("post {} is duplicated, skipped", post_id);
+            restored.skipped_posts += 1;
+        }
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Fixture;
+    use crate::storage::memory::InMemoryStorage;
+    use prost::Message;
+
+    fn encoded<M: Message>(message: &M) -> Vec<u8> {
+        let mut buf = Vec::new();
+        message.encode(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = InMemoryStorage::new();
+        let seeded = Fixture::new(1561)
+            .users(5)
+            .chats(3, |c| c.members(2..4).posts(5..20))
+            .dialogs(1)
+            .seed(&source)
+            .unwrap();
+        // the optional parts of the post survive as well
+        let chat_id = seeded.chats[0];
+        let count = source.chat_posts_count(chat_id).unwrap();
+        let first = source.read_chat_posts(chat_id, 0, 1).unwrap().remove(0);
+        let post = Post {
+            id: 42,
+            chat_id,
+            user_id: seeded.users[0],
+            text: String::from("see attached"),
+            attachments: vec![AttachmentInfo {
+                id: 7,
+                name: String::from("notes.txt"),
+                size: 1536,
+            }],
+            created: first.created + 1,
+            author_name: String::from("user0"),
+            reactions: vec![PostReaction {
+                emoji: String::from("👍"),
+                user_ids: vec![seeded.users[1]],
+            }],
+            reply_to_post_id: first.id,
+            forwarded_from: Some(ForwardedFrom {
+                chat_id: seeded.chats[1],
+                post_id: 43,
+                user_id: seeded.users[2],
+                author_name: String::from("user2"),
+            }),
+            idempotency_key: String::new(),
+            seq: 0,
+            kind: PostKind::Regular as i32,
+        };
+        assert!(source.write_post(&post).unwrap());
+        // the users registering again need their secrets
+        source
+            .write_user_secret(seeded.users[0], b"digest")
+            .unwrap();
+        let backup = export(&source).unwrap();
+        assert_eq!(backup.posts.len(), seeded.posts + 1);
+        // through the text of the document
+        let text = serde_json::to_string_pretty(&backup).unwrap();
+        let target = InMemoryStorage::new();
+        let restored = import(&target, serde_json::from_str(&text).unwrap()).unwrap();
+        assert_eq!(
+            restored,
+            Restored {
+                users: 5,
+                chats: 4,
+                posts: seeded.posts + 1,
+                skipped_posts: 0,
+            }
+        );
+        let bytes = |users: Vec<User>| users.iter().map(encoded).collect::<Vec<_>>();
+        assert_eq!(
+            bytes(target.read_all_users().unwrap()),
+            bytes(source.read_all_users().unwrap())
+        );
+        assert_eq!(
+            target.read_user_secret(seeded.users[0]).unwrap(),
+            Some(b"digest".to_vec())
+        );
+        assert_eq!(target.read_user_secret(seeded.users[1]).unwrap(), None);
+        for chat_id in &seeded.chats {
+            let chat = source.read_chat(*chat_id).unwrap().map(|c| encoded(&c));
+            assert_eq!(
+                target.read_chat(*chat_id).unwrap().map(|c| encoded(&c)),
+                chat
+            );
+            let count = source.chat_posts_count(*chat_id).unwrap();
+            assert_eq!(target.chat_posts_count(*chat_id).unwrap(), count);
+            let posts = |storage: &InMemoryStorage| {
+                let posts = storage.read_chat_posts(*chat_id, 0, count).unwrap();
+                posts.iter().map(encoded).collect::<Vec<_>>()
+            };
+            assert_eq!(posts(&target), posts(&source));
+        }
+        assert_eq!(target.chat_posts_count(chat_id).unwrap(), count + 1);
+        // once restored, the database is not filled again
+        let err = import(&target, backup).unwrap_err();
+        assert!(err.to_string().contains("not empty"));
+    }
+
+    #[test]
+    fn posts_of_missing_chats_skipped() {
+        let source = InMemoryStorage::new();
+        let seeded = Fixture::new(1562)
+            .users(3)
+            .chats(2, |c| c.members(2..3).posts(3..4))
+            .seed(&source)
+            .unwrap();
+        let mut backup = export(&source).unwrap();
+        let missing = seeded.chats[1];
+        backup.chats.retain(|chat| chat.id != missing);
+        let target = InMemoryStorage::new();
+        let restored = import(&target, backup.clone()).unwrap();
+        assert_eq!(
+            (restored.chats, restored.posts, restored.skipped_posts),
+            (1, 3, 3)
+        );
+        assert_eq!(target.chat_posts_count(missing).unwrap(), 0);
+        assert!(target.read_chat(missing).unwrap().is_none());
+        // the document of another layout is refused as a whole
+        backup.version = BACKUP_VERSION + 1;
+        let err = import(&InMemoryStorage::new(), backup).unwrap_err();
+        assert!(err.to_string().contains("not supported"));
+    }
+}
@@ -0,0 +1,319 @@
+use crate::client_service::{ChatHistory, ChatRoomEvent};
+use crate::proto::{Chat, ChatId, ChatUpdate, Post, User, UserId};
+use log::warn;
+use prost::Message;
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+pub const DEF_CACHED_POSTS: usize = 50;
+const CACHE_DIR: &str = "migchat";
+const USERS_FILE: &str = "users.pb";
+const CHATS_FILE: &str = "chats.pb";
+const POSTS_FILE: &str = "posts.pb";
+
+// the cache dir of XDG, ~/.cache if not set
+pub fn default_dir() -> Option<PathBuf> {
+    let cache_dir = match env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache_dir.join(CACHE_DIR))
+}
+
+// the messages one after another, each prefixed by its length
+fn read_messages<M: Message + Default>(path: &Path) -> io::Result<Vec<M>> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut buf = content.as_slice();
+    let mut messages = Vec::new();
+    while !buf.is_empty() {
+        let message = M::decode_length_delimited(&mut buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+fn write_messages<'a, M: Message + 'a, I: Iterator<Item = &'a M>>(
+    path: &Path,
+    messages: I,
+) -> io::Result<()> {
+    let mut buf = Vec::new();
+    for message in messages {
+        message
+            .encode_length_delimited(&mut buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, buf)?;
+    fs::rename(&tmp, path)
+}
+
+// the users, the chats and the recent posts as told by the server last,
+// shown at start before the server answers
+pub struct ChatCache {
+    dir: PathBuf,
+    posts_per_chat: usize,
+    users: BTreeMap<UserId, User>,
+    // the chat and the count of its posts on the server
+    chats: BTreeMap<ChatId, (Chat, usize)>,
+    // the newest last
+    posts: BTreeMap<ChatId, VecDeque<Post>>,
+}
+
+impl ChatCache {
+    pub fn new(dir: &Path, posts_per_chat: usize) -> Self {
+        ChatCache {
+            dir: dir.to_path_buf(),
+            posts_per_chat,
+            users: BTreeMap::new(),
+            chats: BTreeMap::new(),
+            posts: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.chats.is_empty()
+    }
+
+    // the missing or corrupt files are an empty cache
+    pub fn load(&mut self) {
+        let loaded = (|| -> io::Result<()> {
+            for user in read_messages::<User>(&self.dir.join(USERS_FILE))? {
+                self.users.insert(user.id, user);
+            }
+            for update in read_messages::<ChatUpdate>(&self.dir.join(CHATS_FILE))? {
+                if let Some(chat) = update.chat {
+                    let history_len = update.currently_posts as usize;
+                    self.chats.insert(chat.id, (chat, history_len));
+                }
+            }
+            for post in read_messages::<Post>(&self.dir.join(POSTS_FILE))? {
+                self.note_post(post);
+            }
+            Ok(())
+        })();
+        if let Err(e) = loaded {
+            warn!("cache {} is not usable, {}", self.dir.display(), e);
+            self.users.clear();
+            self.chats.clear();
+            self.posts.clear();
+        }
+    }
+
+    // replaces the files entirely, the dir is created if missing
+    pub fn save(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        write_messages(&self.dir.join(USERS_FILE), self.users.values())?;
+        let updates: Vec<ChatUpdate> = self
+            .chats
+            .values()
+            .map(|(chat, history_len)| ChatUpdate {
+                chat: Some(chat.clone()),
+                currently_posts: *history_len as u64,
+                ..Default::default()
+            })
+            .collect();
+        write_messages(&self.dir.join(CHATS_FILE), updates.iter())?;
+        write_messages(&self.dir.join(POSTS_FILE), self.posts.values().flatten())
+    }
+
+    fn note_post(&mut self, post: Post) {
+        let posts = self.posts.entry(post.chat_id).or_default();
+        match posts.iter_mut().find(|p| p.id == post.id) {
+            // the reactions have changed
+            Some(cached) => *cached = post,
+            None => posts.push_back(post),
+        }
+        while posts.len() > self.posts_per_chat {
+            posts.pop_front();
+        }
+    }
+
+    fn forget_chat(&mut self, chat_id: ChatId) {
+        self.chats.remove(&chat_id);
+        self.posts.remove(&chat_id);
+    }
+
+    // keeps what the live streams tell
+    pub fn note(&mut self, event: &ChatRoomEvent) {
+        match event {
+            ChatRoomEvent::UserInfo(user) => {
+                self.users.insert(user.id, user.clone());
+            }
+            ChatRoomEvent::ChatUpdated(chat, history_len) => {
+                self.chats.insert(chat.id, (chat.clone(), *history_len));
+            }
+            ChatRoomEvent::ChatDeleted(chat_id) | ChatRoomEvent::ChatLeft(chat_id) => {
+                self.forget_chat(*chat_id)
+            }
+            ChatRoomEvent::NewPost(post) => self.note_post(post.clone()),
+            ChatRoomEvent::PostDeleted(chat_id, post_id) => {
+                if let Some(posts) = self.posts.get_mut(chat_id) {
+                    posts.retain(|p| p.id != *post_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // the cached as if told by the server, the posts as the history not to be taken for new
+    pub fn events(&self) -> Vec<ChatRoomEvent> {
+        let mut events: Vec<ChatRoomEvent> = self
+            .users
+            .values()
+            .map(|user| ChatRoomEvent::UserInfo(user.clone()))
+            .collect();
+        for (chat, history_len) in self.chats.values() {
+            events.push(ChatRoomEvent::ChatUpdated(chat.clone(), *history_len));
+        }
+        for (chat_id, (_, history_len)) in &self.chats {
+            if let Some(posts) = self.posts.get(chat_id).filter(|posts| !posts.is_empty()) {
+                events.push(ChatRoomEvent::History(ChatHistory {
+                    chat_id: *chat_id,
+                    idx_from: history_len.saturating_sub(posts.len()),
+                    posts: posts.iter().cloned().collect(),
+                }));
+            }
+        }
+        events
+    }
+
+    // the cached chats the server does not list anymore are forgotten, returns their ids
+    pub fn reconcile(&mut self, listed: &HashSet<ChatId>) -> Vec<ChatId> {
+        let gone: Vec<ChatId> = self
+            .chats
+            .keys()
+            .filter(|chat_id| !listed.contains(chat_id))
+            .copied()
+            .collect();
+        for chat_id in &gone {
+            self.forget_chat(*chat_id);
+        }
+        gone
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_DIR: &str = "migchat-test-cache";
+
+    fn chat(id: ChatId, description: &str) -> Chat {
+        Chat {
+            id,
+            description: String::from(description),
+            users: vec![1, 2],
+            ..Default::default()
+        }
+    }
+
+    fn post(id: u64, chat_id: ChatId, text: &str) -> Post {
+        Post {
+            id,
+            chat_id,
+            user_id: 2,
+            text: String::from(text),
+            created: 1_600_000_000 + id,
+            ..Default::default()
+        }
+    }
+
+    fn filled(dir: &Path) -> ChatCache {
+        let mut cache = ChatCache::new(dir, 2);
+        cache.note(&ChatRoomEvent::UserInfo(User {
+            id: 2,
+            name: String::from("Bob"),
+            short_name: String::from("bob"),
+            ..Default::default()
+        }));
+        cache.note(&ChatRoomEvent::ChatUpdated(chat(10, "ops"), 5));
+        cache.note(&ChatRoomEvent::ChatUpdated(chat(20, "dev"), 1));
+        for (id, text) in &[(1, "a"), (2, "b"), (3, "c")] {
+            cache.note(&ChatRoomEvent::NewPost(post(*id, 10, text)));
+        }
+        // the post comes again with its reactions
+        cache.note(&ChatRoomEvent::NewPost(post(3, 10, "c edited")));
+        cache.note(&ChatRoomEvent::NewPost(post(4, 20, "d")));
+        cache
+    }
+
+    // the events in short
+    fn told(cache: &ChatCache) -> Vec<String> {
+        cache
+            .events()
+            .iter()
+            .map(|event| match event {
+                ChatRoomEvent::UserInfo(user) => format!("user {}", user.short_name),
+                ChatRoomEvent::ChatUpdated(chat, history_len) => {
+                    format!("chat {} of {}", chat.description, history_len)
+                }
+                ChatRoomEvent::History(hist) => format!(
+                    "history {} from {}: {}",
+                    hist.chat_id,
+                    hist.idx_from,
+                    hist.posts
+                        .iter()
+                        .map(|p| p.text.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(",")
+                ),
+                _ => String::from("unexpected"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cache_saved_and_loaded() {
+        let _ = fs::remove_dir_all(TEST_DIR);
+        let dir = Path::new(TEST_DIR);
+        let mut empty = ChatCache::new(dir, 2);
+        empty.load();
+        assert!(empty.is_empty());
+
+        let cache = filled(dir);
+        let expected = vec![
+            "user bob",
+            "chat ops of 5",
+            "chat dev of 1",
+            "history 10 from 3: b,c edited",
+            "history 20 from 0: d",
+        ];
+        assert_eq!(told(&cache), expected);
+        cache.save().unwrap();
+        let mut loaded = ChatCache::new(dir, 2);
+        loaded.load();
+        assert_eq!(told(&loaded), expected);
+
+        // the corrupt files are no cache
+        fs::write(dir.join(CHATS_FILE), b"\x05\x0a\x03").unwrap();
+        let mut corrupt = ChatCache::new(dir, 2);
+        corrupt.load();
+        assert!(corrupt.is_empty());
+        assert!(corrupt.events().is_empty());
+        let _ = fs::remove_dir_all(TEST_DIR);
+    }
+
+    #[test]
+    fn deleted_chat_reconciled() {
+        let mut cache = filled(Path::new(TEST_DIR));
+        // deleted while the client was not running
+        let listed: HashSet<ChatId> = vec![20, 30].into_iter().collect();
+        assert_eq!(cache.reconcile(&listed), vec![10]);
+        assert_eq!(
+            told(&cache),
+            vec!["user bob", "chat dev of 1", "history 20 from 0: d"]
+        );
+        // deleted while running
+        cache.note(&ChatRoomEvent::ChatDeleted(20));
+        assert_eq!(told(&cache), vec!["user bob"]);
+        assert!(cache.reconcile(&HashSet::new()).is_empty());
+    }
+}
@@ -1,17 +1,18 @@
 // use std::error::Error;
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use config::{Config, Environment, File};
 use crossterm::{
     event::{
         self, DisableMouseCapture, EnableMouseCapture, Event as CEvent, KeyCode, KeyEvent,
-        KeyModifiers,
+        KeyModifiers, MouseEvent,
     },
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use log::{error, info, warn, LevelFilter};
 use std::{
-    io::stdout,
+    io::{stdout, Write},
+    path::PathBuf,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -21,23 +22,41 @@ use std::{
 use tokio::sync::mpsc;
 use tui::{backend::CrosstermBackend, Terminal};
 
-mod client_service;
-mod proto;
+use migchat::{cache, client_service, export, identity, relay, state};
+use migchat_core::{chat_spec, post_limits, proto};
+
+mod headless;
+mod replay;
 mod ui;
 
-use client_service::{ChatRoomEvent, Command, MigchatClient};
-use proto::UserInfo;
+use client_service::{
+    Attachment, BackoffConfig, ChatExport, ChatRoomEvent, Command, MigchatClient, RetryConfig,
+    TlsConfig,
+};
 
 const APP_NAME: &str = "migchat";
 const CONFIG: &str = "config";
 const CONFIG_DEF: &str = "client.toml";
 const CONFIG_ENV: &str = "MIGC";
 const DEF_SERVER: &str = "http://0.0.0.0:50051";
+const DEF_FILTERS_FILE: &str = "filters.txt";
+const DEF_USER_IDS_FILE: &str = "user_ids.txt";
+const RECORD: &str = "record";
+const RECORD_SCRUB: &str = "record-scrub";
+const REPLAY: &str = "replay";
+const REPLAY_SPEED: &str = "replay-speed";
+const SEND: &str = "send";
+const MESSAGE: &str = "message";
+const LISTEN: &str = "listen";
 
 // Events
 pub enum Event {
     // crossterm input events, keyboard
     Input(KeyEvent),
+    // burst of typed keys, i.e. pasted text
+    Paste(String),
+    // clicks and wheel
+    Mouse(MouseEvent),
     // timer ticks
     Tick,
     // gRPC client events
@@ -46,6 +65,247 @@ pub enum Event {
     Exit,
 }
 
+// the client service sends into the same channel as the terminal
+impl From<ChatRoomEvent> for Event {
+    fn from(event: ChatRoomEvent) -> Self {
+        Event::Client(event)
+    }
+}
+
+impl client_service::ServiceEvent for Event {
+    fn exit() -> Self {
+        Event::Exit
+    }
+}
+
+// keys queued at once cannot be typed by hand, they are pasted
+fn pasted_text(keys: &[KeyEvent]) -> Option<String> {
+    if keys.len() < 2 {
+        return None;
+    }
+    let mut text = String::with_capacity(keys.len());
+    for key in keys {
+        if key
+            .modifiers
+            .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+        {
+            return None;
+        }
+        match key.code {
+            KeyCode::Char(c) => text.push(c),
+            KeyCode::Enter => text.push('\n'),
+            KeyCode::Tab => text.push('\t'),
+            _ => return None,
+        }
+    }
+    Some(text)
+}
+
+// reads [keys] section of the config: action = "[context/]keys" or a list of them
+fn read_key_overrides(settings: &Config) -> Vec<(String, Vec<String>)> {
+    let mut overrides = Vec::new();
+    if let Ok(table) = settings.get_table("keys") {
+        for (action, value) in table {
+            let specs = match value.clone().into_array() {
+                Ok(array) => array
+                    .into_iter()
+                    .filter_map(|v| v.into_str().ok())
+                    .collect(),
+                Err(_) => value.into_str().map(|s| vec![s]).unwrap_or_default(),
+            };
+            overrides.push((action, specs));
+        }
+    }
+    overrides
+}
+
+// the client service as configured, the commands come from the channel
+fn new_client(
+    settings: &Config,
+    rx_command: mpsc::Receiver<Command>,
+) -> Result<MigchatClient, Box<dyn std::error::Error>> {
+    let mut relay_config = relay::RelayConfig::default();
+    if let Ok(secs) = settings.get_int("event_send_timeout_secs") {
+        relay_config.send_timeout = Duration::from_secs(secs.max(1) as u64);
+    }
+    if let Ok(count) = settings.get_int("event_shed_after") {
+        relay_config.shed_after = count.max(1) as u32;
+    }
+    let mut backoff_config = BackoffConfig::default();
+    if let Ok(ms) = settings.get_int("reconnect_base_ms") {
+        backoff_config.base = Duration::from_millis(ms.max(1) as u64);
+    }
+    if let Ok(secs) = settings.get_int("reconnect_cap_secs") {
+        backoff_config.cap = Duration::from_secs(secs.max(1) as u64);
+    }
+    if let Ok(jitter) = settings.get_float("reconnect_jitter") {
+        backoff_config.jitter = jitter.max(0.0).min(1.0);
+    }
+    let mut client = MigchatClient::new(rx_command, relay_config, backoff_config);
+    if let Ok(secs) = settings.get_int("heartbeat_secs") {
+        client.set_heartbeat(Duration::from_secs(secs.max(1) as u64));
+    }
+    // the commands failed transiently, e.g. timed out, are attempted that many times in all
+    if let Ok(attempts) = settings.get_int("command_attempts") {
+        client.set_retry(RetryConfig {
+            attempts: attempts.max(1) as u32,
+            ..RetryConfig::default()
+        });
+    }
+    // the id assigned by the server is presented by the following logins
+    let user_ids_file = PathBuf::from(
+        settings
+            .get_str("user_ids_file")
+            .unwrap_or_else(|_| String::from(DEF_USER_IDS_FILE)),
+    );
+    client.set_identities(identity::Identities::new(&user_ids_file));
+    // the registration and the posts got are remembered between the runs
+    match settings
+        .get_str("state_file")
+        .map(PathBuf::from)
+        .ok()
+        .or_else(state::default_path)
+    {
+        Some(path) => client.set_state_file(state::StateFile::new(&path)),
+        None => warn!("state is not kept, neither state_file nor HOME is set"),
+    }
+    // the connection is encrypted once the CA of the server is set
+    if let Ok(ca) = settings.get_str("tls_ca") {
+        let identity = match (settings.get_str("tls_cert"), settings.get_str("tls_key")) {
+            (Ok(cert), Ok(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+            (Err(_), Err(_)) => None,
+            _ => return Err("tls_cert and tls_key are to be set together".into()),
+        };
+        client.set_tls(TlsConfig {
+            ca: PathBuf::from(ca),
+            domain: settings.get_str("tls_domain").ok(),
+            identity,
+        });
+    }
+    Ok(client)
+}
+
+fn remote_address(settings: &Config) -> String {
+    if let Ok(addr) = settings.get_str("connection") {
+        addr
+    } else {
+        warn!("server connection is not set, use default {}", DEF_SERVER);
+        String::from(DEF_SERVER)
+    }
+}
+
+// --send or --listen as the configured user, without the terminal
+async fn run_headless(
+    matches: &ArgMatches<'_>,
+    settings: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let user_info = proto::UserInfo {
+        name: settings.get_str("name").unwrap_or_default(),
+        short_name: settings.get_str("short_name").unwrap_or_default(),
+    };
+    // the service waits for the name forever otherwise
+    if user_info.name.is_empty() && user_info.short_name.is_empty() {
+        return Err("name or short_name is to be set in the config".into());
+    }
+    let (tx_command, rx_command) = mpsc::channel::<Command>(16);
+    let mut client = new_client(settings, rx_command)?;
+    // the bot enters the chats it is invited to, the user is asked in the UI
+    let policy = client_service::InvitationPolicy {
+        auto_accept: settings
+            .get_bool("auto_accept_invitations")
+            .unwrap_or(false),
+    };
+    client.set_invitation_policy(policy);
+    let remote = remote_address(settings);
+    match matches.value_of(SEND) {
+        Some(chat) => {
+            let target = headless::Target::parse(chat)?;
+            let text = matches.value_of(MESSAGE).unwrap_or_default();
+            let text = post_limits::normalize_line_endings(text).into_owned();
+            post_limits::PostLimits::default().check(&text, 0)?;
+            let one_shot = headless::OneShot::new(target, text);
+            headless::send(client, tx_command, remote, user_info, one_shot).await?;
+        }
+        None => headless::listen(client, tx_command, remote, user_info, policy).await?,
+    }
+    Ok(())
+}
+
+// passes the event to the app, returns false once exit is required
+fn handle_event(app: &mut ui::App, event: Event) -> bool {
+    match event {
+        Event::Input(event) => match event.code {
+            KeyCode::Char(c) => app.on_key(
+                c,
+                event.modifiers.contains(KeyModifiers::CONTROL),
+                event.modifiers.contains(KeyModifiers::ALT),
+            ),
+            // the same table lists the keys in the help
+            code => match ui::named_key(code) {
+                Some(key) => (key.handler)(app),
+                None => {
+                    if let KeyCode::F(n) = code {
+                        app.on_function_key(
+                            n,
+                            event.modifiers.contains(KeyModifiers::CONTROL),
+                            event.modifiers.contains(KeyModifiers::ALT),
+                        );
+                    }
+                }
+            },
+        },
+        Event::Paste(text) => app.on_paste(&text),
+        Event::Mouse(event) => app.on_mouse(event),
+        Event::Tick => {
+            app.on_tick();
+        }
+        Event::Client(chat_event) => match chat_event {
+            ChatRoomEvent::Registered(user_id) => app.on_registered(user_id),
+            ChatRoomEvent::UserInfo(user) => app.on_user_info(user),
+            ChatRoomEvent::ChatUpdated(chat, history_len) => app.on_chat_updated(chat, history_len),
+            ChatRoomEvent::Invitation(invitation) => app.on_get_invited(invitation),
+            ChatRoomEvent::NewPost(post) => app.on_new_post(post),
+            ChatRoomEvent::ChatDeleted(chat_id) => app.on_chat_deleted(chat_id),
+            ChatRoomEvent::ChatLeft(chat_id) => app.on_chat_left(chat_id),
+            ChatRoomEvent::ChatDegraded(chat_id, degraded) => {
+                app.on_chat_degraded(chat_id, degraded)
+            }
+            ChatRoomEvent::Membership(chat_id, membership) => {
+                app.on_membership(chat_id, membership)
+            }
+            ChatRoomEvent::Typing(chat_id, user_id, typing) => {
+                app.on_typing(chat_id, user_id, typing)
+            }
+            ChatRoomEvent::ReadPosition(chat_id, post_id, unread) => {
+                app.on_read_position(chat_id, post_id, unread)
+            }
+            ChatRoomEvent::Muted(chat_id, muted) => app.on_muted(chat_id, muted),
+            ChatRoomEvent::UserEntered(user_id) => app.on_user_entered(user_id),
+            ChatRoomEvent::UserGone(user_id) => app.on_user_gone(user_id),
+            ChatRoomEvent::History(hist) => app.on_history(hist.chat_id, hist.idx_from, hist.posts),
+            ChatRoomEvent::ChatMembers(chat_id, users) => app.on_chat_members(chat_id, users),
+            ChatRoomEvent::PostAccepted(chat_id, post_id) => app.on_post_accepted(chat_id, post_id),
+            ChatRoomEvent::PostDeleted(chat_id, post_id) => app.on_post_deleted(chat_id, post_id),
+            ChatRoomEvent::PostFailed(chat_id, text) => app.on_post_failed(chat_id, text),
+            ChatRoomEvent::CommandFailed(command, text) => app.on_command_failed(command, text),
+            ChatRoomEvent::SearchResults(query, posts) => app.on_search_results(query, posts),
+            ChatRoomEvent::Notice(text) => app.on_notice(text),
+            ChatRoomEvent::Connected => app.on_connected(),
+            ChatRoomEvent::Disconnected => app.on_disconnected(),
+            ChatRoomEvent::ReconnectScheduled {
+                attempt,
+                next_in_secs,
+            } => app.on_reconnect_scheduled(attempt, next_in_secs),
+            ChatRoomEvent::ReconnectAttempt => app.on_reconnect_attempt(),
+            ChatRoomEvent::ReconnectFailed { error } => app.on_reconnect_failed(error),
+            ChatRoomEvent::Stale(stale) => app.on_stale(stale),
+            ChatRoomEvent::Error(e) => app.on_notice(e.to_string()),
+        },
+        Event::Exit => return false,
+    }
+    true
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // commnad line
@@ -63,6 +323,58 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .help("Sets a custom config file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name(RECORD)
+                .long(RECORD)
+                .value_name("FILE")
+                .help("Records the processed events into the file")
+                .takes_value(true)
+                .conflicts_with(REPLAY),
+        )
+        .arg(
+            Arg::with_name(RECORD_SCRUB)
+                .long(RECORD_SCRUB)
+                .help("Replaces post texts in the record by placeholders")
+                .requires(RECORD),
+        )
+        .arg(
+            Arg::with_name(REPLAY)
+                .long(REPLAY)
+                .value_name("FILE")
+                .help("Runs the UI against the recorded events without connecting to server")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(REPLAY_SPEED)
+                .long(REPLAY_SPEED)
+                .value_name("FACTOR")
+                .help("Accelerates the replay, 0 replays as fast as possible")
+                .takes_value(true)
+                .requires(REPLAY),
+        )
+        .arg(
+            Arg::with_name(SEND)
+                .long(SEND)
+                .value_name("CHAT")
+                .help("Posts the message into the chat of the id or the spec and exits, no UI")
+                .takes_value(true)
+                .requires(MESSAGE)
+                .conflicts_with_all(&[RECORD, REPLAY, LISTEN]),
+        )
+        .arg(
+            Arg::with_name(MESSAGE)
+                .long(MESSAGE)
+                .value_name("TEXT")
+                .help("The text posted by --send")
+                .takes_value(true)
+                .requires(SEND),
+        )
+        .arg(
+            Arg::with_name(LISTEN)
+                .long(LISTEN)
+                .help("Prints the posts received as JSON lines until Ctrl+C, no UI")
+                .conflicts_with_all(&[RECORD, REPLAY]),
+        )
         .get_matches();
     let config_file = matches.value_of(CONFIG).unwrap_or(CONFIG_DEF);
     info!("Using config: {}", config_file);
@@ -78,6 +390,68 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .merge(Environment::with_prefix(CONFIG_ENV))
         .unwrap();
 
+    // one-shot modes for the scripts, the failure is told by the exit code
+    if matches.is_present(SEND) || matches.is_present(LISTEN) {
+        if let Err(e) = run_headless(&matches, &settings).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // settings affecting the UI, the replayed session brings its own
+    let mut session = replay::Session {
+        name: settings.get_str("name").unwrap_or_default(),
+        short_name: settings.get_str("short_name").unwrap_or_default(),
+        key_overrides: read_key_overrides(&settings),
+        composer_max_lines: ui::ComposerLimits::default().max_lines,
+        composer_max_bytes: ui::ComposerLimits::default().max_bytes,
+        unsend_grace_secs: settings
+            .get_int("unsend_grace_secs")
+            .ok()
+            .map(|secs| secs.max(0) as u64),
+        late_delivery_secs: settings
+            .get_int("late_delivery_secs")
+            .ok()
+            .map(|secs| secs.max(0) as u64),
+        language: settings.get_str("language").unwrap_or_default(),
+        notify: settings.get_str("notify").unwrap_or_default(),
+        user_colors: settings.get_bool("user_colors").ok(),
+        palette: settings.get::<Vec<String>>("palette").unwrap_or_default(),
+        config_filters: settings.get::<Vec<String>>("filters").unwrap_or_default(),
+        filters: Vec::new(),
+    };
+    if let Ok(value) = settings.get_int("composer_max_lines") {
+        session.composer_max_lines = value.max(1) as usize;
+    }
+    if let Ok(value) = settings.get_int("composer_max_bytes") {
+        session.composer_max_bytes = value.max(1) as usize;
+    }
+    // the filters added by the user
+    let filters_file = PathBuf::from(
+        settings
+            .get_str("filters_file")
+            .unwrap_or_else(|_| String::from(DEF_FILTERS_FILE)),
+    );
+    session.filters = ui::load_rules(&filters_file)
+        .map_err(|e| format!("failed loading filters {}, {}", filters_file.display(), e))?
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let recorded = match matches.value_of(REPLAY) {
+        Some(file) => {
+            let (replayed, recorded) =
+                replay::load(file).map_err(|e| format!("failed loading replay {}, {}", file, e))?;
+            session = replayed;
+            Some(recorded)
+        }
+        None => None,
+    };
+    let extended_log = settings.get_bool("extended_log").unwrap_or(false);
+
+    // key bindings, conflicting overrides prevent from start
+    let keys = session.key_bindings()?;
+
     // logging
     if tui_logger::init_logger(log::LevelFilter::Debug).is_err() {
         // failed initializing logger
@@ -95,6 +469,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tui_logger::set_level_for_target("hyper", LevelFilter::Warn);
     tui_logger::set_level_for_target("hyper::client::connect::http", LevelFilter::Warn);
 
+    if let Some(recorded) = recorded {
+        let speed = match matches.value_of(REPLAY_SPEED) {
+            Some(value) => value.parse::<f64>()?,
+            None => 1.0,
+        };
+        return replay_session(&session, recorded, speed, extended_log, keys);
+    }
+    let mut recorder = match matches.value_of(RECORD) {
+        Some(file) => Some(replay::Recorder::create(
+            file,
+            &session,
+            matches.is_present(RECORD_SCRUB),
+        )?),
+        None => None,
+    };
+
     let exit_flag = Arc::new(AtomicBool::new(false));
 
     // intercom channels
@@ -113,12 +503,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .checked_sub(last_tick.elapsed())
                 .unwrap_or_else(|| Duration::from_secs(0));
             if event::poll(timeout).unwrap() {
-                if let CEvent::Key(key) = event::read().unwrap() {
-                    if tx_event_copy.send(Event::Input(key)).await.is_err() {
+                // take all the keys queued so far
+                let mut keys = Vec::new();
+                let mut clicks = Vec::new();
+                loop {
+                    match event::read().unwrap() {
+                        CEvent::Key(key) => keys.push(key),
+                        CEvent::Mouse(mouse) => clicks.push(Event::Mouse(mouse)),
+                        CEvent::Resize(..) => {}
+                    }
+                    if !event::poll(Duration::from_secs(0)).unwrap() {
+                        break;
+                    }
+                }
+                let mut events: Vec<Event> = match pasted_text(&keys) {
+                    Some(text) => vec![Event::Paste(text)],
+                    None => keys.into_iter().map(Event::Input).collect(),
+                };
+                events.append(&mut clicks);
+                let mut failed = false;
+                for event in events {
+                    if tx_event_copy.send(event).await.is_err() {
                         error!("failed sending key");
+                        failed = true;
                         break;
                     }
                 }
+                if failed {
+                    break;
+                }
             }
             if last_tick.elapsed() >= tick_rate {
                 if tx_event_copy.send(Event::Tick).await.is_err() {
@@ -134,14 +547,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     // launch client
-    let mut client = MigchatClient::new(rx_command);
+    let mut client = new_client(&settings, rx_command)?;
+    // the UI is populated at once by the cache of the previous run
+    if settings.get_bool("cache").unwrap_or(true) {
+        match settings
+            .get_str("cache_dir")
+            .map(PathBuf::from)
+            .ok()
+            .or_else(cache::default_dir)
+        {
+            Some(dir) => {
+                let posts_per_chat = settings
+                    .get_int("cache_posts_per_chat")
+                    .map(|count| count.max(1) as usize)
+                    .unwrap_or(cache::DEF_CACHED_POSTS);
+                client.set_cache(cache::ChatCache::new(&dir, posts_per_chat));
+            }
+            None => warn!("nothing is cached, neither cache_dir nor HOME is set"),
+        }
+    }
+    let relay_stats = client.relay_stats();
     let exit_flag_copy = exit_flag.clone();
-    let remote = if let Ok(addr) = settings.get_str("connection") {
-        addr
-    } else {
-        warn!("server connection is not set, use default {}", DEF_SERVER);
-        String::from(DEF_SERVER)
-    };
+    let remote = remote_address(&settings);
+    let server_address = remote.clone();
+    let accept_invitations = settings
+        .get_bool("auto_accept_invitations")
+        .unwrap_or(false);
     let chat_service = tokio::spawn(async move {
         let tx_event_copy = tx_event.clone();
         if !client
@@ -157,11 +588,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     // launch UI
-    let user = UserInfo {
-        name: settings.get_str("name").unwrap_or_default(),
-        short_name: settings.get_str("short_name").unwrap_or_default(),
-    };
-    let extended_log = settings.get_bool("extended_log").unwrap_or(false);
     tokio::task::block_in_place(move || {
         if enable_raw_mode().is_ok() {
             let mut stdout = stdout();
@@ -169,66 +595,47 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let backend = CrosstermBackend::new(stdout);
                 if let Ok(mut terminal) = Terminal::new(backend) {
                     if terminal.clear().is_ok() {
-                        let mut app = ui::App::new(user, tx_command, extended_log);
+                        let mut app = session.new_app(tx_command, extended_log, keys);
+                        app.server_address = server_address;
+                        app.relay_stats = relay_stats;
+                        app.filters_file = Some(filters_file);
+                        app.attachments = true;
+                        app.accept_invitations = accept_invitations;
                         loop {
                             if terminal.draw(|f| ui::draw(f, &mut app)).is_err() {
                                 println!("failed drawing UI");
                                 break;
                             }
+                            if app.take_bell() {
+                                let backend = terminal.backend_mut();
+                                if backend
+                                    .write_all(b"\x07")
+                                    .and_then(|_| backend.flush())
+                                    .is_err()
+                                {
+                                    error!("failed ringing the bell");
+                                }
+                            }
                             if let Some(event) = rx_event.blocking_recv() {
-                                match event {
-                                    Event::Input(event) => match event.code {
-                                        KeyCode::Esc => app.on_esc(),
-                                        KeyCode::Enter => app.on_enter(),
-                                        KeyCode::Char(c) => app.on_key(
-                                            c,
-                                            event.modifiers.contains(KeyModifiers::CONTROL),
-                                            event.modifiers.contains(KeyModifiers::ALT),
-                                        ),
-                                        KeyCode::Left => app.on_left(),
-                                        KeyCode::Up => app.on_up(),
-                                        KeyCode::Right => app.on_right(),
-                                        KeyCode::Down => app.on_down(),
-                                        KeyCode::Backspace => app.on_backspace(),
-                                        _ => {}
-                                    },
-                                    Event::Tick => {
-                                        app.on_tick();
-                                    }
-                                    Event::Client(chat_event) => match chat_event {
-                                        ChatRoomEvent::Registered(user_id) => {
-                                            app.on_registered(user_id)
-                                        }
-                                        ChatRoomEvent::UserInfo(user) => app.on_user_info(user),
-                                        ChatRoomEvent::ChatUpdated(chat, history_len) => {
-                                            app.on_chat_updated(chat, history_len)
-                                        }
-                                        ChatRoomEvent::Invitation(invitation) => {
-                                            app.on_get_invited(invitation)
-                                        }
-                                        ChatRoomEvent::NewPost(post) => app.on_new_post(post),
-                                        ChatRoomEvent::ChatDeleted(chat_id) => {
-                                            app.on_chat_deleted(chat_id)
-                                        }
-                                        ChatRoomEvent::UserEntered(user_id) => {
-                                            app.on_user_entered(user_id)
-                                        }
-                                        ChatRoomEvent::UserGone(user_id) => {
-                                            app.on_user_gone(user_id)
-                                        }
-                                        ChatRoomEvent::History(hist) => {
-                                            app.on_history(hist.chat_id, hist.idx_from, hist.posts)
-                                        }
-                                    },
-                                    Event::Exit => {
-                                        exit_flag.store(true, Ordering::Relaxed);
-                                        break;
+                                if let Some(rec) = &mut recorder {
+                                    if let Err(e) = rec.record(&event) {
+                                        error!("failed recording event, {}", e);
+                                        recorder = None;
                                     }
                                 }
+                                if !handle_event(&mut app, event) {
+                                    exit_flag.store(true, Ordering::Relaxed);
+                                    break;
+                                }
                             } else {
                                 break;
                             }
                         }
+                        if let Some(rec) = recorder {
+                            if let Err(e) = rec.finish() {
+                                println!("failed finishing record, {}", e);
+                            }
+                        }
                     }
                     let _ = disable_raw_mode();
                     let _ = execute!(
@@ -248,3 +655,78 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("exitting migchat-client application");
     Ok(())
 }
+
+// runs the UI against the recorded events, the final state is printed on exit
+fn replay_session(
+    session: &replay::Session,
+    recorded: Vec<replay::Recorded>,
+    speed: f64,
+    extended_log: bool,
+    keys: ui::KeyBindings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // there is no server, the commands are dropped
+    let (tx_command, mut rx_command) = mpsc::channel::<Command>(16);
+    tokio::spawn(async move { while rx_command.recv().await.is_some() {} });
+    let dump = tokio::task::block_in_place(move || {
+        let mut dump = None;
+        if enable_raw_mode().is_ok() {
+            let mut stdout = stdout();
+            if execute!(stdout, EnterAlternateScreen, EnableMouseCapture).is_ok() {
+                let backend = CrosstermBackend::new(stdout);
+                if let Ok(mut terminal) = Terminal::new(backend) {
+                    if terminal.clear().is_ok() {
+                        let mut app = session.new_app(tx_command, extended_log, keys);
+                        app.server_address = String::from("replay");
+                        let _ = terminal.draw(|f| ui::draw(f, &mut app));
+                        replay::replay(&mut app, recorded, speed, |app| {
+                            let _ = terminal.draw(|f| ui::draw(f, app));
+                        });
+                        dump = Some(app.state_dump());
+                    }
+                    let _ = disable_raw_mode();
+                    let _ = execute!(
+                        terminal.backend_mut(),
+                        LeaveAlternateScreen,
+                        DisableMouseCapture
+                    );
+                    let _ = terminal.show_cursor();
+                }
+            }
+        }
+        dump
+    });
+    match dump {
+        Some(dump) => {
+            println!("{}", dump);
+            Ok(())
+        }
+        None => Err("failed running UI".to_string().into()),
+    }
+}
+
+#[test]
+fn test_pasted_text() {
+    let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+    // a single key is typed
+    assert_eq!(pasted_text(&[key(KeyCode::Char('a'))]), None);
+    assert_eq!(
+        pasted_text(&[
+            key(KeyCode::Char('a')),
+            key(KeyCode::Enter),
+            KeyEvent::new(KeyCode::Char('B'), KeyModifiers::SHIFT),
+        ]),
+        Some(String::from("a\nB"))
+    );
+    // navigation and shortcuts are never pasted
+    assert_eq!(
+        pasted_text(&[key(KeyCode::Char('a')), key(KeyCode::Up)]),
+        None
+    );
+    assert_eq!(
+        pasted_text(&[
+            key(KeyCode::Char('a')),
+            KeyEvent::new(KeyCode::Char('q'), KeyModifiers::CONTROL),
+        ]),
+        None
+    );
+}
@@ -1,27 +1,45 @@
+use crate::cache::ChatCache;
+use crate::export::{self, ExportFormat, Watermark};
+use crate::gaps::{GapDetector, Seen};
+use crate::identity::Identities;
 use crate::proto::chat_room_service_client::ChatRoomServiceClient;
 use crate::proto::{
-    Chat, ChatId, ChatInfo, ChatReference, HistoryParams, Invitation, Post, Registration, User,
-    UserId, UserInfo, NOT_USER_ID,
+    bearer_value, heartbeat, AttachmentChunk, AttachmentInfo, Chat, ChatId, ChatInfo,
+    ChatInfoUpdate, ChatMute, ChatReference, ForwardedFrom, HistoryParams, Invitation,
+    MemberReference, Membership, Post, PostId, PostKind, PostReference, Reaction, ReadMark,
+    Registration, SearchRequest, UpdateChats, UpdateUsers, User, UserId, UserInfo, UserUpdate,
+    AUTHORIZATION_KEY, CHAT_STATUS_FLAGS_ADJUSTED, CHAT_STATUS_FOUND, CHAT_STATUS_KEY,
+    LIST_LIMIT_KEY, LIST_OFFSET_KEY, NOT_CHAT_ID, NOT_POST_ID, NOT_USER_ID, POST_ID_KEY,
+    SESSION_TOKEN_KEY, SINCE_CREATED_KEY, SINCE_POST_ID_KEY, USER_ID_KEY, USER_SECRET_KEY,
 };
-use crate::Event;
+use crate::relay::{EventRelay, RelayConfig, RelayStats};
+use crate::state::{ClientState, StateFile};
 
+use futures::{Future, StreamExt};
 use log::{debug, error, info, warn};
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity},
 };
-use tokio::sync::mpsc;
-use tonic::transport::{Channel, Endpoint};
 
+/// The posts of the chat asked by [`Command::GetHistory`].
 pub struct ChatHistory {
     pub chat_id: ChatId,
     pub idx_from: usize,
     pub posts: Vec<Post>,
 }
 
+/// What the server tells the user, the replies to the commands included.
 pub enum ChatRoomEvent {
     Registered(UserId),
     UserInfo(User), // contains user_id, name, short_name
@@ -29,43 +47,631 @@ pub enum ChatRoomEvent {
     UserGone(UserId),
     ChatUpdated(Chat, usize), // chat, history_len
     ChatDeleted(ChatId),
-    Invitation(Invitation), // contains user_id, chat_id
-    NewPost(Post),          // contains chat_id, user_id, text, [attachments]
-    History(ChatHistory),   // contains requested idx_from, count, history
+    ChatLeft(ChatId),
+    ChatDegraded(ChatId, bool), // chat, history is unavailable
+    Invitation(Invitation),     // contains user_id, chat_id
+    NewPost(Post),              // contains chat_id, user_id, text, [attachments]
+    History(ChatHistory),       // contains requested idx_from, count, history
+    ChatMembers(ChatId, Vec<User>),
+    Membership(ChatId, Membership),
+    Typing(ChatId, UserId, bool),
+    ReadPosition(ChatId, PostId, usize), // the last post read, the others' posts unread after it
+    Muted(ChatId, bool),                 // the chat is muted by the user on any device
+    Connected,
+    Disconnected,
+    // the next connection attempt is delayed by the backoff
+    ReconnectScheduled { attempt: u32, next_in_secs: u64 },
+    ReconnectAttempt,
+    ReconnectFailed { error: String },
+    // the users, the chats and the posts told are cached ones, until the server confirms them
+    Stale(bool),
+    PostAccepted(ChatId, PostId),
+    PostFailed(ChatId, String), // chat, notice
+    PostDeleted(ChatId, PostId),
+    // the command has failed every attempt, the post among them is to be offered for resend
+    CommandFailed(Command, String),
+    // the query and the posts found in all the chats, the newest first
+    SearchResults(String, Vec<Post>),
+    Notice(String), // failure of the user's request to show
+    // the recoverable failure of the service, the connection is established again
+    Error(ClientError),
+}
+
+/// The events of the consumer the service sends its own into, the consumer has its
+/// input and the like among them.
+pub trait ServiceEvent: From<ChatRoomEvent> + Send + 'static {
+    /// The service has stopped on [`Command::Exit`].
+    fn exit() -> Self;
+}
+
+/// The events of a consumer having none of its own, e.g. a bot.
+pub enum ClientEvent {
+    Client(ChatRoomEvent),
+    Exit,
+}
+
+impl From<ChatRoomEvent> for ClientEvent {
+    fn from(event: ChatRoomEvent) -> Self {
+        ClientEvent::Client(event)
+    }
+}
+
+impl ServiceEvent for ClientEvent {
+    fn exit() -> Self {
+        ClientEvent::Exit
+    }
+}
+
+// the chat to write into the file, its authors are named as the user knows them
+#[derive(Clone)]
+pub struct ChatExport {
+    pub chat_id: ChatId,
+    pub path: PathBuf,
+    pub format: ExportFormat,
+    pub watermark: Watermark,
+    pub authors: HashMap<UserId, String>,
+}
+
+// content to upload with the short post referencing it
+#[derive(Clone)]
+pub struct Attachment {
+    pub chat_id: ChatId,
+    pub file_name: String,
+    pub content: String,
+    pub text: String,
 }
 
+/// The requests of the user, [`Command::Register`] comes first.
+#[derive(Clone)]
 pub enum Command {
-    Register(UserInfo),        //register on server
-    CreateChat(ChatInfo),      // create new chat
-    Invite(Invitation),        // invite user to chat
-    EnterChat(ChatId),         // enter chat specified
-    Post(Post),                // send new post
-    Exit,                      // exit chat room
-    GetHistory(HistoryParams), // chat, starting index, count
+    Register(UserInfo),            //register on server
+    UpdateUser(UserInfo),          // change own display names
+    CreateChat(ChatInfo),          // create new chat
+    UpdateChat(ChatInfoUpdate),    // change description and flags of chat
+    Invite(Invitation),            // invite user to chat
+    DeclineInvitation(Invitation), // return invitation to the inviter
+    EnterChat(ChatId),             // enter chat specified
+    LeaveChat(ChatId),             // leave chat specified
+    KickUser(MemberReference),     // remove member from own chat
+    Post(Post),                    // send new post
+    Exit,                          // exit chat room
+    GetHistory(HistoryParams),     // chat, starting index, count
+    GetChatMembers(ChatId),        // members of the chat with their names
+    DeletePost(PostReference),     // delete own post
+    PostAttachment(Attachment),    // upload content and post the reference
+    SendFile(ChatId, PathBuf),     // upload the file and post the reference
+    ReconnectNow,                  // skip the rest of the reconnection delay
+    Typing(ChatId),                // tell the chat members the user is typing
+    MarkRead(ChatId, PostId),      // the posts of the chat are read up to the one
+    MuteChat(ChatId, bool),        // mute or unmute the chat on every device
+    React(Reaction),               // toggle own reaction to the post
+    // copy the post into another chat
+    ForwardPost { post: Post, target_chat: ChatId },
+    RefreshUsers, // tell all the users with their statuses once more
+    RefreshChats, // tell all the visible chats once more
+    // find the posts of all the own chats
+    Search(String),
+    // write the whole history of the chat into the file
+    ExportChat(ChatExport),
+}
+
+// translates failed request status into the text for user
+pub fn notice_text(action: &str, status: &tonic::Status) -> String {
+    match status.code() {
+        tonic::Code::NotFound => format!("{}: no longer available", action),
+        tonic::Code::PermissionDenied => format!("{}: not permitted", action),
+        tonic::Code::Unavailable => format!("{}: server is unavailable", action),
+        _ => format!("{}: {}", action, status.message()),
+    }
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY_MIN: Duration = Duration::from_millis(250);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(30);
+const DEF_RETRY_ATTEMPTS: u32 = 3;
+const RETRY_DELAY_MAX: Duration = Duration::from_secs(2);
+// the idle client calls the server that often to stay online
+const DEF_HEARTBEAT: Duration = Duration::from_secs(30);
+// commands kept while disconnected, the oldest ones are dropped on overflow
+const PENDING_COMMANDS_CAPACITY: usize = 64;
+// typing in the same chat is told the server no more often
+const TYPING_INTERVAL: Duration = Duration::from_secs(2);
+// the content of the attachment is uploaded in chunks of that many bytes at most
+const UPLOAD_CHUNK_LEN: usize = 64 * 1024;
+// the snapshots of the users and the chats are listed by pages of that many entries
+const SNAPSHOT_PAGE_LEN: usize = 256;
+// the posts found by the search are shown that many at most
+const SEARCH_RESULTS_LEN: u32 = 50;
+// the history of the exported chat is fetched by pages of that many posts
+const EXPORT_PAGE_LEN: usize = 200;
+// the posts fetched at most for a gap of the posts stream
+const BACKFILL_POSTS_MAX: u64 = 200;
+// the state is saved that often besides the exit, not to lose much if killed
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How the service answers the invitations by itself, the consumer is asked otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InvitationPolicy {
+    /// The chat is entered at once, e.g. by a bot.
+    pub auto_accept: bool,
+}
+
+impl InvitationPolicy {
+    /// The command answering the invitation of the user, the others' declines are
+    /// just told.
+    pub fn answer(&self, user_id: UserId, invitation: &Invitation) -> Option<Command> {
+        if self.auto_accept && !invitation.declined && invitation.to_user_id == user_id {
+            Some(Command::EnterChat(invitation.chat_id))
+        } else {
+            None
+        }
+    }
+}
+
+// drops the repeated typing in the same chat within the interval
+#[derive(Default)]
+pub struct TypingThrottle {
+    // the chat and the time the typing has been let through last
+    sent: Option<(ChatId, Instant)>,
+}
+
+impl TypingThrottle {
+    pub fn is_due(&mut self, chat_id: ChatId, now: Instant) -> bool {
+        match self.sent {
+            Some((sent_chat_id, sent))
+                if sent_chat_id == chat_id
+                    && now.saturating_duration_since(sent) < TYPING_INTERVAL =>
+            {
+                false
+            }
+            _ => {
+                self.sent = Some((chat_id, now));
+                true
+            }
+        }
+    }
+}
+
+// the first chunk names the attachment and tells its size, the content follows
+fn upload_chunks(name: String, content: &[u8]) -> Vec<AttachmentChunk> {
+    let mut chunks = vec![AttachmentChunk {
+        name,
+        size: content.len() as u64,
+        data: Vec::new(),
+    }];
+    chunks.extend(
+        content
+            .chunks(UPLOAD_CHUNK_LEN)
+            .map(|data| AttachmentChunk {
+                data: data.to_vec(),
+                ..Default::default()
+            }),
+    );
+    chunks
+}
+
+// the delay doubles from `base` up to `cap` with every failed attempt,
+// up to its `jitter` part is taken off at random
+#[derive(Clone, Copy, Debug)]
+pub struct BackoffConfig {
+    pub base: Duration,
+    pub cap: Duration,
+    pub jitter: f64,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            base: RECONNECT_DELAY_MIN,
+            cap: RECONNECT_DELAY_MAX,
+            jitter: 0.5,
+        }
+    }
+}
+
+/// The commands failed transiently, e.g. timed out, are attempted that many times in all,
+/// the delays between the attempts grow as those of the reconnection.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryConfig {
+    pub attempts: u32,
+    pub backoff: BackoffConfig,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: DEF_RETRY_ATTEMPTS,
+            backoff: BackoffConfig {
+                base: RECONNECT_DELAY_MIN,
+                cap: RETRY_DELAY_MAX,
+                jitter: 0.5,
+            },
+        }
+    }
+}
+
+// the server is verified against the CA, the client presents its own certificate if set
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub ca: PathBuf,
+    // the name in the certificate of the server if it is not the host of the address
+    pub domain: Option<String>,
+    // the certificate of the client and its key
+    pub identity: Option<(PathBuf, PathBuf)>,
+}
+
+impl TlsConfig {
+    // the unreadable files are told along with the settings naming them
+    fn load(&self) -> Result<ClientTlsConfig, String> {
+        let read = |key: &str, path: &PathBuf| {
+            std::fs::read(path).map_err(|e| format!("{} {}, {}", key, path.display(), e))
+        };
+        let ca = read("tls_ca", &self.ca)?;
+        let mut config = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca));
+        if let Some(domain) = &self.domain {
+            config = config.domain_name(domain.clone());
+        }
+        if let Some((cert, key)) = &self.identity {
+            config = config.identity(Identity::from_pem(
+                read("tls_cert", cert)?,
+                read("tls_key", key)?,
+            ));
+        }
+        Ok(config)
+    }
+}
+
+// the transport errors tell the cause in their sources only
+fn causes(e: &dyn std::error::Error) -> String {
+    let mut text = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        text.push_str(", ");
+        text.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    text
+}
+
+// exponential backoff with jitter, the delay is random within [d * (1 - jitter), d]
+struct Backoff {
+    config: BackoffConfig,
+    delay: Duration,
+    // failed attempts since the last connection
+    attempt: u32,
 }
 
+impl Backoff {
+    fn new(config: BackoffConfig) -> Self {
+        Backoff {
+            config,
+            delay: config.base,
+            attempt: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.delay = self.config.base;
+        self.attempt = 0;
+    }
+
+    fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let spread = self.delay.mul_f64(self.config.jitter.max(0.0).min(1.0));
+        let jitter = Duration::from_millis(rand::random::<u64>() % (spread.as_millis() as u64 + 1));
+        let delay = self.delay - jitter;
+        self.delay = self.config.cap.min(self.delay * 2);
+        self.attempt += 1;
+        delay
+    }
+}
+
+// whole seconds left, rounded up not to show zero while still waiting
+pub fn countdown_secs(delay: Duration) -> u64 {
+    let secs = delay.as_secs();
+    if delay.subsec_nanos() > 0 {
+        secs + 1
+    } else {
+        secs
+    }
+}
+
+// the users update both streamed and listed is told the UI alike
+fn users_events(update_users: UpdateUsers) -> Vec<ChatRoomEvent> {
+    let mut events = Vec::new();
+    for user in update_users.added {
+        debug!("user info: {}", &user);
+        events.push(ChatRoomEvent::UserInfo(user));
+    }
+    for id in update_users.online {
+        debug!("user online: {}", id);
+        events.push(ChatRoomEvent::UserEntered(id));
+    }
+    for id in update_users.offline {
+        debug!("user offline: {}", id);
+        events.push(ChatRoomEvent::UserGone(id));
+    }
+    events
+}
+
+// the chats update both streamed and listed is told the UI alike
+fn chats_events(updated_chats: UpdateChats) -> Vec<ChatRoomEvent> {
+    let mut events = Vec::new();
+    for update in updated_chats.updated {
+        debug!(
+            "chat updated: {:?}, {} elder posts",
+            &update.chat, update.currently_posts
+        );
+        if let Some(chat) = update.chat {
+            let chat_id = chat.id;
+            events.push(ChatRoomEvent::ChatUpdated(
+                chat,
+                update.currently_posts as usize,
+            ));
+            events.push(ChatRoomEvent::ChatDegraded(chat_id, update.degraded));
+            events.push(ChatRoomEvent::Membership(chat_id, update.my_membership()));
+            events.push(ChatRoomEvent::Muted(chat_id, update.muted));
+            if update.last_read_post_id != NOT_POST_ID {
+                events.push(ChatRoomEvent::ReadPosition(
+                    chat_id,
+                    update.last_read_post_id,
+                    update.unread_posts as usize,
+                ));
+            }
+        } else {
+            error!("illegal chat update received, {:?}", update);
+        }
+    }
+    for chat_id in updated_chats.gone {
+        debug!("chat has gone: {}", chat_id);
+        events.push(ChatRoomEvent::ChatDeleted(chat_id));
+    }
+    events
+}
+
+// the request of the snapshot page starting with the entry given
+fn snapshot_page(user_id: UserId, offset: usize) -> tonic::Request<Registration> {
+    let mut request = tonic::Request::new(Registration { user_id });
+    let metadata = request.metadata_mut();
+    metadata.insert(
+        LIST_OFFSET_KEY,
+        MetadataValue::from(offset.to_string().as_str()),
+    );
+    metadata.insert(
+        LIST_LIMIT_KEY,
+        MetadataValue::from(SNAPSHOT_PAGE_LEN.to_string().as_str()),
+    );
+    request
+}
+
+// the cache of the users, the chats and the posts shared by the streams
+type SharedCache = Arc<Mutex<ChatCache>>;
+
+fn note_cached(cache: &Option<SharedCache>, event: &ChatRoomEvent) {
+    if let Some(Ok(mut cache)) = cache.as_ref().map(|cache| cache.lock()) {
+        cache.note(event);
+    }
+}
+
+async fn send_event<E: ServiceEvent>(tx_event: &mpsc::Sender<E>, event: ChatRoomEvent) {
+    if let Err(e) = tx_event.send(E::from(event)).await {
+        error!("failed routing connection event: {}", e);
+    }
+}
+
+// transport failures come as Unknown status
+fn is_connection_lost(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable | tonic::Code::Unknown
+    )
+}
+
+// the same request may succeed later
+fn is_retryable(status: &tonic::Status) -> bool {
+    is_connection_lost(status) || status.code() == tonic::Code::DeadlineExceeded
+}
+
+// the server takes the retries of the post for the first attempt by it
+fn idempotency_key() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+// the way command loop has ended
+enum Served {
+    Exit,
+    Lost(Option<ClientError>), // the reason told by the streams
+}
+
+// what the server has told on registration, the secret is given to the new user only
+struct Registered {
+    user_id: UserId,
+    created: u64,
+    secret: Option<String>,
+}
+
+// tasks reading the server streams, they are stopped with the connection
+struct Subscriptions {
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// The connection to the server serving the commands of the user, it registers again
+/// and resubscribes once the connection is lost.
+///
+/// ```no_run
+/// use migchat::client_service::{BackoffConfig, ChatRoomEvent, ClientEvent, Command, MigchatClient};
+/// use migchat::proto::{Post, UserInfo};
+/// use migchat::relay::RelayConfig;
+/// use std::sync::{atomic::AtomicBool, Arc};
+/// use tokio::sync::mpsc;
+///
+/// # async fn echo_bot() {
+/// let (tx_command, rx_command) = mpsc::channel(16);
+/// let (tx_event, mut rx_event) = mpsc::channel::<ClientEvent>(64);
+/// let mut client =
+///     MigchatClient::new(rx_command, RelayConfig::default(), BackoffConfig::default());
+/// let exit_flag = Arc::new(AtomicBool::new(false));
+/// tokio::spawn(async move { client.launch("http://127.0.0.1:50051", tx_event, exit_flag).await });
+/// let bot = UserInfo {
+///     name: String::from("Echo Bot"),
+///     short_name: String::from("echo"),
+/// };
+/// tx_command.send(Command::Register(bot)).await.ok();
+/// let mut bot_id = None;
+/// while let Some(event) = rx_event.recv().await {
+///     match event {
+///         ClientEvent::Client(ChatRoomEvent::Registered(user_id)) => bot_id = Some(user_id),
+///         ClientEvent::Client(ChatRoomEvent::NewPost(post)) if Some(post.user_id) != bot_id => {
+///             let echo = Post {
+///                 chat_id: post.chat_id,
+///                 user_id: bot_id.unwrap_or_default(),
+///                 text: post.text,
+///                 ..Default::default()
+///             };
+///             tx_command.send(Command::Post(echo)).await.ok();
+///         }
+///         ClientEvent::Exit => break,
+///         _ => {}
+///     }
+/// }
+/// # }
+/// ```
 pub struct MigchatClient {
     rx_command: mpsc::Receiver<Command>,
+    // commands received while disconnected, replayed after reconnect
+    pending: VecDeque<Command>,
+    // buffering of the stream events for the UI
+    relay_config: RelayConfig,
+    relay_stats: Arc<RelayStats>,
+    backoff_config: BackoffConfig,
+    retry_config: RetryConfig,
+    heartbeat: Duration,
+    // the registration and the newest posts delivered, the posts stream resumes after them
+    state: Arc<Mutex<ClientState>>,
+    // the state is not kept between the runs if not set
+    state_file: Option<StateFile>,
+    typing: TypingThrottle,
+    // the ids assigned by the servers, presented by the following registrations
+    identities: Option<Identities>,
+    // plaintext if not set
+    tls: Option<TlsConfig>,
+    invitation_policy: InvitationPolicy,
+    // nothing is cached if not set
+    cache: Option<SharedCache>,
 }
 
 impl MigchatClient {
-    pub fn new(rx_command: mpsc::Receiver<Command>) -> Self {
-        MigchatClient { rx_command }
+    /// The commands are read from the channel once [`MigchatClient::launch`]ed.
+    pub fn new(
+        rx_command: mpsc::Receiver<Command>,
+        relay_config: RelayConfig,
+        backoff_config: BackoffConfig,
+    ) -> Self {
+        MigchatClient {
+            rx_command,
+            pending: VecDeque::with_capacity(PENDING_COMMANDS_CAPACITY),
+            relay_config,
+            relay_stats: Arc::new(RelayStats::default()),
+            backoff_config,
+            retry_config: RetryConfig::default(),
+            heartbeat: DEF_HEARTBEAT,
+            state: Arc::new(Mutex::new(ClientState::default())),
+            state_file: None,
+            typing: TypingThrottle::default(),
+            identities: None,
+            tls: None,
+            invitation_policy: InvitationPolicy::default(),
+            cache: None,
+        }
+    }
+
+    pub fn set_heartbeat(&mut self, interval: Duration) {
+        self.heartbeat = interval;
+    }
+
+    pub fn set_retry(&mut self, config: RetryConfig) {
+        self.retry_config = config;
+    }
+
+    pub fn set_identities(&mut self, identities: Identities) {
+        self.identities = Some(identities);
+    }
+
+    pub fn set_tls(&mut self, tls: TlsConfig) {
+        self.tls = Some(tls);
+    }
+
+    pub fn set_invitation_policy(&mut self, policy: InvitationPolicy) {
+        self.invitation_policy = policy;
+    }
+
+    pub fn set_state_file(&mut self, file: StateFile) {
+        self.state_file = Some(file);
+    }
+
+    /// Keeps the users, the chats and the recent posts to be shown at once the next run,
+    /// the cache is presented to the same login on the same server only.
+    pub fn set_cache(&mut self, cache: ChatCache) {
+        self.cache = Some(Arc::new(Mutex::new(cache)));
+    }
+
+    // the cache is saved along with the state
+    fn save_state(&self) {
+        if let Some(file) = &self.state_file {
+            let state = match self.state.lock() {
+                Ok(state) => state.clone(),
+                Err(_) => return,
+            };
+            if let Err(e) = file.save(&state) {
+                warn!("failed saving state, {}", e);
+            }
+        }
+        if let Some(Ok(cache)) = self.cache.as_ref().map(|cache| cache.lock()) {
+            if let Err(e) = cache.save() {
+                warn!("failed saving cache, {}", e);
+            }
+        }
     }
 
-    pub async fn launch(
+    pub fn relay_stats(&self) -> Arc<RelayStats> {
+        self.relay_stats.clone()
+    }
+
+    /// Waits for [`Command::Register`] and serves the commands until [`Command::Exit`] or
+    /// the exit flag set, the events go to `tx_event`; fails if the server refuses the
+    /// registration or the address or the TLS files are not usable.
+    pub async fn launch<E: ServiceEvent>(
         &mut self,
         server_address: &str,
-        tx_event: mpsc::Sender<Event>,
+        tx_event: mpsc::Sender<E>,
         exit_flag: Arc<AtomicBool>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), ClientError> {
         let remote = String::from(server_address);
-        let channel = Endpoint::from_shared(remote)?
-            .timeout(Duration::from_secs(10))
-            .connect()
-            .await?;
-
-        let mut client = ChatRoomServiceClient::new(channel);
+        let mut endpoint = Endpoint::from_shared(remote)
+            .map_err(|e| ClientError::Config(format!("connection {}, {}", server_address, e)))?
+            .timeout(CONNECT_TIMEOUT);
+        // the certificates are parsed here, not to fail every connection attempt instead
+        if let Some(tls) = &self.tls {
+            endpoint = endpoint
+                .tls_config(tls.load().map_err(ClientError::Config)?)
+                .map_err(|e| {
+                    ClientError::Config(format!(
+                        "tls_ca, tls_cert or tls_key is not usable, {}",
+                        causes(&e)
+                    ))
+                })?;
+        }
 
         // wait registartion info from App/UI
         let mut user_info = UserInfo::default();
@@ -79,7 +685,7 @@ impl MigchatClient {
                 }
                 Command::Exit => {
                     info!("exit requested, proceed");
-                    if let Err(e) = tx_event.send(Event::Exit).await {
+                    if let Err(e) = tx_event.send(E::exit()).await {
                         error!("failed routing exit event chat: {}", e);
                     }
                     return Ok(());
@@ -93,321 +699,2366 @@ impl MigchatClient {
             }
         }
 
-        // register
-        info!("logging as {}", &user_info);
-        let reg_req = tonic::Request::new(user_info);
-        let user_id: UserId = if let Ok(reg_res) = client.register(reg_req).await {
-            let user_id = if let Some(reg) = reg_res.into_inner().registration {
-                reg.user_id
-            } else {
-                NOT_USER_ID
-            };
-            info!("logged successfully");
-            if let Err(e) = tx_event
-                .send(Event::Client(ChatRoomEvent::Registered(user_id)))
-                .await
-            {
-                error!("failed to translate own user_id to UI: {}", e);
-            }
-            // launch accepting users in separate task
-            let fut = MigchatClient::read_users_stream(client.clone(), tx_event.clone(), user_id);
-            tokio::spawn(fut);
-            // launch accepting invitations in separate task
-            let fut =
-                MigchatClient::read_invitations_stream(client.clone(), tx_event.clone(), user_id);
-            tokio::spawn(fut);
-            // launch accepting chats in separate task
-            let fut = MigchatClient::read_chats_stream(client.clone(), tx_event.clone(), user_id);
-            tokio::spawn(fut);
-            // launch accepting posts in separate task
-            let fut = MigchatClient::read_posts_stream(client.clone(), tx_event.clone(), user_id);
-            tokio::spawn(fut);
-            user_id
-        } else {
-            warn!("registration failed");
-            return Err(Box::new(ClientServiceError {
-                text: String::from("failed to register on server"),
-            }));
+        let mut known = match &self.identities {
+            Some(identities) => identities
+                .load(server_address, &user_info.short_name)
+                .unwrap_or_else(|e| {
+                    warn!("failed reading user ids, {}", e);
+                    None
+                }),
+            None => None,
         };
+        // the state of another server or login is not presented, nor the corrupt one
+        let mut stale = false;
+        if let Some(file) = &self.state_file {
+            let saved = file.load();
+            if saved.is_for(server_address, &user_info.short_name) {
+                known = Some((saved.user_id, saved.secret.clone()));
+                if let Ok(mut state) = self.state.lock() {
+                    *state = saved;
+                }
+                stale = self.present_cache(&tx_event).await;
+            }
+        }
 
-        // start command loop
+        let mut backoff = Backoff::new(self.backoff_config);
+        let mut connected_before = false;
         loop {
-            match tokio::time::timeout(Duration::from_millis(500), self.rx_command.recv()).await {
-                Err(_) => {
-                    // timeout, test exit flag and recv commands
-                    if exit_flag.load(Ordering::Relaxed) {
-                        break;
+            if backoff.attempt() > 0 {
+                send_event(&tx_event, ChatRoomEvent::ReconnectAttempt).await;
+            }
+            let relay = (self.relay_config, &self.relay_stats);
+            let state = self.state.clone();
+            let registration = (&user_info, known.as_ref());
+            let streamed = (self.invitation_policy, self.cache.clone());
+            match MigchatClient::connect(&endpoint, registration, &tx_event, relay, state, streamed)
+                .await
+            {
+                Ok((mut client, registered, subscriptions, rx_lost)) => {
+                    let user_id = registered.user_id;
+                    backoff.reset();
+                    // the streams tell what has changed meanwhile through the relay shedding
+                    // the events of the lagging UI, the snapshots are told in full first
+                    if connected_before {
+                        self.pending.retain(|command| {
+                            !matches!(command, Command::RefreshUsers | Command::RefreshChats)
+                        });
+                        self.pending.push_front(Command::RefreshChats);
+                        self.pending.push_front(Command::RefreshUsers);
                     }
-                }
-                Ok(command) => match command {
-                    Some(command) => match command {
-                        Command::CreateChat(info) => {
-                            assert_eq!(info.user_id, user_id);
-                            match client.create_chat(info).await {
-                                Ok(response) => {
-                                    if let Err(e) = tx_event
-                                        .send(Event::Client(ChatRoomEvent::ChatUpdated(
-                                            response.into_inner(),
-                                            // just created chat cannot contain elder posts
-                                            0,
-                                        )))
-                                        .await
-                                    {
-                                        error!("failed routing created chat: {}", e);
-                                    }
-                                }
-                                Err(e) => {
-                                    warn!("failed to create chat: {}", e);
-                                }
-                            }
-                        }
-                        Command::Invite(invitation) => match client.invite_user(invitation).await {
-                            Ok(response) => {
-                                debug!("invite user: {:?}", response.into_inner());
-                            }
-                            Err(e) => {
-                                warn!("failed to invite user: {}", e);
-                            }
-                        },
-                        Command::Post(post) => {
-                            assert_eq!(post.user_id, user_id);
-                            match client.create_post(post).await {
-                                Ok(response) => {
-                                    debug!("send post: {:?}", response.into_inner());
-                                }
-                                Err(e) => {
-                                    warn!("failed to create chat: {}", e);
-                                }
-                            }
-                        }
-                        Command::EnterChat(chat_id) => {
-                            match client.enter_chat(ChatReference { user_id, chat_id }).await {
-                                Ok(response) => {
-                                    debug!("send post: {:?}", response.into_inner());
-                                }
-                                Err(e) => {
-                                    warn!("failed to create chat: {}", e);
-                                }
+                    connected_before = true;
+                    // the next registration presents the id assigned and the secret given with it
+                    if let Some(secret) = registered.secret {
+                        if let Some(identities) = &self.identities {
+                            let login = &user_info.short_name;
+                            if let Err(e) = identities.save(server_address, login, user_id, &secret)
+                            {
+                                warn!("failed saving user id, {}", e);
                             }
                         }
-                        Command::Exit => {
-                            match client.logout(Registration { user_id }).await {
-                                Ok(response) => {
-                                    debug!("logout: {:?}", response.into_inner());
-                                }
-                                Err(e) => {
-                                    warn!("failed to logout: {}", e);
-                                }
-                            }
-                            if let Err(e) = tx_event.send(Event::Exit).await {
-                                error!("failed routing exit event chat: {}", e);
+                        known = Some((user_id, secret));
+                    }
+                    if let Ok(mut state) = self.state.lock() {
+                        state.server = String::from(server_address);
+                        state.user_id = user_id;
+                        state.secret = known
+                            .as_ref()
+                            .map(|(_, secret)| secret.clone())
+                            .unwrap_or_default();
+                        state.created = registered.created;
+                        state.name = user_info.name.clone();
+                        state.short_name = user_info.short_name.clone();
+                    }
+                    self.save_state();
+                    if let Err(e) = tx_event.send(E::from(ChatRoomEvent::Connected)).await {
+                        error!("failed routing connected event: {}", e);
+                    }
+                    if stale {
+                        stale = !self.reconcile_cache(&mut client, user_id, &tx_event).await;
+                    }
+                    let served = self
+                        .serve(client, user_id, rx_lost, &tx_event, &exit_flag)
+                        .await;
+                    drop(subscriptions);
+                    match served {
+                        Served::Exit => break,
+                        Served::Lost(reason) => {
+                            warn!("connection to {} is lost", server_address);
+                            // shown to the user, not only in the log
+                            if let Some(e) = reason.filter(ClientError::is_recoverable) {
+                                send_event(&tx_event, ChatRoomEvent::Error(e)).await;
                             }
-                        }
-                        Command::Register(_) => {
-                            warn!("user has alredy registered");
-                        }
-                        Command::GetHistory(params) => {
-                            let idx_from = params.idx_from as usize;
-                            let chat_id = params.chat_id;
-                            match client.get_chat_history(params).await {
-                                Ok(response) => {
-                                    if let Err(e) = tx_event
-                                        .send(Event::Client(ChatRoomEvent::History(ChatHistory {
-                                            chat_id,
-                                            idx_from,
-                                            posts: response.into_inner().posts,
-                                        })))
-                                        .await
-                                    {
-                                        error!("failed routing chat history: {}", e);
-                                    }
-                                }
-                                Err(e) => {
-                                    warn!("failed getting chat history, {}", e);
-                                }
+                            if let Err(e) =
+                                tx_event.send(E::from(ChatRoomEvent::Disconnected)).await
+                            {
+                                error!("failed routing disconnected event: {}", e);
                             }
                         }
-                    },
-                    None => {
-                        info!("command channel has closed by receiver");
-                        break;
+                    }
+                }
+                Err(status) => match ClientError::from(status) {
+                    ClientError::Connect(error) => {
+                        warn!("failed to connect {}: {}", server_address, error);
+                        send_event(&tx_event, ChatRoomEvent::ReconnectFailed { error }).await;
+                    }
+                    e => {
+                        warn!("registration failed");
+                        return Err(e);
                     }
                 },
             }
+            let delay = backoff.next_delay();
+            info!("reconnecting in {} ms", delay.as_millis());
+            let scheduled = ChatRoomEvent::ReconnectScheduled {
+                attempt: backoff.attempt(),
+                next_in_secs: countdown_secs(delay),
+            };
+            send_event(&tx_event, scheduled).await;
+            if self.wait_reconnect(delay, &tx_event, &exit_flag).await {
+                break;
+            }
         }
+        self.save_state();
         info!("exitting, bye!");
         Ok(())
     }
 
-    async fn read_users_stream(
-        client: ChatRoomServiceClient<Channel>,
-        tx_event: mpsc::Sender<Event>,
-        user_id: UserId,
-    ) {
-        let mut client = client;
-        match client
-            .get_users(tonic::Request::new(Registration { user_id }))
-            .await
-        {
-            Ok(response) => {
-                let mut stream = response.into_inner();
-                while let Some(update_users) = stream.message().await.ok().flatten() {
-                    if !update_users.added.is_empty() {
-                        for user in update_users.added {
-                            debug!("user info: {}", &user);
-                            if let Err(e) = tx_event
-                                .send(Event::Client(ChatRoomEvent::UserInfo(user)))
-                                .await
-                            {
-                                error!("failed to transfer entered user: {}", e);
-                            }
-                        }
-                    }
-                    for id in update_users.online {
-                        debug!("user online: {}", id);
-                        if let Err(e) = tx_event
-                            .send(Event::Client(ChatRoomEvent::UserEntered(id)))
-                            .await
-                        {
-                            error!("failed to transfer entered user: {}", e);
-                        }
-                    }
-                    for id in update_users.offline {
-                        debug!("user offline: {}", id);
-                        if let Err(e) = tx_event
-                            .send(Event::Client(ChatRoomEvent::UserGone(id)))
-                            .await
-                        {
-                            error!("failed to transfer offline user: {}", e);
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                warn!("no more updated users: {}", e);
+    // tells the cached as if the server has, returns true if anything is told
+    async fn present_cache<E: ServiceEvent>(&self, tx_event: &mpsc::Sender<E>) -> bool {
+        let events = match self.cache.as_ref().map(|cache| cache.lock()) {
+            Some(Ok(mut cache)) => {
+                cache.load();
+                cache.events()
             }
+            _ => return false,
+        };
+        if events.is_empty() {
+            return false;
+        }
+        info!("presenting cache until connected");
+        send_event(tx_event, ChatRoomEvent::Stale(true)).await;
+        for event in events {
+            send_event(tx_event, event).await;
         }
+        true
     }
 
-    async fn read_invitations_stream(
-        client: ChatRoomServiceClient<Channel>,
-        tx_event: mpsc::Sender<Event>,
+    // the cached chats the server does not list anymore have gone while the client was not
+    // running, the rest is confirmed by the streams; returns false if the chats are not listed
+    async fn reconcile_cache<E: ServiceEvent>(
+        &self,
+        client: &mut ChatRoomServiceClient<Channel>,
         user_id: UserId,
-    ) {
-        let mut client = client;
-        match client
-            .get_invitations(tonic::Request::new(Registration { user_id }))
-            .await
-        {
-            Ok(response) => {
-                let mut stream = response.into_inner();
-                while let Some(invitation) = stream.message().await.ok().flatten() {
-                    debug!("new invitation: {:?}", &invitation);
-                    if let Err(e) = tx_event
-                        .send(Event::Client(ChatRoomEvent::Invitation(invitation)))
-                        .await
-                    {
-                        error!("failed to transfer invitation to UI {}", e);
+        tx_event: &mpsc::Sender<E>,
+    ) -> bool {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return true,
+        };
+        let mut listed = HashSet::new();
+        let mut offset = 0;
+        loop {
+            match client.list_chats(snapshot_page(user_id, offset)).await {
+                Ok(response) => {
+                    let updated_chats = response.into_inner();
+                    let count = updated_chats.updated.len();
+                    listed.extend(
+                        updated_chats
+                            .updated
+                            .iter()
+                            .flat_map(|u| &u.chat)
+                            .map(|c| c.id),
+                    );
+                    if count < SNAPSHOT_PAGE_LEN {
+                        break;
                     }
+                    offset += count;
+                }
+                Err(e) => {
+                    warn!("failed listing chats to reconcile cache, {}", e);
+                    return false;
                 }
             }
-            Err(e) => {
-                warn!("no more invitations: {}", e);
-            }
         }
+        let gone = match cache.lock() {
+            Ok(mut cache) => cache.reconcile(&listed),
+            Err(_) => Vec::new(),
+        };
+        for chat_id in gone {
+            debug!("cached chat has gone: {}", chat_id);
+            send_event(tx_event, ChatRoomEvent::ChatDeleted(chat_id)).await;
+        }
+        send_event(tx_event, ChatRoomEvent::Stale(false)).await;
+        true
     }
 
-    async fn read_posts_stream(
-        client: ChatRoomServiceClient<Channel>,
-        tx_event: mpsc::Sender<Event>,
-        user_id: UserId,
-    ) {
-        let mut client = client;
-        match client
-            .get_posts(tonic::Request::new(Registration { user_id }))
+    // registers on the server and subscribes to its streams,
+    // any of the streams ending signals the connection is lost
+    async fn connect<E: ServiceEvent>(
+        endpoint: &Endpoint,
+        (user_info, known): (&UserInfo, Option<&(UserId, String)>),
+        tx_event: &mpsc::Sender<E>,
+        (relay_config, relay_stats): (RelayConfig, &Arc<RelayStats>),
+        state: Arc<Mutex<ClientState>>,
+        (invitation_policy, cache): (InvitationPolicy, Option<SharedCache>),
+    ) -> Result<
+        (
+            ChatRoomServiceClient<Channel>,
+            Registered,
+            Subscriptions,
+            mpsc::Receiver<ClientError>,
+        ),
+        tonic::Status,
+    > {
+        let channel = endpoint
+            .connect()
             .await
-        {
-            Ok(response) => {
-                let mut stream = response.into_inner();
-                while let Some(post) = stream.message().await.ok().flatten() {
-                    debug!("new post: {:?}", &post);
-                    if let Err(e) = tx_event
-                        .send(Event::Client(ChatRoomEvent::NewPost(post)))
-                        .await
-                    {
-                        error!("failed to transfer post to UI {}", e);
-                    }
+            .map_err(|e| tonic::Status::unavailable(format!("{}", e)))?;
+        let mut client = ChatRoomServiceClient::new(channel.clone());
+
+        // register
+        info!("logging as {}", user_info);
+        let mut reg_req = tonic::Request::new(user_info.clone());
+        if let Some((user_id, secret)) = known {
+            reg_req.metadata_mut().insert(
+                USER_ID_KEY,
+                MetadataValue::from(user_id.to_string().as_str()),
+            );
+            if !secret.is_empty() {
+                if let Ok(secret) = secret.parse() {
+                    reg_req.metadata_mut().insert(USER_SECRET_KEY, secret);
                 }
             }
-            Err(e) => {
-                warn!("no more posts: {}", e);
+        }
+        let response = client.register(reg_req).await?;
+        let token = response
+            .metadata()
+            .get(SESSION_TOKEN_KEY)
+            .and_then(|v| v.to_str().ok())
+            .map(bearer_value);
+        let secret = response
+            .metadata()
+            .get(USER_SECRET_KEY)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let registered = response.into_inner();
+        let user_id = match registered.registration {
+            Some(reg) => reg.user_id,
+            None => NOT_USER_ID,
+        };
+        info!("logged successfully");
+        // the token of the session is presented by all the following calls
+        match token {
+            Some(token) => {
+                let token = token
+                    .parse::<MetadataValue<Ascii>>()
+                    .map_err(|_| tonic::Status::internal("invalid session token"))?;
+                client = ChatRoomServiceClient::with_interceptor(
+                    channel,
+                    move |mut request: tonic::Request<()>| {
+                        request
+                            .metadata_mut()
+                            .insert(AUTHORIZATION_KEY, token.clone());
+                        Ok(request)
+                    },
+                );
             }
+            None => warn!("server has not issued a session token"),
         }
-    }
-
-    async fn read_chats_stream(
-        client: ChatRoomServiceClient<Channel>,
-        tx_event: mpsc::Sender<Event>,
-        user_id: UserId,
-    ) {
-        let mut client = client;
-        match client
-            .get_chats(tonic::Request::new(Registration { user_id }))
+        if let Err(e) = tx_event
+            .send(E::from(ChatRoomEvent::Registered(user_id)))
             .await
         {
-            Ok(response) => {
-                let mut stream = response.into_inner();
-                while let Some(updated_chats) = stream.message().await.ok().flatten() {
-                    if !updated_chats.updated.is_empty() {
-                        for update in updated_chats.updated {
-                            debug!(
-                                "chat updated: {:?}, {} elder posts",
-                                &update.chat, update.currently_posts
-                            );
-                            if let Some(chat) = update.chat {
-                                if let Err(e) = tx_event
-                                    .send(Event::Client(ChatRoomEvent::ChatUpdated(
-                                        chat,
-                                        update.currently_posts as usize,
-                                    )))
-                                    .await
-                                {
-                                    error!("failed to transfer updated chat: {}", e);
-                                }
-                            } else {
-                                error!("illegal chat update received, {:?}", update);
-                            }
-                        }
-                    }
-                    if !updated_chats.gone.is_empty() {
-                        for chat_id in updated_chats.gone {
-                            debug!("chat has gone: {}", chat_id);
-                            if let Err(e) = tx_event
-                                .send(Event::Client(ChatRoomEvent::ChatDeleted(chat_id)))
+            error!("failed to translate own user_id to UI: {}", e);
+        }
+        let (tx_lost, rx_lost) = mpsc::channel(1);
+        let subscriptions = Subscriptions {
+            tasks: vec![
+                // launch accepting users in separate task
+                tokio::spawn(watch_stream(
+                    MigchatClient::read_users_stream(
+                        client.clone(),
+                        tx_event.clone(),
+                        EventRelay::new("users", relay_config, relay_stats.clone()),
+                        user_id,
+                        cache.clone(),
+                    ),
+                    tx_lost.clone(),
+                )),
+                // launch accepting invitations in separate task
+                tokio::spawn(watch_stream(
+                    MigchatClient::read_invitations_stream(
+                        client.clone(),
+                        tx_event.clone(),
+                        EventRelay::new("invitations", relay_config, relay_stats.clone()),
+                        user_id,
+                        invitation_policy,
+                    ),
+                    tx_lost.clone(),
+                )),
+                // launch accepting chats in separate task
+                tokio::spawn(watch_stream(
+                    MigchatClient::read_chats_stream(
+                        client.clone(),
+                        tx_event.clone(),
+                        EventRelay::new("chats", relay_config, relay_stats.clone()),
+                        user_id,
+                        cache.clone(),
+                    ),
+                    tx_lost.clone(),
+                )),
+                // launch accepting posts in separate task
+                tokio::spawn(watch_stream(
+                    MigchatClient::read_posts_stream(
+                        client.clone(),
+                        tx_event.clone(),
+                        EventRelay::new("posts", relay_config, relay_stats.clone()),
+                        user_id,
+                        state,
+                        cache,
+                    ),
+                    tx_lost.clone(),
+                )),
+                // launch accepting typing members in separate task
+                tokio::spawn(watch_stream(
+                    MigchatClient::read_typing_stream(
+                        client.clone(),
+                        tx_event.clone(),
+                        EventRelay::new("typing", relay_config, relay_stats.clone()),
+                        user_id,
+                    ),
+                    tx_lost,
+                )),
+            ],
+        };
+        let registered = Registered {
+            user_id,
+            created: registered.created,
+            secret,
+        };
+        Ok((client, registered, subscriptions, rx_lost))
+    }
+
+    // keeps accepting commands during the delay, exit and reconnect are the only ones
+    // not queued, returns true if exit is requested meanwhile
+    async fn wait_reconnect<E: ServiceEvent>(
+        &mut self,
+        delay: Duration,
+        tx_event: &mpsc::Sender<E>,
+        exit_flag: &Arc<AtomicBool>,
+    ) -> bool {
+        let deadline = Instant::now() + delay;
+        loop {
+            if exit_flag.load(Ordering::Relaxed) {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            let timeout = Duration::from_millis(500).min(deadline - now);
+            match tokio::time::timeout(timeout, self.rx_command.recv()).await {
+                Err(_) => {}
+                Ok(Some(Command::Exit)) => {
+                    info!("exit requested while disconnected");
+                    if let Err(e) = tx_event.send(E::exit()).await {
+                        error!("failed routing exit event chat: {}", e);
+                    }
+                    return true;
+                }
+                Ok(Some(Command::Register(_))) => {
+                    warn!("user has alredy registered");
+                }
+                Ok(Some(Command::ReconnectNow)) => {
+                    info!("reconnecting now as requested");
+                    return false;
+                }
+                Ok(Some(command)) => self.enqueue(command),
+                Ok(None) => {
+                    info!("command channel has closed by receiver");
+                    return true;
+                }
+            }
+        }
+    }
+
+    fn enqueue(&mut self, command: Command) {
+        // the typing is over by the time of reconnect
+        if let Command::Typing(_) = command {
+            return;
+        }
+        if self.pending.len() >= PENDING_COMMANDS_CAPACITY {
+            warn!("too many commands while disconnected, the oldest one is dropped");
+            self.pending.pop_front();
+        }
+        self.pending.push_back(command);
+    }
+
+    async fn serve<E: ServiceEvent>(
+        &mut self,
+        mut client: ChatRoomServiceClient<Channel>,
+        user_id: UserId,
+        mut rx_lost: mpsc::Receiver<ClientError>,
+        tx_event: &mpsc::Sender<E>,
+        exit_flag: &Arc<AtomicBool>,
+    ) -> Served {
+        let mut last_call = Instant::now();
+        let mut last_saved = Instant::now();
+        // replay commands queued while disconnected
+        while let Some(command) = self.pending.pop_front() {
+            if !self
+                .execute_retrying(&mut client, user_id, command, tx_event)
+                .await
+            {
+                return Served::Lost(None);
+            }
+        }
+        // start command loop
+        loop {
+            if exit_flag.load(Ordering::Relaxed) {
+                return Served::Exit;
+            }
+            if last_saved.elapsed() >= STATE_SAVE_INTERVAL {
+                last_saved = Instant::now();
+                self.save_state();
+            }
+            if last_call.elapsed() >= self.heartbeat {
+                last_call = Instant::now();
+                match client.get_chat_history(heartbeat()).await {
+                    Ok(_) => {}
+                    // the server has forgotten the session
+                    Err(e)
+                        if is_connection_lost(&e) || e.code() == tonic::Code::Unauthenticated =>
+                    {
+                        warn!("heartbeat failed, {}", e);
+                        return Served::Lost(None);
+                    }
+                    Err(e) => warn!("heartbeat failed, {}", e),
+                }
+            }
+            tokio::select! {
+                lost = rx_lost.recv() => return Served::Lost(lost),
+                command = tokio::time::timeout(Duration::from_millis(500), self.rx_command.recv()) => {
+                    match command {
+                        // timeout, test exit flag and recv commands
+                        Err(_) => {}
+                        Ok(Some(Command::Exit)) => {
+                            MigchatClient::logout(&mut client, user_id, tx_event).await;
+                            return Served::Exit;
+                        }
+                        Ok(Some(Command::Typing(chat_id)))
+                            if !self.typing.is_due(chat_id, Instant::now()) => {}
+                        Ok(Some(command)) => {
+                            last_call = Instant::now();
+                            if !self
+                                .execute_retrying(&mut client, user_id, command, tx_event)
                                 .await
                             {
-                                error!("failed to transfer deleted chat: {}", e);
+                                return Served::Lost(None);
                             }
                         }
+                        Ok(None) => {
+                            info!("command channel has closed by receiver");
+                            return Served::Exit;
+                        }
                     }
                 }
             }
+        }
+    }
+
+    async fn logout<E: ServiceEvent>(
+        client: &mut ChatRoomServiceClient<Channel>,
+        user_id: UserId,
+        tx_event: &mpsc::Sender<E>,
+    ) {
+        match client.logout(Registration { user_id }).await {
+            Ok(response) => {
+                debug!("logout: {:?}", response.into_inner());
+            }
             Err(e) => {
-                warn!("no more updated chats: {}", e);
+                warn!("failed to logout: {}", e);
+            }
+        }
+        if let Err(e) = tx_event.send(E::exit()).await {
+            error!("failed routing exit event chat: {}", e);
+        }
+    }
+
+    // uploads the content and posts the reference to it
+    async fn post_file(
+        client: &mut ChatRoomServiceClient<Channel>,
+        mut post: Post,
+        name: String,
+        content: &[u8],
+    ) -> Result<(), tonic::Status> {
+        let chunks = upload_chunks(name, content);
+        let info: AttachmentInfo = client
+            .upload_attachment(futures::stream::iter(chunks))
+            .await?
+            .into_inner();
+        debug!("upload attachment: {:?}", info);
+        post.attachments.push(info);
+        let response = client.create_post(post).await?;
+        debug!("post attachment: {:?}", response.into_inner());
+        Ok(())
+    }
+
+    // the command failed transiently is attempted again after the delay, the order of the
+    // commands is kept; the one failed every attempt is told the consumer,
+    // returns false if the connection is lost
+    async fn execute_retrying<E: ServiceEvent>(
+        &self,
+        client: &mut ChatRoomServiceClient<Channel>,
+        user_id: UserId,
+        mut command: Command,
+        tx_event: &mpsc::Sender<E>,
+    ) -> bool {
+        let mut backoff = Backoff::new(self.retry_config.backoff);
+        loop {
+            let (failed, status) =
+                match MigchatClient::execute(client, user_id, command, tx_event).await {
+                    Ok(()) => return true,
+                    Err(failed) => failed,
+                };
+            // the typing is over by the time of the retry
+            if let Command::Typing(_) = failed {
+                return !is_connection_lost(&status);
+            }
+            if backoff.attempt() + 1 >= self.retry_config.attempts {
+                warn!(
+                    "request has failed {} times, {}",
+                    backoff.attempt() + 1,
+                    status
+                );
+                let text = notice_text("request", &status);
+                send_event(tx_event, ChatRoomEvent::CommandFailed(failed, text)).await;
+                return !is_connection_lost(&status);
             }
+            let delay = backoff.next_delay();
+            debug!("retrying request in {} ms, {}", delay.as_millis(), status);
+            tokio::time::sleep(delay).await;
+            command = failed;
         }
     }
+
+    // gives the command back with the status if it has failed transiently
+    async fn execute<E: ServiceEvent>(
+        client: &mut ChatRoomServiceClient<Channel>,
+        user_id: UserId,
+        command: Command,
+        tx_event: &mpsc::Sender<E>,
+    ) -> Result<(), (Command, tonic::Status)> {
+        // the key is kept by the retries
+        let command = match command {
+            Command::Post(mut post) if post.idempotency_key.is_empty() => {
+                post.idempotency_key = idempotency_key();
+                Command::Post(post)
+            }
+            command => command,
+        };
+        let retry = command.clone();
+        match command {
+            Command::CreateChat(info) => {
+                assert_eq!(info.user_id, user_id);
+                match client.create_chat(info).await {
+                    Ok(response) => {
+                        let found = response
+                            .metadata()
+                            .get(CHAT_STATUS_KEY)
+                            .and_then(|v| v.to_str().ok())
+                            .map(|v| v == CHAT_STATUS_FOUND || v == CHAT_STATUS_FLAGS_ADJUSTED)
+                            .unwrap_or(false);
+                        if found {
+                            info!("entered existing chat");
+                        }
+                        if let Err(e) = tx_event
+                            .send(E::from(ChatRoomEvent::ChatUpdated(
+                                response.into_inner(),
+                                // just created chat cannot contain elder posts
+                                0,
+                            )))
+                            .await
+                        {
+                            error!("failed routing created chat: {}", e);
+                        }
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to create chat: {}", e);
+                        let event = ChatRoomEvent::Notice(notice_text("new chat", &e));
+                        if let Err(e) = tx_event.send(E::from(event)).await {
+                            error!("failed routing notice: {}", e);
+                        }
+                    }
+                }
+            }
+            Command::Invite(invitation) => match client.invite_user(invitation).await {
+                Ok(response) => {
+                    debug!("invite user: {:?}", response.into_inner());
+                }
+                Err(e) if is_retryable(&e) => return Err((retry, e)),
+                Err(e) => {
+                    warn!("failed to invite user: {}", e);
+                }
+            },
+            Command::DeclineInvitation(invitation) => {
+                match client.decline_invitation(invitation).await {
+                    Ok(response) => {
+                        debug!("decline invitation: {:?}", response.into_inner());
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to decline invitation: {}", e);
+                    }
+                }
+            }
+            Command::Post(post) => {
+                assert_eq!(post.user_id, user_id);
+                let chat_id = post.chat_id;
+                let event = match client.create_post(post).await {
+                    Ok(response) => {
+                        let post_id = response
+                            .metadata()
+                            .get(POST_ID_KEY)
+                            .and_then(|v| v.to_str().ok())
+                            .and_then(|v| v.parse::<PostId>().ok());
+                        debug!("send post: {:?}", response.into_inner());
+                        post_id.map(|id| ChatRoomEvent::PostAccepted(chat_id, id))
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to create post: {}", e);
+                        Some(ChatRoomEvent::PostFailed(chat_id, notice_text("post", &e)))
+                    }
+                };
+                if let Some(event) = event {
+                    if let Err(e) = tx_event.send(E::from(event)).await {
+                        error!("failed routing post result: {}", e);
+                    }
+                }
+            }
+            Command::ForwardPost { post, target_chat } => {
+                // the server copies the text from the stored post, its origin is enough
+                let forwarded = Post {
+                    id: NOT_POST_ID,
+                    user_id,
+                    chat_id: target_chat,
+                    forwarded_from: Some(ForwardedFrom {
+                        chat_id: post.chat_id,
+                        post_id: post.id,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                };
+                match client.create_post(forwarded).await {
+                    Ok(response) => {
+                        debug!("forward post: {:?}", response.into_inner());
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to forward post: {}", e);
+                        let event = ChatRoomEvent::Notice(notice_text("forward", &e));
+                        if let Err(e) = tx_event.send(E::from(event)).await {
+                            error!("failed routing forward result: {}", e);
+                        }
+                    }
+                }
+            }
+            Command::DeletePost(post_ref) => {
+                let chat_id = post_ref.chat_id;
+                let post_id = post_ref.post_id;
+                let event = match client.delete_post(post_ref).await {
+                    Ok(response) => {
+                        debug!("delete post: {:?}", response.into_inner());
+                        ChatRoomEvent::PostDeleted(chat_id, post_id)
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to delete post: {}", e);
+                        ChatRoomEvent::Notice(notice_text("unsend", &e))
+                    }
+                };
+                if let Err(e) = tx_event.send(E::from(event)).await {
+                    error!("failed routing deleted post: {}", e);
+                }
+            }
+            Command::React(reaction) => {
+                // the post comes again with the reactions on the posts stream
+                match client.react_to_post(reaction).await {
+                    Ok(response) => {
+                        debug!("react: {:?}", response.into_inner());
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to react: {}", e);
+                        let event = ChatRoomEvent::Notice(notice_text("react", &e));
+                        if let Err(e) = tx_event.send(E::from(event)).await {
+                            error!("failed routing reaction result: {}", e);
+                        }
+                    }
+                }
+            }
+            Command::PostAttachment(attachment) => {
+                let post = Post {
+                    id: NOT_POST_ID,
+                    user_id,
+                    chat_id: attachment.chat_id,
+                    text: attachment.text,
+                    ..Default::default()
+                };
+                let content = attachment.content.into_bytes();
+                match MigchatClient::post_file(client, post, attachment.file_name, &content).await {
+                    Ok(()) => {}
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to post attachment: {}", e);
+                        let event = ChatRoomEvent::Notice(notice_text("attach", &e));
+                        if let Err(e) = tx_event.send(E::from(event)).await {
+                            error!("failed routing attachment result: {}", e);
+                        }
+                    }
+                }
+            }
+            Command::SendFile(chat_id, path) => {
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let read = tokio::task::spawn_blocking(move || std::fs::read(path)).await;
+                let notice = match read {
+                    Ok(Ok(content)) => {
+                        let post = Post {
+                            id: NOT_POST_ID,
+                            user_id,
+                            chat_id,
+                            ..Default::default()
+                        };
+                        match MigchatClient::post_file(client, post, name, &content).await {
+                            Ok(()) => None,
+                            Err(e) if is_retryable(&e) => return Err((retry, e)),
+                            Err(e) => {
+                                warn!("failed to send file: {}", e);
+                                Some(notice_text("send file", &e))
+                            }
+                        }
+                    }
+                    Ok(Err(e)) => {
+                        warn!("failed to read {}: {}", name, e);
+                        Some(format!("send file: {}", e))
+                    }
+                    Err(e) => {
+                        error!("failed reading file: {}", e);
+                        Some(format!("send file: {}", e))
+                    }
+                };
+                if let Some(notice) = notice {
+                    let event = ChatRoomEvent::Notice(notice);
+                    if let Err(e) = tx_event.send(E::from(event)).await {
+                        error!("failed routing send file result: {}", e);
+                    }
+                }
+            }
+            Command::EnterChat(chat_id) => {
+                match client.enter_chat(ChatReference { user_id, chat_id }).await {
+                    Ok(response) => {
+                        debug!("send post: {:?}", response.into_inner());
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to create chat: {}", e);
+                    }
+                }
+            }
+            Command::UpdateChat(update) => {
+                // the members get the updated chat by the chats stream
+                match client.update_chat_info(update).await {
+                    Ok(response) => {
+                        debug!("update chat info: {:?}", response.into_inner());
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to update chat info: {}", e);
+                        let event = ChatRoomEvent::Notice(notice_text("rename", &e));
+                        if let Err(e) = tx_event.send(E::from(event)).await {
+                            error!("failed routing notice: {}", e);
+                        }
+                    }
+                }
+            }
+            Command::UpdateUser(info) => {
+                // everyone including the user gets the new names by the users stream
+                let update = UserUpdate {
+                    user_id,
+                    name: info.name,
+                    short_name: info.short_name,
+                };
+                match client.update_user(update).await {
+                    Ok(response) => {
+                        debug!("update user: {:?}", response.into_inner());
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to update user: {}", e);
+                        let event = ChatRoomEvent::Notice(notice_text("profile", &e));
+                        if let Err(e) = tx_event.send(E::from(event)).await {
+                            error!("failed routing notice: {}", e);
+                        }
+                    }
+                }
+            }
+            Command::RefreshUsers => {
+                let mut offset = 0;
+                loop {
+                    match client.list_users(snapshot_page(user_id, offset)).await {
+                        Ok(response) => {
+                            let update_users = response.into_inner();
+                            let listed = update_users.added.len();
+                            for event in users_events(update_users) {
+                                if let Err(e) = tx_event.send(E::from(event)).await {
+                                    error!("failed routing listed users: {}", e);
+                                }
+                            }
+                            if listed < SNAPSHOT_PAGE_LEN {
+                                break;
+                            }
+                            offset += listed;
+                        }
+                        Err(e) if is_retryable(&e) => return Err((retry, e)),
+                        Err(e) => {
+                            warn!("failed listing users, {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Command::RefreshChats => {
+                let mut offset = 0;
+                loop {
+                    match client.list_chats(snapshot_page(user_id, offset)).await {
+                        Ok(response) => {
+                            let updated_chats = response.into_inner();
+                            let listed = updated_chats.updated.len();
+                            for event in chats_events(updated_chats) {
+                                if let Err(e) = tx_event.send(E::from(event)).await {
+                                    error!("failed routing listed chats: {}", e);
+                                }
+                            }
+                            if listed < SNAPSHOT_PAGE_LEN {
+                                break;
+                            }
+                            offset += listed;
+                        }
+                        Err(e) if is_retryable(&e) => return Err((retry, e)),
+                        Err(e) => {
+                            warn!("failed listing chats, {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Command::LeaveChat(chat_id) => {
+                let event = match client.leave_chat(ChatReference { user_id, chat_id }).await {
+                    Ok(response) => {
+                        debug!("leave chat: {:?}", response.into_inner());
+                        ChatRoomEvent::ChatLeft(chat_id)
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to leave chat: {}", e);
+                        ChatRoomEvent::Notice(notice_text("leave", &e))
+                    }
+                };
+                if let Err(e) = tx_event.send(E::from(event)).await {
+                    error!("failed routing left chat: {}", e);
+                }
+            }
+            Command::KickUser(member_ref) => {
+                // the members get the updated chat by the chats stream
+                match client.kick_user(member_ref).await {
+                    Ok(response) => {
+                        debug!("kick user: {:?}", response.into_inner());
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to kick user: {}", e);
+                        let event = ChatRoomEvent::Notice(notice_text("kick", &e));
+                        if let Err(e) = tx_event.send(E::from(event)).await {
+                            error!("failed routing notice: {}", e);
+                        }
+                    }
+                }
+            }
+            Command::Exit => {
+                MigchatClient::logout(client, user_id, tx_event).await;
+            }
+            Command::Register(_) => {
+                warn!("user has alredy registered");
+            }
+            Command::ReconnectNow => {
+                debug!("already connected");
+            }
+            Command::MarkRead(chat_id, last_post_id) => {
+                match client
+                    .mark_read(ReadMark {
+                        user_id,
+                        chat_id,
+                        last_post_id,
+                    })
+                    .await
+                {
+                    Ok(response) => {
+                        debug!("mark read: {:?}", response.into_inner());
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to mark read: {}", e);
+                    }
+                }
+            }
+            Command::MuteChat(chat_id, muted) => {
+                // the chats stream tells the mute back, to the other devices as well
+                match client
+                    .set_chat_mute(ChatMute {
+                        user_id,
+                        chat_id,
+                        muted,
+                    })
+                    .await
+                {
+                    Ok(response) => {
+                        debug!("mute chat: {:?}", response.into_inner());
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to mute chat: {}", e);
+                        let event = ChatRoomEvent::Notice(notice_text("mute", &e));
+                        if let Err(e) = tx_event.send(E::from(event)).await {
+                            error!("failed routing notice: {}", e);
+                        }
+                    }
+                }
+            }
+            Command::Typing(chat_id) => {
+                match client.set_typing(ChatReference { user_id, chat_id }).await {
+                    Ok(response) => {
+                        debug!("set typing: {:?}", response.into_inner());
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed to set typing: {}", e);
+                    }
+                }
+            }
+            Command::GetHistory(params) => {
+                let idx_from = params.idx_from as usize;
+                let chat_id = params.chat_id;
+                match client.get_chat_history(params).await {
+                    Ok(response) => {
+                        if let Err(e) = tx_event
+                            .send(E::from(ChatRoomEvent::History(ChatHistory {
+                                chat_id,
+                                idx_from,
+                                posts: response.into_inner().posts,
+                            })))
+                            .await
+                        {
+                            error!("failed routing chat history: {}", e);
+                        }
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed getting chat history, {}", e);
+                    }
+                }
+            }
+            Command::GetChatMembers(chat_id) => {
+                match client
+                    .get_chat_members(ChatReference { user_id, chat_id })
+                    .await
+                {
+                    Ok(response) => {
+                        if let Err(e) = tx_event
+                            .send(E::from(ChatRoomEvent::ChatMembers(
+                                chat_id,
+                                response.into_inner().users,
+                            )))
+                            .await
+                        {
+                            error!("failed routing chat members: {}", e);
+                        }
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed getting chat members, {}", e);
+                    }
+                }
+            }
+            Command::Search(query) => {
+                let request = SearchRequest {
+                    user_id,
+                    chat_id: NOT_CHAT_ID,
+                    query: query.clone(),
+                    limit: SEARCH_RESULTS_LEN,
+                    offset: 0,
+                };
+                let event = match client.search_posts(request).await {
+                    Ok(response) => {
+                        ChatRoomEvent::SearchResults(query, response.into_inner().posts)
+                    }
+                    Err(e) if is_retryable(&e) => return Err((retry, e)),
+                    Err(e) => {
+                        warn!("failed searching posts, {}", e);
+                        ChatRoomEvent::Notice(notice_text("search", &e))
+                    }
+                };
+                if let Err(e) = tx_event.send(E::from(event)).await {
+                    error!("failed routing search results: {}", e);
+                }
+            }
+            Command::ExportChat(ChatExport {
+                chat_id,
+                path,
+                format,
+                watermark,
+                authors,
+            }) => {
+                // the pages are fetched until the empty one, the damaged posts are skipped
+                let mut posts = Vec::new();
+                let mut idx_from = 0;
+                loop {
+                    let params = HistoryParams {
+                        chat_id,
+                        idx_from: idx_from as u64,
+                        count: EXPORT_PAGE_LEN as u64,
+                        before_seq: 0,
+                    };
+                    match client.get_chat_history(params).await {
+                        Ok(response) => {
+                            let history = response.into_inner();
+                            // advisory, the posts are read anyway, the file is just not written
+                            if history.export_discouraged {
+                                info!("export: chat {} discourages exporting", chat_id);
+                                let event = ChatRoomEvent::Notice(String::from(
+                                    "export: the owner of the chat does not allow it",
+                                ));
+                                if let Err(e) = tx_event.send(E::from(event)).await {
+                                    error!("failed routing notice: {}", e);
+                                }
+                                return Ok(());
+                            }
+                            let page = history.posts;
+                            if page.is_empty() {
+                                break;
+                            }
+                            posts.extend(page);
+                            idx_from += EXPORT_PAGE_LEN;
+                            info!("export: chat {}, posts fetched: {}", chat_id, posts.len());
+                        }
+                        Err(e) if is_retryable(&e) => return Err((retry, e)),
+                        Err(e) => {
+                            warn!("failed exporting chat {}: {}", chat_id, e);
+                            let event = ChatRoomEvent::Notice(notice_text("export", &e));
+                            if let Err(e) = tx_event.send(E::from(event)).await {
+                                error!("failed routing notice: {}", e);
+                            }
+                            return Ok(());
+                        }
+                    }
+                }
+                let count = posts.len();
+                let written = tokio::task::spawn_blocking(move || {
+                    export::write_transcript(&path, format, &watermark, &authors, &posts)
+                        .map(|()| path)
+                })
+                .await;
+                let notice = match written {
+                    Ok(Ok(path)) => {
+                        info!(
+                            "export: chat {} written to {}, posts: {}",
+                            chat_id,
+                            path.display(),
+                            count
+                        );
+                        None
+                    }
+                    Ok(Err(e)) => {
+                        warn!("failed writing chat {}: {}", chat_id, e);
+                        Some(format!("export: {}", e))
+                    }
+                    Err(e) => {
+                        error!("failed writing transcript: {}", e);
+                        Some(format!("export: {}", e))
+                    }
+                };
+                if let Some(notice) = notice {
+                    let event = ChatRoomEvent::Notice(notice);
+                    if let Err(e) = tx_event.send(E::from(event)).await {
+                        error!("failed routing export result: {}", e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_users_stream<E: ServiceEvent>(
+        mut client: ChatRoomServiceClient<Channel>,
+        tx_event: mpsc::Sender<E>,
+        mut relay: EventRelay,
+        user_id: UserId,
+        cache: Option<SharedCache>,
+    ) -> Result<(), ClientError> {
+        let stream = client
+            .get_users(tonic::Request::new(Registration { user_id }))
+            .await
+            .map_err(|e| stream_failed("users", e))?
+            .into_inner();
+        relay
+            .run(stream, &tx_event, |update_users, relay| {
+                for event in users_events(update_users) {
+                    note_cached(&cache, &event);
+                    relay.push(event);
+                }
+            })
+            .await?;
+        Err(ClientError::StreamClosed("users"))
+    }
+
+    async fn read_invitations_stream<E: ServiceEvent>(
+        mut client: ChatRoomServiceClient<Channel>,
+        tx_event: mpsc::Sender<E>,
+        mut relay: EventRelay,
+        user_id: UserId,
+        policy: InvitationPolicy,
+    ) -> Result<(), ClientError> {
+        let stream = client
+            .get_invitations(tonic::Request::new(Registration { user_id }))
+            .await
+            .map_err(|e| stream_failed("invitations", e))?
+            .into_inner();
+        let tx_answer = tx_event.clone();
+        relay
+            .run(stream, &tx_event, move |invitation, relay| {
+                debug!("new invitation: {:?}", &invitation);
+                // the answered invitation is told as well, the chat follows by the chats stream
+                if let Some(command) = policy.answer(user_id, &invitation) {
+                    info!("answering invitation to chat {}", invitation.chat_id);
+                    let mut client = client.clone();
+                    let tx_event = tx_answer.clone();
+                    tokio::spawn(async move {
+                        let answered =
+                            MigchatClient::execute(&mut client, user_id, command, &tx_event).await;
+                        if answered.is_err() {
+                            warn!("invitation is not answered, connection is lost");
+                        }
+                    });
+                }
+                relay.push(ChatRoomEvent::Invitation(invitation));
+            })
+            .await?;
+        Err(ClientError::StreamClosed("invitations"))
+    }
+
+    async fn read_posts_stream<E: ServiceEvent>(
+        mut client: ChatRoomServiceClient<Channel>,
+        tx_event: mpsc::Sender<E>,
+        mut relay: EventRelay,
+        user_id: UserId,
+        state: Arc<Mutex<ClientState>>,
+        cache: Option<SharedCache>,
+    ) -> Result<(), ClientError> {
+        let mut request = tonic::Request::new(Registration { user_id });
+        // the posts delivered before the reconnect or the restart are not replayed
+        if let Some((post_id, created)) = state.lock().ok().and_then(|s| s.resume_after()) {
+            let metadata = request.metadata_mut();
+            metadata.insert(
+                SINCE_POST_ID_KEY,
+                MetadataValue::from(post_id.to_string().as_str()),
+            );
+            metadata.insert(
+                SINCE_CREATED_KEY,
+                MetadataValue::from(created.to_string().as_str()),
+            );
+        }
+        let stream = client
+            .get_posts(request)
+            .await
+            .map_err(|e| stream_failed("posts", e))?
+            .into_inner();
+        // the posts skipped by the stream come before the post after them
+        let gaps = Arc::new(Mutex::new(GapDetector::default()));
+        let stream = Box::pin(stream.then(move |message| {
+            let client = client.clone();
+            let gaps = gaps.clone();
+            async move { Ok::<_, tonic::Status>(backfill(client, &gaps, message?).await) }
+        }));
+        relay
+            .run(stream, &tx_event, |posts, relay| {
+                for post in posts {
+                    // the tombstone of the deleted post neither advances the stream nor is shown
+                    if post.kind() == PostKind::Deleted {
+                        debug!("deleted post: {} in chat {}", post.id, post.chat_id);
+                        let event = ChatRoomEvent::PostDeleted(post.chat_id, post.id);
+                        note_cached(&cache, &event);
+                        relay.push(event);
+                        continue;
+                    }
+                    debug!("new post: {:?}", &post);
+                    if let Ok(mut state) = state.lock() {
+                        state.advance(post.chat_id, post.id, post.created);
+                    }
+                    let event = ChatRoomEvent::NewPost(post);
+                    note_cached(&cache, &event);
+                    relay.push(event);
+                }
+            })
+            .await?;
+        Err(ClientError::StreamClosed("posts"))
+    }
+
+    async fn read_typing_stream<E: ServiceEvent>(
+        mut client: ChatRoomServiceClient<Channel>,
+        tx_event: mpsc::Sender<E>,
+        mut relay: EventRelay,
+        user_id: UserId,
+    ) -> Result<(), ClientError> {
+        let stream = client
+            .get_typing(tonic::Request::new(Registration { user_id }))
+            .await
+            .map_err(|e| stream_failed("typing", e))?
+            .into_inner();
+        relay
+            .run(stream, &tx_event, |event, relay| {
+                debug!("typing: {:?}", &event);
+                relay.push(ChatRoomEvent::Typing(
+                    event.chat_id,
+                    event.user_id,
+                    event.typing,
+                ));
+            })
+            .await?;
+        Err(ClientError::StreamClosed("typing"))
+    }
+
+    async fn read_chats_stream<E: ServiceEvent>(
+        mut client: ChatRoomServiceClient<Channel>,
+        tx_event: mpsc::Sender<E>,
+        mut relay: EventRelay,
+        user_id: UserId,
+        cache: Option<SharedCache>,
+    ) -> Result<(), ClientError> {
+        let stream = client
+            .get_chats(tonic::Request::new(Registration { user_id }))
+            .await
+            .map_err(|e| stream_failed("chats", e))?
+            .into_inner();
+        relay
+            .run(stream, &tx_event, |updated_chats, relay| {
+                for event in chats_events(updated_chats) {
+                    note_cached(&cache, &event);
+                    relay.push(event);
+                }
+            })
+            .await?;
+        Err(ClientError::StreamClosed("chats"))
+    }
+}
+
+// the post preceded by the posts of its chat skipped right before it, fetched by their seqs;
+// those delivered meanwhile are not repeated
+async fn backfill(
+    mut client: ChatRoomServiceClient<Channel>,
+    gaps: &Mutex<GapDetector>,
+    post: Post,
+) -> Vec<Post> {
+    let seen = gaps
+        .lock()
+        .ok()
+        .map(|mut gaps| gaps.note(post.chat_id, post.seq));
+    let skipped = match seen {
+        Some(Seen::Gap(skipped)) => skipped,
+        _ => return vec![post],
+    };
+    debug!(
+        "gap of {} posts in chat {} before seq {}",
+        skipped.end - skipped.start,
+        post.chat_id,
+        post.seq
+    );
+    let params = HistoryParams {
+        chat_id: post.chat_id,
+        idx_from: 0,
+        count: (skipped.end - skipped.start).min(BACKFILL_POSTS_MAX),
+        before_seq: post.seq,
+    };
+    let mut posts = match client.get_chat_history(params).await {
+        Ok(response) => response.into_inner().posts,
+        Err(e) => {
+            warn!("failed backfilling chat {}, {}", post.chat_id, e);
+            Vec::new()
+        }
+    };
+    posts.sort_by_key(|p| p.seq);
+    match gaps.lock() {
+        Ok(mut gaps) => posts.retain(|p| gaps.note(post.chat_id, p.seq) == Seen::Late),
+        Err(_) => posts.clear(),
+    }
+    posts.push(post);
+    posts
+}
+
+// the stream is not opened, the connection is lost
+fn stream_failed(name: &'static str, status: tonic::Status) -> ClientError {
+    warn!("no more {}: {}", name, status);
+    ClientError::StreamClosed(name)
 }
 
+// the streams end with the error only, any of them means the connection is lost;
+// the first one tells why
+async fn watch_stream<F>(stream: F, tx_lost: mpsc::Sender<ClientError>)
+where
+    F: Future<Output = Result<(), ClientError>>,
+{
+    if let Err(e) = stream.await {
+        let _ = tx_lost.try_send(e);
+    }
+}
+
+// the failures of the service, the registration refused and the UI gone are fatal
 #[derive(Debug)]
-struct ClientServiceError {
-    text: String,
+pub enum ClientError {
+    // the server address or the TLS files are not usable
+    Config(String),
+    // the server is unreachable, the transport failure
+    Connect(String),
+    Register(tonic::Status),
+    // the stream of the name, the connection is lost
+    StreamClosed(&'static str),
+    // the UI is gone
+    ChannelClosed,
+}
+
+impl ClientError {
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, ClientError::Connect(_) | ClientError::StreamClosed(_))
+    }
+}
+
+// transport failures are to be retried, the other statuses are the refusals
+impl From<tonic::Status> for ClientError {
+    fn from(status: tonic::Status) -> Self {
+        if is_connection_lost(&status) {
+            ClientError::Connect(String::from(status.message()))
+        } else {
+            ClientError::Register(status)
+        }
+    }
 }
 
-impl std::error::Error for ClientServiceError {}
+impl<T> From<mpsc::error::SendError<T>> for ClientError {
+    fn from(_: mpsc::error::SendError<T>) -> Self {
+        ClientError::ChannelClosed
+    }
+}
 
-impl std::fmt::Display for ClientServiceError {
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Register(status) => Some(status),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ClientError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.text)
+        match self {
+            ClientError::Config(text) => write!(f, "{}", text),
+            ClientError::Connect(text) => write!(f, "failed to connect, {}", text),
+            ClientError::Register(status) => {
+                write!(f, "failed to register on server, {}", status.message())
+            }
+            ClientError::StreamClosed(name) => write!(f, "{} stream is closed", name),
+            ClientError::ChannelClosed => write!(f, "UI is gone"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::chat_room_service_server::{ChatRoomService, ChatRoomServiceServer};
+    use crate::proto::{self, RegistrationInfo, Result as RpcResult};
+    use std::{
+        net::SocketAddr,
+        path::Path,
+        sync::{atomic::AtomicUsize, Mutex},
+    };
+    use tokio::sync::{broadcast, watch};
+    use tokio_stream::wrappers::ReceiverStream;
+    use tonic::{transport::Server, Request, Response, Status};
+
+    #[tokio::test]
+    async fn client_errors_converted() {
+        let e = ClientError::from(tonic::Status::unavailable("connection refused"));
+        assert!(matches!(&e, ClientError::Connect(text) if text == "connection refused"));
+        assert!(e.is_recoverable());
+        assert_eq!(e.to_string(), "failed to connect, connection refused");
+        // the refusal keeps its status
+        let e = ClientError::from(tonic::Status::already_exists("short name 'user' is taken"));
+        assert!(
+            matches!(&e, ClientError::Register(status) if status.code() == tonic::Code::AlreadyExists)
+        );
+        assert!(!e.is_recoverable());
+        assert_eq!(
+            e.to_string(),
+            "failed to register on server, short name 'user' is taken"
+        );
+        assert!(std::error::Error::source(&e).is_some());
+        // the UI is gone
+        let (tx_event, rx_event) = mpsc::channel::<ClientEvent>(1);
+        drop(rx_event);
+        let e = ClientError::from(tx_event.send(ClientEvent::Exit).await.unwrap_err());
+        assert!(matches!(e, ClientError::ChannelClosed));
+        assert!(!e.is_recoverable());
+        assert_eq!(
+            ClientError::StreamClosed("posts").to_string(),
+            "posts stream is closed"
+        );
+    }
+
+    #[test]
+    fn test_notice_text() {
+        let status = tonic::Status::not_found("post does not exist");
+        assert_eq!(
+            notice_text("unsend", &status),
+            "unsend: no longer available"
+        );
+        let status = tonic::Status::permission_denied("user 2 is not the author of post 1");
+        assert_eq!(notice_text("unsend", &status), "unsend: not permitted");
+        let status = tonic::Status::internal("failed remove post");
+        assert_eq!(notice_text("unsend", &status), "unsend: failed remove post");
+    }
+
+    #[test]
+    fn backoff_stays_within_bounds() {
+        let config = BackoffConfig {
+            base: Duration::from_millis(100),
+            cap: Duration::from_secs(1),
+            jitter: 0.3,
+        };
+        let mut backoff = Backoff::new(config);
+        for round in 0..3 {
+            let mut nominal = config.base;
+            for attempt in 1..=10 {
+                let delay = backoff.next_delay();
+                assert!(delay <= nominal, "{:?} > {:?}", delay, nominal);
+                assert!(delay >= nominal.mul_f64(0.7), "{:?} < {:?}", delay, nominal);
+                assert_eq!(backoff.attempt(), attempt);
+                nominal = config.cap.min(nominal * 2);
+            }
+            assert_eq!(nominal, config.cap, "round {}", round);
+            backoff.reset();
+            assert_eq!(backoff.attempt(), 0);
+        }
+    }
+
+    #[test]
+    fn typing_throttled_per_chat() {
+        let mut throttle = TypingThrottle::default();
+        let start = Instant::now();
+        assert!(throttle.is_due(10, start));
+        assert!(!throttle.is_due(10, start + Duration::from_secs(1)));
+        // another chat is told at once
+        assert!(throttle.is_due(20, start + Duration::from_secs(1)));
+        assert!(throttle.is_due(10, start + Duration::from_secs(1)));
+        assert!(throttle.is_due(10, start + TYPING_INTERVAL + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn invitations_answered_by_policy() {
+        let invitation = |to_user_id, declined| Invitation {
+            from_user_id: 2,
+            to_user_id,
+            chat_id: 20,
+            declined,
+        };
+        let policy = InvitationPolicy { auto_accept: true };
+        assert!(matches!(
+            policy.answer(1, &invitation(1, false)),
+            Some(Command::EnterChat(20))
+        ));
+        // the decline of the own invitation is not to be answered
+        assert!(policy.answer(1, &invitation(1, true)).is_none());
+        assert!(policy.answer(1, &invitation(3, false)).is_none());
+        // the user is asked by default
+        assert!(InvitationPolicy::default()
+            .answer(1, &invitation(1, false))
+            .is_none());
+    }
+
+    #[test]
+    fn upload_chunked() {
+        let content = vec![7u8; 2 * UPLOAD_CHUNK_LEN + 1];
+        let chunks = upload_chunks(String::from("file.bin"), &content);
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(
+            (chunks[0].name.as_str(), chunks[0].size),
+            ("file.bin", content.len() as u64)
+        );
+        assert!(chunks[0].data.is_empty());
+        assert_eq!(chunks[3].data.len(), 1);
+        // nothing but the name for the empty file
+        assert_eq!(upload_chunks(String::from("empty"), &[]).len(), 1);
+    }
+
+    #[test]
+    fn countdown_rounds_up() {
+        assert_eq!(countdown_secs(Duration::from_secs(0)), 0);
+        assert_eq!(countdown_secs(Duration::from_millis(1)), 1);
+        assert_eq!(countdown_secs(Duration::from_millis(41_001)), 42);
+        assert_eq!(countdown_secs(Duration::from_secs(42)), 42);
+    }
+
+    // minimal chat room: single user, posts are broadcasted to everybody
+    struct MockChatRoom {
+        posts: broadcast::Sender<Post>,
+        // every post created by the client
+        created: Arc<Mutex<Vec<Post>>>,
+        // the cursors the posts stream is resumed after
+        cursors: Arc<Mutex<Vec<String>>>,
+        // streams end when the sender is dropped
+        stopped: watch::Receiver<()>,
+        // the streams opened
+        subscribed: Arc<AtomicUsize>,
+    }
+
+    impl MockChatRoom {
+        fn until_stopped<T: Send + 'static>(&self) -> ReceiverStream<Result<T, Status>> {
+            self.subscribed.fetch_add(1, Ordering::Relaxed);
+            let (tx, rx) = mpsc::channel(1);
+            let mut stopped = self.stopped.clone();
+            tokio::spawn(async move {
+                let _ = stopped.changed().await;
+                drop(tx);
+            });
+            ReceiverStream::new(rx)
+        }
+    }
+
+    #[tonic::async_trait]
+    impl ChatRoomService for MockChatRoom {
+        async fn register(
+            &self,
+            _: Request<UserInfo>,
+        ) -> Result<Response<RegistrationInfo>, Status> {
+            Ok(Response::new(RegistrationInfo {
+                registration: Some(Registration { user_id: 1 }),
+                created: 0,
+            }))
+        }
+
+        async fn update_user(&self, _: Request<UserUpdate>) -> Result<Response<RpcResult>, Status> {
+            Err(Status::unimplemented("update_user"))
+        }
+
+        async fn list_users(
+            &self,
+            _: Request<Registration>,
+        ) -> Result<Response<UpdateUsers>, Status> {
+            Err(Status::unimplemented("list_users"))
+        }
+
+        async fn list_chats(
+            &self,
+            _: Request<Registration>,
+        ) -> Result<Response<UpdateChats>, Status> {
+            Err(Status::unimplemented("list_chats"))
+        }
+
+        type GetInvitationsStream = ReceiverStream<Result<Invitation, Status>>;
+
+        async fn get_invitations(
+            &self,
+            _: Request<Registration>,
+        ) -> Result<Response<Self::GetInvitationsStream>, Status> {
+            Ok(Response::new(self.until_stopped()))
+        }
+
+        async fn logout(&self, _: Request<Registration>) -> Result<Response<RpcResult>, Status> {
+            Ok(Response::new(RpcResult::default()))
+        }
+
+        type GetPostsStream = ReceiverStream<Result<Post, Status>>;
+
+        async fn get_posts(
+            &self,
+            request: Request<Registration>,
+        ) -> Result<Response<Self::GetPostsStream>, Status> {
+            if let Some(cursor) = request.metadata().get(SINCE_POST_ID_KEY) {
+                let cursor = String::from(cursor.to_str().unwrap());
+                self.cursors.lock().unwrap().push(cursor);
+            }
+            self.subscribed.fetch_add(1, Ordering::Relaxed);
+            let (tx, rx) = mpsc::channel(4);
+            let mut posts = self.posts.subscribe();
+            let mut stopped = self.stopped.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        post = posts.recv() => match post {
+                            Ok(post) => {
+                                if tx.send(Ok(post)).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(_) => break,
+                        },
+                        _ = stopped.changed() => break,
+                    }
+                }
+            });
+            Ok(Response::new(ReceiverStream::new(rx)))
+        }
+
+        type GetUsersStream = ReceiverStream<Result<proto::UpdateUsers, Status>>;
+
+        async fn get_users(
+            &self,
+            _: Request<Registration>,
+        ) -> Result<Response<Self::GetUsersStream>, Status> {
+            Ok(Response::new(self.until_stopped()))
+        }
+
+        type GetChatsStream = ReceiverStream<Result<proto::UpdateChats, Status>>;
+
+        async fn get_chats(
+            &self,
+            _: Request<Registration>,
+        ) -> Result<Response<Self::GetChatsStream>, Status> {
+            Ok(Response::new(self.until_stopped()))
+        }
+
+        type GetTypingStream = ReceiverStream<Result<proto::TypingEvent, Status>>;
+
+        async fn get_typing(
+            &self,
+            _: Request<Registration>,
+        ) -> Result<Response<Self::GetTypingStream>, Status> {
+            Ok(Response::new(self.until_stopped()))
+        }
+
+        async fn create_post(&self, request: Request<Post>) -> Result<Response<RpcResult>, Status> {
+            self.created.lock().unwrap().push(request.into_inner());
+            Ok(Response::new(RpcResult::default()))
+        }
+
+        async fn delete_post(
+            &self,
+            _: Request<PostReference>,
+        ) -> Result<Response<RpcResult>, Status> {
+            Err(Status::unimplemented("delete_post"))
+        }
+
+        async fn create_chat(&self, _: Request<ChatInfo>) -> Result<Response<Chat>, Status> {
+            Err(Status::unimplemented("create_chat"))
+        }
+
+        async fn update_chat_info(
+            &self,
+            _: Request<ChatInfoUpdate>,
+        ) -> Result<Response<Chat>, Status> {
+            Err(Status::unimplemented("update_chat_info"))
+        }
+
+        async fn kick_user(
+            &self,
+            _: Request<MemberReference>,
+        ) -> Result<Response<RpcResult>, Status> {
+            Err(Status::unimplemented("kick_user"))
+        }
+
+        async fn invite_user(&self, _: Request<Invitation>) -> Result<Response<RpcResult>, Status> {
+            Err(Status::unimplemented("invite_user"))
+        }
+
+        async fn decline_invitation(
+            &self,
+            _: Request<Invitation>,
+        ) -> Result<Response<RpcResult>, Status> {
+            Err(Status::unimplemented("decline_invitation"))
+        }
+
+        async fn enter_chat(
+            &self,
+            _: Request<ChatReference>,
+        ) -> Result<Response<RpcResult>, Status> {
+            Err(Status::unimplemented("enter_chat"))
+        }
+
+        async fn leave_chat(
+            &self,
+            _: Request<ChatReference>,
+        ) -> Result<Response<RpcResult>, Status> {
+            Err(Status::unimplemented("leave_chat"))
+        }
+
+        // a post in every chat, chat 4 discourages the export
+        async fn get_chat_history(
+            &self,
+            request: Request<HistoryParams>,
+        ) -> Result<Response<proto::ChatHistory>, Status> {
+            let params = request.into_inner();
+            let posts = if params.idx_from == 0 && params.count > 0 {
+                vec![Post {
+                    id: 1,
+                    chat_id: params.chat_id,
+                    user_id: 2,
+                    text: String::from("history"),
+                    ..Default::default()
+                }]
+            } else {
+                Vec::new()
+            };
+            Ok(Response::new(proto::ChatHistory {
+                posts,
+                export_discouraged: params.chat_id == 4,
+            }))
+        }
+
+        async fn get_chat_members(
+            &self,
+            _: Request<ChatReference>,
+        ) -> Result<Response<proto::ChatMembers>, Status> {
+            Err(Status::unimplemented("get_chat_members"))
+        }
+
+        async fn set_typing(
+            &self,
+            _: Request<ChatReference>,
+        ) -> Result<Response<RpcResult>, Status> {
+            Err(Status::unimplemented("set_typing"))
+        }
+
+        async fn mark_read(&self, _: Request<ReadMark>) -> Result<Response<RpcResult>, Status> {
+            Err(Status::unimplemented("mark_read"))
+        }
+
+        async fn set_chat_mute(&self, _: Request<ChatMute>) -> Result<Response<RpcResult>, Status> {
+            Err(Status::unimplemented("set_chat_mute"))
+        }
+
+        async fn react_to_post(&self, _: Request<Reaction>) -> Result<Response<RpcResult>, Status> {
+            Err(Status::unimplemented("react_to_post"))
+        }
+
+        async fn upload_attachment(
+            &self,
+            _: Request<tonic::Streaming<AttachmentChunk>>,
+        ) -> Result<Response<AttachmentInfo>, Status> {
+            Err(Status::unimplemented("upload_attachment"))
+        }
+
+        type DownloadAttachmentStream = ReceiverStream<Result<AttachmentChunk, Status>>;
+
+        async fn download_attachment(
+            &self,
+            _: Request<proto::AttachmentReference>,
+        ) -> Result<Response<Self::DownloadAttachmentStream>, Status> {
+            Err(Status::unimplemented("download_attachment"))
+        }
+
+        async fn search_posts(
+            &self,
+            _: Request<SearchRequest>,
+        ) -> Result<Response<proto::SearchResult>, Status> {
+            Err(Status::unimplemented("search_posts"))
+        }
+
+        async fn take_snapshot(
+            &self,
+            _: Request<proto::SnapshotRequest>,
+        ) -> Result<Response<proto::SnapshotResult>, Status> {
+            Err(Status::unimplemented("take_snapshot"))
+        }
+    }
+
+    struct RunningServer {
+        posts: broadcast::Sender<Post>,
+        cursors: Arc<Mutex<Vec<String>>>,
+        // the next requests fail with the codes, one each
+        failing: Arc<Mutex<VecDeque<tonic::Code>>>,
+        subscribed: Arc<AtomicUsize>,
+        stop: watch::Sender<()>,
+        server: JoinHandle<Result<(), tonic::transport::Error>>,
+    }
+
+    impl RunningServer {
+        fn start(addr: SocketAddr, created: Arc<Mutex<Vec<Post>>>) -> Self {
+            Self::start_with(Server::builder(), addr, created)
+        }
+
+        fn start_with(builder: Server, addr: SocketAddr, created: Arc<Mutex<Vec<Post>>>) -> Self {
+            let (posts, _) = broadcast::channel(16);
+            let (stop, stopped) = watch::channel(());
+            let mut shutdown = stopped.clone();
+            let cursors = Arc::new(Mutex::new(Vec::new()));
+            let subscribed = Arc::new(AtomicUsize::new(0));
+            let chat_room = MockChatRoom {
+                posts: posts.clone(),
+                created,
+                cursors: cursors.clone(),
+                stopped,
+                subscribed: subscribed.clone(),
+            };
+            let failing = Arc::new(Mutex::new(VecDeque::new()));
+            let flaky = failing.clone();
+            let interceptor = move |request: Request<()>| match flaky.lock().unwrap().pop_front() {
+                Some(code) => Err(Status::new(code, "flaky")),
+                None => Ok(request),
+            };
+            let server = tokio::spawn(
+                builder
+                    .add_service(ChatRoomServiceServer::with_interceptor(
+                        chat_room,
+                        interceptor,
+                    ))
+                    .serve_with_shutdown(addr, async move {
+                        let _ = shutdown.changed().await;
+                    }),
+            );
+            RunningServer {
+                posts,
+                cursors,
+                failing,
+                subscribed,
+                stop,
+                server,
+            }
+        }
+
+        async fn stop(self) {
+            drop(self.stop);
+            self.server.await.unwrap().unwrap();
+        }
+    }
+
+    // skips other events, false on timeout
+    async fn wait_event<F: Fn(&ChatRoomEvent) -> bool>(
+        rx_event: &mut mpsc::Receiver<ClientEvent>,
+        predicate: F,
+    ) -> bool {
+        let wait = async {
+            while let Some(event) = rx_event.recv().await {
+                if let ClientEvent::Client(event) = &event {
+                    if predicate(event) {
+                        return true;
+                    }
+                }
+            }
+            false
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait)
+            .await
+            .unwrap_or(false)
+    }
+
+    // the client subscribes asynchronously, so the post is repeated until received
+    async fn deliver_post(
+        server: &RunningServer,
+        rx_event: &mut mpsc::Receiver<ClientEvent>,
+        text: &str,
+    ) {
+        let post = Post {
+            id: 7,
+            chat_id: 3,
+            user_id: 2,
+            text: String::from(text),
+            ..Default::default()
+        };
+        for _ in 0..50 {
+            let _ = server.posts.send(post.clone());
+            let received = tokio::time::timeout(
+                Duration::from_millis(200),
+                wait_event(
+                    rx_event,
+                    |event| matches!(event, ChatRoomEvent::NewPost(post) if post.text == text),
+                ),
+            )
+            .await;
+            if let Ok(true) = received {
+                return;
+            }
+        }
+        panic!("post '{}' has not been received", text);
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_server_restart() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let created = Arc::new(Mutex::new(Vec::new()));
+        let server = RunningServer::start(addr, created.clone());
+
+        let (tx_command, rx_command) = mpsc::channel(16);
+        let (tx_event, mut rx_event) = mpsc::channel(64);
+        let exit_flag = Arc::new(AtomicBool::new(false));
+        let remote = format!("http://{}", addr);
+        let client = tokio::spawn(async move {
+            let mut client =
+                MigchatClient::new(rx_command, RelayConfig::default(), BackoffConfig::default());
+            client.launch(&remote, tx_event, exit_flag).await.is_ok()
+        });
+        tx_command
+            .send(Command::Register(UserInfo {
+                name: String::from("User Name"),
+                short_name: String::from("user"),
+            }))
+            .await
+            .unwrap();
+        assert!(wait_event(&mut rx_event, |e| matches!(e, ChatRoomEvent::Connected)).await);
+        deliver_post(&server, &mut rx_event, "before").await;
+
+        server.stop().await;
+        // the user is told why before the disconnection
+        assert!(
+            wait_event(&mut rx_event, |e| matches!(
+                e,
+                ChatRoomEvent::Error(ClientError::StreamClosed(_))
+            ))
+            .await
+        );
+        assert!(wait_event(&mut rx_event, |e| matches!(e, ChatRoomEvent::Disconnected)).await);
+        // queued while disconnected
+        tx_command
+            .send(Command::Post(Post {
+                user_id: 1,
+                chat_id: 3,
+                text: String::from("queued"),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+
+        let server = RunningServer::start(addr, created.clone());
+        assert!(wait_event(&mut rx_event, |e| matches!(e, ChatRoomEvent::Connected)).await);
+        deliver_post(&server, &mut rx_event, "after").await;
+        // resumed after the post delivered before
+        assert_eq!(*server.cursors.lock().unwrap(), vec!["7"]);
+        // replayed after reconnect
+        for _ in 0..50 {
+            if !created.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(
+            created
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|p| p.text.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["queued"]
+        );
+
+        tx_command.send(Command::Exit).await.unwrap();
+        assert!(client.await.unwrap());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn discouraged_export_refused() {
+        let addr = free_addr();
+        let server = RunningServer::start(addr, Arc::new(Mutex::new(Vec::new())));
+        let (tx_command, rx_command) = mpsc::channel(16);
+        let (tx_event, mut rx_event) = mpsc::channel(64);
+        let remote = format!("http://{}", addr);
+        let client = tokio::spawn(async move {
+            let mut client =
+                MigchatClient::new(rx_command, RelayConfig::default(), BackoffConfig::default());
+            let exit_flag = Arc::new(AtomicBool::new(false));
+            client.launch(&remote, tx_event, exit_flag).await.is_ok()
+        });
+        register(&tx_command).await;
+        assert!(wait_event(&mut rx_event, |e| matches!(e, ChatRoomEvent::Connected)).await);
+        let path = |chat_id| std::env::temp_dir().join(format!("migchat-export-{}.txt", chat_id));
+        let export = |chat_id| {
+            Command::ExportChat(ChatExport {
+                chat_id,
+                path: path(chat_id),
+                format: ExportFormat::Text,
+                watermark: Watermark {
+                    exported_by: String::from("user (1)"),
+                    chat_id,
+                    server: format!("http://{}", addr),
+                    exported: 1_600_000_000,
+                },
+                authors: HashMap::new(),
+            })
+        };
+        let _ = std::fs::remove_file(path(4));
+        tx_command.send(export(4)).await.unwrap();
+        assert!(
+            wait_event(&mut rx_event, |e| matches!(
+                e,
+                ChatRoomEvent::Notice(text) if text.contains("does not allow")
+            ))
+            .await
+        );
+        assert!(!path(4).exists());
+        // the allowed one is written with the watermark
+        tx_command.send(export(3)).await.unwrap();
+        for _ in 0..50 {
+            if path(3).exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let transcript = std::fs::read_to_string(path(3)).unwrap();
+        assert!(transcript.starts_with("# exported by user (1) from chat 3 of http://"));
+        assert!(transcript.contains("\n  history\n"));
+        let _ = std::fs::remove_file(path(3));
+
+        tx_command.send(Command::Exit).await.unwrap();
+        assert!(client.await.unwrap());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn transient_failures_retried() {
+        let addr = free_addr();
+        let created = Arc::new(Mutex::new(Vec::new()));
+        let server = RunningServer::start(addr, created.clone());
+        let (tx_command, rx_command) = mpsc::channel(16);
+        let (tx_event, mut rx_event) = mpsc::channel(64);
+        let remote = format!("http://{}", addr);
+        let client = tokio::spawn(async move {
+            let mut client =
+                MigchatClient::new(rx_command, RelayConfig::default(), BackoffConfig::default());
+            client.set_retry(RetryConfig {
+                attempts: 3,
+                backoff: BackoffConfig {
+                    base: Duration::from_millis(10),
+                    cap: Duration::from_millis(50),
+                    jitter: 0.0,
+                },
+            });
+            let exit_flag = Arc::new(AtomicBool::new(false));
+            client.launch(&remote, tx_event, exit_flag).await.is_ok()
+        });
+        register(&tx_command).await;
+        assert!(wait_event(&mut rx_event, |e| matches!(e, ChatRoomEvent::Connected)).await);
+        // the streams are not to take the failures
+        for _ in 0..50 {
+            if server.subscribed.load(Ordering::Relaxed) == 5 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let post = |text: &str| {
+            Command::Post(Post {
+                user_id: 1,
+                chat_id: 3,
+                text: String::from(text),
+                ..Default::default()
+            })
+        };
+
+        // the third attempt succeeds
+        server.failing.lock().unwrap().extend(vec![
+            tonic::Code::Unavailable,
+            tonic::Code::DeadlineExceeded,
+        ]);
+        tx_command.send(post("retried")).await.unwrap();
+        // the following one waits for it
+        tx_command.send(post("next")).await.unwrap();
+        for _ in 0..50 {
+            if created.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        {
+            let created = created.lock().unwrap();
+            let texts: Vec<&str> = created.iter().map(|p| p.text.as_str()).collect();
+            assert_eq!(texts, vec!["retried", "next"]);
+            assert!(!created[0].idempotency_key.is_empty());
+            assert_ne!(created[0].idempotency_key, created[1].idempotency_key);
+        }
+
+        // every attempt fails, the post is given back
+        server
+            .failing
+            .lock()
+            .unwrap()
+            .extend(vec![tonic::Code::DeadlineExceeded; 3]);
+        tx_command.send(post("failed")).await.unwrap();
+        assert!(
+            wait_event(&mut rx_event, |e| matches!(
+                e,
+                ChatRoomEvent::CommandFailed(Command::Post(post), _) if post.text == "failed"
+            ))
+            .await
+        );
+        assert_eq!(created.lock().unwrap().len(), 2);
+        assert!(server.failing.lock().unwrap().is_empty());
+
+        tx_command.send(Command::Exit).await.unwrap();
+        assert!(client.await.unwrap());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn state_kept_across_runs() {
+        const TEST_STATE: &str = "migchat-test-client-state.json";
+        let _ = std::fs::remove_file(TEST_STATE);
+        let addr = free_addr();
+        let server = RunningServer::start(addr, Arc::new(Mutex::new(Vec::new())));
+        let remote = format!("http://{}", addr);
+        let run = |tx_event: mpsc::Sender<ClientEvent>| {
+            let (tx_command, rx_command) = mpsc::channel(16);
+            let remote = remote.clone();
+            let client = tokio::spawn(async move {
+                let mut client = MigchatClient::new(
+                    rx_command,
+                    RelayConfig::default(),
+                    BackoffConfig::default(),
+                );
+                client.set_state_file(StateFile::new(Path::new(TEST_STATE)));
+                let exit_flag = Arc::new(AtomicBool::new(false));
+                client.launch(&remote, tx_event, exit_flag).await.is_ok()
+            });
+            (tx_command, client)
+        };
+
+        let (tx_event, mut rx_event) = mpsc::channel(64);
+        let (tx_command, client) = run(tx_event);
+        register(&tx_command).await;
+        assert!(wait_event(&mut rx_event, |e| matches!(e, ChatRoomEvent::Connected)).await);
+        deliver_post(&server, &mut rx_event, "first run").await;
+        tx_command.send(Command::Exit).await.unwrap();
+        assert!(client.await.unwrap());
+        let saved = StateFile::new(Path::new(TEST_STATE)).load();
+        assert!(saved.is_for(&remote, "user"));
+        assert_eq!(saved.name, "User Name");
+        assert_eq!(saved.cursors.get(&3), Some(&(7, 0)));
+
+        // the posts got by the first run are not replayed
+        let (tx_event, mut rx_event) = mpsc::channel(64);
+        let (tx_command, client) = run(tx_event);
+        register(&tx_command).await;
+        assert!(wait_event(&mut rx_event, |e| matches!(e, ChatRoomEvent::Connected)).await);
+        for _ in 0..50 {
+            if !server.cursors.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*server.cursors.lock().unwrap(), vec!["7"]);
+        tx_command.send(Command::Exit).await.unwrap();
+        assert!(client.await.unwrap());
+        server.stop().await;
+        let _ = std::fs::remove_file(TEST_STATE);
+    }
+
+    fn free_addr() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn launch_client(
+        addr: SocketAddr,
+        backoff_config: BackoffConfig,
+    ) -> (
+        mpsc::Sender<Command>,
+        mpsc::Receiver<ClientEvent>,
+        JoinHandle<bool>,
+    ) {
+        let (tx_command, rx_command) = mpsc::channel(16);
+        let (tx_event, rx_event) = mpsc::channel(64);
+        let exit_flag = Arc::new(AtomicBool::new(false));
+        let remote = format!("http://{}", addr);
+        let client = tokio::spawn(async move {
+            let mut client = MigchatClient::new(rx_command, RelayConfig::default(), backoff_config);
+            client.launch(&remote, tx_event, exit_flag).await.is_ok()
+        });
+        (tx_command, rx_event, client)
+    }
+
+    async fn register(tx_command: &mpsc::Sender<Command>) {
+        tx_command
+            .send(Command::Register(UserInfo {
+                name: String::from("User Name"),
+                short_name: String::from("user"),
+            }))
+            .await
+            .unwrap();
+    }
+
+    // the reconnection events in the order of arrival, the other events are skipped
+    async fn next_reconnect_events(
+        rx_event: &mut mpsc::Receiver<ClientEvent>,
+        count: usize,
+    ) -> Vec<String> {
+        let mut events = Vec::new();
+        while events.len() < count {
+            let event = tokio::time::timeout(Duration::from_secs(10), rx_event.recv())
+                .await
+                .expect("event in time")
+                .unwrap();
+            match event {
+                ClientEvent::Client(ChatRoomEvent::ReconnectScheduled {
+                    attempt,
+                    next_in_secs,
+                }) => events.push(format!("scheduled {} in {}", attempt, next_in_secs)),
+                ClientEvent::Client(ChatRoomEvent::ReconnectAttempt) => {
+                    events.push(String::from("attempt"))
+                }
+                ClientEvent::Client(ChatRoomEvent::ReconnectFailed { .. }) => {
+                    events.push(String::from("failed"))
+                }
+                ClientEvent::Client(ChatRoomEvent::Connected) => {
+                    events.push(String::from("connected"))
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn reconnect_progress_is_reported() {
+        let addr = free_addr();
+        let backoff_config = BackoffConfig {
+            base: Duration::from_millis(50),
+            cap: Duration::from_millis(100),
+            jitter: 0.0,
+        };
+        let (tx_command, mut rx_event, client) = launch_client(addr, backoff_config);
+        register(&tx_command).await;
+        assert_eq!(
+            next_reconnect_events(&mut rx_event, 8).await,
+            vec![
+                "failed",
+                "scheduled 1 in 1",
+                "attempt",
+                "failed",
+                "scheduled 2 in 1",
+                "attempt",
+                "failed",
+                "scheduled 3 in 1",
+            ]
+        );
+        // the attempts start over once connected
+        let server = RunningServer::start(addr, Arc::new(Mutex::new(Vec::new())));
+        assert!(wait_event(&mut rx_event, |e| matches!(e, ChatRoomEvent::Connected)).await);
+        server.stop().await;
+        assert!(wait_event(&mut rx_event, |e| matches!(e, ChatRoomEvent::Disconnected)).await);
+        assert_eq!(
+            next_reconnect_events(&mut rx_event, 1).await,
+            vec!["scheduled 1 in 1"]
+        );
+
+        tx_command.send(Command::Exit).await.unwrap();
+        assert!(client.await.unwrap());
+    }
+
+    // the CA and the certificate it has signed for localhost with its key, all in PEM
+    fn tls_fixture() -> (String, String, String) {
+        let mut params = rcgen::CertificateParams::new(Vec::new());
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        let params = rcgen::CertificateParams::new(vec![String::from("localhost")]);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        (
+            ca.serialize_pem().unwrap(),
+            cert.serialize_pem_with_signer(&ca).unwrap(),
+            cert.serialize_private_key_pem(),
+        )
+    }
+
+    #[tokio::test]
+    async fn registers_over_tls() {
+        const TEST_CA: &str = "migchat-test-tls-ca.pem";
+        let (ca, cert, key) = tls_fixture();
+        std::fs::write(TEST_CA, ca).unwrap();
+        let addr = free_addr();
+        let tls = tonic::transport::ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+        let builder = Server::builder().tls_config(tls).unwrap();
+        let server = RunningServer::start_with(builder, addr, Arc::new(Mutex::new(Vec::new())));
+
+        let (tx_command, rx_command) = mpsc::channel(16);
+        let (tx_event, mut rx_event) = mpsc::channel(64);
+        let mut client =
+            MigchatClient::new(rx_command, RelayConfig::default(), BackoffConfig::default());
+        // the address is not the name in the certificate
+        client.set_tls(TlsConfig {
+            ca: PathBuf::from(TEST_CA),
+            domain: Some(String::from("localhost")),
+            identity: None,
+        });
+        let remote = format!("https://{}", addr);
+        let client = tokio::spawn(async move {
+            let exit_flag = Arc::new(AtomicBool::new(false));
+            client.launch(&remote, tx_event, exit_flag).await.is_ok()
+        });
+        register(&tx_command).await;
+        assert!(wait_event(&mut rx_event, |e| matches!(e, ChatRoomEvent::Registered(1))).await);
+        tx_command.send(Command::Exit).await.unwrap();
+        assert!(client.await.unwrap());
+        server.stop().await;
+
+        // the client refuses to start with the CA missing
+        let (_tx_command, rx_command) = mpsc::channel(16);
+        let (tx_event, _rx_event) = mpsc::channel::<ClientEvent>(64);
+        let mut client =
+            MigchatClient::new(rx_command, RelayConfig::default(), BackoffConfig::default());
+        client.set_tls(TlsConfig {
+            ca: PathBuf::from("missing-ca.pem"),
+            domain: None,
+            identity: None,
+        });
+        let e = client
+            .launch(
+                &format!("https://{}", addr),
+                tx_event,
+                Arc::new(AtomicBool::new(false)),
+            )
+            .await
+            .unwrap_err();
+        assert!(e.to_string().starts_with("tls_ca missing-ca.pem"), "{}", e);
+        let _ = std::fs::remove_file(TEST_CA);
+    }
+
+    #[tokio::test]
+    async fn reconnect_now_skips_the_delay() {
+        let backoff_config = BackoffConfig {
+            base: Duration::from_secs(60),
+            cap: Duration::from_secs(60),
+            jitter: 0.0,
+        };
+        let (tx_command, mut rx_event, client) = launch_client(free_addr(), backoff_config);
+        register(&tx_command).await;
+        assert_eq!(
+            next_reconnect_events(&mut rx_event, 2).await,
+            vec!["failed", "scheduled 1 in 60"]
+        );
+        tx_command.send(Command::ReconnectNow).await.unwrap();
+        let attempt = tokio::time::timeout(
+            Duration::from_secs(5),
+            next_reconnect_events(&mut rx_event, 1),
+        )
+        .await
+        .expect("attempt long before the delay");
+        assert_eq!(attempt, vec!["attempt"]);
+
+        tx_command.send(Command::Exit).await.unwrap();
+        assert!(client.await.unwrap());
     }
 }
@@ -0,0 +1,177 @@
+use super::{PostId, UserId};
+use log::error;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+use tokio::sync::watch;
+
+// the keys of the user kept, the least recently seen one is forgotten first
+const KEYS_PER_USER: usize = 64;
+
+// the post of the key, the one being created yet tells its id to those waiting
+enum Accepted {
+    Pending(watch::Receiver<Option<PostId>>),
+    Done(PostId),
+}
+
+// what the attempt of the post is to do by its idempotency key
+pub enum Reserved {
+    // the first attempt creates the post, then accepts or releases the key
+    First(watch::Sender<Option<PostId>>),
+    // the post created by the earlier attempt
+    Accepted(PostId),
+    // the earlier attempt is creating the post, the id comes or the channel closes on failure
+    Pending(watch::Receiver<Option<PostId>>),
+}
+
+// the posts accepted by the idempotency keys of their authors, the retried post is not
+// created twice; kept in memory only, the retry coming after the restart creates the post
+// once more
+#[derive(Default)]
+pub struct AcceptedPosts {
+    // the most recently seen last
+    accepted: Mutex<HashMap<UserId, VecDeque<(String, Accepted)>>>,
+}
+
+impl AcceptedPosts {
+    // the key is taken by the first attempt at once, the concurrent retry waits for it
+    pub fn reserve(&self, user_id: UserId, key: &str) -> Reserved {
+        let mut accepted = match self.accepted.lock() {
+            Ok(accepted) => accepted,
+            Err(_) => {
+                error!("failed locking accepted posts");
+                return Reserved::First(watch::channel(None).0);
+            }
+        };
+        let keys = accepted.entry(user_id).or_default();
+        if let Some(idx) = keys.iter().position(|(k, _)| k == key) {
+            if let Some(seen) = keys.remove(idx) {
+                let reserved = match &seen.1 {
+                    Accepted::Pending(rx) => Reserved::Pending(rx.clone()),
+                    Accepted::Done(post_id) => Reserved::Accepted(*post_id),
+                };
+                keys.push_back(seen);
+                return reserved;
+            }
+        }
+        // counted against the kept keys once accepted, a failed attempt forgets no other
+        let (tx, rx) = watch::channel(None);
+        keys.push_back((String::from(key), Accepted::Pending(rx)));
+        Reserved::First(tx)
+    }
+
+    // the post of the first attempt is created, those waiting get its id
+    pub fn accept(
+        &self,
+        user_id: UserId,
+        key: String,
+        first: watch::Sender<Option<PostId>>,
+        post_id: PostId,
+    ) {
+        match self.accepted.lock() {
+            Ok(mut accepted) => {
+                let keys = accepted.entry(user_id).or_default();
+                match keys.iter_mut().find(|(k, _)| *k == key) {
+                    Some(seen) => seen.1 = Accepted::Done(post_id),
+                    None => keys.push_back((key, Accepted::Done(post_id))),
+                }
+                while keys.len() > KEYS_PER_USER {
+                    keys.pop_front();
+                }
+            }
+            Err(_) => error!("failed locking accepted posts"),
+        }
+        let _ = first.send(Some(post_id));
+    }
+
+    // the first attempt has failed, the key is free for the next one
+    pub fn release(&self, user_id: UserId, key: &str, first: watch::Sender<Option<PostId>>) {
+        match self.accepted.lock() {
+            Ok(mut accepted) => {
+                if let Some(keys) = accepted.get_mut(&user_id) {
+                    keys.retain(|(k, seen)| k != key || matches!(seen, Accepted::Done(_)));
+                }
+            }
+            Err(_) => error!("failed locking accepted posts"),
+        }
+        // closed only after the key is free, the waiting ones reserve it again
+        drop(first);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the post created by the earlier attempt, the key stays free otherwise
+    fn find(accepted: &AcceptedPosts, user_id: UserId, key: &str) -> Option<PostId> {
+        match accepted.reserve(user_id, key) {
+            Reserved::Accepted(post_id) => Some(post_id),
+            Reserved::First(first) => {
+                accepted.release(user_id, key, first);
+                None
+            }
+            Reserved::Pending(_) => panic!("key {} is pending", key),
+        }
+    }
+
+    fn accept(accepted: &AcceptedPosts, user_id: UserId, key: &str, post_id: PostId) {
+        match accepted.reserve(user_id, key) {
+            Reserved::First(first) => accepted.accept(user_id, String::from(key), first, post_id),
+            _ => panic!("key {} is taken", key),
+        }
+    }
+
+    #[test]
+    fn least_recent_keys_forgotten() {
+        let accepted = AcceptedPosts::default();
+        assert_eq!(find(&accepted, 1, "a"), None);
+        accept(&accepted, 1, "a", 10);
+        assert_eq!(find(&accepted, 1, "a"), Some(10));
+        // the keys are the user's own
+        assert_eq!(find(&accepted, 2, "a"), None);
+        assert_eq!(find(&accepted, 1, "b"), None);
+        for i in 1..KEYS_PER_USER as u64 {
+            accept(&accepted, 1, &format!("k{}", i), 10 + i);
+        }
+        // seen again, the oldest one is forgotten instead
+        assert_eq!(find(&accepted, 1, "a"), Some(10));
+        accept(&accepted, 1, "b", 100);
+        assert_eq!(find(&accepted, 1, "k1"), None);
+        assert_eq!(find(&accepted, 1, "a"), Some(10));
+        assert_eq!(find(&accepted, 1, "b"), Some(100));
+        // the other users keep theirs
+        accept(&accepted, 2, "a", 200);
+        assert_eq!(find(&accepted, 2, "a"), Some(200));
+        assert_eq!(accepted.accepted.lock().unwrap()[&1].len(), KEYS_PER_USER);
+    }
+
+    #[tokio::test]
+    async fn pending_keys_awaited() {
+        let accepted = AcceptedPosts::default();
+        let first = match accepted.reserve(1, "a") {
+            Reserved::First(first) => first,
+            _ => panic!("key is taken"),
+        };
+        let mut pending = match accepted.reserve(1, "a") {
+            Reserved::Pending(pending) => pending,
+            _ => panic!("key is not pending"),
+        };
+        // the failed attempt frees the key for the one waiting
+        accepted.release(1, "a", first);
+        assert!(pending.changed().await.is_err());
+        let first = match accepted.reserve(1, "a") {
+            Reserved::First(first) => first,
+            _ => panic!("key is not free"),
+        };
+        let mut pending = match accepted.reserve(1, "a") {
+            Reserved::Pending(pending) => pending,
+            _ => panic!("key is not pending"),
+        };
+        accepted.accept(1, String::from("a"), first, 10);
+        assert!(pending.changed().await.is_ok());
+        assert_eq!(*pending.borrow(), Some(10));
+        assert_eq!(find(&accepted, 1, "a"), Some(10));
+    }
+}
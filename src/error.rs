@@ -0,0 +1,112 @@
+use super::InternalError;
+use tonic::Status;
+
+// the failures of the storage and the chat room, the handlers answer with the code
+// of the variant instead of the internal error
+#[derive(Debug, thiserror::Error)]
+pub enum ServerError {
+    // what is missing, e.g. "chat 5"
+    #[error("{0} does not exist")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("storage, {0}")]
+    Storage(#[from] jammdb::Error),
+    #[error("storage, {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("malformed record, {0}")]
+    Decode(#[from] prost::DecodeError),
+    #[error("failed encode record, {0}")]
+    Encode(#[from] prost::EncodeError),
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<String> for ServerError {
+    fn from(text: String) -> Self {
+        ServerError::Other(text)
+    }
+}
+
+impl From<&str> for ServerError {
+    fn from(text: &str) -> Self {
+        ServerError::Other(String::from(text))
+    }
+}
+
+// the errors of the backup and the others not classified yet
+impl From<InternalError> for ServerError {
+    fn from(e: InternalError) -> Self {
+        ServerError::Other(e.to_string())
+    }
+}
+
+impl From<ServerError> for Status {
+    fn from(e: ServerError) -> Self {
+        match e {
+            ServerError::NotFound(_) => Status::not_found(e.to_string()),
+            ServerError::Conflict(_) => Status::already_exists(e.to_string()),
+            // the stored record is damaged
+            ServerError::Decode(_) => Status::data_loss(e.to_string()),
+            _ => Status::internal(e.to_string()),
+        }
+    }
+}
+
+// the item read by its id is to exist
+pub trait Found<T> {
+    fn found(self, what: &str) -> Result<T, ServerError>;
+}
+
+impl<T> Found<T> for Result<Option<T>, ServerError> {
+    fn found(self, what: &str) -> Result<T, ServerError> {
+        self?.ok_or_else(|| ServerError::NotFound(String::from(what)))
+    }
+}
+
+// Ok(false) of the updates and the removals, the item is gone meanwhile
+impl Found<()> for Result<bool, ServerError> {
+    fn found(self, what: &str) -> Result<(), ServerError> {
+        if self? {
+            Ok(())
+        } else {
+            Err(ServerError::NotFound(String::from(what)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{memory::InMemoryStorage, ChatStorage};
+    use crate::Post;
+    use tonic::Code;
+
+    #[test]
+    fn status_codes_mapped() {
+        let storage = InMemoryStorage::new();
+        let status = Status::from(storage.read_post(1, 2).found("post").unwrap_err());
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.message(), "post does not exist");
+        let post = Post {
+            id: 2,
+            chat_id: 1,
+            ..Default::default()
+        };
+        let status = Status::from(storage.update_post(&post).found("post 2").unwrap_err());
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.message(), "post 2 does not exist");
+
+        let status = Status::from(ServerError::Conflict(String::from(
+            "short name 'a' is taken",
+        )));
+        assert_eq!(status.code(), Code::AlreadyExists);
+        assert_eq!(status.message(), "short name 'a' is taken");
+        let status = Status::from(ServerError::from(prost::DecodeError::new("broken")));
+        assert_eq!(status.code(), Code::DataLoss);
+        let status = Status::from(ServerError::from("lock is poisoned"));
+        assert_eq!(status.code(), Code::Internal);
+    }
+}
@@ -0,0 +1,319 @@
+use crate::proto::{ChatId, Post, UserId};
+use chrono::{Local, TimeZone};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+// the posts of a page of the transcript, each one ends with the footer
+const PAGE_POSTS: usize = 100;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExportFormat {
+    // a line per post with the time and the author, the lines of the post indented
+    Text,
+    // a json object per line
+    Json,
+}
+
+impl ExportFormat {
+    // the json by the extension, the text otherwise
+    pub fn of_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") || ext.eq_ignore_ascii_case("jsonl") => {
+                ExportFormat::Json
+            }
+            _ => ExportFormat::Text,
+        }
+    }
+}
+
+// who exported the chat from where and when, a leaked transcript is attributable by it
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct Watermark {
+    pub exported_by: String,
+    pub chat_id: ChatId,
+    // the address of the server the history is fetched from
+    pub server: String,
+    pub exported: u64,
+}
+
+impl Watermark {
+    fn text(&self) -> String {
+        let exported = Local.timestamp(self.exported as i64, 0);
+        format!(
+            "exported by {} from chat {} of {} at {}",
+            self.exported_by,
+            self.chat_id,
+            self.server,
+            exported.format("%d.%m.%Y %H:%M")
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct ExportedHeader<'a> {
+    watermark: &'a Watermark,
+}
+
+#[derive(Serialize)]
+struct ExportedFooter<'a> {
+    page: usize,
+    watermark: &'a Watermark,
+}
+
+#[derive(Serialize)]
+struct ExportedPost<'a> {
+    id: u64,
+    author: &'a str,
+    created: u64,
+    text: &'a str,
+}
+
+// the known name of the user, the name stamped on the post otherwise
+fn author_of<'a>(post: &'a Post, authors: &'a HashMap<UserId, String>) -> &'a str {
+    match authors.get(&post.user_id) {
+        Some(name) => name,
+        None => &post.author_name,
+    }
+}
+
+// the watermark heads the transcript and ends every page of it
+fn write_posts<W: Write>(
+    out: &mut W,
+    format: ExportFormat,
+    watermark: &Watermark,
+    authors: &HashMap<UserId, String>,
+    posts: &[Post],
+) -> io::Result<()> {
+    match format {
+        ExportFormat::Text => writeln!(out, "# {}", watermark.text())?,
+        ExportFormat::Json => {
+            serde_json::to_writer(&mut *out, &ExportedHeader { watermark })?;
+            writeln!(out)?;
+        }
+    }
+    for (idx, page) in posts.chunks(PAGE_POSTS).enumerate() {
+        write_page(out, format, authors, page)?;
+        let page = idx + 1;
+        match format {
+            ExportFormat::Text => writeln!(out, "# page {}, {}", page, watermark.text())?,
+            ExportFormat::Json => {
+                serde_json::to_writer(&mut *out, &ExportedFooter { page, watermark })?;
+                writeln!(out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_page<W: Write>(
+    out: &mut W,
+    format: ExportFormat,
+    authors: &HashMap<UserId, String>,
+    posts: &[Post],
+) -> io::Result<()> {
+    for post in posts {
+        let author = author_of(post, authors);
+        match format {
+            ExportFormat::Text => {
+                let created = Local.timestamp(post.created as i64, 0);
+                write!(out, "{} {}:", created.format("%d.%m.%Y %H:%M"), author)?;
+                for line in post.text.lines() {
+                    write!(out, "\n  {}", line)?;
+                }
+                writeln!(out)?;
+            }
+            ExportFormat::Json => {
+                let exported = ExportedPost {
+                    id: post.id,
+                    author,
+                    created: post.created,
+                    text: &post.text,
+                };
+                serde_json::to_writer(&mut *out, &exported)?;
+                writeln!(out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// the transcript appears complete or not at all, the temporary file is removed on failure
+pub fn write_transcript(
+    path: &Path,
+    format: ExportFormat,
+    watermark: &Watermark,
+    authors: &HashMap<UserId, String>,
+    posts: &[Post],
+) -> io::Result<()> {
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let written = File::create(&tmp)
+        .and_then(|file| {
+            let mut out = BufWriter::new(file);
+            write_posts(&mut out, format, watermark, authors, posts)?;
+            out.into_inner()?.sync_all()
+        })
+        .and_then(|()| fs::rename(&tmp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_FILE: &str = "migchat-test-export.json";
+    const TEST_DIR: &str = "migchat-test-export-dir";
+
+    fn post(id: u64, user_id: UserId, text: &str) -> Post {
+        Post {
+            id,
+            user_id,
+            text: String::from(text),
+            created: 1_600_000_000 + id,
+            author_name: String::from("former"),
+            ..Default::default()
+        }
+    }
+
+    fn watermark() -> Watermark {
+        Watermark {
+            exported_by: String::from("alice (1)"),
+            chat_id: 10,
+            server: String::from("http://[::1]:50051"),
+            exported: 1_600_001_000,
+        }
+    }
+
+    #[test]
+    fn transcript_formats() {
+        assert_eq!(
+            ExportFormat::of_path(Path::new("chat.JSON")),
+            ExportFormat::Json
+        );
+        assert_eq!(
+            ExportFormat::of_path(Path::new("chat.jsonl")),
+            ExportFormat::Json
+        );
+        assert_eq!(
+            ExportFormat::of_path(Path::new("chat.txt")),
+            ExportFormat::Text
+        );
+        assert_eq!(ExportFormat::of_path(Path::new("chat")), ExportFormat::Text);
+        let mut authors = HashMap::new();
+        authors.insert(1, String::from("alice"));
+        let posts = vec![post(1, 1, "hello\nworld"), post(2, 2, "bye")];
+        let watermark = watermark();
+        let mut text = Vec::new();
+        write_posts(&mut text, ExportFormat::Text, &watermark, &authors, &posts).unwrap();
+        let time = |id| {
+            let created = Local.timestamp(1_600_000_000 + id as i64, 0);
+            created.format("%d.%m.%Y %H:%M").to_string()
+        };
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            format!(
+                "# {mark}\n{} alice:\n  hello\n  world\n{} former:\n  bye\n# page 1, {mark}\n",
+                time(1),
+                time(2),
+                mark = watermark.text()
+            )
+        );
+        let mut json = Vec::new();
+        write_posts(&mut json, ExportFormat::Json, &watermark, &authors, &posts).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(json)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["watermark"]["chat_id"], 10);
+        assert_eq!(lines[1]["id"], 1);
+        assert_eq!(lines[1]["author"], "alice");
+        assert_eq!(lines[1]["created"], 1_600_000_001u64);
+        assert_eq!(lines[1]["text"], "hello\nworld");
+        assert_eq!(lines[2]["author"], "former");
+        assert_eq!(lines[3]["page"], 1);
+    }
+
+    #[test]
+    fn watermarked_pages() {
+        let watermark = watermark();
+        let text = watermark.text();
+        assert!(text.starts_with("exported by alice (1) from chat 10 of http://[::1]:50051 at "));
+        let posts: Vec<Post> = (1..=(PAGE_POSTS * 2 + 1) as u64)
+            .map(|id| post(id, 1, "text"))
+            .collect();
+        let authors = HashMap::new();
+        let mut out = Vec::new();
+        write_posts(&mut out, ExportFormat::Text, &watermark, &authors, &posts).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let marks: Vec<&str> = out.lines().filter(|line| line.starts_with('#')).collect();
+        assert_eq!(
+            marks,
+            vec![
+                format!("# {}", text),
+                format!("# page 1, {}", text),
+                format!("# page 2, {}", text),
+                format!("# page 3, {}", text),
+            ]
+        );
+        // the footer ends the page
+        assert_eq!(out.lines().last(), Some(marks[3]));
+        let mut json = Vec::new();
+        write_posts(&mut json, ExportFormat::Json, &watermark, &authors, &posts).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(json)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), posts.len() + 4);
+        let header = &lines[0]["watermark"];
+        assert_eq!(header["exported_by"], "alice (1)");
+        assert_eq!(header["chat_id"], 10);
+        assert_eq!(header["server"], "http://[::1]:50051");
+        assert_eq!(header["exported"], 1_600_001_000u64);
+        assert_eq!(lines[PAGE_POSTS + 1]["page"], 1);
+        assert_eq!(lines[PAGE_POSTS + 1]["watermark"], *header);
+        assert_eq!(lines[posts.len() + 3]["page"], 3);
+    }
+
+    #[test]
+    fn failed_export_leaves_nothing() {
+        let posts = vec![post(1, 1, "hello")];
+        let authors = HashMap::new();
+        let _ = fs::remove_file(TEST_FILE);
+        write_transcript(
+            Path::new(TEST_FILE),
+            ExportFormat::Json,
+            &watermark(),
+            &authors,
+            &posts,
+        )
+        .unwrap();
+        assert!(Path::new(TEST_FILE).exists());
+        assert!(!Path::new(&format!("{}.tmp", TEST_FILE)).exists());
+        let _ = fs::remove_file(TEST_FILE);
+        // the directory is not replaced by the transcript
+        let _ = fs::create_dir(TEST_DIR);
+        let res = write_transcript(
+            Path::new(TEST_DIR),
+            ExportFormat::Text,
+            &watermark(),
+            &authors,
+            &posts,
+        );
+        assert!(res.is_err());
+        assert!(!Path::new(&format!("{}.tmp", TEST_DIR)).exists());
+        let _ = fs::remove_dir(TEST_DIR);
+    }
+}
@@ -0,0 +1,336 @@
+use crate::proto::PostKind;
+use crate::storage::ChatStorage;
+use crate::{Chat, ChatId, InternalError, Post, PostId, User, UserId};
+use chrono::Utc;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use std::ops::Range;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const WORDS: [&str; 16] = [
+    "build", "release", "review", "merge", "nightly", "lunch", "deploy", "rollback", "bug", "fix",
+    "test", "docs", "meeting", "later", "done", "thanks",
+];
+
+pub fn now() -> u64 {
+    Utc::now().timestamp() as u64
+}
+
+pub fn days_ago(days: u64) -> u64 {
+    now().saturating_sub(days * SECS_PER_DAY)
+}
+
+// the chats added at once are alike, their sizes vary within the ranges
+#[derive(Clone, Debug)]
+pub struct ChatShape {
+    members: Range<usize>,
+    posts: Range<usize>,
+    // unix secs, the posts of the chat are spread over it in order
+    time_range: Range<u64>,
+}
+
+impl ChatShape {
+    fn new(time_range: Range<u64>) -> Self {
+        ChatShape {
+            members: 2..5,
+            posts: 10..50,
+            time_range,
+        }
+    }
+
+    pub fn members(mut self, members: Range<usize>) -> Self {
+        self.members = members;
+        self
+    }
+
+    pub fn posts(mut self, posts: Range<usize>) -> Self {
+        self.posts = posts;
+        self
+    }
+
+    pub fn time_range(mut self, time_range: Range<u64>) -> Self {
+        self.time_range = time_range;
+        self
+    }
+}
+
+// the ids of the seeded data in the order of adding
+#[derive(Debug, PartialEq)]
+pub struct Seeded {
+    pub users: Vec<UserId>,
+    pub chats: Vec<ChatId>,
+    pub posts: usize,
+}
+
+// Seeds the storage with users, chats and posts referring to each other consistently, e.g.
+// `Fixture::new(1).users(20).chats(5, |c| c.members(3..8).posts(100..500))`.
+// The same seed gives the same data as long as the time ranges are the same.
+pub struct Fixture {
+    seed: u64,
+    users: usize,
+    chats: Vec<(usize, ChatShape)>,
+    dialogs: usize,
+    // the last month by default
+    time_range: Range<u64>,
+}
+
+impl Fixture {
+    pub fn new(seed: u64) -> Self {
+        Fixture {
+            seed,
+            users: 0,
+            chats: Vec::new(),
+            dialogs: 0,
+            time_range: days_ago(30)..now(),
+        }
+    }
+
+    // the chats added after it take the range unless they have their own,
+    // the users are created at its start
+    pub fn time_range(mut self, time_range: Range<u64>) -> Self {
+        self.time_range = time_range;
+        self
+    }
+
+    pub fn users(mut self, count: usize) -> Self {
+        self.users = count;
+        self
+    }
+
+    pub fn chats<F: FnOnce(ChatShape) -> ChatShape>(mut self, count: usize, shape: F) -> Self {
+        let shape = shape(ChatShape::new(self.time_range.clone()));
+        self.chats.push((count, shape));
+        self
+    }
+
+    // chats of two members without description, added after the others
+    pub fn dialogs(mut self, count: usize) -> Self {
+        self.dialogs = count;
+        self
+    }
+
+    pub fn seed<S: ChatStorage>(&self, storage: &S) -> Result<Seeded, InternalError> {
+        if self.users == 0 && (self.dialogs > 0 || !self.chats.is_empty()) {
+            return Err("chats need users".into());
+        }
+        if self.users < 2 && self.dialogs > 0 {
+            return Err("dialogs need two users".into());
+        }
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut seeded = Seeded {
+            users: Vec::with_capacity(self.users),
+            chats: Vec::new(),
+            posts: 0,
+        };
+        let created = self
+            .chats
+            .iter()
+            .map(|(_, shape)| shape.time_range.start)
+            .fold(self.time_range.start, u64::min);
+        for i in 0..self.users {
+            let user = User {
+                id: new_id(&mut rng, |id| seeded.users.contains(&id)),
+                name: format!("User {}", i),
+                short_name: format!("user{}", i),
+                created,
+            };
+            storage.write_user(user.id, &user)?;
+            seeded.users.push(user.id);
+        }
+        let dialog = ChatShape::new(self.time_range.clone()).members(2..3);
+        let regular: usize = self.chats.iter().map(|(count, _)| count).sum();
+        let shapes = self
+            .chats
+            .iter()
+            .flat_map(|(count, shape)| std::iter::repeat(shape).take(*count))
+            .chain(std::iter::repeat(&dialog).take(self.dialogs));
+        for (i, shape) in shapes.enumerate() {
+            let count = pick(&mut rng, &shape.members).max(1).min(self.users);
+            let mut users: Vec<UserId> = seeded
+                .users
+                .choose_multiple(&mut rng, count)
+                .copied()
+                .collect();
+            users.sort_unstable();
+            let is_dialog = i >= regular;
+            let owner_id = users.first().copied().unwrap_or_default();
+            let chat = Chat {
+                id: new_id(&mut rng, |id| seeded.chats.contains(&id)),
+                permanent: true,
+                description: if is_dialog {
+                    String::new()
+                } else {
+                    format!("chat {}", i)
+                },
+                users,
+                created: shape.time_range.start,
+                owner_id,
+                export_discouraged: false,
+            };
+            storage.write_chat(chat.id, &chat)?;
+            seeded.chats.push(chat.id);
+            seeded.posts += seed_posts(storage, &mut rng, &chat, shape)?;
+        }
+        Ok(seeded)
+    }
+}
+
+// the posts of the members in the order of their creation
+fn seed_posts<S: ChatStorage>(
+    storage: &S,
+    rng: &mut StdRng,
+    chat: &Chat,
+    shape: &ChatShape,
+) -> Result<usize, InternalError> {
+    let count = pick(rng, &shape.posts);
+    let mut times: Vec<u64> = (0..count).map(|_| pick(rng, &shape.time_range)).collect();
+    times.sort_unstable();
+    for created in times {
+        let words = rng.gen_range(1..=8);
+        let mut post = Post {
+            id: rng.gen(),
+            chat_id: chat.id,
+            user_id: chat.users.choose(rng).copied().unwrap_or_default(),
+            text: (0..words)
+                .map(|_| WORDS.choose(rng).copied().unwrap_or_default())
+                .collect::<Vec<&str>>()
+                .join(" "),
+            attachments: Vec::new(),
+            created,
+            author_name: String::new(),
+            reactions: Vec::new(),
+            reply_to_post_id: 0,
+            forwarded_from: None,
+            idempotency_key: String::new(),
+            seq: 0,
+            kind: PostKind::Regular as i32,
+        };
+        // the taken id is drawn again
+        while post.id == crate::proto::NOT_POST_ID || !storage.write_post(&post)? {
+            post.id = rng.gen::<PostId>();
+        }
+    }
+    Ok(count)
+}
+
+// the empty range gives its start
+fn pick<T>(rng: &mut StdRng, range: &Range<T>) -> T
+where
+    T: Copy + PartialOrd + rand::distributions::uniform::SampleUniform,
+{
+    if range.start < range.end {
+        rng.gen_range(range.clone())
+    } else {
+        range.start
+    }
+}
+
+fn new_id<F: Fn(u64) -> bool>(rng: &mut StdRng, taken: F) -> u64 {
+    loop {
+        let id = rng.gen();
+        if id != 0 && !taken(id) {
+            return id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{memory::InMemoryStorage, Storage};
+
+    const TEST_DB: &str = "migchat-test-fixtures.db";
+    const START: u64 = 1_600_000_000;
+
+    fn fixture(seed: u64) -> Fixture {
+        Fixture::new(seed)
+            .time_range(START..START + 30 * SECS_PER_DAY)
+            .users(20)
+            .chats(5, |c| c.members(3..8).posts(100..200))
+            .chats(2, |c| {
+                c.members(20..30).posts(0..1).time_range(START..START)
+            })
+            .dialogs(3)
+    }
+
+    fn contents<S: ChatStorage>(storage: &S, seeded: &Seeded) -> (Vec<User>, Vec<Chat>, Vec<Post>) {
+        let mut posts = Vec::new();
+        for chat_id in &seeded.chats {
+            let count = storage.chat_posts_count(*chat_id).unwrap();
+            posts.extend(storage.read_chat_posts(*chat_id, 0, count).unwrap());
+        }
+        let mut users = storage.read_all_users().unwrap();
+        users.sort_by_key(|u| u.id);
+        let mut chats = storage.read_all_chats().unwrap();
+        chats.sort_by_key(|c| c.id);
+        (users, chats, posts)
+    }
+
+    #[test]
+    fn same_seed_same_data() {
+        let (first, second) = (InMemoryStorage::new(), InMemoryStorage::new());
+        let seeded = fixture(1528).seed(&first).unwrap();
+        assert_eq!(fixture(1528).seed(&second).unwrap(), seeded);
+        assert_eq!(contents(&first, &seeded), contents(&second, &seeded));
+        let other = InMemoryStorage::new();
+        assert_ne!(fixture(1529).seed(&other).unwrap().users, seeded.users);
+    }
+
+    #[test]
+    fn cardinalities_honored() {
+        let storage = InMemoryStorage::new();
+        let seeded = fixture(1).seed(&storage).unwrap();
+        let (users, chats, posts) = contents(&storage, &seeded);
+        assert_eq!(users.len(), 20);
+        assert_eq!(seeded.chats.len(), 10);
+        assert_eq!(chats.len(), 10);
+        assert_eq!(posts.len(), seeded.posts);
+        for (i, chat_id) in seeded.chats.iter().enumerate() {
+            let chat = storage.read_chat(*chat_id).unwrap().unwrap();
+            let count = storage.chat_posts_count(*chat_id).unwrap();
+            match i {
+                0..=4 => {
+                    assert!((3..8).contains(&chat.users.len()));
+                    assert!((100..200).contains(&count));
+                }
+                // no more members than users
+                5..=6 => {
+                    assert_eq!(chat.users.len(), 20);
+                    assert_eq!(count, 0);
+                }
+                _ => {
+                    assert_eq!(chat.users.len(), 2);
+                    assert!(chat.description.is_empty());
+                }
+            }
+        }
+        assert!(Fixture::new(1).dialogs(1).seed(&storage).is_err());
+    }
+
+    #[test]
+    fn data_consistent() {
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let storage = Storage::new(TEST_DB).unwrap();
+            let seeded = fixture(7).seed(&storage).unwrap();
+            for chat_id in &seeded.chats {
+                assert_eq!(storage.verify_chat_posts(*chat_id, false).unwrap(), 0);
+                let chat = storage.read_chat(*chat_id).unwrap().unwrap();
+                for user_id in &chat.users {
+                    assert!(storage.read_user(*user_id).unwrap().is_some());
+                    assert!(storage.read_user_chats(*user_id).unwrap().contains(chat_id));
+                }
+                let count = storage.chat_posts_count(*chat_id).unwrap();
+                let posts = storage.read_chat_posts(*chat_id, 0, count).unwrap();
+                assert!(posts.windows(2).all(|w| w[0].created <= w[1].created));
+                for post in &posts {
+                    assert!(chat.users.contains(&post.user_id));
+                    assert_eq!(
+                        storage.locate_post(post.id).unwrap().map(|(id, _)| id),
+                        Some(*chat_id)
+                    );
+                }
+            }
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::proto::ChatId;
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Range,
+};
+
+// the gaps of a chat kept waiting for their posts, the eldest one is given up first
+const MAX_GAPS_PER_CHAT: usize = 32;
+
+#[derive(Debug, PartialEq)]
+pub enum Seen {
+    // the next one of its chat, the first one or an unnumbered one
+    InOrder,
+    // at or below the highest seq seen and not missing, e.g. the post again with its reactions
+    Repeated,
+    // fills a part of the gap
+    Late,
+    // the seqs skipped right before the post
+    Gap(Range<u64>),
+}
+
+// the highest seq seen in every chat and the seqs skipped below it; the posts removed on
+// the server leave gaps as well, those are never filled
+#[derive(Default)]
+pub struct GapDetector {
+    highest: HashMap<ChatId, u64>,
+    // ascending and not overlapping
+    missing: HashMap<ChatId, VecDeque<Range<u64>>>,
+}
+
+impl GapDetector {
+    pub fn note(&mut self, chat_id: ChatId, seq: u64) -> Seen {
+        if seq == 0 {
+            return Seen::InOrder;
+        }
+        let highest = match self.highest.get_mut(&chat_id) {
+            Some(highest) => highest,
+            None => {
+                self.highest.insert(chat_id, seq);
+                return Seen::InOrder;
+            }
+        };
+        if seq > *highest {
+            let skipped = *highest + 1..seq;
+            *highest = seq;
+            if skipped.is_empty() {
+                return Seen::InOrder;
+            }
+            let gaps = self.missing.entry(chat_id).or_default();
+            gaps.push_back(skipped.clone());
+            if gaps.len() > MAX_GAPS_PER_CHAT {
+                gaps.pop_front();
+            }
+            return Seen::Gap(skipped);
+        }
+        let gaps = match self.missing.get_mut(&chat_id) {
+            Some(gaps) => gaps,
+            None => return Seen::Repeated,
+        };
+        match gaps.iter().position(|gap| gap.contains(&seq)) {
+            Some(idx) => {
+                let gap = gaps.remove(idx).unwrap_or_default();
+                // the rest of the gap on both sides, in order
+                for rest in [seq + 1..gap.end, gap.start..seq].iter() {
+                    if !rest.is_empty() {
+                        gaps.insert(idx, rest.clone());
+                    }
+                }
+                Seen::Late
+            }
+            None => Seen::Repeated,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaps_detected() {
+        let mut gaps = GapDetector::default();
+        assert_eq!(gaps.note(1, 5), Seen::InOrder);
+        assert_eq!(gaps.note(1, 6), Seen::InOrder);
+        // the chats are numbered apart
+        assert_eq!(gaps.note(2, 100), Seen::InOrder);
+        assert_eq!(gaps.note(1, 10), Seen::Gap(7..10));
+        assert_eq!(gaps.note(2, 102), Seen::Gap(101..102));
+        // reordered, the gap is filled from the middle
+        assert_eq!(gaps.note(1, 8), Seen::Late);
+        assert_eq!(gaps.missing[&1], vec![7..8, 9..10]);
+        assert_eq!(gaps.note(1, 9), Seen::Late);
+        assert_eq!(gaps.note(1, 7), Seen::Late);
+        assert!(gaps.missing[&1].is_empty());
+        // duplicates, the backfill of the posts arrived meanwhile included
+        assert_eq!(gaps.note(1, 8), Seen::Repeated);
+        assert_eq!(gaps.note(1, 10), Seen::Repeated);
+        assert_eq!(gaps.note(1, 3), Seen::Repeated);
+        assert_eq!(gaps.note(2, 101), Seen::Late);
+        assert_eq!(gaps.note(2, 101), Seen::Repeated);
+        // the unnumbered ones are never missed
+        assert_eq!(gaps.note(1, 0), Seen::InOrder);
+        assert_eq!(gaps.note(1, 11), Seen::InOrder);
+    }
+
+    #[test]
+    fn eldest_gaps_given_up() {
+        let mut gaps = GapDetector::default();
+        gaps.note(1, 1);
+        for i in 1..=MAX_GAPS_PER_CHAT as u64 + 1 {
+            assert_eq!(gaps.note(1, 2 * i + 1), Seen::Gap(2 * i..2 * i + 1));
+        }
+        assert_eq!(gaps.missing[&1].len(), MAX_GAPS_PER_CHAT);
+        // the eldest one is taken for seen
+        assert_eq!(gaps.note(1, 2), Seen::Repeated);
+        assert_eq!(gaps.note(1, 4), Seen::Late);
+    }
+}
@@ -0,0 +1,449 @@
+use crate::chat_spec::{ChatSpec, ChatSpecError};
+use crate::client_service::{
+    ChatRoomEvent, ClientError, ClientEvent, Command, InvitationPolicy, MigchatClient,
+};
+use crate::proto::{ChatId, ChatInfo, Post, UserId, UserInfo, NOT_POST_ID, NOT_USER_ID};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+// the post is to be accepted within, the reconnections included
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+// the service logs out on exit unless the server is gone
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the chat to post into, the id is taken as is
+#[derive(Debug, PartialEq)]
+pub enum Target {
+    Id(ChatId),
+    Spec(ChatSpec),
+}
+
+impl Target {
+    pub fn parse(s: &str) -> Result<Self, ChatSpecError> {
+        match s.trim().parse::<ChatId>() {
+            Ok(chat_id) => Ok(Target::Id(chat_id)),
+            Err(_) => s.parse::<ChatSpec>().map(Target::Spec),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Stage {
+    Registering,
+    // the logins of the spec are not all known yet
+    Resolving(String),
+    Creating(ChatInfo),
+    Posting(ChatId),
+}
+
+// what the one-shot send does on the event
+pub enum Step {
+    Wait,
+    Send(Command),
+    Done,
+    Failed(String),
+}
+
+// registers, finds or creates the chat and posts once, driven by the events of the service
+pub struct OneShot {
+    target: Target,
+    text: String,
+    user_id: UserId,
+    // the logins told by the users stream so far
+    users: HashMap<String, UserId>,
+    stage: Stage,
+}
+
+impl OneShot {
+    pub fn new(target: Target, text: String) -> Self {
+        OneShot {
+            target,
+            text,
+            user_id: NOT_USER_ID,
+            users: HashMap::new(),
+            stage: Stage::Registering,
+        }
+    }
+
+    fn post(&mut self, chat_id: ChatId) -> Step {
+        self.stage = Stage::Posting(chat_id);
+        Step::Send(Command::Post(Post {
+            id: NOT_POST_ID,
+            user_id: self.user_id,
+            chat_id,
+            text: self.text.clone(),
+            ..Default::default()
+        }))
+    }
+
+    fn resolve(&mut self) -> Step {
+        let spec = match &self.target {
+            Target::Id(chat_id) => {
+                let chat_id = *chat_id;
+                return self.post(chat_id);
+            }
+            Target::Spec(spec) => spec,
+        };
+        let users = &self.users;
+        match spec.resolve(self.user_id, |login| users.get(login).copied()) {
+            Ok(info) => {
+                self.stage = Stage::Creating(info.clone());
+                Step::Send(Command::CreateChat(info))
+            }
+            // the users are told one by one, the others are not waited for
+            Err(e) => match spec.resolve(self.user_id, |_| Some(0)) {
+                Ok(_) => {
+                    self.stage = Stage::Resolving(e.to_string());
+                    Step::Wait
+                }
+                Err(e) => Step::Failed(e.to_string()),
+            },
+        }
+    }
+
+    pub fn on_event(&mut self, event: ChatRoomEvent) -> Step {
+        match event {
+            ChatRoomEvent::Registered(user_id) if self.stage == Stage::Registering => {
+                self.user_id = user_id;
+                self.resolve()
+            }
+            ChatRoomEvent::UserInfo(user) => {
+                self.users.insert(user.short_name, user.id);
+                match self.stage {
+                    Stage::Resolving(_) => self.resolve(),
+                    _ => Step::Wait,
+                }
+            }
+            ChatRoomEvent::ChatUpdated(chat, _) => match &self.stage {
+                // the same description and members make the same chat
+                Stage::Creating(info)
+                    if chat.description == info.description
+                        && info.desired_users.iter().all(|u| chat.users.contains(u)) =>
+                {
+                    self.post(chat.id)
+                }
+                _ => Step::Wait,
+            },
+            ChatRoomEvent::Notice(text) if matches!(self.stage, Stage::Creating(_)) => {
+                Step::Failed(text)
+            }
+            ChatRoomEvent::PostAccepted(chat_id, _) if self.stage == Stage::Posting(chat_id) => {
+                Step::Done
+            }
+            ChatRoomEvent::PostFailed(chat_id, text) if self.stage == Stage::Posting(chat_id) => {
+                Step::Failed(text)
+            }
+            // the chat or the post has failed every attempt
+            ChatRoomEvent::CommandFailed(_, text) => Step::Failed(text),
+            ChatRoomEvent::ReconnectFailed { error } => {
+                Step::Failed(format!("failed to connect, {}", error))
+            }
+            _ => Step::Wait,
+        }
+    }
+
+    // what has not been done in time
+    pub fn timed_out(&self) -> String {
+        match &self.stage {
+            Stage::Registering => String::from("not registered in time"),
+            Stage::Resolving(e) => e.clone(),
+            Stage::Creating(info) => format!("chat '{}' is not created in time", info.description),
+            Stage::Posting(chat_id) => format!("post to chat {} is not accepted in time", chat_id),
+        }
+    }
+}
+
+// the service registers the user, the events are read by the caller
+fn start(
+    mut client: MigchatClient,
+    remote: String,
+) -> (
+    mpsc::Receiver<ClientEvent>,
+    JoinHandle<Result<(), ClientError>>,
+) {
+    let (tx_event, rx_event) = mpsc::channel(64);
+    let exit_flag = Arc::new(AtomicBool::new(false));
+    let service =
+        tokio::spawn(async move { client.launch(remote.as_str(), tx_event, exit_flag).await });
+    (rx_event, service)
+}
+
+// the reason the service has stopped on its own
+async fn stopped(service: JoinHandle<Result<(), ClientError>>) -> String {
+    match service.await {
+        Ok(Ok(())) => String::from("chat service stopped"),
+        Ok(Err(e)) => e.to_string(),
+        Err(e) => format!("chat service failed, {}", e),
+    }
+}
+
+// the service logs out, the events are not read anymore
+async fn exit(tx_command: &mpsc::Sender<Command>, service: JoinHandle<Result<(), ClientError>>) {
+    if tx_command.send(Command::Exit).await.is_ok() {
+        let _ = tokio::time::timeout(EXIT_TIMEOUT, service).await;
+    }
+}
+
+// sends the post and waits for the server to accept it
+pub async fn send(
+    client: MigchatClient,
+    tx_command: mpsc::Sender<Command>,
+    remote: String,
+    user_info: UserInfo,
+    mut one_shot: OneShot,
+) -> Result<(), String> {
+    let (mut rx_event, service) = start(client, remote);
+    tx_command
+        .send(Command::Register(user_info))
+        .await
+        .map_err(|_| String::from("chat service stopped"))?;
+    let deadline = tokio::time::Instant::now() + SEND_TIMEOUT;
+    let sent = loop {
+        let event = match tokio::time::timeout_at(deadline, rx_event.recv()).await {
+            Ok(Some(ClientEvent::Client(event))) => event,
+            Ok(Some(ClientEvent::Exit)) => break Err(String::from("chat service stopped")),
+            Ok(None) => return Err(stopped(service).await),
+            Err(_) => break Err(one_shot.timed_out()),
+        };
+        match one_shot.on_event(event) {
+            Step::Wait => {}
+            Step::Send(command) => {
+                if tx_command.send(command).await.is_err() {
+                    drop(rx_event);
+                    return Err(stopped(service).await);
+                }
+            }
+            Step::Done => break Ok(()),
+            Step::Failed(e) => break Err(e),
+        }
+    };
+    drop(rx_event);
+    exit(&tx_command, service).await;
+    sent
+}
+
+// a json object per line
+#[derive(Serialize)]
+struct ListenedPost<'a> {
+    chat_id: ChatId,
+    id: u64,
+    author: &'a str,
+    created: u64,
+    text: &'a str,
+}
+
+fn write_post<W: Write>(
+    out: &mut W,
+    authors: &HashMap<UserId, String>,
+    post: &Post,
+) -> io::Result<()> {
+    let listened = ListenedPost {
+        chat_id: post.chat_id,
+        id: post.id,
+        author: authors.get(&post.user_id).unwrap_or(&post.author_name),
+        created: post.created,
+        text: &post.text,
+    };
+    serde_json::to_writer(&mut *out, &listened)?;
+    writeln!(out)?;
+    // the reader of the pipe gets the post at once
+    out.flush()
+}
+
+// prints the posts received until Ctrl+C, the connection is restored if lost;
+// the chats entered by the policy are told on stderr not to mix with the posts
+pub async fn listen(
+    client: MigchatClient,
+    tx_command: mpsc::Sender<Command>,
+    remote: String,
+    user_info: UserInfo,
+    policy: InvitationPolicy,
+) -> Result<(), String> {
+    let (mut rx_event, service) = start(client, remote);
+    tx_command
+        .send(Command::Register(user_info))
+        .await
+        .map_err(|_| String::from("chat service stopped"))?;
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let stdout = io::stdout();
+    let mut authors = HashMap::new();
+    let mut user_id = NOT_USER_ID;
+    let listened = loop {
+        let event = tokio::select! {
+            _ = &mut ctrl_c => break Ok(()),
+            event = rx_event.recv() => event,
+        };
+        match event {
+            Some(ClientEvent::Client(ChatRoomEvent::Registered(id))) => user_id = id,
+            Some(ClientEvent::Client(ChatRoomEvent::Invitation(invitation))) => {
+                if policy.answer(user_id, &invitation).is_some() {
+                    eprintln!("entered chat {}", invitation.chat_id);
+                }
+            }
+            Some(ClientEvent::Client(ChatRoomEvent::UserInfo(user))) => {
+                authors.insert(user.id, user.name);
+            }
+            Some(ClientEvent::Client(ChatRoomEvent::NewPost(post))) => {
+                // the reader is gone, e.g. `head` has got enough
+                if let Err(e) = write_post(&mut stdout.lock(), &authors, &post) {
+                    break if e.kind() == io::ErrorKind::BrokenPipe {
+                        Ok(())
+                    } else {
+                        Err(format!("failed writing post, {}", e))
+                    };
+                }
+            }
+            Some(ClientEvent::Client(_)) => {}
+            Some(ClientEvent::Exit) => break Err(String::from("chat service stopped")),
+            None => return Err(stopped(service).await),
+        }
+    };
+    drop(rx_event);
+    exit(&tx_command, service).await;
+    listened
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{Chat, User};
+
+    fn user(id: UserId, short_name: &str) -> ChatRoomEvent {
+        ChatRoomEvent::UserInfo(User {
+            id,
+            name: short_name.to_uppercase(),
+            short_name: String::from(short_name),
+            ..Default::default()
+        })
+    }
+
+    fn failed(step: Step) -> String {
+        match step {
+            Step::Failed(e) => e,
+            _ => panic!("failure expected"),
+        }
+    }
+
+    fn sent_post(step: Step) -> Post {
+        match step {
+            Step::Send(Command::Post(post)) => post,
+            _ => panic!("post expected"),
+        }
+    }
+
+    #[test]
+    fn post_sent_by_id() {
+        assert_eq!(Target::parse(" 42 ").unwrap(), Target::Id(42));
+        let mut one_shot = OneShot::new(Target::parse("42").unwrap(), String::from("hi"));
+        assert!(matches!(
+            one_shot.on_event(ChatRoomEvent::Connected),
+            Step::Wait
+        ));
+        let post = sent_post(one_shot.on_event(ChatRoomEvent::Registered(5)));
+        assert_eq!(
+            (post.chat_id, post.user_id, post.text.as_str()),
+            (42, 5, "hi")
+        );
+        // the posts of the others are not the one sent
+        assert!(matches!(
+            one_shot.on_event(ChatRoomEvent::PostAccepted(41, 1)),
+            Step::Wait
+        ));
+        let refused = ChatRoomEvent::PostFailed(42, String::from("post: not permitted"));
+        assert_eq!(failed(one_shot.on_event(refused)), "post: not permitted");
+        assert_eq!(
+            one_shot.timed_out(),
+            "post to chat 42 is not accepted in time"
+        );
+    }
+
+    #[test]
+    fn chat_created_before_post() {
+        let target = Target::parse("ops @bob").unwrap();
+        let mut one_shot = OneShot::new(target, String::from("deployed"));
+        // bob is not known yet
+        assert!(matches!(
+            one_shot.on_event(ChatRoomEvent::Registered(1)),
+            Step::Wait
+        ));
+        assert_eq!(one_shot.timed_out(), "unknown user: '@bob'");
+        assert!(matches!(one_shot.on_event(user(1, "alice")), Step::Wait));
+        let info = match one_shot.on_event(user(2, "bob")) {
+            Step::Send(Command::CreateChat(info)) => info,
+            _ => panic!("chat creation expected"),
+        };
+        assert_eq!(
+            (info.user_id, info.description.as_str(), info.desired_users),
+            (1, "ops", vec![2])
+        );
+        // the chats of the snapshot are not the one created
+        let chat = |id, description: &str, users| {
+            ChatRoomEvent::ChatUpdated(
+                Chat {
+                    id,
+                    description: String::from(description),
+                    users,
+                    ..Default::default()
+                },
+                0,
+            )
+        };
+        assert!(matches!(
+            one_shot.on_event(chat(7, "ops", vec![1])),
+            Step::Wait
+        ));
+        assert!(matches!(
+            one_shot.on_event(chat(8, "dev", vec![1, 2])),
+            Step::Wait
+        ));
+        let post = sent_post(one_shot.on_event(chat(9, "ops", vec![1, 2])));
+        assert_eq!(post.chat_id, 9);
+        assert!(matches!(
+            one_shot.on_event(ChatRoomEvent::PostAccepted(9, 3)),
+            Step::Done
+        ));
+
+        // the spec the server cannot create is not waited for
+        let target = Target::parse("ops +public").unwrap();
+        let mut one_shot = OneShot::new(target, String::from("deployed"));
+        assert!(matches!(
+            one_shot.on_event(ChatRoomEvent::Registered(1)),
+            Step::Failed(_)
+        ));
+        // nor the refused one
+        let mut one_shot = OneShot::new(Target::parse("ops").unwrap(), String::from("x"));
+        one_shot.on_event(ChatRoomEvent::Registered(1));
+        let refused = ChatRoomEvent::Notice(String::from("new chat: too long"));
+        assert_eq!(failed(one_shot.on_event(refused)), "new chat: too long");
+    }
+
+    #[test]
+    fn posts_listened_as_json() {
+        let mut authors = HashMap::new();
+        authors.insert(1, String::from("Alice"));
+        let post = |user_id, text: &str| Post {
+            id: 3,
+            chat_id: 9,
+            user_id,
+            text: String::from(text),
+            created: 1_600_000_000,
+            author_name: String::from("former"),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        write_post(&mut out, &authors, &post(1, "a\n\"b\"")).unwrap();
+        write_post(&mut out, &authors, &post(2, "c")).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"chat_id\":9,\"id\":3,\"author\":\"Alice\",\"created\":1600000000,\"text\":\"a\\n\\\"b\\\"\"}\n\
+             {\"chat_id\":9,\"id\":3,\"author\":\"former\",\"created\":1600000000,\"text\":\"c\"}\n"
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use super::ChatRoomImpl;
+use crate::storage::ChatStorage;
+use log::{error, info, warn};
+use std::{sync::Arc, time::Duration};
+use tonic_health::{server::HealthReporter, ServingStatus};
+
+const DEF_CHECK_INTERVAL_SECS: u64 = 10;
+// the key is only read by the self-check, reading it is enough to see the storage work
+const CHECK_KEY: &str = "health_check";
+// the status of the server as a whole is asked for by the empty name
+const SERVER: &str = "";
+
+// the status the balancers see
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthConfig {
+    // the storage is read that often, the server is not serving while it is unreadable
+    pub check_interval: Duration,
+    // the server is reported not serving for that long before its streams are closed
+    pub drain: Duration,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            check_interval: Duration::from_secs(DEF_CHECK_INTERVAL_SECS),
+            drain: Duration::default(),
+        }
+    }
+}
+
+// the server and the chat room service have the same status
+pub async fn set_status(reporter: &mut HealthReporter, service: &str, status: ServingStatus) {
+    reporter.set_service_status(SERVER, status).await;
+    reporter.set_service_status(service, status).await;
+}
+
+// a cheap read transaction, blocks the thread
+fn check<S: ChatStorage>(chat_room: &ChatRoomImpl<S>) -> bool {
+    match chat_room.storage.read_meta(CHECK_KEY) {
+        Ok(_) => true,
+        Err(e) => {
+            error!("storage self-check failed, {}", e);
+            false
+        }
+    }
+}
+
+// serving while the storage is readable, the status is reported on change only;
+// the config is read anew every check
+pub async fn run<S: ChatStorage>(
+    chat_room: Arc<ChatRoomImpl<S>>,
+    mut reporter: HealthReporter,
+    service: &'static str,
+) {
+    let mut serving = None;
+    loop {
+        let checked = {
+            let chat_room = chat_room.clone();
+            tokio::task::spawn_blocking(move || check(&chat_room)).await
+        };
+        let healthy = match checked {
+            Ok(healthy) => healthy,
+            Err(e) => {
+                error!("storage self-check task failed, {}", e);
+                false
+            }
+        };
+        if serving != Some(healthy) {
+            let status = if healthy {
+                info!("health: serving");
+                ServingStatus::Serving
+            } else {
+                warn!("health: not serving, the storage is unreadable");
+                ServingStatus::NotServing
+            };
+            set_status(&mut reporter, service, status).await;
+            serving = Some(healthy);
+        }
+        tokio::time::sleep(chat_room.tunables().health.check_interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::chat_room_service_server::ChatRoomServiceServer;
+    use crate::proxy::{ProxyConfig, ProxyFilter};
+    use crate::settings::Tunables;
+    use crate::storage::memory::InMemoryStorage;
+    use crate::{Limits, Listener};
+    use tonic::transport::{Channel, NamedService};
+    use tonic_health::proto::{
+        health_check_response::ServingStatus as Status, health_client::HealthClient,
+        HealthCheckRequest,
+    };
+
+    async fn status(client: &mut HealthClient<Channel>, service: &str) -> i32 {
+        client
+            .check(HealthCheckRequest {
+                service: String::from(service),
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .status
+    }
+
+    // the status of the service once it becomes the expected one
+    async fn wait_status(client: &mut HealthClient<Channel>, service: &str, expected: Status) {
+        let changed = async {
+            while status(client, service).await != expected as i32 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), changed)
+            .await
+            .expect("status in time");
+    }
+
+    #[tokio::test]
+    async fn not_serving_on_shutdown() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        chat_room.reconfigure(Tunables {
+            health: HealthConfig {
+                check_interval: Duration::from_millis(50),
+                drain: Duration::from_millis(500),
+            },
+            ..Tunables::default()
+        });
+        // take a free port
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (tx_stop, rx_stop) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(crate::serve(
+            chat_room.clone(),
+            Listener::Bind(addr),
+            None,
+            Arc::new(ProxyFilter::new(ProxyConfig::default())),
+            None,
+            async {
+                let _ = rx_stop.await;
+            },
+        ));
+        let mut client = None;
+        for _ in 0..50 {
+            match HealthClient::connect(format!("http://{}", addr)).await {
+                Ok(connected) => {
+                    client = Some(connected);
+                    break;
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+        let mut client = client.expect("server is started");
+        let service =
+            <ChatRoomServiceServer<Arc<ChatRoomImpl<InMemoryStorage>>> as NamedService>::NAME;
+        wait_status(&mut client, service, Status::Serving).await;
+        assert_eq!(status(&mut client, SERVER).await, Status::Serving as i32);
+
+        // the connected balancer sees the server drain before it stops
+        tx_stop.send(()).unwrap();
+        wait_status(&mut client, service, Status::NotServing).await;
+        assert_eq!(status(&mut client, SERVER).await, Status::NotServing as i32);
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use crate::proto::UserId;
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+// the ids the servers have assigned to the logins and the secrets given with them,
+// one per line: "id:secret server login", the lines saved before the secrets have no secret
+pub struct Identities {
+    path: PathBuf,
+}
+
+struct Entry {
+    user_id: UserId,
+    secret: String,
+    server: String,
+    login: String,
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let mut parts = line.trim().splitn(3, ' ');
+    let mut identity = parts.next()?.splitn(2, ':');
+    let user_id = identity.next()?.parse().ok()?;
+    let secret = identity.next().unwrap_or_default().to_string();
+    let server = parts.next()?.to_string();
+    let login = parts.next()?.to_string();
+    Some(Entry {
+        user_id,
+        secret,
+        server,
+        login,
+    })
+}
+
+impl Identities {
+    pub fn new(path: &Path) -> Self {
+        Identities {
+            path: path.to_path_buf(),
+        }
+    }
+
+    // the missing file is no ids, malformed lines are skipped
+    fn read(&self) -> io::Result<Vec<Entry>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            if let Some(entry) = parse_entry(&line?) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn load(&self, server: &str, login: &str) -> io::Result<Option<(UserId, String)>> {
+        Ok(self
+            .read()?
+            .into_iter()
+            .find(|e| e.server == server && e.login == login)
+            .map(|e| (e.user_id, e.secret)))
+    }
+
+    // replaces the file entirely
+    pub fn save(&self, server: &str, login: &str, user_id: UserId, secret: &str) -> io::Result<()> {
+        let mut entries = self.read()?;
+        entries.retain(|e| e.server != server || e.login != login);
+        entries.push(Entry {
+            user_id,
+            secret: secret.to_string(),
+            server: server.to_string(),
+            login: login.to_string(),
+        });
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for entry in &entries {
+            writeln!(
+                file,
+                "{}:{} {} {}",
+                entry.user_id, entry.secret, entry.server, entry.login
+            )?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_FILE: &str = "migchat-test-identities.txt";
+
+    #[test]
+    fn saved_ids_loaded() {
+        let _ = fs::remove_file(TEST_FILE);
+        let identities = Identities::new(Path::new(TEST_FILE));
+        assert_eq!(identities.load("http://a", "user").unwrap(), None);
+        identities.save("http://a", "user", 1, "s1").unwrap();
+        identities.save("http://b", "user", 2, "s2").unwrap();
+        identities
+            .save("http://a", "login with spaces", 3, "s3")
+            .unwrap();
+        // the id of the login is replaced
+        identities.save("http://a", "user", 4, "s4").unwrap();
+        let loaded = |server, login| identities.load(server, login).unwrap();
+        assert_eq!(loaded("http://a", "user"), Some((4, String::from("s4"))));
+        assert_eq!(loaded("http://b", "user"), Some((2, String::from("s2"))));
+        assert_eq!(
+            loaded("http://a", "login with spaces"),
+            Some((3, String::from("s3")))
+        );
+        assert_eq!(loaded("http://b", "other"), None);
+        let _ = fs::remove_file(TEST_FILE);
+    }
+
+    #[test]
+    fn ids_without_secrets_loaded() {
+        const OLD_FILE: &str = "migchat-test-identities-old.txt";
+        fs::write(OLD_FILE, "5 http://a user\n").unwrap();
+        let identities = Identities::new(Path::new(OLD_FILE));
+        assert_eq!(
+            identities.load("http://a", "user").unwrap(),
+            Some((5, String::new()))
+        );
+        let _ = fs::remove_file(OLD_FILE);
+    }
+}
@@ -0,0 +1,19 @@
+//! The client of the migchat server without its terminal UI, e.g. to build a bot.
+//!
+//! [`client_service::MigchatClient`] takes the [`client_service::Command`]s from its
+//! channel and sends the [`client_service::ChatRoomEvent`]s of the server into the channel
+//! of the consumer, the reconnection included. The messages are those of
+//! [`migchat_core::proto`], re-exported by the `proto` feature.
+
+#[cfg(feature = "proto")]
+pub use migchat_core::proto;
+#[cfg(not(feature = "proto"))]
+use migchat_core::proto;
+
+pub mod cache;
+pub mod client_service;
+pub mod export;
+pub mod gaps;
+pub mod identity;
+pub mod relay;
+pub mod state;
@@ -0,0 +1,176 @@
+use super::{ChatRoomImpl, UserChanged, UserId};
+use crate::storage::ChatStorage;
+use log::{debug, error, info};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+const DEF_IDLE_TIMEOUT_SECS: u64 = 90;
+const DEF_CHECK_INTERVAL_SECS: u64 = 10;
+const DEF_SESSION_TIMEOUT_SECS: u64 = 3600;
+
+// the clients are expected to call at least once per the timeout, heartbeat if idle
+#[derive(Clone, Debug, PartialEq)]
+pub struct PresenceConfig {
+    // the silent user goes offline after it
+    pub idle_timeout: Duration,
+    pub check_interval: Duration,
+    // the session not called for that long ends, its token expires
+    pub session_timeout: Duration,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        PresenceConfig {
+            idle_timeout: Duration::from_secs(DEF_IDLE_TIMEOUT_SECS),
+            check_interval: Duration::from_secs(DEF_CHECK_INTERVAL_SECS),
+            session_timeout: Duration::from_secs(DEF_SESSION_TIMEOUT_SECS),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Presence {
+    // online users with the time they were last seen
+    online: RwLock<HashMap<UserId, Instant>>,
+    // count of the open streams of the users
+    streams: Mutex<HashMap<UserId, usize>>,
+}
+
+impl Presence {
+    pub fn is_online(&self, user_id: UserId) -> bool {
+        match self.online.read() {
+            Ok(online) => online.contains_key(&user_id),
+            Err(_) => {
+                error!("failed locking online users");
+                false
+            }
+        }
+    }
+
+    // returns true if the user has come online
+    pub fn touch(&self, user_id: UserId, now: Instant) -> bool {
+        match self.online.write() {
+            Ok(mut online) => online.insert(user_id, now).is_none(),
+            Err(_) => {
+                error!("failed locking online users");
+                false
+            }
+        }
+    }
+
+    // returns true if the user was online
+    pub fn set_offline(&self, user_id: UserId) -> bool {
+        match self.online.write() {
+            Ok(mut online) => online.remove(&user_id).is_some(),
+            Err(_) => {
+                error!("failed locking online users");
+                false
+            }
+        }
+    }
+
+    // the users not seen within the timeout go offline
+    pub fn expire_idle(&self, now: Instant, timeout: Duration) -> Vec<UserId> {
+        let mut expired = Vec::new();
+        match self.online.write() {
+            Ok(mut online) => online.retain(|id, last_seen| {
+                if now.saturating_duration_since(*last_seen) < timeout {
+                    true
+                } else {
+                    expired.push(*id);
+                    false
+                }
+            }),
+            Err(_) => error!("failed locking online users"),
+        }
+        expired
+    }
+
+    pub fn stream_opened(&self, user_id: UserId) {
+        if let Ok(mut streams) = self.streams.lock() {
+            *streams.entry(user_id).or_default() += 1;
+        } else {
+            error!("failed locking streams of users");
+        }
+    }
+
+    // returns true if the last stream of the user has been closed
+    pub fn stream_closed(&self, user_id: UserId) -> bool {
+        if let Ok(mut streams) = self.streams.lock() {
+            match streams.get_mut(&user_id) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    false
+                }
+                Some(_) => {
+                    streams.remove(&user_id);
+                    true
+                }
+                None => false,
+            }
+        } else {
+            error!("failed locking streams of users");
+            false
+        }
+    }
+}
+
+// the crashed clients and those which have lost the network go offline eventually,
+// their sessions end later; the config is read anew every check
+pub async fn run<S: ChatStorage>(chat_room: Arc<ChatRoomImpl<S>>) {
+    info!("use presence config: {:?}", chat_room.tunables().presence);
+    loop {
+        tokio::time::sleep(chat_room.tunables().presence.check_interval).await;
+        let config = chat_room.tunables().presence;
+        for user_id in chat_room
+            .presence
+            .expire_idle(Instant::now(), config.idle_timeout)
+        {
+            debug!("{} has not been seen for a while, offline", user_id);
+            chat_room.notify_user_changed(UserChanged::Offline(user_id));
+        }
+        let expired = chat_room.expire_sessions(Instant::now(), config.session_timeout);
+        if expired > 0 {
+            debug!("{} idle session(s) expired", expired);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_users_expire() {
+        let presence = Presence::default();
+        let start = Instant::now();
+        assert!(presence.touch(1, start));
+        assert!(presence.touch(2, start));
+        assert!(!presence.touch(1, start + Duration::from_secs(60)));
+        let timeout = Duration::from_secs(90);
+        assert!(presence
+            .expire_idle(start + Duration::from_secs(89), timeout)
+            .is_empty());
+        assert_eq!(
+            presence.expire_idle(start + Duration::from_secs(100), timeout),
+            vec![2]
+        );
+        assert!(presence.is_online(1));
+        assert!(!presence.is_online(2));
+        // back with the next call
+        assert!(presence.touch(2, start + Duration::from_secs(101)));
+    }
+
+    #[test]
+    fn last_stream_closed() {
+        let presence = Presence::default();
+        presence.stream_opened(1);
+        presence.stream_opened(1);
+        assert!(!presence.stream_closed(1));
+        assert!(presence.stream_closed(1));
+        assert!(!presence.stream_closed(1));
+    }
+}
@@ -0,0 +1,278 @@
+use log::{debug, error, warn};
+use std::{
+    collections::HashSet,
+    fmt,
+    net::IpAddr,
+    sync::{Mutex, RwLock},
+};
+use tonic::{
+    metadata::{Ascii, MetadataMap, MetadataValue},
+    Request, Status,
+};
+
+const DEF_FORWARDED_FOR_KEY: &str = "x-forwarded-for";
+const DEF_VERIFIED_USER_KEY: &str = "x-verified-user";
+// the honored values are passed to the handlers under the keys never taken from the clients
+pub(crate) const CLIENT_ADDR_KEY: &str = "x-migchat-client-addr";
+pub(crate) const CLIENT_USER_KEY: &str = "x-migchat-client-user";
+
+// the reverse proxy injecting the verified identity of its clients
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyConfig {
+    // peers whose forwarded metadata is honored, none by default
+    pub trusted_peers: Vec<IpAddr>,
+    pub forwarded_for_key: String,
+    pub verified_user_key: String,
+    // rejects the requests without the verified user honored
+    pub require_identity: bool,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            trusted_peers: Vec::new(),
+            forwarded_for_key: String::from(DEF_FORWARDED_FOR_KEY),
+            verified_user_key: String::from(DEF_VERIFIED_USER_KEY),
+            require_identity: false,
+        }
+    }
+}
+
+pub struct ProxyFilter {
+    // replaced on reload, the requests in flight keep the one they started with
+    config: RwLock<ProxyConfig>,
+    // untrusted peers already reported for forwarding the metadata
+    reported: Mutex<HashSet<IpAddr>>,
+}
+
+impl ProxyFilter {
+    pub fn new(config: ProxyConfig) -> Self {
+        ProxyFilter {
+            config: RwLock::new(config),
+            reported: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn reconfigure(&self, config: ProxyConfig) {
+        match self.config.write() {
+            Ok(mut current) => *current = config,
+            Err(_) => error!("failed locking proxy config"),
+        }
+    }
+
+    // the interceptor of the service
+    pub fn intercept(&self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let peer = request.remote_addr().map(|addr| addr.ip());
+        self.filter(peer, request.metadata_mut())?;
+        Ok(request)
+    }
+
+    // moves the forwarded values of a trusted peer under the internal keys,
+    // drops those of anyone else
+    fn filter(&self, peer: Option<IpAddr>, metadata: &mut MetadataMap) -> Result<(), Status> {
+        metadata.remove(CLIENT_ADDR_KEY);
+        metadata.remove(CLIENT_USER_KEY);
+        let config = self
+            .config
+            .read()
+            .map_err(|_| Status::internal("failed locking proxy config"))?;
+        let forwarded_for = metadata.remove(config.forwarded_for_key.as_str());
+        let verified_user = metadata.remove(config.verified_user_key.as_str());
+        let trusted = peer.map_or(false, |ip| config.trusted_peers.contains(&ip));
+        if trusted {
+            // the first address is the client's one, the rest are the intermediate proxies
+            if let Some(client_addr) = forwarded_for
+                .as_ref()
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .map(str::trim)
+                .filter(|addr| !addr.is_empty())
+                .and_then(|addr| addr.parse::<MetadataValue<Ascii>>().ok())
+            {
+                metadata.insert(CLIENT_ADDR_KEY, client_addr);
+            }
+            if let Some(user) = verified_user {
+                metadata.insert(CLIENT_USER_KEY, user);
+            }
+        } else if forwarded_for.is_some() || verified_user.is_some() {
+            self.report_untrusted(peer);
+        }
+        if config.require_identity && metadata.get(CLIENT_USER_KEY).is_none() {
+            debug!("request from {:?} has no verified identity", peer);
+            return Err(Status::unauthenticated("no verified client identity"));
+        }
+        Ok(())
+    }
+
+    fn report_untrusted(&self, peer: Option<IpAddr>) {
+        let peer = match peer {
+            Some(peer) => peer,
+            None => return,
+        };
+        let first = self
+            .reported
+            .lock()
+            .map(|mut reported| reported.insert(peer))
+            .unwrap_or(false);
+        if first {
+            warn!(
+                "forwarded client identity from untrusted peer {} is ignored",
+                peer
+            );
+        }
+    }
+}
+
+// the client as vouched for by the trusted proxy, otherwise the direct peer
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientIdentity {
+    pub addr: Option<String>,
+    pub verified_user: Option<String>,
+    // the address is the one forwarded by the trusted proxy, not the peer's
+    pub forwarded: bool,
+}
+
+impl ClientIdentity {
+    pub fn of<T>(request: &Request<T>) -> Self {
+        let metadata = request.metadata();
+        let value = |key| {
+            metadata
+                .get(key)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        let forwarded = value(CLIENT_ADDR_KEY);
+        ClientIdentity {
+            forwarded: forwarded.is_some(),
+            addr: forwarded.or_else(|| request.remote_addr().map(|a| a.to_string())),
+            verified_user: value(CLIENT_USER_KEY),
+        }
+    }
+
+    // the address of the client behind the trusted proxy, all the proxy's clients
+    // would be taken for one by the peer address
+    pub fn forwarded_addr(&self) -> Option<&str> {
+        self.addr.as_deref().filter(|_| self.forwarded)
+    }
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addr = self.addr.as_deref().unwrap_or("unknown address");
+        match &self.verified_user {
+            Some(user) => write!(f, "{} at {}", user, addr),
+            None => write!(f, "{}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROXY: [u8; 4] = [10, 0, 0, 1];
+
+    fn forwarded() -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(
+            DEF_FORWARDED_FOR_KEY,
+            MetadataValue::from_static("192.0.2.7, 10.0.0.1"),
+        );
+        metadata.insert(DEF_VERIFIED_USER_KEY, MetadataValue::from_static("alice"));
+        // forged by the client
+        metadata.insert(CLIENT_USER_KEY, MetadataValue::from_static("mallory"));
+        metadata
+    }
+
+    fn filter(require_identity: bool) -> ProxyFilter {
+        ProxyFilter::new(ProxyConfig {
+            trusted_peers: vec![IpAddr::from(PROXY)],
+            require_identity,
+            ..ProxyConfig::default()
+        })
+    }
+
+    fn identity(metadata: MetadataMap) -> ClientIdentity {
+        let mut request = Request::new(());
+        *request.metadata_mut() = metadata;
+        ClientIdentity::of(&request)
+    }
+
+    #[test]
+    fn trusted_peer_forwards_identity() {
+        let filter = filter(false);
+        let mut metadata = forwarded();
+        filter
+            .filter(Some(IpAddr::from(PROXY)), &mut metadata)
+            .unwrap();
+        assert!(metadata.get(DEF_FORWARDED_FOR_KEY).is_none());
+        let identity = identity(metadata);
+        assert_eq!(identity.addr.as_deref(), Some("192.0.2.7"));
+        assert_eq!(identity.forwarded_addr(), Some("192.0.2.7"));
+        assert_eq!(identity.verified_user.as_deref(), Some("alice"));
+        assert_eq!(identity.to_string(), "alice at 192.0.2.7");
+    }
+
+    #[test]
+    fn untrusted_peer_is_ignored() {
+        let filter = filter(false);
+        for peer in &[Some(IpAddr::from([192, 0, 2, 1])), None] {
+            let mut metadata = forwarded();
+            filter.filter(*peer, &mut metadata).unwrap();
+            assert_eq!(
+                identity(metadata),
+                ClientIdentity {
+                    addr: None,
+                    verified_user: None,
+                    forwarded: false
+                }
+            );
+        }
+        // reported once per peer
+        let mut metadata = forwarded();
+        filter
+            .filter(Some(IpAddr::from([192, 0, 2, 1])), &mut metadata)
+            .unwrap();
+        assert_eq!(filter.reported.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn identity_required() {
+        let filter = filter(true);
+        let mut metadata = forwarded();
+        assert!(filter
+            .filter(Some(IpAddr::from(PROXY)), &mut metadata)
+            .is_ok());
+        // the proxy has not verified the client
+        let mut metadata = forwarded();
+        metadata.remove(DEF_VERIFIED_USER_KEY);
+        let e = filter
+            .filter(Some(IpAddr::from(PROXY)), &mut metadata)
+            .unwrap_err();
+        assert_eq!(e.code(), tonic::Code::Unauthenticated);
+        // nobody else is trusted to
+        let mut metadata = forwarded();
+        assert!(filter
+            .filter(Some(IpAddr::from([192, 0, 2, 1])), &mut metadata)
+            .is_err());
+    }
+
+    #[test]
+    fn reconfigured_on_the_fly() {
+        let filter = filter(true);
+        let peer = Some(IpAddr::from([192, 0, 2, 1]));
+        filter.reconfigure(ProxyConfig {
+            trusted_peers: vec![IpAddr::from([192, 0, 2, 1])],
+            ..ProxyConfig::default()
+        });
+        let mut metadata = forwarded();
+        filter.filter(peer, &mut metadata).unwrap();
+        assert_eq!(identity(metadata).verified_user.as_deref(), Some("alice"));
+        // the former proxy is no longer trusted, the identity is not required
+        let mut metadata = forwarded();
+        filter
+            .filter(Some(IpAddr::from(PROXY)), &mut metadata)
+            .unwrap();
+        assert_eq!(identity(metadata).verified_user, None);
+    }
+}
@@ -0,0 +1,180 @@
+use super::{ChatRoomImpl, UserId};
+use crate::storage::ChatStorage;
+use log::{debug, error};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const DEF_POSTS_PER_MINUTE: u32 = 60;
+const DEF_INVITATIONS_PER_MINUTE: u32 = 10;
+// the bucket is full again long before, forgetting it changes nothing
+const EXPIRE_AFTER: Duration = Duration::from_secs(5 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Action {
+    Post,
+    Invitation,
+}
+
+// whom the calls are counted against
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum RateKey {
+    // the client behind the trusted proxy, whatever users it registers
+    Addr(String),
+    User(UserId),
+}
+
+impl fmt::Display for RateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateKey::Addr(addr) => write!(f, "client {}", addr),
+            RateKey::User(user_id) => write!(f, "user {}", user_id),
+        }
+    }
+}
+
+// calls of every client per minute, no limit if zero;
+// the unused ones are saved up to a minute's worth
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimits {
+    pub posts_per_minute: u32,
+    pub invitations_per_minute: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            posts_per_minute: DEF_POSTS_PER_MINUTE,
+            invitations_per_minute: DEF_INVITATIONS_PER_MINUTE,
+        }
+    }
+}
+
+impl RateLimits {
+    pub fn per_minute(&self, action: Action) -> u32 {
+        match action {
+            Action::Post => self.posts_per_minute,
+            Action::Invitation => self.invitations_per_minute,
+        }
+    }
+}
+
+// the calls left and the time they were counted at
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// token buckets of the clients, kept in memory only
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<(Action, RateKey), Bucket>>,
+}
+
+impl RateLimiter {
+    // takes a call from the bucket of the client, the time to wait for the next one if it is empty
+    pub fn take(
+        &self,
+        action: Action,
+        key: RateKey,
+        per_minute: u32,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        if per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = per_minute as f64;
+        let per_sec = capacity / 60.0;
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(_) => {
+                error!("failed locking rate limits");
+                return Ok(());
+            }
+        };
+        let bucket = buckets.entry((action, key)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = capacity.min(bucket.tokens + elapsed * per_sec);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+
+    // the buckets of the clients idle for long are dropped, returns how many
+    pub fn expire(&self, now: Instant) -> usize {
+        match self.buckets.lock() {
+            Ok(mut buckets) => {
+                let before = buckets.len();
+                buckets.retain(|_, bucket| {
+                    now.saturating_duration_since(bucket.updated) < EXPIRE_AFTER
+                });
+                before - buckets.len()
+            }
+            Err(_) => {
+                error!("failed locking rate limits");
+                0
+            }
+        }
+    }
+}
+
+// the limiter does not grow with every user ever seen
+pub async fn run<S: ChatStorage>(chat_room: Arc<ChatRoomImpl<S>>) {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        let expired = chat_room.rate_limiter.expire(Instant::now());
+        if expired > 0 {
+            debug!("{} idle rate limit(s) dropped", expired);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refilled_and_expired() {
+        let limiter = RateLimiter::default();
+        let user = RateKey::User;
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        // a minute's worth at once, then one per second
+        for _ in 0..60 {
+            assert_eq!(limiter.take(Action::Post, user(1), 60, start), Ok(()));
+        }
+        assert_eq!(
+            limiter.take(Action::Post, user(1), 60, start),
+            Err(Duration::from_secs(1))
+        );
+        let wait = limiter
+            .take(Action::Post, user(1), 60, at(400))
+            .unwrap_err();
+        assert!(wait > Duration::from_millis(599) && wait <= Duration::from_millis(600));
+        assert_eq!(limiter.take(Action::Post, user(1), 60, at(1010)), Ok(()));
+        // the others and the other calls are counted apart
+        assert_eq!(limiter.take(Action::Post, user(2), 60, start), Ok(()));
+        assert_eq!(limiter.take(Action::Invitation, user(1), 1, start), Ok(()));
+        assert!(limiter.take(Action::Invitation, user(1), 1, start).is_err());
+        let client = RateKey::Addr(String::from("192.0.2.7"));
+        assert_eq!(limiter.take(Action::Invitation, client, 1, start), Ok(()));
+        // no limit
+        for _ in 0..100 {
+            assert_eq!(limiter.take(Action::Post, user(3), 0, start), Ok(()));
+        }
+        // the buckets of the start go first
+        assert_eq!(limiter.expire(at(1000) + EXPIRE_AFTER), 3);
+        assert_eq!(limiter.expire(at(1010) + EXPIRE_AFTER), 1);
+    }
+}
@@ -0,0 +1,435 @@
+use crate::client_service::{ChatRoomEvent, ClientError, ServiceEvent};
+use crate::proto::{ChatId, UserId};
+use futures::{Stream, StreamExt};
+use log::{debug, info, warn};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{sync::mpsc, time::Instant};
+
+#[derive(Clone, Copy, Debug)]
+pub struct RelayConfig {
+    // a send to the UI taking longer is a stall
+    pub send_timeout: Duration,
+    // superseded events are shed once the stall lasts this many timeouts
+    pub shed_after: u32,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig {
+            send_timeout: Duration::from_secs(5),
+            shed_after: 3,
+        }
+    }
+}
+
+// shared by the relays of all streams, shown by the UI
+#[derive(Default, Debug)]
+pub struct RelayStats {
+    // send timeouts so far
+    pub stalls: AtomicU64,
+    // events dropped as superseded
+    pub shed: AtomicU64,
+    // events read from the server but not accepted by the UI yet
+    pub pending: AtomicUsize,
+    // relays waiting for the UI right now
+    pub stalled: AtomicUsize,
+}
+
+impl RelayStats {
+    // count of events waiting for the stalled UI, none if the UI is fine
+    pub fn stalled_pending(&self) -> Option<usize> {
+        if self.stalled.load(Ordering::Relaxed) > 0 {
+            Some(self.pending.load(Ordering::Relaxed))
+        } else {
+            None
+        }
+    }
+}
+
+// events describing the same state, only the latest one matters
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+enum Coalesce {
+    User(UserId),
+    Presence(UserId),
+    Chat(ChatId),
+    ChatState(ChatId),
+    Membership(ChatId),
+    Typing(ChatId, UserId),
+    ReadPosition(ChatId),
+    Muted(ChatId),
+}
+
+fn coalesce_key(event: &ChatRoomEvent) -> Option<Coalesce> {
+    match event {
+        ChatRoomEvent::UserInfo(user) => Some(Coalesce::User(user.id)),
+        ChatRoomEvent::UserEntered(id) | ChatRoomEvent::UserGone(id) => {
+            Some(Coalesce::Presence(*id))
+        }
+        ChatRoomEvent::ChatUpdated(chat, _) => Some(Coalesce::Chat(chat.id)),
+        ChatRoomEvent::ChatDegraded(id, _) => Some(Coalesce::ChatState(*id)),
+        ChatRoomEvent::Membership(id, _) => Some(Coalesce::Membership(*id)),
+        ChatRoomEvent::Typing(chat_id, user_id, _) => Some(Coalesce::Typing(*chat_id, *user_id)),
+        ChatRoomEvent::ReadPosition(id, _, _) => Some(Coalesce::ReadPosition(*id)),
+        ChatRoomEvent::Muted(id, _) => Some(Coalesce::Muted(*id)),
+        _ => None,
+    }
+}
+
+/// Buffers the events of a server stream while the UI does not accept them,
+/// so the stream is read on. Posts and the other events are only delayed,
+/// events superseded by the later ones are shed if the stall lasts.
+pub struct EventRelay {
+    name: &'static str,
+    config: RelayConfig,
+    stats: Arc<RelayStats>,
+    queue: VecDeque<ChatRoomEvent>,
+    // consecutive send timeouts
+    level: u32,
+    deadline: Option<Instant>,
+}
+
+impl EventRelay {
+    pub fn new(name: &'static str, config: RelayConfig, stats: Arc<RelayStats>) -> Self {
+        EventRelay {
+            name,
+            config,
+            stats,
+            queue: VecDeque::new(),
+            level: 0,
+            deadline: None,
+        }
+    }
+
+    pub fn push(&mut self, event: ChatRoomEvent) {
+        if self.queue.is_empty() {
+            self.deadline = Some(Instant::now() + self.config.send_timeout);
+        }
+        self.queue.push_back(event);
+        self.stats.pending.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn pop(&mut self) -> Option<ChatRoomEvent> {
+        let event = self.queue.pop_front()?;
+        self.stats.pending.fetch_sub(1, Ordering::Relaxed);
+        // the UI is moving, the next event gets the full timeout
+        self.deadline = if self.queue.is_empty() {
+            None
+        } else {
+            Some(Instant::now() + self.config.send_timeout)
+        };
+        if self.level > 0 {
+            info!(
+                "{} events: UI accepts events again after {} timeouts, {} pending",
+                self.name,
+                self.level,
+                self.queue.len()
+            );
+            self.level = 0;
+            self.stats.stalled.fetch_sub(1, Ordering::Relaxed);
+        }
+        Some(event)
+    }
+
+    fn on_timeout(&mut self) {
+        self.level += 1;
+        self.deadline = Some(Instant::now() + self.config.send_timeout);
+        self.stats.stalls.fetch_add(1, Ordering::Relaxed);
+        if self.level == 1 {
+            self.stats.stalled.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "{} events: UI does not accept events for {:?}, {} pending",
+                self.name,
+                self.config.send_timeout,
+                self.queue.len()
+            );
+        }
+        if self.level >= self.config.shed_after {
+            if self.level == self.config.shed_after {
+                warn!(
+                    "{} events: UI is stalled, superseded events are dropped",
+                    self.name
+                );
+            }
+            let shed = self.shed();
+            if shed > 0 {
+                debug!("{} events: {} superseded events dropped", self.name, shed);
+            }
+        }
+    }
+
+    // drops the events followed by the newer ones of the same state, returns their count;
+    // an update of a chat keeps the largest history length as the chat may be new to the UI
+    fn shed(&mut self) -> usize {
+        let before = self.queue.len();
+        let mut kept: Vec<ChatRoomEvent> = Vec::with_capacity(before);
+        let mut latest: HashMap<Coalesce, usize> = HashMap::new();
+        while let Some(event) = self.queue.pop_back() {
+            if let ChatRoomEvent::ChatDeleted(id) = &event {
+                // the chat may come again, its updates before are kept
+                latest.remove(&Coalesce::Chat(*id));
+                latest.remove(&Coalesce::ChatState(*id));
+            }
+            match coalesce_key(&event) {
+                Some(key) => match latest.get(&key) {
+                    Some(&idx) => {
+                        if let (
+                            ChatRoomEvent::ChatUpdated(_, older),
+                            ChatRoomEvent::ChatUpdated(_, newer),
+                        ) = (&event, &mut kept[idx])
+                        {
+                            *newer = (*newer).max(*older);
+                        }
+                    }
+                    None => {
+                        latest.insert(key, kept.len());
+                        kept.push(event);
+                    }
+                },
+                None => kept.push(event),
+            }
+        }
+        self.queue = kept.into_iter().rev().collect();
+        let shed = before - self.queue.len();
+        self.stats.pending.fetch_sub(shed, Ordering::Relaxed);
+        self.stats.shed.fetch_add(shed as u64, Ordering::Relaxed);
+        shed
+    }
+
+    /// Reads the stream converting its messages into events until it ends,
+    /// then delivers the rest. Returns early if the UI is gone.
+    pub async fn run<T, S, F, E>(
+        &mut self,
+        mut stream: S,
+        tx_event: &mpsc::Sender<E>,
+        mut convert: F,
+    ) -> Result<(), ClientError>
+    where
+        E: ServiceEvent,
+        S: Stream<Item = Result<T, tonic::Status>> + Unpin,
+        F: FnMut(T, &mut EventRelay),
+    {
+        let mut reading = true;
+        let mut ended = Ok(());
+        while reading || !self.queue.is_empty() {
+            let pending = !self.queue.is_empty();
+            let deadline = self.deadline.unwrap_or_else(Instant::now);
+            tokio::select! {
+                message = stream.next(), if reading => match message {
+                    Some(Ok(message)) => convert(message, self),
+                    Some(Err(e)) => {
+                        warn!("{} stream failed: {}", self.name, e);
+                        reading = false;
+                    }
+                    None => reading = false,
+                },
+                permit = tx_event.reserve(), if pending => match permit {
+                    Ok(permit) => {
+                        if let Some(event) = self.pop() {
+                            permit.send(E::from(event));
+                        }
+                    }
+                    Err(_) => {
+                        warn!("{} events: UI is gone", self.name);
+                        ended = Err(ClientError::ChannelClosed);
+                        break;
+                    }
+                },
+                _ = tokio::time::sleep_until(deadline), if pending => self.on_timeout(),
+            }
+        }
+        self.release();
+        ended
+    }
+
+    // the rest is not delivered
+    fn release(&mut self) {
+        self.stats
+            .pending
+            .fetch_sub(self.queue.len(), Ordering::Relaxed);
+        self.queue.clear();
+        self.deadline = None;
+        if self.level > 0 {
+            self.stats.stalled.fetch_sub(1, Ordering::Relaxed);
+            self.level = 0;
+        }
+    }
+}
+
+// the reading task is aborted on reconnect
+impl Drop for EventRelay {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_service::ClientEvent;
+    use crate::proto::{Chat, Post};
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn config() -> RelayConfig {
+        RelayConfig {
+            send_timeout: TIMEOUT,
+            shed_after: 2,
+        }
+    }
+
+    fn post(id: u64) -> ChatRoomEvent {
+        ChatRoomEvent::NewPost(Post {
+            id,
+            chat_id: 1,
+            ..Default::default()
+        })
+    }
+
+    fn chat(id: ChatId, history_len: usize) -> ChatRoomEvent {
+        ChatRoomEvent::ChatUpdated(
+            Chat {
+                id,
+                ..Default::default()
+            },
+            history_len,
+        )
+    }
+
+    // short text of the event to compare
+    fn brief(event: &ChatRoomEvent) -> String {
+        match event {
+            ChatRoomEvent::NewPost(post) => format!("post {}", post.id),
+            ChatRoomEvent::ChatUpdated(chat, len) => format!("chat {} {}", chat.id, len),
+            ChatRoomEvent::ChatDeleted(id) => format!("deleted {}", id),
+            ChatRoomEvent::ChatDegraded(id, degraded) => format!("degraded {} {}", id, degraded),
+            ChatRoomEvent::UserEntered(id) => format!("online {}", id),
+            ChatRoomEvent::UserGone(id) => format!("offline {}", id),
+            _ => String::from("other"),
+        }
+    }
+
+    #[test]
+    fn superseded_events_are_shed() {
+        let stats = Arc::new(RelayStats::default());
+        let mut relay = EventRelay::new("test", config(), stats.clone());
+        for event in vec![
+            ChatRoomEvent::UserEntered(2),
+            chat(10, 5),
+            post(1),
+            ChatRoomEvent::UserGone(2),
+            ChatRoomEvent::ChatDegraded(10, true),
+            chat(10, 0),
+            ChatRoomEvent::UserEntered(3),
+            post(2),
+            ChatRoomEvent::ChatDeleted(10),
+            chat(10, 1),
+            ChatRoomEvent::ChatDegraded(10, false),
+            chat(20, 0),
+            chat(20, 0),
+        ] {
+            relay.push(event);
+        }
+        assert_eq!(relay.shed(), 3);
+        assert_eq!(
+            relay.queue.iter().map(brief).collect::<Vec<_>>(),
+            vec![
+                "post 1",
+                "offline 2",
+                "degraded 10 true",
+                // the first update may introduce the chat
+                "chat 10 5",
+                "online 3",
+                "post 2",
+                "deleted 10",
+                "chat 10 1",
+                "degraded 10 false",
+                "chat 20 0",
+            ]
+        );
+        assert_eq!(stats.pending.load(Ordering::Relaxed), 10);
+        assert_eq!(stats.shed.load(Ordering::Relaxed), 3);
+        // nothing to shed anymore
+        assert_eq!(relay.shed(), 0);
+        // the aborted relay does not count its events
+        drop(relay);
+        assert_eq!(stats.pending.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn stalled_ui_does_not_block_the_stream() {
+        let stats = Arc::new(RelayStats::default());
+        let mut relay = EventRelay::new("test", config(), stats.clone());
+        // the server is able to send only if the stream is read
+        let (tx_server, rx_server) = mpsc::channel::<Result<u64, tonic::Status>>(1);
+        let (tx_event, mut rx_event) = mpsc::channel(1);
+        let server = tokio::spawn(async move {
+            for i in 0..20 {
+                let message = if i % 2 == 0 { i } else { 1000 + i % 4 };
+                tx_server.send(Ok(message)).await.unwrap();
+            }
+        });
+        let reader = tokio::spawn(async move {
+            let stream = tokio_stream::wrappers::ReceiverStream::new(rx_server);
+            relay
+                .run(stream, &tx_event, |message, relay| {
+                    if message < 1000 {
+                        relay.push(post(message));
+                    } else {
+                        relay.push(ChatRoomEvent::UserEntered(message - 1000));
+                    }
+                })
+                .await
+                .unwrap();
+            relay
+        });
+        // nobody reads the events meanwhile
+        tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("the stream is read while the UI is stalled")
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while stats.shed.load(Ordering::Relaxed) == 0 {
+                tokio::time::sleep(TIMEOUT).await;
+            }
+        })
+        .await
+        .expect("superseded events are shed");
+        assert!(stats.stalls.load(Ordering::Relaxed) >= 2);
+        assert!(stats.stalled_pending().unwrap() > 0);
+        // the UI recovers
+        let mut received = Vec::new();
+        while let Some(event) = rx_event.recv().await {
+            if let ClientEvent::Client(event) = event {
+                received.push(brief(&event));
+            }
+        }
+        let relay = reader.await.unwrap();
+        assert_eq!(relay.level, 0);
+        assert!(stats.stalled_pending().is_none());
+        assert_eq!(stats.pending.load(Ordering::Relaxed), 0);
+        // all posts in order
+        assert_eq!(
+            received
+                .iter()
+                .filter(|e| e.starts_with("post"))
+                .cloned()
+                .collect::<Vec<_>>(),
+            (0..20)
+                .step_by(2)
+                .map(|i| format!("post {}", i))
+                .collect::<Vec<_>>()
+        );
+        // the presence of each user is delivered at least once
+        for id in &[1, 3] {
+            assert!(received.contains(&format!("online {}", id)));
+        }
+        assert!(received.iter().filter(|e| e.starts_with("online")).count() < 10);
+    }
+}
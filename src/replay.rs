@@ -0,0 +1,925 @@
+use crate::client_service::{ChatHistory, ChatRoomEvent, Command};
+use crate::proto::{self, AttachmentId, ChatId, PostId, UserId};
+use crate::ui::{self, App, ComposerLimits, FilterRule, KeyBindings, UserColors};
+use crate::Event;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+// must be bumped on any incompatible change of the recorded items
+pub const SCHEMA_REVISION: u32 = 1;
+// replaces every non-whitespace char of the scrubbed texts
+const SCRUB_CHAR: char = 'x';
+
+// client settings affecting the UI, replay starts with the recorded ones
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Session {
+    pub name: String,
+    pub short_name: String,
+    pub key_overrides: Vec<(String, Vec<String>)>,
+    pub composer_max_lines: usize,
+    pub composer_max_bytes: usize,
+    pub unsend_grace_secs: Option<u64>,
+    #[serde(default)]
+    pub late_delivery_secs: Option<u64>,
+    #[serde(default)]
+    pub language: String,
+    // "bell", "title" or "off" as set by the config
+    #[serde(default)]
+    pub notify: String,
+    // the authors are colored unless disabled, by the default palette if none
+    #[serde(default)]
+    pub user_colors: Option<bool>,
+    #[serde(default)]
+    pub palette: Vec<String>,
+    // rules hiding posts, those of the config are not changed by the user
+    #[serde(default)]
+    pub config_filters: Vec<String>,
+    #[serde(default)]
+    pub filters: Vec<String>,
+}
+
+impl Session {
+    pub fn key_bindings(&self) -> Result<KeyBindings, Box<dyn Error>> {
+        KeyBindings::with_overrides(&self.key_overrides)
+            .map_err(|e| format!("invalid key bindings, {}", e).into())
+    }
+
+    pub fn new_app(
+        &self,
+        tx_command: mpsc::Sender<Command>,
+        extended_log: bool,
+        keys: KeyBindings,
+    ) -> App {
+        let user = proto::UserInfo {
+            name: self.name.clone(),
+            short_name: self.short_name.clone(),
+        };
+        let mut app = App::new(user, tx_command, extended_log, keys);
+        app.composer_limits = ComposerLimits {
+            max_lines: self.composer_max_lines,
+            max_bytes: self.composer_max_bytes,
+        };
+        if let Some(secs) = self.unsend_grace_secs {
+            app.unsend_grace = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.late_delivery_secs {
+            app.late_delivery = Duration::from_secs(secs);
+        }
+        if !self.language.is_empty() {
+            match self.language.parse() {
+                Ok(language) => app.language = language,
+                Err(e) => warn!("{}, English is used", e),
+            }
+        }
+        if !self.notify.is_empty() {
+            match self.notify.parse() {
+                Ok(notify) => app.notify = notify,
+                Err(e) => warn!("{}, notifications are off", e),
+            }
+        }
+        app.user_colors = match (self.user_colors, parse_palette(&self.palette)) {
+            (Some(false), _) => UserColors::disabled(),
+            (_, palette) if palette.is_empty() => UserColors::default(),
+            (_, palette) => UserColors::new(palette),
+        };
+        app.set_filters(
+            parse_filters(&self.config_filters),
+            parse_filters(&self.filters),
+        );
+        app
+    }
+}
+
+fn parse_palette(names: &[String]) -> Vec<tui::style::Color> {
+    let mut palette = Vec::with_capacity(names.len());
+    for name in names {
+        match ui::parse_color(name) {
+            Ok(color) => palette.push(color),
+            Err(e) => warn!("{}, skipped in the palette", e),
+        }
+    }
+    palette
+}
+
+fn parse_filters(rules: &[String]) -> Vec<FilterRule> {
+    let mut filters = Vec::with_capacity(rules.len());
+    for rule in rules {
+        match rule.parse() {
+            Ok(rule) => filters.push(rule),
+            Err(e) => warn!("filter '{}' is ignored, {}", rule, e),
+        }
+    }
+    filters
+}
+
+// the first line of the file
+#[derive(Serialize, Deserialize)]
+struct Header {
+    schema: u32,
+    scrubbed: bool,
+    session: Session,
+}
+
+// mirrors of the events processed by the UI
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum RecordedKey {
+    Char(char),
+    Enter,
+    Esc,
+    Left,
+    Up,
+    Right,
+    Down,
+    Backspace,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct RecordedUser {
+    pub id: UserId,
+    pub name: String,
+    pub short_name: String,
+    pub created: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct RecordedChat {
+    pub id: ChatId,
+    pub permanent: bool,
+    pub description: String,
+    pub users: Vec<UserId>,
+    pub created: u64,
+    #[serde(default)]
+    pub owner_id: UserId,
+    #[serde(default)]
+    pub export_discouraged: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct RecordedInvitation {
+    pub chat_id: ChatId,
+    pub from_user_id: UserId,
+    pub to_user_id: UserId,
+    #[serde(default)]
+    pub declined: bool,
+}
+
+// the content of the attachments is not recorded
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct RecordedPost {
+    pub id: PostId,
+    pub chat_id: ChatId,
+    pub user_id: UserId,
+    pub text: String,
+    pub created: u64,
+    #[serde(default)]
+    pub author_name: String,
+    // emoji and the users reacted with it
+    #[serde(default)]
+    pub reactions: Vec<(String, Vec<UserId>)>,
+    #[serde(default)]
+    pub reply_to_post_id: PostId,
+    // the first author of the forwarded post and the name it was stamped with
+    #[serde(default)]
+    pub forwarded_from: Option<(UserId, String)>,
+    // id, name and size of every attachment
+    #[serde(default)]
+    pub attachments: Vec<(AttachmentId, String, u64)>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum RecordedEvent {
+    Key {
+        key: RecordedKey,
+        ctrl: bool,
+        alt: bool,
+    },
+    Paste(String),
+    // consecutive ticks are collapsed
+    Ticks(u32),
+    Registered(UserId),
+    UserInfo(RecordedUser),
+    UserEntered(UserId),
+    UserGone(UserId),
+    ChatUpdated(RecordedChat, usize),
+    ChatDeleted(ChatId),
+    ChatDegraded(ChatId, bool),
+    // the value of proto::Membership
+    Membership(ChatId, i32),
+    Typing(ChatId, UserId, bool),
+    ReadPosition(ChatId, PostId, usize),
+    Muted(ChatId, bool),
+    ChatLeft(ChatId),
+    Invitation(RecordedInvitation),
+    NewPost(RecordedPost),
+    History {
+        chat_id: ChatId,
+        idx_from: usize,
+        posts: Vec<RecordedPost>,
+    },
+    ChatMembers(ChatId, Vec<RecordedUser>),
+    Connected,
+    Disconnected,
+    ReconnectScheduled {
+        attempt: u32,
+        next_in_secs: u64,
+    },
+    ReconnectAttempt,
+    ReconnectFailed(String),
+    Stale(bool),
+    PostAccepted(ChatId, PostId),
+    PostFailed(ChatId, String),
+    // the post of the command failed every attempt
+    PostUndelivered(RecordedPost, String),
+    PostDeleted(ChatId, PostId),
+    SearchResults {
+        query: String,
+        posts: Vec<RecordedPost>,
+    },
+    Notice(String),
+    Exit,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Recorded {
+    // since the recording has started
+    pub at_ms: u64,
+    pub event: RecordedEvent,
+}
+
+impl From<&proto::User> for RecordedUser {
+    fn from(user: &proto::User) -> Self {
+        RecordedUser {
+            id: user.id,
+            name: user.name.clone(),
+            short_name: user.short_name.clone(),
+            created: user.created,
+        }
+    }
+}
+
+impl From<RecordedUser> for proto::User {
+    fn from(user: RecordedUser) -> Self {
+        proto::User {
+            id: user.id,
+            name: user.name,
+            short_name: user.short_name,
+            created: user.created,
+        }
+    }
+}
+
+impl From<&proto::Post> for RecordedPost {
+    fn from(post: &proto::Post) -> Self {
+        RecordedPost {
+            id: post.id,
+            chat_id: post.chat_id,
+            user_id: post.user_id,
+            text: post.text.clone(),
+            created: post.created,
+            author_name: post.author_name.clone(),
+            reactions: post
+                .reactions
+                .iter()
+                .map(|r| (r.emoji.clone(), r.user_ids.clone()))
+                .collect(),
+            reply_to_post_id: post.reply_to_post_id,
+            forwarded_from: post
+                .forwarded_from
+                .as_ref()
+                .map(|origin| (origin.user_id, origin.author_name.clone())),
+            attachments: post
+                .attachments
+                .iter()
+                .map(|a| (a.id, a.name.clone(), a.size))
+                .collect(),
+        }
+    }
+}
+
+impl From<RecordedPost> for proto::Post {
+    fn from(post: RecordedPost) -> Self {
+        proto::Post {
+            id: post.id,
+            chat_id: post.chat_id,
+            user_id: post.user_id,
+            text: post.text,
+            created: post.created,
+            author_name: post.author_name,
+            reactions: post
+                .reactions
+                .into_iter()
+                .map(|(emoji, user_ids)| proto::PostReaction { emoji, user_ids })
+                .collect(),
+            reply_to_post_id: post.reply_to_post_id,
+            forwarded_from: post.forwarded_from.map(|(user_id, author_name)| {
+                proto::ForwardedFrom {
+                    user_id,
+                    author_name,
+                    ..Default::default()
+                }
+            }),
+            attachments: post
+                .attachments
+                .into_iter()
+                .map(|(id, name, size)| proto::AttachmentInfo { id, name, size })
+                .collect(),
+            idempotency_key: String::new(),
+            seq: 0,
+            kind: proto::PostKind::Regular as i32,
+        }
+    }
+}
+
+impl RecordedEvent {
+    // keys and events ignored by the UI are not recorded
+    fn from_event(event: &Event) -> Option<Self> {
+        let recorded = match event {
+            Event::Input(key_event) => RecordedEvent::Key {
+                key: match key_event.code {
+                    KeyCode::Char(c) => RecordedKey::Char(c),
+                    KeyCode::Enter => RecordedKey::Enter,
+                    KeyCode::Esc => RecordedKey::Esc,
+                    KeyCode::Left => RecordedKey::Left,
+                    KeyCode::Up => RecordedKey::Up,
+                    KeyCode::Right => RecordedKey::Right,
+                    KeyCode::Down => RecordedKey::Down,
+                    KeyCode::Backspace => RecordedKey::Backspace,
+                    _ => return None,
+                },
+                ctrl: key_event.modifiers.contains(KeyModifiers::CONTROL),
+                alt: key_event.modifiers.contains(KeyModifiers::ALT),
+            },
+            Event::Paste(text) => RecordedEvent::Paste(text.clone()),
+            // the clicks depend on the size of the terminal
+            Event::Mouse(_) => return None,
+            Event::Tick => RecordedEvent::Ticks(1),
+            Event::Client(chat_event) => match chat_event {
+                ChatRoomEvent::Registered(user_id) => RecordedEvent::Registered(*user_id),
+                ChatRoomEvent::UserInfo(user) => RecordedEvent::UserInfo(user.into()),
+                ChatRoomEvent::UserEntered(user_id) => RecordedEvent::UserEntered(*user_id),
+                ChatRoomEvent::UserGone(user_id) => RecordedEvent::UserGone(*user_id),
+                ChatRoomEvent::ChatUpdated(chat, history_len) => RecordedEvent::ChatUpdated(
+                    RecordedChat {
+                        id: chat.id,
+                        permanent: chat.permanent,
+                        description: chat.description.clone(),
+                        users: chat.users.clone(),
+                        created: chat.created,
+                        owner_id: chat.owner_id,
+                        export_discouraged: chat.export_discouraged,
+                    },
+                    *history_len,
+                ),
+                ChatRoomEvent::ChatDeleted(chat_id) => RecordedEvent::ChatDeleted(*chat_id),
+                ChatRoomEvent::ChatLeft(chat_id) => RecordedEvent::ChatLeft(*chat_id),
+                ChatRoomEvent::ChatDegraded(chat_id, degraded) => {
+                    RecordedEvent::ChatDegraded(*chat_id, *degraded)
+                }
+                ChatRoomEvent::Membership(chat_id, membership) => {
+                    RecordedEvent::Membership(*chat_id, *membership as i32)
+                }
+                ChatRoomEvent::Typing(chat_id, user_id, typing) => {
+                    RecordedEvent::Typing(*chat_id, *user_id, *typing)
+                }
+                ChatRoomEvent::ReadPosition(chat_id, post_id, unread) => {
+                    RecordedEvent::ReadPosition(*chat_id, *post_id, *unread)
+                }
+                ChatRoomEvent::Muted(chat_id, muted) => RecordedEvent::Muted(*chat_id, *muted),
+                ChatRoomEvent::Invitation(invitation) => {
+                    RecordedEvent::Invitation(RecordedInvitation {
+                        chat_id: invitation.chat_id,
+                        from_user_id: invitation.from_user_id,
+                        to_user_id: invitation.to_user_id,
+                        declined: invitation.declined,
+                    })
+                }
+                ChatRoomEvent::NewPost(post) => RecordedEvent::NewPost(post.into()),
+                ChatRoomEvent::History(hist) => RecordedEvent::History {
+                    chat_id: hist.chat_id,
+                    idx_from: hist.idx_from,
+                    posts: hist.posts.iter().map(RecordedPost::from).collect(),
+                },
+                ChatRoomEvent::ChatMembers(chat_id, users) => RecordedEvent::ChatMembers(
+                    *chat_id,
+                    users.iter().map(RecordedUser::from).collect(),
+                ),
+                ChatRoomEvent::Connected => RecordedEvent::Connected,
+                ChatRoomEvent::Disconnected => RecordedEvent::Disconnected,
+                ChatRoomEvent::ReconnectScheduled {
+                    attempt,
+                    next_in_secs,
+                } => RecordedEvent::ReconnectScheduled {
+                    attempt: *attempt,
+                    next_in_secs: *next_in_secs,
+                },
+                ChatRoomEvent::ReconnectAttempt => RecordedEvent::ReconnectAttempt,
+                ChatRoomEvent::ReconnectFailed { error } => {
+                    RecordedEvent::ReconnectFailed(error.clone())
+                }
+                ChatRoomEvent::Stale(stale) => RecordedEvent::Stale(*stale),
+                ChatRoomEvent::PostAccepted(chat_id, post_id) => {
+                    RecordedEvent::PostAccepted(*chat_id, *post_id)
+                }
+                ChatRoomEvent::PostFailed(chat_id, text) => {
+                    RecordedEvent::PostFailed(*chat_id, text.clone())
+                }
+                ChatRoomEvent::PostDeleted(chat_id, post_id) => {
+                    RecordedEvent::PostDeleted(*chat_id, *post_id)
+                }
+                ChatRoomEvent::CommandFailed(Command::Post(post), text) => {
+                    RecordedEvent::PostUndelivered(post.into(), text.clone())
+                }
+                // replayed as the notice it is shown as
+                ChatRoomEvent::CommandFailed(_, text) => RecordedEvent::Notice(text.clone()),
+                ChatRoomEvent::SearchResults(query, posts) => RecordedEvent::SearchResults {
+                    query: query.clone(),
+                    posts: posts.iter().map(RecordedPost::from).collect(),
+                },
+                ChatRoomEvent::Notice(text) => RecordedEvent::Notice(text.clone()),
+                // replayed as the notice it is shown as
+                ChatRoomEvent::Error(e) => RecordedEvent::Notice(e.to_string()),
+            },
+            Event::Exit => RecordedEvent::Exit,
+        };
+        Some(recorded)
+    }
+
+    // replaces post texts keeping their layout
+    fn scrub(&mut self) {
+        match self {
+            RecordedEvent::Paste(text) => *text = scrub_text(text),
+            RecordedEvent::NewPost(post) => post.text = scrub_text(&post.text),
+            RecordedEvent::History { posts, .. } => {
+                for post in posts {
+                    post.text = scrub_text(&post.text);
+                }
+            }
+            RecordedEvent::SearchResults { query, posts } => {
+                *query = scrub_text(query);
+                for post in posts {
+                    post.text = scrub_text(&post.text);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn into_events(self) -> Vec<Event> {
+        let client = |chat_event| vec![Event::Client(chat_event)];
+        match self {
+            RecordedEvent::Key { key, ctrl, alt } => {
+                let code = match key {
+                    RecordedKey::Char(c) => KeyCode::Char(c),
+                    RecordedKey::Enter => KeyCode::Enter,
+                    RecordedKey::Esc => KeyCode::Esc,
+                    RecordedKey::Left => KeyCode::Left,
+                    RecordedKey::Up => KeyCode::Up,
+                    RecordedKey::Right => KeyCode::Right,
+                    RecordedKey::Down => KeyCode::Down,
+                    RecordedKey::Backspace => KeyCode::Backspace,
+                };
+                let mut modifiers = KeyModifiers::NONE;
+                modifiers.set(KeyModifiers::CONTROL, ctrl);
+                modifiers.set(KeyModifiers::ALT, alt);
+                vec![Event::Input(KeyEvent::new(code, modifiers))]
+            }
+            RecordedEvent::Paste(text) => vec![Event::Paste(text)],
+            RecordedEvent::Ticks(count) => (0..count).map(|_| Event::Tick).collect(),
+            RecordedEvent::Registered(user_id) => client(ChatRoomEvent::Registered(user_id)),
+            RecordedEvent::UserInfo(user) => client(ChatRoomEvent::UserInfo(user.into())),
+            RecordedEvent::UserEntered(user_id) => client(ChatRoomEvent::UserEntered(user_id)),
+            RecordedEvent::UserGone(user_id) => client(ChatRoomEvent::UserGone(user_id)),
+            RecordedEvent::ChatUpdated(chat, history_len) => client(ChatRoomEvent::ChatUpdated(
+                proto::Chat {
+                    id: chat.id,
+                    permanent: chat.permanent,
+                    description: chat.description,
+                    users: chat.users,
+                    created: chat.created,
+                    owner_id: chat.owner_id,
+                    export_discouraged: chat.export_discouraged,
+                },
+                history_len,
+            )),
+            RecordedEvent::ChatDeleted(chat_id) => client(ChatRoomEvent::ChatDeleted(chat_id)),
+            RecordedEvent::ChatLeft(chat_id) => client(ChatRoomEvent::ChatLeft(chat_id)),
+            RecordedEvent::ChatDegraded(chat_id, degraded) => {
+                client(ChatRoomEvent::ChatDegraded(chat_id, degraded))
+            }
+            RecordedEvent::Membership(chat_id, membership) => client(ChatRoomEvent::Membership(
+                chat_id,
+                proto::Membership::from_i32(membership).unwrap_or(proto::Membership::None),
+            )),
+            RecordedEvent::Typing(chat_id, user_id, typing) => {
+                client(ChatRoomEvent::Typing(chat_id, user_id, typing))
+            }
+            RecordedEvent::ReadPosition(chat_id, post_id, unread) => {
+                client(ChatRoomEvent::ReadPosition(chat_id, post_id, unread))
+            }
+            RecordedEvent::Muted(chat_id, muted) => client(ChatRoomEvent::Muted(chat_id, muted)),
+            RecordedEvent::Invitation(invitation) => {
+                client(ChatRoomEvent::Invitation(proto::Invitation {
+                    chat_id: invitation.chat_id,
+                    from_user_id: invitation.from_user_id,
+                    to_user_id: invitation.to_user_id,
+                    declined: invitation.declined,
+                }))
+            }
+            RecordedEvent::NewPost(post) => client(ChatRoomEvent::NewPost(post.into())),
+            RecordedEvent::History {
+                chat_id,
+                idx_from,
+                posts,
+            } => client(ChatRoomEvent::History(ChatHistory {
+                chat_id,
+                idx_from,
+                posts: posts.into_iter().map(proto::Post::from).collect(),
+            })),
+            RecordedEvent::ChatMembers(chat_id, users) => client(ChatRoomEvent::ChatMembers(
+                chat_id,
+                users.into_iter().map(proto::User::from).collect(),
+            )),
+            RecordedEvent::Connected => client(ChatRoomEvent::Connected),
+            RecordedEvent::Disconnected => client(ChatRoomEvent::Disconnected),
+            RecordedEvent::ReconnectScheduled {
+                attempt,
+                next_in_secs,
+            } => client(ChatRoomEvent::ReconnectScheduled {
+                attempt,
+                next_in_secs,
+            }),
+            RecordedEvent::ReconnectAttempt => client(ChatRoomEvent::ReconnectAttempt),
+            RecordedEvent::ReconnectFailed(error) => {
+                client(ChatRoomEvent::ReconnectFailed { error })
+            }
+            RecordedEvent::Stale(stale) => client(ChatRoomEvent::Stale(stale)),
+            RecordedEvent::PostAccepted(chat_id, post_id) => {
+                client(ChatRoomEvent::PostAccepted(chat_id, post_id))
+            }
+            RecordedEvent::PostFailed(chat_id, text) => {
+                client(ChatRoomEvent::PostFailed(chat_id, text))
+            }
+            RecordedEvent::PostDeleted(chat_id, post_id) => {
+                client(ChatRoomEvent::PostDeleted(chat_id, post_id))
+            }
+            RecordedEvent::PostUndelivered(post, text) => client(ChatRoomEvent::CommandFailed(
+                Command::Post(post.into()),
+                text,
+            )),
+            RecordedEvent::SearchResults { query, posts } => client(ChatRoomEvent::SearchResults(
+                query,
+                posts.into_iter().map(proto::Post::from).collect(),
+            )),
+            RecordedEvent::Notice(text) => client(ChatRoomEvent::Notice(text)),
+            RecordedEvent::Exit => vec![Event::Exit],
+        }
+    }
+}
+
+// length preserving placeholder, whitespaces are kept
+fn scrub_text(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_whitespace() { c } else { SCRUB_CHAR })
+        .collect()
+}
+
+// appends the processed events to the file as json lines
+pub struct Recorder {
+    out: BufWriter<File>,
+    started: Instant,
+    scrub: bool,
+    // ticks not written yet and the time of the last one
+    ticks: u32,
+    ticks_at: u64,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P, session: &Session, scrub: bool) -> io::Result<Self> {
+        let mut recorder = Recorder {
+            out: BufWriter::new(File::create(path)?),
+            started: Instant::now(),
+            scrub,
+            ticks: 0,
+            ticks_at: 0,
+        };
+        recorder.write(&Header {
+            schema: SCHEMA_REVISION,
+            scrubbed: scrub,
+            session: session.clone(),
+        })?;
+        Ok(recorder)
+    }
+
+    pub fn record(&mut self, event: &Event) -> io::Result<()> {
+        let at_ms = self.started.elapsed().as_millis() as u64;
+        match RecordedEvent::from_event(event) {
+            Some(RecordedEvent::Ticks(count)) => {
+                self.ticks += count;
+                self.ticks_at = at_ms;
+                Ok(())
+            }
+            Some(mut event) => {
+                self.flush_ticks()?;
+                if self.scrub {
+                    event.scrub();
+                }
+                self.write(&Recorded { at_ms, event })
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush_ticks()?;
+        self.out.flush()
+    }
+
+    fn flush_ticks(&mut self) -> io::Result<()> {
+        if self.ticks > 0 {
+            let ticks = Recorded {
+                at_ms: self.ticks_at,
+                event: RecordedEvent::Ticks(self.ticks),
+            };
+            self.ticks = 0;
+            self.write(&ticks)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write<T: Serialize>(&mut self, item: &T) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, item)?;
+        self.out.write_all(b"\n")
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    // line number, description
+    Format(usize, String),
+    // revision of the file
+    Schema(u32),
+}
+
+impl Error for ReplayError {}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(e) => write!(f, "{}", e),
+            ReplayError::Format(line, text) => write!(f, "line {}: {}", line, text),
+            ReplayError::Schema(schema) => write!(
+                f,
+                "schema revision {} is not supported, expected {}",
+                schema, SCHEMA_REVISION
+            ),
+        }
+    }
+}
+
+// reads the recorded session and its events
+pub fn load<P: AsRef<Path>>(path: P) -> Result<(Session, Vec<Recorded>), ReplayError> {
+    let file = File::open(path).map_err(ReplayError::Io)?;
+    let mut lines = BufReader::new(file).lines();
+    let header: Header = match lines.next() {
+        Some(line) => {
+            let line = line.map_err(ReplayError::Io)?;
+            // revision is checked before the rest of the header
+            let value: serde_json::Value = serde_json::from_str(&line)
+                .map_err(|e| ReplayError::Format(1, format!("{}", e)))?;
+            match value.get("schema").and_then(|v| v.as_u64()) {
+                Some(schema) if schema == SCHEMA_REVISION as u64 => {}
+                Some(schema) => return Err(ReplayError::Schema(schema as u32)),
+                None => {
+                    return Err(ReplayError::Format(1, String::from("no schema revision")));
+                }
+            }
+            serde_json::from_value(value).map_err(|e| ReplayError::Format(1, format!("{}", e)))?
+        }
+        None => return Err(ReplayError::Format(1, String::from("empty file"))),
+    };
+    let mut recorded = Vec::new();
+    for (idx, line) in lines.enumerate() {
+        let line = line.map_err(ReplayError::Io)?;
+        let item = serde_json::from_str(&line)
+            .map_err(|e| ReplayError::Format(idx + 2, format!("{}", e)))?;
+        recorded.push(item);
+    }
+    Ok((header.session, recorded))
+}
+
+// feeds the recorded events to the app keeping their pace accelerated by `speed`,
+// zero speed replays as fast as possible; stops at the recorded exit
+pub fn replay<F: FnMut(&mut App)>(app: &mut App, recorded: Vec<Recorded>, speed: f64, mut draw: F) {
+    let started = Instant::now();
+    for item in recorded {
+        if speed > 0.0 {
+            let due = Duration::from_secs_f64(item.at_ms as f64 / 1000.0 / speed);
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        for event in item.event.into_events() {
+            if !crate::handle_event(app, event) {
+                return;
+            }
+            draw(app);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_FILE: &str = "migchat-test-replay.jsonl";
+    const TEST_FILE_PLAIN: &str = "migchat-test-replay-plain.jsonl";
+    const TEST_FILE_SCRUBBED: &str = "migchat-test-replay-scrubbed.jsonl";
+    const TEST_FILE_SCHEMA: &str = "migchat-test-replay-schema.jsonl";
+
+    fn test_session() -> Session {
+        Session {
+            name: String::from("User Name"),
+            short_name: String::from("user"),
+            key_overrides: Vec::new(),
+            composer_max_lines: 50,
+            composer_max_bytes: 4096,
+            unsend_grace_secs: None,
+            late_delivery_secs: None,
+            language: String::new(),
+            notify: String::new(),
+            user_colors: None,
+            palette: Vec::new(),
+            config_filters: Vec::new(),
+            filters: Vec::new(),
+        }
+    }
+
+    // the commands are dropped as there is no server
+    fn test_app(session: &Session) -> App {
+        let (tx_command, mut rx_command) = mpsc::channel(16);
+        thread::spawn(move || while rx_command.blocking_recv().is_some() {});
+        session.new_app(tx_command, false, session.key_bindings().unwrap())
+    }
+
+    fn key(c: char) -> Event {
+        Event::Input(KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE))
+    }
+
+    fn post(id: PostId, chat_id: ChatId, user_id: UserId, text: &str) -> proto::Post {
+        proto::Post {
+            id,
+            chat_id,
+            user_id,
+            text: String::from(text),
+            ..Default::default()
+        }
+    }
+
+    fn scripted_session() -> Vec<Event> {
+        let chat = |id, description: &str| proto::Chat {
+            id,
+            description: String::from(description),
+            users: vec![1, 2],
+            ..Default::default()
+        };
+        vec![
+            Event::Client(ChatRoomEvent::Connected),
+            Event::Client(ChatRoomEvent::Registered(1)),
+            Event::Client(ChatRoomEvent::UserInfo(proto::User {
+                id: 2,
+                name: String::from("Other"),
+                short_name: String::from("other"),
+                created: 0,
+            })),
+            Event::Client(ChatRoomEvent::UserEntered(2)),
+            Event::Client(ChatRoomEvent::ChatUpdated(chat(20, "second"), 0)),
+            Event::Client(ChatRoomEvent::Membership(20, proto::Membership::Member)),
+            Event::Client(ChatRoomEvent::ChatUpdated(chat(10, "first"), 1)),
+            Event::Client(ChatRoomEvent::Membership(10, proto::Membership::Member)),
+            Event::Tick,
+            Event::Tick,
+            Event::Tick,
+            Event::Client(ChatRoomEvent::NewPost(post(101, 20, 2, "hi\nthere"))),
+            Event::Input(KeyEvent::new(KeyCode::Down, KeyModifiers::NONE)),
+            Event::Input(KeyEvent::new(KeyCode::Down, KeyModifiers::NONE)),
+            Event::Client(ChatRoomEvent::History(ChatHistory {
+                chat_id: 10,
+                idx_from: 0,
+                posts: vec![post(100, 10, 2, "old one")],
+            })),
+            key('p'),
+            Event::Paste(String::from("pasted\ttext")),
+            Event::Input(KeyEvent::new(KeyCode::Backspace, KeyModifiers::NONE)),
+            Event::Input(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)),
+            Event::Tick,
+            Event::Client(ChatRoomEvent::PostAccepted(20, 102)),
+            Event::Client(ChatRoomEvent::NewPost(post(102, 20, 1, "pasted\ttex"))),
+            Event::Client(ChatRoomEvent::Disconnected),
+            Event::Client(ChatRoomEvent::Notice(String::from(
+                "post: server is unavailable",
+            ))),
+            Event::Exit,
+            // never reached
+            Event::Client(ChatRoomEvent::ChatDeleted(10)),
+        ]
+    }
+
+    // runs the session live and records it
+    fn record_session(path: &str, scrub: bool) -> String {
+        let session = test_session();
+        let mut app = test_app(&session);
+        let mut recorder = Recorder::create(path, &session, scrub).unwrap();
+        for event in scripted_session() {
+            recorder.record(&event).unwrap();
+            if !crate::handle_event(&mut app, event) {
+                break;
+            }
+        }
+        recorder.finish().unwrap();
+        app.state_dump()
+    }
+
+    fn replay_file(path: &str) -> String {
+        let (session, recorded) = load(path).unwrap();
+        let mut app = test_app(&session);
+        replay(&mut app, recorded, 0.0, |_| {});
+        app.state_dump()
+    }
+
+    #[test]
+    fn record_replay_round_trip() {
+        let live = record_session(TEST_FILE, false);
+        assert!(live.contains("chat 20"));
+        assert_eq!(replay_file(TEST_FILE), live);
+        let (session, recorded) = load(TEST_FILE).unwrap();
+        assert_eq!(session, test_session());
+        // ticks are collapsed, the events after exit are not recorded
+        assert_eq!(recorded[8].event, RecordedEvent::Ticks(3));
+        assert_eq!(recorded.len(), scripted_session().len() - 3);
+        assert_eq!(recorded.last().unwrap().event, RecordedEvent::Exit);
+        let _ = std::fs::remove_file(TEST_FILE);
+    }
+
+    #[test]
+    fn scrubbing_preserves_structure() {
+        record_session(TEST_FILE_PLAIN, false);
+        record_session(TEST_FILE_SCRUBBED, true);
+        let (_, plain) = load(TEST_FILE_PLAIN).unwrap();
+        let (_, scrubbed) = load(TEST_FILE_SCRUBBED).unwrap();
+        assert_eq!(plain.len(), scrubbed.len());
+        for (plain, scrubbed) in plain.into_iter().zip(scrubbed.into_iter()) {
+            let mut expected = plain.event.clone();
+            expected.scrub();
+            assert_eq!(scrubbed.event, expected);
+            if let (RecordedEvent::NewPost(plain), RecordedEvent::NewPost(scrubbed)) =
+                (&plain.event, &scrubbed.event)
+            {
+                assert_ne!(plain.text, scrubbed.text);
+                assert_eq!(plain.text.chars().count(), scrubbed.text.chars().count());
+            }
+        }
+        assert_eq!(scrub_text("hi\nthere ж"), "xx\nxxxxx x");
+        // the scrubbed session replays to the same structure
+        let dump = replay_file(TEST_FILE_SCRUBBED);
+        assert!(dump.contains("post 101 by 2: \"xx\\nxxxxx\""));
+        assert_eq!(
+            dump.lines().count(),
+            replay_file(TEST_FILE_PLAIN).lines().count()
+        );
+        let _ = std::fs::remove_file(TEST_FILE_PLAIN);
+        let _ = std::fs::remove_file(TEST_FILE_SCRUBBED);
+    }
+
+    #[test]
+    fn incompatible_schema() {
+        std::fs::write(
+            TEST_FILE_SCHEMA,
+            format!("{{\"schema\":{}}}\n", SCHEMA_REVISION + 1),
+        )
+        .unwrap();
+        assert!(matches!(
+            load(TEST_FILE_SCHEMA),
+            Err(ReplayError::Schema(schema)) if schema == SCHEMA_REVISION + 1
+        ));
+        std::fs::write(TEST_FILE_SCHEMA, "{}\n").unwrap();
+        assert!(matches!(
+            load(TEST_FILE_SCHEMA),
+            Err(ReplayError::Format(1, _))
+        ));
+        let _ = std::fs::remove_file(TEST_FILE_SCHEMA);
+    }
+}
@@ -1,34 +1,134 @@
-use clap::{App, Arg};
-use config::{Config, Environment, File};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use env_logger::{fmt::TimestampPrecision, Builder, Env, Target};
+use futures::future::{self, Future};
 use log::{debug, error, info, warn};
 use std::{
-    collections::{HashMap, HashSet},
-    fmt,
+    collections::HashMap,
+    fmt, fs,
+    io::{BufReader, BufWriter, Write},
+    net::SocketAddr,
     path::Path,
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
-use tokio::sync::mpsc;
-use tonic::transport::Server;
+use tokio::{
+    net::TcpListener,
+    signal,
+    sync::{broadcast, mpsc, watch},
+};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{
+    transport::{NamedService, Server, ServerTlsConfig},
+    Request, Status,
+};
+use tonic_health::ServingStatus;
+
+use migchat_core::{post_limits, proto};
 
-mod proto;
 mod storage;
 
+use proto::chat_room_admin_server::ChatRoomAdminServer;
 use proto::chat_room_service_server::ChatRoomServiceServer;
-pub use proto::{Chat, ChatId, User, UserId};
-use proto::{Invitation, Post};
-use storage::Storage;
+use proto::{
+    bearer_token, Invitation, Post, PostKind, TypingEvent, AUTHORIZATION_KEY, RETRY_AFTER_KEY,
+};
+pub use proto::{Chat, ChatId, PostId, User, UserId};
+use storage::{sqlite::SqliteStorage, ChatStorage, Storage};
 
 type InternalError = Box<dyn std::error::Error + Send + Sync + 'static>;
 
+mod admin_service;
+mod backup;
+mod dedup;
+mod error;
+#[cfg(test)]
+mod fixtures;
+mod health;
+mod presence;
+mod proxy;
+mod rate_limit;
 mod server_service;
+mod settings;
+mod snapshot;
+mod systemd;
+mod timeline;
+mod tls;
+mod typing;
+mod verifier;
+
+use dedup::AcceptedPosts;
+use error::ServerError;
+use presence::Presence;
+use proxy::{ClientIdentity, ProxyFilter};
+use rate_limit::{Action, RateKey, RateLimiter};
+use settings::{ServerSettings, Tunables};
+use systemd::{Notifier, State};
+use typing::{Recipients, Typing};
 
 const APP_NAME: &str = "migchat-server";
 const CONFIG: &str = "config";
 const CONFIG_DEF: &str = "migchat-server.toml";
 const CONFIG_ENV: &str = "MIGSRV";
-const DEF_ENDPOINT: &str = "0.0.0.0:50051";
-const DEF_DB_FILE: &str = "migchat_server.db";
+const REPLAY_CHAT: &str = "replay-chat";
+const EXPORT: &str = "export";
+const IMPORT: &str = "import";
+const FORCE: &str = "force";
+const DEF_MAX_DESCRIPTION_LEN: usize = 128;
+const DEF_MAX_CHAT_MEMBERS: usize = 256;
+const DEF_BULK_FETCH_POSTS: usize = 500;
+const DEF_STAMP_AUTHOR_NAMES: bool = true;
+const DEF_SYSTEM_POSTS: bool = true;
+const DEF_MAX_ATTACHMENT_SIZE: usize = 8 * 1024 * 1024;
+// notifications kept for the slowest stream before it starts skipping them
+const NOTIFICATIONS_CAPACITY: usize = 64;
+
+// tunable limits applied to the incoming requests
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    // max length of the chat description in chars
+    pub max_description_len: usize,
+    // max count of users in a single chat
+    pub max_chat_members: usize,
+    // history fetches of more posts are recorded as possible exports
+    pub bulk_fetch_posts: usize,
+    // new posts keep the short name of the author at the moment, a few bytes per post
+    pub stamp_author_names: bool,
+    // the chats get the posts of the server telling who entered and left them
+    pub system_posts: bool,
+    // max size of the uploaded attachment in bytes, the upload is collected in memory
+    pub max_attachment_size: usize,
+    // max size of the post text in bytes, its line endings normalized
+    pub max_post_bytes: usize,
+    // max count of the files attached to a single post
+    pub max_attachments: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_description_len: DEF_MAX_DESCRIPTION_LEN,
+            max_chat_members: DEF_MAX_CHAT_MEMBERS,
+            bulk_fetch_posts: DEF_BULK_FETCH_POSTS,
+            stamp_author_names: DEF_STAMP_AUTHOR_NAMES,
+            system_posts: DEF_SYSTEM_POSTS,
+            max_attachment_size: DEF_MAX_ATTACHMENT_SIZE,
+            max_post_bytes: post_limits::DEF_MAX_POST_BYTES,
+            max_attachments: post_limits::DEF_MAX_ATTACHMENTS,
+        }
+    }
+}
+
+impl Limits {
+    fn post_limits(&self) -> post_limits::PostLimits {
+        post_limits::PostLimits {
+            max_bytes: self.max_post_bytes,
+            max_attachments: self.max_attachments,
+        }
+    }
+}
 
 #[derive(Clone)]
 enum UserChanged {
@@ -39,186 +139,744 @@ enum UserChanged {
 
 #[derive(Clone)]
 enum ChatChanged {
-    Updated(Arc<Chat>),
+    // chat, the count of its posts, whether its posts are damaged
+    Updated(Arc<Chat>, u64, bool),
+    // the chat turned private, it is gone for those who are not its members
+    Hidden(Arc<Chat>),
+    // the invitation of the user to the chat is pending or answered
+    Invited(ChatId, UserId, bool),
+    // the user has muted or unmuted the chat
+    Muted(ChatId, UserId, bool),
+    // the member is removed by the owner, the chat is gone for the member
+    Removed(ChatId, UserId),
     Closed(ChatId),
 }
 
-pub struct ChatRoomImpl {
-    storage: Storage,
-    // new users:
-    users_listeners: RwLock<HashMap<UserId, mpsc::Sender<UserChanged>>>,
-    // new invitations:
-    invitations_listeners: RwLock<HashMap<UserId, mpsc::Sender<Invitation>>>,
-    // new chats:
-    chats_listeners: RwLock<HashMap<UserId, mpsc::Sender<ChatChanged>>>,
-    // new posts:
-    posts_listeners: RwLock<HashMap<UserId, mpsc::Sender<Arc<Post>>>>,
-    // users statuses, stores online users:
-    online_users: RwLock<HashSet<UserId>>,
+#[derive(Clone)]
+struct PostNotification {
+    post: Arc<Post>,
+    // chat members at the moment of posting
+    recipients: Arc<Vec<UserId>>,
+}
+
+#[derive(Clone)]
+struct TypingNotification {
+    event: TypingEvent,
+    // chat members at the moment the typing has started, the typing one is skipped
+    recipients: Recipients,
+}
+
+pub struct ChatRoomImpl<S: ChatStorage = Storage> {
+    storage: S,
+    // replaced on reload, read by every request
+    tunables: RwLock<Tunables>,
+    // notifications, every stream subscribes to the appropriate one:
+    users_events: broadcast::Sender<UserChanged>,
+    chats_events: broadcast::Sender<ChatChanged>,
+    posts_events: broadcast::Sender<PostNotification>,
+    typing_events: broadcast::Sender<TypingNotification>,
+    // new invitations, every session of the user gets them:
+    invitations_listeners: RwLock<HashMap<UserId, HashMap<String, mpsc::Sender<Invitation>>>>,
+    // active sessions by their tokens, dropping the sender stops all streams of the session:
+    sessions: RwLock<HashMap<String, (watch::Sender<()>, watch::Receiver<()>)>>,
+    // tokens minted by register, one per session, the other calls are made on behalf of
+    // their users; the tokens not used for long expire with their sessions:
+    tokens: RwLock<HashMap<String, (UserId, Instant)>>,
+    // no more streams are accepted after shutdown:
+    stopped: AtomicBool,
+    // users statuses, the silent users go offline:
+    presence: Arc<Presence>,
+    // typing users, never stored:
+    typing: Typing,
+    // posts and invitations of every user, never stored:
+    rate_limiter: RateLimiter,
+    // posts by the idempotency keys of their authors, never stored:
+    accepted_posts: AcceptedPosts,
+    // reactions are toggled one at a time, each reads the post and writes it back:
+    reacting: Mutex<()>,
+    // storage snapshots are taken one at a time:
+    snapshotting: Mutex<()>,
 }
 
 impl ChatRoomImpl {
-    fn new<P: AsRef<Path>>(db_file: P) -> Result<Self, InternalError> {
-        let storage = Storage::new(db_file)?;
-        Ok(Self {
+    fn new<P: AsRef<Path>>(db_file: P, limits: Limits) -> Result<Self, ServerError> {
+        Ok(Self::with_storage(Storage::new(db_file)?, limits))
+    }
+}
+
+impl<S: ChatStorage> ChatRoomImpl<S> {
+    fn with_storage(storage: S, limits: Limits) -> Self {
+        Self {
             storage,
-            // chats: RwLock::new(HashMap::new()),
-            users_listeners: RwLock::new(HashMap::new()),
+            tunables: RwLock::new(Tunables {
+                limits,
+                ..Tunables::default()
+            }),
+            users_events: broadcast::channel(NOTIFICATIONS_CAPACITY).0,
+            chats_events: broadcast::channel(NOTIFICATIONS_CAPACITY).0,
+            posts_events: broadcast::channel(NOTIFICATIONS_CAPACITY).0,
+            typing_events: broadcast::channel(NOTIFICATIONS_CAPACITY).0,
             invitations_listeners: RwLock::new(HashMap::new()),
-            chats_listeners: RwLock::new(HashMap::new()),
-            posts_listeners: RwLock::new(HashMap::new()),
-            online_users: RwLock::new(HashSet::new()),
-        })
+            sessions: RwLock::new(HashMap::new()),
+            tokens: RwLock::new(HashMap::new()),
+            stopped: AtomicBool::new(false),
+            presence: Arc::new(Presence::default()),
+            typing: Typing::default(),
+            rate_limiter: RateLimiter::default(),
+            accepted_posts: AcceptedPosts::default(),
+            reacting: Mutex::new(()),
+            snapshotting: Mutex::new(()),
+        }
     }
 
-    fn actualize_chat_listeners(&self) {
-        if let Ok(mut listeners) = self.chats_listeners.write() {
-            let before = listeners.len();
-            listeners.retain(|_k, v| !v.is_closed());
-            let removed = before - listeners.len();
-            if removed > 0 {
-                info!(
-                    "{} outdated chat listener(s) was/were found and removed",
-                    removed
-                );
+    fn tunables(&self) -> Tunables {
+        match self.tunables.read() {
+            Ok(tunables) => tunables.clone(),
+            Err(_) => {
+                error!("failed locking tunables, use defaults");
+                Tunables::default()
             }
         }
     }
 
-    // returns true if all listeners were notified, otherwise if at least one
-    // failed to notify returns false
-    // call to actualize_chat_listeners() is recommended if the method returns false
-    async fn notify_chat_changed(&self, notification: ChatChanged) -> bool {
-        let mut send_list = Vec::new();
-        if let Ok(listeners) = self.chats_listeners.read() {
-            for listener in listeners.values() {
-                send_list.push(listener.clone());
-            }
+    fn reconfigure(&self, tunables: Tunables) {
+        match self.tunables.write() {
+            Ok(mut current) => *current = tunables,
+            Err(_) => error!("failed locking tunables"),
+        }
+    }
+
+    fn ensure_running(&self) -> Result<(), tonic::Status> {
+        if self.stopped.load(Ordering::Relaxed) {
+            Err(tonic::Status::unavailable("server is shutting down"))
+        } else {
+            Ok(())
+        }
+    }
+
+    // returns the stop signal of the streams of the request's session, starts it if required
+    fn session<T>(&self, request: &Request<T>) -> Result<watch::Receiver<()>, tonic::Status> {
+        self.ensure_running()?;
+        let token = session_token(request)?;
+        if let Ok(mut sessions) = self.sessions.write() {
+            Ok(sessions
+                .entry(String::from(token))
+                .or_insert_with(|| watch::channel(()))
+                .1
+                .clone())
+        } else {
+            Err(tonic::Status::internal("no access to sessions"))
+        }
+    }
+
+    // the token the user is to bring in the following calls
+    fn new_token(&self, user_id: UserId) -> Result<String, Status> {
+        let token = format!("{:032x}", rand::random::<u128>());
+        if let Ok(mut tokens) = self.tokens.write() {
+            tokens.insert(token.clone(), (user_id, Instant::now()));
+            Ok(token)
+        } else {
+            Err(Status::internal("no access to session tokens"))
         }
-        if !send_list.is_empty() {
-            let mut fails = false;
-            for tx in send_list {
-                if let Err(e) = tx.send(notification.clone()).await {
-                    error!("failed to broadcast new chat: {}", e);
-                    fails = true;
+    }
+
+    // the user the token of the request has been minted for, every use keeps it from expiring
+    fn authenticate<T>(&self, request: &Request<T>) -> Result<UserId, Status> {
+        let token = session_token(request)?;
+        let user_id = match self.tokens.write() {
+            Ok(mut tokens) => match tokens.get_mut(token) {
+                Some((user_id, used)) => {
+                    *used = Instant::now();
+                    *user_id
                 }
-            }
-            !fails
+                None => return Err(Status::unauthenticated("unknown or expired session token")),
+            },
+            Err(_) => return Err(Status::internal("no access to session tokens")),
+        };
+        self.touch(user_id);
+        Ok(user_id)
+    }
+
+    // every call of the user keeps it online, the one gone offline is back
+    fn touch(&self, user_id: UserId) {
+        if self.presence.touch(user_id, Instant::now()) {
+            debug!("{} is back online", user_id);
+            self.notify_user_changed(UserChanged::Online(user_id));
+        }
+    }
+
+    // the user goes offline with the last of its streams
+    fn open_stream(&self, user_id: UserId) -> StreamGuard {
+        self.presence.stream_opened(user_id);
+        StreamGuard {
+            user_id,
+            presence: self.presence.clone(),
+            users_events: self.users_events.clone(),
+        }
+    }
+
+    // the request is made on behalf of the user it claims
+    fn authorize<T>(&self, request: &Request<T>, user_id: UserId) -> Result<(), Status> {
+        let authenticated = self.authenticate(request)?;
+        if authenticated == user_id {
+            Ok(())
         } else {
-            true
+            warn!(
+                "user {} acts on behalf of {} from {}",
+                authenticated,
+                user_id,
+                ClientIdentity::of(request)
+            );
+            Err(Status::permission_denied("session token of another user"))
         }
     }
 
-    fn actualize_user_listeners(&self) {
-        if let Ok(mut listeners) = self.users_listeners.write() {
-            let before = listeners.len();
-            listeners.retain(|_k, v| !v.is_closed());
-            let removed = before - listeners.len();
-            if removed > 0 {
-                info!(
-                    "{} outdated user listener(s) was/were found and removed",
-                    removed
+    // the call is counted against the rate limit of the client behind the trusted proxy,
+    // of the user otherwise
+    fn limit_rate(
+        &self,
+        action: Action,
+        user_id: UserId,
+        client: &ClientIdentity,
+    ) -> Result<(), Status> {
+        let per_minute = self.tunables().rate.per_minute(action);
+        // the users vouched for by the proxy are told apart behind the same address
+        let key = match client.forwarded_addr() {
+            Some(addr) if client.verified_user.is_none() => RateKey::Addr(String::from(addr)),
+            _ => RateKey::User(user_id),
+        };
+        match self
+            .rate_limiter
+            .take(action, key.clone(), per_minute, Instant::now())
+        {
+            Ok(()) => Ok(()),
+            Err(wait) => {
+                // whole seconds, the client retrying at once is refused again
+                let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                debug!(
+                    "{} exceeds {:?} rate limit as user {}",
+                    key, action, user_id
                 );
+                let mut status = Status::resource_exhausted(format!(
+                    "rate limit of {} per minute is exceeded, retry in {} s",
+                    per_minute, secs
+                ));
+                if let Ok(value) = secs.to_string().parse() {
+                    status.metadata_mut().insert(RETRY_AFTER_KEY, value);
+                }
+                Err(status)
             }
         }
     }
 
-    // returns true if all listeners were notified, orhewise, if at least one
-    // failed to notify, returns false
-    // call to actualize_user_listeners() is recommended if the method returns false
-    async fn notify_user_changed(&self, notification: UserChanged) -> bool {
-        let mut send_list = Vec::new();
-        if let Ok(listeners) = self.users_listeners.read() {
-            for listener in listeners.values() {
-                send_list.push(listener.clone());
+    // ends all streams with a clean EOF, returns the count of closed streams
+    fn shutdown(&self) -> usize {
+        self.stopped.store(true, Ordering::Relaxed);
+        let mut closed = self.users_events.receiver_count()
+            + self.chats_events.receiver_count()
+            + self.posts_events.receiver_count();
+        if let Ok(mut sessions) = self.sessions.write() {
+            sessions.clear();
+        } else {
+            error!("failed locking sessions (shutdown)");
+        }
+        if let Ok(mut listeners) = self.invitations_listeners.write() {
+            closed += listeners.values().map(HashMap::len).sum::<usize>();
+            listeners.clear();
+        } else {
+            error!("failed locking invitations listeners (shutdown)");
+        }
+        closed
+    }
+
+    // stops all streams of the session and expires its token, those of the user's other
+    // sessions go on; returns whether the user has other sessions
+    fn end_session(&self, token: &str, user_id: UserId) -> bool {
+        if let Ok(mut sessions) = self.sessions.write() {
+            if sessions.remove(token).is_some() {
+                debug!("session of {} has ended", user_id);
             }
+        } else {
+            error!("failed locking sessions");
         }
-        if !send_list.is_empty() {
-            let mut fails = false;
-            for tx in send_list {
-                if let Err(e) = tx.send(notification.clone()).await {
-                    error!("failed to broadcast user info: {}", e);
-                    fails = true;
+        if let Ok(mut listeners) = self.invitations_listeners.write() {
+            if let Some(sessions) = listeners.get_mut(&user_id) {
+                if sessions.remove(token).is_some() {
+                    debug!("stop streaming invitations to {}", user_id);
+                }
+                if sessions.is_empty() {
+                    listeners.remove(&user_id);
                 }
             }
-            !fails
         } else {
-            true
+            error!("failed locking invitations listeners");
+        }
+        if let Ok(mut tokens) = self.tokens.write() {
+            tokens.remove(token);
+            tokens.values().any(|(id, _)| *id == user_id)
+        } else {
+            error!("failed locking session tokens");
+            false
         }
     }
 
-    fn actualize_post_listeners(&self) {
-        if let Ok(mut listeners) = self.posts_listeners.write() {
-            let before = listeners.len();
-            listeners.retain(|_k, v| !v.is_closed());
-            let removed = before - listeners.len();
-            if removed > 0 {
-                info!(
-                    "{} outdated post listener(s) was/were found and removed",
-                    removed
-                );
+    // the sessions whose tokens have not been used for the timeout end, the disconnected
+    // clients leave them behind; returns the count of the ended sessions
+    fn expire_sessions(&self, now: Instant, timeout: Duration) -> usize {
+        let mut expired = Vec::new();
+        if let Ok(mut tokens) = self.tokens.write() {
+            tokens.retain(|token, (user_id, used)| {
+                let alive = now.saturating_duration_since(*used) < timeout;
+                if !alive {
+                    expired.push((token.clone(), *user_id));
+                }
+                alive
+            });
+        } else {
+            error!("failed locking session tokens");
+        }
+        for (token, user_id) in &expired {
+            debug!("session of {} has expired", user_id);
+            if !self.end_session(token, *user_id) && self.presence.set_offline(*user_id) {
+                self.notify_user_changed(UserChanged::Offline(*user_id));
             }
         }
+        expired.len()
+    }
+
+    // ends all sessions of the user expiring their tokens, the user goes offline;
+    // returns the count of the ended sessions
+    fn end_user_sessions(&self, user_id: UserId) -> usize {
+        let mut revoked = Vec::new();
+        if let Ok(mut tokens) = self.tokens.write() {
+            tokens.retain(|token, (id, _)| {
+                if *id == user_id {
+                    revoked.push(token.clone());
+                }
+                *id != user_id
+            });
+        } else {
+            error!("failed locking session tokens");
+        }
+        for token in &revoked {
+            self.end_session(token, user_id);
+        }
+        if self.presence.set_offline(user_id) {
+            self.notify_user_changed(UserChanged::Offline(user_id));
+        }
+        revoked.len()
+    }
+
+    // the invitations listeners of all the user's sessions
+    fn invitations_senders(&self, user_id: UserId) -> Vec<mpsc::Sender<Invitation>> {
+        match self.invitations_listeners.read() {
+            Ok(listeners) => listeners
+                .get(&user_id)
+                .map(|sessions| sessions.values().cloned().collect())
+                .unwrap_or_default(),
+            Err(_) => {
+                error!("failed locking invitations listeners");
+                Vec::new()
+            }
+        }
+    }
+
+    // publishing never waits for subscribers, having no subscribers is not an error
+    fn notify_chat_changed(&self, notification: ChatChanged) {
+        let _ = self.chats_events.send(notification);
+    }
+
+    fn notify_chat_updated(&self, chat: Chat) {
+        let posts = self.storage.chat_posts_count(chat.id).unwrap_or_default() as u64;
+        let degraded = self.storage.is_degraded(chat.id).unwrap_or_default();
+        self.notify_chat_changed(ChatChanged::Updated(Arc::new(chat), posts, degraded));
+    }
+
+    // the invitee's streams take the changed membership with the update of the chat
+    fn notify_invitation(&self, chat_id: ChatId, user_id: UserId, pending: bool) {
+        self.notify_chat_changed(ChatChanged::Invited(chat_id, user_id, pending));
+        if let Ok(Some(chat)) = self.storage.read_chat(chat_id) {
+            self.notify_chat_updated(chat);
+        }
+    }
+
+    // the user's streams on every device take the mute with the update of the chat
+    fn notify_mute(&self, chat_id: ChatId, user_id: UserId, muted: bool) {
+        self.notify_chat_changed(ChatChanged::Muted(chat_id, user_id, muted));
+        if let Ok(Some(chat)) = self.storage.read_chat(chat_id) {
+            self.notify_chat_updated(chat);
+        }
+    }
+
+    // lets the members know the chat's posts got damaged since `was_degraded` was read
+    fn check_degraded(&self, chat_id: ChatId, was_degraded: bool) {
+        if !was_degraded && self.storage.is_degraded(chat_id).unwrap_or_default() {
+            if let Ok(Some(chat)) = self.storage.read_chat(chat_id) {
+                self.notify_chat_updated(chat);
+            }
+        }
+    }
+
+    fn notify_user_changed(&self, notification: UserChanged) {
+        let _ = self.users_events.send(notification);
     }
 
     // notifies all chat members about new post
-    // returns true if all listeners were notified, orhewise, if at least one
-    // failed to notify, returns false
-    // call to actualize_post_listeners() is recommended if the method returns false
-    async fn notify_new_post(&self, post: Post) -> bool {
+    fn notify_new_post(&self, post: Post) {
         // найти чат по id, запомнить список user_id-получателей - всех участников, включая автора поста
-        let mut users = Vec::new();
-        if let Ok(Some(chat)) = self.storage.read_chat(post.chat_id) {
-            for u in chat.users.as_slice() {
-                users.push(*u);
-            }
+        let recipients = match self.storage.read_chat(post.chat_id) {
+            Ok(Some(chat)) => chat.users,
+            _ => Vec::new(),
+        };
+        // the post ends typing it
+        if let Some(typing) = self.typing.stop(post.chat_id, post.user_id) {
+            self.notify_typing(post.chat_id, post.user_id, false, typing);
         }
-        if users.is_empty() {
-            true
-        } else {
-            // создать список каналов к получателям поста из списка получателей
-            let mut send_list = Vec::new();
-            if let Ok(listeners) = self.posts_listeners.read() {
-                for user_id in users {
-                    debug!("search channel to {} for post", user_id);
-                    if let Some(listener) = listeners.get(&user_id) {
-                        debug!("found channel to {} for post", user_id);
-                        send_list.push(listener.clone());
-                    }
-                }
+        self.send_post(post, recipients);
+    }
+
+    // the changed post, e.g. its reactions, goes to the chat members as the new one does
+    fn notify_post_updated(&self, post: Post) {
+        let recipients = match self.storage.read_chat(post.chat_id) {
+            Ok(Some(chat)) => chat.users,
+            _ => Vec::new(),
+        };
+        self.send_post(post, recipients);
+    }
+
+    // the deleted post goes to the chat members as its tombstone, the id and the kind only
+    fn notify_post_deleted(&self, chat_id: ChatId, post_id: PostId) {
+        let recipients = match self.storage.read_chat(chat_id) {
+            Ok(Some(chat)) => chat.users,
+            _ => Vec::new(),
+        };
+        let tombstone = Post {
+            id: post_id,
+            chat_id,
+            kind: PostKind::Deleted as i32,
+            ..Default::default()
+        };
+        self.send_post(tombstone, recipients);
+    }
+
+    fn send_post(&self, post: Post, recipients: Vec<UserId>) {
+        if !recipients.is_empty() {
+            let _ = self.posts_events.send(PostNotification {
+                post: Arc::new(post),
+                recipients: Arc::new(recipients),
+            });
+        }
+    }
+
+    fn notify_typing(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        typing: bool,
+        recipients: Recipients,
+    ) {
+        let _ = self.typing_events.send(TypingNotification {
+            event: TypingEvent {
+                chat_id,
+                user_id,
+                typing,
+            },
+            recipients,
+        });
+    }
+}
+
+// the token the call is made with, one per session of the user
+fn session_token<T>(request: &Request<T>) -> Result<&str, Status> {
+    request
+        .metadata()
+        .get(AUTHORIZATION_KEY)
+        .and_then(|value| value.to_str().ok())
+        .and_then(bearer_token)
+        .ok_or_else(|| Status::unauthenticated("no session token"))
+}
+
+struct StreamGuard {
+    user_id: UserId,
+    presence: Arc<Presence>,
+    users_events: broadcast::Sender<UserChanged>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if self.presence.stream_closed(self.user_id) && self.presence.set_offline(self.user_id) {
+            debug!("the last stream of {} has closed, offline", self.user_id);
+            let _ = self.users_events.send(UserChanged::Offline(self.user_id));
+        }
+    }
+}
+
+// Forwards notifications into the client's stream until either the client disconnects,
+// the session ends or the publisher is gone. A slow client affects only its own stream:
+// it skips the notifications overwritten in the meantime.
+// The initial items, if any, are streamed first, `convert` filters out and converts the rest.
+fn spawn_stream<N, T, I, F>(
+    what: &'static str,
+    user_id: UserId,
+    initial: I,
+    mut events: broadcast::Receiver<N>,
+    mut session: watch::Receiver<()>,
+    guard: StreamGuard,
+    tx: mpsc::Sender<Result<T, tonic::Status>>,
+    mut convert: F,
+) where
+    N: Clone + Send + 'static,
+    T: Send + 'static,
+    I: IntoIterator<Item = T> + Send + 'static,
+    I::IntoIter: Send,
+    F: FnMut(N) -> Option<T> + Send + 'static,
+{
+    tokio::spawn(async move {
+        debug!("start streaming {} to {}", what, user_id);
+        for item in initial {
+            if let Err(e) = tx.send(Ok(item)).await {
+                error!("failed sending existing {}: {}", what, e);
+                break;
             }
-            // подготовить пост и разослать
-            if !send_list.is_empty() {
-                let send_post = Arc::new(post);
-                let mut fails = false;
-                for tx in send_list {
-                    if let Err(e) = tx.send(send_post.clone()).await {
-                        error!("failed to send post: {}", e);
-                        fails = true;
+        }
+        loop {
+            let notification = tokio::select! {
+                received = events.recv() => match received {
+                    Ok(notification) => notification,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(
+                            "stream of {} to {} lags, {} notification(s) skipped",
+                            what, user_id, skipped
+                        );
+                        continue;
                     }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = session.changed() => break,
+                _ = tx.closed() => break,
+            };
+            if let Some(item) = convert(notification) {
+                if let Err(e) = tx.send(Ok(item)).await {
+                    error!("failed sending {}: {}, stop", what, e);
+                    break;
                 }
-                !fails
-            } else {
-                true
             }
         }
-    }
+        debug!("stream of {} to {} has stopped", what, user_id);
+        drop(guard);
+    });
 }
 
-impl fmt::Debug for ChatRoomImpl {
+impl<S: ChatStorage> fmt::Debug for ChatRoomImpl<S> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("ChatRoomImpl")
-            .field("users_listeners", &format!("{:?}", self.users_listeners))
+            .field("tunables", &self.tunables())
+            .field("users_subscribers", &self.users_events.receiver_count())
             .field(
                 "invitations_listeners",
                 &format!("{:?}", self.invitations_listeners),
             )
-            .field("chats_listeners", &format!("{:?}", self.chats_listeners))
-            .field("posts_listeners", &format!("{:?}", self.posts_listeners))
+            .field("chats_subscribers", &self.chats_events.receiver_count())
+            .field("posts_subscribers", &self.posts_events.receiver_count())
             .finish()
     }
 }
 
+// where the connections are accepted
+enum Listener {
+    // bound when the serving starts
+    Bind(SocketAddr),
+    // already listening, e.g. passed by the service manager
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+// the connections of the unix socket have no remote address to report
+#[cfg(unix)]
+struct UnixConnection(tokio::net::UnixStream);
+
+#[cfg(unix)]
+impl tonic::transport::server::Connected for UnixConnection {}
+
+#[cfg(unix)]
+impl tokio::io::AsyncRead for UnixConnection {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+#[cfg(unix)]
+impl tokio::io::AsyncWrite for UnixConnection {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::pin::Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+// serves until the signal, then reports not serving for the drain time and closes all streams
+// to let the server stop gracefully; the admin service is served along if its token is set
+async fn serve<S: ChatStorage, F: Future<Output = ()>>(
+    chat_room: Arc<ChatRoomImpl<S>>,
+    listener: Listener,
+    tls: Option<ServerTlsConfig>,
+    proxy: Arc<ProxyFilter>,
+    admin_token: Option<String>,
+    signal: F,
+) -> Result<(), tonic::transport::Error> {
+    let svc = ChatRoomServiceServer::with_interceptor(chat_room.clone(), move |request| {
+        proxy.intercept(request)
+    });
+    let admin_svc = admin_token.map(|token| {
+        ChatRoomAdminServer::with_interceptor(chat_room.clone(), move |request| {
+            admin_service::check_token(&token, request)
+        })
+    });
+    let mut builder = Server::builder();
+    if let Some(tls) = tls {
+        builder = builder.tls_config(tls)?;
+    }
+    let (reporter, health_svc) = tonic_health::server::health_reporter();
+    let service = <ChatRoomServiceServer<Arc<ChatRoomImpl<S>>> as NamedService>::NAME;
+    let (health_check, stop_health_check) =
+        future::abortable(health::run(chat_room.clone(), reporter.clone(), service));
+    tokio::spawn(health_check);
+    let router = builder
+        .add_service(health_svc)
+        .add_service(svc)
+        .add_optional_service(admin_svc);
+    let stop_check = stop_health_check.clone();
+    let signal = async move {
+        signal.await;
+        // the self-check is not to report serving again
+        stop_check.abort();
+        let mut reporter = reporter;
+        health::set_status(&mut reporter, service, ServingStatus::NotServing).await;
+        let drain = chat_room.tunables().health.drain;
+        if drain > Duration::default() {
+            info!("draining for {} ms", drain.as_millis());
+            tokio::time::sleep(drain).await;
+        }
+        let closed = chat_room.shutdown();
+        info!("shutting down, {} active stream(s) closed", closed);
+    };
+    let served = match listener {
+        Listener::Bind(addr) => router.serve_with_shutdown(addr, signal).await,
+        Listener::Tcp(listener) => {
+            let incoming = TcpListenerStream::new(listener);
+            router.serve_with_incoming_shutdown(incoming, signal).await
+        }
+        #[cfg(unix)]
+        Listener::Unix(listener) => {
+            use futures::StreamExt;
+            let incoming = tokio_stream::wrappers::UnixListenerStream::new(listener)
+                .map(|connection| connection.map(UnixConnection));
+            router.serve_with_incoming_shutdown(incoming, signal).await
+        }
+    };
+    // releases its reference to the chat room whatever the serving has ended with
+    stop_health_check.abort();
+    served
+}
+
+// resolves on Ctrl+C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            error!("failed to listen Ctrl+C, {}", e);
+            future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("failed to listen SIGTERM, {}", e);
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => info!("Ctrl+C received"),
+        _ = terminate => info!("SIGTERM received"),
+    }
+}
+
+// applies the reloaded settings those can be changed on the fly,
+// returns the settings the server runs with now
+fn apply_settings<S: ChatStorage>(
+    chat_room: &ChatRoomImpl<S>,
+    proxy: &ProxyFilter,
+    running: &ServerSettings,
+    loaded: ServerSettings,
+) -> ServerSettings {
+    let changes = settings::diff(running, &loaded);
+    for key in &changes.restart_required {
+        warn!("{} has changed, restart to apply it", key);
+    }
+    if changes.applied.is_empty() {
+        info!("no settings to apply");
+        return running.clone();
+    }
+    info!("apply changed {}", changes.applied.join(", "));
+    let settings = settings::merge(running, loaded);
+    chat_room.reconfigure(settings.tunables.clone());
+    proxy.reconfigure(settings.proxy.clone());
+    // the filter of the environment still applies
+    log::set_max_level(settings.log_level.unwrap_or(log::LevelFilter::Trace));
+    settings
+}
+
+// re-reads the config on SIGHUP, the invalid one changes nothing
+async fn reload_on_hangup<S: ChatStorage>(
+    chat_room: Arc<ChatRoomImpl<S>>,
+    proxy: Arc<ProxyFilter>,
+    mut running: ServerSettings,
+    config_file: String,
+) {
+    #[cfg(unix)]
+    match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(mut sighup) => {
+            while sighup.recv().await.is_some() {
+                info!("SIGHUP received, reload {}", config_file);
+                match settings::load(&config_file) {
+                    Ok(loaded) => running = apply_settings(&chat_room, &proxy, &running, loaded),
+                    Err(e) => error!("config is not reloaded, {}", e),
+                }
+            }
+        }
+        Err(e) => error!("failed to listen SIGHUP, {}", e),
+    }
+    #[cfg(not(unix))]
+    let _ = (chat_room, proxy, &mut running, config_file);
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // commnad line
@@ -236,47 +894,251 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .help("Sets a custom config file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name(EXPORT)
+                .long(EXPORT)
+                .value_name("FILE")
+                .help("Writes the users, the chats and the posts into the JSON file and exits")
+                .takes_value(true)
+                .conflicts_with(IMPORT),
+        )
+        .arg(
+            Arg::with_name(IMPORT)
+                .long(IMPORT)
+                .value_name("FILE")
+                .help("Fills the empty database from the JSON file written by --export and exits")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(FORCE)
+                .long(FORCE)
+                .help("Removes the existing database before --import")
+                .requires(IMPORT),
+        )
+        .subcommand(
+            SubCommand::with_name(REPLAY_CHAT)
+                .about("Prints the chat as of a point in its history, no server needed")
+                .arg(
+                    Arg::with_name("chat_id")
+                        .help("Id of the chat")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("until-seq")
+                        .long("until-seq")
+                        .value_name("N")
+                        .help("The last post seen is the one with the seq, inclusive")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("until-time")
+                        .long("until-time")
+                        .value_name("T")
+                        .help("Posts created up to the time, unix secs or RFC 3339, inclusive")
+                        .takes_value(true)
+                        .conflicts_with("until-seq"),
+                )
+                .arg(
+                    Arg::with_name("diff-seq")
+                        .long("diff-seq")
+                        .value_name("M")
+                        .help("Also prints the posts added between the seqs N and M")
+                        .takes_value(true)
+                        .requires("until-seq"),
+                ),
+        )
         .get_matches();
     let config_file = matches.value_of(CONFIG).unwrap_or(CONFIG_DEF);
-    info!("Using config: {}", config_file);
-
-    // config
-    let mut settings = Config::default();
-    settings
-        // Add in `./Settings.toml`
-        .merge(File::with_name(config_file))
-        .unwrap()
-        // Add in settings from the environment (with a prefix of APP)
-        // Eg.. `APP_DEBUG=1 ./target/app` would set the `debug` key
-        .merge(Environment::with_prefix(CONFIG_ENV))
-        .unwrap();
 
     Builder::from_env(Env::default().default_filter_or("debug,h2=info,tower=info,hyper=info"))
         .target(Target::Stdout)
         .format_timestamp(Some(TimestampPrecision::Seconds))
         .init();
 
-    let endpoint = if let Ok(addr) = settings.get_str("endpoint") {
-        addr
-    } else {
-        warn!("server connection is not set, use default {}", DEF_ENDPOINT);
-        String::from(DEF_ENDPOINT)
-    };
+    info!("Using config: {}", config_file);
+    let settings = settings::load(config_file)?;
+    if let Some(level) = settings.log_level {
+        log::set_max_level(level);
+    }
+    info!("use {} as DB storage", settings.db_file);
+    info!("use {} storage backend", settings.storage);
+    info!("use limits: {:?}", settings.tunables.limits);
+    info!("use proxy config: {:?}", settings.proxy);
 
-    let dbfile = if let Ok(name) = settings.get_str("dbfile") {
-        info!("use {} as DB storage", name);
-        name
-    } else {
-        warn!("DB file is not set, use default {}", DEF_DB_FILE);
-        String::from(DEF_DB_FILE)
+    let dbfile = settings.db_file.clone();
+    if let Some(args) = matches.subcommand_matches(REPLAY_CHAT) {
+        let text = match settings.storage.as_str() {
+            "sqlite" => replay_chat(&SqliteStorage::new(dbfile)?, args)?,
+            _ => replay_chat(&Storage::new(dbfile)?, args)?,
+        };
+        print!("{}", text);
+        return Ok(());
+    }
+    if let Some(file) = matches.value_of(EXPORT) {
+        let backup = match settings.storage.as_str() {
+            "sqlite" => backup::export(&SqliteStorage::new(dbfile)?)?,
+            _ => backup::export(&Storage::new(dbfile)?)?,
+        };
+        let mut out = BufWriter::new(fs::File::create(file)?);
+        serde_json::to_writer_pretty(&mut out, &backup)?;
+        out.flush()?;
+        info!(
+            "exported users: {}, chats: {}, posts: {} to {}",
+            backup.users.len(),
+            backup.chats.len(),
+            backup.posts.len(),
+            file
+        );
+        return Ok(());
+    }
+    if let Some(file) = matches.value_of(IMPORT) {
+        let backup = serde_json::from_reader(BufReader::new(fs::File::open(file)?))?;
+        if matches.is_present(FORCE) && Path::new(&dbfile).exists() {
+            warn!("removing the existing database {}", dbfile);
+            fs::remove_file(&dbfile)?;
+        }
+        let restored = match settings.storage.as_str() {
+            "sqlite" => backup::import(&SqliteStorage::new(dbfile)?, backup)?,
+            _ => backup::import(&Storage::new(dbfile)?, backup)?,
+        };
+        info!(
+            "imported users: {}, chats: {}, posts: {} from {}, posts skipped: {}",
+            restored.users, restored.chats, restored.posts, file, restored.skipped_posts
+        );
+        return Ok(());
+    }
+
+    let limits = settings.tunables.limits.clone();
+    let config_file = String::from(config_file);
+    match settings.storage.as_str() {
+        "sqlite" => {
+            let storage = SqliteStorage::new(dbfile)?;
+            run(
+                ChatRoomImpl::with_storage(storage, limits),
+                settings,
+                config_file,
+            )
+            .await
+        }
+        // the storage has been validated with the settings
+        _ => run(ChatRoomImpl::new(dbfile, limits)?, settings, config_file).await,
+    }
+}
+
+fn replay_chat<S: ChatStorage>(storage: &S, args: &ArgMatches) -> Result<String, InternalError> {
+    let number = |name: &str| -> Result<Option<u64>, InternalError> {
+        match args.value_of(name) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|e| format!("{} '{}', {}", name, value, e).into()),
+            None => Ok(None),
+        }
+    };
+    let chat_id = number("chat_id")?.unwrap_or_default();
+    let boundary = match (number("until-seq")?, args.value_of("until-time")) {
+        (Some(seq), _) => timeline::Boundary::Seq(seq),
+        (None, Some(time)) => timeline::Boundary::parse_time(time)?,
+        (None, None) => timeline::Boundary::Seq(u64::MAX),
     };
+    timeline::replay_chat(storage, chat_id, boundary, number("diff-seq")?)
+}
 
-    let addr = endpoint.parse().unwrap();
-    let chat_room = ChatRoomImpl::new(dbfile)?;
-    info!("Chat room is listening on {}", addr);
+// the backend is chosen at runtime, the rest does not depend on it
+async fn run<S: ChatStorage>(
+    chat_room: ChatRoomImpl<S>,
+    settings: ServerSettings,
+    config_file: String,
+) -> Result<(), InternalError> {
+    let addr: SocketAddr = settings.endpoint.parse()?;
+    // the misconfigured certificate stops the start
+    let tls = match &settings.tls {
+        Some(files) => Some(tls::load(files)?),
+        None => None,
+    };
+    chat_room.reconfigure(settings.tunables.clone());
+    let chat_room = Arc::new(chat_room);
+    let proxy = Arc::new(ProxyFilter::new(settings.proxy.clone()));
+    let notifier = Notifier::from_env()?.map(Arc::new);
+    let listener = match systemd::inherited_listener()? {
+        Some(listener) => {
+            info!("Chat room is listening on the inherited socket");
+            listener
+        }
+        // the service manager is told of the readiness once the server is bound
+        None if notifier.is_some() => {
+            info!("Chat room is listening on {}", addr);
+            Listener::Tcp(TcpListener::bind(addr).await?)
+        }
+        None => {
+            info!("Chat room is listening on {}", addr);
+            Listener::Bind(addr)
+        }
+    };
+    let watchdog_interval =
+        systemd::watchdog_interval(|key| std::env::var(key).ok(), std::process::id())?;
+    let watchdog = match (&notifier, watchdog_interval) {
+        (Some(notifier), Some(interval)) => {
+            info!("watchdog is pinged every {} ms", interval.as_millis());
+            Some(tokio::spawn(systemd::watchdog(notifier.clone(), interval)))
+        }
+        _ => None,
+    };
 
-    let svc = ChatRoomServiceServer::new(chat_room);
-    Server::builder().add_service(svc).serve(addr).await?;
+    let verifier = tokio::spawn(verifier::run(chat_room.clone()));
+    let presence = tokio::spawn(presence::run(chat_room.clone()));
+    let typing = tokio::spawn(typing::run(chat_room.clone()));
+    let snapshot = tokio::spawn(snapshot::run(chat_room.clone()));
+    let rate_limit = tokio::spawn(rate_limit::run(chat_room.clone()));
+    let admin_token = settings.admin_token.clone();
+    if admin_token.is_some() {
+        info!("admin service is enabled");
+    }
+    let reload = tokio::spawn(reload_on_hangup(
+        chat_room.clone(),
+        proxy.clone(),
+        settings,
+        config_file,
+    ));
+    let signal = {
+        let notifier = notifier.clone();
+        async move {
+            shutdown_signal().await;
+            if let Some(notifier) = notifier {
+                notifier.notify(&[State::Stopping]);
+            }
+        }
+    };
+    if let Some(notifier) = &notifier {
+        notifier.notify(&[State::Ready, State::Status(String::from("serving"))]);
+    }
+    if tls.is_some() {
+        info!("TLS is enabled");
+    }
+    serve(chat_room.clone(), listener, tls, proxy, admin_token, signal).await?;
+    // releases the references of the background tasks
+    if let Some(watchdog) = watchdog {
+        watchdog.abort();
+        let _ = watchdog.await;
+    }
+    verifier.abort();
+    let _ = verifier.await;
+    presence.abort();
+    let _ = presence.await;
+    typing.abort();
+    let _ = typing.await;
+    snapshot.abort();
+    let _ = snapshot.await;
+    rate_limit.abort();
+    let _ = rate_limit.await;
+    reload.abort();
+    let _ = reload.await;
 
+    // the service has released its reference
+    match Arc::try_unwrap(chat_room) {
+        Ok(chat_room) => chat_room.storage.close(),
+        Err(_) => warn!("chat room is still in use, storage is left to be closed on exit"),
+    }
+    info!("Chat room has stopped");
     Ok(())
 }
@@ -1,25 +1,56 @@
 use chrono::prelude::*;
-use futures::Stream; //, StreamExt};
-use fxhash::FxHasher64;
-use log::{debug, error};
-use std::{collections::BTreeSet, hash::Hasher, ops::Deref, pin::Pin, sync::Arc};
+use futures::{Stream, StreamExt};
+use log::{debug, error, info, warn};
+use migchat_core::ids::{self, normalize_description};
+use migchat_core::post_limits::normalize_line_endings;
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashSet},
+    ops::Deref,
+    pin::Pin,
+    sync::Arc,
+    time::Instant,
+};
 use tokio::sync::mpsc;
-use tonic::{Request, Response, Status};
+use tonic::{metadata::MetadataValue, Request, Response, Status};
 
+use super::dedup::Reserved;
+use super::error::Found;
 use super::proto::chat_room_service_server::ChatRoomService;
 use super::proto::{
-    ChatHistory, ChatInfo, ChatReference, ChatUpdate, HistoryParams, Invitation, Post,
-    Registration, RegistrationInfo, Result as RpcResult, UpdateChats, UpdateUsers, UserInfo,
-    NOT_CHAT_ID, NOT_POST_ID,
+    AttachmentChunk, AttachmentId, AttachmentInfo, AttachmentReference, ChatHistory, ChatInfo,
+    ChatInfoUpdate, ChatMembers, ChatMute, ChatReference, ChatUpdate, ForwardedFrom, HistoryParams,
+    Invitation, MemberReference, Membership, Post, PostKind, PostReaction, PostReference, Reaction,
+    ReadMark, Registration, RegistrationInfo, Result as RpcResult, SearchRequest, SearchResult,
+    SnapshotRequest, SnapshotResult, TypingEvent, UpdateChats, UpdateUsers, UserInfo, UserUpdate,
+    CHAT_STATUS_CREATED, CHAT_STATUS_FLAGS_ADJUSTED, CHAT_STATUS_FOUND, CHAT_STATUS_KEY,
+    LIST_LIMIT_KEY, LIST_OFFSET_KEY, NOT_CHAT_ID, NOT_POST_ID, NOT_USER_ID, POST_ID_KEY,
+    SESSION_TOKEN_KEY, SINCE_CREATED_KEY, SINCE_POST_ID_KEY, USER_ID_KEY, USER_SECRET_KEY,
+};
+use super::proxy::ClientIdentity;
+use super::rate_limit::Action;
+use super::snapshot;
+use super::storage::ChatStorage;
+use super::{
+    session_token, spawn_stream, Chat, ChatChanged, ChatId, ChatRoomImpl, PostId, PostNotification,
+    ServerError, TypingNotification, User, UserChanged, UserId,
 };
-use super::{Chat, ChatChanged, ChatRoomImpl, User, UserChanged, UserId};
 
-fn get_user_id(user: &UserInfo) -> u64 {
-    let mut hasher = FxHasher64::default();
-    hasher.write(user.name.as_bytes());
-    hasher.write(user.short_name.as_bytes());
-    hasher.finish()
-}
+// a random post id is hardly ever taken, let alone several times in a row
+const POST_ID_ATTEMPTS: usize = 8;
+// the ids of unrelated chats hardly ever collide, let alone several times in a row
+const CHAT_ID_PROBES: u32 = 4;
+// the author's short name kept with the post is cut to it, in chars
+const MAX_AUTHOR_NAME_LEN: usize = 32;
+// the last posts of every chat replayed to the resumed posts stream at most
+const REPLAY_POSTS: usize = 256;
+// the emoji or the shortcode of a reaction at most, in bytes
+const MAX_EMOJI_LEN: usize = 32;
+// the name of an attachment at most, in bytes
+const MAX_ATTACHMENT_NAME_LEN: usize = 255;
+// the posts found by one search request at most, the default page too
+const MAX_SEARCH_RESULTS: usize = 100;
 
 fn new_post_id() -> u64 {
     let mut v = NOT_POST_ID;
@@ -29,6 +60,44 @@ fn new_post_id() -> u64 {
     v
 }
 
+// writes the post under a new id, which is regenerated while taken by another post,
+// the written post takes the sequence number given by the storage;
+// Ok(false) if no free id has been found
+fn write_new_post<S: ChatStorage, F: FnMut() -> PostId>(
+    storage: &S,
+    post: &mut Post,
+    mut new_id: F,
+) -> Result<bool, ServerError> {
+    for _ in 0..POST_ID_ATTEMPTS {
+        post.id = new_id();
+        if storage.write_post(post)? {
+            if let Some((_, seq)) = storage.locate_post(post.id)? {
+                post.seq = seq;
+            }
+            return Ok(true);
+        }
+        warn!("post id {} is taken, regenerating", post.id);
+    }
+    Ok(false)
+}
+
+fn new_user_id() -> u64 {
+    let mut v = NOT_USER_ID;
+    while v == NOT_USER_ID {
+        v = rand::random();
+    }
+    v
+}
+
+// the secret the user proves its id with, only its digest is stored
+fn new_user_secret() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+fn secret_digest(secret: &str) -> Vec<u8> {
+    Sha256::digest(secret.as_bytes()).to_vec()
+}
+
 fn new_chat_id() -> u64 {
     let mut v = NOT_CHAT_ID;
     while v == NOT_CHAT_ID {
@@ -38,24 +107,222 @@ fn new_chat_id() -> u64 {
 }
 
 // chat must have a predictable reproducable id
-// based on its description if is not empty or its members,
-// the reasons are
+// based on its description if is not empty or its members including the creator,
+// the flags do not affect the id, the reasons are
 // - avoid having chats with empty names in chat list
 // - display such a chat like a dialog of its members
 // - chat must be discoverable by any member instead of creating new and new ones
-fn get_chat_id(description: &str, users: &[UserId]) -> u64 {
-    let mut hasher = FxHasher64::default();
-    if !description.is_empty() {
-        hasher.write(description.as_bytes());
-        hasher.finish()
-    } else if !users.is_empty() {
-        for id in users {
-            hasher.write(&id.to_le_bytes());
-        }
-        hasher.finish()
+// the next probe is tried while the id is taken by an unrelated chat, see `is_same_chat`
+fn get_chat_id(description: &str, users: &[UserId], probe: u32) -> u64 {
+    ids::chat_id_probe(description, users, probe).unwrap_or_else(new_chat_id)
+}
+
+// whether the stored chat is the one the description or the members lead to rather
+// than another one of the same hash; the members might have left a dialog since, but
+// the dialog with someone else is not entered, though it might have been invited to
+fn is_same_chat(chat: &Chat, description: &str, members: &[UserId]) -> bool {
+    if description.is_empty() {
+        chat.description.is_empty() && chat.users.iter().all(|u| members.contains(u))
+    } else {
+        chat.description == description
+    }
+}
+
+// removes the user from the chat, the non-permanent chat left by everyone is closed;
+// `may_remove` checks the chat as stored, nothing is changed if it fails;
+// Ok(true) if the user has been removed and the chat stays open
+pub fn remove_member<S, F>(
+    chat_room: &ChatRoomImpl<S>,
+    chat_id: ChatId,
+    user_id: UserId,
+    mut may_remove: F,
+) -> Result<bool, Status>
+where
+    S: ChatStorage,
+    F: FnMut(&Chat) -> Result<(), Status>,
+{
+    let mut denied = None;
+    let mut removed = false;
+    let updated_chat = chat_room
+        .storage
+        .update_chat(chat_id, |mut_ref_chat| {
+            if let Err(status) = may_remove(mut_ref_chat) {
+                denied = Some(status);
+                false
+            } else if mut_ref_chat.users.contains(&user_id) {
+                mut_ref_chat.users.retain(|&id| id != user_id);
+                removed = true;
+                true
+            } else {
+                false
+            }
+        })
+        .found("chat")?;
+    if let Some(status) = denied {
+        return Err(status);
+    }
+    if !updated_chat.permanent && updated_chat.users.is_empty() {
+        //remove chat
+        if let Err(e) = chat_room.storage.remove_chat(chat_id) {
+            error!("internal, {}", e);
+        }
+        chat_room.notify_chat_changed(ChatChanged::Closed(chat_id));
+        Ok(false)
     } else {
-        new_chat_id()
+        chat_room.notify_chat_updated(updated_chat);
+        Ok(removed)
+    }
+}
+
+// the name of the author as of the posting, the posts are readable after renames
+fn author_name(user: &User) -> String {
+    user.short_name.chars().take(MAX_AUTHOR_NAME_LEN).collect()
+}
+
+// the short name of the member in the system posts
+pub fn member_name<S: ChatStorage>(storage: &S, user_id: UserId) -> String {
+    match storage.read_user(user_id) {
+        Ok(Some(user)) => author_name(&user),
+        Ok(None) => format!("user {}", user_id),
+        Err(e) => {
+            warn!("failed to read user {}, {}", user_id, e);
+            format!("user {}", user_id)
+        }
+    }
+}
+
+// tells the members the membership of the chat has changed, the post is of no user;
+// the change is done already, the failure to post is only logged
+pub fn post_system_notice<S: ChatStorage>(
+    chat_room: &ChatRoomImpl<S>,
+    chat_id: ChatId,
+    text: String,
+) {
+    if !chat_room.tunables().limits.system_posts {
+        return;
+    }
+    let mut post = Post {
+        chat_id,
+        user_id: NOT_USER_ID,
+        text,
+        created: Utc::now().timestamp() as u64,
+        kind: PostKind::System as i32,
+        ..Default::default()
+    };
+    match write_new_post(&chat_room.storage, &mut post, new_post_id) {
+        Ok(true) => chat_room.notify_new_post(post),
+        Ok(false) => error!("failed to find a free id of system post"),
+        Err(e) => error!("failed to save system post, {}", e),
+    }
+}
+
+// takes the post out of Arc if it is the last reference, clones it otherwise
+fn unwrap_shared(post: Arc<Post>) -> Post {
+    Arc::try_unwrap(post).unwrap_or_else(|shared| (*shared).clone())
+}
+
+// the newest post the client has got before resubscribing to the posts
+struct PostCursor {
+    post_id: PostId,
+    // the post with the id must have been created at the time if it is given
+    created: Option<u64>,
+}
+
+fn metadata_u64<T>(request: &Request<T>, key: &str) -> Result<Option<u64>, Status> {
+    match request.metadata().get(key) {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .map(Some)
+            .ok_or_else(|| Status::invalid_argument(format!("invalid {}", key))),
+        None => Ok(None),
+    }
+}
+
+fn post_cursor<T>(request: &Request<T>) -> Result<Option<PostCursor>, Status> {
+    Ok(match metadata_u64(request, SINCE_POST_ID_KEY)? {
+        Some(post_id) => Some(PostCursor {
+            post_id,
+            created: metadata_u64(request, SINCE_CREATED_KEY)?,
+        }),
+        None => None,
+    })
+}
+
+// The posts of the user's chats after the cursor in the order of their creation.
+// The unknown cursor replays the last posts of every chat, the posts of the other chats
+// created at the same second as the cursor are replayed too, the client drops the repeated.
+fn replay_posts<S: ChatStorage>(
+    storage: &S,
+    user_id: UserId,
+    cursor: &PostCursor,
+) -> Result<Vec<Post>, ServerError> {
+    let last = match storage.locate_post(cursor.post_id)? {
+        Some((chat_id, _)) => storage.read_post(chat_id, cursor.post_id)?.filter(|post| {
+            cursor
+                .created
+                .map_or(true, |created| created == post.created)
+        }),
+        None => None,
+    };
+    if last.is_none() {
+        debug!("unknown post cursor {}, full replay", cursor.post_id);
+    }
+    let mut replay = Vec::new();
+    for chat_id in storage.read_user_chats(user_id)? {
+        let count = storage.chat_posts_count(chat_id)?;
+        let tail =
+            storage.read_chat_posts(chat_id, count.saturating_sub(REPLAY_POSTS), REPLAY_POSTS)?;
+        match &last {
+            Some(last) if last.chat_id == chat_id => {
+                match tail.iter().position(|post| post.id == last.id) {
+                    Some(pos) => replay.extend(tail.into_iter().skip(pos + 1)),
+                    None => replay.extend(tail.into_iter().filter(|p| p.created >= last.created)),
+                }
+            }
+            Some(last) => replay.extend(tail.into_iter().filter(|p| p.created >= last.created)),
+            None => replay.extend(tail),
+        }
     }
+    // stable, the posts of a chat keep their order
+    replay.sort_by_key(|post| post.created);
+    Ok(replay)
+}
+
+// runs the part of a handler touching the storage on a blocking thread,
+// the async workers stay free for the other requests meanwhile
+pub async fn blocking<S, T, F>(chat_room: &Arc<ChatRoomImpl<S>>, op: F) -> Result<T, Status>
+where
+    S: ChatStorage,
+    T: Send + 'static,
+    F: FnOnce(&ChatRoomImpl<S>) -> Result<T, Status> + Send + 'static,
+{
+    let chat_room = chat_room.clone();
+    tokio::task::spawn_blocking(move || op(&chat_room))
+        .await
+        .unwrap_or_else(|e| Err(Status::internal(format!("storage task failed, {}", e))))
+}
+
+// tells the client the id of its post
+fn post_accepted(post_id: PostId) -> Response<RpcResult> {
+    let mut response = Response::new(RpcResult {
+        ok: true,
+        description: String::from("accepted"),
+    });
+    response.metadata_mut().insert(
+        POST_ID_KEY,
+        MetadataValue::from(post_id.to_string().as_str()),
+    );
+    response
+}
+
+// marks create_chat() response as either new or existing chat
+fn with_chat_status(mut response: Response<Chat>, status: &'static str) -> Response<Chat> {
+    response
+        .metadata_mut()
+        .insert(CHAT_STATUS_KEY, MetadataValue::from_static(status));
+    response
 }
 
 // return false if chat is invisible for specified user
@@ -67,58 +334,553 @@ fn is_chat_visible_for(chat: &Chat, user_id: UserId) -> bool {
     }
 }
 
+// the relation of the user to the chat shown by the chats stream, the pending invitations
+// are those of the stream; nobody spectates chats yet, so it is never Spectator
+fn my_membership(chat: &Chat, user_id: UserId, invited: &HashSet<ChatId>) -> Membership {
+    if chat.users.contains(&user_id) {
+        Membership::Member
+    } else if invited.contains(&chat.id) {
+        Membership::Invited
+    } else {
+        Membership::None
+    }
+}
+
+// the forwarded post is copied from the stored one, the client tells only where it is;
+// the forwarding user must be a member of the chat it comes from as well
+fn copy_forwarded<S: ChatStorage>(
+    storage: &S,
+    post: &mut Post,
+    origin: &ForwardedFrom,
+) -> Result<(), Status> {
+    let chat = storage
+        .read_chat(origin.chat_id)
+        .found(&format!("chat {}", origin.chat_id))?;
+    if !chat.users.contains(&post.user_id) {
+        return Err(Status::permission_denied(format!(
+            "user {} is not a member of chat {}",
+            post.user_id, origin.chat_id
+        )));
+    }
+    let original = storage
+        .read_post(origin.chat_id, origin.post_id)
+        .found("post")?;
+    post.text = original.text;
+    post.attachments = original.attachments;
+    // forwarded again, it is still from the first author
+    post.forwarded_from = Some(original.forwarded_from.unwrap_or(ForwardedFrom {
+        chat_id: original.chat_id,
+        post_id: original.id,
+        user_id: original.user_id,
+        author_name: original.author_name,
+    }));
+    Ok(())
+}
+
+// the content of the upload and the name it comes with in the first chunk;
+// the upload exceeding the limit is refused without waiting for its end
+async fn collect_upload<St>(mut chunks: St, max_size: usize) -> Result<(String, Vec<u8>), Status>
+where
+    St: Stream<Item = Result<AttachmentChunk, Status>> + Unpin,
+{
+    let too_large = || Status::resource_exhausted(format!("attachment exceeds {} bytes", max_size));
+    let mut name = None;
+    let mut content = Vec::new();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        if name.is_none() {
+            // the declared size is checked before any content is taken
+            if chunk.size > max_size as u64 {
+                return Err(too_large());
+            }
+            name = Some(chunk.name);
+        }
+        if content.len() + chunk.data.len() > max_size {
+            return Err(too_large());
+        }
+        content.extend_from_slice(&chunk.data);
+    }
+    match name {
+        Some(name) if !name.is_empty() && name.len() <= MAX_ATTACHMENT_NAME_LEN => {
+            Ok((name, content))
+        }
+        _ => Err(Status::invalid_argument(format!(
+            "attachment name must be 1 to {} bytes",
+            MAX_ATTACHMENT_NAME_LEN
+        ))),
+    }
+}
+
+// the same reaction of the user again takes it back, returns whether it has been added
+fn toggle_reaction(post: &mut Post, user_id: UserId, emoji: &str) -> bool {
+    match post.reactions.iter().position(|r| r.emoji == emoji) {
+        Some(idx) => {
+            let reaction = &mut post.reactions[idx];
+            match reaction.user_ids.iter().position(|&id| id == user_id) {
+                Some(pos) => {
+                    reaction.user_ids.remove(pos);
+                    if reaction.user_ids.is_empty() {
+                        post.reactions.remove(idx);
+                    }
+                    false
+                }
+                None => {
+                    reaction.user_ids.push(user_id);
+                    true
+                }
+            }
+        }
+        None => {
+            post.reactions.push(PostReaction {
+                emoji: String::from(emoji),
+                user_ids: vec![user_id],
+            });
+            true
+        }
+    }
+}
+
+// the last post read by the user and the count of the others' posts after it,
+// nothing is read if the user has never marked the chat
+fn read_position<S: ChatStorage>(storage: &S, chat_id: ChatId, user_id: UserId) -> (PostId, u64) {
+    let unread = |seq| match storage.count_posts_after(chat_id, seq, user_id) {
+        Ok(count) => count as u64,
+        Err(e) => {
+            error!("failed to count unread posts of chat {}, {}", chat_id, e);
+            0
+        }
+    };
+    match storage.read_read_mark(chat_id, user_id) {
+        Ok(Some((post_id, seq))) => (post_id, unread(seq)),
+        Ok(None) => (NOT_POST_ID, 0),
+        Err(e) => {
+            error!("failed to read the read mark of chat {}, {}", chat_id, e);
+            (NOT_POST_ID, 0)
+        }
+    }
+}
+
+// the member chats are looked up by the index, only the public ones are filtered
+fn read_visible_chats<S: ChatStorage>(
+    storage: &S,
+    user_id: UserId,
+) -> Result<Vec<Chat>, ServerError> {
+    let mut chats = Vec::new();
+    for chat_id in storage.read_user_chats(user_id)? {
+        if let Some(chat) = storage.read_chat(chat_id)? {
+            chats.push(chat);
+        }
+    }
+    chats.extend(
+        storage.read_chats_where(|c| !c.description.is_empty() && !c.users.contains(&user_id))?,
+    );
+    Ok(chats)
+}
+
+// the part of a snapshot asked by list_users() and list_chats(), everything by default
+#[derive(Default)]
+struct Page {
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl Page {
+    fn apply<T>(&self, entries: Vec<T>) -> Vec<T> {
+        let entries = entries.into_iter().skip(self.offset);
+        match self.limit {
+            Some(limit) => entries.take(limit).collect(),
+            None => entries.collect(),
+        }
+    }
+}
+
+fn list_page<T>(request: &Request<T>) -> Result<Page, Status> {
+    Ok(Page {
+        offset: metadata_u64(request, LIST_OFFSET_KEY)?.unwrap_or_default() as usize,
+        limit: metadata_u64(request, LIST_LIMIT_KEY)?.map(|limit| limit as usize),
+    })
+}
+
+// the snapshots are shared by the streams and the unary calls so they are told alike
+impl<S: ChatStorage> ChatRoomImpl<S> {
+    // the users but the asking one, ordered by id, with their statuses
+    fn users_snapshot(&self, user_id: UserId, page: &Page) -> UpdateUsers {
+        let mut users = match self.storage.read_all_users() {
+            Ok(users) => users,
+            Err(e) => {
+                error!("failed to read existing users, {}", e);
+                Vec::new()
+            }
+        };
+        users.retain(|u| u.id != user_id);
+        users.sort_by_key(|u| u.id);
+        let users = page.apply(users);
+        let (online, offline) = users
+            .iter()
+            .map(|u| u.id)
+            .partition(|id| self.presence.is_online(*id));
+        UpdateUsers {
+            added: users,
+            online,
+            offline,
+        }
+    }
+
+    // the chats visible to the user, ordered by id, with the membership and the read position,
+    // along with all the chats the user is invited to
+    fn chats_snapshot(&self, user_id: UserId, page: &Page) -> (Vec<ChatUpdate>, HashSet<ChatId>) {
+        let storage = &self.storage;
+        let invited: HashSet<ChatId> = match storage.read_invitations_to(user_id) {
+            Ok(invitations) => invitations.iter().map(|i| i.chat_id).collect(),
+            Err(e) => {
+                error!("failed to read invitations to {}, {}", user_id, e);
+                HashSet::new()
+            }
+        };
+        let mut chats = match read_visible_chats(storage, user_id) {
+            Ok(chats) => chats,
+            Err(e) => {
+                error!("failed to read existing chats, {}", e);
+                Vec::new()
+            }
+        };
+        chats.sort_by_key(|c| c.id);
+        let updates = page
+            .apply(chats)
+            .into_iter()
+            .map(|c| {
+                let id = c.id;
+                let membership = my_membership(&c, user_id, &invited);
+                let (last_read_post_id, unread_posts) = read_position(storage, id, user_id);
+                ChatUpdate {
+                    chat: Some(c),
+                    currently_posts: storage.chat_posts_count(id).unwrap_or_default() as u64,
+                    degraded: storage.is_degraded(id).unwrap_or_default(),
+                    my_membership: membership as i32,
+                    last_read_post_id,
+                    unread_posts,
+                    muted: storage.is_chat_muted(id, user_id).unwrap_or_default(),
+                }
+            })
+            .collect();
+        (updates, invited)
+    }
+}
+
+// the post checked and stored, the idempotency key is handled by the caller
+async fn create_post_once<S: ChatStorage>(
+    chat_room: &Arc<ChatRoomImpl<S>>,
+    mut post: Post,
+    client: &ClientIdentity,
+) -> Result<PostId, Status> {
+    let limits = chat_room.tunables().limits;
+    // the forwarded one takes the text and the attachments of the original
+    if post.forwarded_from.is_none() {
+        if let Cow::Owned(text) = normalize_line_endings(&post.text) {
+            post.text = text;
+        }
+        limits
+            .post_limits()
+            .check(&post.text, post.attachments.len())
+            .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+    }
+    chat_room.limit_rate(Action::Post, post.user_id, client)?;
+    let stamp_author_names = limits.stamp_author_names;
+    blocking(chat_room, move |chat_room| {
+        // only members of an existing chat are allowed to post into it
+        match chat_room.storage.read_chat(post.chat_id) {
+            Err(e) => return Err(tonic::Status::internal(format!("failed read chats, {}", e))),
+            Ok(None) => {
+                return Err(tonic::Status::not_found(format!(
+                    "chat {} does not exist",
+                    post.chat_id
+                )))
+            }
+            Ok(Some(chat)) => {
+                if !chat.users.contains(&post.user_id) {
+                    return Err(tonic::Status::permission_denied(format!(
+                        "user {} is not a member of chat {}",
+                        post.user_id, post.chat_id
+                    )));
+                }
+            }
+        }
+        // the reply stays in the chat of the post replied to
+        if post.reply_to_post_id != NOT_POST_ID {
+            match chat_room.storage.locate_post(post.reply_to_post_id) {
+                Ok(Some((chat_id, _))) if chat_id == post.chat_id => {}
+                Ok(_) => {
+                    return Err(tonic::Status::invalid_argument(format!(
+                        "post {} replied to is not in chat {}",
+                        post.reply_to_post_id, post.chat_id
+                    )))
+                }
+                Err(e) => {
+                    return Err(tonic::Status::internal(format!(
+                        "failed locate post, {}",
+                        e
+                    )))
+                }
+            }
+        }
+        if let Some(origin) = post.forwarded_from.take() {
+            copy_forwarded(&chat_room.storage, &mut post, &origin)?;
+        }
+        // the attachments are referred to by their ids, the rest is as uploaded
+        for attachment in post.attachments.iter_mut() {
+            match chat_room.storage.read_attachment_info(attachment.id) {
+                Ok(Some(info)) => *attachment = info,
+                Ok(None) => {
+                    return Err(tonic::Status::invalid_argument(format!(
+                        "attachment {} does not exist",
+                        attachment.id
+                    )))
+                }
+                Err(e) => {
+                    return Err(tonic::Status::internal(format!(
+                        "failed read attachments, {}",
+                        e
+                    )))
+                }
+            }
+        }
+        post.created = Utc::now().timestamp() as u64;
+        // the reactions are toggled by the members afterwards only
+        post.reactions.clear();
+        // the name given by the client is never kept
+        post.author_name = if stamp_author_names {
+            match chat_room.storage.read_user(post.user_id) {
+                Ok(Some(user)) => author_name(&user),
+                Ok(None) => String::new(),
+                Err(e) => {
+                    warn!("failed to read author {}, {}", post.user_id, e);
+                    String::new()
+                }
+            }
+        } else {
+            String::new()
+        };
+        let degraded = chat_room
+            .storage
+            .is_degraded(post.chat_id)
+            .unwrap_or_default();
+        match write_new_post(&chat_room.storage, &mut post, new_post_id) {
+            Ok(true) => {}
+            Ok(false) => {
+                error!("failed to find a free post id");
+                return Err(tonic::Status::internal("failed to save post"));
+            }
+            Err(e) => {
+                error!("failed to save post, {}", e);
+                chat_room.check_degraded(post.chat_id, degraded);
+                return Err(tonic::Status::internal("failed to save post"));
+            }
+        }
+        let post_id = post.id;
+        chat_room.notify_new_post(post);
+        Ok(post_id)
+    })
+    .await
+}
+
 #[tonic::async_trait]
-impl ChatRoomService for ChatRoomImpl {
+impl<S: ChatStorage> ChatRoomService for Arc<ChatRoomImpl<S>> {
     #[doc = " Sends a reqistration request"]
     async fn register(
         &self,
         request: Request<UserInfo>,
     ) -> Result<Response<RegistrationInfo>, Status> {
-        debug!("register(): {:?}", &request);
+        debug!("register(): {:?}", request.get_ref());
+        let client = ClientIdentity::of(&request);
+        let known_id = metadata_u64(&request, USER_ID_KEY)?;
+        let secret = request
+            .metadata()
+            .get(USER_SECRET_KEY)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
         let user_info = request.into_inner();
-        let id = get_user_id(&user_info);
-        if let Ok(mut online_users) = self.online_users.write() {
-            online_users.insert(id);
-        } else {
-            error!("fatal internal, failed to access online users collection");
-        }
-        // test existing
-        match self.storage.read_user(id) {
-            Err(e) => return Err(tonic::Status::internal(format!("{}", e))),
-            Ok(opt) => {
-                if let Some(u) = opt {
-                    debug!("{} ({}) already registered", u.short_name, u.name);
-                    if !self.notify_user_changed(UserChanged::Online(u.id)).await {
-                        self.actualize_user_listeners();
+        let (mut response, id, new_secret) = blocking(self, move |chat_room| {
+            let storage = &chat_room.storage;
+            let read_user = |id| {
+                storage
+                    .read_user(id)
+                    .map_err(|e| tonic::Status::internal(format!("failed read users, {}", e)))
+            };
+            let read_secret = |id| {
+                storage
+                    .read_user_secret(id)
+                    .map_err(|e| tonic::Status::internal(format!("failed read users, {}", e)))
+            };
+            let taken = || {
+                tonic::Status::already_exists(format!(
+                    "short name '{}' is taken, choose another or present the assigned user id and secret",
+                    user_info.short_name
+                ))
+            };
+            // the returning user presents the id assigned before along with the secret,
+            // the id is known to everyone and proves nothing alone
+            let returning = match known_id {
+                Some(id) => read_user(id)?,
+                None => match storage
+                    .find_user_id(&user_info.short_name)
+                    .map_err(|e| tonic::Status::internal(format!("failed read users, {}", e)))?
+                {
+                    Some(id) => read_user(id)?,
+                    None => None,
+                },
+            };
+            if let Some(u) = returning {
+                let digest = read_secret(u.id)?;
+                let proven = match (&digest, &secret) {
+                    (Some(digest), Some(secret)) => *digest == secret_digest(secret),
+                    _ => false,
+                };
+                // the user registered before the secrets were issued has none,
+                // it is found by its names as then and is given the secret now
+                let legacy = digest.is_none()
+                    && u.short_name == user_info.short_name
+                    && u.name == user_info.name;
+                if !proven && !legacy {
+                    if known_id.is_none() {
+                        return Err(taken());
                     }
-                    return Ok(Response::new(RegistrationInfo {
-                        registration: Some(Registration { user_id: id }),
-                        created: u.created,
-                    }));
+                    warn!(
+                        "registration as {} without its secret refused, connected from {}",
+                        u.id, client
+                    );
+                    return Err(tonic::Status::unauthenticated(format!(
+                        "the secret of user {} is missing or wrong",
+                        u.id
+                    )));
                 }
+                let issued = if legacy {
+                    info!(
+                        "{} ({}) registered before the secrets were issued gets one, connected from {}",
+                        u.short_name, u.name, client
+                    );
+                    let secret = new_user_secret();
+                    storage
+                        .write_user_secret(u.id, &secret_digest(&secret))
+                        .map_err(|e| tonic::Status::internal(format!("{}", e)))?;
+                    Some(secret)
+                } else {
+                    debug!(
+                        "{} ({}) already registered, connected from {}",
+                        u.short_name, u.name, client
+                    );
+                    None
+                };
+                chat_room.presence.touch(u.id, Instant::now());
+                chat_room.notify_user_changed(UserChanged::Online(u.id));
+                let response = Response::new(RegistrationInfo {
+                    registration: Some(Registration { user_id: u.id }),
+                    created: u.created,
+                });
+                return Ok((response, u.id, issued));
             }
-        }
-        let new_user = User {
-            id,
-            name: user_info.name,
-            short_name: user_info.short_name,
-            created: Utc::now().timestamp() as u64,
-        };
-        // store new user
-        if !self
-            .notify_user_changed(UserChanged::Info(Arc::new(new_user.clone())))
-            .await
-        {
-            self.actualize_user_listeners();
-        }
-        if let Err(e) = self.storage.write_user(new_user.id, &new_user) {
-            Err(tonic::Status::internal(format!("{}", e)))
-        } else {
-            Ok(Response::new(RegistrationInfo {
+            let mut id = NOT_USER_ID;
+            for _ in 0..POST_ID_ATTEMPTS {
+                let candidate = new_user_id();
+                if read_user(candidate)?.is_none() {
+                    id = candidate;
+                    break;
+                }
+                warn!("user id {} is taken, regenerating", candidate);
+            }
+            if id == NOT_USER_ID {
+                return Err(tonic::Status::resource_exhausted("no free user id found"));
+            }
+            let new_user = User {
+                id,
+                name: user_info.name.clone(),
+                short_name: user_info.short_name.clone(),
+                created: Utc::now().timestamp() as u64,
+            };
+            // the same short name would make the users indistinguishable,
+            // the concurrent registration with it is checked in the same transaction
+            let secret = new_user_secret();
+            match storage.create_user(&new_user, &secret_digest(&secret)) {
+                Ok(true) => {}
+                Ok(false) => return Err(taken()),
+                Err(e) => return Err(tonic::Status::internal(format!("{}", e))),
+            }
+            info!(
+                "{} ({}) registered from {}",
+                new_user.short_name, new_user.name, client
+            );
+            chat_room.presence.touch(id, Instant::now());
+            chat_room.notify_user_changed(UserChanged::Info(Arc::new(new_user.clone())));
+            let response = Response::new(RegistrationInfo {
                 registration: Some(Registration { user_id: id }),
                 created: new_user.created,
-            }))
+            });
+            Ok((response, id, Some(secret)))
+        })
+        .await?;
+        // every following call of the client is to bring it
+        let token = self.new_token(id)?;
+        response
+            .metadata_mut()
+            .insert(SESSION_TOKEN_KEY, MetadataValue::from(token.as_str()));
+        // the new user is told the secret once, the client is to keep it
+        if let Some(secret) = new_secret {
+            response
+                .metadata_mut()
+                .insert(USER_SECRET_KEY, MetadataValue::from(secret.as_str()));
+        }
+        Ok(response)
+    }
+
+    #[doc = " Changes the display names of the user, its id stays the same"]
+    async fn update_user(
+        &self,
+        request: tonic::Request<UserUpdate>,
+    ) -> Result<tonic::Response<RpcResult>, tonic::Status> {
+        debug!("update_user(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let update = request.into_inner();
+        let name = update.name.trim().to_string();
+        let short_name = update.short_name.trim().to_string();
+        if name.is_empty() || short_name.is_empty() {
+            return Err(tonic::Status::invalid_argument(
+                "user name and short name must not be empty",
+            ));
         }
+        blocking(self, move |chat_room| {
+            let storage = &chat_room.storage;
+            // the short name of another user is not to be taken
+            let taken = match storage.find_user_id(&short_name)? {
+                Some(other_id) if other_id != update.user_id => storage.read_user(other_id)?,
+                _ => None,
+            };
+            if taken.is_some() {
+                return Err(
+                    ServerError::Conflict(format!("short name '{}' is taken", short_name)).into(),
+                );
+            }
+            let mut changed = false;
+            let updated = storage.update_user(update.user_id, |mut_ref_user| {
+                changed = mut_ref_user.name != name || mut_ref_user.short_name != short_name;
+                if changed {
+                    mut_ref_user.name = name.clone();
+                    mut_ref_user.short_name = short_name.clone();
+                }
+                changed
+            });
+            let user = updated.found(&format!("user {}", update.user_id))?;
+            if changed {
+                info!(
+                    "user {} renamed to {} ({})",
+                    user.id, user.short_name, user.name
+                );
+                chat_room.notify_user_changed(UserChanged::Info(Arc::new(user)));
+            }
+            Ok(Response::new(RpcResult {
+                ok: true,
+                description: String::from(if changed { "updated" } else { "unchanged" }),
+            }))
+        })
+        .await
     }
 
     #[doc = "Server streaming response type for the GetInvitations method."]
@@ -131,36 +893,75 @@ impl ChatRoomService for ChatRoomImpl {
         request: tonic::Request<Registration>,
     ) -> Result<tonic::Response<Self::GetInvitationsStream>, tonic::Status> {
         // get source channel of invitations
-        debug!("get_invitations(): {:?}", &request);
+        debug!("get_invitations(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let token = String::from(session_token(&request)?);
         let user_id = request.into_inner().user_id;
+        self.ensure_running()?;
         let (listener, notifier) = mpsc::channel(4);
         if let Ok(mut listeners) = self.invitations_listeners.write() {
             // test alive
-            listeners.retain(|k, v| {
-                if v.is_closed() {
+            listeners.retain(|k, sessions| {
+                sessions.retain(|_, v| !v.is_closed());
+                if sessions.is_empty() {
                     debug!("stop streaming invitations to {}", k);
                     false
                 } else {
                     true
                 }
             });
-            // add new
-            listeners.insert(user_id, listener);
+            // add new, replaces the former one of the session
+            listeners
+                .entry(user_id)
+                .or_default()
+                .insert(token, listener);
         } else {
             return Err(tonic::Status::internal("no access to invitation channel"));
         };
+        // unanswered invitations and replies came while the user was away
+        let stored = blocking(self, move |chat_room| {
+            let mut stored = chat_room
+                .storage
+                .read_invitations_to(user_id)
+                .unwrap_or_else(|e| {
+                    error!("failed to read invitations to {}, {}", user_id, e);
+                    Vec::new()
+                });
+            match chat_room.storage.take_invitation_replies(user_id) {
+                Ok(replies) => stored.extend(replies),
+                Err(e) => error!("failed to read invitation replies to {}, {}", user_id, e),
+            }
+            Ok(stored)
+        })
+        .await?;
         // launch stream source
         let (tx, rx) = mpsc::channel(4);
+        let guard = self.open_stream(user_id);
         tokio::spawn(async move {
             debug!("start streaming invitations to {}", user_id);
+            for invitation in stored {
+                if let Err(e) = tx.send(Ok(invitation)).await {
+                    error!("failed streaming invoitations: {}", e);
+                    return;
+                }
+            }
             let mut notifier = notifier;
-            while let Some(invitation) = notifier.recv().await {
+            loop {
+                // the vanished client is noticed without waiting for the next invitation
+                let invitation = tokio::select! {
+                    received = notifier.recv() => match received {
+                        Some(invitation) => invitation,
+                        None => break,
+                    },
+                    _ = tx.closed() => break,
+                };
                 if let Err(e) = tx.send(Ok(invitation)).await {
                     error!("failed streaming invoitations: {}", e);
                     break;
                 }
             }
             debug!("stream of invitations to {} has stopped", user_id);
+            drop(guard);
         });
         Ok(Response::new(Box::pin(
             tokio_stream::wrappers::ReceiverStream::new(rx),
@@ -172,44 +973,14 @@ impl ChatRoomService for ChatRoomImpl {
         &self,
         request: tonic::Request<Registration>,
     ) -> Result<tonic::Response<RpcResult>, tonic::Status> {
-        debug!("logout(): {:?}", &request);
+        debug!("logout(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let token = String::from(session_token(&request)?);
         let user_id = request.into_inner().user_id;
-        if let Ok(mut listeners) = self.users_listeners.write() {
-            if listeners.remove(&user_id).is_some() {
-                debug!("stop streaming users to {}", user_id);
-            }
-        } else {
-            error!("failed locking users listeners (logout)");
-        }
-        if let Ok(mut listeners) = self.chats_listeners.write() {
-            if listeners.remove(&user_id).is_some() {
-                debug!("stop streaming chats to {}", user_id);
-            }
-        } else {
-            error!("failed locking chats listeners (logout)");
-        }
-        if let Ok(mut listeners) = self.invitations_listeners.write() {
-            if listeners.remove(&user_id).is_some() {
-                debug!("stop streaming invitations to {}", user_id);
-            }
-        } else {
-            error!("failed locking invitations listeners (logout)");
-        }
-        if let Ok(mut listeners) = self.posts_listeners.write() {
-            if listeners.remove(&user_id).is_some() {
-                debug!("stop streaming posts to {}", user_id);
-            }
-        } else {
-            error!("failed locking posts listeners (logout)");
-        }
-        if let Ok(mut online_users) = self.online_users.write() {
-            online_users.remove(&user_id);
-        }
-        if !self
-            .notify_user_changed(UserChanged::Offline(user_id))
-            .await
-        {
-            self.actualize_user_listeners();
+        // stops streaming users, chats, posts and invitations to this session only
+        let other_sessions = self.end_session(&token, user_id);
+        if !other_sessions && self.presence.set_offline(user_id) {
+            self.notify_user_changed(UserChanged::Offline(user_id));
         }
         Ok(Response::new(RpcResult {
             ok: true,
@@ -226,32 +997,81 @@ impl ChatRoomService for ChatRoomImpl {
         &self,
         request: tonic::Request<Registration>,
     ) -> Result<tonic::Response<Self::GetPostsStream>, tonic::Status> {
-        debug!("get_posts(): {:?}", &request);
+        debug!("get_posts(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let session = self.session(&request)?;
+        let cursor = post_cursor(&request)?;
         let user_id = request.into_inner().user_id;
-        let (listener, notifier) = mpsc::channel::<Arc<Post>>(4);
-        if let Ok(mut listeners) = self.posts_listeners.write() {
-            listeners.insert(user_id, listener);
-        } else {
-            return Err(tonic::Status::internal("no access to posts listeners"));
-        }
-        // do not collect existing posts from chats where user is a member,
-        // client app must query desired posts itself
-        // start permanent listener that streams data to remote client
+        // subscribe before reading the posts to replay to miss nothing
+        let events = self.posts_events.subscribe();
+        // existing posts are collected only after the cursor of the resumed stream,
+        // client app must query the other desired posts itself
+        let replay = match cursor {
+            Some(cursor) => {
+                blocking(self, move |chat_room| {
+                    replay_posts(&chat_room.storage, user_id, &cursor).map_err(Status::from)
+                })
+                .await?
+            }
+            None => Vec::new(),
+        };
         let (tx, rx) = mpsc::channel(4);
-        tokio::spawn(async move {
-            debug!("start streaming posts to {}", user_id);
-            // re-translate new users
-            let mut notifier = notifier;
-            while let Some(post) = notifier.recv().await {
-                debug!("re-translating new post to {}", user_id);
-                if let Err(e) = tx.send(Ok((*post).clone())).await {
-                    error!("failed sending post: {}, stop", e);
-                    break;
+        spawn_stream(
+            "posts",
+            user_id,
+            replay.into_iter().map(Arc::new).collect::<Vec<_>>(),
+            events,
+            session,
+            self.open_stream(user_id),
+            tx,
+            move |notification: PostNotification| {
+                if notification.recipients.contains(&user_id) {
+                    Some(notification.post)
+                } else {
+                    None
                 }
-            }
-            debug!("stream of posts to {} has stopped", user_id);
-        });
-        // start streaming activity, data consumer
+            },
+        );
+        // shared posts are materialized only at the encoding boundary
+        let stream =
+            tokio_stream::wrappers::ReceiverStream::new(rx).map(|post| post.map(unwrap_shared));
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    #[doc = "Server streaming response type for the GetTyping method."]
+    type GetTypingStream =
+        Pin<Box<dyn Stream<Item = Result<TypingEvent, tonic::Status>> + Send + Sync + 'static>>;
+
+    #[doc = " Asks for the members of the user's chats starting and stopping typing"]
+    async fn get_typing(
+        &self,
+        request: tonic::Request<Registration>,
+    ) -> Result<tonic::Response<Self::GetTypingStream>, tonic::Status> {
+        debug!("get_typing(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let session = self.session(&request)?;
+        let user_id = request.into_inner().user_id;
+        let events = self.typing_events.subscribe();
+        let (tx, rx) = mpsc::channel(4);
+        // those typing now are not replayed, their indicators go off soon anyway
+        spawn_stream(
+            "typing",
+            user_id,
+            Vec::new(),
+            events,
+            session,
+            self.open_stream(user_id),
+            tx,
+            move |notification: TypingNotification| {
+                if notification.event.user_id != user_id
+                    && notification.recipients.contains(&user_id)
+                {
+                    Some(notification.event)
+                } else {
+                    None
+                }
+            },
+        );
         Ok(Response::new(Box::pin(
             tokio_stream::wrappers::ReceiverStream::new(rx),
         )))
@@ -266,70 +1086,40 @@ impl ChatRoomService for ChatRoomImpl {
         &self,
         request: tonic::Request<Registration>,
     ) -> Result<tonic::Response<Self::GetUsersStream>, tonic::Status> {
-        debug!("get_users(): {:?}", &request);
+        debug!("get_users(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let session = self.session(&request)?;
         let user_id = request.into_inner().user_id;
-        let (listener, notifier) = mpsc::channel::<UserChanged>(4);
-        if let Ok(mut listeners) = self.users_listeners.write() {
-            // test alive
-            listeners.retain(|k, v| {
-                if v.is_closed() {
-                    debug!("stop streaming users to {}", k);
-                    false
-                } else {
-                    true
-                }
-            });
-            // add new
-            listeners.insert(user_id, listener);
-        } else {
-            return Err(tonic::Status::internal("no access to users listeners"));
-        }
-        // collect existing users
-        let existing = if let Ok(mut users) = self.storage.read_all_users() {
-            users.retain(|u| u.id != user_id);
-            users
+        // subscribe before reading existing users to miss nothing
+        let events = self.users_events.subscribe();
+        // collect existing users with their statuses
+        let existing = blocking(self, move |chat_room| {
+            Ok(chat_room.users_snapshot(user_id, &Page::default()))
+        })
+        .await?;
+        let initial = if !existing.added.is_empty() {
+            debug!(
+                "sending {} existing users to {}",
+                existing.added.len(),
+                user_id
+            );
+            Some(existing)
         } else {
-            error!("failed to read existing users");
-            Vec::new()
+            None
         };
-        // collect statuses
-        let mut online = Vec::new();
-        let mut offline = Vec::new();
-        if let Ok(online_users) = self.online_users.read() {
-            for u in &existing {
-                if online_users.contains(&u.id) {
-                    online.push(u.id);
-                } else {
-                    offline.push(u.id);
-                }
-            }
-        } else {
-            error!("fatal internal, failed to access users statuses");
-        }
-        // start permanent listener that streams data to remote client
+        // start permanent listener that streams data to remote client,
+        // all new users will start with online status
         let (tx, rx) = mpsc::channel(4);
-        tokio::spawn(async move {
-            debug!("start streaming users to {}", user_id);
-            if !existing.is_empty() {
-                // send existing users
-                let start_update = UpdateUsers {
-                    added: existing,
-                    online,
-                    offline,
-                };
-                debug!(
-                    "sending {} existing users to {}",
-                    start_update.added.len(),
-                    user_id
-                );
-                if let Err(e) = tx.send(Ok(start_update)).await {
-                    error!("failed sending existing users: {}", e);
-                }
-            }
-            // re-translate new users, all new users will start with online status
-            let mut notifier = notifier;
-            while let Some(notification) = notifier.recv().await {
-                let update = match notification {
+        spawn_stream(
+            "users",
+            user_id,
+            initial,
+            events,
+            session,
+            self.open_stream(user_id),
+            tx,
+            move |notification| {
+                Some(match notification {
                     UserChanged::Info(user) => {
                         debug!("re-translating new online user {} to {}", user.id, user_id);
                         UpdateUsers {
@@ -342,7 +1132,7 @@ impl ChatRoomService for ChatRoomImpl {
                         debug!("re-translating entered {} to {}", id, user_id);
                         UpdateUsers {
                             added: Vec::new(),
-                            online: vec![user_id],
+                            online: vec![id],
                             offline: Vec::new(),
                         }
                     }
@@ -351,17 +1141,12 @@ impl ChatRoomService for ChatRoomImpl {
                         UpdateUsers {
                             added: Vec::new(),
                             online: Vec::new(),
-                            offline: vec![user_id],
+                            offline: vec![id],
                         }
                     }
-                };
-                if let Err(e) = tx.send(Ok(update)).await {
-                    error!("failed sending users update: {}, stop", e);
-                    break;
-                }
-            }
-            debug!("stream of users to {} has stopped", user_id);
-        });
+                })
+            },
+        );
         // start streaming activity, data consumer
         Ok(Response::new(Box::pin(
             tokio_stream::wrappers::ReceiverStream::new(rx),
@@ -377,96 +1162,168 @@ impl ChatRoomService for ChatRoomImpl {
         &self,
         request: tonic::Request<Registration>,
     ) -> Result<tonic::Response<Self::GetChatsStream>, tonic::Status> {
-        debug!("get_chats(): {:?}", &request);
+        debug!("get_chats(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let session = self.session(&request)?;
         let user_id = request.into_inner().user_id;
-        let (listener, notifier) = mpsc::channel::<ChatChanged>(4);
-        if let Ok(mut listeners) = self.chats_listeners.write() {
-            listeners.insert(user_id, listener);
-        } else {
-            // failed locking listeners
-            return Err(tonic::Status::internal("no access to chat listeners"));
-        }
+        // subscribe before reading existing chats to miss nothing
+        let events = self.chats_events.subscribe();
         // collect existing chats
-        let existing = if let Ok(mut chats) = self.storage.read_all_chats() {
-            chats.retain(|c| is_chat_visible_for(&c, user_id));
-            chats
-                .drain(..)
-                .map(|c| {
-                    let id = c.id;
-                    ChatUpdate {
-                        chat: Some(c),
-                        currently_posts: self.storage.chat_posts_count(id).unwrap_or_default()
-                            as u64,
-                    }
-                })
-                .collect()
+        let (existing, mut invited) = blocking(self, move |chat_room| {
+            Ok(chat_room.chats_snapshot(user_id, &Page::default()))
+        })
+        .await?;
+        let mut muted: HashSet<ChatId> = existing
+            .iter()
+            .filter(|u| u.muted)
+            .filter_map(|u| u.chat.as_ref().map(|c| c.id))
+            .collect();
+        let initial = if !existing.is_empty() {
+            debug!("sending {} existing chats to {}", existing.len(), user_id);
+            Some(UpdateChats {
+                updated: existing,
+                gone: Vec::new(),
+            })
         } else {
-            error!("failed to read existing chats");
-            Vec::new()
+            None
         };
         // start permanent listener that streams data to remote client
         let (tx, rx) = mpsc::channel(4);
-        tokio::spawn(async move {
-            debug!("start streaming chats to {}", user_id);
-            if !existing.is_empty() {
-                // send existing chats
-                let start_update = UpdateChats {
-                    updated: existing,
-                    gone: Vec::new(),
-                };
-                debug!(
-                    "sending {} existing chats to {}",
-                    start_update.updated.len(),
-                    user_id
-                );
-                if let Err(e) = tx.send(Ok(start_update)).await {
-                    error!("failed sending existing chats: {}", e);
+        spawn_stream(
+            "chats",
+            user_id,
+            initial,
+            events,
+            session,
+            self.open_stream(user_id),
+            tx,
+            move |notification| match notification {
+                ChatChanged::Updated(chat, posts, degraded) => {
+                    if !is_chat_visible_for(&chat, user_id) {
+                        return None;
+                    }
+                    let membership = my_membership(&chat, user_id, &invited);
+                    if membership == Membership::Member {
+                        // entering answers the invitations
+                        invited.remove(&chat.id);
+                    }
+                    debug!("re-translating new chat to {}", user_id);
+                    // the read position is told with the existing chats only
+                    Some(UpdateChats {
+                        updated: vec![ChatUpdate {
+                            chat: Some((*chat).clone()),
+                            currently_posts: posts,
+                            degraded,
+                            my_membership: membership as i32,
+                            last_read_post_id: NOT_POST_ID,
+                            unread_posts: 0,
+                            muted: muted.contains(&chat.id),
+                        }],
+                        gone: Vec::new(),
+                    })
                 }
-            }
-            // re-translate new chats
-            let mut notifier = notifier;
-            while let Some(notification) = notifier.recv().await {
-                let update = match notification {
-                    ChatChanged::Updated(chat) => {
-                        if !is_chat_visible_for(&chat, user_id) {
-                            continue;
-                        }
-                        debug!("re-translating new chat to {}", user_id);
-                        UpdateChats {
-                            updated: vec![ChatUpdate {
-                                chat: Some((*chat).clone()),
-                                currently_posts: 0,
-                            }],
-                            gone: Vec::new(),
+                ChatChanged::Hidden(chat) => {
+                    if is_chat_visible_for(&chat, user_id) {
+                        return None;
+                    }
+                    debug!("re-translating hidden chat to {}", user_id);
+                    Some(UpdateChats {
+                        updated: Vec::new(),
+                        gone: vec![chat.id],
+                    })
+                }
+                ChatChanged::Invited(id, invitee, pending) => {
+                    // the update of the chat follows
+                    if invitee == user_id {
+                        if pending {
+                            invited.insert(id);
+                        } else {
+                            invited.remove(&id);
                         }
                     }
-                    ChatChanged::Closed(id) => {
-                        debug!("re-translating closed chat to {}", user_id);
-                        UpdateChats {
-                            updated: Vec::new(),
-                            gone: vec![id],
+                    None
+                }
+                ChatChanged::Muted(id, muter, is_muted) => {
+                    // the update of the chat follows
+                    if muter == user_id {
+                        if is_muted {
+                            muted.insert(id);
+                        } else {
+                            muted.remove(&id);
                         }
                     }
-                };
-                if let Err(e) = tx.send(Ok(update)).await {
-                    error!("failed sending chat: {}", e);
-                    break;
+                    None
                 }
-            }
-            debug!("stream of chats to {} has stopped", user_id);
-        });
+                ChatChanged::Removed(id, removed) => {
+                    if removed != user_id {
+                        return None;
+                    }
+                    invited.remove(&id);
+                    debug!("re-translating removal from chat to {}", user_id);
+                    Some(UpdateChats {
+                        updated: Vec::new(),
+                        gone: vec![id],
+                    })
+                }
+                ChatChanged::Closed(id) => {
+                    invited.remove(&id);
+                    debug!("re-translating closed chat to {}", user_id);
+                    Some(UpdateChats {
+                        updated: Vec::new(),
+                        gone: vec![id],
+                    })
+                }
+            },
+        );
         // start streaming activity, data consumer
         Ok(Response::new(Box::pin(
             tokio_stream::wrappers::ReceiverStream::new(rx),
         )))
     }
 
+    #[doc = " Lists the users once, a page of what get_users() starts with"]
+    async fn list_users(
+        &self,
+        request: tonic::Request<Registration>,
+    ) -> Result<tonic::Response<UpdateUsers>, tonic::Status> {
+        debug!("list_users(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let page = list_page(&request)?;
+        let user_id = request.into_inner().user_id;
+        let users = blocking(self, move |chat_room| {
+            Ok(chat_room.users_snapshot(user_id, &page))
+        })
+        .await?;
+        Ok(Response::new(users))
+    }
+
+    #[doc = " Lists the chats once, a page of what get_chats() starts with"]
+    async fn list_chats(
+        &self,
+        request: tonic::Request<Registration>,
+    ) -> Result<tonic::Response<UpdateChats>, tonic::Status> {
+        debug!("list_chats(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let page = list_page(&request)?;
+        let user_id = request.into_inner().user_id;
+        let (updated, _) = blocking(self, move |chat_room| {
+            Ok(chat_room.chats_snapshot(user_id, &page))
+        })
+        .await?;
+        Ok(Response::new(UpdateChats {
+            updated,
+            gone: Vec::new(),
+        }))
+    }
+
     #[doc = " Creates new post"]
     async fn create_post(
         &self,
         request: tonic::Request<Post>,
     ) -> Result<tonic::Response<RpcResult>, tonic::Status> {
-        debug!("create_post(): {:?}", &request);
+        debug!("create_post(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let client = ClientIdentity::of(&request);
         let mut post = request.into_inner();
         if post.id != NOT_POST_ID {
             return Err(tonic::Status::invalid_argument(format!(
@@ -474,18 +1331,250 @@ impl ChatRoomService for ChatRoomImpl {
                 NOT_POST_ID
             )));
         }
-        post.id = new_post_id();
-        post.created = Utc::now().timestamp() as u64;
-        if let Err(e) = self.storage.write_post(&post) {
-            error!("failed to save post, {}", e);
+        // the notices are posted by the server only, the members would fake them otherwise
+        post.kind = PostKind::Regular as i32;
+        // the retry of the post created already is answered as the first attempt was,
+        // the one made meanwhile waits for the first attempt to end
+        let key = std::mem::take(&mut post.idempotency_key);
+        let user_id = post.user_id;
+        let mut first = None;
+        while !key.is_empty() && first.is_none() {
+            match self.accepted_posts.reserve(user_id, &key) {
+                Reserved::First(reserved) => first = Some(reserved),
+                Reserved::Accepted(post_id) => {
+                    debug!("post {} is retried", post_id);
+                    return Ok(post_accepted(post_id));
+                }
+                // closed if the first attempt fails, the key is reserved again then
+                Reserved::Pending(mut pending) => {
+                    let _ = pending.changed().await;
+                }
+            }
         }
-        if !self.notify_new_post(post).await {
-            self.actualize_post_listeners();
+        let result = create_post_once(self, post, &client).await;
+        if let Some(first) = first {
+            match &result {
+                Ok(post_id) => self.accepted_posts.accept(user_id, key, first, *post_id),
+                Err(_) => self.accepted_posts.release(user_id, &key, first),
+            }
         }
-        Ok(Response::new(RpcResult {
-            ok: true,
-            description: String::from("accepted"),
-        }))
+        result.map(post_accepted)
+    }
+
+    #[doc = " Tells the other members of the chat the user is typing"]
+    async fn set_typing(
+        &self,
+        request: tonic::Request<ChatReference>,
+    ) -> Result<tonic::Response<RpcResult>, tonic::Status> {
+        debug!("set_typing(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let chat_ref = request.into_inner();
+        blocking(self, move |chat_room| {
+            let chat = chat_room
+                .storage
+                .read_chat(chat_ref.chat_id)
+                .found("chat")?;
+            if !chat.users.contains(&chat_ref.user_id) {
+                return Err(tonic::Status::permission_denied(format!(
+                    "user {} is not a member of chat {}",
+                    chat_ref.user_id, chat_ref.chat_id
+                )));
+            }
+            let members = chat.users;
+            if let Some(recipients) =
+                chat_room
+                    .typing
+                    .start(chat_ref.chat_id, chat_ref.user_id, members, Instant::now())
+            {
+                chat_room.notify_typing(chat_ref.chat_id, chat_ref.user_id, true, recipients);
+            }
+            Ok(Response::new(RpcResult {
+                ok: true,
+                description: String::from("typing"),
+            }))
+        })
+        .await
+    }
+
+    #[doc = " Deletes own post"]
+    async fn delete_post(
+        &self,
+        request: tonic::Request<PostReference>,
+    ) -> Result<tonic::Response<RpcResult>, tonic::Status> {
+        debug!("delete_post(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let post_ref = request.into_inner();
+        blocking(self, move |chat_room| {
+            let storage = &chat_room.storage;
+            match storage.locate_post(post_ref.post_id)? {
+                Some((chat_id, _)) if chat_id == post_ref.chat_id => {}
+                _ => return Err(ServerError::NotFound(String::from("post")).into()),
+            }
+            let post = storage
+                .read_post(post_ref.chat_id, post_ref.post_id)
+                .found("post")?;
+            if post.user_id != post_ref.user_id {
+                return Err(tonic::Status::permission_denied(format!(
+                    "user {} is not the author of post {}",
+                    post_ref.user_id, post_ref.post_id
+                )));
+            }
+            storage
+                .remove_post(post_ref.chat_id, post_ref.post_id)
+                .found("post")?;
+            chat_room.notify_post_deleted(post_ref.chat_id, post_ref.post_id);
+            Ok(Response::new(RpcResult {
+                ok: true,
+                description: String::from("deleted"),
+            }))
+        })
+        .await
+    }
+
+    #[doc = " Toggles the reaction of the user to the post"]
+    async fn react_to_post(
+        &self,
+        request: tonic::Request<Reaction>,
+    ) -> Result<tonic::Response<RpcResult>, tonic::Status> {
+        debug!("react_to_post(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let reaction = request.into_inner();
+        if reaction.emoji.is_empty() || reaction.emoji.len() > MAX_EMOJI_LEN {
+            return Err(tonic::Status::invalid_argument(format!(
+                "emoji must be 1 to {} bytes",
+                MAX_EMOJI_LEN
+            )));
+        }
+        blocking(self, move |chat_room| {
+            let storage = &chat_room.storage;
+            let chat = storage.read_chat(reaction.chat_id).found("chat")?;
+            if !chat.users.contains(&reaction.user_id) {
+                return Err(tonic::Status::permission_denied(format!(
+                    "user {} is not a member of chat {}",
+                    reaction.user_id, reaction.chat_id
+                )));
+            }
+            let _reacting = chat_room
+                .reacting
+                .lock()
+                .map_err(|_| tonic::Status::internal("reactions are poisoned"))?;
+            let mut post = storage
+                .read_post(reaction.chat_id, reaction.post_id)
+                .found("post")?;
+            let added = toggle_reaction(&mut post, reaction.user_id, &reaction.emoji);
+            // removed meanwhile
+            storage.update_post(&post).found("post")?;
+            chat_room.notify_post_updated(post);
+            Ok(Response::new(RpcResult {
+                ok: true,
+                description: String::from(if added { "reacted" } else { "unreacted" }),
+            }))
+        })
+        .await
+    }
+
+    #[doc = " Uploads the content of the attachment to refer to by the posts"]
+    async fn upload_attachment(
+        &self,
+        request: tonic::Request<tonic::Streaming<AttachmentChunk>>,
+    ) -> Result<tonic::Response<AttachmentInfo>, tonic::Status> {
+        // the chunks carry no user, the upload is of the one the session is of
+        let user_id = self.authenticate(&request)?;
+        debug!("upload_attachment(): by {}", user_id);
+        let max_size = self.tunables().limits.max_attachment_size;
+        let (name, content) = match collect_upload(request.into_inner(), max_size).await {
+            Ok(upload) => upload,
+            Err(e) => {
+                warn!("upload of {} is refused, {}", user_id, e.message());
+                return Err(e);
+            }
+        };
+        let mut info = AttachmentInfo {
+            id: 0,
+            name,
+            size: content.len() as u64,
+        };
+        blocking(self, move |chat_room| {
+            // random ids like those of the posts
+            for _ in 0..POST_ID_ATTEMPTS {
+                info.id = new_post_id();
+                match chat_room.storage.write_attachment(&info, &content) {
+                    Ok(true) => return Ok(Response::new(info)),
+                    Ok(false) => warn!("attachment id {} is taken, regenerating", info.id),
+                    Err(e) => {
+                        error!("failed to save attachment, {}", e);
+                        return Err(tonic::Status::internal("failed to save attachment"));
+                    }
+                }
+            }
+            error!("failed to find a free attachment id");
+            Err(tonic::Status::internal("failed to save attachment"))
+        })
+        .await
+    }
+
+    #[doc = "Server streaming response type for the DownloadAttachment method."]
+    type DownloadAttachmentStream =
+        Pin<Box<dyn Stream<Item = Result<AttachmentChunk, tonic::Status>> + Send + Sync + 'static>>;
+
+    #[doc = " Downloads the content of the attachment, the first chunk names it"]
+    async fn download_attachment(
+        &self,
+        request: tonic::Request<AttachmentReference>,
+    ) -> Result<tonic::Response<Self::DownloadAttachmentStream>, tonic::Status> {
+        debug!("download_attachment(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let attachment_id: AttachmentId = request.into_inner().attachment_id;
+        let info = blocking(self, move |chat_room| {
+            match chat_room.storage.read_attachment_info(attachment_id) {
+                Ok(Some(info)) => Ok(info),
+                Ok(None) => Err(tonic::Status::not_found("attachment does not exist")),
+                Err(e) => Err(tonic::Status::internal(format!(
+                    "failed read attachments, {}",
+                    e
+                ))),
+            }
+        })
+        .await?;
+        let (tx, rx) = mpsc::channel(4);
+        let chat_room = self.clone();
+        tokio::spawn(async move {
+            let header = AttachmentChunk {
+                name: info.name,
+                size: info.size,
+                data: Vec::new(),
+            };
+            if tx.send(Ok(header)).await.is_err() {
+                return;
+            }
+            // a chunk at a time is read, the content is never loaded as a whole
+            for idx in 0.. {
+                let chunk = blocking(&chat_room, move |chat_room| {
+                    chat_room
+                        .storage
+                        .read_attachment_chunk(attachment_id, idx)
+                        .map_err(|e| {
+                            tonic::Status::internal(format!("failed read attachments, {}", e))
+                        })
+                })
+                .await;
+                let item = match chunk {
+                    Ok(Some(data)) => Ok(AttachmentChunk {
+                        data,
+                        ..Default::default()
+                    }),
+                    Ok(None) => break,
+                    Err(e) => Err(e),
+                };
+                let failed = item.is_err();
+                if tx.send(item).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(
+            tokio_stream::wrappers::ReceiverStream::new(rx),
+        )))
     }
 
     #[doc = " Creates new chat"]
@@ -493,68 +1582,233 @@ impl ChatRoomService for ChatRoomImpl {
         &self,
         request: tonic::Request<ChatInfo>,
     ) -> Result<tonic::Response<Chat>, tonic::Status> {
-        debug!("create_chat(): {:?}", &request);
-        let info = request.get_ref();
-        let users = if info.auto_enter {
-            // filter out duplicated users and sort them as well
-            let mut tmp = BTreeSet::new();
-            tmp.insert(info.user_id);
-            for u in &info.desired_users {
-                tmp.insert(*u);
-            }
-            tmp.into_iter().collect()
-        } else {
-            Vec::new()
-        };
-        let id = get_chat_id(&info.description, &users);
-        // test chat exists and enter the chat if that has not been done before
-        match self.storage.update_chat(id, |mut_ref_chat| {
-            if info.auto_enter && !mut_ref_chat.users.contains(&info.user_id) {
-                mut_ref_chat.users.push(info.user_id);
-                true
-            } else {
-                false
-            }
-        }) {
-            Ok(Some(chat)) => {
-                // chat was found & updated if needed
-                if !self
-                    .notify_chat_changed(ChatChanged::Updated(Arc::new(chat.clone())))
-                    .await
-                {
-                    self.actualize_chat_listeners();
+        debug!("create_chat(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let info = request.into_inner();
+        let description = normalize_description(&info.description);
+        let limits = self.tunables().limits;
+        if description.chars().count() > limits.max_description_len {
+            return Err(tonic::Status::invalid_argument(format!(
+                "chat description exceeds {} chars",
+                limits.max_description_len
+            )));
+        }
+        // filter out duplicated users and sort them as well
+        let mut members = BTreeSet::new();
+        members.insert(info.user_id);
+        for u in &info.desired_users {
+            members.insert(*u);
+        }
+        if members.len() > limits.max_chat_members {
+            return Err(tonic::Status::invalid_argument(format!(
+                "chat cannot contain more than {} users",
+                limits.max_chat_members
+            )));
+        }
+        if description.is_empty() && !info.auto_enter && members.len() == 1 {
+            // such a chat is neither visible nor discoverable by anybody
+            return Err(tonic::Status::invalid_argument(
+                "chat without description must have members",
+            ));
+        }
+        let members: Vec<UserId> = members.into_iter().collect();
+        blocking(self, move |chat_room| {
+            for probe in 0..CHAT_ID_PROBES {
+                let id = get_chat_id(&description, &members, probe);
+                // test chat exists and enter the chat if that has not been done before
+                let mut status = CHAT_STATUS_FOUND;
+                let mut collided = false;
+                // the user removed by the owner does not enter by creating the chat again
+                let banned = info.auto_enter && chat_room.storage.is_banned(id, info.user_id)?;
+                let mut refused = false;
+                match chat_room.storage.update_chat(id, |mut_ref_chat| {
+                    if !is_same_chat(mut_ref_chat, &description, &members) {
+                        collided = true;
+                        return false;
+                    }
+                    let mut updated = false;
+                    if info.auto_enter && !mut_ref_chat.users.contains(&info.user_id) {
+                        if banned {
+                            refused = true;
+                            return false;
+                        }
+                        mut_ref_chat.users.push(info.user_id);
+                        updated = true;
+                    }
+                    // only a member can make the chat permanent, it is never downgraded
+                    if info.permanent
+                        && !mut_ref_chat.permanent
+                        && mut_ref_chat.users.contains(&info.user_id)
+                    {
+                        mut_ref_chat.permanent = true;
+                        status = CHAT_STATUS_FLAGS_ADJUSTED;
+                        updated = true;
+                    }
+                    updated
+                }) {
+                    Ok(Some(_)) if collided => {
+                        // never enter an unrelated chat, its history is not for strangers
+                        warn!("chat id {} is taken by another chat, probing next", id);
+                    }
+                    Ok(Some(_)) if refused => {
+                        return Err(tonic::Status::permission_denied(format!(
+                            "user {} is not invited to chat {}",
+                            info.user_id, id
+                        )))
+                    }
+                    Ok(Some(chat)) => {
+                        // chat was found & updated if needed
+                        chat_room.notify_chat_updated(chat.clone());
+                        return Ok(with_chat_status(Response::new(chat), status));
+                    }
+                    Ok(None) => {
+                        // chat was not found, add new
+                        let users = if info.auto_enter { members } else { Vec::new() };
+                        let chat = Chat {
+                            id,
+                            permanent: info.permanent,
+                            description,
+                            users,
+                            created: Utc::now().timestamp() as u64,
+                            owner_id: info.user_id,
+                            export_discouraged: info.export_discouraged,
+                        };
+                        return if let Err(e) = chat_room.storage.write_chat(id, &chat) {
+                            Err(tonic::Status::internal(format!(
+                                "failed to create chat, {}",
+                                e
+                            )))
+                        } else {
+                            chat_room.notify_chat_updated(chat.clone());
+                            Ok(with_chat_status(Response::new(chat), CHAT_STATUS_CREATED))
+                        };
+                    }
+                    Err(e) => {
+                        return Err(tonic::Status::internal(format!(
+                            "failed to access chats, {}",
+                            e
+                        )))
+                    }
                 }
-                Ok(Response::new(chat))
             }
-            Ok(None) => {
-                // chat was not found, add new
-                let chat = Chat {
-                    id,
-                    permanent: info.permanent,
-                    description: info.description.clone(),
-                    users,
-                    created: Utc::now().timestamp() as u64,
-                };
-                if let Err(e) = self.storage.write_chat(id, &chat) {
-                    Err(tonic::Status::internal(format!(
-                        "failed to create chat, {}",
-                        e
+            Err(tonic::Status::already_exists(format!(
+                "chat '{}' conflicts with other chats, choose another description",
+                description
+            )))
+        })
+        .await
+    }
+
+    #[doc = " Changes the description and the flags of the chat"]
+    async fn update_chat_info(
+        &self,
+        request: tonic::Request<ChatInfoUpdate>,
+    ) -> Result<tonic::Response<Chat>, tonic::Status> {
+        debug!("update_chat_info(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let update = request.into_inner();
+        let description = normalize_description(&update.description);
+        let limits = self.tunables().limits;
+        if description.chars().count() > limits.max_description_len {
+            return Err(tonic::Status::invalid_argument(format!(
+                "chat description exceeds {} chars",
+                limits.max_description_len
+            )));
+        }
+        blocking(self, move |chat_room| {
+            let storage = &chat_room.storage;
+            let chat = match storage.read_chat(update.chat_id) {
+                Ok(Some(chat)) => chat,
+                Ok(None) => {
+                    return Err(tonic::Status::not_found(format!(
+                        "chat {} does not exist",
+                        update.chat_id
                     )))
-                } else {
-                    if !self
-                        .notify_chat_changed(ChatChanged::Updated(Arc::new(chat.clone())))
-                        .await
-                    {
-                        self.actualize_chat_listeners();
+                }
+                Err(e) => return Err(tonic::Status::internal(format!("failed read chats, {}", e))),
+            };
+            if !chat.users.contains(&update.user_id) {
+                return Err(tonic::Status::permission_denied(format!(
+                    "user {} is not a member of chat {}",
+                    update.user_id, chat.id
+                )));
+            }
+            if update.export_discouraged != chat.export_discouraged
+                && chat.owner_id != update.user_id
+            {
+                return Err(tonic::Status::permission_denied(format!(
+                    "user {} is not the owner of chat {} to change its export",
+                    update.user_id, chat.id
+                )));
+            }
+            // the id is kept, so the chat the new description leads to must not exist
+            let mut members = chat.users.clone();
+            members.sort_unstable();
+            for probe in 0..CHAT_ID_PROBES {
+                let twin_id = get_chat_id(&description, &members, probe);
+                if twin_id == chat.id {
+                    break;
+                }
+                match storage.read_chat(twin_id) {
+                    Ok(None) => break,
+                    Ok(Some(twin)) if is_same_chat(&twin, &description, &members) => {
+                        return Err(tonic::Status::already_exists(format!(
+                            "chat '{}' already exists",
+                            description
+                        )))
+                    }
+                    // an unrelated chat of the same hash, the twin might be at the next probe
+                    Ok(Some(_)) => {}
+                    Err(e) => {
+                        return Err(tonic::Status::internal(format!("failed read chats, {}", e)))
+                    }
+                }
+            }
+            let was_public = !chat.description.is_empty();
+            // the requester could have left meanwhile
+            let mut is_member = true;
+            let mut changed = false;
+            let updated = storage.update_chat(chat.id, |mut_ref_chat| {
+                is_member = mut_ref_chat.users.contains(&update.user_id);
+                changed = is_member
+                    && (mut_ref_chat.description != description
+                        || mut_ref_chat.permanent != update.permanent
+                        || mut_ref_chat.export_discouraged != update.export_discouraged);
+                if changed {
+                    mut_ref_chat.description = description.clone();
+                    mut_ref_chat.permanent = update.permanent;
+                    mut_ref_chat.export_discouraged = update.export_discouraged;
+                }
+                changed
+            });
+            match updated {
+                Ok(Some(_)) if !is_member => Err(tonic::Status::permission_denied(format!(
+                    "user {} is not a member of chat {}",
+                    update.user_id, update.chat_id
+                ))),
+                Ok(Some(chat)) => {
+                    if changed {
+                        info!("chat {} updated by {}", chat.id, update.user_id);
+                        // those who are not members lose the chat turned private
+                        if was_public && chat.description.is_empty() {
+                            chat_room
+                                .notify_chat_changed(ChatChanged::Hidden(Arc::new(chat.clone())));
+                        }
+                        chat_room.notify_chat_updated(chat.clone());
                     }
                     Ok(Response::new(chat))
                 }
+                Ok(None) => Err(tonic::Status::not_found(format!(
+                    "chat {} does not exist",
+                    update.chat_id
+                ))),
+                Err(e) => Err(tonic::Status::internal(format!(
+                    "failed to access chats, {}",
+                    e
+                ))),
             }
-            Err(e) => Err(tonic::Status::internal(format!(
-                "failed to access chats, {}",
-                e
-            ))),
-        }
+        })
+        .await
     }
 
     #[doc = " Invites user to chat"]
@@ -562,165 +1816,607 @@ impl ChatRoomService for ChatRoomImpl {
         &self,
         request: tonic::Request<Invitation>,
     ) -> Result<tonic::Response<RpcResult>, tonic::Status> {
-        debug!("invite_user(): {:?}", &request);
+        debug!("invite_user(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().from_user_id)?;
+        let client = ClientIdentity::of(&request);
         let invitation = request.into_inner();
-        // test chat exists
-        match self.storage.read_chat(invitation.chat_id) {
-            Err(e) => return Err(tonic::Status::internal(format!("failed read chats, {}", e))),
-            Ok(None) => {
-                return Err(tonic::Status::not_found(format!(
-                    "chat {} does not exist",
-                    invitation.chat_id
-                )))
-            }
-            _ => {}
-        }
-        // test recepient exists
-        match self.storage.read_user(invitation.to_user_id) {
-            Err(e) => return Err(tonic::Status::internal(format!("{}", e))),
-            Ok(opt) => {
-                if opt.is_none() {
+        self.limit_rate(Action::Invitation, invitation.from_user_id, &client)?;
+        let (chat_id, from_user_id, to_user_id) = (
+            invitation.chat_id,
+            invitation.from_user_id,
+            invitation.to_user_id,
+        );
+        blocking(self, move |chat_room| {
+            // test chat exists
+            match chat_room.storage.read_chat(chat_id) {
+                Err(e) => return Err(tonic::Status::internal(format!("failed read chats, {}", e))),
+                Ok(None) => {
                     return Err(tonic::Status::not_found(format!(
-                        "user {} is not registered",
-                        invitation.to_user_id
-                    )));
+                        "chat {} does not exist",
+                        chat_id
+                    )))
                 }
+                // only the owner takes back the user it has removed
+                Ok(Some(chat)) => {
+                    if chat.owner_id != from_user_id
+                        && chat_room.storage.is_banned(chat_id, to_user_id)?
+                    {
+                        return Err(tonic::Status::permission_denied(format!(
+                            "user {} has been removed from chat {} by its owner",
+                            to_user_id, chat_id
+                        )));
+                    }
+                }
+            }
+            // test recepient exists
+            match chat_room.storage.read_user(to_user_id) {
+                Err(e) => Err(tonic::Status::internal(format!("{}", e))),
+                Ok(None) => Err(tonic::Status::not_found(format!(
+                    "user {} is not registered",
+                    to_user_id
+                ))),
+                Ok(Some(_)) => Ok(()),
             }
+        })
+        .await?;
+        // try to get send channels of all the sessions and send invitation
+        let senders = self.invitations_senders(invitation.to_user_id);
+        if senders.is_empty() {
+            return Err(tonic::Status::not_found(format!(
+                "{} did not subscribe to invitations",
+                invitation.to_user_id
+            )));
         }
-        // try to get send channel and send invitation
-        let tx = if let Ok(listeners) = self.invitations_listeners.read() {
-            if let Some(tx) = listeners.get(&invitation.to_user_id) {
-                tx.clone()
-            } else {
-                return Err(tonic::Status::not_found(format!(
-                    "{} did not subscribe to invitations",
-                    invitation.to_user_id
-                )));
+        // kept until answered
+        let stored = invitation.clone();
+        blocking(self, move |chat_room| {
+            chat_room.storage.write_invitation(&stored).map_err(|e| {
+                error!("failed to store invitation: {}", e);
+                tonic::Status::internal("failed to store invitation")
+            })?;
+            chat_room.notify_invitation(stored.chat_id, stored.to_user_id, true);
+            Ok(())
+        })
+        .await?;
+        let mut delivered = false;
+        for tx in senders {
+            match tx.send(invitation.clone()).await {
+                Ok(_) => delivered = true,
+                Err(e) => error!("failed to send invitation: {}", e),
             }
-        } else {
-            return Err(tonic::Status::internal(
-                "failed read invitation subscribers",
-            ));
-        };
-        if let Err(e) = tx.send(invitation).await {
-            error!("failed to send invitation: {}", e);
-            Err(tonic::Status::internal("failed to send invitation"))
-        } else {
+        }
+        if delivered {
             Ok(Response::new(RpcResult {
                 ok: true,
                 description: "invitation has been sent".to_string(),
             }))
+        } else {
+            Err(tonic::Status::internal("failed to send invitation"))
         }
     }
 
-    #[doc = " Enters the chat"]
-    async fn enter_chat(
+    #[doc = " Declines the invitation, the inviter gets the reply"]
+    async fn decline_invitation(
         &self,
-        request: tonic::Request<ChatReference>,
+        request: tonic::Request<Invitation>,
     ) -> Result<tonic::Response<RpcResult>, tonic::Status> {
-        debug!("enter_chat(): {:?}", &request);
-        let chat_ref = request.into_inner();
-        match self.storage.update_chat(chat_ref.chat_id, |mut_ref_chat| {
-            if !mut_ref_chat.users.contains(&chat_ref.user_id) {
-                mut_ref_chat.users.push(chat_ref.user_id);
-                true
-            } else {
-                false
-            }
-        }) {
-            Ok(Some(chat)) => {
-                if !self
-                    .notify_chat_changed(ChatChanged::Updated(Arc::new(chat)))
-                    .await
-                {
-                    self.actualize_chat_listeners();
+        debug!("decline_invitation(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().to_user_id)?;
+        let invitation = request.into_inner();
+        let invitation = blocking(self, move |chat_room| {
+            match chat_room.storage.remove_invitation(&invitation) {
+                Ok(true) => {
+                    chat_room.notify_invitation(invitation.chat_id, invitation.to_user_id, false);
+                    Ok(invitation)
                 }
-                Ok(Response::new(RpcResult {
-                    ok: true,
-                    description: String::from("entered the chat"),
-                }))
+                Ok(false) => Err(tonic::Status::not_found("invitation does not exist")),
+                Err(e) => Err(tonic::Status::internal(format!(
+                    "failed access invitations, {}",
+                    e
+                ))),
             }
-            Ok(None) => Err(tonic::Status::not_found("chat does not exist")),
-            Err(e) => Err(tonic::Status::internal(format!(
-                "failed access chats, {}",
-                e
-            ))),
+        })
+        .await?;
+        let reply = Invitation {
+            declined: true,
+            ..invitation
+        };
+        let mut delivered = false;
+        for tx in self.invitations_senders(reply.from_user_id) {
+            delivered |= tx.send(reply.clone()).await.is_ok();
+        }
+        if !delivered {
+            // the inviter gets it on the next subscription
+            debug!("keep reply to {} until subscribed", reply.from_user_id);
+            blocking(self, move |chat_room| {
+                chat_room
+                    .storage
+                    .write_invitation_reply(&reply)
+                    .map_err(|e| {
+                        error!("failed to store invitation reply: {}", e);
+                        tonic::Status::internal("failed to store reply")
+                    })
+            })
+            .await?;
         }
+        Ok(Response::new(RpcResult {
+            ok: true,
+            description: "invitation has been declined".to_string(),
+        }))
     }
 
-    #[doc = " Leaves active chat"]
-    async fn leave_chat(
+    #[doc = " Enters the chat"]
+    async fn enter_chat(
         &self,
         request: tonic::Request<ChatReference>,
     ) -> Result<tonic::Response<RpcResult>, tonic::Status> {
-        debug!("leave_chat(): {:?}", &request);
+        debug!("enter_chat(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
         let chat_ref = request.into_inner();
-        let updated_chat = match self.storage.update_chat(chat_ref.chat_id, |mut_ref_chat| {
-            if mut_ref_chat.users.contains(&chat_ref.user_id) {
-                mut_ref_chat.users.retain(|&id| id != chat_ref.user_id);
-                true
-            } else {
-                false
-            }
-        }) {
-            Ok(Some(chat)) => {
-                if !self
-                    .notify_chat_changed(ChatChanged::Updated(Arc::new(chat.clone())))
-                    .await
+        blocking(self, move |chat_room| {
+            let storage = &chat_room.storage;
+            let chat = storage.read_chat(chat_ref.chat_id).found("chat")?;
+            // the owner and the invited enter any chat, the rest only the public ones
+            // they have not been removed from
+            if !chat.users.contains(&chat_ref.user_id) && chat.owner_id != chat_ref.user_id {
+                let invited = storage
+                    .read_invitations_to(chat_ref.user_id)?
+                    .iter()
+                    .any(|invitation| invitation.chat_id == chat_ref.chat_id);
+                if invited {
+                    storage.set_banned(chat_ref.chat_id, chat_ref.user_id, false)?;
+                } else if chat.description.is_empty()
+                    || storage.is_banned(chat_ref.chat_id, chat_ref.user_id)?
                 {
-                    self.actualize_chat_listeners();
+                    return Err(tonic::Status::permission_denied(format!(
+                        "user {} is not invited to chat {}",
+                        chat_ref.user_id, chat_ref.chat_id
+                    )));
                 }
-                chat
             }
-            Ok(None) => return Err(tonic::Status::not_found("chat does not exist")),
-            Err(e) => {
-                return Err(tonic::Status::internal(format!(
+            let mut entered = false;
+            match chat_room
+                .storage
+                .update_chat(chat_ref.chat_id, |mut_ref_chat| {
+                    if !mut_ref_chat.users.contains(&chat_ref.user_id) {
+                        mut_ref_chat.users.push(chat_ref.user_id);
+                        entered = true;
+                        true
+                    } else {
+                        false
+                    }
+                }) {
+                Ok(Some(chat)) => {
+                    // entering answers the invitations there
+                    if let Err(e) = chat_room
+                        .storage
+                        .remove_invitations_to(chat_ref.chat_id, chat_ref.user_id)
+                    {
+                        error!("failed to remove answered invitations: {}", e);
+                    }
+                    chat_room.notify_chat_updated(chat);
+                    if entered {
+                        let name = member_name(&chat_room.storage, chat_ref.user_id);
+                        post_system_notice(
+                            chat_room,
+                            chat_ref.chat_id,
+                            format!("{} entered the chat", name),
+                        );
+                    }
+                    Ok(Response::new(RpcResult {
+                        ok: true,
+                        description: String::from("entered the chat"),
+                    }))
+                }
+                Ok(None) => Err(tonic::Status::not_found("chat does not exist")),
+                Err(e) => Err(tonic::Status::internal(format!(
                     "failed access chats, {}",
                     e
-                )))
-            }
-        };
-        let all_notified = if !updated_chat.permanent && updated_chat.users.is_empty() {
-            //remove chat
-            if let Err(e) = self.storage.remove_chat(chat_ref.chat_id) {
-                error!("internal, {}", e);
+                ))),
             }
-            self.notify_chat_changed(ChatChanged::Closed(chat_ref.chat_id))
-                .await
-        } else {
-            self.notify_chat_changed(ChatChanged::Updated(Arc::new(updated_chat)))
-                .await
-        };
-        if !all_notified {
-            self.actualize_chat_listeners();
-        }
-        Ok(Response::new(RpcResult {
-            ok: true,
-            description: String::from("entered the chat"),
-        }))
+        })
+        .await
     }
 
-    #[doc = " Get older posts from the particular chat"]
-    async fn get_chat_history(
-        &self,
+    #[doc = " Leaves active chat"]
+    async fn leave_chat(
+        &self,
+        request: tonic::Request<ChatReference>,
+    ) -> Result<tonic::Response<RpcResult>, tonic::Status> {
+        debug!("leave_chat(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let chat_ref = request.into_inner();
+        blocking(self, move |chat_room| {
+            if remove_member(chat_room, chat_ref.chat_id, chat_ref.user_id, |_| Ok(()))? {
+                let name = member_name(&chat_room.storage, chat_ref.user_id);
+                post_system_notice(
+                    chat_room,
+                    chat_ref.chat_id,
+                    format!("{} left the chat", name),
+                );
+            }
+            Ok(Response::new(RpcResult {
+                ok: true,
+                description: String::from("left the chat"),
+            }))
+        })
+        .await
+    }
+
+    #[doc = " Removes the member from the chat, only its owner can"]
+    async fn kick_user(
+        &self,
+        request: tonic::Request<MemberReference>,
+    ) -> Result<tonic::Response<RpcResult>, tonic::Status> {
+        debug!("kick_user(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let member_ref = request.into_inner();
+        let (chat_id, owner_id, kicked_id) =
+            (member_ref.chat_id, member_ref.user_id, member_ref.member_id);
+        if kicked_id == owner_id {
+            return Err(tonic::Status::invalid_argument(
+                "the owner leaves the chat instead",
+            ));
+        }
+        blocking(self, move |chat_room| {
+            let removed = remove_member(chat_room, chat_id, kicked_id, |chat| {
+                if chat.owner_id != owner_id {
+                    Err(tonic::Status::permission_denied(format!(
+                        "user {} does not own chat {}",
+                        owner_id, chat_id
+                    )))
+                } else if !chat.users.contains(&kicked_id) {
+                    Err(tonic::Status::not_found(format!(
+                        "user {} is not a member of chat {}",
+                        kicked_id, chat_id
+                    )))
+                } else {
+                    Ok(())
+                }
+            })?;
+            info!(
+                "user {} kicked from chat {} by {}",
+                kicked_id, chat_id, owner_id
+            );
+            // the named chat stays visible to the kicked user otherwise
+            chat_room.notify_chat_changed(ChatChanged::Removed(chat_id, kicked_id));
+            if removed {
+                let storage = &chat_room.storage;
+                // entering again takes an invitation of the owner
+                storage.set_banned(chat_id, kicked_id, true)?;
+                let text = format!(
+                    "{} was removed by {}",
+                    member_name(storage, kicked_id),
+                    member_name(storage, owner_id)
+                );
+                post_system_notice(chat_room, chat_id, text);
+            }
+            Ok(Response::new(RpcResult {
+                ok: true,
+                description: String::from("kicked from the chat"),
+            }))
+        })
+        .await
+    }
+
+    #[doc = " Gets the users of the chat"]
+    async fn get_chat_members(
+        &self,
+        request: tonic::Request<ChatReference>,
+    ) -> Result<tonic::Response<ChatMembers>, tonic::Status> {
+        debug!("get_chat_members(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let chat_ref = request.into_inner();
+        blocking(self, move |chat_room| {
+            let chat = match chat_room.storage.read_chat(chat_ref.chat_id) {
+                Ok(Some(chat)) => chat,
+                Ok(None) => return Err(tonic::Status::not_found("chat does not exist")),
+                Err(e) => {
+                    return Err(tonic::Status::internal(format!(
+                        "failed access chats, {}",
+                        e
+                    )))
+                }
+            };
+            // the members of a private chat are known only to each other
+            if chat.description.is_empty() && !chat.users.contains(&chat_ref.user_id) {
+                return Err(tonic::Status::permission_denied(format!(
+                    "user {} is not a member of chat {}",
+                    chat_ref.user_id, chat.id
+                )));
+            }
+            let mut users = Vec::with_capacity(chat.users.len());
+            for user_id in &chat.users {
+                match chat_room.storage.read_user(*user_id) {
+                    Ok(Some(user)) => users.push(user),
+                    Ok(None) => warn!("member {} of chat {} is unknown", user_id, chat.id),
+                    Err(e) => {
+                        return Err(tonic::Status::internal(format!(
+                            "failed access users, {}",
+                            e
+                        )))
+                    }
+                }
+            }
+            Ok(Response::new(ChatMembers {
+                chat_id: chat.id,
+                users,
+            }))
+        })
+        .await
+    }
+
+    #[doc = " Marks the posts of the chat read up to the given one"]
+    async fn mark_read(
+        &self,
+        request: tonic::Request<ReadMark>,
+    ) -> Result<tonic::Response<RpcResult>, tonic::Status> {
+        debug!("mark_read(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let mark = request.into_inner();
+        blocking(self, move |chat_room| {
+            let storage = &chat_room.storage;
+            match storage.read_chat(mark.chat_id) {
+                Ok(Some(chat)) if chat.users.contains(&mark.user_id) => {}
+                Ok(Some(_)) => {
+                    return Err(tonic::Status::permission_denied(format!(
+                        "user {} is not a member of chat {}",
+                        mark.user_id, mark.chat_id
+                    )))
+                }
+                Ok(None) => return Err(tonic::Status::not_found("chat does not exist")),
+                Err(e) => return Err(tonic::Status::internal(format!("failed read chats, {}", e))),
+            }
+            let seq = match storage.locate_post(mark.last_post_id) {
+                Ok(Some((chat_id, seq))) if chat_id == mark.chat_id => seq,
+                Ok(_) => return Err(tonic::Status::not_found("post does not exist")),
+                Err(e) => {
+                    return Err(tonic::Status::internal(format!(
+                        "failed locate post, {}",
+                        e
+                    )))
+                }
+            };
+            // the stale mark of another session does not move the pointer back
+            match storage.advance_read_mark(mark.chat_id, mark.user_id, mark.last_post_id, seq) {
+                Ok(advanced) => Ok(Response::new(RpcResult {
+                    ok: true,
+                    description: String::from(if advanced {
+                        "marked read"
+                    } else {
+                        "read further already"
+                    }),
+                })),
+                Err(e) => Err(tonic::Status::internal(format!(
+                    "failed write read mark, {}",
+                    e
+                ))),
+            }
+        })
+        .await
+    }
+
+    #[doc = " Mutes or unmutes the chat for the user, on every device"]
+    async fn set_chat_mute(
+        &self,
+        request: tonic::Request<ChatMute>,
+    ) -> Result<tonic::Response<RpcResult>, tonic::Status> {
+        debug!("set_chat_mute(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let mute = request.into_inner();
+        blocking(self, move |chat_room| {
+            let chat = chat_room.storage.read_chat(mute.chat_id).found("chat")?;
+            if !chat.users.contains(&mute.user_id) {
+                return Err(tonic::Status::permission_denied(format!(
+                    "user {} is not a member of chat {}",
+                    mute.user_id, mute.chat_id
+                )));
+            }
+            chat_room
+                .storage
+                .set_chat_muted(mute.chat_id, mute.user_id, mute.muted)?;
+            chat_room.notify_mute(mute.chat_id, mute.user_id, mute.muted);
+            Ok(Response::new(RpcResult {
+                ok: true,
+                description: String::from(if mute.muted { "muted" } else { "unmuted" }),
+            }))
+        })
+        .await
+    }
+
+    #[doc = " Get older posts from the particular chat"]
+    async fn get_chat_history(
+        &self,
         request: tonic::Request<HistoryParams>,
     ) -> Result<tonic::Response<ChatHistory>, tonic::Status> {
-        debug!("get_chat_history(): {:?}", &request);
+        debug!("get_chat_history(): {:?}", request.get_ref());
+        let user_id = self.authenticate(&request)?;
+        let client = ClientIdentity::of(&request);
         let params = request.into_inner();
-        match self.storage.read_chat_posts(
-            params.chat_id,
-            params.idx_from as usize,
-            params.count as usize,
-        ) {
-            Ok(history) => Ok(Response::new(ChatHistory { posts: history })),
-            Err(e) => Err(tonic::Status::internal(format!("{}", e))),
+        // the heartbeat of the idle client, it has been seen already
+        if params.count == 0 {
+            return Ok(Response::new(ChatHistory::default()));
+        }
+        // members can read the posts anyway, the record only makes a leak attributable
+        if params.count as usize > self.tunables().limits.bulk_fetch_posts {
+            info!(
+                "bulk history fetch: {} posts of chat {} from {} by {} ({})",
+                params.count, params.chat_id, params.idx_from, user_id, client
+            );
+        }
+        blocking(self, move |chat_room| {
+            let chat = chat_room.storage.read_chat(params.chat_id).found("chat")?;
+            if !chat.users.contains(&user_id) {
+                return Err(tonic::Status::permission_denied(format!(
+                    "user {} is not a member of chat {}",
+                    user_id, params.chat_id
+                )));
+            }
+            let degraded = chat_room
+                .storage
+                .is_degraded(params.chat_id)
+                .unwrap_or_default();
+            // the posts missed by the client are asked for by their seqs
+            let history = if params.before_seq != 0 {
+                chat_room.storage.read_chat_posts_before(
+                    params.chat_id,
+                    params.before_seq,
+                    params.count as usize,
+                )
+            } else {
+                chat_room.storage.read_chat_posts(
+                    params.chat_id,
+                    params.idx_from as usize,
+                    params.count as usize,
+                )
+            };
+            match history {
+                Ok(history) => {
+                    chat_room.check_degraded(params.chat_id, degraded);
+                    Ok(Response::new(ChatHistory {
+                        posts: history,
+                        export_discouraged: chat.export_discouraged,
+                    }))
+                }
+                Err(e) => Err(tonic::Status::internal(format!("{}", e))),
+            }
+        })
+        .await
+    }
+
+    #[doc = " Searches the posts of the user's chats, the newest first"]
+    async fn search_posts(
+        &self,
+        request: tonic::Request<SearchRequest>,
+    ) -> Result<tonic::Response<SearchResult>, tonic::Status> {
+        debug!("search_posts(): {:?}", request.get_ref());
+        self.authorize(&request, request.get_ref().user_id)?;
+        let search = request.into_inner();
+        if search.query.trim().is_empty() {
+            return Err(tonic::Status::invalid_argument("empty search query"));
+        }
+        let limit = match search.limit as usize {
+            0 => MAX_SEARCH_RESULTS,
+            limit => limit.min(MAX_SEARCH_RESULTS),
+        };
+        let offset = search.offset as usize;
+        blocking(self, move |chat_room| {
+            let storage = &chat_room.storage;
+            let chat_ids = if search.chat_id != NOT_CHAT_ID {
+                match storage.read_chat(search.chat_id) {
+                    Ok(Some(chat)) if chat.users.contains(&search.user_id) => vec![chat.id],
+                    Ok(Some(_)) => {
+                        return Err(tonic::Status::permission_denied(format!(
+                            "user {} is not a member of chat {}",
+                            search.user_id, search.chat_id
+                        )))
+                    }
+                    Ok(None) => return Err(tonic::Status::not_found("chat does not exist")),
+                    Err(e) => {
+                        return Err(tonic::Status::internal(format!("failed read chats, {}", e)))
+                    }
+                }
+            } else {
+                storage.read_user_chats(search.user_id).map_err(|e| {
+                    tonic::Status::internal(format!("failed read user chats, {}", e))
+                })?
+            };
+            // every chat gives its newest matches enough to fill the page
+            let mut posts = Vec::new();
+            for chat_id in chat_ids {
+                match storage.search_chat_posts(chat_id, &search.query, offset + limit) {
+                    Ok(found) => posts.extend(found),
+                    Err(e) => {
+                        return Err(tonic::Status::internal(format!(
+                            "failed search chat {}, {}",
+                            chat_id, e
+                        )))
+                    }
+                }
+            }
+            posts.sort_by(|a, b| (b.created, b.id).cmp(&(a.created, a.id)));
+            let posts = posts.into_iter().skip(offset).take(limit).collect();
+            Ok(Response::new(SearchResult { posts }))
+        })
+        .await
+    }
+
+    #[doc = " Takes a snapshot of the storage on demand, the operators only"]
+    async fn take_snapshot(
+        &self,
+        request: tonic::Request<SnapshotRequest>,
+    ) -> Result<tonic::Response<SnapshotResult>, tonic::Status> {
+        debug!("take_snapshot(): {:?}", request.get_ref());
+        let user_id = request.get_ref().user_id;
+        self.authorize(&request, user_id)?;
+        let config = self.tunables().snapshot;
+        if !config.operators.contains(&user_id) {
+            warn!("user {} is not an operator, snapshot refused", user_id);
+            return Err(tonic::Status::permission_denied(format!(
+                "user {} is not an operator",
+                user_id
+            )));
         }
+        blocking(self, move |chat_room| {
+            match snapshot::take(chat_room, &config) {
+                Ok(path) => {
+                    info!("user {} took storage snapshot {}", user_id, path.display());
+                    Ok(Response::new(SnapshotResult {
+                        file: path.display().to_string(),
+                    }))
+                }
+                Err(e) => {
+                    error!("storage snapshot failed, {}", e);
+                    Err(tonic::Status::internal(format!("snapshot failed, {}", e)))
+                }
+            }
+        })
+        .await
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fixtures::Fixture;
+    use crate::proto::chat_room_service_client::ChatRoomServiceClient;
+    use crate::proto::{bearer_value, heartbeat, AUTHORIZATION_KEY, RETRY_AFTER_KEY};
+    use crate::proxy::{ProxyConfig, ProxyFilter, CLIENT_ADDR_KEY, CLIENT_USER_KEY};
+    use crate::rate_limit::RateLimits;
+    use crate::settings::Tunables;
+    use crate::storage::memory::InMemoryStorage;
+    use crate::{ChatId, Limits};
+    use std::time::Duration;
+
+    fn chat_info(
+        user_id: UserId,
+        description: &str,
+        auto_enter: bool,
+        desired_users: Vec<UserId>,
+    ) -> ChatInfo {
+        ChatInfo {
+            user_id,
+            permanent: true,
+            auto_enter,
+            description: description.to_string(),
+            desired_users,
+            export_discouraged: false,
+        }
+    }
+
+    // the request carries the token minted for the user as by register()
+    fn authorized<S: ChatStorage, T>(
+        chat_room: &Arc<ChatRoomImpl<S>>,
+        user_id: UserId,
+        message: T,
+    ) -> Request<T> {
+        let token = chat_room.new_token(user_id).unwrap();
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert(AUTHORIZATION_KEY, bearer_value(&token).parse().unwrap());
+        request
+    }
 
     #[test]
     fn sorted_users() {
@@ -762,14 +2458,14 @@ mod tests {
             name: "user 3".to_string(),
             short_name: "u3".to_string(),
         };
-        let id_u1 = get_user_id(&user1);
-        let id_u2 = get_user_id(&user2);
-        let id_u3 = get_user_id(&user3);
+        let id_u1 = ids::user_id(&user1);
+        let id_u2 = ids::user_id(&user2);
+        let id_u3 = ids::user_id(&user3);
 
-        let id_c12 = get_chat_id("", &vec![id_u1, id_u2]);
-        let id_c13 = get_chat_id("", &vec![id_u1, id_u3]);
-        let id_c23 = get_chat_id("", &vec![id_u2, id_u3]);
-        let id_c123 = get_chat_id("", &vec![id_u1, id_u2, id_u3]);
+        let id_c12 = get_chat_id("", &vec![id_u1, id_u2], 0);
+        let id_c13 = get_chat_id("", &vec![id_u1, id_u3], 0);
+        let id_c23 = get_chat_id("", &vec![id_u2, id_u3], 0);
+        let id_c123 = get_chat_id("", &vec![id_u1, id_u2, id_u3], 0);
         assert_ne!(id_c12, id_c13);
         assert_ne!(id_c12, id_c23);
         assert_ne!(id_c12, id_c123);
@@ -777,4 +2473,3691 @@ mod tests {
         assert_ne!(id_c123, id_c13);
         assert_ne!(id_c123, id_c23);
     }
+
+    #[test]
+    fn shared_post_is_not_cloned() {
+        let post = Post {
+            text: String::from("text"),
+            ..Default::default()
+        };
+        let text_ptr = post.text.as_ptr();
+        let first = Arc::new(post);
+        let second = first.clone();
+        // a copy is made while the post is still shared
+        let copy = unwrap_shared(first);
+        assert_ne!(copy.text.as_ptr(), text_ptr);
+        // the last reference gives the post away as is
+        let last = unwrap_shared(second);
+        assert_eq!(last.text.as_ptr(), text_ptr);
+    }
+
+    #[test]
+    fn taken_post_id_is_regenerated() {
+        let storage = InMemoryStorage::new();
+        let mut post = Post {
+            chat_id: 1,
+            ..Default::default()
+        };
+        let mut ids = vec![5, 5, 6].into_iter();
+        assert!(write_new_post(&storage, &mut post, || ids.next().unwrap()).unwrap());
+        assert_eq!(post.id, 5);
+        assert!(write_new_post(&storage, &mut post, || ids.next().unwrap()).unwrap());
+        assert_eq!(post.id, 6);
+        // the later post of the chat follows
+        assert_eq!(post.seq, 1);
+        assert_eq!(storage.chat_posts_count(1).unwrap(), 2);
+        assert_eq!(
+            storage.locate_post(6).unwrap().map(|(chat_id, _)| chat_id),
+            Some(1)
+        );
+        // no free id at all
+        assert!(!write_new_post(&storage, &mut post, || 5).unwrap());
+        assert_eq!(storage.chat_posts_count(1).unwrap(), 2);
+    }
+
+    // delays reading the history and writing the posts, the rest goes to the memory storage as is
+    struct SlowStorage {
+        inner: InMemoryStorage,
+        delay: Duration,
+    }
+
+    impl ChatStorage for SlowStorage {
+        fn close(self) {
+            self.inner.close()
+        }
+
+        fn backup(&self, path: &std::path::Path) -> Result<(), ServerError> {
+            self.inner.backup(path)
+        }
+
+        fn read_user(&self, id: UserId) -> Result<Option<User>, ServerError> {
+            self.inner.read_user(id)
+        }
+
+        fn write_user(&self, id: UserId, user: &User) -> Result<(), ServerError> {
+            self.inner.write_user(id, user)
+        }
+
+        fn create_user(&self, user: &User, secret_digest: &[u8]) -> Result<bool, ServerError> {
+            self.inner.create_user(user, secret_digest)
+        }
+
+        fn find_user_id(&self, short_name: &str) -> Result<Option<UserId>, ServerError> {
+            self.inner.find_user_id(short_name)
+        }
+
+        fn update_user<F: FnMut(&mut User) -> bool>(
+            &self,
+            id: UserId,
+            updater: F,
+        ) -> Result<Option<User>, ServerError> {
+            self.inner.update_user(id, updater)
+        }
+
+        fn read_all_users(&self) -> Result<Vec<User>, ServerError> {
+            self.inner.read_all_users()
+        }
+
+        fn remove_user(&self, id: UserId) -> Result<(), ServerError> {
+            self.inner.remove_user(id)
+        }
+
+        fn read_chat(&self, id: ChatId) -> Result<Option<Chat>, ServerError> {
+            self.inner.read_chat(id)
+        }
+
+        fn write_chat(&self, id: ChatId, chat: &Chat) -> Result<(), ServerError> {
+            self.inner.write_chat(id, chat)
+        }
+
+        fn update_chat<F: FnMut(&mut Chat) -> bool>(
+            &self,
+            id: ChatId,
+            updater: F,
+        ) -> Result<Option<Chat>, ServerError> {
+            self.inner.update_chat(id, updater)
+        }
+
+        fn read_all_chats(&self) -> Result<Vec<Chat>, ServerError> {
+            self.inner.read_all_chats()
+        }
+
+        fn read_chats_where<F: FnMut(&Chat) -> bool>(
+            &self,
+            predicate: F,
+        ) -> Result<Vec<Chat>, ServerError> {
+            self.inner.read_chats_where(predicate)
+        }
+
+        fn read_chats_after(
+            &self,
+            after: Option<ChatId>,
+            limit: usize,
+        ) -> Result<Vec<Chat>, ServerError> {
+            self.inner.read_chats_after(after, limit)
+        }
+
+        fn remove_chat(&self, id: ChatId) -> Result<(), ServerError> {
+            self.inner.remove_chat(id)
+        }
+
+        fn read_user_chats(&self, user_id: UserId) -> Result<Vec<ChatId>, ServerError> {
+            self.inner.read_user_chats(user_id)
+        }
+
+        fn write_invitation(&self, invitation: &Invitation) -> Result<(), ServerError> {
+            self.inner.write_invitation(invitation)
+        }
+
+        fn read_invitations_to(&self, user_id: UserId) -> Result<Vec<Invitation>, ServerError> {
+            self.inner.read_invitations_to(user_id)
+        }
+
+        fn remove_invitation(&self, invitation: &Invitation) -> Result<bool, ServerError> {
+            self.inner.remove_invitation(invitation)
+        }
+
+        fn remove_invitations_to(
+            &self,
+            chat_id: ChatId,
+            user_id: UserId,
+        ) -> Result<usize, ServerError> {
+            self.inner.remove_invitations_to(chat_id, user_id)
+        }
+
+        fn write_invitation_reply(&self, reply: &Invitation) -> Result<(), ServerError> {
+            self.inner.write_invitation_reply(reply)
+        }
+
+        fn take_invitation_replies(&self, user_id: UserId) -> Result<Vec<Invitation>, ServerError> {
+            self.inner.take_invitation_replies(user_id)
+        }
+
+        fn read_meta(&self, key: &str) -> Result<Option<Vec<u8>>, ServerError> {
+            self.inner.read_meta(key)
+        }
+
+        fn write_meta(&self, key: &str, value: &[u8]) -> Result<(), ServerError> {
+            self.inner.write_meta(key, value)
+        }
+
+        fn remove_meta(&self, key: &str) -> Result<(), ServerError> {
+            self.inner.remove_meta(key)
+        }
+
+        fn write_post(&self, post: &Post) -> Result<bool, ServerError> {
+            std::thread::sleep(self.delay);
+            self.inner.write_post(post)
+        }
+
+        fn locate_post(&self, post_id: PostId) -> Result<Option<(ChatId, u64)>, ServerError> {
+            self.inner.locate_post(post_id)
+        }
+
+        fn chat_posts_count(&self, chat_id: ChatId) -> Result<usize, ServerError> {
+            self.inner.chat_posts_count(chat_id)
+        }
+
+        fn read_chat_posts(
+            &self,
+            chat_id: ChatId,
+            idx_from: usize,
+            count: usize,
+        ) -> Result<Vec<Post>, ServerError> {
+            std::thread::sleep(self.delay);
+            self.inner.read_chat_posts(chat_id, idx_from, count)
+        }
+
+        fn read_post(&self, chat_id: ChatId, post_id: PostId) -> Result<Option<Post>, ServerError> {
+            self.inner.read_post(chat_id, post_id)
+        }
+
+        fn remove_post(&self, chat_id: ChatId, post_id: PostId) -> Result<bool, ServerError> {
+            self.inner.remove_post(chat_id, post_id)
+        }
+
+        fn update_post(&self, post: &Post) -> Result<bool, ServerError> {
+            self.inner.update_post(post)
+        }
+
+        fn verify_chat_posts(&self, chat_id: ChatId, repair: bool) -> Result<usize, ServerError> {
+            self.inner.verify_chat_posts(chat_id, repair)
+        }
+
+        fn salvage_chat_posts(&self, chat_id: ChatId) -> Result<usize, ServerError> {
+            self.inner.salvage_chat_posts(chat_id)
+        }
+
+        fn count_posts_after(
+            &self,
+            chat_id: ChatId,
+            seq: u64,
+            user_id: UserId,
+        ) -> Result<usize, ServerError> {
+            self.inner.count_posts_after(chat_id, seq, user_id)
+        }
+
+        fn read_read_mark(
+            &self,
+            chat_id: ChatId,
+            user_id: UserId,
+        ) -> Result<Option<(PostId, u64)>, ServerError> {
+            self.inner.read_read_mark(chat_id, user_id)
+        }
+
+        fn advance_read_mark(
+            &self,
+            chat_id: ChatId,
+            user_id: UserId,
+            post_id: PostId,
+            seq: u64,
+        ) -> Result<bool, ServerError> {
+            self.inner.advance_read_mark(chat_id, user_id, post_id, seq)
+        }
+
+        fn is_chat_muted(&self, chat_id: ChatId, user_id: UserId) -> Result<bool, ServerError> {
+            self.inner.is_chat_muted(chat_id, user_id)
+        }
+
+        fn set_chat_muted(
+            &self,
+            chat_id: ChatId,
+            user_id: UserId,
+            muted: bool,
+        ) -> Result<(), ServerError> {
+            self.inner.set_chat_muted(chat_id, user_id, muted)
+        }
+
+        fn is_banned(&self, chat_id: ChatId, user_id: UserId) -> Result<bool, ServerError> {
+            self.inner.is_banned(chat_id, user_id)
+        }
+
+        fn set_banned(
+            &self,
+            chat_id: ChatId,
+            user_id: UserId,
+            banned: bool,
+        ) -> Result<(), ServerError> {
+            self.inner.set_banned(chat_id, user_id, banned)
+        }
+
+        fn write_attachment(
+            &self,
+            info: &AttachmentInfo,
+            content: &[u8],
+        ) -> Result<bool, ServerError> {
+            self.inner.write_attachment(info, content)
+        }
+
+        fn read_attachment_info(
+            &self,
+            id: AttachmentId,
+        ) -> Result<Option<AttachmentInfo>, ServerError> {
+            self.inner.read_attachment_info(id)
+        }
+
+        fn read_attachment_chunk(
+            &self,
+            id: AttachmentId,
+            idx: usize,
+        ) -> Result<Option<Vec<u8>>, ServerError> {
+            self.inner.read_attachment_chunk(id, idx)
+        }
+    }
+
+    // the test runtime has a single worker, which a storage call made in place would block
+    #[tokio::test]
+    async fn slow_storage_blocks_no_requests() {
+        const DELAY: Duration = Duration::from_millis(600);
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            SlowStorage {
+                inner: InMemoryStorage::new(),
+                delay: DELAY,
+            },
+            Limits::default(),
+        ));
+        let chat = chat_room
+            .create_chat(authorized(
+                &chat_room,
+                1,
+                chat_info(1, "chat", true, Vec::new()),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let history = tokio::spawn({
+            let chat_room = chat_room.clone();
+            async move {
+                chat_room
+                    .get_chat_history(authorized(
+                        &chat_room,
+                        1,
+                        HistoryParams {
+                            chat_id: chat.id,
+                            idx_from: 0,
+                            count: 10,
+                            before_seq: 0,
+                        },
+                    ))
+                    .await
+            }
+        });
+        // let the history request reach the storage
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let started = std::time::Instant::now();
+        chat_room
+            .register(Request::new(UserInfo {
+                name: String::from("user"),
+                short_name: String::from("u"),
+            }))
+            .await
+            .unwrap();
+        chat_room
+            .create_chat(authorized(
+                &chat_room,
+                1,
+                chat_info(1, "other", true, Vec::new()),
+            ))
+            .await
+            .unwrap();
+        assert!(started.elapsed() < DELAY / 2);
+        assert!(history.await.unwrap().unwrap().get_ref().posts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_retry_created_once() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            SlowStorage {
+                inner: InMemoryStorage::new(),
+                delay: Duration::from_millis(300),
+            },
+            Limits::default(),
+        ));
+        let chat = chat_room
+            .create_chat(authorized(
+                &chat_room,
+                1,
+                chat_info(1, "chat", true, Vec::new()),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        // the retry of the timed out call comes while the first attempt is still writing
+        let post = || {
+            chat_room.create_post(authorized(
+                &chat_room,
+                1,
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id: chat.id,
+                    user_id: 1,
+                    text: String::from("once"),
+                    idempotency_key: String::from("key"),
+                    ..Default::default()
+                },
+            ))
+        };
+        let before = chat_room.storage.chat_posts_count(chat.id).unwrap();
+        let (first, retry) = tokio::join!(post(), post());
+        let post_id = |response: Response<RpcResult>| {
+            response
+                .metadata()
+                .get(POST_ID_KEY)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+        };
+        let first = post_id(first.unwrap());
+        assert!(first.is_some());
+        assert_eq!(post_id(retry.unwrap()), first);
+        assert_eq!(
+            chat_room.storage.chat_posts_count(chat.id).unwrap(),
+            before + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn post_from_non_member() {
+        const TEST_DB: &str = "migchat-test-post-non-member.db";
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            // dialog of users 1 and 2
+            let chat = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    1,
+                    ChatInfo {
+                        user_id: 1,
+                        permanent: false,
+                        auto_enter: true,
+                        description: String::new(),
+                        desired_users: vec![2],
+                        export_discouraged: false,
+                    },
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            let mut stream = chat_room
+                .get_posts(authorized(&chat_room, 2, Registration { user_id: 2 }))
+                .await
+                .unwrap()
+                .into_inner();
+            // user 3 is not a member
+            let res = chat_room
+                .create_post(authorized(
+                    &chat_room,
+                    3,
+                    Post {
+                        id: NOT_POST_ID,
+                        chat_id: chat.id,
+                        user_id: 3,
+                        text: String::from("intrusion"),
+                        ..Default::default()
+                    },
+                ))
+                .await;
+            assert_eq!(res.unwrap_err().code(), tonic::Code::PermissionDenied);
+            // unknown chat
+            let res = chat_room
+                .create_post(authorized(
+                    &chat_room,
+                    1,
+                    Post {
+                        id: NOT_POST_ID,
+                        chat_id: chat.id.wrapping_add(1),
+                        user_id: 1,
+                        text: String::from("orphan"),
+                        ..Default::default()
+                    },
+                ))
+                .await;
+            assert_eq!(res.unwrap_err().code(), tonic::Code::NotFound);
+            assert_eq!(chat_room.storage.chat_posts_count(chat.id).unwrap(), 0);
+            // the stream ends with the chat room, nothing has been delivered
+            drop(chat_room);
+            assert!(stream.next().await.is_none());
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    #[tokio::test]
+    async fn author_name_stamped() {
+        fn rename(chat_room: &ChatRoomImpl<InMemoryStorage>, short_name: &str) {
+            let user = User {
+                id: 1,
+                name: String::from("User Name"),
+                short_name: String::from(short_name),
+                created: 0,
+            };
+            chat_room.storage.write_user(1, &user).unwrap();
+        }
+
+        for &stamp_author_names in &[true, false] {
+            let chat_room = Arc::new(ChatRoomImpl::with_storage(
+                InMemoryStorage::new(),
+                Limits {
+                    stamp_author_names,
+                    ..Limits::default()
+                },
+            ));
+            rename(&chat_room, "old");
+            let chat = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    1,
+                    chat_info(1, "names", true, vec![]),
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            post_by(&chat_room, 1, chat.id, "before").await;
+            // the renamed author keeps the name in the former posts
+            rename(&chat_room, &"x".repeat(MAX_AUTHOR_NAME_LEN + 8));
+            post_by(&chat_room, 1, chat.id, "after").await;
+            let names: Vec<String> = chat_room
+                .storage
+                .read_chat_posts(chat.id, 0, 2)
+                .unwrap()
+                .into_iter()
+                .map(|p| p.author_name)
+                .collect();
+            if stamp_author_names {
+                assert_eq!(
+                    names,
+                    vec![String::from("old"), "x".repeat(MAX_AUTHOR_NAME_LEN)]
+                );
+            } else {
+                assert!(names.iter().all(String::is_empty));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_own_post() {
+        const TEST_DB: &str = "migchat-test-delete-post.db";
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            let chat = chat_room
+                .create_chat(authorized(&chat_room, 1, chat_info(1, "", true, vec![2])))
+                .await
+                .unwrap()
+                .into_inner();
+            let response = chat_room
+                .create_post(authorized(
+                    &chat_room,
+                    1,
+                    Post {
+                        id: NOT_POST_ID,
+                        chat_id: chat.id,
+                        user_id: 1,
+                        text: String::from("typo"),
+                        ..Default::default()
+                    },
+                ))
+                .await
+                .unwrap();
+            let post_id = response
+                .metadata()
+                .get(POST_ID_KEY)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap();
+            let post_ref = |user_id| {
+                authorized(
+                    &chat_room,
+                    user_id,
+                    PostReference {
+                        user_id,
+                        chat_id: chat.id,
+                        post_id,
+                    },
+                )
+            };
+            // only the author can delete the post
+            let res = chat_room.delete_post(post_ref(2)).await;
+            assert_eq!(res.unwrap_err().code(), tonic::Code::PermissionDenied);
+            assert_eq!(chat_room.storage.chat_posts_count(chat.id).unwrap(), 1);
+            let mut stream = chat_room
+                .get_posts(authorized(&chat_room, 2, Registration { user_id: 2 }))
+                .await
+                .unwrap()
+                .into_inner();
+            let res = chat_room.delete_post(post_ref(1)).await;
+            assert!(res.unwrap().into_inner().ok);
+            assert_eq!(chat_room.storage.chat_posts_count(chat.id).unwrap(), 0);
+            // the other member is told about the deletion
+            let tombstone = tokio::time::timeout(Duration::from_secs(1), stream.next())
+                .await
+                .expect("deletion is delivered in time")
+                .unwrap()
+                .unwrap();
+            assert_eq!(tombstone.kind(), PostKind::Deleted);
+            assert_eq!((tombstone.chat_id, tombstone.id), (chat.id, post_id));
+            assert!(tombstone.text.is_empty());
+            let res = chat_room.delete_post(post_ref(1)).await;
+            assert_eq!(res.unwrap_err().code(), tonic::Code::NotFound);
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    #[tokio::test]
+    async fn retried_post_created_once() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let chat = chat_room
+            .create_chat(authorized(
+                &chat_room,
+                1,
+                chat_info(1, "retries", true, vec![]),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let send = |key: &str| {
+            chat_room.create_post(authorized(
+                &chat_room,
+                1,
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id: chat.id,
+                    user_id: 1,
+                    text: String::from("once"),
+                    idempotency_key: String::from(key),
+                    ..Default::default()
+                },
+            ))
+        };
+        let post_id = |response: &Response<RpcResult>| {
+            response
+                .metadata()
+                .get(POST_ID_KEY)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap()
+        };
+        let mut fanned_out = chat_room.posts_events.subscribe();
+        let first = post_id(&send("k1").await.unwrap());
+        let retried = send("k1").await.unwrap();
+        assert_eq!(post_id(&retried), first);
+        assert!(retried.into_inner().ok);
+        assert_eq!(chat_room.storage.chat_posts_count(chat.id).unwrap(), 1);
+        // the members get the post once
+        assert_eq!(fanned_out.try_recv().unwrap().post.id, first);
+        assert!(fanned_out.try_recv().is_err());
+        // the key is not stored with the post
+        let stored = chat_room
+            .storage
+            .read_post(chat.id, first)
+            .unwrap()
+            .unwrap();
+        assert!(stored.idempotency_key.is_empty());
+        // another post, and those with no key are never taken for retries
+        assert_ne!(post_id(&send("k2").await.unwrap()), first);
+        send("").await.unwrap();
+        send("").await.unwrap();
+        assert_eq!(chat_room.storage.chat_posts_count(chat.id).unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn forwarded_between_member_chats() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let mut chat_ids = Vec::new();
+        // both are members of the first one only
+        for (user_id, description, others) in [
+            (1, "both", vec![2]),
+            (1, "first", vec![]),
+            (2, "second", vec![]),
+        ]
+        .iter()
+        {
+            let chat = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    *user_id,
+                    chat_info(*user_id, description, true, others.clone()),
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            chat_ids.push(chat.id);
+        }
+        let (both, first, second) = (chat_ids[0], chat_ids[1], chat_ids[2]);
+        let response = chat_room
+            .create_post(authorized(
+                &chat_room,
+                1,
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id: both,
+                    user_id: 1,
+                    text: String::from("news"),
+                    ..Default::default()
+                },
+            ))
+            .await
+            .unwrap();
+        let post_id = response
+            .metadata()
+            .get(POST_ID_KEY)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap();
+        let forward = |user_id, from_chat_id, post_id, chat_id| {
+            chat_room.create_post(authorized(
+                &chat_room,
+                user_id,
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id,
+                    user_id,
+                    // forged, the stored text is forwarded
+                    text: String::from("fake news"),
+                    forwarded_from: Some(ForwardedFrom {
+                        chat_id: from_chat_id,
+                        post_id,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ))
+        };
+        let last_post = |chat_id| {
+            let count = chat_room.storage.chat_posts_count(chat_id).unwrap();
+            chat_room
+                .storage
+                .read_chat_posts(chat_id, count - 1, 1)
+                .unwrap()
+                .pop()
+                .unwrap()
+        };
+        forward(2, both, post_id, second).await.unwrap();
+        let copy = last_post(second);
+        assert_eq!((copy.user_id, copy.text.as_str()), (2, "news"));
+        let origin = copy.forwarded_from.clone().unwrap();
+        assert_eq!(
+            (origin.chat_id, origin.post_id, origin.user_id),
+            (both, post_id, 1)
+        );
+        // forwarded again, it is still from the first author
+        forward(2, second, copy.id, both).await.unwrap();
+        assert_eq!(last_post(both).forwarded_from, Some(origin));
+        // the forwarding user is a member of both chats
+        let res = forward(2, both, post_id, first).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::PermissionDenied);
+        let res = forward(1, second, copy.id, first).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::PermissionDenied);
+        let res = forward(1, both, 42, first).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::NotFound);
+        assert_eq!(chat_room.storage.chat_posts_count(first).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn replies_stay_in_chat() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let mut chat_ids = Vec::new();
+        for description in ["first", "second"].iter() {
+            let chat = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    1,
+                    chat_info(1, description, true, vec![2]),
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            chat_ids.push(chat.id);
+        }
+        let post = |chat_id, reply_to_post_id| {
+            chat_room.create_post(authorized(
+                &chat_room,
+                1,
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id,
+                    user_id: 1,
+                    text: String::from("post"),
+                    reply_to_post_id,
+                    ..Default::default()
+                },
+            ))
+        };
+        let post_id = |response: Response<RpcResult>| {
+            response
+                .metadata()
+                .get(POST_ID_KEY)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap()
+        };
+        let original = post_id(post(chat_ids[0], NOT_POST_ID).await.unwrap());
+        let reply = post_id(post(chat_ids[0], original).await.unwrap());
+        assert_eq!(
+            chat_room
+                .storage
+                .read_post(chat_ids[0], reply)
+                .unwrap()
+                .unwrap()
+                .reply_to_post_id,
+            original
+        );
+        // neither another chat's post nor unknown one is replied to
+        let res = post(chat_ids[1], original).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::InvalidArgument);
+        let res = post(chat_ids[0], 42).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::InvalidArgument);
+        assert_eq!(chat_room.storage.chat_posts_count(chat_ids[0]).unwrap(), 2);
+        assert_eq!(chat_room.storage.chat_posts_count(chat_ids[1]).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn reactions_toggled() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let chat = chat_room
+            .create_chat(authorized(
+                &chat_room,
+                1,
+                chat_info(1, "reactions", true, vec![2]),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let post_id = chat_room
+            .create_post(authorized(
+                &chat_room,
+                1,
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id: chat.id,
+                    user_id: 1,
+                    text: String::from("vote"),
+                    // forged, never kept
+                    reactions: vec![PostReaction {
+                        emoji: String::from("👍"),
+                        user_ids: vec![2, 3],
+                    }],
+                    ..Default::default()
+                },
+            ))
+            .await
+            .unwrap()
+            .metadata()
+            .get(POST_ID_KEY)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap();
+        let mut posts = chat_room
+            .get_posts(authorized(&chat_room, 2, Registration { user_id: 2 }))
+            .await
+            .unwrap()
+            .into_inner();
+        let react = |user_id, post_id, emoji: &str| {
+            chat_room.react_to_post(authorized(
+                &chat_room,
+                user_id,
+                Reaction {
+                    user_id,
+                    chat_id: chat.id,
+                    post_id,
+                    emoji: String::from(emoji),
+                },
+            ))
+        };
+        let reactions = || {
+            chat_room
+                .storage
+                .read_post(chat.id, post_id)
+                .unwrap()
+                .unwrap()
+                .reactions
+                .into_iter()
+                .map(|r| (r.emoji, r.user_ids))
+                .collect::<Vec<_>>()
+        };
+        react(1, post_id, "👍").await.unwrap();
+        react(2, post_id, "👍").await.unwrap();
+        react(2, post_id, "🎉").await.unwrap();
+        assert_eq!(
+            reactions(),
+            vec![
+                (String::from("👍"), vec![1, 2]),
+                (String::from("🎉"), vec![2])
+            ]
+        );
+        // the members get the post again with the reactions
+        let updated = tokio::time::timeout(Duration::from_secs(1), posts.next())
+            .await
+            .expect("update is delivered in time")
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.id, post_id);
+        assert_eq!(updated.reactions.len(), 1);
+        // the same reaction again takes it back
+        let res = react(2, post_id, "🎉").await.unwrap().into_inner();
+        assert_eq!(res.description, "unreacted");
+        react(1, post_id, "👍").await.unwrap();
+        assert_eq!(reactions(), vec![(String::from("👍"), vec![2])]);
+
+        let res = react(3, post_id, "👍").await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::PermissionDenied);
+        let res = react(2, post_id, "").await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::InvalidArgument);
+        let res = react(2, 42, "👍").await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::NotFound);
+        chat_room
+            .delete_post(authorized(
+                &chat_room,
+                1,
+                PostReference {
+                    user_id: 1,
+                    chat_id: chat.id,
+                    post_id,
+                },
+            ))
+            .await
+            .unwrap();
+        let res = react(2, post_id, "👍").await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn attachments_uploaded() {
+        let chunk = |name: &str, size, data: &[u8]| -> Result<AttachmentChunk, Status> {
+            Ok(AttachmentChunk {
+                name: String::from(name),
+                size,
+                data: data.to_vec(),
+            })
+        };
+        let upload = vec![chunk("notes.txt", 0, b"first "), chunk("", 0, b"second")];
+        let (name, content) = collect_upload(futures::stream::iter(upload), 12)
+            .await
+            .unwrap();
+        assert_eq!(
+            (name.as_str(), content.as_slice()),
+            ("notes.txt", &b"first second"[..])
+        );
+        // refused in the middle, the rest is not waited for
+        let oversize =
+            futures::stream::iter(vec![chunk("big", 0, b"0123456789"), chunk("", 0, b"abc")])
+                .chain(futures::stream::pending());
+        let res = collect_upload(Box::pin(oversize), 12).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::ResourceExhausted);
+        // the declared size is refused at once
+        let declared =
+            futures::stream::iter(vec![chunk("big", 13, b"")]).chain(futures::stream::pending());
+        let res = collect_upload(Box::pin(declared), 12).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::ResourceExhausted);
+        let res = collect_upload(futures::stream::iter(vec![chunk("", 0, b"x")]), 12).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let chat = chat_room
+            .create_chat(authorized(
+                &chat_room,
+                1,
+                chat_info(1, "files", true, vec![]),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let content: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let info = AttachmentInfo {
+            id: 5,
+            name: String::from("data.bin"),
+            size: content.len() as u64,
+        };
+        assert!(chat_room.storage.write_attachment(&info, &content).unwrap());
+        let post_with = |attachment_id| {
+            chat_room.create_post(authorized(
+                &chat_room,
+                1,
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id: chat.id,
+                    user_id: 1,
+                    // forged, the stored name and size are kept
+                    attachments: vec![AttachmentInfo {
+                        id: attachment_id,
+                        name: String::from("other.bin"),
+                        size: 1,
+                    }],
+                    ..Default::default()
+                },
+            ))
+        };
+        let res = post_with(6).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::InvalidArgument);
+        post_with(5).await.unwrap();
+        let posts = chat_room.storage.read_chat_posts(chat.id, 0, 10).unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].attachments, vec![info.clone()]);
+        // the header first, then the content by chunks
+        let mut chunks = chat_room
+            .download_attachment(authorized(
+                &chat_room,
+                1,
+                AttachmentReference {
+                    user_id: 1,
+                    attachment_id: 5,
+                },
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let header = chunks.next().await.unwrap().unwrap();
+        assert_eq!((header.name.as_str(), header.size), ("data.bin", info.size));
+        let mut downloaded = Vec::new();
+        while let Some(chunk) = chunks.next().await {
+            downloaded.extend_from_slice(&chunk.unwrap().data);
+        }
+        assert_eq!(downloaded, content);
+        let res = chat_room
+            .download_attachment(authorized(
+                &chat_room,
+                1,
+                AttachmentReference {
+                    user_id: 1,
+                    attachment_id: 6,
+                },
+            ))
+            .await;
+        assert_eq!(res.err().unwrap().code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn read_marks_survive_restart() {
+        const TEST_DB: &str = "migchat-test-read-marks.db";
+        fn mark(
+            chat_room: &Arc<ChatRoomImpl>,
+            chat_id: ChatId,
+            post_id: PostId,
+        ) -> Request<ReadMark> {
+            authorized(
+                chat_room,
+                2,
+                ReadMark {
+                    user_id: 2,
+                    chat_id,
+                    last_post_id: post_id,
+                },
+            )
+        }
+
+        let _ = std::fs::remove_file(TEST_DB);
+        let chat_id = {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            let chat = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    1,
+                    chat_info(1, "read", true, vec![2]),
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            let mut post_ids = Vec::new();
+            for _ in 0..3 {
+                post_ids.push(post_by(&chat_room, 1, chat.id, "post").await);
+            }
+            chat_room
+                .mark_read(mark(&chat_room, chat.id, post_ids[1]))
+                .await
+                .unwrap();
+            // the stale mark does not move the pointer back
+            chat_room
+                .mark_read(mark(&chat_room, chat.id, post_ids[0]))
+                .await
+                .unwrap();
+            assert_eq!(
+                chat_room
+                    .storage
+                    .read_read_mark(chat.id, 2)
+                    .unwrap()
+                    .map(|(id, _)| id),
+                Some(post_ids[1])
+            );
+            // own posts are not unread
+            post_by(&chat_room, 2, chat.id, "post").await;
+            let status = chat_room
+                .mark_read(mark(&chat_room, chat.id, 42))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::NotFound);
+            chat.id
+        };
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            let mut chats = chat_room
+                .get_chats(authorized(&chat_room, 2, Registration { user_id: 2 }))
+                .await
+                .unwrap()
+                .into_inner();
+            let update = tokio::time::timeout(Duration::from_secs(1), chats.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            let update = &update.updated[0];
+            assert_eq!(update.chat.as_ref().map(|c| c.id), Some(chat_id));
+            assert_ne!(update.last_read_post_id, NOT_POST_ID);
+            assert_eq!(update.unread_posts, 1);
+            // never marked
+            let mut chats = chat_room
+                .get_chats(authorized(&chat_room, 1, Registration { user_id: 1 }))
+                .await
+                .unwrap()
+                .into_inner();
+            let update = chats.next().await.unwrap().unwrap();
+            assert_eq!(update.updated[0].last_read_post_id, NOT_POST_ID);
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    #[tokio::test]
+    async fn chat_mute_survives_restart() {
+        const TEST_DB: &str = "migchat-test-chat-mute.db";
+        fn mute(
+            chat_room: &Arc<ChatRoomImpl>,
+            user_id: UserId,
+            chat_id: ChatId,
+            muted: bool,
+        ) -> Request<ChatMute> {
+            authorized(
+                chat_room,
+                user_id,
+                ChatMute {
+                    user_id,
+                    chat_id,
+                    muted,
+                },
+            )
+        }
+
+        let _ = std::fs::remove_file(TEST_DB);
+        let chat_id = {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            let chat = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    1,
+                    chat_info(1, "quiet", true, vec![2]),
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            // the other device of the user is told at once
+            let mut chats = chat_room
+                .get_chats(authorized(&chat_room, 2, Registration { user_id: 2 }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(!next_update(&mut chats).await.updated[0].muted);
+            chat_room
+                .set_chat_mute(mute(&chat_room, 2, chat.id, true))
+                .await
+                .unwrap();
+            assert!(next_update(&mut chats).await.updated[0].muted);
+            // unmuted and muted again are alike
+            for &muted in &[false, false, true, true] {
+                chat_room
+                    .set_chat_mute(mute(&chat_room, 2, chat.id, muted))
+                    .await
+                    .unwrap();
+            }
+            assert!(chat_room.storage.is_chat_muted(chat.id, 2).unwrap());
+            // the members only
+            let status = chat_room
+                .set_chat_mute(mute(&chat_room, 3, chat.id, true))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+            let status = chat_room
+                .set_chat_mute(mute(&chat_room, 2, 42, true))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::NotFound);
+            chat.id
+        };
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            let mut chats = chat_room
+                .get_chats(authorized(&chat_room, 2, Registration { user_id: 2 }))
+                .await
+                .unwrap()
+                .into_inner();
+            let update = next_update(&mut chats).await;
+            assert_eq!(update.updated[0].chat.as_ref().map(|c| c.id), Some(chat_id));
+            assert!(update.updated[0].muted);
+            // the mute is the user's own
+            let mut chats = chat_room
+                .get_chats(authorized(&chat_room, 1, Registration { user_id: 1 }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(!next_update(&mut chats).await.updated[0].muted);
+            // the other members' streams keep their own mutes
+            chat_room
+                .set_chat_mute(mute(&chat_room, 1, chat_id, true))
+                .await
+                .unwrap();
+            assert!(next_update(&mut chats).await.updated[0].muted);
+            assert!(chat_room.storage.is_chat_muted(chat_id, 2).unwrap());
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    #[tokio::test]
+    async fn create_chat_validation() {
+        const TEST_DB: &str = "migchat-test-create-chat.db";
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let limits = Limits {
+                max_description_len: 10,
+                max_chat_members: 3,
+                ..Limits::default()
+            };
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, limits).unwrap());
+            let code_of = |res: Result<Response<Chat>, Status>| res.unwrap_err().code();
+            let status_of = |res: &Response<Chat>| {
+                res.metadata()
+                    .get(CHAT_STATUS_KEY)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_string())
+            };
+            // anonymous chat without members, the creator alone is not a member either
+            for desired_users in vec![Vec::new(), vec![1]] {
+                let res = chat_room
+                    .create_chat(authorized(
+                        &chat_room,
+                        1,
+                        chat_info(1, "   ", false, desired_users),
+                    ))
+                    .await;
+                assert_eq!(code_of(res), tonic::Code::InvalidArgument);
+            }
+            // too long description
+            let res = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    1,
+                    chat_info(1, "0123456789A", true, Vec::new()),
+                ))
+                .await;
+            assert_eq!(code_of(res), tonic::Code::InvalidArgument);
+            // too many members, the creator is counted as well
+            let res = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    1,
+                    chat_info(1, "", true, vec![2, 3, 4]),
+                ))
+                .await;
+            assert_eq!(code_of(res), tonic::Code::InvalidArgument);
+            // duplicated members are counted once
+            let res = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    1,
+                    chat_info(1, "", true, vec![1, 2, 2, 3]),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(status_of(&res), Some(CHAT_STATUS_CREATED.to_string()));
+            assert_eq!(res.get_ref().users, vec![1, 2, 3]);
+            // description is normalized before identification
+            let created = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    1,
+                    chat_info(1, "  a   chat ", false, Vec::new()),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(status_of(&created), Some(CHAT_STATUS_CREATED.to_string()));
+            assert_eq!(created.get_ref().description, "a chat");
+            let found = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    2,
+                    chat_info(2, "a chat", true, Vec::new()),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(status_of(&found), Some(CHAT_STATUS_FOUND.to_string()));
+            assert_eq!(found.get_ref().id, created.get_ref().id);
+            assert_eq!(found.get_ref().users, vec![2]);
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    fn chat_status_of(res: &Response<Chat>) -> Option<&str> {
+        res.metadata()
+            .get(CHAT_STATUS_KEY)
+            .and_then(|v| v.to_str().ok())
+    }
+
+    #[tokio::test]
+    async fn chat_id_does_not_depend_on_flags() {
+        const TEST_DB: &str = "migchat-test-chat-id-flags.db";
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            let mut combination = 0;
+            for &description in &["", "named"] {
+                for &first_auto_enter in &[true, false] {
+                    for &second_auto_enter in &[true, false] {
+                        // separate members for every combination to not to find the others
+                        combination += 1;
+                        let (u1, u2) = (combination * 10 + 1, combination * 10 + 2);
+                        let description = if description.is_empty() {
+                            String::new()
+                        } else {
+                            format!("{} {}", description, combination)
+                        };
+                        let expected_id = get_chat_id(&description, &[u1, u2], 0);
+                        let new = chat_room
+                            .create_chat(authorized(
+                                &chat_room,
+                                u1,
+                                chat_info(u1, &description, first_auto_enter, vec![u2]),
+                            ))
+                            .await
+                            .unwrap();
+                        assert_eq!(chat_status_of(&new), Some(CHAT_STATUS_CREATED));
+                        assert_eq!(new.get_ref().id, expected_id);
+                        let expected_users = if first_auto_enter {
+                            vec![u1, u2]
+                        } else {
+                            Vec::new()
+                        };
+                        assert_eq!(new.get_ref().users, expected_users);
+                        // the same members requested by the other side
+                        let existing = chat_room
+                            .create_chat(authorized(
+                                &chat_room,
+                                u2,
+                                chat_info(u2, &description, second_auto_enter, vec![u1]),
+                            ))
+                            .await
+                            .unwrap();
+                        assert_eq!(chat_status_of(&existing), Some(CHAT_STATUS_FOUND));
+                        assert_eq!(existing.get_ref().id, expected_id);
+                        assert_eq!(
+                            existing.get_ref().users.contains(&u2),
+                            first_auto_enter || second_auto_enter
+                        );
+                    }
+                }
+            }
+            // the dialog is a different chat than a group with the third user
+            let group = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    11,
+                    chat_info(11, "", true, vec![12, 13]),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(chat_status_of(&group), Some(CHAT_STATUS_CREATED));
+            assert_ne!(group.get_ref().id, get_chat_id("", &[11, 12], 0));
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    #[tokio::test]
+    async fn permanent_flag_reconciliation() {
+        const TEST_DB: &str = "migchat-test-permanent-flag.db";
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            let request = |user_id, description: &str, permanent, auto_enter| {
+                authorized(
+                    &chat_room,
+                    user_id,
+                    ChatInfo {
+                        permanent,
+                        ..chat_info(user_id, description, auto_enter, Vec::new())
+                    },
+                )
+            };
+            let created = chat_room
+                .create_chat(request(1, "named", false, true))
+                .await
+                .unwrap();
+            assert!(!created.get_ref().permanent);
+            // non-member cannot escalate
+            let res = chat_room
+                .create_chat(request(3, "named", true, false))
+                .await
+                .unwrap();
+            assert_eq!(chat_status_of(&res), Some(CHAT_STATUS_FOUND));
+            assert!(!res.get_ref().permanent);
+            // member can, entering the chat by the same request
+            let res = chat_room
+                .create_chat(request(2, "named", true, true))
+                .await
+                .unwrap();
+            assert_eq!(chat_status_of(&res), Some(CHAT_STATUS_FLAGS_ADJUSTED));
+            assert!(res.get_ref().permanent);
+            assert_eq!(res.get_ref().users, vec![1, 2]);
+            // nobody can downgrade
+            let res = chat_room
+                .create_chat(request(1, "named", false, true))
+                .await
+                .unwrap();
+            assert_eq!(chat_status_of(&res), Some(CHAT_STATUS_FOUND));
+            assert!(res.get_ref().permanent);
+            // already permanent chat is just found
+            let res = chat_room
+                .create_chat(request(2, "named", true, true))
+                .await
+                .unwrap();
+            assert_eq!(chat_status_of(&res), Some(CHAT_STATUS_FOUND));
+            assert!(res.get_ref().permanent);
+            assert_eq!(
+                chat_room.storage.read_chat(res.get_ref().id).unwrap(),
+                Some(res.into_inner())
+            );
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    #[tokio::test]
+    async fn posts_and_invitations_rate_limited() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let seeded = Fixture::new(1568)
+            .users(2)
+            .chats(1, |c| c.members(2..3).posts(0..1))
+            .seed(&chat_room.storage)
+            .unwrap();
+        let (writer, chat_id) = (seeded.users[0], seeded.chats[0]);
+        // a post a second
+        chat_room.reconfigure(Tunables {
+            rate: RateLimits {
+                posts_per_minute: 60,
+                invitations_per_minute: 1,
+            },
+            ..Tunables::default()
+        });
+        let post = |text: &str| {
+            chat_room.create_post(authorized(
+                &chat_room,
+                writer,
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id,
+                    user_id: writer,
+                    text: String::from(text),
+                    ..Default::default()
+                },
+            ))
+        };
+        // the unused posts of a minute are saved up
+        for i in 0..60 {
+            post(&format!("burst {}", i)).await.unwrap();
+        }
+        let err = post("flood").await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        assert!(err.message().contains("retry in 1 s"), "{}", err.message());
+        assert_eq!(
+            err.metadata()
+                .get(RETRY_AFTER_KEY)
+                .and_then(|v| v.to_str().ok()),
+            Some("1")
+        );
+        // the limit is the writer's only
+        chat_room
+            .create_post(authorized(
+                &chat_room,
+                seeded.users[1],
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id,
+                    user_id: seeded.users[1],
+                    text: String::from("other"),
+                    ..Default::default()
+                },
+            ))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        post("recovered").await.unwrap();
+
+        // the invitation refused for another reason is counted all the same
+        let invite = || {
+            chat_room.invite_user(authorized(
+                &chat_room,
+                writer,
+                Invitation {
+                    from_user_id: writer,
+                    to_user_id: seeded.users[1],
+                    chat_id,
+                    ..Default::default()
+                },
+            ))
+        };
+        assert_eq!(invite().await.unwrap_err().code(), tonic::Code::NotFound);
+        let err = invite().await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        assert!(err.message().contains("retry in 60 s"), "{}", err.message());
+    }
+
+    #[tokio::test]
+    async fn rate_limited_by_forwarded_address() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let seeded = Fixture::new(1522)
+            .users(3)
+            .chats(1, |c| c.members(3..4))
+            .seed(&chat_room.storage)
+            .unwrap();
+        let chat_id = seeded.chats[0];
+        chat_room.reconfigure(Tunables {
+            rate: RateLimits {
+                posts_per_minute: 1,
+                invitations_per_minute: 1,
+            },
+            ..Tunables::default()
+        });
+        // as moved under the internal key by the proxy filter
+        let post = |user_id, addr: Option<&'static str>, verified: Option<&'static str>| {
+            let mut request = authorized(
+                &chat_room,
+                user_id,
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id,
+                    user_id,
+                    text: String::from("hi"),
+                    ..Default::default()
+                },
+            );
+            if let Some(addr) = addr {
+                request
+                    .metadata_mut()
+                    .insert(CLIENT_ADDR_KEY, MetadataValue::from_static(addr));
+            }
+            if let Some(user) = verified {
+                request
+                    .metadata_mut()
+                    .insert(CLIENT_USER_KEY, MetadataValue::from_static(user));
+            }
+            chat_room.create_post(request)
+        };
+        let users = &seeded.users;
+        post(users[0], Some("192.0.2.7"), None).await.unwrap();
+        // another user of the same client is counted with the first one
+        let err = post(users[1], Some("192.0.2.7"), None).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        // the other clients behind the proxy are counted apart
+        post(users[1], Some("192.0.2.8"), None).await.unwrap();
+        // the same user from another client is not limited by the first one
+        post(users[0], Some("192.0.2.9"), None).await.unwrap();
+        // nor the user without the forwarded address
+        post(users[2], None, None).await.unwrap();
+        let err = post(users[2], None, None).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        // the users verified by the proxy are counted apart at the same address
+        post(users[0], Some("192.0.2.10"), Some("alice"))
+            .await
+            .unwrap();
+        post(users[1], Some("192.0.2.10"), Some("bob"))
+            .await
+            .unwrap();
+        // and each of them is counted at any address
+        let err = post(users[0], Some("192.0.2.11"), Some("alice"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn post_content_validated() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits {
+                max_post_bytes: 8,
+                max_attachments: 1,
+                ..Limits::default()
+            },
+        ));
+        let seeded = Fixture::new(1569)
+            .users(1)
+            .chats(1, |c| c.members(1..2).posts(0..1))
+            .seed(&chat_room.storage)
+            .unwrap();
+        let (writer, chat_id) = (seeded.users[0], seeded.chats[0]);
+        let post = |text: &str, attachments: usize| {
+            chat_room.create_post(authorized(
+                &chat_room,
+                writer,
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id,
+                    user_id: writer,
+                    text: String::from(text),
+                    attachments: vec![AttachmentInfo::default(); attachments],
+                    ..Default::default()
+                },
+            ))
+        };
+        for (text, attachments) in &[("", 0), (" \n\t", 0), ("123456789", 0), ("x", 2)] {
+            let err = post(text, *attachments).await.unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument, "{:?}", text);
+        }
+        post("12345678", 0).await.unwrap();
+        // the size is of the text with the line endings normalized
+        post("123\r\n5678", 0).await.unwrap();
+        let posts = chat_room.storage.read_chat_posts(chat_id, 0, 10).unwrap();
+        let texts: Vec<&str> = posts.iter().map(|p| p.text.as_str()).collect();
+        assert_eq!(texts, vec!["12345678", "123\n5678"]);
+    }
+
+    #[tokio::test]
+    async fn stalled_subscriber_does_not_block_others() {
+        const TEST_DB: &str = "migchat-test-stalled-subscriber.db";
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            // the writer is not slowed down by the rate limit
+            chat_room.reconfigure(Tunables {
+                rate: RateLimits {
+                    posts_per_minute: 0,
+                    ..RateLimits::default()
+                },
+                ..Tunables::default()
+            });
+            let seeded = Fixture::new(1633)
+                .users(3)
+                .chats(1, |c| c.members(3..4).posts(0..1))
+                .seed(&chat_room.storage)
+                .unwrap();
+            let (stalled, reader, writer) = (seeded.users[0], seeded.users[1], seeded.users[2]);
+            let chat_id = seeded.chats[0];
+            // the stalled user never reads its stream
+            let _stalled = chat_room
+                .get_posts(authorized(
+                    &chat_room,
+                    stalled,
+                    Registration { user_id: stalled },
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            let mut active = chat_room
+                .get_posts(authorized(
+                    &chat_room,
+                    reader,
+                    Registration { user_id: reader },
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            // far more posts than the stalled stream is able to buffer
+            for i in 0..200 {
+                chat_room
+                    .create_post(authorized(
+                        &chat_room,
+                        writer,
+                        Post {
+                            id: NOT_POST_ID,
+                            chat_id,
+                            user_id: writer,
+                            text: format!("post {}", i),
+                            ..Default::default()
+                        },
+                    ))
+                    .await
+                    .unwrap();
+                let post = tokio::time::timeout(Duration::from_secs(1), active.next())
+                    .await
+                    .expect("post is delivered in time")
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(post.text, format!("post {}", i));
+            }
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    // the posts stream resumed after the post
+    fn since<T>(mut request: Request<T>, post: &Post) -> Request<T> {
+        let metadata = request.metadata_mut();
+        metadata.insert(SINCE_POST_ID_KEY, post.id.to_string().parse().unwrap());
+        metadata.insert(SINCE_CREATED_KEY, post.created.to_string().parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn posts_resumed_after_cursor() {
+        const TEST_DB: &str = "migchat-test-posts-resumed.db";
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            let seeded = Fixture::new(1528)
+                .users(2)
+                .chats(1, |c| c.members(2..3).posts(0..1))
+                .seed(&chat_room.storage)
+                .unwrap();
+            let (reader, writer) = (seeded.users[0], seeded.users[1]);
+            let chat_id = seeded.chats[0];
+            let post = |i: usize| {
+                authorized(
+                    &chat_room,
+                    writer,
+                    Post {
+                        id: NOT_POST_ID,
+                        chat_id,
+                        user_id: writer,
+                        text: format!("post {}", i),
+                        ..Default::default()
+                    },
+                )
+            };
+            let subscription = || authorized(&chat_room, reader, Registration { user_id: reader });
+            let mut posts = chat_room
+                .get_posts(subscription())
+                .await
+                .unwrap()
+                .into_inner();
+            let mut received = Vec::new();
+            for i in 0..5 {
+                chat_room.create_post(post(i)).await.unwrap();
+                let delivered = tokio::time::timeout(Duration::from_secs(1), posts.next()).await;
+                received.push(delivered.unwrap().unwrap().unwrap());
+            }
+            drop(posts);
+            // posted while the reader is away
+            for i in 5..8 {
+                chat_room.create_post(post(i)).await.unwrap();
+            }
+            let cursor = received.last().unwrap();
+            let mut posts = chat_room
+                .get_posts(since(subscription(), cursor))
+                .await
+                .unwrap()
+                .into_inner();
+            chat_room.create_post(post(8)).await.unwrap();
+            for i in 5..9 {
+                assert_eq!(next_text(&mut posts).await, Some(format!("post {}", i)));
+            }
+            let more = tokio::time::timeout(Duration::from_millis(200), posts.next()).await;
+            assert!(more.is_err());
+            // the unknown cursor replays all
+            let unknown = Post {
+                id: cursor.id.wrapping_add(1),
+                ..cursor.clone()
+            };
+            let mut posts = chat_room
+                .get_posts(since(subscription(), &unknown))
+                .await
+                .unwrap()
+                .into_inner();
+            for i in 0..9 {
+                assert_eq!(next_text(&mut posts).await, Some(format!("post {}", i)));
+            }
+            // the malformed one is rejected
+            let mut request = subscription();
+            request
+                .metadata_mut()
+                .insert(SINCE_POST_ID_KEY, "latest".parse().unwrap());
+            assert_eq!(
+                chat_room.get_posts(request).await.unwrap_err().code(),
+                tonic::Code::InvalidArgument
+            );
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    fn stored_invitation(chat_room: &ChatRoomImpl) -> Invitation {
+        let invitation = Invitation {
+            chat_id: 10,
+            from_user_id: 1,
+            to_user_id: 2,
+            declined: false,
+        };
+        chat_room.storage.write_invitation(&invitation).unwrap();
+        invitation
+    }
+
+    #[tokio::test]
+    async fn decline_with_inviter_online() {
+        const TEST_DB: &str = "migchat-test-decline-online.db";
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            let invitation = stored_invitation(&chat_room);
+            let mut invitations = chat_room
+                .get_invitations(authorized(&chat_room, 1, Registration { user_id: 1 }))
+                .await
+                .unwrap()
+                .into_inner();
+            chat_room
+                .decline_invitation(authorized(&chat_room, 2, invitation.clone()))
+                .await
+                .unwrap();
+            let reply = tokio::time::timeout(Duration::from_secs(1), invitations.next())
+                .await
+                .expect("reply is delivered in time")
+                .unwrap()
+                .unwrap();
+            assert!(reply.declined);
+            assert_eq!(reply.chat_id, invitation.chat_id);
+            assert_eq!(reply.to_user_id, invitation.to_user_id);
+            assert!(chat_room
+                .storage
+                .take_invitation_replies(1)
+                .unwrap()
+                .is_empty());
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    #[tokio::test]
+    async fn decline_with_inviter_offline() {
+        const TEST_DB: &str = "migchat-test-decline-offline.db";
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            let invitation = stored_invitation(&chat_room);
+            chat_room
+                .decline_invitation(authorized(&chat_room, 2, invitation))
+                .await
+                .unwrap();
+            // the reply is persisted until the inviter subscribes
+            let mut invitations = chat_room
+                .get_invitations(authorized(&chat_room, 1, Registration { user_id: 1 }))
+                .await
+                .unwrap()
+                .into_inner();
+            let reply = tokio::time::timeout(Duration::from_secs(1), invitations.next())
+                .await
+                .expect("reply is delivered in time")
+                .unwrap()
+                .unwrap();
+            assert!(reply.declined);
+            assert!(chat_room
+                .storage
+                .take_invitation_replies(1)
+                .unwrap()
+                .is_empty());
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    #[tokio::test]
+    async fn chat_members_listed() {
+        async fn members(
+            chat_room: &Arc<ChatRoomImpl<InMemoryStorage>>,
+            chat_id: ChatId,
+            user_id: UserId,
+        ) -> Result<ChatMembers, Status> {
+            let chat_ref = ChatReference { user_id, chat_id };
+            chat_room
+                .get_chat_members(authorized(chat_room, user_id, chat_ref))
+                .await
+                .map(Response::into_inner)
+        }
+
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let seeded = Fixture::new(1530)
+            .users(4)
+            .chats(1, |c| c.members(2..3).posts(0..1))
+            .dialogs(1)
+            .seed(&chat_room.storage)
+            .unwrap();
+        for chat_id in &seeded.chats {
+            let chat = chat_room.storage.read_chat(*chat_id).unwrap().unwrap();
+            let outsider = *seeded
+                .users
+                .iter()
+                .find(|id| !chat.users.contains(id))
+                .unwrap();
+            let listed = members(&chat_room, *chat_id, chat.users[0]).await.unwrap();
+            assert_eq!(listed.chat_id, *chat_id);
+            let ids: Vec<UserId> = listed.users.iter().map(|u| u.id).collect();
+            assert_eq!(ids, chat.users);
+            assert!(listed.users.iter().all(|u| !u.short_name.is_empty()));
+            // the members of the dialog are private
+            let listed = members(&chat_room, *chat_id, outsider).await;
+            if chat.description.is_empty() {
+                assert_eq!(listed.unwrap_err().code(), tonic::Code::PermissionDenied);
+            } else {
+                assert_eq!(listed.unwrap().users.len(), chat.users.len());
+            }
+        }
+        let err = members(&chat_room, 1, seeded.users[0]).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn posts_searched() {
+        async fn search(
+            chat_room: &Arc<ChatRoomImpl<InMemoryStorage>>,
+            user_id: UserId,
+            chat_id: ChatId,
+            query: &str,
+            (offset, limit): (u32, u32),
+        ) -> Result<Vec<(ChatId, u64)>, Status> {
+            let request = SearchRequest {
+                user_id,
+                chat_id,
+                query: String::from(query),
+                limit,
+                offset,
+            };
+            let found = chat_room
+                .search_posts(authorized(chat_room, user_id, request))
+                .await?
+                .into_inner();
+            Ok(found.posts.iter().map(|p| (p.chat_id, p.created)).collect())
+        }
+
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        // user 1 is a member of chats 10 and 20, user 2 of chats 20 and 30
+        for (chat_id, users) in [(10, vec![1]), (20, vec![1, 2]), (30, vec![2])].iter() {
+            let chat = Chat {
+                id: *chat_id,
+                description: format!("chat {}", chat_id),
+                users: users.clone(),
+                owner_id: users[0],
+                ..Default::default()
+            };
+            chat_room.storage.write_chat(*chat_id, &chat).unwrap();
+        }
+        for created in 1..=9 {
+            let chat_id = 10 * (created % 3 + 1);
+            let text = if created % 2 == 0 {
+                "Deploy done"
+            } else {
+                "lunch?"
+            };
+            let post = Post {
+                id: 100 + created,
+                chat_id,
+                user_id: 2,
+                text: format!("{} #{}", text, created),
+                created,
+                ..Default::default()
+            };
+            assert!(chat_room.storage.write_post(&post).unwrap());
+        }
+        // the posts of the own chats only, the newest first
+        let found = search(&chat_room, 1, NOT_CHAT_ID, "DEPLOY", (0, 0)).await;
+        assert_eq!(found.unwrap(), vec![(10, 6), (20, 4)]);
+        let found = search(&chat_room, 2, NOT_CHAT_ID, "deploy", (0, 0)).await;
+        assert_eq!(found.unwrap(), vec![(30, 8), (20, 4), (30, 2)]);
+        // the pages follow one another
+        let found = search(&chat_room, 2, NOT_CHAT_ID, "deploy", (0, 2)).await;
+        assert_eq!(found.unwrap(), vec![(30, 8), (20, 4)]);
+        let found = search(&chat_room, 2, NOT_CHAT_ID, "deploy", (1, 2)).await;
+        assert_eq!(found.unwrap(), vec![(20, 4), (30, 2)]);
+        let found = search(&chat_room, 2, NOT_CHAT_ID, "deploy", (3, 2)).await;
+        assert!(found.unwrap().is_empty());
+        // a single chat of the user
+        let found = search(&chat_room, 2, 30, "deploy", (0, 0)).await;
+        assert_eq!(found.unwrap(), vec![(30, 8), (30, 2)]);
+        let err = search(&chat_room, 1, 30, "deploy", (0, 0))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        let err = search(&chat_room, 1, 40, "deploy", (0, 0))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+        let err = search(&chat_room, 1, NOT_CHAT_ID, " ", (0, 0))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn snapshot_taken_by_operators() {
+        const TEST_DIR: &str = "migchat-test-snapshot-rpc";
+        let _ = std::fs::remove_dir_all(TEST_DIR);
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let seeded = Fixture::new(1562)
+            .users(2)
+            .seed(&chat_room.storage)
+            .unwrap();
+        let operator = chat_room
+            .storage
+            .read_user(seeded.users[0])
+            .unwrap()
+            .unwrap();
+        let mut tunables = Tunables::default();
+        tunables.snapshot.dir = std::path::PathBuf::from(TEST_DIR);
+        tunables.snapshot.operators = vec![operator.id];
+        chat_room.reconfigure(tunables);
+        let request = |user_id| SnapshotRequest { user_id };
+        let user_id = seeded.users[1];
+        let err = chat_room
+            .take_snapshot(authorized(&chat_room, user_id, request(user_id)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        // the name of the operator taken by another user grants nothing
+        chat_room
+            .storage
+            .update_user(user_id, |user| {
+                user.short_name = operator.short_name.clone();
+                true
+            })
+            .unwrap();
+        let err = chat_room
+            .take_snapshot(authorized(&chat_room, user_id, request(user_id)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        let user_id = seeded.users[0];
+        let taken = chat_room
+            .take_snapshot(authorized(&chat_room, user_id, request(user_id)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(std::path::Path::new(&taken.file).exists());
+        let _ = std::fs::remove_dir_all(TEST_DIR);
+    }
+
+    #[tokio::test]
+    async fn decline_unknown_invitation() {
+        const TEST_DB: &str = "migchat-test-decline-unknown.db";
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            let invitation = stored_invitation(&chat_room);
+            chat_room
+                .decline_invitation(authorized(&chat_room, 2, invitation.clone()))
+                .await
+                .unwrap();
+            // already declined
+            let err = chat_room
+                .decline_invitation(authorized(&chat_room, 2, invitation.clone()))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), tonic::Code::NotFound);
+            // never sent
+            let err = chat_room
+                .decline_invitation(authorized(
+                    &chat_room,
+                    2,
+                    Invitation {
+                        chat_id: 20,
+                        ..invitation
+                    },
+                ))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), tonic::Code::NotFound);
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    #[tokio::test]
+    async fn leave_chat() {
+        const TEST_DB: &str = "migchat-test-leave-chat.db";
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            let chat = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    1,
+                    chat_info(1, "talk", true, vec![2]),
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            let res = chat_room
+                .leave_chat(authorized(
+                    &chat_room,
+                    2,
+                    ChatReference {
+                        user_id: 2,
+                        chat_id: chat.id,
+                    },
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(res.description, "left the chat");
+            assert_eq!(
+                chat_room
+                    .storage
+                    .read_chat(chat.id)
+                    .unwrap()
+                    .map(|c| c.users),
+                Some(vec![1])
+            );
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    #[tokio::test]
+    async fn chat_id_collision() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        // the unrelated chats of the same hash as "chat" and the dialog of 1 and 2
+        let unrelated = |id, description: &str| Chat {
+            id,
+            description: description.to_string(),
+            users: vec![5],
+            ..Default::default()
+        };
+        let chat_id = get_chat_id("chat", &[], 0);
+        let dialog_id = get_chat_id("", &[1, 2], 0);
+        chat_room
+            .storage
+            .write_chat(chat_id, &unrelated(chat_id, "secret"))
+            .unwrap();
+        chat_room
+            .storage
+            .write_chat(dialog_id, &unrelated(dialog_id, ""))
+            .unwrap();
+        let create = |user_id, description: &str, users| {
+            chat_room.create_chat(authorized(
+                &chat_room,
+                user_id,
+                chat_info(user_id, description, true, users),
+            ))
+        };
+        // a new chat is created at the next probe instead of entering the unrelated one
+        let created = create(1, "chat", vec![]).await.unwrap();
+        assert_eq!(chat_status_of(&created), Some(CHAT_STATUS_CREATED));
+        assert_eq!(created.get_ref().id, get_chat_id("chat", &[], 1));
+        assert_eq!(
+            chat_room.storage.read_chat(chat_id).unwrap(),
+            Some(unrelated(chat_id, "secret"))
+        );
+        // and is found there later
+        let found = create(2, "chat", vec![]).await.unwrap();
+        assert_eq!(chat_status_of(&found), Some(CHAT_STATUS_FOUND));
+        assert_eq!(found.get_ref().id, created.get_ref().id);
+        let dialog = create(1, "", vec![2]).await.unwrap();
+        assert_eq!(chat_status_of(&dialog), Some(CHAT_STATUS_CREATED));
+        assert_eq!(dialog.get_ref().id, get_chat_id("", &[1, 2], 1));
+        // the renamed chat must not duplicate the one found at the next probe
+        let other = create(1, "other", vec![]).await.unwrap();
+        let rename = ChatInfoUpdate {
+            chat_id: other.get_ref().id,
+            user_id: 1,
+            description: String::from("chat"),
+            permanent: true,
+            export_discouraged: false,
+        };
+        let res = chat_room
+            .update_chat_info(authorized(&chat_room, 1, rename))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::AlreadyExists);
+        // all the probes are taken
+        for probe in 0..CHAT_ID_PROBES {
+            let id = get_chat_id("taken", &[], probe);
+            chat_room
+                .storage
+                .write_chat(id, &unrelated(id, "secret"))
+                .unwrap();
+        }
+        let res = create(1, "taken", vec![]).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn chat_info_updated() {
+        fn rename(chat_id: ChatId, user_id: UserId, description: &str) -> ChatInfoUpdate {
+            ChatInfoUpdate {
+                chat_id,
+                user_id,
+                description: description.to_string(),
+                permanent: false,
+                export_discouraged: false,
+            }
+        }
+
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let chat = chat_room
+            .create_chat(authorized(
+                &chat_room,
+                1,
+                chat_info(1, "tpyo", true, vec![2]),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut chats = chat_room
+            .get_chats(authorized(&chat_room, 3, Registration { user_id: 3 }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(next_update(&mut chats).await.updated.len(), 1);
+        // only the members rename the chat
+        let res = chat_room
+            .update_chat_info(authorized(&chat_room, 3, rename(chat.id, 3, "typo")))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::PermissionDenied);
+        let res = chat_room
+            .update_chat_info(authorized(&chat_room, 1, rename(1, 1, "typo")))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::NotFound);
+        let renamed = chat_room
+            .update_chat_info(authorized(&chat_room, 2, rename(chat.id, 2, " typo ")))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(renamed.id, chat.id);
+        assert_eq!(renamed.description, "typo");
+        assert!(!renamed.permanent);
+        assert_eq!(chat_room.storage.read_chat(chat.id).unwrap(), Some(renamed));
+        let update = next_update(&mut chats).await;
+        assert_eq!(update.updated[0].chat.as_ref().unwrap().description, "typo");
+        // the description of another chat is taken
+        chat_room
+            .create_chat(authorized(
+                &chat_room,
+                1,
+                chat_info(1, "other", true, vec![]),
+            ))
+            .await
+            .unwrap();
+        let res = chat_room
+            .update_chat_info(authorized(&chat_room, 1, rename(chat.id, 1, "other")))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::AlreadyExists);
+        // the dialog is visible to its members only
+        chat_room
+            .update_chat_info(authorized(&chat_room, 1, rename(chat.id, 1, "")))
+            .await
+            .unwrap();
+        assert!(next_update(&mut chats).await.gone.is_empty());
+        assert_eq!(next_update(&mut chats).await.gone, vec![chat.id]);
+        // and becomes visible to everyone once named
+        chat_room
+            .update_chat_info(authorized(&chat_room, 1, rename(chat.id, 1, "named")))
+            .await
+            .unwrap();
+        let update = next_update(&mut chats).await;
+        assert_eq!(update.updated[0].chat.as_ref().map(|c| c.id), Some(chat.id));
+    }
+
+    #[tokio::test]
+    async fn members_kicked_by_owner() {
+        async fn kick(
+            chat_room: &Arc<ChatRoomImpl<InMemoryStorage>>,
+            chat_id: ChatId,
+            user_id: UserId,
+            member_id: UserId,
+        ) -> Result<Response<RpcResult>, Status> {
+            let member_ref = MemberReference {
+                chat_id,
+                user_id,
+                member_id,
+            };
+            chat_room
+                .kick_user(authorized(chat_room, user_id, member_ref))
+                .await
+        }
+
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let chat = chat_room
+            .create_chat(authorized(
+                &chat_room,
+                1,
+                chat_info(1, "owned", true, vec![2, 3]),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(chat.owner_id, 1);
+        let stored = chat_room.storage.read_chat(chat.id).unwrap().unwrap();
+        assert_eq!(stored.owner_id, 1);
+        let mut chats = chat_room
+            .get_chats(authorized(&chat_room, 2, Registration { user_id: 2 }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(next_update(&mut chats).await.updated.len(), 1);
+        let code = |res: Result<Response<RpcResult>, Status>| res.unwrap_err().code();
+        assert_eq!(
+            code(kick(&chat_room, chat.id, 2, 3).await),
+            tonic::Code::PermissionDenied
+        );
+        assert_eq!(
+            code(kick(&chat_room, chat.id, 1, 1).await),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(
+            code(kick(&chat_room, chat.id, 1, 4).await),
+            tonic::Code::NotFound
+        );
+        assert_eq!(code(kick(&chat_room, 1, 1, 2).await), tonic::Code::NotFound);
+        kick(&chat_room, chat.id, 1, 2).await.unwrap();
+        assert_eq!(
+            chat_room
+                .storage
+                .read_chat(chat.id)
+                .unwrap()
+                .map(|c| c.users),
+            Some(vec![1, 3])
+        );
+        // the named chat is still visible, yet it is gone for the kicked member
+        let update = next_update(&mut chats).await;
+        assert_eq!(update.updated[0].chat.as_ref().unwrap().users, vec![1, 3]);
+        let update = next_update(&mut chats).await;
+        assert_eq!(update.gone, vec![chat.id]);
+    }
+
+    #[tokio::test]
+    async fn membership_changes_posted() {
+        let chat_ref = |user_id, chat_id| ChatReference { user_id, chat_id };
+        for &system_posts in &[true, false] {
+            let chat_room = Arc::new(ChatRoomImpl::with_storage(
+                InMemoryStorage::new(),
+                Limits {
+                    system_posts,
+                    ..Limits::default()
+                },
+            ));
+            for (id, short_name) in [(1, "owner"), (2, "bob"), (3, "carol")].iter() {
+                let user = User {
+                    id: *id,
+                    name: String::new(),
+                    short_name: String::from(*short_name),
+                    created: 0,
+                };
+                chat_room.storage.write_user(*id, &user).unwrap();
+            }
+            let chat = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    1,
+                    chat_info(1, "club", true, vec![2]),
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            for _ in 0..2 {
+                chat_room
+                    .enter_chat(authorized(&chat_room, 3, chat_ref(3, chat.id)))
+                    .await
+                    .unwrap();
+            }
+            // the unknown user is named by the id
+            chat_room
+                .enter_chat(authorized(&chat_room, 4, chat_ref(4, chat.id)))
+                .await
+                .unwrap();
+            chat_room
+                .leave_chat(authorized(&chat_room, 2, chat_ref(2, chat.id)))
+                .await
+                .unwrap();
+            let member_ref = MemberReference {
+                chat_id: chat.id,
+                user_id: 1,
+                member_id: 3,
+            };
+            chat_room
+                .kick_user(authorized(&chat_room, 1, member_ref))
+                .await
+                .unwrap();
+            let posts = chat_room.storage.read_chat_posts(chat.id, 0, 10).unwrap();
+            if !system_posts {
+                assert!(posts.is_empty());
+                continue;
+            }
+            let texts: Vec<&str> = posts.iter().map(|p| p.text.as_str()).collect();
+            assert_eq!(
+                texts,
+                vec![
+                    "carol entered the chat",
+                    "user 4 entered the chat",
+                    "bob left the chat",
+                    "carol was removed by owner",
+                ]
+            );
+            assert!(posts
+                .iter()
+                .all(|p| p.user_id == NOT_USER_ID && p.kind() == PostKind::System));
+            // never unread
+            assert_eq!(
+                chat_room.storage.count_posts_after(chat.id, 0, 1).unwrap(),
+                0
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn kicked_user_stays_out() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let chat_ref = |user_id, chat_id| ChatReference { user_id, chat_id };
+        let chat = chat_room
+            .create_chat(authorized(
+                &chat_room,
+                1,
+                chat_info(1, "club", true, vec![2]),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        // the public chat is open to everyone
+        chat_room
+            .enter_chat(authorized(&chat_room, 3, chat_ref(3, chat.id)))
+            .await
+            .unwrap();
+        let member_ref = MemberReference {
+            chat_id: chat.id,
+            user_id: 1,
+            member_id: 3,
+        };
+        chat_room
+            .kick_user(authorized(&chat_room, 1, member_ref))
+            .await
+            .unwrap();
+        let err = chat_room
+            .enter_chat(authorized(&chat_room, 3, chat_ref(3, chat.id)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        // nor by creating the chat again
+        let err = chat_room
+            .create_chat(authorized(
+                &chat_room,
+                3,
+                chat_info(3, "club", true, Vec::new()),
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        // another member does not take the user back
+        let invitation = |from_user_id| Invitation {
+            chat_id: chat.id,
+            from_user_id,
+            to_user_id: 3,
+            declined: false,
+        };
+        let err = chat_room
+            .invite_user(authorized(&chat_room, 2, invitation(2)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        let members = |chat_id| chat_room.storage.read_chat(chat_id).unwrap().unwrap().users;
+        assert!(!members(chat.id).contains(&3));
+        // the owner does
+        chat_room.storage.write_invitation(&invitation(1)).unwrap();
+        chat_room
+            .enter_chat(authorized(&chat_room, 3, chat_ref(3, chat.id)))
+            .await
+            .unwrap();
+        assert!(members(chat.id).contains(&3));
+        assert!(!chat_room.storage.is_banned(chat.id, 3).unwrap());
+        // the private chat takes an invitation
+        let dialog = chat_room
+            .create_chat(authorized(&chat_room, 1, chat_info(1, "", true, vec![2])))
+            .await
+            .unwrap()
+            .into_inner();
+        let err = chat_room
+            .enter_chat(authorized(&chat_room, 3, chat_ref(3, dialog.id)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        assert!(!members(dialog.id).contains(&3));
+    }
+
+    #[tokio::test]
+    async fn system_posts_not_faked() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let chat = chat_room
+            .create_chat(authorized(
+                &chat_room,
+                1,
+                chat_info(1, "club", true, Vec::new()),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let response = chat_room
+            .create_post(authorized(
+                &chat_room,
+                1,
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id: chat.id,
+                    user_id: 1,
+                    text: String::from("owner was removed by admin"),
+                    kind: PostKind::System as i32,
+                    ..Default::default()
+                },
+            ))
+            .await
+            .unwrap();
+        let post_id = response
+            .metadata()
+            .get(POST_ID_KEY)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap();
+        let stored = chat_room
+            .storage
+            .read_post(chat.id, post_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.kind(), PostKind::Regular);
+        assert_eq!(stored.user_id, 1);
+    }
+
+    #[test]
+    fn membership_of_chat() {
+        let chat = Chat {
+            id: 10,
+            permanent: true,
+            description: String::from("public"),
+            users: vec![1],
+            created: 0,
+            owner_id: 1,
+            export_discouraged: false,
+        };
+        let invited: HashSet<ChatId> = vec![10].into_iter().collect();
+        assert_eq!(my_membership(&chat, 1, &invited), Membership::Member);
+        assert_eq!(my_membership(&chat, 2, &invited), Membership::Invited);
+        assert_eq!(my_membership(&chat, 2, &HashSet::new()), Membership::None);
+    }
+
+    #[tokio::test]
+    async fn membership_shaped_per_user() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let mut chat_ids = Vec::new();
+        for description in &["invited", "other"] {
+            let chat = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    1,
+                    chat_info(1, description, true, Vec::new()),
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            chat_ids.push(chat.id);
+        }
+        let invitation = Invitation {
+            chat_id: chat_ids[0],
+            from_user_id: 1,
+            to_user_id: 2,
+            declined: false,
+        };
+        chat_room.storage.write_invitation(&invitation).unwrap();
+        let mut chats = chat_room
+            .get_chats(authorized(&chat_room, 2, Registration { user_id: 2 }))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut existing = next_update(&mut chats).await.updated;
+        existing.sort_by_key(|u| {
+            chat_ids
+                .iter()
+                .position(|id| *id == u.chat.as_ref().unwrap().id)
+        });
+        let memberships: Vec<Membership> = existing.iter().map(|u| u.my_membership()).collect();
+        assert_eq!(memberships, vec![Membership::Invited, Membership::None]);
+        // the creator is shown the same chats as a member
+        let mut own = chat_room
+            .get_chats(authorized(&chat_room, 1, Registration { user_id: 1 }))
+            .await
+            .unwrap()
+            .into_inner();
+        let update = next_update(&mut own).await;
+        assert!(update
+            .updated
+            .iter()
+            .all(|u| u.my_membership() == Membership::Member));
+        // declined
+        chat_room
+            .decline_invitation(authorized(&chat_room, 2, invitation.clone()))
+            .await
+            .unwrap();
+        let update = next_update(&mut chats).await;
+        assert_eq!(update.updated[0].my_membership(), Membership::None);
+        // invited again and entered
+        chat_room.storage.write_invitation(&invitation).unwrap();
+        chat_room.notify_invitation(invitation.chat_id, 2, true);
+        let update = next_update(&mut chats).await;
+        assert_eq!(update.updated[0].my_membership(), Membership::Invited);
+        chat_room
+            .enter_chat(authorized(
+                &chat_room,
+                2,
+                ChatReference {
+                    user_id: 2,
+                    chat_id: chat_ids[0],
+                },
+            ))
+            .await
+            .unwrap();
+        let update = next_update(&mut chats).await;
+        assert_eq!(update.updated[0].my_membership(), Membership::Member);
+        assert!(chat_room.storage.read_invitations_to(2).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn typing_told_to_members() {
+        async fn next_event<S>(typing: &mut S) -> TypingEvent
+        where
+            S: Stream<Item = Result<TypingEvent, Status>> + Unpin,
+        {
+            tokio::time::timeout(Duration::from_secs(1), typing.next())
+                .await
+                .expect("event is delivered in time")
+                .unwrap()
+                .unwrap()
+        }
+
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let chat = chat_room
+            .create_chat(authorized(
+                &chat_room,
+                1,
+                chat_info(1, "typing", true, vec![2]),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut typing = chat_room
+            .get_typing(authorized(&chat_room, 2, Registration { user_id: 2 }))
+            .await
+            .unwrap()
+            .into_inner();
+        let chat_ref = ChatReference {
+            user_id: 1,
+            chat_id: chat.id,
+        };
+        // the repeat is not told again
+        for _ in 0..2 {
+            chat_room
+                .set_typing(authorized(&chat_room, 1, chat_ref.clone()))
+                .await
+                .unwrap();
+        }
+        let event = next_event(&mut typing).await;
+        assert_eq!(
+            (event.chat_id, event.user_id, event.typing),
+            (chat.id, 1, true)
+        );
+        // the post ends typing
+        chat_room
+            .create_post(authorized(
+                &chat_room,
+                1,
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id: chat.id,
+                    user_id: 1,
+                    text: String::from("done"),
+                    ..Default::default()
+                },
+            ))
+            .await
+            .unwrap();
+        let event = next_event(&mut typing).await;
+        assert_eq!((event.user_id, event.typing), (1, false));
+        // not a member
+        let status = chat_room
+            .set_typing(authorized(
+                &chat_room,
+                3,
+                ChatReference {
+                    user_id: 3,
+                    chat_id: chat.id,
+                },
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn damaged_posts_degrade_chat() {
+        const TEST_DB: &str = "migchat-test-degraded-chat.db";
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            let chat = chat_room
+                .create_chat(authorized(
+                    &chat_room,
+                    1,
+                    chat_info(1, "damaged", true, vec![2]),
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            let mut chats = chat_room
+                .get_chats(authorized(&chat_room, 2, Registration { user_id: 2 }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(!next_update(&mut chats).await.updated[0].degraded);
+            for key in 100..120 {
+                chat_room.storage.plant_raw_post(chat.id, key, &[0xff; 3]);
+            }
+            let history = chat_room
+                .get_chat_history(authorized(
+                    &chat_room,
+                    1,
+                    HistoryParams {
+                        chat_id: chat.id,
+                        idx_from: 0,
+                        count: 100,
+                        before_seq: 0,
+                    },
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(history.posts.is_empty());
+            // the members are hinted
+            assert!(next_update(&mut chats).await.updated[0].degraded);
+            // the chat remains usable
+            chat_room
+                .create_post(authorized(
+                    &chat_room,
+                    1,
+                    Post {
+                        id: NOT_POST_ID,
+                        chat_id: chat.id,
+                        user_id: 1,
+                        text: String::from("still here"),
+                        ..Default::default()
+                    },
+                ))
+                .await
+                .unwrap();
+            // repair clears the hint
+            let config = crate::verifier::VerifierConfig {
+                auto_repair: true,
+                pause: Duration::from_millis(1),
+                ..Default::default()
+            };
+            let stats = crate::verifier::VerifierStats::default();
+            crate::verifier::verify_slice(&chat_room, &config, &stats)
+                .await
+                .unwrap();
+            let repaired = next_update(&mut chats).await;
+            assert!(!repaired.updated[0].degraded);
+            // the update carries the count of the salvaged posts
+            assert_eq!(repaired.updated[0].currently_posts, 1);
+            let history = chat_room
+                .get_chat_history(authorized(
+                    &chat_room,
+                    1,
+                    HistoryParams {
+                        chat_id: chat.id,
+                        idx_from: 0,
+                        count: 100,
+                        before_seq: 0,
+                    },
+                ))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(history.posts.len(), 1);
+            assert_eq!(history.posts[0].text, "still here");
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    async fn next_update<S>(chats: &mut S) -> UpdateChats
+    where
+        S: Stream<Item = Result<UpdateChats, Status>> + Unpin,
+    {
+        tokio::time::timeout(Duration::from_secs(1), chats.next())
+            .await
+            .expect("update is delivered in time")
+            .unwrap()
+            .unwrap()
+    }
+
+    // the post of the user with the id assigned, the forged author name is not kept
+    async fn post_by<S: ChatStorage>(
+        chat_room: &Arc<ChatRoomImpl<S>>,
+        user_id: UserId,
+        chat_id: ChatId,
+        text: &str,
+    ) -> PostId {
+        let response = chat_room
+            .create_post(authorized(
+                chat_room,
+                user_id,
+                Post {
+                    id: NOT_POST_ID,
+                    chat_id,
+                    user_id,
+                    text: String::from(text),
+                    author_name: String::from("forged"),
+                    ..Default::default()
+                },
+            ))
+            .await
+            .unwrap();
+        response
+            .metadata()
+            .get(POST_ID_KEY)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap()
+    }
+
+    // the user and the authorization value of its session
+    async fn registered<S: ChatStorage>(
+        chat_room: &Arc<ChatRoomImpl<S>>,
+        short_name: &str,
+    ) -> (UserId, String) {
+        let (user_id, token, _) = registered_as(chat_room, short_name, None).await;
+        (user_id, token)
+    }
+
+    // the returning user presents the id and the secret given by the first registration,
+    // the new one is given the secret
+    async fn registered_as<S: ChatStorage>(
+        chat_room: &Arc<ChatRoomImpl<S>>,
+        short_name: &str,
+        known: Option<(UserId, &str)>,
+    ) -> (UserId, String, String) {
+        let registered = chat_room
+            .register(registration(short_name, known))
+            .await
+            .unwrap();
+        let value = |key| {
+            registered
+                .metadata()
+                .get(key)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        };
+        let token = value(SESSION_TOKEN_KEY).map(|v| bearer_value(&v)).unwrap();
+        let secret = value(USER_SECRET_KEY).unwrap_or_default();
+        let user_id = registered.into_inner().registration.unwrap().user_id;
+        (user_id, token, secret)
+    }
+
+    fn registration(short_name: &str, known: Option<(UserId, &str)>) -> Request<UserInfo> {
+        let mut request = Request::new(UserInfo {
+            name: String::from("User Name"),
+            short_name: String::from(short_name),
+        });
+        if let Some((user_id, secret)) = known {
+            let metadata = request.metadata_mut();
+            metadata.insert(USER_ID_KEY, user_id.to_string().parse().unwrap());
+            metadata.insert(USER_SECRET_KEY, secret.parse().unwrap());
+        }
+        request
+    }
+
+    fn with_token<T>(token: &str, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert(AUTHORIZATION_KEY, token.parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn session_token_required() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let (user_id, token) = registered(&chat_room, "user").await;
+        let request = |user_id, token: &str| {
+            let mut request = Request::new(chat_info(user_id, "", true, Vec::new()));
+            if !token.is_empty() {
+                request
+                    .metadata_mut()
+                    .insert(AUTHORIZATION_KEY, token.parse().unwrap());
+            }
+            request
+        };
+        let code_of = |res: Result<Response<Chat>, Status>| res.unwrap_err().code();
+        // missing token
+        let res = chat_room.create_chat(request(user_id, "")).await;
+        assert_eq!(code_of(res), tonic::Code::Unauthenticated);
+        // wrong token
+        let wrong = bearer_value(&"0".repeat(32));
+        let res = chat_room.create_chat(request(user_id, &wrong)).await;
+        assert_eq!(code_of(res), tonic::Code::Unauthenticated);
+        // the token of another user
+        let res = chat_room
+            .create_chat(request(user_id.wrapping_add(1), &token))
+            .await;
+        assert_eq!(code_of(res), tonic::Code::PermissionDenied);
+        assert!(chat_room
+            .create_chat(request(user_id, &token))
+            .await
+            .is_ok());
+        // expires with the logout
+        chat_room
+            .logout(with_token(&token, Registration { user_id }))
+            .await
+            .unwrap();
+        let res = chat_room.create_chat(request(user_id, &token)).await;
+        assert_eq!(code_of(res), tonic::Code::Unauthenticated);
+    }
+
+    // the peer keeps calling while waiting for the users gone offline
+    async fn next_offline<S, U>(
+        chat_room: &Arc<ChatRoomImpl<S>>,
+        token: &str,
+        users: &mut U,
+    ) -> Vec<UserId>
+    where
+        S: ChatStorage,
+        U: Stream<Item = Result<UpdateUsers, Status>> + Unpin,
+    {
+        let started = std::time::Instant::now();
+        loop {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            chat_room
+                .get_chat_history(with_token(token, heartbeat()))
+                .await
+                .unwrap();
+            match tokio::time::timeout(Duration::from_millis(100), users.next()).await {
+                Ok(Some(Ok(UpdateUsers { offline, .. }))) if !offline.is_empty() => return offline,
+                Ok(Some(Ok(_))) | Err(_) => {}
+                other => panic!("unexpected users update {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_user_goes_offline() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let (silent, _) = registered(&chat_room, "silent").await;
+        let (peer, token) = registered(&chat_room, "peer").await;
+        let mut users = chat_room
+            .get_users(with_token(&token, Registration { user_id: peer }))
+            .await
+            .unwrap()
+            .into_inner();
+        chat_room.reconfigure(Tunables {
+            presence: crate::presence::PresenceConfig {
+                idle_timeout: Duration::from_millis(300),
+                check_interval: Duration::from_millis(50),
+                ..Default::default()
+            },
+            ..Tunables::default()
+        });
+        let presence = tokio::spawn(crate::presence::run(chat_room.clone()));
+        let offline = next_offline(&chat_room, &token, &mut users).await;
+        assert_eq!(offline, vec![silent]);
+        assert!(chat_room.presence.is_online(peer));
+        presence.abort();
+        // the client dropping its last stream goes offline at once
+        let (gone, gone_token) = registered(&chat_room, "gone").await;
+        let posts = chat_room
+            .get_posts(with_token(&gone_token, Registration { user_id: gone }))
+            .await
+            .unwrap()
+            .into_inner();
+        drop(posts);
+        let offline = next_offline(&chat_room, &token, &mut users).await;
+        assert_eq!(offline, vec![gone]);
+    }
+
+    #[tokio::test]
+    async fn reloaded_settings_apply() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let proxy = ProxyFilter::new(ProxyConfig::default());
+        let running = crate::settings::parse(&config::Config::default()).unwrap();
+        let (user_id, token) = registered(&chat_room, "owner").await;
+        let create = |description: &str| {
+            let info = chat_info(user_id, description, false, vec![2, 3]);
+            chat_room.create_chat(with_token(&token, info))
+        };
+        assert!(create("before").await.is_ok());
+        let mut loaded = running.clone();
+        loaded.endpoint = String::from("127.0.0.1:50052");
+        loaded.tunables.limits.max_chat_members = 2;
+        let running = crate::apply_settings(&chat_room, &proxy, &running, loaded);
+        // the next request honors the new limit at once
+        let res = create("after").await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::InvalidArgument);
+        assert_eq!(running.tunables.limits.max_chat_members, 2);
+        // the endpoint requires restart
+        assert_eq!(running.endpoint, "0.0.0.0:50051");
+    }
+
+    #[tokio::test]
+    async fn user_renamed() {
+        fn rename(user_id: UserId, short_name: &str, name: &str) -> UserUpdate {
+            UserUpdate {
+                user_id,
+                name: name.to_string(),
+                short_name: short_name.to_string(),
+            }
+        }
+
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let (user_id, token, secret) = registered_as(&chat_room, "user", None).await;
+        let (peer, peer_token) = registered(&chat_room, "peer").await;
+        let mut users = chat_room
+            .get_users(with_token(&peer_token, Registration { user_id: peer }))
+            .await
+            .unwrap()
+            .into_inner();
+        // only the user itself is allowed
+        let res = chat_room
+            .update_user(with_token(
+                &peer_token,
+                rename(user_id, "renamed", "New Name"),
+            ))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::PermissionDenied);
+        let res = chat_room
+            .update_user(with_token(&token, rename(user_id, " ", "New Name")))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::InvalidArgument);
+        // the names of another user are taken
+        let res = chat_room
+            .update_user(with_token(&token, rename(user_id, "peer", "User Name")))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::AlreadyExists);
+        let res = chat_room
+            .update_user(with_token(&token, rename(user_id, "renamed", " New Name ")))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(res.description, "updated");
+        let stored = chat_room.storage.read_user(user_id).unwrap().unwrap();
+        assert_eq!(stored.short_name, "renamed");
+        assert_eq!(stored.name, "New Name");
+        // the peers learn the new names
+        let started = std::time::Instant::now();
+        loop {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            let update = tokio::time::timeout(Duration::from_secs(1), users.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            if update.added.iter().any(|u| u == &stored) {
+                break;
+            }
+        }
+        // the same names leave it unchanged
+        let res = chat_room
+            .update_user(with_token(&token, rename(user_id, "renamed", "New Name")))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(res.description, "unchanged");
+        // the returning user is found by the id, not by the names
+        let (same_id, ..) = registered_as(&chat_room, "user", Some((user_id, &secret))).await;
+        assert_eq!(same_id, user_id);
+        assert_eq!(
+            chat_room
+                .storage
+                .read_user(user_id)
+                .unwrap()
+                .unwrap()
+                .short_name,
+            "renamed"
+        );
+    }
+
+    #[tokio::test]
+    async fn user_ids_assigned() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let (user_id, _, secret) = registered_as(&chat_room, "user", None).await;
+        assert!(!secret.is_empty());
+        let info = UserInfo {
+            name: String::from("User Name"),
+            short_name: String::from("user"),
+        };
+        assert_ne!(user_id, ids::user_id(&info));
+        // the secret is not stored as is
+        let digest = chat_room.storage.read_user_secret(user_id).unwrap();
+        assert_eq!(digest, Some(secret_digest(&secret)));
+        // the namesake is refused rather than logged into the account
+        let res = chat_room.register(registration("user", None)).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::AlreadyExists);
+        // the same full name with another short name is another user
+        let (other_id, _) = registered(&chat_room, "other").await;
+        assert_ne!(other_id, user_id);
+        // the returning user presents the id along with the secret, given only once
+        let (same_id, _, again) = registered_as(&chat_room, "user", Some((user_id, &secret))).await;
+        assert_eq!(same_id, user_id);
+        assert!(again.is_empty());
+        // the unknown id is no account, the new user is registered
+        let (new_id, ..) = registered_as(&chat_room, "new", Some((12345, "unknown"))).await;
+        assert_ne!(new_id, 12345);
+        let mut request = registration("user", None);
+        request
+            .metadata_mut()
+            .insert(USER_ID_KEY, "not a number".parse().unwrap());
+        let res = chat_room.register(request).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn user_id_alone_refused() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let (user_id, _, _) = registered_as(&chat_room, "user", None).await;
+        // the id is public, anyone may present it
+        let mut request = registration("user", None);
+        request
+            .metadata_mut()
+            .insert(USER_ID_KEY, user_id.to_string().parse().unwrap());
+        let res = chat_room.register(request).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::Unauthenticated);
+        let res = chat_room
+            .register(registration("user", Some((user_id, "guessed"))))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn legacy_users_given_secrets() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        // the user registered when the ids were derived from the names has no secret
+        let legacy = UserInfo {
+            name: String::from("User Name"),
+            short_name: String::from("legacy"),
+        };
+        let legacy_id = ids::user_id(&legacy);
+        let user = User {
+            id: legacy_id,
+            name: legacy.name,
+            short_name: legacy.short_name,
+            created: 1,
+        };
+        chat_room.storage.write_user(legacy_id, &user).unwrap();
+        // neither the id alone nor the short name alone log into it
+        let mut request = registration("other", None);
+        request
+            .metadata_mut()
+            .insert(USER_ID_KEY, legacy_id.to_string().parse().unwrap());
+        let res = chat_room.register(request).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::Unauthenticated);
+        let mut request = registration("legacy", None);
+        request.get_mut().name = String::from("Other Name");
+        let res = chat_room.register(request).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::AlreadyExists);
+        // the names do once, the secret is given then
+        let (user_id, _, secret) = registered_as(&chat_room, "legacy", None).await;
+        assert_eq!(user_id, legacy_id);
+        assert!(!secret.is_empty());
+        let res = chat_room.register(registration("legacy", None)).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::AlreadyExists);
+        let (user_id, _, again) =
+            registered_as(&chat_room, "legacy", Some((legacy_id, &secret))).await;
+        assert_eq!(user_id, legacy_id);
+        assert!(again.is_empty());
+    }
+
+    #[tokio::test]
+    async fn same_short_name_registered_once() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let attempts: Vec<_> = (0..8)
+            .map(|_| {
+                let chat_room = chat_room.clone();
+                tokio::spawn(async move { chat_room.register(registration("user", None)).await })
+            })
+            .collect();
+        let mut registered = 0;
+        for attempt in attempts {
+            match attempt.await.unwrap() {
+                Ok(_) => registered += 1,
+                Err(e) => assert_eq!(e.code(), tonic::Code::AlreadyExists),
+            }
+        }
+        assert_eq!(registered, 1);
+        assert_eq!(chat_room.storage.read_all_users().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn snapshots_listed() {
+        fn paged(token: &str, user_id: UserId, offset: &str, limit: &str) -> Request<Registration> {
+            let mut request = with_token(token, Registration { user_id });
+            let metadata = request.metadata_mut();
+            metadata.insert(LIST_OFFSET_KEY, offset.parse().unwrap());
+            metadata.insert(LIST_LIMIT_KEY, limit.parse().unwrap());
+            request
+        }
+
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let (user_id, token) = registered(&chat_room, "user").await;
+        let mut online = Vec::new();
+        for name in &["first", "second"] {
+            online.push(registered(&chat_room, name).await.0);
+        }
+        // the user stored but never registered in the session is offline
+        let stored = User {
+            id: 12345,
+            name: String::from("Stored User"),
+            short_name: String::from("stored"),
+            created: 1,
+        };
+        chat_room.storage.write_user(stored.id, &stored).unwrap();
+        let mut all: Vec<UserId> = online.iter().copied().chain(Some(stored.id)).collect();
+        all.sort_unstable();
+        let public = chat_room
+            .create_chat(authorized(
+                &chat_room,
+                online[0],
+                chat_info(online[0], "public", true, vec![]),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        // the dialog of the others is not visible to the user
+        chat_room
+            .create_chat(authorized(
+                &chat_room,
+                online[0],
+                chat_info(online[0], "", true, vec![online[1]]),
+            ))
+            .await
+            .unwrap();
+        let own = chat_room
+            .create_chat(authorized(
+                &chat_room,
+                user_id,
+                chat_info(user_id, "own", true, vec![]),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut visible = vec![public.id, own.id];
+        visible.sort_unstable();
+
+        // the lists tell what the streams start with
+        let listed = chat_room
+            .list_users(with_token(&token, Registration { user_id }))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut users = chat_room
+            .get_users(with_token(&token, Registration { user_id }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(users.next().await.unwrap().unwrap(), listed);
+        let ids: Vec<UserId> = listed.added.iter().map(|u| u.id).collect();
+        assert_eq!(ids, all);
+        assert_eq!(listed.offline, vec![stored.id]);
+        assert_eq!(listed.online.len(), 2);
+        let listed = chat_room
+            .list_chats(with_token(&token, Registration { user_id }))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut chats = chat_room
+            .get_chats(with_token(&token, Registration { user_id }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(chats.next().await.unwrap().unwrap(), listed);
+        let ids: Vec<ChatId> = listed
+            .updated
+            .iter()
+            .map(|c| c.chat.as_ref().unwrap().id)
+            .collect();
+        assert_eq!(ids, visible);
+
+        // the pages
+        let page = chat_room
+            .list_users(paged(&token, user_id, "1", "1"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(page.added.len(), 1);
+        assert_eq!(page.added[0].id, all[1]);
+        assert_eq!(page.online.len() + page.offline.len(), 1);
+        let page = chat_room
+            .list_users(paged(&token, user_id, "3", "10"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(page.added.is_empty());
+        let page = chat_room
+            .list_chats(paged(&token, user_id, "0", "1"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(page.updated.len(), 1);
+        assert_eq!(page.updated[0].chat.as_ref().unwrap().id, visible[0]);
+        let res = chat_room
+            .list_chats(paged(&token, user_id, "0", "many"))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::InvalidArgument);
+        // the lists are the user's own
+        let res = chat_room
+            .list_users(with_token(&token, Registration { user_id: online[0] }))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn offline_after_last_stream() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let (peer, token) = registered(&chat_room, "peer").await;
+        let mut users = chat_room
+            .get_users(with_token(&token, Registration { user_id: peer }))
+            .await
+            .unwrap()
+            .into_inner();
+        let (user_id, user_token) = registered(&chat_room, "user").await;
+        let streams = (
+            chat_room
+                .get_posts(with_token(&user_token, Registration { user_id }))
+                .await
+                .unwrap()
+                .into_inner(),
+            chat_room
+                .get_invitations(with_token(&user_token, Registration { user_id }))
+                .await
+                .unwrap()
+                .into_inner(),
+        );
+        // one of several streams drops, the user is still there
+        drop(streams.0);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(chat_room.presence.is_online(user_id));
+        drop(streams.1);
+        let offline = next_offline(&chat_room, &token, &mut users).await;
+        assert_eq!(offline, vec![user_id]);
+    }
+
+    async fn next_text<P>(posts: &mut P) -> Option<String>
+    where
+        P: Stream<Item = Result<Post, Status>> + Unpin,
+    {
+        tokio::time::timeout(Duration::from_secs(1), posts.next())
+            .await
+            .expect("post is delivered in time")
+            .map(|post| post.unwrap().text)
+    }
+
+    #[tokio::test]
+    async fn sessions_of_same_user() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        // the same user on two terminals
+        let (user_id, first, secret) = registered_as(&chat_room, "user", None).await;
+        let (_, second, _) = registered_as(&chat_room, "user", Some((user_id, &secret))).await;
+        let (peer, peer_token) = registered(&chat_room, "peer").await;
+        let chat = chat_room
+            .create_chat(with_token(
+                &peer_token,
+                chat_info(peer, "both", true, vec![user_id]),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut streams = Vec::new();
+        let mut invitations = Vec::new();
+        for token in &[&first, &second] {
+            let posts = chat_room
+                .get_posts(with_token(token, Registration { user_id }))
+                .await
+                .unwrap()
+                .into_inner();
+            streams.push(posts);
+            let invited = chat_room
+                .get_invitations(with_token(token, Registration { user_id }))
+                .await
+                .unwrap()
+                .into_inner();
+            invitations.push(invited);
+        }
+        let post = |text: &str| Post {
+            id: NOT_POST_ID,
+            chat_id: chat.id,
+            user_id: peer,
+            text: String::from(text),
+            ..Default::default()
+        };
+        chat_room
+            .create_post(with_token(&peer_token, post("to both")))
+            .await
+            .unwrap();
+        for stream in streams.iter_mut() {
+            assert_eq!(next_text(stream).await.as_deref(), Some("to both"));
+        }
+        chat_room
+            .invite_user(with_token(
+                &peer_token,
+                Invitation {
+                    chat_id: chat.id,
+                    from_user_id: peer,
+                    to_user_id: user_id,
+                    declined: false,
+                },
+            ))
+            .await
+            .unwrap();
+        for invited in invitations.iter_mut() {
+            let invitation = tokio::time::timeout(Duration::from_secs(1), invited.next())
+                .await
+                .expect("invitation is delivered in time");
+            assert_eq!(invitation.unwrap().unwrap().chat_id, chat.id);
+        }
+        // one terminal logs out, the other one goes on
+        chat_room
+            .logout(with_token(&first, Registration { user_id }))
+            .await
+            .unwrap();
+        assert!(chat_room.presence.is_online(user_id));
+        chat_room
+            .create_post(with_token(&peer_token, post("to second")))
+            .await
+            .unwrap();
+        assert_eq!(next_text(&mut streams[0]).await, None);
+        assert_eq!(
+            next_text(&mut streams[1]).await.as_deref(),
+            Some("to second")
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_sessions_expire() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let (user_id, token) = registered(&chat_room, "user").await;
+        let mut posts = chat_room
+            .get_posts(with_token(&token, Registration { user_id }))
+            .await
+            .unwrap()
+            .into_inner();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let (peer, peer_token) = registered(&chat_room, "peer").await;
+        assert_eq!(
+            chat_room.expire_sessions(Instant::now(), Duration::from_millis(100)),
+            1
+        );
+        // the streams of the session end along with the token
+        assert_eq!(next_text(&mut posts).await, None);
+        assert!(chat_room.sessions.read().unwrap().is_empty());
+        let res = chat_room
+            .get_chat_history(with_token(&token, heartbeat()))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::Unauthenticated);
+        assert!(!chat_room.presence.is_online(user_id));
+        // the used one is kept
+        chat_room
+            .get_chat_history(with_token(&peer_token, heartbeat()))
+            .await
+            .unwrap();
+        assert!(chat_room.presence.is_online(peer));
+    }
+
+    #[tokio::test]
+    async fn history_of_members_only() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let (user_id, token) = registered(&chat_room, "user").await;
+        let (_, stranger_token) = registered(&chat_room, "stranger").await;
+        let chat = chat_room
+            .create_chat(with_token(
+                &token,
+                chat_info(user_id, "public", true, Vec::new()),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let history = |chat_id| HistoryParams {
+            chat_id,
+            idx_from: 0,
+            count: 10,
+            before_seq: 0,
+        };
+        assert!(chat_room
+            .get_chat_history(with_token(&token, history(chat.id)))
+            .await
+            .is_ok());
+        // the chat is public, its posts are still for the members
+        let res = chat_room
+            .get_chat_history(with_token(&stranger_token, history(chat.id)))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::PermissionDenied);
+        let res = chat_room
+            .get_chat_history(with_token(&token, history(chat.id.wrapping_add(1))))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn export_discouraged_by_owner() {
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let (owner, token) = registered(&chat_room, "owner").await;
+        let (member, member_token) = registered(&chat_room, "member").await;
+        let info = ChatInfo {
+            export_discouraged: true,
+            ..chat_info(owner, "sensitive", true, vec![member])
+        };
+        let chat = chat_room
+            .create_chat(with_token(&token, info))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(chat.export_discouraged);
+        let stored = chat_room.storage.read_chat(chat.id).unwrap().unwrap();
+        assert!(stored.export_discouraged);
+        let history = HistoryParams {
+            chat_id: chat.id,
+            idx_from: 0,
+            count: 10,
+            before_seq: 0,
+        };
+        let page = chat_room
+            .get_chat_history(with_token(&member_token, history.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(page.export_discouraged);
+        // the members rename the chat, the export is the owner's to allow
+        let update = |user_id, description: &str, export_discouraged| ChatInfoUpdate {
+            chat_id: chat.id,
+            user_id,
+            description: description.to_string(),
+            permanent: true,
+            export_discouraged,
+        };
+        let res = chat_room
+            .update_chat_info(with_token(
+                &member_token,
+                update(member, "sensitive", false),
+            ))
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::PermissionDenied);
+        let renamed = chat_room
+            .update_chat_info(with_token(&member_token, update(member, "secret", true)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(renamed.export_discouraged);
+        let allowed = chat_room
+            .update_chat_info(with_token(&token, update(owner, "secret", false)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!allowed.export_discouraged);
+        let page = chat_room
+            .get_chat_history(with_token(&member_token, history))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!page.export_discouraged);
+    }
+
+    #[tokio::test]
+    async fn shutdown_ends_streams() {
+        const TEST_DB: &str = "migchat-test-shutdown.db";
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let chat_room = Arc::new(ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap());
+            // take a free port
+            let addr = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let (tx_stop, rx_stop) = tokio::sync::oneshot::channel::<()>();
+            let server = tokio::spawn(crate::serve(
+                chat_room.clone(),
+                crate::Listener::Bind(addr),
+                None,
+                Arc::new(ProxyFilter::new(ProxyConfig::default())),
+                None,
+                async {
+                    let _ = rx_stop.await;
+                },
+            ));
+            let mut client = None;
+            for _ in 0..50 {
+                match ChatRoomServiceClient::connect(format!("http://{}", addr)).await {
+                    Ok(connected) => {
+                        client = Some(connected);
+                        break;
+                    }
+                    Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+                }
+            }
+            let mut client = client.expect("server is started");
+            let registered = client
+                .register(Request::new(UserInfo {
+                    name: String::from("User Name"),
+                    short_name: String::from("user"),
+                }))
+                .await
+                .unwrap();
+            let token = registered
+                .metadata()
+                .get(SESSION_TOKEN_KEY)
+                .and_then(|v| v.to_str().ok())
+                .map(bearer_value)
+                .unwrap();
+            let user_id = registered.into_inner().registration.unwrap().user_id;
+            let request = || {
+                let mut request = Request::new(Registration { user_id });
+                request
+                    .metadata_mut()
+                    .insert(AUTHORIZATION_KEY, token.parse().unwrap());
+                request
+            };
+            let mut users = client.get_users(request()).await.unwrap().into_inner();
+            let mut invitations = client
+                .get_invitations(request())
+                .await
+                .unwrap()
+                .into_inner();
+            tx_stop.send(()).unwrap();
+            assert!(matches!(users.message().await, Ok(None)));
+            assert!(matches!(invitations.message().await, Ok(None)));
+            drop(client);
+            server.await.unwrap().unwrap();
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
 }
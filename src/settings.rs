@@ -0,0 +1,556 @@
+use super::{InternalError, Limits, CONFIG_ENV};
+use crate::{
+    health::HealthConfig, presence::PresenceConfig, proxy::ProxyConfig, rate_limit::RateLimits,
+    snapshot::SnapshotConfig, tls::TlsFiles, verifier::VerifierConfig,
+};
+use config::{Config, ConfigError, Environment, File};
+use log::LevelFilter;
+use std::{path::PathBuf, time::Duration};
+
+const DEF_ENDPOINT: &str = "0.0.0.0:50051";
+const DEF_DB_FILE: &str = "migchat_server.db";
+const DEF_STORAGE: &str = "jammdb";
+const STORAGES: [&str; 2] = ["jammdb", "sqlite"];
+
+// the settings the chat room and its background tasks read on every use
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tunables {
+    pub limits: Limits,
+    pub verifier: VerifierConfig,
+    pub presence: PresenceConfig,
+    pub snapshot: SnapshotConfig,
+    pub health: HealthConfig,
+    pub rate: RateLimits,
+}
+
+// the settings of the server, the config file overridden by the environment
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerSettings {
+    // the ones below are applied on restart only
+    pub endpoint: String,
+    pub db_file: String,
+    pub storage: String,
+    // the admin service is served if set
+    pub admin_token: Option<String>,
+    // plaintext if not set
+    pub tls: Option<TlsFiles>,
+    // the ones below are applied on reload
+    pub tunables: Tunables,
+    pub proxy: ProxyConfig,
+    // the filter of the environment is in effect if not set
+    pub log_level: Option<LevelFilter>,
+}
+
+// names of the changed settings
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+    pub applied: Vec<&'static str>,
+    // have changed but keep their running values until restart
+    pub restart_required: Vec<&'static str>,
+}
+
+// the missing key is not an error, the invalid value is
+fn optional<T>(key: &str, value: Result<T, ConfigError>, errors: &mut Vec<String>) -> Option<T> {
+    match value {
+        Ok(value) => Some(value),
+        Err(ConfigError::NotFound(_)) => None,
+        Err(e) => {
+            errors.push(format!("{}, {}", key, e));
+            None
+        }
+    }
+}
+
+pub fn load(config_file: &str) -> Result<ServerSettings, InternalError> {
+    let mut config = Config::default();
+    config
+        .merge(File::with_name(config_file))?
+        .merge(Environment::with_prefix(CONFIG_ENV))?;
+    parse(&config)
+}
+
+// all the invalid values are reported at once, nothing is taken from the invalid config
+pub fn parse(config: &Config) -> Result<ServerSettings, InternalError> {
+    let mut errors = Vec::new();
+    let endpoint = optional("endpoint", config.get_str("endpoint"), &mut errors);
+    let db_file = optional("dbfile", config.get_str("dbfile"), &mut errors);
+    let storage = optional("storage", config.get_str("storage"), &mut errors);
+    let mut ints = Vec::new();
+    for key in &[
+        "max_description_len",
+        "max_chat_members",
+        "bulk_fetch_posts",
+        "max_attachment_size",
+        "max_post_bytes",
+        "max_attachments",
+        "verify_startup_delay_secs",
+        "verify_interval_secs",
+        "verify_slice",
+        "presence_timeout_secs",
+        "presence_check_secs",
+        "session_timeout_secs",
+        "backup_interval_secs",
+        "backup_keep",
+        "health_check_secs",
+        "shutdown_drain_secs",
+        "posts_per_minute",
+        "invitations_per_minute",
+    ] {
+        ints.push((*key, optional(key, config.get_int(key), &mut errors)));
+    }
+    let auto_repair = optional("auto_repair", config.get_bool("auto_repair"), &mut errors);
+    let stamp_author_names = optional(
+        "stamp_author_names",
+        config.get_bool("stamp_author_names"),
+        &mut errors,
+    );
+    let system_posts = optional("system_posts", config.get_bool("system_posts"), &mut errors);
+    let trusted_proxies = optional(
+        "trusted_proxies",
+        config.get::<Vec<String>>("trusted_proxies"),
+        &mut errors,
+    );
+    let forwarded_for_key = optional(
+        "proxy_forwarded_for_key",
+        config.get_str("proxy_forwarded_for_key"),
+        &mut errors,
+    );
+    let verified_user_key = optional(
+        "proxy_verified_user_key",
+        config.get_str("proxy_verified_user_key"),
+        &mut errors,
+    );
+    let require_identity = optional(
+        "require_proxy_identity",
+        config.get_bool("require_proxy_identity"),
+        &mut errors,
+    );
+    let backup_dir = optional("backup_dir", config.get_str("backup_dir"), &mut errors);
+    let backup_operators = optional(
+        "backup_operators",
+        config.get::<Vec<String>>("backup_operators"),
+        &mut errors,
+    );
+    let admin_token = optional("admin_token", config.get_str("admin_token"), &mut errors);
+    let tls_cert = optional("tls_cert", config.get_str("tls_cert"), &mut errors);
+    let tls_key = optional("tls_key", config.get_str("tls_key"), &mut errors);
+    let tls_client_ca = optional(
+        "tls_client_ca",
+        config.get_str("tls_client_ca"),
+        &mut errors,
+    );
+    let log_level = optional("log_level", config.get_str("log_level"), &mut errors);
+
+    let mut settings = ServerSettings {
+        endpoint: endpoint.unwrap_or_else(|| String::from(DEF_ENDPOINT)),
+        db_file: db_file.unwrap_or_else(|| String::from(DEF_DB_FILE)),
+        storage: storage.unwrap_or_else(|| String::from(DEF_STORAGE)),
+        // the empty token does not enable the service
+        admin_token: admin_token.filter(|token| !token.is_empty()),
+        tls: None,
+        tunables: Tunables::default(),
+        proxy: ProxyConfig::default(),
+        log_level: None,
+    };
+    if let Err(e) = settings.endpoint.parse::<std::net::SocketAddr>() {
+        errors.push(format!("endpoint '{}', {}", settings.endpoint, e));
+    }
+    if !STORAGES.contains(&settings.storage.as_str()) {
+        errors.push(format!(
+            "unknown storage '{}', use jammdb or sqlite",
+            settings.storage
+        ));
+    }
+    match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => {
+            settings.tls = Some(TlsFiles {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+                client_ca: tls_client_ca.map(PathBuf::from),
+            })
+        }
+        (None, None) if tls_client_ca.is_some() => {
+            errors.push(String::from("tls_client_ca requires tls_cert and tls_key"))
+        }
+        (None, None) => {}
+        _ => errors.push(String::from("tls_cert and tls_key are to be set together")),
+    }
+
+    let tunables = &mut settings.tunables;
+    for (key, value) in ints {
+        let value = match value {
+            Some(value) if value < 0 => {
+                errors.push(format!("{} is negative", key));
+                continue;
+            }
+            Some(value) => value as u64,
+            None => continue,
+        };
+        match key {
+            "max_description_len" => tunables.limits.max_description_len = value as usize,
+            "max_chat_members" => tunables.limits.max_chat_members = value as usize,
+            "bulk_fetch_posts" => tunables.limits.bulk_fetch_posts = value as usize,
+            "max_attachment_size" => tunables.limits.max_attachment_size = value as usize,
+            "max_post_bytes" => tunables.limits.max_post_bytes = value as usize,
+            "max_attachments" => tunables.limits.max_attachments = value as usize,
+            "verify_startup_delay_secs" => {
+                tunables.verifier.startup_delay = Duration::from_secs(value)
+            }
+            "verify_interval_secs" => tunables.verifier.interval = Duration::from_secs(value),
+            "verify_slice" => tunables.verifier.slice = value as usize,
+            "presence_timeout_secs" => {
+                tunables.presence.idle_timeout = Duration::from_secs(value.max(1))
+            }
+            "presence_check_secs" => {
+                tunables.presence.check_interval = Duration::from_secs(value.max(1))
+            }
+            "session_timeout_secs" => {
+                tunables.presence.session_timeout = Duration::from_secs(value.max(1))
+            }
+            // zero turns the periodic snapshots off
+            "backup_interval_secs" if value == 0 => tunables.snapshot.interval = None,
+            "backup_interval_secs" => tunables.snapshot.interval = Some(Duration::from_secs(value)),
+            "backup_keep" => tunables.snapshot.keep = (value as usize).max(1),
+            "health_check_secs" => {
+                tunables.health.check_interval = Duration::from_secs(value.max(1))
+            }
+            "shutdown_drain_secs" => tunables.health.drain = Duration::from_secs(value),
+            // zero lifts the limit
+            "posts_per_minute" => tunables.rate.posts_per_minute = value as u32,
+            "invitations_per_minute" => tunables.rate.invitations_per_minute = value as u32,
+            _ => unreachable!(),
+        }
+    }
+    if let Some(value) = auto_repair {
+        tunables.verifier.auto_repair = value;
+    }
+    if let Some(value) = stamp_author_names {
+        tunables.limits.stamp_author_names = value;
+    }
+    if let Some(value) = system_posts {
+        tunables.limits.system_posts = value;
+    }
+    if let Some(dir) = backup_dir {
+        tunables.snapshot.dir = PathBuf::from(dir);
+    }
+    // the ids are written as strings, most of them do not fit the integers of toml
+    if let Some(operators) = backup_operators {
+        let mut ids = Vec::new();
+        for operator in operators {
+            match operator.parse() {
+                Ok(id) => ids.push(id),
+                Err(e) => errors.push(format!("backup operator '{}', {}", operator, e)),
+            }
+        }
+        tunables.snapshot.operators = ids;
+    }
+
+    let proxy = &mut settings.proxy;
+    for peer in trusted_proxies.unwrap_or_default() {
+        match peer.parse() {
+            Ok(ip) => proxy.trusted_peers.push(ip),
+            Err(e) => errors.push(format!("trusted proxy '{}', {}", peer, e)),
+        }
+    }
+    if let Some(key) = forwarded_for_key {
+        proxy.forwarded_for_key = key.to_lowercase();
+    }
+    if let Some(key) = verified_user_key {
+        proxy.verified_user_key = key.to_lowercase();
+    }
+    if let Some(value) = require_identity {
+        proxy.require_identity = value;
+    }
+
+    if let Some(level) = log_level {
+        match level.parse() {
+            Ok(level) => settings.log_level = Some(level),
+            Err(_) => errors.push(format!("unknown log_level '{}'", level)),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(settings)
+    } else {
+        Err(errors.join("; ").into())
+    }
+}
+
+pub fn diff(running: &ServerSettings, loaded: &ServerSettings) -> Changes {
+    let mut changes = Changes::default();
+    let (old, new) = (&running.tunables, &loaded.tunables);
+    for (changed, key) in &[
+        (running.endpoint != loaded.endpoint, "endpoint"),
+        (running.db_file != loaded.db_file, "dbfile"),
+        (running.storage != loaded.storage, "storage"),
+        (running.admin_token != loaded.admin_token, "admin_token"),
+        (running.tls != loaded.tls, "tls"),
+        // the verifier waits for it once after the start
+        (
+            old.verifier.startup_delay != new.verifier.startup_delay,
+            "verify_startup_delay_secs",
+        ),
+    ] {
+        if *changed {
+            changes.restart_required.push(*key);
+        }
+    }
+    for (changed, key) in &[
+        (
+            old.limits.max_description_len != new.limits.max_description_len,
+            "max_description_len",
+        ),
+        (
+            old.limits.max_chat_members != new.limits.max_chat_members,
+            "max_chat_members",
+        ),
+        (
+            old.limits.bulk_fetch_posts != new.limits.bulk_fetch_posts,
+            "bulk_fetch_posts",
+        ),
+        (
+            old.limits.stamp_author_names != new.limits.stamp_author_names,
+            "stamp_author_names",
+        ),
+        (
+            old.limits.system_posts != new.limits.system_posts,
+            "system_posts",
+        ),
+        (
+            old.limits.max_attachment_size != new.limits.max_attachment_size,
+            "max_attachment_size",
+        ),
+        (
+            old.limits.max_post_bytes != new.limits.max_post_bytes,
+            "max_post_bytes",
+        ),
+        (
+            old.limits.max_attachments != new.limits.max_attachments,
+            "max_attachments",
+        ),
+        (
+            old.verifier.interval != new.verifier.interval,
+            "verify_interval_secs",
+        ),
+        (old.verifier.slice != new.verifier.slice, "verify_slice"),
+        (
+            old.verifier.auto_repair != new.verifier.auto_repair,
+            "auto_repair",
+        ),
+        (
+            old.presence.idle_timeout != new.presence.idle_timeout,
+            "presence_timeout_secs",
+        ),
+        (
+            old.presence.check_interval != new.presence.check_interval,
+            "presence_check_secs",
+        ),
+        (
+            old.presence.session_timeout != new.presence.session_timeout,
+            "session_timeout_secs",
+        ),
+        (
+            old.snapshot.interval != new.snapshot.interval,
+            "backup_interval_secs",
+        ),
+        (old.snapshot.dir != new.snapshot.dir, "backup_dir"),
+        (old.snapshot.keep != new.snapshot.keep, "backup_keep"),
+        (
+            old.snapshot.operators != new.snapshot.operators,
+            "backup_operators",
+        ),
+        (
+            old.health.check_interval != new.health.check_interval,
+            "health_check_secs",
+        ),
+        (old.health.drain != new.health.drain, "shutdown_drain_secs"),
+        (
+            old.rate.posts_per_minute != new.rate.posts_per_minute,
+            "posts_per_minute",
+        ),
+        (
+            old.rate.invitations_per_minute != new.rate.invitations_per_minute,
+            "invitations_per_minute",
+        ),
+        (
+            running.proxy.trusted_peers != loaded.proxy.trusted_peers,
+            "trusted_proxies",
+        ),
+        (
+            running.proxy.forwarded_for_key != loaded.proxy.forwarded_for_key,
+            "proxy_forwarded_for_key",
+        ),
+        (
+            running.proxy.verified_user_key != loaded.proxy.verified_user_key,
+            "proxy_verified_user_key",
+        ),
+        (
+            running.proxy.require_identity != loaded.proxy.require_identity,
+            "require_proxy_identity",
+        ),
+        (running.log_level != loaded.log_level, "log_level"),
+    ] {
+        if *changed {
+            changes.applied.push(*key);
+        }
+    }
+    changes
+}
+
+// the settings to run with after the reload, those requiring restart keep their running values
+pub fn merge(running: &ServerSettings, loaded: ServerSettings) -> ServerSettings {
+    let mut tunables = loaded.tunables;
+    tunables.verifier.startup_delay = running.tunables.verifier.startup_delay;
+    ServerSettings {
+        endpoint: running.endpoint.clone(),
+        db_file: running.db_file.clone(),
+        storage: running.storage.clone(),
+        admin_token: running.admin_token.clone(),
+        tls: running.tls.clone(),
+        tunables,
+        proxy: loaded.proxy,
+        log_level: loaded.log_level,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // every test has its own file, they run in parallel
+    fn load_str(name: &str, content: &str) -> Result<ServerSettings, InternalError> {
+        let file = format!("migchat-test-{}.toml", name);
+        fs::write(&file, content).unwrap();
+        let result = load(&file);
+        let _ = fs::remove_file(&file);
+        result
+    }
+
+    #[test]
+    fn settings_parsed() {
+        let settings = load_str(
+            "settings-parsed",
+            r#"
+            storage = "sqlite"
+            admin_token = "s3cret"
+            tls_cert = "server.pem"
+            tls_key = "server.key"
+            max_chat_members = 8
+            stamp_author_names = false
+            system_posts = false
+            max_attachment_size = 1024
+            max_post_bytes = 4096
+            verify_interval_secs = 60
+            presence_timeout_secs = 0
+            session_timeout_secs = 600
+            backup_interval_secs = 3600
+            backup_dir = "/var/backups/migchat"
+            backup_operators = ["12345678901234567890"]
+            shutdown_drain_secs = 5
+            posts_per_minute = 0
+            trusted_proxies = ["10.0.0.1"]
+            proxy_verified_user_key = "X-User"
+            log_level = "info"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.endpoint, DEF_ENDPOINT);
+        assert_eq!(settings.storage, "sqlite");
+        assert_eq!(settings.admin_token.as_deref(), Some("s3cret"));
+        assert_eq!(
+            settings.tls,
+            Some(TlsFiles {
+                cert: PathBuf::from("server.pem"),
+                key: PathBuf::from("server.key"),
+                client_ca: None,
+            })
+        );
+        assert_eq!(settings.tunables.limits.max_chat_members, 8);
+        assert!(!settings.tunables.limits.stamp_author_names);
+        assert!(!settings.tunables.limits.system_posts);
+        assert_eq!(settings.tunables.limits.max_attachment_size, 1024);
+        assert_eq!(settings.tunables.limits.max_post_bytes, 4096);
+        assert_eq!(settings.tunables.verifier.interval, Duration::from_secs(60));
+        assert_eq!(
+            settings.tunables.presence.idle_timeout,
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            settings.tunables.presence.session_timeout,
+            Duration::from_secs(600)
+        );
+        let snapshot = &settings.tunables.snapshot;
+        assert_eq!(snapshot.interval, Some(Duration::from_secs(3600)));
+        assert_eq!(snapshot.dir, PathBuf::from("/var/backups/migchat"));
+        assert_eq!(snapshot.operators, vec![12345678901234567890]);
+        assert_eq!(settings.tunables.health.drain, Duration::from_secs(5));
+        assert_eq!(settings.tunables.rate.posts_per_minute, 0);
+        assert_eq!(settings.proxy.trusted_peers.len(), 1);
+        assert_eq!(settings.proxy.verified_user_key, "x-user");
+        assert_eq!(settings.log_level, Some(LevelFilter::Info));
+    }
+
+    #[test]
+    fn invalid_settings_reported() {
+        let e = load_str(
+            "settings-invalid",
+            r#"
+            storage = "redis"
+            max_chat_members = -1
+            verify_slice = "many"
+            trusted_proxies = ["proxy.local"]
+            backup_operators = ["admin"]
+            tls_cert = "server.pem"
+            log_level = "loud"
+            "#,
+        )
+        .unwrap_err()
+        .to_string();
+        for key in &[
+            "storage",
+            "max_chat_members",
+            "verify_slice",
+            "proxy.local",
+            "admin",
+            "tls_cert",
+            "log_level",
+        ] {
+            assert!(e.contains(key), "{} is not reported in: {}", key, e);
+        }
+        assert!(load_str("settings-broken", "max_chat_members = [").is_err());
+    }
+
+    #[test]
+    fn changes_found() {
+        let running = load_str("settings-running", "max_chat_members = 8").unwrap();
+        let loaded = load_str(
+            "settings-loaded",
+            r#"
+            endpoint = "127.0.0.1:50052"
+            max_chat_members = 16
+            verify_startup_delay_secs = 1
+            backup_keep = 3
+            require_proxy_identity = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            diff(&running, &loaded),
+            Changes {
+                applied: vec!["max_chat_members", "backup_keep", "require_proxy_identity"],
+                restart_required: vec!["endpoint", "verify_startup_delay_secs"],
+            }
+        );
+        // those requiring restart are left as they are
+        let merged = merge(&running, loaded);
+        assert_eq!(merged.endpoint, running.endpoint);
+        assert_eq!(
+            merged.tunables.verifier.startup_delay,
+            running.tunables.verifier.startup_delay
+        );
+        assert_eq!(merged.tunables.limits.max_chat_members, 16);
+        assert!(merged.proxy.require_identity);
+        assert_eq!(diff(&merged, &merged), Changes::default());
+    }
+}
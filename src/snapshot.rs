@@ -0,0 +1,188 @@
+use super::{ChatRoomImpl, InternalError, UserId};
+use crate::storage::ChatStorage;
+use chrono::Local;
+use log::{error, info, warn};
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+const DEF_DIR: &str = "backups";
+const DEF_KEEP: usize = 7;
+// the disabled snapshots are looked for being enabled by reload that often
+const DISABLED_CHECK_SECS: u64 = 60;
+// the snapshots are named by the time they are taken, the names sort as the times
+const NAME_PREFIX: &str = "migchat-";
+const NAME_SUFFIX: &str = ".db";
+const NAME_TIME_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+// periodic copies of the storage, the operators may take one on demand as well
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotConfig {
+    // no periodic snapshots if not set
+    pub interval: Option<Duration>,
+    pub dir: PathBuf,
+    // the older snapshots beyond the count are removed
+    pub keep: usize,
+    // ids of the users allowed to take a snapshot on demand, never their names
+    // which the users choose themselves
+    pub operators: Vec<UserId>,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig {
+            interval: None,
+            dir: PathBuf::from(DEF_DIR),
+            keep: DEF_KEEP,
+            operators: Vec::new(),
+        }
+    }
+}
+
+fn is_snapshot(name: &str) -> bool {
+    name.starts_with(NAME_PREFIX) && name.ends_with(NAME_SUFFIX)
+}
+
+// removes the oldest snapshots of the directory leaving the newest ones
+fn prune(dir: &Path, keep: usize) -> Result<usize, InternalError> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if name.to_str().map(is_snapshot).unwrap_or(false) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    let excess = names.len().saturating_sub(keep);
+    for name in &names[..excess] {
+        fs::remove_file(dir.join(name))?;
+    }
+    Ok(excess)
+}
+
+// takes the snapshot into the directory and prunes the older ones, blocks the thread;
+// the snapshot appears complete or not at all
+pub fn take<S: ChatStorage>(
+    chat_room: &ChatRoomImpl<S>,
+    config: &SnapshotConfig,
+) -> Result<PathBuf, InternalError> {
+    // the periodic one and those on demand go one at a time
+    let _taking = chat_room
+        .snapshotting
+        .lock()
+        .map_err(|_| "snapshot lock is poisoned")?;
+    fs::create_dir_all(&config.dir)?;
+    let name = format!(
+        "{}{}{}",
+        NAME_PREFIX,
+        Local::now().format(NAME_TIME_FORMAT),
+        NAME_SUFFIX
+    );
+    let path = config.dir.join(name);
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let _ = fs::remove_file(&tmp);
+    let taken = chat_room
+        .storage
+        .backup(&tmp)
+        .and_then(|()| fs::rename(&tmp, &path).map_err(|e| e.into()));
+    if let Err(e) = taken {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    info!("storage snapshot is taken to {}", path.display());
+    match prune(&config.dir, config.keep.max(1)) {
+        Ok(0) => {}
+        Ok(removed) => info!("{} old snapshot(s) removed", removed),
+        Err(e) => warn!("old snapshots are not removed, {}", e),
+    }
+    Ok(path)
+}
+
+// the config is read anew every period, the failed snapshot waits for the next one
+pub async fn run<S: ChatStorage>(chat_room: Arc<ChatRoomImpl<S>>) {
+    let config = chat_room.tunables().snapshot;
+    info!("use snapshot config: {:?}", config);
+    loop {
+        let interval = match chat_room.tunables().snapshot.interval {
+            Some(interval) => interval,
+            None => {
+                tokio::time::sleep(Duration::from_secs(DISABLED_CHECK_SECS)).await;
+                continue;
+            }
+        };
+        tokio::time::sleep(interval).await;
+        let config = chat_room.tunables().snapshot;
+        if config.interval.is_none() {
+            continue;
+        }
+        let chat_room = chat_room.clone();
+        match tokio::task::spawn_blocking(move || take(&chat_room, &config)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!("storage snapshot failed, {}", e),
+            Err(e) => error!("storage snapshot task failed, {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Fixture;
+    use crate::storage::{memory::InMemoryStorage, Storage};
+    use crate::Limits;
+
+    const TEST_DIR: &str = "migchat-test-snapshots";
+
+    #[test]
+    fn snapshots_pruned() {
+        let _ = fs::remove_dir_all(TEST_DIR);
+        let chat_room = ChatRoomImpl::with_storage(InMemoryStorage::new(), Limits::default());
+        let seeded = Fixture::new(1562)
+            .users(4)
+            .chats(2, |c| c.members(2..4).posts(5..10))
+            .seed(&chat_room.storage)
+            .unwrap();
+        let config = SnapshotConfig {
+            dir: PathBuf::from(TEST_DIR),
+            keep: 2,
+            ..SnapshotConfig::default()
+        };
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let dir = Path::new(TEST_DIR);
+        for name in &[
+            "migchat-20200101-000000.db",
+            "migchat-20210101-000000.db",
+            "notes.txt",
+        ] {
+            fs::write(dir.join(name), b"old").unwrap();
+        }
+        let path = take(&chat_room, &config).unwrap();
+        {
+            let snapshot = Storage::new(&path).unwrap();
+            assert_eq!(snapshot.read_all_users().unwrap().len(), seeded.users.len());
+            for chat_id in &seeded.chats {
+                assert_eq!(
+                    snapshot.chat_posts_count(*chat_id).unwrap(),
+                    chat_room.storage.chat_posts_count(*chat_id).unwrap()
+                );
+            }
+        }
+        // the newest ones are left, the other files are not touched
+        let mut names: Vec<String> = fs::read_dir(TEST_DIR)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], "migchat-20210101-000000.db");
+        assert_eq!(Path::new(TEST_DIR).join(&names[1]), path);
+        assert_eq!(names[2], "notes.txt");
+        let _ = fs::remove_dir_all(TEST_DIR);
+    }
+}
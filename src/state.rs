@@ -0,0 +1,166 @@
+use crate::proto::{ChatId, PostId, UserId, NOT_USER_ID};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+const STATE_DIR: &str = "migchat";
+const STATE_FILE: &str = "state.json";
+
+// what the client remembers between the runs, kept as a json object
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientState {
+    // the server the rest is valid for
+    pub server: String,
+    pub user_id: UserId,
+    // the secret given with the id, presented along with it
+    pub secret: String,
+    // the time of the first registration as told by the server
+    pub created: u64,
+    // the names the server has accepted
+    pub name: String,
+    pub short_name: String,
+    // the id and the creation time of the newest post got in every chat
+    pub cursors: BTreeMap<ChatId, (PostId, u64)>,
+}
+
+impl ClientState {
+    // the state of another server or login is not presented
+    pub fn is_for(&self, server: &str, short_name: &str) -> bool {
+        self.user_id != NOT_USER_ID && self.server == server && self.short_name == short_name
+    }
+
+    // the posts stream resumes after the newest post of all the chats
+    pub fn resume_after(&self) -> Option<(PostId, u64)> {
+        self.cursors
+            .values()
+            .max_by_key(|(_, created)| *created)
+            .copied()
+    }
+
+    // the cursor of the chat never goes back
+    pub fn advance(&mut self, chat_id: ChatId, post_id: PostId, created: u64) {
+        let cursor = self.cursors.entry(chat_id).or_insert((post_id, created));
+        if cursor.1 <= created {
+            *cursor = (post_id, created);
+        }
+    }
+}
+
+// the data dir of XDG, ~/.local/share if not set
+pub fn default_path() -> Option<PathBuf> {
+    let data_dir = match env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".local/share"),
+    };
+    Some(data_dir.join(STATE_DIR).join(STATE_FILE))
+}
+
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    pub fn new(path: &Path) -> Self {
+        StateFile {
+            path: path.to_path_buf(),
+        }
+    }
+
+    // the missing or corrupt file is a fresh start, it is overwritten by the next save
+    pub fn load(&self) -> ClientState {
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return ClientState::default(),
+            Err(e) => {
+                warn!("failed reading state {}, {}", self.path.display(), e);
+                return ClientState::default();
+            }
+        };
+        serde_json::from_slice(&content).unwrap_or_else(|e| {
+            warn!("state {} is corrupt, {}", self.path.display(), e);
+            ClientState::default()
+        })
+    }
+
+    // replaces the file entirely, its dir is created if missing
+    pub fn save(&self, state: &ClientState) -> io::Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(state)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_FILE: &str = "migchat-test-state.json";
+    const TEST_DIR: &str = "migchat-test-state-dir";
+
+    fn state() -> ClientState {
+        let mut state = ClientState {
+            server: String::from("http://a"),
+            user_id: 7,
+            secret: String::from("secret"),
+            created: 1_600_000_000,
+            name: String::from("User Name"),
+            short_name: String::from("user"),
+            ..Default::default()
+        };
+        state.advance(10, 1, 100);
+        state.advance(20, 2, 300);
+        state.advance(10, 3, 200);
+        // the older post does not move the cursor back
+        state.advance(20, 4, 250);
+        state
+    }
+
+    #[test]
+    fn state_saved_and_loaded() {
+        let state = state();
+        assert_eq!(state.cursors.get(&10), Some(&(3, 200)));
+        assert_eq!(state.resume_after(), Some((2, 300)));
+        assert!(state.is_for("http://a", "user"));
+        assert!(!state.is_for("http://b", "user"));
+        assert!(!state.is_for("http://a", "other"));
+        assert!(!ClientState::default().is_for("", ""));
+        assert_eq!(ClientState::default().resume_after(), None);
+
+        let _ = fs::remove_dir_all(TEST_DIR);
+        let file = StateFile::new(&Path::new(TEST_DIR).join(STATE_FILE));
+        assert_eq!(file.load(), ClientState::default());
+        file.save(&state).unwrap();
+        assert_eq!(file.load(), state);
+        // the fields missing in the older file are defaults
+        fs::write(
+            Path::new(TEST_DIR).join(STATE_FILE),
+            r#"{"server":"http://a","user_id":7,"cursors":{"10":[3,200]}}"#,
+        )
+        .unwrap();
+        let loaded = file.load();
+        assert_eq!((loaded.user_id, loaded.created), (7, 0));
+        assert_eq!(loaded.resume_after(), Some((3, 200)));
+        let _ = fs::remove_dir_all(TEST_DIR);
+    }
+
+    #[test]
+    fn corrupt_state_ignored() {
+        let file = StateFile::new(Path::new(TEST_FILE));
+        for content in &["", "{\"user_id\":", "[1, 2]", "{\"user_id\":\"seven\"}"] {
+            fs::write(TEST_FILE, content).unwrap();
+            assert_eq!(file.load(), ClientState::default());
+        }
+        // the next save replaces the corrupt file
+        file.save(&state()).unwrap();
+        assert_eq!(file.load(), state());
+        let _ = fs::remove_file(TEST_FILE);
+    }
+}
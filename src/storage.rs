@@ -1,117 +1,1030 @@
-use super::{Chat, ChatId, InternalError, Post, User, UserId};
+use super::{Chat, ChatId, Invitation, Post, PostId, ServerError, User, UserId};
+use crate::proto::{AttachmentId, AttachmentInfo, NOT_USER_ID};
 use bytes::BytesMut;
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use prost::Message;
-use std::path::Path;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+// the storage for the tests, nothing is kept on disk
+#[cfg(test)]
+pub mod memory;
+// the storage in an SQLite database, selected by the server configuration
+pub mod sqlite;
 
 const BUCKET_USERS: &str = "users";
 const BUCKET_CHATS: &str = "chats";
 const BUCKET_POSTS: &str = "posts";
+const BUCKET_META: &str = "meta";
+// invitations waiting for the answer
+const BUCKET_INVITATIONS: &str = "invitations";
+// declined invitations not delivered to the inviters yet
+const BUCKET_REPLIES: &str = "replies";
+// post id -> the chat and the sequence number of the post
+const BUCKET_POST_INDEX: &str = "post_index";
+// chat id -> the count of the chat's post records
+const BUCKET_POST_COUNTS: &str = "post_counts";
+// user id -> the ids of the chats the user is a member of
+const BUCKET_USER_CHATS: &str = "user_chats";
+// (chat id, user id) -> the sequence number and the id of the last post read
+const BUCKET_READ_MARKS: &str = "read_marks";
+// attachment id -> its info, (attachment id, chunk index) -> the chunk of its content
+const BUCKET_ATTACHMENTS: &str = "attachments";
+// short name -> the id of the user registered with it
+const BUCKET_USER_NAMES: &str = "user_names";
+// (chat id, user id) -> nothing, the chat is muted by the user
+const BUCKET_MUTES: &str = "mutes";
+// (chat id, user id) -> nothing, the user has been removed from the chat by its owner
+const BUCKET_BANS: &str = "bans";
+// the content of an attachment is split into values of that many bytes at most
+const ATTACHMENT_CHUNK_LEN: usize = 64 * 1024;
+// version of the schema the storage has been migrated to, kept in the meta bucket
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+const ENCODE_BUF_CAPACITY: usize = 4096;
+// undecodable posts in a row meaning the chat's bucket is damaged
+const DAMAGE_THRESHOLD: usize = 8;
+// the posts are searched through by pages of that many
+const SEARCH_PAGE_LEN: usize = 256;
+// the flat buckets copied by the snapshot as they are, the posts are copied by the chats
+const SNAPSHOT_BUCKETS: &[&str] = &[
+    BUCKET_USERS,
+    BUCKET_CHATS,
+    BUCKET_META,
+    BUCKET_INVITATIONS,
+    BUCKET_REPLIES,
+    BUCKET_POST_INDEX,
+    BUCKET_POST_COUNTS,
+    BUCKET_USER_CHATS,
+    BUCKET_READ_MARKS,
+    BUCKET_ATTACHMENTS,
+    BUCKET_USER_NAMES,
+    BUCKET_MUTES,
+    BUCKET_BANS,
+];
 
-pub struct Storage {
-    db: jammdb::DB,
+thread_local! {
+    // reusable serialization buffer, see encode()
+    static ENCODE_BUF: RefCell<BytesMut> = RefCell::new(BytesMut::with_capacity(ENCODE_BUF_CAPACITY));
 }
 
-impl Storage {
-    pub fn new<P: AsRef<Path>>(db_file: P) -> Result<Self, InternalError> {
-        let db = jammdb::DB::open(db_file)?;
-        // create users bucket in DB if not exists
-        let tx = db.tx(true)?;
-        match tx.create_bucket(BUCKET_USERS) {
+// serializes the item into the thread's buffer and splits the result off,
+// the memory is reclaimed by the next call as soon as DB releases the returned bytes
+fn encode<M: Message>(item: &M) -> Result<BytesMut, prost::EncodeError> {
+    ENCODE_BUF.with(|buf| {
+        let mut buf = buf.borrow_mut();
+        buf.reserve(item.encoded_len());
+        item.encode(&mut *buf).map(|_| buf.split())
+    })
+}
+
+fn degraded_key(chat_id: ChatId) -> String {
+    format!("degraded/{}", chat_id)
+}
+
+fn secret_key(user_id: UserId) -> String {
+    format!("secret/{}", user_id)
+}
+
+// posts are keyed by the big-endian sequence numbers for jammdb to iterate them in order
+fn post_key(seq: u64) -> [u8; 8] {
+    seq.to_be_bytes()
+}
+
+// the post as read, the sequence number is its key and not kept in the record
+fn sequenced(key: &[u8], post: Post) -> Post {
+    Post {
+        seq: key_bytes(key).map_or(0, u64::from_be_bytes),
+        ..post
+    }
+}
+
+fn attachment_key(id: AttachmentId) -> [u8; 8] {
+    id.to_le_bytes()
+}
+
+// the chunks of an attachment follow its info in the order of their big-endian indexes
+fn attachment_chunk_key(id: AttachmentId, idx: u32) -> [u8; 12] {
+    let mut key = [0u8; 12];
+    key[..8].copy_from_slice(&id.to_le_bytes());
+    key[8..].copy_from_slice(&idx.to_be_bytes());
+    key
+}
+
+fn invitation_key(invitation: &Invitation) -> Vec<u8> {
+    let mut key = Vec::with_capacity(24);
+    key.extend_from_slice(&invitation.chat_id.to_le_bytes());
+    key.extend_from_slice(&invitation.from_user_id.to_le_bytes());
+    key.extend_from_slice(&invitation.to_user_id.to_le_bytes());
+    key
+}
+
+// an upgrade of the schema from the previous version, applied within the transaction
+// of the whole migration; steps have to tolerate the data already upgraded
+struct Migration {
+    name: &'static str,
+    apply: fn(&jammdb::Tx) -> Result<(), ServerError>,
+}
+
+// the schema version is the count of the steps applied, append new steps only
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "users, chats, posts and meta buckets",
+        apply: create_base_buckets,
+    },
+    Migration {
+        name: "invitations and replies buckets",
+        apply: create_invitation_buckets,
+    },
+    Migration {
+        name: "big-endian post keys",
+        apply: rekey_posts_big_endian,
+    },
+    Migration {
+        name: "post id index",
+        apply: create_post_index,
+    },
+    Migration {
+        name: "user chats index",
+        apply: create_user_chats_index,
+    },
+    Migration {
+        name: "read marks bucket",
+        apply: create_read_marks_bucket,
+    },
+    Migration {
+        name: "attachments bucket",
+        apply: create_attachments_bucket,
+    },
+    Migration {
+        name: "user short names index",
+        apply: create_user_names_index,
+    },
+    Migration {
+        name: "mutes bucket",
+        apply: create_mutes_bucket,
+    },
+    Migration {
+        name: "bans bucket",
+        apply: create_bans_bucket,
+    },
+];
+
+fn create_buckets(tx: &jammdb::Tx, bucket_names: &[&str]) -> Result<(), ServerError> {
+    for bucket_name in bucket_names {
+        match tx.create_bucket(*bucket_name) {
             Ok(_) => {}
             Err(jammdb::Error::BucketExists) => {}
-            Err(e) => return Err(format!("{}", e).into()),
+            Err(e) => return Err(e.into()),
         }
-        tx.commit()?;
-        // create chats bucket in DB if not exists
-        let tx = db.tx(true)?;
-        match tx.create_bucket(BUCKET_CHATS) {
-            Ok(_) => {}
-            Err(jammdb::Error::BucketExists) => {}
-            Err(e) => return Err(format!("{}", e).into()),
+    }
+    Ok(())
+}
+
+fn create_base_buckets(tx: &jammdb::Tx) -> Result<(), ServerError> {
+    create_buckets(tx, &[BUCKET_USERS, BUCKET_CHATS, BUCKET_POSTS, BUCKET_META])
+}
+
+fn create_invitation_buckets(tx: &jammdb::Tx) -> Result<(), ServerError> {
+    create_buckets(tx, &[BUCKET_INVITATIONS, BUCKET_REPLIES])
+}
+
+fn create_read_marks_bucket(tx: &jammdb::Tx) -> Result<(), ServerError> {
+    create_buckets(tx, &[BUCKET_READ_MARKS])
+}
+
+fn create_attachments_bucket(tx: &jammdb::Tx) -> Result<(), ServerError> {
+    create_buckets(tx, &[BUCKET_ATTACHMENTS])
+}
+
+fn create_mutes_bucket(tx: &jammdb::Tx) -> Result<(), ServerError> {
+    create_buckets(tx, &[BUCKET_MUTES])
+}
+
+fn create_bans_bucket(tx: &jammdb::Tx) -> Result<(), ServerError> {
+    create_buckets(tx, &[BUCKET_BANS])
+}
+
+// the posts were keyed by little-endian sequence numbers, which jammdb orders bytewise,
+// so the history of a chat went out of order after 256 posts; the posts are looked up
+// by the chats still existing as those of the removed chats are gone with them
+fn rekey_posts_big_endian(tx: &jammdb::Tx) -> Result<(), ServerError> {
+    let chat_keys: Vec<Vec<u8>> = tx
+        .get_bucket(BUCKET_CHATS)?
+        .kv_pairs()
+        .map(|pair| pair.key().to_vec())
+        .collect();
+    let posts_bucket = tx.get_bucket(BUCKET_POSTS)?;
+    for chat_key in &chat_keys {
+        let chat_bucket = match posts_bucket.get_bucket(chat_key) {
+            Ok(chat_bucket) => chat_bucket,
+            // no posts or damaged, the latter is up to the verifier
+            Err(_) => continue,
+        };
+        let posts: Vec<([u8; 8], BytesMut)> = chat_bucket
+            .kv_pairs()
+            .filter_map(|pair| {
+                let mut key = [0u8; 8];
+                if pair.key().len() != key.len() {
+                    return None;
+                }
+                key.copy_from_slice(pair.key());
+                Some((key, BytesMut::from(pair.value())))
+            })
+            .collect();
+        if !posts_keyed_le(posts.iter().map(|(key, _)| key)) {
+            continue;
         }
-        tx.commit()?;
-        // create posts bucket in DB if not exists
-        let tx = db.tx(true)?;
-        match tx.create_bucket(BUCKET_POSTS) {
-            Ok(_) => {}
-            Err(jammdb::Error::BucketExists) => {}
-            Err(e) => return Err(format!("{}", e).into()),
+        for (key, _) in &posts {
+            chat_bucket.delete(key)?;
+        }
+        for (key, value) in posts {
+            chat_bucket.put(&post_key(u64::from_le_bytes(key)), value)?;
         }
-        tx.commit()?;
-        Ok(Self { db })
     }
+    Ok(())
+}
 
-    // operations with users
+// the sequence numbers are far below 2^32, so the wrong byte order makes them huge;
+// the keys reading the same either way need no rekeying
+fn posts_keyed_le<'a, I: Iterator<Item = &'a [u8; 8]>>(keys: I) -> bool {
+    let (le_max, be_max) = keys.fold((0, 0), |(le_max, be_max), key| {
+        (
+            u64::max(le_max, u64::from_le_bytes(*key)),
+            u64::max(be_max, u64::from_be_bytes(*key)),
+        )
+    });
+    le_max < be_max
+}
 
-    pub fn read_user(&self, id: UserId) -> Result<Option<User>, InternalError> {
-        self.read_from_db::<User>(BUCKET_USERS, &id.to_le_bytes())
+fn key_bytes(key: &[u8]) -> Option<[u8; 8]> {
+    let mut bytes = [0u8; 8];
+    if key.len() != bytes.len() {
+        return None;
     }
+    bytes.copy_from_slice(key);
+    Some(bytes)
+}
 
-    pub fn write_user(&self, id: UserId, user: &User) -> Result<(), InternalError> {
-        self.write_to_db::<User>(BUCKET_USERS, &id.to_le_bytes(), user)
+fn post_location(chat_id: ChatId, seq: u64) -> BytesMut {
+    let mut location = BytesMut::with_capacity(16);
+    location.extend_from_slice(&chat_id.to_le_bytes());
+    location.extend_from_slice(&seq.to_le_bytes());
+    location
+}
+
+fn parse_post_location(value: &[u8]) -> Option<(ChatId, u64)> {
+    if value.len() != 16 {
+        return None;
     }
+    let chat_id = u64::from_le_bytes(key_bytes(&value[..8])?);
+    let seq = u64::from_le_bytes(key_bytes(&value[8..])?);
+    Some((chat_id, seq))
+}
 
-    /// Tries to conditionally update specified user.
-    /// Returns:
-    /// - InternalError if some error happens
-    /// - Ok(Some(user)) if user was found and successfully updated; user contains *new* value
-    /// - Ok(Some(user)) if user was found but updater returned false; user contains *unchanged* value, and database remains unchanged
-    /// - Ok(None) if user was not found
-    pub fn _update_user<F: FnMut(&mut User) -> bool>(
-        &self,
-        id: UserId,
-        updater: F,
-    ) -> Result<Option<User>, InternalError> {
-        self.update_in_db::<User, _>(BUCKET_USERS, &id.to_le_bytes(), updater)
+// the marks of a chat go together, so do the mutes and the bans
+fn chat_user_key(chat_id: ChatId, user_id: UserId) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key[..8].copy_from_slice(&chat_id.to_le_bytes());
+    key[8..].copy_from_slice(&user_id.to_le_bytes());
+    key
+}
+
+fn read_mark_value(post_id: PostId, seq: u64) -> BytesMut {
+    let mut value = BytesMut::with_capacity(16);
+    value.extend_from_slice(&seq.to_le_bytes());
+    value.extend_from_slice(&post_id.to_le_bytes());
+    value
+}
+
+fn parse_read_mark(value: &[u8]) -> Option<(PostId, u64)> {
+    if value.len() != 16 {
+        return None;
     }
+    let seq = u64::from_le_bytes(key_bytes(&value[..8])?);
+    let post_id = u64::from_le_bytes(key_bytes(&value[8..])?);
+    Some((post_id, seq))
+}
 
-    pub fn read_all_users(&self) -> Result<Vec<User>, InternalError> {
-        self.read_all_from_db::<User>(BUCKET_USERS)
+// the read marks, the mutes or the bans of the chat
+fn remove_chat_user_keys(
+    tx: &jammdb::Tx,
+    bucket_name: &str,
+    chat_id: ChatId,
+) -> Result<(), ServerError> {
+    let bucket = tx.get_bucket(bucket_name)?;
+    let prefix = chat_id.to_le_bytes();
+    let keys: Vec<Vec<u8>> = bucket
+        .kv_pairs()
+        .filter(|pair| pair.key().starts_with(&prefix))
+        .map(|pair| pair.key().to_vec())
+        .collect();
+    for key in keys {
+        bucket.delete(&key)?;
     }
+    Ok(())
+}
 
-    #[allow(dead_code)]
-    pub fn remove_user(&self, id: UserId) -> Result<(), InternalError> {
-        // remove the user out of all chats
-        self.update_all_in_db::<Chat, _>(BUCKET_CHATS, |mut_ref_chat| {
-            let cnt_before = mut_ref_chat.users.len();
-            mut_ref_chat.users.retain(|&u| u != id);
-            mut_ref_chat.users.len() < cnt_before
+fn locate_post_in(tx: &jammdb::Tx, post_id: PostId) -> Result<Option<(ChatId, u64)>, ServerError> {
+    let index = tx.get_bucket(BUCKET_POST_INDEX)?;
+    Ok(index
+        .get_kv(&post_id.to_le_bytes())
+        .and_then(|kv| parse_post_location(kv.value())))
+}
+
+// the chat's decodable posts by their ids with their sequence numbers
+fn chat_post_locations(
+    tx: &jammdb::Tx,
+    chat_id: ChatId,
+) -> Result<HashMap<PostId, u64>, ServerError> {
+    let posts_bucket = tx.get_bucket(BUCKET_POSTS)?;
+    let chat_bucket = match posts_bucket.get_bucket(&chat_id.to_le_bytes()) {
+        Ok(chat_bucket) => chat_bucket,
+        Err(jammdb::Error::BucketMissing) => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let locations = chat_bucket
+        .kv_pairs()
+        .filter_map(|pair| {
+            let seq = u64::from_be_bytes(key_bytes(pair.key())?);
+            let post = Post::decode(pair.value()).ok()?;
+            if post.chat_id == chat_id {
+                Some((post.id, seq))
+            } else {
+                None
+            }
         })
-        .and(self.remove_from_db::<User>(BUCKET_USERS, &id.to_le_bytes()))
+        .collect();
+    Ok(locations)
+}
+
+// compares the index with the chat's posts; the entries pointing at the posts gone
+// and the posts not pointed at properly are counted and fixed if `repair`
+fn sync_post_index(tx: &jammdb::Tx, chat_id: ChatId, repair: bool) -> Result<usize, ServerError> {
+    let mut expected = chat_post_locations(tx, chat_id)?;
+    let index = tx.get_bucket(BUCKET_POST_INDEX)?;
+    let stale: Vec<Vec<u8>> = index
+        .kv_pairs()
+        .filter(|pair| match parse_post_location(pair.value()) {
+            Some((located_chat_id, _)) if located_chat_id == chat_id => key_bytes(pair.key())
+                .map_or(true, |key| !expected.contains_key(&u64::from_le_bytes(key))),
+            _ => false,
+        })
+        .map(|pair| pair.key().to_vec())
+        .collect();
+    expected.retain(|post_id, seq| {
+        index
+            .get_kv(&post_id.to_le_bytes())
+            .and_then(|kv| parse_post_location(kv.value()))
+            != Some((chat_id, *seq))
+    });
+    if repair {
+        for key in &stale {
+            index.delete(key)?;
+        }
+        for (post_id, seq) in &expected {
+            index.put(&post_id.to_le_bytes(), post_location(chat_id, *seq))?;
+        }
     }
+    Ok(stale.len() + expected.len())
+}
 
-    // operations with chats
+// Ok(None) for the chats not counted yet, i.e. not posted to since the counters appeared
+fn read_post_count(tx: &jammdb::Tx, chat_id: ChatId) -> Result<Option<usize>, ServerError> {
+    let counts = match tx.get_bucket(BUCKET_POST_COUNTS) {
+        Ok(counts) => counts,
+        Err(jammdb::Error::BucketMissing) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let count = counts
+        .get_kv(&chat_id.to_le_bytes())
+        .and_then(|kv| key_bytes(kv.value()))
+        .map(|count| u64::from_le_bytes(count) as usize);
+    Ok(count)
+}
 
-    pub fn read_chat(&self, id: ChatId) -> Result<Option<Chat>, InternalError> {
-        self.read_from_db::<Chat>(BUCKET_CHATS, &id.to_le_bytes())
+fn count_chat_records(tx: &jammdb::Tx, chat_id: ChatId) -> Result<usize, ServerError> {
+    match tx
+        .get_bucket(BUCKET_POSTS)?
+        .get_bucket(&chat_id.to_le_bytes())
+    {
+        Ok(chat_bucket) => Ok(chat_bucket.kv_pairs().count()),
+        Err(jammdb::Error::BucketMissing) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_post_count(tx: &jammdb::Tx, chat_id: ChatId, count: usize) -> Result<(), ServerError> {
+    let counts = tx.get_or_create_bucket(BUCKET_POST_COUNTS)?;
+    let value = (count as u64).to_le_bytes();
+    counts.put(&chat_id.to_le_bytes(), BytesMut::from(&value[..]))?;
+    Ok(())
+}
+
+// follows the change of the chat's records already made in the transaction,
+// the chat not counted yet is counted in full
+fn adjust_post_count(tx: &jammdb::Tx, chat_id: ChatId, delta: isize) -> Result<(), ServerError> {
+    let count = match read_post_count(tx, chat_id)? {
+        Some(count) => (count as isize + delta).max(0) as usize,
+        None => count_chat_records(tx, chat_id)?,
+    };
+    write_post_count(tx, chat_id, count)
+}
+
+fn remove_post_count(tx: &jammdb::Tx, chat_id: ChatId) -> Result<(), ServerError> {
+    match tx.get_bucket(BUCKET_POST_COUNTS) {
+        Ok(counts) => match counts.delete(&chat_id.to_le_bytes()) {
+            Ok(_) | Err(jammdb::Error::KeyValueMissing) => Ok(()),
+            Err(e) => Err(e.into()),
+        },
+        Err(jammdb::Error::BucketMissing) => Ok(()),
+        Err(e) => Err(e.into()),
     }
+}
 
-    pub fn write_chat(&self, id: ChatId, chat: &Chat) -> Result<(), InternalError> {
-        self.write_to_db::<Chat>(BUCKET_CHATS, &id.to_le_bytes(), chat)
+// indexes the posts of the existing chats from scratch, the damaged chats are skipped
+// to be reindexed by the verifier once salvaged; returns the count of indexed posts
+fn rebuild_post_index(tx: &jammdb::Tx) -> Result<usize, ServerError> {
+    match tx.delete_bucket(BUCKET_POST_INDEX) {
+        Ok(_) | Err(jammdb::Error::BucketMissing) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let index = tx.create_bucket(BUCKET_POST_INDEX)?;
+    let chat_ids: Vec<ChatId> = tx
+        .get_bucket(BUCKET_CHATS)?
+        .kv_pairs()
+        .filter_map(|pair| key_bytes(pair.key()).map(u64::from_le_bytes))
+        .collect();
+    let mut indexed = 0;
+    for chat_id in chat_ids {
+        let locations = match chat_post_locations(tx, chat_id) {
+            Ok(locations) => locations,
+            Err(e) => {
+                error!("posts of chat {} are not indexed, {}", chat_id, e);
+                continue;
+            }
+        };
+        for (post_id, seq) in locations {
+            index.put(&post_id.to_le_bytes(), post_location(chat_id, seq))?;
+            indexed += 1;
+        }
     }
+    Ok(indexed)
+}
 
-    /// Tries to conditionally update specified chat.
-    /// Returns:
-    /// - InternalError if some error happens
-    /// - Ok(Some(chat)) if chat was found and successfully updated; chat contains *new* value
-    /// - Ok(Some(chat)) if chat was found but updater returned false; chat contains *unchanged* value, and database remains unchanged
-    /// - Ok(None) if chat was not found
-    pub fn update_chat<F: FnMut(&mut Chat) -> bool>(
+fn create_post_index(tx: &jammdb::Tx) -> Result<(), ServerError> {
+    let indexed = rebuild_post_index(tx)?;
+    info!("{} posts are indexed", indexed);
+    Ok(())
+}
+
+// the ids are kept sorted
+fn chat_ids_value(chat_ids: &[ChatId]) -> BytesMut {
+    let mut value = BytesMut::with_capacity(chat_ids.len() * 8);
+    for chat_id in chat_ids {
+        value.extend_from_slice(&chat_id.to_le_bytes());
+    }
+    value
+}
+
+fn parse_chat_ids(value: &[u8]) -> Option<Vec<ChatId>> {
+    if value.len() % 8 != 0 {
+        return None;
+    }
+    value
+        .chunks(8)
+        .map(|chunk| key_bytes(chunk).map(u64::from_le_bytes))
+        .collect()
+}
+
+fn read_user_chats_in(tx: &jammdb::Tx, user_id: UserId) -> Result<Vec<ChatId>, ServerError> {
+    let index = tx.get_bucket(BUCKET_USER_CHATS)?;
+    match index.get_kv(&user_id.to_le_bytes()) {
+        Some(kv) => parse_chat_ids(kv.value())
+            .ok_or_else(|| format!("malformed chats index of user {}", user_id).into()),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn write_user_chats(
+    tx: &jammdb::Tx,
+    user_id: UserId,
+    chat_ids: &[ChatId],
+) -> Result<(), ServerError> {
+    let index = tx.get_bucket(BUCKET_USER_CHATS)?;
+    let key = user_id.to_le_bytes();
+    if chat_ids.is_empty() {
+        match index.delete(&key) {
+            Ok(_) | Err(jammdb::Error::KeyValueMissing) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    } else {
+        index.put(&key, chat_ids_value(chat_ids))?;
+        Ok(())
+    }
+}
+
+// follows the change of the chat's members made in the transaction
+fn sync_user_chats(
+    tx: &jammdb::Tx,
+    chat_id: ChatId,
+    before: &[UserId],
+    after: &[UserId],
+) -> Result<(), ServerError> {
+    for user_id in before.iter().filter(|u| !after.contains(u)) {
+        let mut chat_ids = read_user_chats_in(tx, *user_id).unwrap_or_default();
+        chat_ids.retain(|id| *id != chat_id);
+        write_user_chats(tx, *user_id, &chat_ids)?;
+    }
+    for user_id in after.iter().filter(|u| !before.contains(u)) {
+        let mut chat_ids = read_user_chats_in(tx, *user_id).unwrap_or_default();
+        if let Err(idx) = chat_ids.binary_search(&chat_id) {
+            chat_ids.insert(idx, chat_id);
+        }
+        write_user_chats(tx, *user_id, &chat_ids)?;
+    }
+    Ok(())
+}
+
+// the members of the stored chat, none if it is missing or undecodable
+fn stored_chat_users(tx: &jammdb::Tx, chat_id: ChatId) -> Result<Vec<UserId>, ServerError> {
+    Ok(tx
+        .get_bucket(BUCKET_CHATS)?
+        .get_kv(&chat_id.to_le_bytes())
+        .and_then(|kv| Chat::decode(kv.value()).ok())
+        .map(|chat| chat.users)
+        .unwrap_or_default())
+}
+
+// indexes the members of the existing chats from scratch, returns the count of the users
+fn rebuild_user_chats(tx: &jammdb::Tx) -> Result<usize, ServerError> {
+    match tx.delete_bucket(BUCKET_USER_CHATS) {
+        Ok(_) | Err(jammdb::Error::BucketMissing) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let index = tx.create_bucket(BUCKET_USER_CHATS)?;
+    let mut user_chats: HashMap<UserId, Vec<ChatId>> = HashMap::new();
+    for pair in tx.get_bucket(BUCKET_CHATS)?.kv_pairs() {
+        match Chat::decode(pair.value()) {
+            Ok(chat) => {
+                for user_id in chat.users {
+                    user_chats.entry(user_id).or_default().push(chat.id);
+                }
+            }
+            Err(e) => error!("members of a chat are not indexed, {}", e),
+        }
+    }
+    for (user_id, chat_ids) in &mut user_chats {
+        chat_ids.sort_unstable();
+        chat_ids.dedup();
+        index.put(&user_id.to_le_bytes(), chat_ids_value(chat_ids))?;
+    }
+    Ok(user_chats.len())
+}
+
+fn create_user_chats_index(tx: &jammdb::Tx) -> Result<(), ServerError> {
+    let indexed = rebuild_user_chats(tx)?;
+    info!("chats of {} users are indexed", indexed);
+    Ok(())
+}
+
+// the short name of the stored user, none if it is missing or undecodable
+fn stored_short_name(tx: &jammdb::Tx, user_id: UserId) -> Result<Option<String>, ServerError> {
+    Ok(tx
+        .get_bucket(BUCKET_USERS)?
+        .get_kv(&user_id.to_le_bytes())
+        .and_then(|kv| User::decode(kv.value()).ok())
+        .map(|user| user.short_name))
+}
+
+// follows the change of the user's short name made in the transaction,
+// an empty name is not indexed
+fn sync_user_name(
+    tx: &jammdb::Tx,
+    user_id: UserId,
+    before: &str,
+    after: &str,
+) -> Result<(), ServerError> {
+    let index = tx.get_bucket(BUCKET_USER_NAMES)?;
+    let key = user_id.to_le_bytes();
+    // the name released might be indexed for another user already
+    let released = !before.is_empty()
+        && before != after
+        && index
+            .get_kv(before.as_bytes())
+            .map_or(false, |kv| kv.value() == &key[..]);
+    if released {
+        match index.delete(before.as_bytes()) {
+            Ok(_) | Err(jammdb::Error::KeyValueMissing) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if !after.is_empty() {
+        index.put(after.as_bytes(), BytesMut::from(&key[..]))?;
+    }
+    Ok(())
+}
+
+// the users registered before the index are looked up by their short names as well,
+// the newest one wins if several share a name
+fn create_user_names_index(tx: &jammdb::Tx) -> Result<(), ServerError> {
+    match tx.delete_bucket(BUCKET_USER_NAMES) {
+        Ok(_) | Err(jammdb::Error::BucketMissing) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let index = tx.create_bucket(BUCKET_USER_NAMES)?;
+    let mut users: Vec<User> = Vec::new();
+    for pair in tx.get_bucket(BUCKET_USERS)?.kv_pairs() {
+        match User::decode(pair.value()) {
+            Ok(user) if !user.short_name.is_empty() => users.push(user),
+            Ok(_) => {}
+            Err(e) => error!("short name of a user is not indexed, {}", e),
+        }
+    }
+    users.sort_by_key(|u| u.created);
+    for user in &users {
+        index.put(
+            user.short_name.as_bytes(),
+            BytesMut::from(&user.id.to_le_bytes()[..]),
+        )?;
+    }
+    info!("short names of {} users are indexed", users.len());
+    Ok(())
+}
+
+// the database without the meta bucket is not migrated yet
+fn read_schema_version(db: &jammdb::DB) -> Result<usize, ServerError> {
+    let tx = db.tx(false)?;
+    let version = match tx.get_bucket(BUCKET_META) {
+        Ok(bucket) => match bucket.get_kv(SCHEMA_VERSION_KEY.as_bytes()) {
+            Some(kv) => {
+                let mut bytes = [0u8; 4];
+                if kv.value().len() != bytes.len() {
+                    return Err("malformed storage schema version".into());
+                }
+                bytes.copy_from_slice(kv.value());
+                u32::from_le_bytes(bytes) as usize
+            }
+            None => 0,
+        },
+        Err(jammdb::Error::BucketMissing) => 0,
+        Err(e) => return Err(e.into()),
+    };
+    Ok(version)
+}
+
+fn backup_path(db_file: &Path, version: usize) -> PathBuf {
+    let mut name = db_file.as_os_str().to_owned();
+    name.push(format!(".v{}.bak", version));
+    PathBuf::from(name)
+}
+
+// applies the pending steps all or nothing, the existing database is copied aside first
+fn migrate(
+    db: &jammdb::DB,
+    db_file: &Path,
+    existed: bool,
+    migrations: &[Migration],
+) -> Result<(), ServerError> {
+    let version = read_schema_version(db)?;
+    if version > migrations.len() {
+        return Err(format!(
+            "storage schema version {} is newer than the supported {}, upgrade the server",
+            version,
+            migrations.len()
+        )
+        .into());
+    }
+    if version == migrations.len() {
+        debug!("storage schema version {} is up to date", version);
+        return Ok(());
+    }
+    if existed {
+        let backup = backup_path(db_file, version);
+        std::fs::copy(db_file, &backup)?;
+        info!(
+            "storage schema version {} is backed up to {}",
+            version,
+            backup.display()
+        );
+    }
+    let tx = db.tx(true)?;
+    for (idx, step) in migrations.iter().enumerate().skip(version) {
+        info!("migrating storage to version {}: {}", idx + 1, step.name);
+        if let Err(e) = (step.apply)(&tx) {
+            error!(
+                "storage migration to version {} failed, nothing is changed: {}",
+                idx + 1,
+                e
+            );
+            return Err(format!("storage migration \"{}\" failed: {}", step.name, e).into());
+        }
+    }
+    let new_version = (migrations.len() as u32).to_le_bytes();
+    tx.get_bucket(BUCKET_META)?.put(
+        SCHEMA_VERSION_KEY.as_bytes(),
+        BytesMut::from(&new_version[..]),
+    )?;
+    tx.commit()?;
+    info!("storage schema is migrated to version {}", migrations.len());
+    Ok(())
+}
+
+fn copy_pairs(from: &jammdb::Bucket, to: &jammdb::Bucket) -> Result<(), ServerError> {
+    for pair in from.kv_pairs() {
+        to.put(pair.key(), BytesMut::from(pair.value()))?;
+    }
+    Ok(())
+}
+
+// copies the database as the read transaction sees it into the new file; the posts are
+// looked up by the existing chats, the damaged ones are left to the verifier
+fn copy_db(db: &jammdb::DB, path: &Path) -> Result<(), ServerError> {
+    let source = db.tx(false)?;
+    let target_db = jammdb::DB::open(path)?;
+    let target = target_db.tx(true)?;
+    for bucket_name in SNAPSHOT_BUCKETS {
+        match source.get_bucket(*bucket_name) {
+            Ok(bucket) => copy_pairs(&bucket, &target.create_bucket(*bucket_name)?)?,
+            // not migrated to yet
+            Err(jammdb::Error::BucketMissing) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let source_posts = source.get_bucket(BUCKET_POSTS)?;
+    let target_posts = target.create_bucket(BUCKET_POSTS)?;
+    for chat in source.get_bucket(BUCKET_CHATS)?.kv_pairs() {
+        let chat_bucket = match source_posts.get_bucket(chat.key()) {
+            Ok(chat_bucket) => chat_bucket,
+            Err(jammdb::Error::BucketMissing) => continue,
+            Err(e) => {
+                let chat_id = key_bytes(chat.key()).map(ChatId::from_le_bytes);
+                warn!("posts of chat {:?} are not copied, {}", chat_id, e);
+                continue;
+            }
+        };
+        let copied = target_posts.create_bucket(chat.key())?;
+        copy_pairs(&chat_bucket, &copied)?;
+        // the sequence goes on after the copied posts, the numbers are not reused
+        let last_seq = chat_bucket
+            .kv_pairs()
+            .last()
+            .and_then(|pair| key_bytes(pair.key()))
+            .map(u64::from_be_bytes)
+            .unwrap_or_default();
+        while copied.next_int() < last_seq {}
+    }
+    target.commit()?;
+    Ok(())
+}
+
+// the state of the chat room; the jammdb storage keeps it in the file,
+// the memory one serves the tests
+pub trait ChatStorage: Send + Sync + 'static {
+    fn close(self);
+    // a consistent copy of the storage in the new file, the writers go on meanwhile
+    fn backup(&self, path: &Path) -> Result<(), ServerError>;
+
+    // operations with users
+
+    fn read_user(&self, id: UserId) -> Result<Option<User>, ServerError>;
+    fn write_user(&self, id: UserId, user: &User) -> Result<(), ServerError>;
+    // stores the new user along with the digest of its secret at once unless another user
+    // has its short name, false then
+    fn create_user(&self, user: &User, secret_digest: &[u8]) -> Result<bool, ServerError>;
+    // the id of the user registered with the short name, if any
+    fn find_user_id(&self, short_name: &str) -> Result<Option<UserId>, ServerError>;
+    // Ok(None) if the user was not found, otherwise the user as stored after the updater;
+    // nothing is stored if the updater returns false
+    fn update_user<F: FnMut(&mut User) -> bool>(
+        &self,
+        id: UserId,
+        updater: F,
+    ) -> Result<Option<User>, ServerError>;
+    fn read_all_users(&self) -> Result<Vec<User>, ServerError>;
+    // the user is removed out of the chats as well, its short name is released
+    fn remove_user(&self, id: UserId) -> Result<(), ServerError>;
+
+    // operations with chats
+
+    fn read_chat(&self, id: ChatId) -> Result<Option<Chat>, ServerError>;
+    fn write_chat(&self, id: ChatId, chat: &Chat) -> Result<(), ServerError>;
+    // Ok(None) if the chat was not found, otherwise the chat as stored after the updater;
+    // nothing is stored if the updater returns false
+    fn update_chat<F: FnMut(&mut Chat) -> bool>(
         &self,
         id: ChatId,
         updater: F,
-    ) -> Result<Option<Chat>, InternalError> {
-        self.update_in_db::<Chat, _>(BUCKET_CHATS, &id.to_le_bytes(), updater)
+    ) -> Result<Option<Chat>, ServerError>;
+    fn read_all_chats(&self) -> Result<Vec<Chat>, ServerError>;
+    fn read_chats_where<F: FnMut(&Chat) -> bool>(
+        &self,
+        predicate: F,
+    ) -> Result<Vec<Chat>, ServerError>;
+    // chats are ordered by the bytes of their little-endian ids
+    fn read_chats_after(
+        &self,
+        after: Option<ChatId>,
+        limit: usize,
+    ) -> Result<Vec<Chat>, ServerError>;
+    // the chat's posts are removed too
+    fn remove_chat(&self, id: ChatId) -> Result<(), ServerError>;
+    // ids of the chats the user is a member of, ascending, without scanning the chats
+    fn read_user_chats(&self, user_id: UserId) -> Result<Vec<ChatId>, ServerError>;
+
+    // operations with invitations, one per (chat, inviter, invitee)
+
+    fn write_invitation(&self, invitation: &Invitation) -> Result<(), ServerError>;
+    fn read_invitations_to(&self, user_id: UserId) -> Result<Vec<Invitation>, ServerError>;
+    fn remove_invitation(&self, invitation: &Invitation) -> Result<bool, ServerError>;
+    fn remove_invitations_to(&self, chat_id: ChatId, user_id: UserId)
+        -> Result<usize, ServerError>;
+    fn write_invitation_reply(&self, reply: &Invitation) -> Result<(), ServerError>;
+    fn take_invitation_replies(&self, user_id: UserId) -> Result<Vec<Invitation>, ServerError>;
+
+    // operations with service metadata
+
+    fn read_meta(&self, key: &str) -> Result<Option<Vec<u8>>, ServerError>;
+    fn write_meta(&self, key: &str, value: &[u8]) -> Result<(), ServerError>;
+    fn remove_meta(&self, key: &str) -> Result<(), ServerError>;
+
+    // operations with posts, kept in the order of writing; the posts are read with their
+    // sequence numbers, growing within the chat, and in their order
+
+    // Ok(false) if another post has the id, nothing is written then
+    fn write_post(&self, post: &Post) -> Result<bool, ServerError>;
+    // the chat and the sequence number of the post, without scanning the chats
+    fn locate_post(&self, post_id: PostId) -> Result<Option<(ChatId, u64)>, ServerError>;
+    fn chat_posts_count(&self, chat_id: ChatId) -> Result<usize, ServerError>;
+    fn read_chat_posts(
+        &self,
+        chat_id: ChatId,
+        idx_from: usize,
+        count: usize,
+    ) -> Result<Vec<Post>, ServerError>;
+    fn read_post(&self, chat_id: ChatId, post_id: PostId) -> Result<Option<Post>, ServerError>;
+    fn remove_post(&self, chat_id: ChatId, post_id: PostId) -> Result<bool, ServerError>;
+    // Ok(false) if the post is not found, the post keeps its place among the chat's posts
+    fn update_post(&self, post: &Post) -> Result<bool, ServerError>;
+    fn verify_chat_posts(&self, chat_id: ChatId, repair: bool) -> Result<usize, ServerError>;
+    fn salvage_chat_posts(&self, chat_id: ChatId) -> Result<usize, ServerError>;
+    // the posts of the others written after the sequence number, e.g. unread by the user,
+    // the system notices are not counted
+    fn count_posts_after(
+        &self,
+        chat_id: ChatId,
+        seq: u64,
+        user_id: UserId,
+    ) -> Result<usize, ServerError>;
+
+    // operations with read marks, one per (chat, user), they are removed with the chat
+
+    // the last post read by the user and its sequence number
+    fn read_read_mark(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+    ) -> Result<Option<(PostId, u64)>, ServerError>;
+    // Ok(false) if the mark is at the sequence number or later, nothing is written then
+    fn advance_read_mark(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        post_id: PostId,
+        seq: u64,
+    ) -> Result<bool, ServerError>;
+
+    // operations with mutes, one per (chat, user), they are removed with the chat
+
+    fn is_chat_muted(&self, chat_id: ChatId, user_id: UserId) -> Result<bool, ServerError>;
+    fn set_chat_muted(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        muted: bool,
+    ) -> Result<(), ServerError>;
+
+    // operations with bans, one per (chat, user), they are removed with the chat
+
+    fn is_banned(&self, chat_id: ChatId, user_id: UserId) -> Result<bool, ServerError>;
+    fn set_banned(&self, chat_id: ChatId, user_id: UserId, banned: bool)
+        -> Result<(), ServerError>;
+
+    // operations with attachments, the content is kept in chunks of ATTACHMENT_CHUNK_LEN
+
+    // Ok(false) if another attachment has the id, nothing is written then
+    fn write_attachment(&self, info: &AttachmentInfo, content: &[u8]) -> Result<bool, ServerError>;
+    fn read_attachment_info(&self, id: AttachmentId)
+        -> Result<Option<AttachmentInfo>, ServerError>;
+    // Ok(None) past the last chunk
+    fn read_attachment_chunk(
+        &self,
+        id: AttachmentId,
+        idx: usize,
+    ) -> Result<Option<Vec<u8>>, ServerError>;
+
+    // the newest posts of the chat whose text contains the query regardless of the case,
+    // newest first; the pages are read from the end until enough posts are found
+    fn search_chat_posts(
+        &self,
+        chat_id: ChatId,
+        query: &str,
+        max: usize,
+    ) -> Result<Vec<Post>, ServerError> {
+        let query = query.to_lowercase();
+        let mut found = Vec::new();
+        let mut end = self.chat_posts_count(chat_id)?;
+        while end > 0 && found.len() < max {
+            let from = end.saturating_sub(SEARCH_PAGE_LEN);
+            let page = self.read_chat_posts(chat_id, from, end - from)?;
+            found.extend(
+                page.into_iter()
+                    .rev()
+                    .filter(|post| post.text.to_lowercase().contains(&query)),
+            );
+            end = from;
+        }
+        found.truncate(max);
+        Ok(found)
     }
 
-    pub fn read_all_chats(&self) -> Result<Vec<Chat>, InternalError> {
-        self.read_all_from_db::<Chat>(BUCKET_CHATS)
+    // the last posts of the chat numbered below the seq, up to `count` in their order;
+    // the pages are read from the end, the recent posts are asked for
+    fn read_chat_posts_before(
+        &self,
+        chat_id: ChatId,
+        seq: u64,
+        count: usize,
+    ) -> Result<Vec<Post>, ServerError> {
+        let mut found = Vec::new();
+        let mut end = self.chat_posts_count(chat_id)?;
+        while end > 0 && found.len() < count {
+            let from = end.saturating_sub(SEARCH_PAGE_LEN);
+            let page = self.read_chat_posts(chat_id, from, end - from)?;
+            found.extend(page.into_iter().rev().filter(|post| post.seq < seq));
+            end = from;
+        }
+        found.truncate(count);
+        found.reverse();
+        Ok(found)
+    }
+
+    // chats with damaged posts are marked until repaired
+
+    fn is_degraded(&self, chat_id: ChatId) -> Result<bool, ServerError> {
+        Ok(self.read_meta(&degraded_key(chat_id))?.is_some())
+    }
+
+    // returns true if the mark has changed
+    fn set_degraded(&self, chat_id: ChatId, degraded: bool) -> Result<bool, ServerError> {
+        let key = degraded_key(chat_id);
+        if self.read_meta(&key)?.is_some() == degraded {
+            return Ok(false);
+        }
+        if degraded {
+            error!(
+                "posts of chat {} are damaged, the chat is degraded",
+                chat_id
+            );
+            self.write_meta(&key, &[1])?;
+        } else {
+            info!("posts of chat {} are repaired", chat_id);
+            self.remove_meta(&key)?;
+        }
+        Ok(true)
+    }
+
+    // the digest of the secret the user presents to register again, the secret is not kept
+
+    fn read_user_secret(&self, user_id: UserId) -> Result<Option<Vec<u8>>, ServerError> {
+        self.read_meta(&secret_key(user_id))
+    }
+
+    fn write_user_secret(&self, user_id: UserId, digest: &[u8]) -> Result<(), ServerError> {
+        self.write_meta(&secret_key(user_id), digest)
+    }
+
+    fn remove_user_secret(&self, user_id: UserId) -> Result<(), ServerError> {
+        self.remove_meta(&secret_key(user_id))
+    }
+}
+
+pub struct Storage {
+    db: jammdb::DB,
+}
+
+impl Storage {
+    pub fn new<P: AsRef<Path>>(db_file: P) -> Result<Self, ServerError> {
+        Self::open_with(db_file.as_ref(), MIGRATIONS)
     }
 
-    pub fn remove_chat(&self, id: ChatId) -> Result<(), InternalError> {
-        self.remove_chat_posts(id)
-            .and(self.remove_from_db::<Chat>(BUCKET_CHATS, &id.to_le_bytes()))
+    fn open_with(db_file: &Path, migrations: &[Migration]) -> Result<Self, ServerError> {
+        let existed = db_file.exists();
+        let db = jammdb::DB::open(db_file)?;
+        migrate(&db, db_file, existed, migrations)?;
+        Ok(Self { db })
     }
 
     // generic operations with user / chats implementation
@@ -120,7 +1033,7 @@ impl Storage {
         &self,
         bucket_name: &str,
         id: &[u8],
-    ) -> Result<Option<M>, InternalError> {
+    ) -> Result<Option<M>, ServerError> {
         match self.db.tx(false) {
             Ok(tx) => match tx.get_bucket(bucket_name) {
                 Ok(bucket) => match bucket.get(id) {
@@ -147,71 +1060,16 @@ impl Storage {
         bucket_name: &str,
         id: &[u8],
         item: &M,
-    ) -> Result<(), InternalError> {
+    ) -> Result<(), ServerError> {
         match self.db.tx(true) {
             Ok(tx) => match tx.get_bucket(bucket_name) {
-                Ok(bucket) => {
-                    let mut buf = BytesMut::new();
-                    match item.encode(&mut buf) {
-                        Ok(_) => match bucket.put(id, buf) {
-                            Ok(_) => tx.commit().map_err(|e| e.into()),
-                            Err(e) => Err(e.into()),
-                        },
+                Ok(bucket) => match encode(item) {
+                    Ok(buf) => match bucket.put(id, buf) {
+                        Ok(_) => tx.commit().map_err(|e| e.into()),
                         Err(e) => Err(e.into()),
-                    }
-                }
-                Err(e) => Err(e.into()),
-            },
-            Err(e) => Err(e.into()),
-        }
-    }
-
-    /// Tries to conditionally update specified item.
-    /// Returns:
-    /// - InternalError if some error happens
-    /// - Ok(Some(item)) if item was found and successfully updated; item contains *new* value
-    /// - Ok(Some(item)) if item was found but updater returned false; item contains *unchanged* value, and database remains unchanged
-    /// - Ok(None) if item was not found
-    fn update_in_db<M: Message + Default + Clone, F: FnMut(&mut M) -> bool>(
-        &self,
-        bucket_name: &str,
-        id: &[u8],
-        mut updater: F,
-    ) -> Result<Option<M>, InternalError> {
-        match self.db.tx(true) {
-            Ok(tx) => match tx.get_bucket(bucket_name) {
-                Ok(bucket) => {
-                    if let Some(kv) = bucket.get_kv(id) {
-                        // read
-                        let bin = kv.value();
-                        match M::decode(bin) {
-                            Ok(mut item) => {
-                                // update
-                                if !updater(&mut item) {
-                                    return Ok(Some(item));
-                                }
-                                // serialize
-                                let mut buf = BytesMut::new();
-                                match item.encode(&mut buf) {
-                                    Ok(_) => match bucket.put(id, buf) {
-                                        // store into db
-                                        Ok(_) => {
-                                            tx.commit().map(|_| Some(item)).map_err(|e| e.into())
-                                        }
-                                        Err(e) => Err(e.into()),
-                                    },
-                                    Err(e) => Err(e.into()),
-                                }
-                            }
-                            Err(e) => {
-                                error!("protobuf parse, {}", e);
-                                Err(e.into())
-                            }
-                        }
-                    } else {
-                        Ok(None)
-                    }
-                }
+                    },
+                    Err(e) => Err(e.into()),
+                },
                 Err(e) => Err(e.into()),
             },
             Err(e) => Err(e.into()),
@@ -220,14 +1078,14 @@ impl Storage {
 
     /// Tries to conditionally update all items in specified bucket.
     /// Returns:
-    /// - InternalError if some error happens
+    /// - ServerError if some error happens
     /// - Ok(count) if some items were updated
     /// - Ok(0) if no item was updated
     fn update_all_in_db<M: Message + Default + Clone, F: FnMut(&mut M) -> bool>(
         &self,
         bucket_name: &str,
         mut updater: F,
-    ) -> Result<usize, InternalError> {
+    ) -> Result<usize, ServerError> {
         match self.db.tx(true) {
             Ok(tx) => match tx.get_bucket(bucket_name) {
                 Ok(bucket) => {
@@ -238,8 +1096,7 @@ impl Storage {
                         match M::decode(bin) {
                             Ok(mut item) => {
                                 if updater(&mut item) {
-                                    let mut buf = BytesMut::new();
-                                    if item.encode(&mut buf).is_ok() {
+                                    if let Ok(buf) = encode(&item) {
                                         match bucket.put(pair.key(), buf) {
                                             Ok(_) => cnt_updated += 1,
                                             Err(e) => error!("failed to store updated item, {}", e),
@@ -265,7 +1122,16 @@ impl Storage {
     fn read_all_from_db<M: Message + Default>(
         &self,
         bucket_name: &str,
-    ) -> Result<Vec<M>, InternalError> {
+    ) -> Result<Vec<M>, ServerError> {
+        self.read_from_db_where::<M, _>(bucket_name, |_| true)
+    }
+
+    // reads only items matching the predicate
+    fn read_from_db_where<M: Message + Default, F: FnMut(&M) -> bool>(
+        &self,
+        bucket_name: &str,
+        mut predicate: F,
+    ) -> Result<Vec<M>, ServerError> {
         match self.db.tx(false) {
             Ok(tx) => match tx.get_bucket(bucket_name) {
                 Ok(bucket) => {
@@ -273,7 +1139,11 @@ impl Storage {
                     for pair in bucket.kv_pairs() {
                         let bin = pair.value();
                         match M::decode(bin) {
-                            Ok(user) => items.push(user),
+                            Ok(item) => {
+                                if predicate(&item) {
+                                    items.push(item);
+                                }
+                            }
                             Err(e) => error!("internal error, {}", e),
                         }
                     }
@@ -285,48 +1155,295 @@ impl Storage {
         }
     }
 
-    fn remove_from_db<M: Message>(
+    // removes the items matching the predicate in a single transaction, returns removed ones
+    fn take_from_db_where<M: Message + Default, F: FnMut(&M, &[u8]) -> bool>(
         &self,
         bucket_name: &str,
-        id: &[u8],
-    ) -> Result<(), InternalError> {
-        // does not require writable tx!
-        // see https://docs.rs/jammdb/0.5.0/jammdb/struct.Bucket.html#method.delete
+        mut predicate: F,
+    ) -> Result<Vec<M>, ServerError> {
         match self.db.tx(true) {
             Ok(tx) => match tx.get_bucket(bucket_name) {
-                Ok(bucket) => bucket
-                    .delete(id)
-                    .map(|_| ())
-                    .map_err(|e| format!("{}", e).into()),
+                Ok(bucket) => {
+                    let mut taken = Vec::new();
+                    let mut keys = Vec::new();
+                    for pair in bucket.kv_pairs() {
+                        match M::decode(pair.value()) {
+                            Ok(item) => {
+                                if predicate(&item, pair.key()) {
+                                    keys.push(pair.key().to_vec());
+                                    taken.push(item);
+                                }
+                            }
+                            Err(e) => error!("internal error, {}", e),
+                        }
+                    }
+                    if keys.is_empty() {
+                        return Ok(taken);
+                    }
+                    for key in keys {
+                        bucket.delete(key)?;
+                    }
+                    tx.commit().map(|_| taken).map_err(|e| e.into())
+                }
                 Err(e) => Err(e.into()),
             },
             Err(e) => Err(e.into()),
         }
     }
 
-    // operations with posts
-    // all posts stored groupped by their chats into separate buckets: BUCKET_POSTS/chat_id/*
-    // the post's key in the storage is a sequential integer to preserve posts natural order
-    pub fn write_post(&self, post: &Post) -> Result<(), InternalError> {
+    fn remove_from_db<M: Message>(&self, bucket_name: &str, id: &[u8]) -> Result<(), ServerError> {
         match self.db.tx(true) {
-            Ok(tx) => match tx.get_or_create_bucket(BUCKET_POSTS) {
-                Ok(posts_bucket) => {
-                    match posts_bucket.get_or_create_bucket(&post.chat_id.to_le_bytes()) {
-                        Ok(chat_bucket) => {
-                            let mut buf = BytesMut::new();
-                            match post.encode(&mut buf) {
-                                Ok(_) => {
-                                    let k = chat_bucket.next_int();
-                                    match chat_bucket.put(&k.to_le_bytes(), buf) {
-                                        Ok(_) => tx.commit().map_err(|e| e.into()),
-                                        Err(e) => Err(e.into()),
-                                    }
-                                }
-                                Err(e) => Err(e.into()),
+            Ok(tx) => match tx.get_bucket(bucket_name) {
+                Ok(bucket) => match bucket.delete(id) {
+                    Ok(_) | Err(jammdb::Error::KeyValueMissing) => {
+                        tx.commit().map_err(|e| e.into())
+                    }
+                    Err(e) => Err(format!("{}", e).into()),
+                },
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // the index entries of the posts go with them
+    fn remove_chat_posts(&self, id: ChatId) -> Result<(), ServerError> {
+        let tx = self.db.tx(true)?;
+        // the posts of a damaged chat are left to be found by the verifier
+        let locations = chat_post_locations(&tx, id).unwrap_or_default();
+        let index = tx.get_bucket(BUCKET_POST_INDEX)?;
+        for (post_id, seq) in locations {
+            let key = post_id.to_le_bytes();
+            let located = index
+                .get_kv(&key)
+                .and_then(|kv| parse_post_location(kv.value()));
+            if located == Some((id, seq)) {
+                index.delete(&key)?;
+            }
+        }
+        match tx
+            .get_bucket(BUCKET_POSTS)?
+            .delete_bucket(&id.to_le_bytes())
+        {
+            // the chat had no posts
+            Ok(_) | Err(jammdb::Error::BucketMissing) => {}
+            Err(e) => return Err(e.into()),
+        }
+        remove_post_count(&tx, id)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl ChatStorage for Storage {
+    // all transactions are committed synchronously, closing just releases the file
+    fn close(self) {
+        debug!("closing storage");
+        drop(self.db);
+    }
+
+    // a read transaction sees the database as it was at its start
+    fn backup(&self, path: &Path) -> Result<(), ServerError> {
+        copy_db(&self.db, path)
+    }
+
+    // operations with users
+
+    fn read_user(&self, id: UserId) -> Result<Option<User>, ServerError> {
+        self.read_from_db::<User>(BUCKET_USERS, &id.to_le_bytes())
+    }
+
+    fn write_user(&self, id: UserId, user: &User) -> Result<(), ServerError> {
+        let tx = self.db.tx(true)?;
+        let before = stored_short_name(&tx, id)?.unwrap_or_default();
+        tx.get_bucket(BUCKET_USERS)?
+            .put(&id.to_le_bytes(), encode(user)?)?;
+        sync_user_name(&tx, id, &before, &user.short_name)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn create_user(&self, user: &User, secret_digest: &[u8]) -> Result<bool, ServerError> {
+        let tx = self.db.tx(true)?;
+        let users = tx.get_bucket(BUCKET_USERS)?;
+        if !user.short_name.is_empty() {
+            // the index might still name the user removed
+            let owner = tx
+                .get_bucket(BUCKET_USER_NAMES)?
+                .get_kv(user.short_name.as_bytes())
+                .map(|kv| kv.value().to_vec());
+            if owner.map_or(false, |owner| users.get_kv(owner.as_slice()).is_some()) {
+                return Ok(false);
+            }
+        }
+        users.put(&user.id.to_le_bytes(), encode(user)?)?;
+        tx.get_bucket(BUCKET_META)?.put(
+            secret_key(user.id).as_bytes(),
+            BytesMut::from(secret_digest),
+        )?;
+        sync_user_name(&tx, user.id, "", &user.short_name)?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn find_user_id(&self, short_name: &str) -> Result<Option<UserId>, ServerError> {
+        if short_name.is_empty() {
+            return Ok(None);
+        }
+        let tx = self.db.tx(false)?;
+        let index = tx.get_bucket(BUCKET_USER_NAMES)?;
+        match index.get_kv(short_name.as_bytes()) {
+            Some(kv) => {
+                let mut bytes = [0u8; 8];
+                if kv.value().len() != bytes.len() {
+                    return Err(format!("malformed user id of '{}'", short_name).into());
+                }
+                bytes.copy_from_slice(kv.value());
+                Ok(Some(UserId::from_le_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Tries to conditionally update specified user.
+    /// Returns:
+    /// - ServerError if some error happens
+    /// - Ok(Some(user)) if user was found and successfully updated; user contains *new* value
+    /// - Ok(Some(user)) if user was found but updater returned false; user contains *unchanged* value, and database remains unchanged
+    /// - Ok(None) if user was not found
+    fn update_user<F: FnMut(&mut User) -> bool>(
+        &self,
+        id: UserId,
+        mut updater: F,
+    ) -> Result<Option<User>, ServerError> {
+        let tx = self.db.tx(true)?;
+        let bucket = tx.get_bucket(BUCKET_USERS)?;
+        let key = id.to_le_bytes();
+        let mut user = match bucket.get_kv(&key) {
+            Some(kv) => User::decode(kv.value()).map_err(|e| {
+                error!("protobuf parse, {}", e);
+                e
+            })?,
+            None => return Ok(None),
+        };
+        let before = user.short_name.clone();
+        if !updater(&mut user) {
+            return Ok(Some(user));
+        }
+        bucket.put(&key, encode(&user)?)?;
+        sync_user_name(&tx, id, &before, &user.short_name)?;
+        tx.commit()?;
+        Ok(Some(user))
+    }
+
+    fn read_all_users(&self) -> Result<Vec<User>, ServerError> {
+        self.read_all_from_db::<User>(BUCKET_USERS)
+    }
+
+    fn remove_user(&self, id: UserId) -> Result<(), ServerError> {
+        // remove the user out of all chats
+        self.update_all_in_db::<Chat, _>(BUCKET_CHATS, |mut_ref_chat| {
+            let cnt_before = mut_ref_chat.users.len();
+            mut_ref_chat.users.retain(|&u| u != id);
+            mut_ref_chat.users.len() < cnt_before
+        })
+        .and(self.remove_from_db::<Chat>(BUCKET_USER_CHATS, &id.to_le_bytes()))
+        .and_then(|_| {
+            // the short name is released
+            let tx = self.db.tx(true)?;
+            if let Some(short_name) = stored_short_name(&tx, id)? {
+                sync_user_name(&tx, id, &short_name, "")?;
+            }
+            tx.commit().map_err(|e| e.into())
+        })
+        .and(self.remove_from_db::<User>(BUCKET_USERS, &id.to_le_bytes()))
+    }
+
+    // operations with chats
+
+    fn read_chat(&self, id: ChatId) -> Result<Option<Chat>, ServerError> {
+        self.read_from_db::<Chat>(BUCKET_CHATS, &id.to_le_bytes())
+    }
+
+    fn write_chat(&self, id: ChatId, chat: &Chat) -> Result<(), ServerError> {
+        let tx = self.db.tx(true)?;
+        let before = stored_chat_users(&tx, id)?;
+        tx.get_bucket(BUCKET_CHATS)?
+            .put(&id.to_le_bytes(), encode(chat)?)?;
+        sync_user_chats(&tx, id, &before, &chat.users)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Tries to conditionally update specified chat.
+    /// Returns:
+    /// - ServerError if some error happens
+    /// - Ok(Some(chat)) if chat was found and successfully updated; chat contains *new* value
+    /// - Ok(Some(chat)) if chat was found but updater returned false; chat contains *unchanged* value, and database remains unchanged
+    /// - Ok(None) if chat was not found
+    fn update_chat<F: FnMut(&mut Chat) -> bool>(
+        &self,
+        id: ChatId,
+        mut updater: F,
+    ) -> Result<Option<Chat>, ServerError> {
+        let tx = self.db.tx(true)?;
+        let bucket = tx.get_bucket(BUCKET_CHATS)?;
+        let key = id.to_le_bytes();
+        let mut chat = match bucket.get_kv(&key) {
+            Some(kv) => Chat::decode(kv.value()).map_err(|e| {
+                error!("protobuf parse, {}", e);
+                e
+            })?,
+            None => return Ok(None),
+        };
+        let before = chat.users.clone();
+        if !updater(&mut chat) {
+            return Ok(Some(chat));
+        }
+        bucket.put(&key, encode(&chat)?)?;
+        sync_user_chats(&tx, id, &before, &chat.users)?;
+        tx.commit()?;
+        Ok(Some(chat))
+    }
+
+    fn read_all_chats(&self) -> Result<Vec<Chat>, ServerError> {
+        self.read_all_from_db::<Chat>(BUCKET_CHATS)
+    }
+
+    fn read_chats_where<F: FnMut(&Chat) -> bool>(
+        &self,
+        predicate: F,
+    ) -> Result<Vec<Chat>, ServerError> {
+        self.read_from_db_where::<Chat, _>(BUCKET_CHATS, predicate)
+    }
+
+    // reads up to `limit` chats stored after the `after` one in the storage order,
+    // allows to walk through all chats in slices
+    fn read_chats_after(
+        &self,
+        after: Option<ChatId>,
+        limit: usize,
+    ) -> Result<Vec<Chat>, ServerError> {
+        let after = after.map(|id| id.to_le_bytes());
+        match self.db.tx(false) {
+            Ok(tx) => match tx.get_bucket(BUCKET_CHATS) {
+                Ok(bucket) => {
+                    let mut chats = Vec::new();
+                    for pair in bucket.kv_pairs() {
+                        if chats.len() == limit {
+                            break;
+                        }
+                        if let Some(after) = &after {
+                            if pair.key() <= &after[..] {
+                                continue;
                             }
                         }
-                        Err(e) => Err(e.into()),
+                        match Chat::decode(pair.value()) {
+                            Ok(chat) => chats.push(chat),
+                            Err(e) => error!("internal error, {}", e),
+                        }
                     }
+                    Ok(chats)
                 }
                 Err(e) => Err(e.into()),
             },
@@ -334,78 +1451,717 @@ impl Storage {
         }
     }
 
-    pub fn chat_posts_count(&self, chat_id: ChatId) -> Result<usize, InternalError> {
+    fn remove_chat(&self, id: ChatId) -> Result<(), ServerError> {
+        self.remove_chat_posts(id)?;
+        let tx = self.db.tx(true)?;
+        let before = stored_chat_users(&tx, id)?;
+        match tx.get_bucket(BUCKET_CHATS)?.delete(&id.to_le_bytes()) {
+            Ok(_) | Err(jammdb::Error::KeyValueMissing) => {}
+            Err(e) => return Err(e.into()),
+        }
+        sync_user_chats(&tx, id, &before, &[])?;
+        remove_chat_user_keys(&tx, BUCKET_READ_MARKS, id)?;
+        remove_chat_user_keys(&tx, BUCKET_MUTES, id)?;
+        remove_chat_user_keys(&tx, BUCKET_BANS, id)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn read_user_chats(&self, user_id: UserId) -> Result<Vec<ChatId>, ServerError> {
+        let tx = self.db.tx(false)?;
+        read_user_chats_in(&tx, user_id)
+    }
+
+    // operations with invitations
+    // an invitation is kept until answered, the key is (chat, inviter, invitee)
+
+    fn write_invitation(&self, invitation: &Invitation) -> Result<(), ServerError> {
+        self.write_to_db(BUCKET_INVITATIONS, &invitation_key(invitation), invitation)
+    }
+
+    fn read_invitations_to(&self, user_id: UserId) -> Result<Vec<Invitation>, ServerError> {
+        self.read_from_db_where::<Invitation, _>(BUCKET_INVITATIONS, |i| i.to_user_id == user_id)
+    }
+
+    // returns false if there was no such invitation
+    fn remove_invitation(&self, invitation: &Invitation) -> Result<bool, ServerError> {
+        let key = invitation_key(invitation);
+        Ok(!self
+            .take_from_db_where::<Invitation, _>(BUCKET_INVITATIONS, |_, k| k == key.as_slice())?
+            .is_empty())
+    }
+
+    // the user has entered the chat, all invitations there are answered
+    fn remove_invitations_to(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+    ) -> Result<usize, ServerError> {
+        Ok(self
+            .take_from_db_where::<Invitation, _>(BUCKET_INVITATIONS, |i, _| {
+                i.chat_id == chat_id && i.to_user_id == user_id
+            })?
+            .len())
+    }
+
+    fn write_invitation_reply(&self, reply: &Invitation) -> Result<(), ServerError> {
+        self.write_to_db(BUCKET_REPLIES, &invitation_key(reply), reply)
+    }
+
+    // the replies are removed as they are to be delivered to the inviter
+    fn take_invitation_replies(&self, user_id: UserId) -> Result<Vec<Invitation>, ServerError> {
+        self.take_from_db_where::<Invitation, _>(BUCKET_REPLIES, |i, _| i.from_user_id == user_id)
+    }
+
+    // operations with service metadata
+
+    fn read_meta(&self, key: &str) -> Result<Option<Vec<u8>>, ServerError> {
         match self.db.tx(false) {
-            Ok(tx) => match tx.get_bucket(BUCKET_POSTS) {
-                Ok(posts_bucket) => match posts_bucket.get_bucket(&chat_id.to_le_bytes()) {
-                    Ok(chat_bucket) => Ok(chat_bucket.kv_pairs().count()),
-                    Err(jammdb::Error::BucketMissing) => Ok(0),
+            Ok(tx) => match tx.get_bucket(BUCKET_META) {
+                Ok(bucket) => Ok(bucket.get_kv(key.as_bytes()).map(|kv| kv.value().to_vec())),
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_meta(&self, key: &str, value: &[u8]) -> Result<(), ServerError> {
+        match self.db.tx(true) {
+            Ok(tx) => match tx.get_bucket(BUCKET_META) {
+                Ok(bucket) => match bucket.put(key.as_bytes(), BytesMut::from(value)) {
+                    Ok(_) => tx.commit().map_err(|e| e.into()),
+                    Err(e) => Err(e.into()),
+                },
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn remove_meta(&self, key: &str) -> Result<(), ServerError> {
+        match self.db.tx(true) {
+            Ok(tx) => match tx.get_bucket(BUCKET_META) {
+                Ok(bucket) => match bucket.delete(key.as_bytes()) {
+                    Ok(_) | Err(jammdb::Error::KeyValueMissing) => {
+                        tx.commit().map_err(|e| e.into())
+                    }
+                    Err(e) => Err(e.into()),
+                },
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // operations with posts
+    // all posts stored groupped by their chats into separate buckets: BUCKET_POSTS/chat_id/*
+    // the post's key in the storage is a sequential integer to preserve posts natural order,
+    // see post_key()
+    // the post is indexed by its id in the same transaction
+    fn write_post(&self, post: &Post) -> Result<bool, ServerError> {
+        let mut damaged = false;
+        let result = match self.db.tx(true) {
+            Ok(tx) => match tx.get_bucket(BUCKET_POST_INDEX) {
+                // the random id is taken
+                Ok(index) if index.get_kv(&post.id.to_le_bytes()).is_some() => Ok(false),
+                Ok(index) => match tx.get_or_create_bucket(BUCKET_POSTS) {
+                    Ok(posts_bucket) => {
+                        match posts_bucket.get_or_create_bucket(&post.chat_id.to_le_bytes()) {
+                            Ok(chat_bucket) => match encode(post) {
+                                Ok(buf) => {
+                                    let k = chat_bucket.next_int();
+                                    let written: Result<_, ServerError> = chat_bucket
+                                        .put(&post_key(k), buf)
+                                        .and_then(|_| {
+                                            index.put(
+                                                &post.id.to_le_bytes(),
+                                                post_location(post.chat_id, k),
+                                            )
+                                        })
+                                        .map_err(|e| e.into());
+                                    written
+                                        .and_then(|_| adjust_post_count(&tx, post.chat_id, 1))
+                                        .and_then(|_| tx.commit().map_err(|e| e.into()))
+                                        .map(|_| true)
+                                }
+                                Err(e) => Err(e.into()),
+                            },
+                            Err(e) => {
+                                // the chat's posts are not a bucket anymore
+                                damaged = true;
+                                Err(e.into())
+                            }
+                        }
+                    }
                     Err(e) => Err(e.into()),
                 },
                 Err(e) => Err(e.into()),
             },
             Err(e) => Err(e.into()),
+        };
+        if damaged {
+            self.set_degraded(post.chat_id, true)?;
         }
+        result
     }
 
-    pub fn read_chat_posts(
+    // the records are counted in full only for the chats not posted to since the counters
+    fn chat_posts_count(&self, chat_id: ChatId) -> Result<usize, ServerError> {
+        let tx = self.db.tx(false)?;
+        match read_post_count(&tx, chat_id)? {
+            Some(count) => Ok(count),
+            None => count_chat_records(&tx, chat_id),
+        }
+    }
+
+    // undecodable posts are skipped, a damaged bucket marks the chat as degraded
+    fn read_chat_posts(
         &self,
         chat_id: ChatId,
         idx_from: usize,
         count: usize,
-    ) -> Result<Vec<Post>, InternalError> {
+    ) -> Result<Vec<Post>, ServerError> {
         if count == 0 {
             return Ok(Vec::new());
         }
-        match self.db.tx(false) {
+        let mut damaged = false;
+        let posts = match self.db.tx(false) {
             Ok(tx) => match tx.get_bucket(BUCKET_POSTS) {
                 Ok(posts_bucket) => match posts_bucket.get_bucket(&chat_id.to_le_bytes()) {
                     Ok(chat_bucket) => {
                         let mut posts = Vec::new();
+                        let mut failed_in_row = 0;
                         for pair in chat_bucket.kv_pairs().skip(idx_from).take(count) {
                             let bin = pair.value();
                             match Post::decode(bin) {
-                                Ok(post) => posts.push(post),
-                                Err(e) => error!("internal error, {}", e),
+                                Ok(post) => {
+                                    failed_in_row = 0;
+                                    posts.push(sequenced(pair.key(), post));
+                                }
+                                Err(e) => {
+                                    error!("internal error, {}", e);
+                                    failed_in_row += 1;
+                                    damaged |= failed_in_row >= DAMAGE_THRESHOLD;
+                                }
                             }
                         }
-                        Ok(posts)
+                        posts
                     }
                     Err(jammdb::Error::BucketMissing) => {
                         debug!("there wasn't any posts in requested chat");
-                        Ok(Vec::new())
+                        Vec::new()
+                    }
+                    Err(e) => {
+                        error!("posts of chat {} are unreadable, {}", chat_id, e);
+                        damaged = true;
+                        Vec::new()
                     }
-                    Err(e) => Err(e.into()),
                 },
-                Err(e) => Err(e.into()),
+                Err(e) => return Err(e.into()),
             },
+            Err(e) => return Err(e.into()),
+        };
+        if damaged {
+            self.set_degraded(chat_id, true)?;
+        }
+        Ok(posts)
+    }
+
+    fn locate_post(&self, post_id: PostId) -> Result<Option<(ChatId, u64)>, ServerError> {
+        locate_post_in(&self.db.tx(false)?, post_id)
+    }
+
+    // the post is looked up by the index instead of scanning the chat
+    fn read_post(&self, chat_id: ChatId, post_id: PostId) -> Result<Option<Post>, ServerError> {
+        let tx = self.db.tx(false)?;
+        let seq = match locate_post_in(&tx, post_id)? {
+            Some((located_chat_id, seq)) if located_chat_id == chat_id => seq,
+            _ => return Ok(None),
+        };
+        let posts_bucket = tx.get_bucket(BUCKET_POSTS)?;
+        match posts_bucket.get_bucket(&chat_id.to_le_bytes()) {
+            Ok(chat_bucket) => Ok(chat_bucket
+                .get_kv(&post_key(seq))
+                .and_then(|kv| Post::decode(kv.value()).ok())
+                .filter(|post| post.id == post_id)
+                .map(|post| Post { seq, ..post })),
+            Err(jammdb::Error::BucketMissing) => Ok(None),
             Err(e) => Err(e.into()),
         }
     }
 
-    fn remove_chat_posts(&self, id: ChatId) -> Result<(), InternalError> {
-        match self.db.tx(true) {
-            Ok(tx) => match tx.get_bucket(BUCKET_POSTS) {
-                Ok(posts_bucket) => posts_bucket
-                    .delete_bucket(&id.to_le_bytes())
-                    .and(tx.commit())
-                    .map_err(|e| e.into()),
-                Err(e) => Err(e.into()),
+    // returns false if the post was not found, the stale index entry is dropped anyway
+    fn remove_post(&self, chat_id: ChatId, post_id: PostId) -> Result<bool, ServerError> {
+        let tx = self.db.tx(true)?;
+        let seq = match locate_post_in(&tx, post_id)? {
+            Some((located_chat_id, seq)) if located_chat_id == chat_id => seq,
+            _ => return Ok(false),
+        };
+        let posts_bucket = tx.get_bucket(BUCKET_POSTS)?;
+        let removed = match posts_bucket.get_bucket(&chat_id.to_le_bytes()) {
+            Ok(chat_bucket) => match chat_bucket.delete(&post_key(seq)) {
+                Ok(_) => true,
+                Err(jammdb::Error::KeyValueMissing) => false,
+                Err(e) => return Err(e.into()),
             },
-            Err(e) => Err(e.into()),
+            Err(jammdb::Error::BucketMissing) => false,
+            Err(e) => return Err(e.into()),
+        };
+        tx.get_bucket(BUCKET_POST_INDEX)?
+            .delete(&post_id.to_le_bytes())?;
+        if removed {
+            adjust_post_count(&tx, chat_id, -1)?;
         }
+        tx.commit()?;
+        Ok(removed)
     }
-}
 
-#[cfg(test)]
-mod tests {
+    // the record is rewritten under its key, neither the index nor the counter change
+    fn update_post(&self, post: &Post) -> Result<bool, ServerError> {
+        let tx = self.db.tx(true)?;
+        let seq = match locate_post_in(&tx, post.id)? {
+            Some((located_chat_id, seq)) if located_chat_id == post.chat_id => seq,
+            _ => return Ok(false),
+        };
+        let posts_bucket = tx.get_bucket(BUCKET_POSTS)?;
+        let chat_bucket = match posts_bucket.get_bucket(&post.chat_id.to_le_bytes()) {
+            Ok(chat_bucket) => chat_bucket,
+            Err(jammdb::Error::BucketMissing) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        if chat_bucket.get_kv(&post_key(seq)).is_none() {
+            return Ok(false);
+        }
+        chat_bucket.put(&post_key(seq), encode(post)?)?;
+        tx.commit()?;
+        Ok(true)
+    }
 
-    use super::*;
+    // finds the chat's post records which can't be decoded or belong to another chat
+    // and the index entries not matching the posts, `repair` removes the records,
+    // fixes the index and rebuilds the posts of a degraded chat;
+    // returns the count of found discrepancies
+    fn verify_chat_posts(&self, chat_id: ChatId, repair: bool) -> Result<usize, ServerError> {
+        let degraded = self.is_degraded(chat_id)?;
+        let mut unreadable = false;
+        let broken: Vec<Vec<u8>> = match self.db.tx(false) {
+            Ok(tx) => match tx.get_bucket(BUCKET_POSTS) {
+                Ok(posts_bucket) => match posts_bucket.get_bucket(&chat_id.to_le_bytes()) {
+                    Ok(chat_bucket) => chat_bucket
+                        .kv_pairs()
+                        .filter(|pair| {
+                            Post::decode(pair.value())
+                                .map(|post| post.chat_id != chat_id)
+                                .unwrap_or(true)
+                        })
+                        .map(|pair| pair.key().to_vec())
+                        .collect(),
+                    Err(jammdb::Error::BucketMissing) => Vec::new(),
+                    Err(e) => {
+                        error!("posts of chat {} are unreadable, {}", chat_id, e);
+                        unreadable = true;
+                        Vec::new()
+                    }
+                },
+                Err(e) => return Err(e.into()),
+            },
+            Err(e) => return Err(e.into()),
+        };
+        // a salvaged chat is reindexed anyway
+        let unindexed = if unreadable {
+            0
+        } else {
+            sync_post_index(&self.db.tx(false)?, chat_id, false)?
+        };
+        let found = broken.len() + (degraded || unreadable) as usize + unindexed;
+        if !repair {
+            if unreadable {
+                self.set_degraded(chat_id, true)?;
+            }
+        } else if degraded || unreadable {
+            self.salvage_chat_posts(chat_id)?;
+        } else if !broken.is_empty() {
+            let tx = self.db.tx(true)?;
+            let posts_bucket = tx.get_bucket(BUCKET_POSTS)?;
+            let chat_bucket = posts_bucket.get_bucket(&chat_id.to_le_bytes())?;
+            for key in &broken {
+                chat_bucket.delete(key)?;
+            }
+            write_post_count(&tx, chat_id, count_chat_records(&tx, chat_id)?)?;
+            tx.commit()?;
+        }
+        if repair && !(degraded || unreadable) && unindexed > 0 {
+            let tx = self.db.tx(true)?;
+            sync_post_index(&tx, chat_id, true)?;
+            tx.commit()?;
+        }
+        Ok(found)
+    }
 
-    const TEST_DB: &str = "migchat-test-storage.db";
-    const BUCKET_ROOT: &str = "ROOT";
+    // rebuilds the chat's posts bucket out of its decodable posts keeping their order
+    // and clears the degraded mark; returns the count of salvaged posts
+    fn salvage_chat_posts(&self, chat_id: ChatId) -> Result<usize, ServerError> {
+        let key = chat_id.to_le_bytes();
+        let tx = self.db.tx(true)?;
+        let posts_bucket = tx.get_bucket(BUCKET_POSTS)?;
+        let posts: Vec<Post> = match posts_bucket.get_bucket(&key) {
+            Ok(chat_bucket) => chat_bucket
+                .kv_pairs()
+                .filter_map(|pair| Post::decode(pair.value()).ok())
+                .filter(|post| post.chat_id == chat_id)
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        match posts_bucket.delete_bucket(&key) {
+            Ok(_) | Err(jammdb::Error::BucketMissing) => {}
+            // the key holds a value instead of the bucket
+            Err(_) => {
+                posts_bucket.delete(&key)?;
+            }
+        }
+        let chat_bucket = posts_bucket.create_bucket(&key)?;
+        for post in &posts {
+            let k = chat_bucket.next_int();
+            chat_bucket.put(&post_key(k), encode(post)?)?;
+        }
+        sync_post_index(&tx, chat_id, true)?;
+        write_post_count(&tx, chat_id, posts.len())?;
+        tx.commit()?;
+        info!("salvaged {} posts of chat {}", posts.len(), chat_id);
+        self.set_degraded(chat_id, false)?;
+        Ok(posts.len())
+    }
 
-    #[test]
+    // the keys are big-endian sequence numbers, the posts before the mark are not decoded
+    fn count_posts_after(
+        &self,
+        chat_id: ChatId,
+        seq: u64,
+        user_id: UserId,
+    ) -> Result<usize, ServerError> {
+        let tx = self.db.tx(false)?;
+        let chat_bucket = match tx
+            .get_bucket(BUCKET_POSTS)?
+            .get_bucket(&chat_id.to_le_bytes())
+        {
+            Ok(chat_bucket) => chat_bucket,
+            Err(jammdb::Error::BucketMissing) => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let count = chat_bucket
+            .kv_pairs()
+            .filter(|pair| key_bytes(pair.key()).map_or(false, |key| u64::from_be_bytes(key) > seq))
+            .filter_map(|pair| Post::decode(pair.value()).ok())
+            .filter(|post| post.user_id != user_id && post.user_id != NOT_USER_ID)
+            .count();
+        Ok(count)
+    }
+
+    // operations with read marks
+
+    fn read_read_mark(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+    ) -> Result<Option<(PostId, u64)>, ServerError> {
+        let tx = self.db.tx(false)?;
+        let mark = tx
+            .get_bucket(BUCKET_READ_MARKS)?
+            .get_kv(&chat_user_key(chat_id, user_id))
+            .and_then(|kv| parse_read_mark(kv.value()));
+        Ok(mark)
+    }
+
+    // compared and written in the same transaction not to go back on the concurrent calls
+    fn advance_read_mark(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        post_id: PostId,
+        seq: u64,
+    ) -> Result<bool, ServerError> {
+        let tx = self.db.tx(true)?;
+        let marks = tx.get_bucket(BUCKET_READ_MARKS)?;
+        let key = chat_user_key(chat_id, user_id);
+        let stored = marks
+            .get_kv(&key)
+            .and_then(|kv| parse_read_mark(kv.value()));
+        if stored.map_or(false, |(_, stored_seq)| stored_seq >= seq) {
+            return Ok(false);
+        }
+        marks.put(&key, read_mark_value(post_id, seq))?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    // operations with mutes
+
+    fn is_chat_muted(&self, chat_id: ChatId, user_id: UserId) -> Result<bool, ServerError> {
+        let tx = self.db.tx(false)?;
+        let muted = tx
+            .get_bucket(BUCKET_MUTES)?
+            .get_kv(&chat_user_key(chat_id, user_id))
+            .is_some();
+        Ok(muted)
+    }
+
+    fn set_chat_muted(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        muted: bool,
+    ) -> Result<(), ServerError> {
+        let tx = self.db.tx(true)?;
+        let mutes = tx.get_bucket(BUCKET_MUTES)?;
+        let key = chat_user_key(chat_id, user_id);
+        if muted {
+            mutes.put(&key, BytesMut::new())?;
+        } else {
+            match mutes.delete(&key) {
+                Ok(_) | Err(jammdb::Error::KeyValueMissing) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // operations with bans
+
+    fn is_banned(&self, chat_id: ChatId, user_id: UserId) -> Result<bool, ServerError> {
+        let tx = self.db.tx(false)?;
+        let banned = tx
+            .get_bucket(BUCKET_BANS)?
+            .get_kv(&chat_user_key(chat_id, user_id))
+            .is_some();
+        Ok(banned)
+    }
+
+    fn set_banned(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        banned: bool,
+    ) -> Result<(), ServerError> {
+        let tx = self.db.tx(true)?;
+        let bans = tx.get_bucket(BUCKET_BANS)?;
+        let key = chat_user_key(chat_id, user_id);
+        if banned {
+            bans.put(&key, BytesMut::new())?;
+        } else {
+            match bans.delete(&key) {
+                Ok(_) | Err(jammdb::Error::KeyValueMissing) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // operations with attachments
+
+    // the id is checked and the whole content is written in the same transaction
+    fn write_attachment(&self, info: &AttachmentInfo, content: &[u8]) -> Result<bool, ServerError> {
+        let tx = self.db.tx(true)?;
+        let attachments = tx.get_bucket(BUCKET_ATTACHMENTS)?;
+        if attachments.get_kv(&attachment_key(info.id)).is_some() {
+            return Ok(false);
+        }
+        attachments.put(&attachment_key(info.id), encode(info)?)?;
+        for (idx, chunk) in content.chunks(ATTACHMENT_CHUNK_LEN).enumerate() {
+            attachments.put(
+                &attachment_chunk_key(info.id, idx as u32),
+                BytesMut::from(chunk),
+            )?;
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn read_attachment_info(
+        &self,
+        id: AttachmentId,
+    ) -> Result<Option<AttachmentInfo>, ServerError> {
+        let tx = self.db.tx(false)?;
+        match tx
+            .get_bucket(BUCKET_ATTACHMENTS)?
+            .get_kv(&attachment_key(id))
+        {
+            Some(kv) => Ok(Some(AttachmentInfo::decode(kv.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn read_attachment_chunk(
+        &self,
+        id: AttachmentId,
+        idx: usize,
+    ) -> Result<Option<Vec<u8>>, ServerError> {
+        let tx = self.db.tx(false)?;
+        let chunk = tx
+            .get_bucket(BUCKET_ATTACHMENTS)?
+            .get_kv(&attachment_chunk_key(id, idx as u32))
+            .map(|kv| kv.value().to_vec());
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::fixtures::Fixture;
+    use crate::proto::{PostKind, PostReaction};
+    use proptest::prelude::*;
+
+    const TEST_DB: &str = "migchat-test-storage.db";
+    const TEST_DB_GARBAGE: &str = "migchat-test-storage-garbage.db";
+    const TEST_DB_ROUND_TRIP: &str = "migchat-test-storage-round-trip.db";
+    const TEST_DB_VERIFY: &str = "migchat-test-storage-verify.db";
+    const TEST_DB_VERIFY_SLICES: &str = "migchat-test-storage-verify-slices.db";
+    const TEST_DB_DEGRADED: &str = "migchat-test-storage-degraded.db";
+    const TEST_DB_REPLACED: &str = "migchat-test-storage-replaced.db";
+    const TEST_DB_INVITATIONS: &str = "migchat-test-storage-invitations.db";
+    const TEST_DB_MIGRATIONS: &str = "migchat-test-storage-migrations.db";
+    const TEST_DB_MIGRATION_FAILED: &str = "migchat-test-storage-migration-failed.db";
+    const TEST_DB_MIGRATION_NEWER: &str = "migchat-test-storage-migration-newer.db";
+    const TEST_DB_POST_ORDER: &str = "migchat-test-storage-post-order.db";
+    const TEST_DB_POST_KEYS: &str = "migchat-test-storage-post-keys.db";
+    const TEST_DB_POST_INDEX: &str = "migchat-test-storage-post-index.db";
+    const TEST_DB_POST_COUNTS: &str = "migchat-test-storage-post-counts.db";
+    const TEST_DB_USER_CHATS: &str = "migchat-test-storage-user-chats.db";
+    const TEST_DB_READ_MARKS: &str = "migchat-test-storage-read-marks.db";
+    const TEST_DB_UPDATE_POST: &str = "migchat-test-storage-update-post.db";
+    const TEST_DB_ATTACHMENTS: &str = "migchat-test-storage-attachments.db";
+    const TEST_DB_UPDATE_USER: &str = "migchat-test-storage-update-user.db";
+    const TEST_DB_SEARCH: &str = "migchat-test-storage-search.db";
+    const TEST_DB_BACKUP: &str = "migchat-test-storage-backup.db";
+    const TEST_DB_BACKUP_SNAPSHOT: &str = "migchat-test-storage-backup-snapshot.db";
+    const BUCKET_ROOT: &str = "ROOT";
+
+    // puts raw bytes into the bucket bypassing protobuf encoding
+    fn plant_raw(storage: &Storage, bucket_name: &str, id: &[u8], blob: &[u8]) {
+        let tx = storage.db.tx(true).unwrap();
+        let bucket = tx.get_bucket(bucket_name).unwrap();
+        bucket.put(id, BytesMut::from(blob)).unwrap();
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn test_encode_reuses_buffer() {
+        let user = User {
+            id: 1,
+            name: String::from("User Name"),
+            short_name: String::from("user"),
+            created: 0,
+        };
+        let chat = Chat {
+            id: 2,
+            permanent: true,
+            description: String::from("chat"),
+            users: vec![1, 2, 3],
+            created: 0,
+            owner_id: 1,
+            export_discouraged: false,
+        };
+        // both results are alive at the same time and must not overlap
+        let user_bin = encode(&user).unwrap();
+        let chat_bin = encode(&chat).unwrap();
+        assert_eq!(User::decode(user_bin).unwrap(), user);
+        assert_eq!(Chat::decode(chat_bin).unwrap(), chat);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn decode_garbage_never_panics(blob in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let _ = std::fs::remove_file(TEST_DB_GARBAGE);
+            {
+                let storage = Storage::new(TEST_DB_GARBAGE).unwrap();
+                plant_raw(&storage, BUCKET_USERS, &1u64.to_le_bytes(), &blob);
+                plant_raw(&storage, BUCKET_CHATS, &1u64.to_le_bytes(), &blob);
+                // either parsed or reported, never panics
+                let _ = storage.read_user(1);
+                let _ = storage.read_chat(1);
+                // corrupted records are skipped by bulk reads
+                prop_assert!(storage.read_all_users().is_ok());
+                prop_assert!(storage.read_all_chats().is_ok());
+            }
+            let _ = std::fs::remove_file(TEST_DB_GARBAGE);
+        }
+
+        #[test]
+        fn user_chat_round_trip(
+            id in 1u64..,
+            name in "\\PC{0,64}",
+            short_name in "\\PC{0,16}",
+            users in proptest::collection::vec(any::<u64>(), 0..16),
+            permanent in any::<bool>(),
+            created in any::<u64>(),
+        ) {
+            let _ = std::fs::remove_file(TEST_DB_ROUND_TRIP);
+            {
+                let storage = Storage::new(TEST_DB_ROUND_TRIP).unwrap();
+                let user = User {
+                    id,
+                    name: name.clone(),
+                    short_name,
+                    created,
+                };
+                storage.write_user(id, &user).unwrap();
+                prop_assert_eq!(storage.read_user(id).unwrap(), Some(user));
+                let chat = Chat {
+                    id,
+                    permanent,
+                    description: name,
+                    users,
+                    created,
+                    owner_id: id,
+                    export_discouraged: permanent,
+                };
+                storage.write_chat(id, &chat).unwrap();
+                prop_assert_eq!(storage.read_chat(id).unwrap(), Some(chat));
+            }
+            let _ = std::fs::remove_file(TEST_DB_ROUND_TRIP);
+        }
+
+        #[test]
+        fn post_round_trip(
+            id in any::<u64>(),
+            chat_id in any::<u64>(),
+            user_id in any::<u64>(),
+            text in "\\PC{0,256}",
+            created in any::<u64>(),
+            author_name in "\\PC{0,32}",
+            reacted in proptest::collection::vec(any::<u64>(), 0..8),
+            reply_to_post_id in any::<u64>(),
+        ) {
+            let post = Post {
+                id,
+                chat_id,
+                user_id,
+                text,
+                attachments: Vec::new(),
+                created,
+                author_name,
+                reactions: vec![PostReaction {
+                    emoji: String::from("👍"),
+                    user_ids: reacted,
+                }],
+                reply_to_post_id,
+                forwarded_from: None,
+                idempotency_key: String::new(),
+                seq: 0,
+                kind: PostKind::Regular as i32,
+            };
+            let mut buf = BytesMut::new();
+            post.encode(&mut buf).unwrap();
+            prop_assert_eq!(Post::decode(buf).unwrap(), post);
+        }
+    }
+
+    #[test]
     fn test_write_post() {
         let _ = std::fs::remove_file(TEST_DB);
         {
@@ -417,6 +2173,13 @@ mod tests {
                 text: String::from("text"),
                 attachments: Vec::new(),
                 created: 0,
+                author_name: String::new(),
+                reactions: Vec::new(),
+                reply_to_post_id: 0,
+                forwarded_from: None,
+                idempotency_key: String::new(),
+                seq: 0,
+                kind: PostKind::Regular as i32,
             };
             let res = storage.write_post(&post);
             assert!(res.is_ok());
@@ -424,6 +2187,1359 @@ mod tests {
         let _ = std::fs::remove_file(TEST_DB);
     }
 
+    #[test]
+    fn test_verify_chat_posts() {
+        let _ = std::fs::remove_file(TEST_DB_VERIFY);
+        {
+            let storage = Storage::new(TEST_DB_VERIFY).unwrap();
+            let mut post = Post {
+                id: 1,
+                chat_id: 2,
+                user_id: 3,
+                text: String::from("text"),
+                attachments: Vec::new(),
+                created: 0,
+                author_name: String::new(),
+                reactions: Vec::new(),
+                reply_to_post_id: 0,
+                forwarded_from: None,
+                idempotency_key: String::new(),
+                seq: 0,
+                kind: PostKind::Regular as i32,
+            };
+            storage.write_post(&post).unwrap();
+            // plant a post of another chat and a garbage record into the chat's bucket
+            post.id = 2;
+            post.chat_id = 5;
+            {
+                let tx = storage.db.tx(true).unwrap();
+                let posts_bucket = tx.get_bucket(BUCKET_POSTS).unwrap();
+                let chat_bucket = posts_bucket.get_bucket(&2u64.to_le_bytes()).unwrap();
+                chat_bucket
+                    .put(&post_key(100), encode(&post).unwrap())
+                    .unwrap();
+                chat_bucket
+                    .put(&post_key(101), BytesMut::from(&[0xffu8; 3][..]))
+                    .unwrap();
+                tx.commit().unwrap();
+            }
+            // report only
+            assert_eq!(storage.verify_chat_posts(2, false).unwrap(), 2);
+            assert_eq!(storage.verify_chat_posts(2, false).unwrap(), 2);
+            // repair keeps the valid post
+            assert_eq!(storage.verify_chat_posts(2, true).unwrap(), 2);
+            assert_eq!(storage.verify_chat_posts(2, false).unwrap(), 0);
+            assert_eq!(storage.chat_posts_count(2).unwrap(), 1);
+            assert!(storage.read_post(2, 1).unwrap().is_some());
+            // chat without posts
+            assert_eq!(storage.verify_chat_posts(7, true).unwrap(), 0);
+        }
+        let _ = std::fs::remove_file(TEST_DB_VERIFY);
+    }
+
+    impl Storage {
+        // puts raw bytes into the chat's posts bucket bypassing protobuf encoding
+        pub fn plant_raw_post(&self, chat_id: ChatId, key: u64, blob: &[u8]) {
+            let tx = self.db.tx(true).unwrap();
+            let posts_bucket = tx.get_bucket(BUCKET_POSTS).unwrap();
+            let chat_bucket = posts_bucket
+                .get_or_create_bucket(&chat_id.to_le_bytes())
+                .unwrap();
+            chat_bucket
+                .put(&post_key(key), BytesMut::from(blob))
+                .unwrap();
+            let count = count_chat_records(&tx, chat_id).unwrap();
+            write_post_count(&tx, chat_id, count).unwrap();
+            tx.commit().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_degraded_chat_salvage() {
+        let _ = std::fs::remove_file(TEST_DB_DEGRADED);
+        {
+            let storage = Storage::new(TEST_DB_DEGRADED).unwrap();
+            let post = |id| Post {
+                id,
+                chat_id: 2,
+                user_id: 3,
+                text: format!("post {}", id),
+                attachments: Vec::new(),
+                created: 0,
+                author_name: String::new(),
+                reactions: Vec::new(),
+                reply_to_post_id: 0,
+                forwarded_from: None,
+                idempotency_key: String::new(),
+                seq: 0,
+                kind: PostKind::Regular as i32,
+            };
+            storage.write_post(&post(1)).unwrap();
+            storage.write_post(&post(2)).unwrap();
+            // a few broken records are tolerated
+            storage.plant_raw_post(2, 100, &[0xff; 3]);
+            assert_eq!(storage.read_chat_posts(2, 0, 100).unwrap().len(), 2);
+            assert!(!storage.is_degraded(2).unwrap());
+            for key in 101..100 + DAMAGE_THRESHOLD as u64 {
+                storage.plant_raw_post(2, key, &[0xff; 3]);
+            }
+            storage.plant_raw_post(2, 200, &encode(&post(3)).unwrap());
+            // decodable posts are still readable
+            let ids = |posts: Vec<Post>| posts.iter().map(|p| p.id).collect::<Vec<PostId>>();
+            assert_eq!(
+                ids(storage.read_chat_posts(2, 0, 100).unwrap()),
+                vec![1, 2, 3]
+            );
+            assert!(storage.is_degraded(2).unwrap());
+            // report only
+            assert_eq!(
+                storage.verify_chat_posts(2, false).unwrap(),
+                DAMAGE_THRESHOLD + 1
+            );
+            assert!(storage.is_degraded(2).unwrap());
+            // repair salvages decodable posts in order
+            assert_eq!(
+                storage.verify_chat_posts(2, true).unwrap(),
+                DAMAGE_THRESHOLD + 1
+            );
+            assert!(!storage.is_degraded(2).unwrap());
+            assert_eq!(storage.chat_posts_count(2).unwrap(), 3);
+            assert_eq!(
+                ids(storage.read_chat_posts(2, 0, 100).unwrap()),
+                vec![1, 2, 3]
+            );
+            // new posts follow the salvaged ones
+            storage.write_post(&post(4)).unwrap();
+            assert_eq!(
+                ids(storage.read_chat_posts(2, 0, 100).unwrap()),
+                vec![1, 2, 3, 4]
+            );
+            assert_eq!(storage.verify_chat_posts(2, false).unwrap(), 0);
+        }
+        let _ = std::fs::remove_file(TEST_DB_DEGRADED);
+    }
+
+    #[test]
+    fn test_posts_bucket_replaced() {
+        let _ = std::fs::remove_file(TEST_DB_REPLACED);
+        {
+            let storage = Storage::new(TEST_DB_REPLACED).unwrap();
+            let post = Post {
+                id: 1,
+                chat_id: 2,
+                user_id: 3,
+                text: String::from("text"),
+                attachments: Vec::new(),
+                created: 0,
+                author_name: String::new(),
+                reactions: Vec::new(),
+                reply_to_post_id: 0,
+                forwarded_from: None,
+                idempotency_key: String::new(),
+                seq: 0,
+                kind: PostKind::Regular as i32,
+            };
+            // the chat's posts bucket is a value
+            plant_raw(
+                &storage,
+                BUCKET_POSTS,
+                &2u64.to_le_bytes(),
+                &encode(&post).unwrap(),
+            );
+            assert!(storage.write_post(&post).is_err());
+            assert!(storage.is_degraded(2).unwrap());
+            assert!(storage.read_chat_posts(2, 0, 10).unwrap().is_empty());
+            // a fresh bucket accepts posts again
+            assert_eq!(storage.salvage_chat_posts(2).unwrap(), 0);
+            assert!(!storage.is_degraded(2).unwrap());
+            storage.write_post(&post).unwrap();
+            assert_eq!(storage.read_chat_posts(2, 0, 10).unwrap(), vec![post]);
+        }
+        let _ = std::fs::remove_file(TEST_DB_REPLACED);
+    }
+
+    #[test]
+    fn test_read_chats_after() {
+        let _ = std::fs::remove_file(TEST_DB_VERIFY_SLICES);
+        {
+            let storage = Storage::new(TEST_DB_VERIFY_SLICES).unwrap();
+            let mut seeded = Fixture::new(1)
+                .users(4)
+                .chats(5, |c| c.posts(0..1))
+                .seed(&storage)
+                .unwrap();
+            let mut seen = Vec::new();
+            let mut after = None;
+            loop {
+                let chats = storage.read_chats_after(after, 2).unwrap();
+                assert!(chats.len() <= 2);
+                if chats.is_empty() {
+                    break;
+                }
+                after = chats.last().map(|chat| chat.id);
+                seen.extend(chats.into_iter().map(|chat| chat.id));
+            }
+            seen.sort_unstable();
+            seeded.chats.sort_unstable();
+            assert_eq!(seen, seeded.chats);
+            // service metadata
+            storage.write_meta("key", b"value").unwrap();
+            assert_eq!(storage.read_meta("key").unwrap(), Some(b"value".to_vec()));
+            assert_eq!(storage.read_meta("missing").unwrap(), None);
+        }
+        let _ = std::fs::remove_file(TEST_DB_VERIFY_SLICES);
+    }
+
+    #[test]
+    fn test_invitations() {
+        let _ = std::fs::remove_file(TEST_DB_INVITATIONS);
+        {
+            let storage = Storage::new(TEST_DB_INVITATIONS).unwrap();
+            let invitation = |chat_id, from_user_id, to_user_id| Invitation {
+                chat_id,
+                from_user_id,
+                to_user_id,
+                declined: false,
+            };
+            storage.write_invitation(&invitation(10, 1, 2)).unwrap();
+            storage.write_invitation(&invitation(10, 3, 2)).unwrap();
+            storage.write_invitation(&invitation(20, 1, 2)).unwrap();
+            storage.write_invitation(&invitation(20, 1, 3)).unwrap();
+            assert_eq!(storage.read_invitations_to(2).unwrap().len(), 3);
+            assert!(storage.remove_invitation(&invitation(20, 1, 2)).unwrap());
+            assert!(!storage.remove_invitation(&invitation(20, 1, 2)).unwrap());
+            // entering the chat answers all invitations there
+            assert_eq!(storage.remove_invitations_to(10, 2).unwrap(), 2);
+            assert!(storage.read_invitations_to(2).unwrap().is_empty());
+            assert_eq!(
+                storage.read_invitations_to(3).unwrap(),
+                vec![invitation(20, 1, 3)]
+            );
+            // replies are delivered once
+            let reply = Invitation {
+                declined: true,
+                ..invitation(20, 1, 3)
+            };
+            storage.write_invitation_reply(&reply).unwrap();
+            assert!(storage.take_invitation_replies(3).unwrap().is_empty());
+            assert_eq!(storage.take_invitation_replies(1).unwrap(), vec![reply]);
+            assert!(storage.take_invitation_replies(1).unwrap().is_empty());
+        }
+        let _ = std::fs::remove_file(TEST_DB_INVITATIONS);
+    }
+
+    #[test]
+    fn test_read_marks() {
+        let _ = std::fs::remove_file(TEST_DB_READ_MARKS);
+        {
+            let storage = Storage::new(TEST_DB_READ_MARKS).unwrap();
+            // the system notice is never unread
+            for (id, user_id) in [(101, 1), (102, 2), (103, 1), (104, NOT_USER_ID)].iter() {
+                let post = Post {
+                    id: *id,
+                    chat_id: 10,
+                    user_id: *user_id,
+                    ..Default::default()
+                };
+                assert!(storage.write_post(&post).unwrap());
+            }
+            let seq = |post_id| storage.locate_post(post_id).unwrap().unwrap().1;
+            assert!(storage.read_read_mark(10, 2).unwrap().is_none());
+            assert!(storage.advance_read_mark(10, 2, 102, seq(102)).unwrap());
+            // never back
+            assert!(!storage.advance_read_mark(10, 2, 101, seq(101)).unwrap());
+            assert!(!storage.advance_read_mark(10, 2, 102, seq(102)).unwrap());
+            assert_eq!(
+                storage.read_read_mark(10, 2).unwrap(),
+                Some((102, seq(102)))
+            );
+            assert_eq!(storage.count_posts_after(10, seq(102), 2).unwrap(), 1);
+            assert_eq!(storage.count_posts_after(10, seq(101), 1).unwrap(), 1);
+            assert!(storage.advance_read_mark(10, 1, 103, seq(103)).unwrap());
+            // the marks go with the chat
+            storage
+                .write_chat(
+                    10,
+                    &Chat {
+                        id: 10,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            storage.remove_chat(10).unwrap();
+            assert!(storage.read_read_mark(10, 1).unwrap().is_none());
+            assert!(storage.read_read_mark(10, 2).unwrap().is_none());
+        }
+        let _ = std::fs::remove_file(TEST_DB_READ_MARKS);
+    }
+
+    #[test]
+    fn test_update_post() {
+        let _ = std::fs::remove_file(TEST_DB_UPDATE_POST);
+        {
+            let storage = Storage::new(TEST_DB_UPDATE_POST).unwrap();
+            for seq in 0..3 {
+                assert!(storage.write_post(&ordered_post(seq)).unwrap());
+            }
+            let mut post = ordered_post(1);
+            post.reactions.push(PostReaction {
+                emoji: String::from("👍"),
+                user_ids: vec![3, 4],
+            });
+            assert!(storage.update_post(&post).unwrap());
+            assert_eq!(storage.read_post(2, post.id).unwrap(), Some(post.clone()));
+            // in place, nothing is added
+            let posts = storage.read_chat_posts(2, 0, 10).unwrap();
+            assert_eq!(posts, vec![ordered_post(0), post.clone(), ordered_post(2)]);
+            assert_eq!(storage.chat_posts_count(2).unwrap(), 3);
+            // neither the removed post nor the post of another chat is written
+            assert!(storage.remove_post(2, ordered_post(2).id).unwrap());
+            assert!(!storage.update_post(&ordered_post(2)).unwrap());
+            assert!(!storage.update_post(&Post { chat_id: 5, ..post }).unwrap());
+            assert_eq!(storage.chat_posts_count(2).unwrap(), 2);
+        }
+        let _ = std::fs::remove_file(TEST_DB_UPDATE_POST);
+    }
+
+    #[test]
+    fn test_search_chat_posts() {
+        let _ = std::fs::remove_file(TEST_DB_SEARCH);
+        {
+            let storage = Storage::new(TEST_DB_SEARCH).unwrap();
+            let count = SEARCH_PAGE_LEN as u64 + 50;
+            for seq in 0..count {
+                assert!(storage.write_post(&ordered_post(seq)).unwrap());
+            }
+            let created = |posts: Vec<Post>| posts.iter().map(|p| p.created).collect::<Vec<_>>();
+            // the newest first across the pages, regardless of the case
+            let found = storage.search_chat_posts(2, "POST 1", 3).unwrap();
+            assert_eq!(created(found), vec![199, 198, 197]);
+            let found = storage.search_chat_posts(2, "post 30", 100).unwrap();
+            assert_eq!(created(found), vec![305, 304, 303, 302, 301, 300, 30]);
+            assert!(storage.search_chat_posts(2, "none", 9).unwrap().is_empty());
+            assert!(storage.search_chat_posts(5, "post", 10).unwrap().is_empty());
+        }
+        let _ = std::fs::remove_file(TEST_DB_SEARCH);
+    }
+
+    #[test]
+    fn test_backup_while_writing() {
+        use std::sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        };
+        const FIRST_WRITTEN: PostId = 1_000_000;
+        let _ = std::fs::remove_file(TEST_DB_BACKUP);
+        let _ = std::fs::remove_file(TEST_DB_BACKUP_SNAPSHOT);
+        {
+            let storage = Arc::new(Storage::new(TEST_DB_BACKUP).unwrap());
+            let seeded = Fixture::new(1562)
+                .users(4)
+                .chats(3, |c| c.members(2..4).posts(20..40))
+                .seed(&*storage)
+                .unwrap();
+            let stop = Arc::new(AtomicBool::new(false));
+            let writer = {
+                let (storage, stop) = (storage.clone(), stop.clone());
+                let (chats, user_id) = (seeded.chats.clone(), seeded.users[0]);
+                std::thread::spawn(move || {
+                    let mut written = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        let post = Post {
+                            id: FIRST_WRITTEN + written as u64,
+                            chat_id: chats[written % chats.len()],
+                            user_id,
+                            text: format!("written {}", written),
+                            ..Default::default()
+                        };
+                        assert!(storage.write_post(&post).unwrap());
+                        written += 1;
+                    }
+                    written
+                })
+            };
+            while storage.locate_post(FIRST_WRITTEN).unwrap().is_none() {
+                std::thread::yield_now();
+            }
+            storage.backup(Path::new(TEST_DB_BACKUP_SNAPSHOT)).unwrap();
+            stop.store(true, Ordering::Relaxed);
+            let written = writer.join().unwrap();
+
+            let snapshot = Storage::new(TEST_DB_BACKUP_SNAPSHOT).unwrap();
+            assert_eq!(snapshot.read_all_users().unwrap().len(), 4);
+            let mut copied = 0;
+            for chat_id in &seeded.chats {
+                let count = snapshot.chat_posts_count(*chat_id).unwrap();
+                let posts = snapshot.read_chat_posts(*chat_id, 0, count).unwrap();
+                assert_eq!(posts.len(), count);
+                assert_eq!(snapshot.verify_chat_posts(*chat_id, false).unwrap(), 0);
+                for post in &posts {
+                    assert!(snapshot.locate_post(post.id).unwrap().is_some());
+                }
+                copied += count;
+            }
+            // the moment between some of the writes
+            assert!(copied > seeded.posts);
+            assert!(copied <= seeded.posts + written);
+            // the snapshot goes on without overwriting the copied posts
+            let chat_id = seeded.chats[0];
+            let count = snapshot.chat_posts_count(chat_id).unwrap();
+            let post = Post {
+                id: 2 * FIRST_WRITTEN,
+                chat_id,
+                user_id: seeded.users[0],
+                text: String::from("after the snapshot"),
+                ..Default::default()
+            };
+            assert!(snapshot.write_post(&post).unwrap());
+            assert_eq!(snapshot.chat_posts_count(chat_id).unwrap(), count + 1);
+            assert_eq!(snapshot.verify_chat_posts(chat_id, false).unwrap(), 0);
+            let last = snapshot.read_chat_posts(chat_id, count, 1).unwrap();
+            assert_eq!(last[0].id, post.id);
+        }
+        let _ = std::fs::remove_file(TEST_DB_BACKUP);
+        let _ = std::fs::remove_file(TEST_DB_BACKUP_SNAPSHOT);
+    }
+
+    #[test]
+    fn test_update_user() {
+        let _ = std::fs::remove_file(TEST_DB_UPDATE_USER);
+        {
+            let storage = Storage::new(TEST_DB_UPDATE_USER).unwrap();
+            let user = User {
+                id: 1,
+                name: String::from("User Name"),
+                short_name: String::from("user"),
+                created: 1,
+            };
+            storage.write_user(user.id, &user).unwrap();
+            // the updater declines, nothing is written
+            let unchanged = storage
+                .update_user(user.id, |u| {
+                    u.short_name = String::from("dropped");
+                    false
+                })
+                .unwrap();
+            assert_eq!(unchanged.unwrap().short_name, "dropped");
+            assert_eq!(storage.read_user(user.id).unwrap(), Some(user.clone()));
+            let updated = storage
+                .update_user(user.id, |u| {
+                    u.short_name = String::from("renamed");
+                    true
+                })
+                .unwrap();
+            let renamed = User {
+                short_name: String::from("renamed"),
+                ..user
+            };
+            assert_eq!(updated, Some(renamed.clone()));
+            assert_eq!(storage.read_user(1).unwrap(), Some(renamed));
+            assert_eq!(storage.update_user(2, |_| true).unwrap(), None);
+            // the short names follow the renaming
+            assert_eq!(storage.find_user_id("renamed").unwrap(), Some(1));
+            assert_eq!(storage.find_user_id("user").unwrap(), None);
+            // the name indexed for another user meanwhile is not released
+            let other = User {
+                id: 2,
+                short_name: String::from("renamed"),
+                ..Default::default()
+            };
+            storage.write_user(other.id, &other).unwrap();
+            storage
+                .update_user(1, |u| {
+                    u.short_name = String::from("again");
+                    true
+                })
+                .unwrap();
+            assert_eq!(storage.find_user_id("renamed").unwrap(), Some(2));
+            assert_eq!(storage.find_user_id("again").unwrap(), Some(1));
+            assert_eq!(storage.find_user_id("").unwrap(), None);
+        }
+        let _ = std::fs::remove_file(TEST_DB_UPDATE_USER);
+    }
+
+    #[test]
+    fn test_attachments() {
+        let _ = std::fs::remove_file(TEST_DB_ATTACHMENTS);
+        {
+            let storage = Storage::new(TEST_DB_ATTACHMENTS).unwrap();
+            let content: Vec<u8> = (0..2 * ATTACHMENT_CHUNK_LEN + 1)
+                .map(|i| (i % 251) as u8)
+                .collect();
+            let info = AttachmentInfo {
+                id: 7,
+                name: String::from("file.bin"),
+                size: content.len() as u64,
+            };
+            assert!(storage.write_attachment(&info, &content).unwrap());
+            // the id is taken, the content is kept
+            assert!(!storage.write_attachment(&info, b"other").unwrap());
+            assert_eq!(storage.read_attachment_info(7).unwrap(), Some(info));
+            let mut read = Vec::new();
+            let mut idx = 0;
+            while let Some(chunk) = storage.read_attachment_chunk(7, idx).unwrap() {
+                assert!(chunk.len() <= ATTACHMENT_CHUNK_LEN);
+                read.extend_from_slice(&chunk);
+                idx += 1;
+            }
+            assert_eq!(idx, 3);
+            assert_eq!(read, content);
+            // the empty one has no chunks
+            let empty = AttachmentInfo {
+                id: 8,
+                name: String::from("empty"),
+                size: 0,
+            };
+            assert!(storage.write_attachment(&empty, &[]).unwrap());
+            assert_eq!(storage.read_attachment_info(8).unwrap(), Some(empty));
+            assert!(storage.read_attachment_chunk(8, 0).unwrap().is_none());
+            assert!(storage.read_attachment_info(9).unwrap().is_none());
+        }
+        let _ = std::fs::remove_file(TEST_DB_ATTACHMENTS);
+    }
+
+    fn remove_with_backups(db_file: &str) {
+        let _ = std::fs::remove_file(db_file);
+        for version in 0..=MIGRATIONS.len() {
+            let _ = std::fs::remove_file(backup_path(Path::new(db_file), version));
+        }
+    }
+
+    fn has_bucket(storage: &Storage, bucket_name: &str) -> bool {
+        storage
+            .db
+            .tx(false)
+            .unwrap()
+            .get_bucket(bucket_name)
+            .is_ok()
+    }
+
+    #[test]
+    fn test_migrations() {
+        remove_with_backups(TEST_DB_MIGRATIONS);
+        {
+            // from the empty database
+            let storage = Storage::new(TEST_DB_MIGRATIONS).unwrap();
+            assert_eq!(read_schema_version(&storage.db).unwrap(), MIGRATIONS.len());
+            for bucket_name in &[
+                BUCKET_USERS,
+                BUCKET_CHATS,
+                BUCKET_POSTS,
+                BUCKET_META,
+                BUCKET_INVITATIONS,
+                BUCKET_REPLIES,
+                BUCKET_POST_INDEX,
+                BUCKET_USER_CHATS,
+                BUCKET_READ_MARKS,
+                BUCKET_ATTACHMENTS,
+                BUCKET_USER_NAMES,
+                BUCKET_MUTES,
+                BUCKET_BANS,
+            ] {
+                assert!(has_bucket(&storage, bucket_name), "{}", bucket_name);
+            }
+            // nothing to back up
+            assert!(!backup_path(Path::new(TEST_DB_MIGRATIONS), 0).exists());
+            storage.write_user(1, &User::default()).unwrap();
+            storage.close();
+        }
+        {
+            // the second run changes nothing
+            let storage = Storage::new(TEST_DB_MIGRATIONS).unwrap();
+            assert_eq!(read_schema_version(&storage.db).unwrap(), MIGRATIONS.len());
+            assert!(storage.read_user(1).unwrap().is_some());
+            assert!(!backup_path(Path::new(TEST_DB_MIGRATIONS), MIGRATIONS.len()).exists());
+        }
+        {
+            // the steps tolerate the upgraded data
+            let db = jammdb::DB::open(TEST_DB_MIGRATIONS).unwrap();
+            let tx = db.tx(true).unwrap();
+            for step in MIGRATIONS {
+                (step.apply)(&tx).unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        {
+            // the database of the older schema is backed up and upgraded
+            let storage = Storage::new(TEST_DB_MIGRATIONS).unwrap();
+            storage
+                .write_meta(SCHEMA_VERSION_KEY, &1u32.to_le_bytes())
+                .unwrap();
+            storage.close();
+            let storage = Storage::new(TEST_DB_MIGRATIONS).unwrap();
+            assert_eq!(read_schema_version(&storage.db).unwrap(), MIGRATIONS.len());
+            assert!(storage.read_user(1).unwrap().is_some());
+            assert!(backup_path(Path::new(TEST_DB_MIGRATIONS), 1).exists());
+        }
+        remove_with_backups(TEST_DB_MIGRATIONS);
+    }
+
+    #[test]
+    fn test_migration_failed() {
+        fn create_partial_then_fail(tx: &jammdb::Tx) -> Result<(), ServerError> {
+            create_buckets(tx, &["partial"])?;
+            Err("step failed".into())
+        }
+        let path = Path::new(TEST_DB_MIGRATION_FAILED);
+        remove_with_backups(TEST_DB_MIGRATION_FAILED);
+        {
+            let storage = Storage::open_with(path, &MIGRATIONS[..1]).unwrap();
+            assert_eq!(read_schema_version(&storage.db).unwrap(), 1);
+            storage.close();
+        }
+        {
+            let failing = [
+                Migration {
+                    name: "base",
+                    apply: create_base_buckets,
+                },
+                Migration {
+                    name: "invitations",
+                    apply: create_invitation_buckets,
+                },
+                Migration {
+                    name: "failing",
+                    apply: create_partial_then_fail,
+                },
+            ];
+            let e = Storage::open_with(path, &failing).err().unwrap();
+            assert!(e.to_string().contains("failing"));
+            assert!(backup_path(path, 1).exists());
+        }
+        {
+            // none of the steps is committed
+            let storage = Storage::open_with(path, &MIGRATIONS[..1]).unwrap();
+            assert_eq!(read_schema_version(&storage.db).unwrap(), 1);
+            assert!(!has_bucket(&storage, BUCKET_INVITATIONS));
+            assert!(!has_bucket(&storage, "partial"));
+        }
+        remove_with_backups(TEST_DB_MIGRATION_FAILED);
+    }
+
+    #[test]
+    fn test_newer_schema_refused() {
+        remove_with_backups(TEST_DB_MIGRATION_NEWER);
+        {
+            let storage = Storage::new(TEST_DB_MIGRATION_NEWER).unwrap();
+            let newer = (MIGRATIONS.len() as u32 + 1).to_le_bytes();
+            storage.write_meta(SCHEMA_VERSION_KEY, &newer).unwrap();
+            storage.close();
+            let e = Storage::new(TEST_DB_MIGRATION_NEWER).err().unwrap();
+            assert!(e.to_string().contains("newer"));
+            // nothing is backed up or changed
+            assert!(
+                !backup_path(Path::new(TEST_DB_MIGRATION_NEWER), MIGRATIONS.len() + 1).exists()
+            );
+        }
+        remove_with_backups(TEST_DB_MIGRATION_NEWER);
+    }
+
+    fn ordered_post(seq: u64) -> Post {
+        Post {
+            id: 1000 + seq,
+            chat_id: 2,
+            user_id: 3,
+            text: format!("post {}", seq),
+            attachments: Vec::new(),
+            created: seq,
+            author_name: String::new(),
+            reactions: Vec::new(),
+            reply_to_post_id: 0,
+            forwarded_from: None,
+            idempotency_key: String::new(),
+            seq,
+            kind: PostKind::Regular as i32,
+        }
+    }
+
+    fn assert_posts_ordered(posts: &[Post], count: usize) {
+        assert_eq!(posts.len(), count);
+        for (seq, post) in posts.iter().enumerate() {
+            assert_eq!(post.created, seq as u64);
+        }
+    }
+
+    #[test]
+    fn test_post_counts() {
+        let _ = std::fs::remove_file(TEST_DB_POST_COUNTS);
+        {
+            let storage = Storage::new(TEST_DB_POST_COUNTS).unwrap();
+            for id in 1..=10 {
+                storage.write_post(&parity_post(id, 1)).unwrap();
+            }
+            storage.write_post(&parity_post(11, 2)).unwrap();
+            assert!(storage.remove_post(1, 3).unwrap());
+            assert!(!storage.remove_post(1, 3).unwrap());
+            assert_eq!(storage.chat_posts_count(1).unwrap(), 9);
+            storage.close();
+
+            let storage = Storage::new(TEST_DB_POST_COUNTS).unwrap();
+            assert_eq!(storage.chat_posts_count(1).unwrap(), 9);
+            assert_eq!(storage.chat_posts_count(2).unwrap(), 1);
+            assert_eq!(storage.chat_posts_count(3).unwrap(), 0);
+            storage.remove_chat(2).unwrap();
+            assert_eq!(storage.chat_posts_count(2).unwrap(), 0);
+            storage.write_post(&parity_post(12, 2)).unwrap();
+            assert_eq!(storage.chat_posts_count(2).unwrap(), 1);
+
+            // the database written before the counters
+            {
+                let tx = storage.db.tx(true).unwrap();
+                tx.delete_bucket(BUCKET_POST_COUNTS).unwrap();
+                tx.commit().unwrap();
+            }
+            assert_eq!(storage.chat_posts_count(1).unwrap(), 9);
+            storage.write_post(&parity_post(13, 1)).unwrap();
+            assert_eq!(
+                read_post_count(&storage.db.tx(false).unwrap(), 1).unwrap(),
+                Some(10)
+            );
+            assert!(storage.remove_post(1, 13).unwrap());
+            assert_eq!(storage.chat_posts_count(1).unwrap(), 9);
+            assert_eq!(storage.read_chat_posts(1, 0, 100).unwrap().len(), 9);
+        }
+        let _ = std::fs::remove_file(TEST_DB_POST_COUNTS);
+    }
+
+    #[test]
+    fn test_post_order_past_256() {
+        let _ = std::fs::remove_file(TEST_DB_POST_ORDER);
+        {
+            let storage = Storage::new(TEST_DB_POST_ORDER).unwrap();
+            for seq in 0..1000 {
+                storage.write_post(&ordered_post(seq)).unwrap();
+            }
+            assert_eq!(storage.chat_posts_count(2).unwrap(), 1000);
+            let posts = storage.read_chat_posts(2, 0, 1000).unwrap();
+            assert!(posts.windows(2).all(|w| w[0].created < w[1].created));
+            assert_posts_ordered(&posts, 1000);
+            let page = storage.read_chat_posts(2, 250, 10).unwrap();
+            assert_eq!(page.first().unwrap().created, 250);
+            assert_eq!(page.last().unwrap().created, 259);
+        }
+        let _ = std::fs::remove_file(TEST_DB_POST_ORDER);
+    }
+
+    #[test]
+    fn test_post_keys_migration() {
+        remove_with_backups(TEST_DB_POST_KEYS);
+        {
+            // the posts written by the server of the previous schema
+            let storage = Storage::new(TEST_DB_POST_KEYS).unwrap();
+            storage.write_chat(2, &parity_chat(2, vec![3])).unwrap();
+            {
+                let tx = storage.db.tx(true).unwrap();
+                let posts_bucket = tx.get_bucket(BUCKET_POSTS).unwrap();
+                let chat_bucket = posts_bucket.create_bucket(&2u64.to_le_bytes()).unwrap();
+                for seq in 0..300 {
+                    let k = chat_bucket.next_int();
+                    chat_bucket
+                        .put(&k.to_le_bytes(), encode(&ordered_post(seq)).unwrap())
+                        .unwrap();
+                }
+                tx.commit().unwrap();
+            }
+            // the schema before the big-endian keys
+            let version = 2u32.to_le_bytes();
+            storage.write_meta(SCHEMA_VERSION_KEY, &version).unwrap();
+            assert_eq!(storage.chat_posts_count(2).unwrap(), 300);
+            // 1 follows 0 and 256, the page of the previous schema is out of order
+            let page = storage.read_chat_posts(2, 0, 3).unwrap();
+            assert_eq!(page[1].created, 256);
+            storage.close();
+
+            let storage = Storage::new(TEST_DB_POST_KEYS).unwrap();
+            assert_eq!(storage.chat_posts_count(2).unwrap(), 300);
+            // the migrated posts are indexed
+            assert_eq!(storage.locate_post(1299).unwrap(), Some((2, 299)));
+            assert_posts_ordered(&storage.read_chat_posts(2, 0, 1000).unwrap(), 300);
+            // the sequence goes on after the migrated posts
+            storage.write_post(&ordered_post(300)).unwrap();
+            assert_posts_ordered(&storage.read_chat_posts(2, 0, 1000).unwrap(), 301);
+
+            // the step applied again leaves the keys as they are
+            let tx = storage.db.tx(true).unwrap();
+            rekey_posts_big_endian(&tx).unwrap();
+            tx.commit().unwrap();
+            assert_posts_ordered(&storage.read_chat_posts(2, 0, 1000).unwrap(), 301);
+        }
+        remove_with_backups(TEST_DB_POST_KEYS);
+    }
+
+    // the posts found by scanning all the chats' buckets
+    fn scan_post_locations(storage: &Storage) -> HashMap<PostId, (ChatId, u64)> {
+        let tx = storage.db.tx(false).unwrap();
+        let posts_bucket = tx.get_bucket(BUCKET_POSTS).unwrap();
+        let mut locations = HashMap::new();
+        for chat in storage.read_all_chats().unwrap() {
+            if let Ok(chat_bucket) = posts_bucket.get_bucket(&chat.id.to_le_bytes()) {
+                for pair in chat_bucket.kv_pairs() {
+                    if let Ok(post) = Post::decode(pair.value()) {
+                        let seq = u64::from_be_bytes(key_bytes(pair.key()).unwrap());
+                        locations.insert(post.id, (chat.id, seq));
+                    }
+                }
+            }
+        }
+        locations
+    }
+
+    fn index_entries(storage: &Storage) -> HashMap<PostId, (ChatId, u64)> {
+        let tx = storage.db.tx(false).unwrap();
+        let index = tx.get_bucket(BUCKET_POST_INDEX).unwrap();
+        let entries = index
+            .kv_pairs()
+            .map(|pair| {
+                (
+                    u64::from_le_bytes(key_bytes(pair.key()).unwrap()),
+                    parse_post_location(pair.value()).unwrap(),
+                )
+            })
+            .collect();
+        entries
+    }
+
+    #[test]
+    fn test_post_index() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let _ = std::fs::remove_file(TEST_DB_POST_INDEX);
+        {
+            let storage = Storage::new(TEST_DB_POST_INDEX).unwrap();
+            let mut rng = StdRng::seed_from_u64(1520);
+            for chat_id in 1..=5 {
+                storage
+                    .write_chat(chat_id, &parity_chat(chat_id, vec![3]))
+                    .unwrap();
+            }
+            let mut written = Vec::new();
+            for _ in 0..300 {
+                let post = parity_post(rng.gen(), rng.gen_range(1..=5));
+                assert!(storage.write_post(&post).unwrap());
+                written.push(post);
+            }
+            let removed: Vec<&Post> = written.iter().step_by(7).collect();
+            for post in &removed {
+                assert!(storage.remove_post(post.chat_id, post.id).unwrap());
+            }
+            storage.remove_chat(5).unwrap();
+            // salvaging renumbers the posts
+            storage.set_degraded(4, true).unwrap();
+            storage.salvage_chat_posts(4).unwrap();
+
+            let scanned = scan_post_locations(&storage);
+            assert_eq!(index_entries(&storage), scanned);
+            for post in &written {
+                let expected = scanned.get(&post.id).copied();
+                assert_eq!(storage.locate_post(post.id).unwrap(), expected);
+                let gone = post.chat_id == 5 || removed.iter().any(|r| r.id == post.id);
+                assert_eq!(expected.is_none(), gone);
+            }
+
+            // the taken id is refused whatever the chat
+            let kept = written
+                .iter()
+                .find(|post| post.chat_id == 1 && scanned.contains_key(&post.id))
+                .unwrap();
+            let count = storage.chat_posts_count(2).unwrap();
+            assert!(!storage.write_post(&parity_post(kept.id, 2)).unwrap());
+            assert_eq!(storage.chat_posts_count(2).unwrap(), count);
+            assert_eq!(storage.locate_post(kept.id).unwrap().unwrap().0, 1);
+
+            // the rebuilt index is the same
+            {
+                let tx = storage.db.tx(true).unwrap();
+                assert_eq!(rebuild_post_index(&tx).unwrap(), scanned.len());
+                tx.commit().unwrap();
+            }
+            assert_eq!(index_entries(&storage), scanned);
+
+            // the verifier restores the lost entry and drops the stale one
+            {
+                let tx = storage.db.tx(true).unwrap();
+                let index = tx.get_bucket(BUCKET_POST_INDEX).unwrap();
+                index.delete(&kept.id.to_le_bytes()).unwrap();
+                index
+                    .put(&7u64.to_le_bytes(), post_location(1, 1_000_000))
+                    .unwrap();
+                tx.commit().unwrap();
+            }
+            assert_eq!(storage.verify_chat_posts(1, false).unwrap(), 2);
+            assert_eq!(storage.locate_post(kept.id).unwrap(), None);
+            assert_eq!(storage.verify_chat_posts(1, true).unwrap(), 2);
+            assert_eq!(storage.verify_chat_posts(1, false).unwrap(), 0);
+            assert_eq!(index_entries(&storage), scanned);
+        }
+        let _ = std::fs::remove_file(TEST_DB_POST_INDEX);
+    }
+
+    // the same assertions run against every storage backend
+
+    fn parity_chat(id: ChatId, users: Vec<UserId>) -> Chat {
+        Chat {
+            id,
+            permanent: false,
+            description: format!("chat {}", id),
+            owner_id: users.first().copied().unwrap_or_default(),
+            users,
+            created: 0,
+            export_discouraged: false,
+        }
+    }
+
+    fn parity_post(id: PostId, chat_id: ChatId) -> Post {
+        Post {
+            id,
+            chat_id,
+            user_id: 1,
+            text: format!("post {}", id),
+            attachments: Vec::new(),
+            created: 0,
+            author_name: String::new(),
+            reactions: Vec::new(),
+            reply_to_post_id: 0,
+            forwarded_from: None,
+            idempotency_key: String::new(),
+            seq: 0,
+            kind: PostKind::Regular as i32,
+        }
+    }
+
+    // the sequence numbers are the backend's own
+    fn unsequenced(posts: Vec<Post>) -> Vec<Post> {
+        posts
+            .into_iter()
+            .map(|post| Post { seq: 0, ..post })
+            .collect()
+    }
+
+    fn check_users<S: ChatStorage>(storage: &S) {
+        let user = |id| User {
+            id,
+            name: format!("User {}", id),
+            short_name: format!("user{}", id),
+            created: 0,
+        };
+        assert_eq!(storage.read_user(1).unwrap(), None);
+        storage.write_user(1, &user(1)).unwrap();
+        storage.write_user(2, &user(2)).unwrap();
+        assert_eq!(storage.read_user(2).unwrap(), Some(user(2)));
+        let renamed = User {
+            name: String::from("Renamed"),
+            ..user(1)
+        };
+        storage.write_user(1, &renamed).unwrap();
+        assert_eq!(storage.read_all_users().unwrap(), vec![renamed, user(2)]);
+    }
+
+    fn check_chats<S: ChatStorage>(storage: &S) {
+        for id in &[2, 256, 1] {
+            storage.write_chat(*id, &parity_chat(*id, vec![1])).unwrap();
+        }
+        let ids = |chats: Vec<Chat>| chats.into_iter().map(|c| c.id).collect::<Vec<_>>();
+        // the order of the key bytes
+        assert_eq!(ids(storage.read_all_chats().unwrap()), vec![256, 1, 2]);
+        assert_eq!(
+            ids(storage.read_chats_after(None, 2).unwrap()),
+            vec![256, 1]
+        );
+        assert_eq!(ids(storage.read_chats_after(Some(1), 2).unwrap()), vec![2]);
+        assert_eq!(
+            ids(storage.read_chats_where(|c| c.id > 1).unwrap()),
+            vec![256, 2]
+        );
+        // updated or left as is
+        let updated = storage
+            .update_chat(1, |chat| {
+                chat.users.push(2);
+                true
+            })
+            .unwrap();
+        assert_eq!(updated, Some(parity_chat(1, vec![1, 2])));
+        let unchanged = storage
+            .update_chat(2, |chat| {
+                chat.users.clear();
+                false
+            })
+            .unwrap();
+        assert_eq!(unchanged, Some(parity_chat(2, vec![1])));
+        assert_eq!(storage.read_chat(2).unwrap(), Some(parity_chat(2, vec![1])));
+        assert_eq!(storage.update_chat(3, |_| true).unwrap(), None);
+        // with the posts and without
+        storage.write_post(&parity_post(10, 1)).unwrap();
+        storage.remove_chat(1).unwrap();
+        storage.remove_chat(2).unwrap();
+        assert_eq!(storage.read_chat(1).unwrap(), None);
+        assert_eq!(storage.chat_posts_count(1).unwrap(), 0);
+        assert_eq!(storage.locate_post(10).unwrap(), None);
+        assert_eq!(ids(storage.read_all_chats().unwrap()), vec![256]);
+    }
+
+    // the index is compared with the members of all chats after every change
+    fn assert_user_chats_consistent<S: ChatStorage>(storage: &S, users: &[UserId]) {
+        let chats = storage.read_all_chats().unwrap();
+        for user_id in users {
+            let mut expected: Vec<ChatId> = chats
+                .iter()
+                .filter(|c| c.users.contains(user_id))
+                .map(|c| c.id)
+                .collect();
+            expected.sort_unstable();
+            assert_eq!(
+                storage.read_user_chats(*user_id).unwrap(),
+                expected,
+                "user {}",
+                user_id
+            );
+        }
+    }
+
+    fn check_user_chats<S: ChatStorage>(storage: &S) {
+        let users = [1, 2, 3, u64::MAX];
+        assert!(storage.read_user_chats(1).unwrap().is_empty());
+        storage.write_chat(2, &parity_chat(2, vec![1, 2])).unwrap();
+        storage.write_chat(256, &parity_chat(256, vec![1])).unwrap();
+        storage
+            .write_chat(1 << 63, &parity_chat(1 << 63, vec![u64::MAX, 1]))
+            .unwrap();
+        assert_user_chats_consistent(storage, &users);
+        assert_eq!(storage.read_user_chats(1).unwrap(), vec![2, 256, 1 << 63]);
+        // enter
+        storage
+            .update_chat(256, |chat| {
+                chat.users.push(3);
+                true
+            })
+            .unwrap();
+        assert_user_chats_consistent(storage, &users);
+        // leave, the last chat of the user
+        storage
+            .update_chat(2, |chat| {
+                chat.users.retain(|u| *u != 2);
+                true
+            })
+            .unwrap();
+        assert_user_chats_consistent(storage, &users);
+        assert!(storage.read_user_chats(2).unwrap().is_empty());
+        // not stored, not indexed
+        storage
+            .update_chat(256, |chat| {
+                chat.users.clear();
+                false
+            })
+            .unwrap();
+        assert_user_chats_consistent(storage, &users);
+        // rewritten with other members
+        storage.write_chat(2, &parity_chat(2, vec![3])).unwrap();
+        assert_user_chats_consistent(storage, &users);
+        storage.remove_chat(256).unwrap();
+        storage.remove_chat(256).unwrap();
+        assert_user_chats_consistent(storage, &users);
+        assert_eq!(storage.read_user_chats(1).unwrap(), vec![1 << 63]);
+        assert_eq!(storage.read_user_chats(3).unwrap(), vec![2]);
+    }
+
+    fn check_posts<S: ChatStorage>(storage: &S) {
+        for id in 1..=3 {
+            storage.write_post(&parity_post(id * 10, 1)).unwrap();
+        }
+        storage.write_post(&parity_post(40, 2)).unwrap();
+        assert_eq!(storage.chat_posts_count(1).unwrap(), 3);
+        assert_eq!(storage.chat_posts_count(3).unwrap(), 0);
+        assert_eq!(
+            unsequenced(storage.read_chat_posts(1, 1, 5).unwrap()),
+            vec![parity_post(20, 1), parity_post(30, 1)]
+        );
+        assert!(storage.read_chat_posts(1, 0, 0).unwrap().is_empty());
+        assert!(storage.read_chat_posts(3, 0, 5).unwrap().is_empty());
+        assert_eq!(
+            unsequenced(storage.read_post(1, 20).unwrap().into_iter().collect()),
+            vec![parity_post(20, 1)]
+        );
+        assert_eq!(storage.read_post(2, 20).unwrap(), None);
+        let located_chat = |post_id| {
+            storage
+                .locate_post(post_id)
+                .unwrap()
+                .map(|(chat_id, _)| chat_id)
+        };
+        assert_eq!(located_chat(20), Some(1));
+        assert_eq!(located_chat(40), Some(2));
+        assert_eq!(located_chat(50), None);
+        // the id is taken
+        assert!(!storage.write_post(&parity_post(20, 2)).unwrap());
+        assert_eq!(storage.chat_posts_count(2).unwrap(), 1);
+        assert!(storage.remove_post(1, 20).unwrap());
+        assert!(!storage.remove_post(1, 20).unwrap());
+        assert!(!storage.remove_post(3, 20).unwrap());
+        assert_eq!(located_chat(20), None);
+        assert_eq!(
+            unsequenced(storage.read_chat_posts(1, 0, 5).unwrap()),
+            vec![parity_post(10, 1), parity_post(30, 1)]
+        );
+    }
+
+    fn check_post_seqs<S: ChatStorage>(storage: &S) {
+        // the chats are posted to in turns
+        for id in 1..=6 {
+            storage.write_post(&parity_post(id, id % 2 + 1)).unwrap();
+        }
+        for chat_id in 1..=2 {
+            let posts = storage.read_chat_posts(chat_id, 0, 10).unwrap();
+            assert_eq!(posts.len(), 3);
+            assert!(posts.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+            for post in &posts {
+                assert_eq!(
+                    storage.locate_post(post.id).unwrap(),
+                    Some((chat_id, post.seq))
+                );
+                assert_eq!(
+                    storage.read_post(chat_id, post.id).unwrap().as_ref(),
+                    Some(post)
+                );
+            }
+        }
+        // the seq of the removed post is not given again, the updated post keeps its own
+        let last = storage.read_chat_posts(1, 2, 1).unwrap().remove(0);
+        assert!(storage.remove_post(1, last.id).unwrap());
+        storage.write_post(&parity_post(7, 1)).unwrap();
+        let mut posts = storage.read_chat_posts(1, 0, 10).unwrap();
+        assert!(posts[2].seq > last.seq);
+        posts[0].text = String::from("edited");
+        assert!(storage.update_post(&posts[0]).unwrap());
+        assert_eq!(storage.read_chat_posts(1, 0, 10).unwrap(), posts);
+        // the posts missed before the seq
+        assert_eq!(
+            storage.read_chat_posts_before(1, posts[2].seq, 5).unwrap(),
+            posts[..2].to_vec()
+        );
+        assert_eq!(
+            storage.read_chat_posts_before(1, posts[2].seq, 1).unwrap(),
+            posts[1..2].to_vec()
+        );
+        assert!(storage
+            .read_chat_posts_before(1, posts[0].seq, 5)
+            .unwrap()
+            .is_empty());
+    }
+
+    fn check_invitations<S: ChatStorage>(storage: &S) {
+        let invitation = |chat_id, from_user_id, to_user_id| Invitation {
+            chat_id,
+            from_user_id,
+            to_user_id,
+            declined: false,
+        };
+        storage.write_invitation(&invitation(10, 1, 2)).unwrap();
+        storage.write_invitation(&invitation(10, 3, 2)).unwrap();
+        storage.write_invitation(&invitation(10, 3, 2)).unwrap();
+        storage.write_invitation(&invitation(20, 1, 3)).unwrap();
+        assert_eq!(storage.read_invitations_to(2).unwrap().len(), 2);
+        assert!(storage.remove_invitation(&invitation(10, 1, 2)).unwrap());
+        assert!(!storage.remove_invitation(&invitation(10, 1, 2)).unwrap());
+        assert_eq!(storage.remove_invitations_to(10, 2).unwrap(), 1);
+        assert_eq!(storage.remove_invitations_to(10, 2).unwrap(), 0);
+        assert_eq!(
+            storage.read_invitations_to(3).unwrap(),
+            vec![invitation(20, 1, 3)]
+        );
+        let reply = Invitation {
+            declined: true,
+            ..invitation(20, 1, 3)
+        };
+        storage.write_invitation_reply(&reply).unwrap();
+        assert!(storage.take_invitation_replies(3).unwrap().is_empty());
+        assert_eq!(storage.take_invitation_replies(1).unwrap(), vec![reply]);
+        assert!(storage.take_invitation_replies(1).unwrap().is_empty());
+    }
+
+    fn check_meta<S: ChatStorage>(storage: &S) {
+        storage.write_meta("key", b"value").unwrap();
+        assert_eq!(storage.read_meta("key").unwrap(), Some(b"value".to_vec()));
+        storage.remove_meta("key").unwrap();
+        storage.remove_meta("key").unwrap();
+        assert_eq!(storage.read_meta("key").unwrap(), None);
+        // the degraded mark is kept until the chat is repaired
+        storage.write_post(&parity_post(10, 1)).unwrap();
+        assert!(storage.set_degraded(1, true).unwrap());
+        assert!(!storage.set_degraded(1, true).unwrap());
+        assert_eq!(storage.verify_chat_posts(1, false).unwrap(), 1);
+        assert!(storage.is_degraded(1).unwrap());
+        assert_eq!(storage.verify_chat_posts(1, true).unwrap(), 1);
+        assert!(!storage.is_degraded(1).unwrap());
+        assert_eq!(storage.verify_chat_posts(1, true).unwrap(), 0);
+        assert_eq!(
+            unsequenced(storage.read_chat_posts(1, 0, 5).unwrap()),
+            vec![parity_post(10, 1)]
+        );
+    }
+
+    fn check_mutes<S: ChatStorage>(storage: &S) {
+        let chat = Chat {
+            id: 10,
+            users: vec![1, 2],
+            ..Default::default()
+        };
+        storage.write_chat(10, &chat).unwrap();
+        assert!(!storage.is_chat_muted(10, 1).unwrap());
+        storage.set_chat_muted(10, 1, true).unwrap();
+        storage.set_chat_muted(10, 1, true).unwrap();
+        // the mute is the user's own
+        assert!(storage.is_chat_muted(10, 1).unwrap());
+        assert!(!storage.is_chat_muted(10, 2).unwrap());
+        assert!(!storage.is_chat_muted(20, 1).unwrap());
+        storage.set_chat_muted(10, 2, true).unwrap();
+        storage.set_chat_muted(10, 1, false).unwrap();
+        storage.set_chat_muted(10, 1, false).unwrap();
+        assert!(!storage.is_chat_muted(10, 1).unwrap());
+        assert!(storage.is_chat_muted(10, 2).unwrap());
+        // the mutes go with the chat
+        storage.remove_chat(10).unwrap();
+        assert!(!storage.is_chat_muted(10, 2).unwrap());
+    }
+
+    fn check_bans<S: ChatStorage>(storage: &S) {
+        let chat = Chat {
+            id: 10,
+            users: vec![1],
+            ..Default::default()
+        };
+        storage.write_chat(10, &chat).unwrap();
+        assert!(!storage.is_banned(10, 2).unwrap());
+        storage.set_banned(10, 2, true).unwrap();
+        storage.set_banned(10, 2, true).unwrap();
+        assert!(storage.is_banned(10, 2).unwrap());
+        assert!(!storage.is_banned(10, 3).unwrap());
+        assert!(!storage.is_banned(20, 2).unwrap());
+        storage.set_banned(10, 3, true).unwrap();
+        storage.set_banned(10, 2, false).unwrap();
+        storage.set_banned(10, 2, false).unwrap();
+        assert!(!storage.is_banned(10, 2).unwrap());
+        // the bans go with the chat
+        storage.remove_chat(10).unwrap();
+        assert!(!storage.is_banned(10, 3).unwrap());
+    }
+
+    fn check_user_creation<S: ChatStorage>(storage: &S) {
+        let user = |id, short_name: &str| User {
+            id,
+            name: String::from("User Name"),
+            short_name: String::from(short_name),
+            created: 1,
+        };
+        assert!(storage.create_user(&user(1, "user"), b"digest").unwrap());
+        assert_eq!(storage.read_user(1).unwrap(), Some(user(1, "user")));
+        assert_eq!(storage.find_user_id("user").unwrap(), Some(1));
+        assert_eq!(
+            storage.read_user_secret(1).unwrap(),
+            Some(b"digest".to_vec())
+        );
+        // the short name is taken, nothing is stored
+        assert!(!storage.create_user(&user(2, "user"), b"other").unwrap());
+        assert_eq!(storage.read_user(2).unwrap(), None);
+        assert_eq!(storage.read_user_secret(2).unwrap(), None);
+        // released by the removed user
+        storage.remove_user(1).unwrap();
+        assert!(storage.create_user(&user(2, "user"), b"other").unwrap());
+        assert_eq!(storage.find_user_id("user").unwrap(), Some(2));
+    }
+
+    // generates a test per check opening the backend for it
+    macro_rules! storage_parity_tests {
+        ($backend:ident, $open:expr, $($check:ident),+) => {
+            mod $backend {
+                use super::*;
+                $(
+                    #[test]
+                    fn $check() {
+                        let db_file = format!(
+                            "migchat-test-storage-{}-{}.db",
+                            stringify!($backend),
+                            stringify!($check)
+                        );
+                        let _ = std::fs::remove_file(&db_file);
+                        {
+                            let storage = $open(db_file.as_str());
+                            super::$check(&storage);
+                            storage.close();
+                        }
+                        let _ = std::fs::remove_file(&db_file);
+                    }
+                )+
+            }
+        };
+    }
+
+    storage_parity_tests!(
+        jammdb_backend,
+        |db_file: &str| Storage::new(db_file).unwrap(),
+        check_users,
+        check_chats,
+        check_user_chats,
+        check_posts,
+        check_post_seqs,
+        check_invitations,
+        check_mutes,
+        check_bans,
+        check_user_creation,
+        check_meta
+    );
+
+    storage_parity_tests!(
+        memory_backend,
+        |_: &str| memory::InMemoryStorage::new(),
+        check_users,
+        check_chats,
+        check_user_chats,
+        check_posts,
+        check_post_seqs,
+        check_invitations,
+        check_mutes,
+        check_bans,
+        check_user_creation,
+        check_meta
+    );
+
+    storage_parity_tests!(
+        sqlite_backend,
+        |db_file: &str| sqlite::SqliteStorage::new(db_file).unwrap(),
+        check_users,
+        check_chats,
+        check_user_chats,
+        check_posts,
+        check_post_seqs,
+        check_invitations,
+        check_mutes,
+        check_bans,
+        check_user_creation,
+        check_meta
+    );
+
+    #[test]
+    fn test_user_chats_rebuilt() {
+        remove_with_backups(TEST_DB_USER_CHATS);
+        {
+            let storage = Storage::new(TEST_DB_USER_CHATS).unwrap();
+            storage.write_chat(1, &parity_chat(1, vec![1, 2])).unwrap();
+            storage.write_chat(2, &parity_chat(2, vec![2])).unwrap();
+            // the database of the version before the index
+            {
+                let tx = storage.db.tx(true).unwrap();
+                tx.delete_bucket(BUCKET_USER_CHATS).unwrap();
+                tx.commit().unwrap();
+            }
+            storage
+                .write_meta(SCHEMA_VERSION_KEY, &4u32.to_le_bytes())
+                .unwrap();
+            storage.close();
+            let storage = Storage::new(TEST_DB_USER_CHATS).unwrap();
+            assert_eq!(storage.read_user_chats(1).unwrap(), vec![1]);
+            assert_eq!(storage.read_user_chats(2).unwrap(), vec![1, 2]);
+            assert_user_chats_consistent(&storage, &[1, 2, 3]);
+        }
+        remove_with_backups(TEST_DB_USER_CHATS);
+    }
+
     #[test]
     fn test_get_or_create_bucket() {
         let _ = std::fs::remove_file(TEST_DB);
@@ -448,6 +3564,13 @@ mod tests {
                     text: String::from("text"),
                     attachments: Vec::new(),
                     created: 0,
+                    author_name: String::new(),
+                    reactions: Vec::new(),
+                    reply_to_post_id: 0,
+                    forwarded_from: None,
+                    idempotency_key: String::new(),
+                    seq: 0,
+                    kind: PostKind::Regular as i32,
                 };
 
                 match db.tx(true) {
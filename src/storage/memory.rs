@@ -0,0 +1,582 @@
+use super::{invitation_key, secret_key, ChatStorage, Storage, ATTACHMENT_CHUNK_LEN};
+use crate::proto::{AttachmentId, AttachmentInfo, NOT_USER_ID};
+use crate::{Chat, ChatId, Invitation, Post, PostId, ServerError, User, UserId};
+use log::{debug, info};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ops::Bound,
+    path::Path,
+    sync::{PoisonError, RwLock},
+};
+
+fn poisoned<T>(_: PoisonError<T>) -> ServerError {
+    "storage lock is poisoned".into()
+}
+
+// the post as read, with its sequence number
+fn sequenced(seq: u64, post: &Post) -> Post {
+    Post {
+        seq,
+        ..post.clone()
+    }
+}
+
+// the posts of a chat with their sequence numbers, which are never reused
+#[derive(Default)]
+struct ChatPosts {
+    next_seq: u64,
+    posts: Vec<(u64, Post)>,
+}
+
+// users and chats are keyed as in the jammdb buckets to be iterated in the same order;
+// the locks are taken in the order of the fields
+#[derive(Default)]
+pub struct InMemoryStorage {
+    users: RwLock<BTreeMap<[u8; 8], User>>,
+    chats: RwLock<BTreeMap<[u8; 8], Chat>>,
+    user_chats: RwLock<HashMap<UserId, BTreeSet<ChatId>>>,
+    user_names: RwLock<HashMap<String, UserId>>,
+    posts: RwLock<HashMap<ChatId, ChatPosts>>,
+    post_index: RwLock<HashMap<PostId, (ChatId, u64)>>,
+    invitations: RwLock<BTreeMap<Vec<u8>, Invitation>>,
+    replies: RwLock<BTreeMap<Vec<u8>, Invitation>>,
+    read_marks: RwLock<HashMap<(ChatId, UserId), (PostId, u64)>>,
+    mutes: RwLock<HashSet<(ChatId, UserId)>>,
+    bans: RwLock<HashSet<(ChatId, UserId)>>,
+    attachments: RwLock<HashMap<AttachmentId, (AttachmentInfo, Vec<Vec<u8>>)>>,
+    meta: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // follows the change of the chat's members, the chats lock is to be held
+    fn sync_user_chats(
+        &self,
+        chat_id: ChatId,
+        before: &[UserId],
+        after: &[UserId],
+    ) -> Result<(), ServerError> {
+        let mut user_chats = self.user_chats.write().map_err(poisoned)?;
+        for user_id in before.iter().filter(|u| !after.contains(u)) {
+            if let Some(chat_ids) = user_chats.get_mut(user_id) {
+                chat_ids.remove(&chat_id);
+                if chat_ids.is_empty() {
+                    user_chats.remove(user_id);
+                }
+            }
+        }
+        for user_id in after.iter().filter(|u| !before.contains(u)) {
+            user_chats.entry(*user_id).or_default().insert(chat_id);
+        }
+        Ok(())
+    }
+
+    // follows the change of the user's short name, the users lock is to be held
+    fn sync_user_name(
+        &self,
+        user_id: UserId,
+        before: &str,
+        after: &str,
+    ) -> Result<(), ServerError> {
+        let mut user_names = self.user_names.write().map_err(poisoned)?;
+        // the name released might be indexed for another user already
+        if before != after && user_names.get(before) == Some(&user_id) {
+            user_names.remove(before);
+        }
+        if !after.is_empty() {
+            user_names.insert(after.to_string(), user_id);
+        }
+        Ok(())
+    }
+}
+
+impl ChatStorage for InMemoryStorage {
+    fn close(self) {
+        debug!("dropping memory storage");
+    }
+
+    // the snapshot is a jammdb file of the users, the chats and their posts
+    fn backup(&self, path: &Path) -> Result<(), ServerError> {
+        let backup = crate::backup::export(self)?;
+        crate::backup::import(&Storage::new(path)?, backup)?;
+        Ok(())
+    }
+
+    // operations with users
+
+    fn read_user(&self, id: UserId) -> Result<Option<User>, ServerError> {
+        let users = self.users.read().map_err(poisoned)?;
+        Ok(users.get(&id.to_le_bytes()).cloned())
+    }
+
+    fn write_user(&self, id: UserId, user: &User) -> Result<(), ServerError> {
+        let mut users = self.users.write().map_err(poisoned)?;
+        let before = users
+            .insert(id.to_le_bytes(), user.clone())
+            .map(|user| user.short_name)
+            .unwrap_or_default();
+        self.sync_user_name(id, &before, &user.short_name)
+    }
+
+    fn create_user(&self, user: &User, secret_digest: &[u8]) -> Result<bool, ServerError> {
+        // the concurrent writers of the users wait until the name is taken
+        let mut users = self.users.write().map_err(poisoned)?;
+        let owner = self.find_user_id(&user.short_name)?;
+        if owner.map_or(false, |owner| users.contains_key(&owner.to_le_bytes())) {
+            return Ok(false);
+        }
+        self.write_meta(&secret_key(user.id), secret_digest)?;
+        users.insert(user.id.to_le_bytes(), user.clone());
+        self.sync_user_name(user.id, "", &user.short_name)?;
+        Ok(true)
+    }
+
+    fn find_user_id(&self, short_name: &str) -> Result<Option<UserId>, ServerError> {
+        let user_names = self.user_names.read().map_err(poisoned)?;
+        Ok(user_names.get(short_name).copied())
+    }
+
+    fn update_user<F: FnMut(&mut User) -> bool>(
+        &self,
+        id: UserId,
+        mut updater: F,
+    ) -> Result<Option<User>, ServerError> {
+        let mut users = self.users.write().map_err(poisoned)?;
+        match users.get_mut(&id.to_le_bytes()) {
+            Some(stored) => {
+                let mut user = stored.clone();
+                if updater(&mut user) {
+                    let before = std::mem::replace(stored, user.clone()).short_name;
+                    self.sync_user_name(id, &before, &user.short_name)?;
+                }
+                Ok(Some(user))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn read_all_users(&self) -> Result<Vec<User>, ServerError> {
+        let users = self.users.read().map_err(poisoned)?;
+        Ok(users.values().cloned().collect())
+    }
+
+    fn remove_user(&self, id: UserId) -> Result<(), ServerError> {
+        for chat_id in self.read_user_chats(id)? {
+            self.update_chat(chat_id, |chat| {
+                chat.users.retain(|&u| u != id);
+                true
+            })?;
+        }
+        let mut users = self.users.write().map_err(poisoned)?;
+        if let Some(user) = users.remove(&id.to_le_bytes()) {
+            self.sync_user_name(id, &user.short_name, "")?;
+        }
+        Ok(())
+    }
+
+    // operations with chats
+
+    fn read_chat(&self, id: ChatId) -> Result<Option<Chat>, ServerError> {
+        let chats = self.chats.read().map_err(poisoned)?;
+        Ok(chats.get(&id.to_le_bytes()).cloned())
+    }
+
+    fn write_chat(&self, id: ChatId, chat: &Chat) -> Result<(), ServerError> {
+        let mut chats = self.chats.write().map_err(poisoned)?;
+        let before = chats
+            .insert(id.to_le_bytes(), chat.clone())
+            .map(|chat| chat.users)
+            .unwrap_or_default();
+        self.sync_user_chats(id, &before, &chat.users)
+    }
+
+    fn update_chat<F: FnMut(&mut Chat) -> bool>(
+        &self,
+        id: ChatId,
+        mut updater: F,
+    ) -> Result<Option<Chat>, ServerError> {
+        let mut chats = self.chats.write().map_err(poisoned)?;
+        match chats.get_mut(&id.to_le_bytes()) {
+            Some(stored) => {
+                let mut chat = stored.clone();
+                if updater(&mut chat) {
+                    let before = std::mem::replace(stored, chat.clone()).users;
+                    self.sync_user_chats(id, &before, &chat.users)?;
+                }
+                Ok(Some(chat))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn read_all_chats(&self) -> Result<Vec<Chat>, ServerError> {
+        self.read_chats_where(|_| true)
+    }
+
+    fn read_chats_where<F: FnMut(&Chat) -> bool>(
+        &self,
+        mut predicate: F,
+    ) -> Result<Vec<Chat>, ServerError> {
+        let chats = self.chats.read().map_err(poisoned)?;
+        Ok(chats.values().filter(|c| predicate(c)).cloned().collect())
+    }
+
+    fn read_chats_after(
+        &self,
+        after: Option<ChatId>,
+        limit: usize,
+    ) -> Result<Vec<Chat>, ServerError> {
+        let from = match after {
+            Some(id) => Bound::Excluded(id.to_le_bytes()),
+            None => Bound::Unbounded,
+        };
+        let chats = self.chats.read().map_err(poisoned)?;
+        Ok(chats
+            .range((from, Bound::Unbounded))
+            .take(limit)
+            .map(|(_, chat)| chat.clone())
+            .collect())
+    }
+
+    fn remove_chat(&self, id: ChatId) -> Result<(), ServerError> {
+        let mut posts = self.posts.write().map_err(poisoned)?;
+        if let Some(chat_posts) = posts.remove(&id) {
+            let mut post_index = self.post_index.write().map_err(poisoned)?;
+            for (_, post) in &chat_posts.posts {
+                post_index.remove(&post.id);
+            }
+        }
+        drop(posts);
+        let mut chats = self.chats.write().map_err(poisoned)?;
+        if let Some(chat) = chats.remove(&id.to_le_bytes()) {
+            self.sync_user_chats(id, &chat.users, &[])?;
+        }
+        drop(chats);
+        let mut read_marks = self.read_marks.write().map_err(poisoned)?;
+        read_marks.retain(|&(chat_id, _), _| chat_id != id);
+        drop(read_marks);
+        let mut mutes = self.mutes.write().map_err(poisoned)?;
+        mutes.retain(|&(chat_id, _)| chat_id != id);
+        drop(mutes);
+        let mut bans = self.bans.write().map_err(poisoned)?;
+        bans.retain(|&(chat_id, _)| chat_id != id);
+        Ok(())
+    }
+
+    fn read_user_chats(&self, user_id: UserId) -> Result<Vec<ChatId>, ServerError> {
+        let user_chats = self.user_chats.read().map_err(poisoned)?;
+        Ok(user_chats
+            .get(&user_id)
+            .map(|chat_ids| chat_ids.iter().copied().collect())
+            .unwrap_or_default())
+    }
+
+    // operations with invitations
+
+    fn write_invitation(&self, invitation: &Invitation) -> Result<(), ServerError> {
+        let mut invitations = self.invitations.write().map_err(poisoned)?;
+        invitations.insert(invitation_key(invitation), invitation.clone());
+        Ok(())
+    }
+
+    fn read_invitations_to(&self, user_id: UserId) -> Result<Vec<Invitation>, ServerError> {
+        let invitations = self.invitations.read().map_err(poisoned)?;
+        Ok(invitations
+            .values()
+            .filter(|i| i.to_user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    fn remove_invitation(&self, invitation: &Invitation) -> Result<bool, ServerError> {
+        let mut invitations = self.invitations.write().map_err(poisoned)?;
+        Ok(invitations.remove(&invitation_key(invitation)).is_some())
+    }
+
+    fn remove_invitations_to(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+    ) -> Result<usize, ServerError> {
+        let mut invitations = self.invitations.write().map_err(poisoned)?;
+        let before = invitations.len();
+        invitations.retain(|_, i| i.chat_id != chat_id || i.to_user_id != user_id);
+        Ok(before - invitations.len())
+    }
+
+    fn write_invitation_reply(&self, reply: &Invitation) -> Result<(), ServerError> {
+        let mut replies = self.replies.write().map_err(poisoned)?;
+        replies.insert(invitation_key(reply), reply.clone());
+        Ok(())
+    }
+
+    fn take_invitation_replies(&self, user_id: UserId) -> Result<Vec<Invitation>, ServerError> {
+        let mut replies = self.replies.write().map_err(poisoned)?;
+        let mut taken = Vec::new();
+        replies.retain(|_, reply| {
+            if reply.from_user_id == user_id {
+                taken.push(reply.clone());
+                false
+            } else {
+                true
+            }
+        });
+        Ok(taken)
+    }
+
+    // operations with service metadata
+
+    fn read_meta(&self, key: &str) -> Result<Option<Vec<u8>>, ServerError> {
+        let meta = self.meta.read().map_err(poisoned)?;
+        Ok(meta.get(key).cloned())
+    }
+
+    fn write_meta(&self, key: &str, value: &[u8]) -> Result<(), ServerError> {
+        let mut meta = self.meta.write().map_err(poisoned)?;
+        meta.insert(String::from(key), value.to_vec());
+        Ok(())
+    }
+
+    fn remove_meta(&self, key: &str) -> Result<(), ServerError> {
+        self.meta.write().map_err(poisoned)?.remove(key);
+        Ok(())
+    }
+
+    // operations with posts
+
+    fn write_post(&self, post: &Post) -> Result<bool, ServerError> {
+        let mut posts = self.posts.write().map_err(poisoned)?;
+        let mut post_index = self.post_index.write().map_err(poisoned)?;
+        if post_index.contains_key(&post.id) {
+            return Ok(false);
+        }
+        let chat_posts = posts.entry(post.chat_id).or_default();
+        let seq = chat_posts.next_seq;
+        chat_posts.next_seq += 1;
+        chat_posts.posts.push((seq, post.clone()));
+        post_index.insert(post.id, (post.chat_id, seq));
+        Ok(true)
+    }
+
+    fn locate_post(&self, post_id: PostId) -> Result<Option<(ChatId, u64)>, ServerError> {
+        let post_index = self.post_index.read().map_err(poisoned)?;
+        Ok(post_index.get(&post_id).copied())
+    }
+
+    fn chat_posts_count(&self, chat_id: ChatId) -> Result<usize, ServerError> {
+        let posts = self.posts.read().map_err(poisoned)?;
+        Ok(posts
+            .get(&chat_id)
+            .map_or(0, |chat_posts| chat_posts.posts.len()))
+    }
+
+    fn read_chat_posts(
+        &self,
+        chat_id: ChatId,
+        idx_from: usize,
+        count: usize,
+    ) -> Result<Vec<Post>, ServerError> {
+        let posts = self.posts.read().map_err(poisoned)?;
+        Ok(posts
+            .get(&chat_id)
+            .map(|chat_posts| {
+                chat_posts
+                    .posts
+                    .iter()
+                    .skip(idx_from)
+                    .take(count)
+                    .map(|(seq, post)| sequenced(*seq, post))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn read_post(&self, chat_id: ChatId, post_id: PostId) -> Result<Option<Post>, ServerError> {
+        let posts = self.posts.read().map_err(poisoned)?;
+        let post_index = self.post_index.read().map_err(poisoned)?;
+        Ok(match post_index.get(&post_id) {
+            Some(&(located_chat_id, seq)) if located_chat_id == chat_id => posts
+                .get(&chat_id)
+                .and_then(|chat_posts| chat_posts.posts.iter().find(|(s, _)| *s == seq))
+                .map(|(seq, post)| sequenced(*seq, post)),
+            _ => None,
+        })
+    }
+
+    fn remove_post(&self, chat_id: ChatId, post_id: PostId) -> Result<bool, ServerError> {
+        let mut posts = self.posts.write().map_err(poisoned)?;
+        let mut post_index = self.post_index.write().map_err(poisoned)?;
+        let seq = match post_index.get(&post_id) {
+            Some(&(located_chat_id, seq)) if located_chat_id == chat_id => seq,
+            _ => return Ok(false),
+        };
+        post_index.remove(&post_id);
+        match posts.get_mut(&chat_id) {
+            Some(chat_posts) => match chat_posts.posts.iter().position(|(s, _)| *s == seq) {
+                Some(idx) => {
+                    chat_posts.posts.remove(idx);
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+            None => Ok(false),
+        }
+    }
+
+    fn update_post(&self, post: &Post) -> Result<bool, ServerError> {
+        let mut posts = self.posts.write().map_err(poisoned)?;
+        let post_index = self.post_index.read().map_err(poisoned)?;
+        let seq = match post_index.get(&post.id) {
+            Some(&(located_chat_id, seq)) if located_chat_id == post.chat_id => seq,
+            _ => return Ok(false),
+        };
+        Ok(posts
+            .get_mut(&post.chat_id)
+            .and_then(|chat_posts| chat_posts.posts.iter_mut().find(|(s, _)| *s == seq))
+            .map(|(_, stored)| *stored = post.clone())
+            .is_some())
+    }
+
+    // posts in memory can't be damaged, only the degraded mark is to be cleared
+    fn verify_chat_posts(&self, chat_id: ChatId, repair: bool) -> Result<usize, ServerError> {
+        let degraded = self.is_degraded(chat_id)?;
+        if degraded && repair {
+            self.salvage_chat_posts(chat_id)?;
+        }
+        Ok(degraded as usize)
+    }
+
+    fn salvage_chat_posts(&self, chat_id: ChatId) -> Result<usize, ServerError> {
+        let count = self.chat_posts_count(chat_id)?;
+        info!("salvaged {} posts of chat {}", count, chat_id);
+        self.set_degraded(chat_id, false)?;
+        Ok(count)
+    }
+
+    fn count_posts_after(
+        &self,
+        chat_id: ChatId,
+        seq: u64,
+        user_id: UserId,
+    ) -> Result<usize, ServerError> {
+        let posts = self.posts.read().map_err(poisoned)?;
+        Ok(posts.get(&chat_id).map_or(0, |chat_posts| {
+            chat_posts
+                .posts
+                .iter()
+                .filter(|(s, post)| {
+                    *s > seq && post.user_id != user_id && post.user_id != NOT_USER_ID
+                })
+                .count()
+        }))
+    }
+
+    // operations with read marks
+
+    fn read_read_mark(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+    ) -> Result<Option<(PostId, u64)>, ServerError> {
+        let read_marks = self.read_marks.read().map_err(poisoned)?;
+        Ok(read_marks.get(&(chat_id, user_id)).copied())
+    }
+
+    fn advance_read_mark(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        post_id: PostId,
+        seq: u64,
+    ) -> Result<bool, ServerError> {
+        let mut read_marks = self.read_marks.write().map_err(poisoned)?;
+        match read_marks.get(&(chat_id, user_id)) {
+            Some(&(_, stored_seq)) if stored_seq >= seq => Ok(false),
+            _ => {
+                read_marks.insert((chat_id, user_id), (post_id, seq));
+                Ok(true)
+            }
+        }
+    }
+
+    // operations with mutes
+
+    fn is_chat_muted(&self, chat_id: ChatId, user_id: UserId) -> Result<bool, ServerError> {
+        let mutes = self.mutes.read().map_err(poisoned)?;
+        Ok(mutes.contains(&(chat_id, user_id)))
+    }
+
+    fn set_chat_muted(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        muted: bool,
+    ) -> Result<(), ServerError> {
+        let mut mutes = self.mutes.write().map_err(poisoned)?;
+        if muted {
+            mutes.insert((chat_id, user_id));
+        } else {
+            mutes.remove(&(chat_id, user_id));
+        }
+        Ok(())
+    }
+
+    // operations with bans
+
+    fn is_banned(&self, chat_id: ChatId, user_id: UserId) -> Result<bool, ServerError> {
+        let bans = self.bans.read().map_err(poisoned)?;
+        Ok(bans.contains(&(chat_id, user_id)))
+    }
+
+    fn set_banned(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        banned: bool,
+    ) -> Result<(), ServerError> {
+        let mut bans = self.bans.write().map_err(poisoned)?;
+        if banned {
+            bans.insert((chat_id, user_id));
+        } else {
+            bans.remove(&(chat_id, user_id));
+        }
+        Ok(())
+    }
+
+    // operations with attachments
+
+    fn write_attachment(&self, info: &AttachmentInfo, content: &[u8]) -> Result<bool, ServerError> {
+        let mut attachments = self.attachments.write().map_err(poisoned)?;
+        if attachments.contains_key(&info.id) {
+            return Ok(false);
+        }
+        let chunks = content
+            .chunks(ATTACHMENT_CHUNK_LEN)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        attachments.insert(info.id, (info.clone(), chunks));
+        Ok(true)
+    }
+
+    fn read_attachment_info(
+        &self,
+        id: AttachmentId,
+    ) -> Result<Option<AttachmentInfo>, ServerError> {
+        let attachments = self.attachments.read().map_err(poisoned)?;
+        Ok(attachments.get(&id).map(|(info, _)| info.clone()))
+    }
+
+    fn read_attachment_chunk(
+        &self,
+        id: AttachmentId,
+        idx: usize,
+    ) -> Result<Option<Vec<u8>>, ServerError> {
+        let attachments = self.attachments.read().map_err(poisoned)?;
+        Ok(attachments
+            .get(&id)
+            .and_then(|(_, chunks)| chunks.get(idx).cloned()))
+    }
+}
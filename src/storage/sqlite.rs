@@ -0,0 +1,1059 @@
+use super::{encode, secret_key, ChatStorage, ATTACHMENT_CHUNK_LEN};
+use crate::proto::{AttachmentId, AttachmentInfo, NOT_USER_ID};
+use crate::{Chat, ChatId, Invitation, Post, PostId, ServerError, User, UserId};
+use log::{debug, error, info};
+use prost::Message;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::{
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+// every table keeps the encoded message along with the columns to query by;
+// users and chats are ordered by the bytes of the little-endian ids like in jammdb
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        id INTEGER PRIMARY KEY,
+        key BLOB NOT NULL,
+        name TEXT NOT NULL,
+        short_name TEXT NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS users_by_key ON users (key);
+    CREATE INDEX IF NOT EXISTS users_by_short_name ON users (short_name);
+    CREATE TABLE IF NOT EXISTS chats (
+        id INTEGER PRIMARY KEY,
+        key BLOB NOT NULL,
+        description TEXT NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS chats_by_key ON chats (key);
+    CREATE TABLE IF NOT EXISTS user_chats (
+        user_id INTEGER NOT NULL,
+        chat_id INTEGER NOT NULL,
+        PRIMARY KEY (user_id, chat_id)
+    );
+    CREATE INDEX IF NOT EXISTS user_chats_by_chat ON user_chats (chat_id);
+    CREATE TABLE IF NOT EXISTS posts (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        chat_id INTEGER NOT NULL,
+        post_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS posts_by_chat ON posts (chat_id, seq);
+    CREATE INDEX IF NOT EXISTS posts_by_id ON posts (post_id);
+    CREATE TABLE IF NOT EXISTS invitations (
+        chat_id INTEGER NOT NULL,
+        from_user_id INTEGER NOT NULL,
+        to_user_id INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (chat_id, from_user_id, to_user_id)
+    );
+    CREATE TABLE IF NOT EXISTS replies (
+        chat_id INTEGER NOT NULL,
+        from_user_id INTEGER NOT NULL,
+        to_user_id INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (chat_id, from_user_id, to_user_id)
+    );
+    CREATE TABLE IF NOT EXISTS read_marks (
+        chat_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        post_id INTEGER NOT NULL,
+        seq INTEGER NOT NULL,
+        PRIMARY KEY (chat_id, user_id)
+    );
+    CREATE TABLE IF NOT EXISTS mutes (
+        chat_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        PRIMARY KEY (chat_id, user_id)
+    );
+    CREATE TABLE IF NOT EXISTS bans (
+        chat_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        PRIMARY KEY (chat_id, user_id)
+    );
+    CREATE TABLE IF NOT EXISTS attachments (
+        id INTEGER PRIMARY KEY,
+        data BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS attachment_chunks (
+        attachment_id INTEGER NOT NULL,
+        idx INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (attachment_id, idx)
+    );
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value BLOB NOT NULL
+    );
+";
+
+// SQLite has signed integers only, ids are stored bit to bit
+fn sql_id(id: u64) -> i64 {
+    id as i64
+}
+
+// undecodable rows are reported and skipped like in jammdb
+fn decode_rows<M: Message + Default>(rows: Vec<Vec<u8>>) -> Vec<M> {
+    rows.into_iter()
+        .filter_map(|data| match M::decode(data.as_slice()) {
+            Ok(item) => Some(item),
+            Err(e) => {
+                error!("internal error, {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn data_column(row: &Row) -> rusqlite::Result<Vec<u8>> {
+    row.get(0)
+}
+
+// replaces the index rows of the chat by its current members
+fn sync_user_chats(conn: &Connection, chat_id: ChatId, users: &[UserId]) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM user_chats WHERE chat_id = ?1",
+        params![sql_id(chat_id)],
+    )?;
+    for user_id in users {
+        conn.execute(
+            "INSERT OR IGNORE INTO user_chats (user_id, chat_id) VALUES (?1, ?2)",
+            params![sql_id(*user_id), sql_id(chat_id)],
+        )?;
+    }
+    Ok(())
+}
+
+fn table_exists(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        params![table],
+        |row| row.get(0),
+    )
+}
+
+// indexes the members of the existing chats from scratch, returns the count of the chats
+fn rebuild_user_chats(conn: &mut Connection) -> Result<usize, ServerError> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM user_chats", params![])?;
+    let rows = {
+        let mut stmt = tx.prepare("SELECT data FROM chats")?;
+        let rows = stmt
+            .query_map(params![], data_column)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows
+    };
+    let chats: Vec<Chat> = decode_rows(rows);
+    for chat in &chats {
+        sync_user_chats(&tx, chat.id, &chat.users)?;
+    }
+    tx.commit()?;
+    Ok(chats.len())
+}
+
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn new<P: AsRef<Path>>(db_file: P) -> Result<Self, ServerError> {
+        let mut conn = Connection::open(db_file)?;
+        // the databases created before the index are indexed once
+        let indexed = table_exists(&conn, "user_chats")?;
+        conn.execute_batch(SCHEMA)?;
+        if !indexed {
+            let chats = rebuild_user_chats(&mut conn)?;
+            info!("members of {} chats are indexed", chats);
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> Result<MutexGuard<Connection>, ServerError> {
+        self.conn
+            .lock()
+            .map_err(|_| "storage connection is poisoned".into())
+    }
+
+    fn read_one<M: Message + Default>(&self, sql: &str, id: u64) -> Result<Option<M>, ServerError> {
+        let conn = self.conn()?;
+        match conn
+            .query_row(sql, params![sql_id(id)], data_column)
+            .optional()?
+        {
+            Some(data) => match M::decode(data.as_slice()) {
+                Ok(item) => Ok(Some(item)),
+                Err(e) => {
+                    error!("protobuf parse, {}", e);
+                    Err(e.into())
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn read_many<M: Message + Default>(
+        &self,
+        sql: &str,
+        args: &[i64],
+    ) -> Result<Vec<M>, ServerError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt
+            .query_map(rusqlite::params_from_iter(args), data_column)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(decode_rows(rows))
+    }
+
+    // the posts of the rows selected as (seq, data), the row's seq is the post's one
+    fn read_posts(&self, sql: &str, args: &[i64]) -> Result<Vec<Post>, ServerError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt
+            .query_map(rusqlite::params_from_iter(args), |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|(seq, data)| match Post::decode(data.as_slice()) {
+                Ok(post) => Some(Post {
+                    seq: seq as u64,
+                    ..post
+                }),
+                Err(e) => {
+                    error!("internal error, {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    fn write_invitation_to(&self, table: &str, invitation: &Invitation) -> Result<(), ServerError> {
+        self.conn()?.execute(
+            &format!(
+                "INSERT OR REPLACE INTO {} (chat_id, from_user_id, to_user_id, data)
+                 VALUES (?1, ?2, ?3, ?4)",
+                table
+            ),
+            params![
+                sql_id(invitation.chat_id),
+                sql_id(invitation.from_user_id),
+                sql_id(invitation.to_user_id),
+                &encode(invitation)?[..]
+            ],
+        )?;
+        Ok(())
+    }
+
+    // (seq, decoded post) of the chat in the order of writing
+    fn chat_rows(&self, chat_id: ChatId) -> Result<Vec<(i64, Option<Post>)>, ServerError> {
+        let conn = self.conn()?;
+        let mut stmt =
+            conn.prepare("SELECT seq, data FROM posts WHERE chat_id = ?1 ORDER BY seq")?;
+        let rows = stmt
+            .query_map(params![sql_id(chat_id)], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows
+            .into_iter()
+            .map(|(seq, data)| (seq, Post::decode(data.as_slice()).ok()))
+            .collect())
+    }
+
+    fn remove_rows(&self, seqs: &[i64]) -> Result<(), ServerError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        for seq in seqs {
+            tx.execute("DELETE FROM posts WHERE seq = ?1", params![seq])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl ChatStorage for SqliteStorage {
+    fn close(self) {
+        debug!("closing storage");
+        match self.conn.into_inner() {
+            Ok(conn) => {
+                if let Err((_, e)) = conn.close() {
+                    error!("failed closing storage, {}", e);
+                }
+            }
+            Err(_) => error!("storage connection is poisoned"),
+        }
+    }
+
+    // the writers wait for the copy, it is taken under the lock of the connection
+    fn backup(&self, path: &Path) -> Result<(), ServerError> {
+        let path = path.to_str().ok_or("snapshot path is not valid unicode")?;
+        self.conn()?.execute("VACUUM INTO ?1", params![path])?;
+        Ok(())
+    }
+
+    // operations with users
+
+    fn read_user(&self, id: UserId) -> Result<Option<User>, ServerError> {
+        self.read_one("SELECT data FROM users WHERE id = ?1", id)
+    }
+
+    fn write_user(&self, id: UserId, user: &User) -> Result<(), ServerError> {
+        self.conn()?.execute(
+            "INSERT OR REPLACE INTO users (id, key, name, short_name, data)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                sql_id(id),
+                &id.to_le_bytes()[..],
+                user.name,
+                user.short_name,
+                &encode(user)?[..]
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn create_user(&self, user: &User, secret_digest: &[u8]) -> Result<bool, ServerError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        if !user.short_name.is_empty() {
+            let taken = tx
+                .query_row(
+                    "SELECT id FROM users WHERE short_name = ?1 LIMIT 1",
+                    params![user.short_name],
+                    |row| row.get::<_, i64>(0),
+                )
+                .optional()?;
+            if taken.is_some() {
+                return Ok(false);
+            }
+        }
+        tx.execute(
+            "INSERT INTO users (id, key, name, short_name, data) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                sql_id(user.id),
+                &user.id.to_le_bytes()[..],
+                user.name,
+                user.short_name,
+                &encode(user)?[..]
+            ],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+            params![secret_key(user.id), secret_digest],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn update_user<F: FnMut(&mut User) -> bool>(
+        &self,
+        id: UserId,
+        mut updater: F,
+    ) -> Result<Option<User>, ServerError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let data = tx
+            .query_row(
+                "SELECT data FROM users WHERE id = ?1",
+                params![sql_id(id)],
+                data_column,
+            )
+            .optional()?;
+        let mut user = match data {
+            Some(data) => User::decode(data.as_slice())?,
+            None => return Ok(None),
+        };
+        if !updater(&mut user) {
+            return Ok(Some(user));
+        }
+        tx.execute(
+            "UPDATE users SET name = ?2, short_name = ?3, data = ?4 WHERE id = ?1",
+            params![sql_id(id), user.name, user.short_name, &encode(&user)?[..]],
+        )?;
+        tx.commit()?;
+        Ok(Some(user))
+    }
+
+    fn find_user_id(&self, short_name: &str) -> Result<Option<UserId>, ServerError> {
+        if short_name.is_empty() {
+            return Ok(None);
+        }
+        let id = self
+            .conn()?
+            .query_row(
+                "SELECT id FROM users WHERE short_name = ?1 LIMIT 1",
+                params![short_name],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?;
+        Ok(id.map(|id| id as UserId))
+    }
+
+    fn read_all_users(&self) -> Result<Vec<User>, ServerError> {
+        self.read_many("SELECT data FROM users ORDER BY key", &[])
+    }
+
+    // the short name goes with the row
+    fn remove_user(&self, id: UserId) -> Result<(), ServerError> {
+        for chat_id in self.read_user_chats(id)? {
+            self.update_chat(chat_id, |chat| {
+                chat.users.retain(|&u| u != id);
+                true
+            })?;
+        }
+        self.conn()?
+            .execute("DELETE FROM users WHERE id = ?1", params![sql_id(id)])?;
+        Ok(())
+    }
+
+    // operations with chats
+
+    fn read_chat(&self, id: ChatId) -> Result<Option<Chat>, ServerError> {
+        self.read_one("SELECT data FROM chats WHERE id = ?1", id)
+    }
+
+    fn write_chat(&self, id: ChatId, chat: &Chat) -> Result<(), ServerError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO chats (id, key, description, data) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                sql_id(id),
+                &id.to_le_bytes()[..],
+                chat.description,
+                &encode(chat)?[..]
+            ],
+        )?;
+        sync_user_chats(&tx, id, &chat.users)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn update_chat<F: FnMut(&mut Chat) -> bool>(
+        &self,
+        id: ChatId,
+        mut updater: F,
+    ) -> Result<Option<Chat>, ServerError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let data = tx
+            .query_row(
+                "SELECT data FROM chats WHERE id = ?1",
+                params![sql_id(id)],
+                data_column,
+            )
+            .optional()?;
+        let mut chat = match data {
+            Some(data) => Chat::decode(data.as_slice())?,
+            None => return Ok(None),
+        };
+        if !updater(&mut chat) {
+            return Ok(Some(chat));
+        }
+        tx.execute(
+            "UPDATE chats SET description = ?2, data = ?3 WHERE id = ?1",
+            params![sql_id(id), chat.description, &encode(&chat)?[..]],
+        )?;
+        sync_user_chats(&tx, id, &chat.users)?;
+        tx.commit()?;
+        Ok(Some(chat))
+    }
+
+    fn read_all_chats(&self) -> Result<Vec<Chat>, ServerError> {
+        self.read_many("SELECT data FROM chats ORDER BY key", &[])
+    }
+
+    fn read_chats_where<F: FnMut(&Chat) -> bool>(
+        &self,
+        mut predicate: F,
+    ) -> Result<Vec<Chat>, ServerError> {
+        let mut chats = self.read_all_chats()?;
+        chats.retain(|c| predicate(c));
+        Ok(chats)
+    }
+
+    fn read_chats_after(
+        &self,
+        after: Option<ChatId>,
+        limit: usize,
+    ) -> Result<Vec<Chat>, ServerError> {
+        let after = after
+            .map(|id| id.to_le_bytes().to_vec())
+            .unwrap_or_default();
+        let conn = self.conn()?;
+        let mut stmt =
+            conn.prepare("SELECT data FROM chats WHERE key > ?1 ORDER BY key LIMIT ?2")?;
+        let rows = stmt
+            .query_map(params![after, limit as i64], data_column)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(decode_rows(rows))
+    }
+
+    fn remove_chat(&self, id: ChatId) -> Result<(), ServerError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM posts WHERE chat_id = ?1", params![sql_id(id)])?;
+        tx.execute("DELETE FROM chats WHERE id = ?1", params![sql_id(id)])?;
+        tx.execute(
+            "DELETE FROM read_marks WHERE chat_id = ?1",
+            params![sql_id(id)],
+        )?;
+        tx.execute("DELETE FROM mutes WHERE chat_id = ?1", params![sql_id(id)])?;
+        tx.execute("DELETE FROM bans WHERE chat_id = ?1", params![sql_id(id)])?;
+        sync_user_chats(&tx, id, &[])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn read_user_chats(&self, user_id: UserId) -> Result<Vec<ChatId>, ServerError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT chat_id FROM user_chats WHERE user_id = ?1")?;
+        let mut chat_ids = stmt
+            .query_map(params![sql_id(user_id)], |row| {
+                row.get::<_, i64>(0).map(|id| id as u64)
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        // the ids above i64::MAX are stored negative
+        chat_ids.sort_unstable();
+        Ok(chat_ids)
+    }
+
+    // operations with invitations
+
+    fn write_invitation(&self, invitation: &Invitation) -> Result<(), ServerError> {
+        self.write_invitation_to("invitations", invitation)
+    }
+
+    fn read_invitations_to(&self, user_id: UserId) -> Result<Vec<Invitation>, ServerError> {
+        self.read_many(
+            "SELECT data FROM invitations WHERE to_user_id = ?1",
+            &[sql_id(user_id)],
+        )
+    }
+
+    fn remove_invitation(&self, invitation: &Invitation) -> Result<bool, ServerError> {
+        let removed = self.conn()?.execute(
+            "DELETE FROM invitations
+             WHERE chat_id = ?1 AND from_user_id = ?2 AND to_user_id = ?3",
+            params![
+                sql_id(invitation.chat_id),
+                sql_id(invitation.from_user_id),
+                sql_id(invitation.to_user_id)
+            ],
+        )?;
+        Ok(removed > 0)
+    }
+
+    fn remove_invitations_to(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+    ) -> Result<usize, ServerError> {
+        Ok(self.conn()?.execute(
+            "DELETE FROM invitations WHERE chat_id = ?1 AND to_user_id = ?2",
+            params![sql_id(chat_id), sql_id(user_id)],
+        )?)
+    }
+
+    fn write_invitation_reply(&self, reply: &Invitation) -> Result<(), ServerError> {
+        self.write_invitation_to("replies", reply)
+    }
+
+    fn take_invitation_replies(&self, user_id: UserId) -> Result<Vec<Invitation>, ServerError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let rows = {
+            let mut stmt = tx.prepare("SELECT data FROM replies WHERE from_user_id = ?1")?;
+            let rows = stmt
+                .query_map(params![sql_id(user_id)], data_column)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            rows
+        };
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+        tx.execute(
+            "DELETE FROM replies WHERE from_user_id = ?1",
+            params![sql_id(user_id)],
+        )?;
+        tx.commit()?;
+        Ok(decode_rows(rows))
+    }
+
+    // operations with service metadata
+
+    fn read_meta(&self, key: &str) -> Result<Option<Vec<u8>>, ServerError> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT value FROM meta WHERE key = ?1",
+                params![key],
+                data_column,
+            )
+            .optional()?)
+    }
+
+    fn write_meta(&self, key: &str, value: &[u8]) -> Result<(), ServerError> {
+        self.conn()?.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    fn remove_meta(&self, key: &str) -> Result<(), ServerError> {
+        self.conn()?
+            .execute("DELETE FROM meta WHERE key = ?1", params![key])?;
+        Ok(())
+    }
+
+    // operations with posts
+    // the posts are ordered by the sequence of writing, which is never reused,
+    // their seq is the one of the row, common to all the chats
+
+    // the id is checked and the post is written in the same transaction
+    fn write_post(&self, post: &Post) -> Result<bool, ServerError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let taken: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM posts WHERE post_id = ?1)",
+            params![sql_id(post.id)],
+            |row| row.get(0),
+        )?;
+        if taken {
+            return Ok(false);
+        }
+        tx.execute(
+            "INSERT INTO posts (chat_id, post_id, user_id, text, data)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                sql_id(post.chat_id),
+                sql_id(post.id),
+                sql_id(post.user_id),
+                post.text,
+                &encode(post)?[..]
+            ],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    // the index of post ids is kept by SQLite
+    fn locate_post(&self, post_id: PostId) -> Result<Option<(ChatId, u64)>, ServerError> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT chat_id, seq FROM posts WHERE post_id = ?1 ORDER BY seq LIMIT 1",
+                params![sql_id(post_id)],
+                |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+            )
+            .optional()?)
+    }
+
+    fn chat_posts_count(&self, chat_id: ChatId) -> Result<usize, ServerError> {
+        let count: i64 = self.conn()?.query_row(
+            "SELECT COUNT(*) FROM posts WHERE chat_id = ?1",
+            params![sql_id(chat_id)],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    // the rows are counted whether decodable or not, as the jammdb records are
+    fn read_chat_posts(
+        &self,
+        chat_id: ChatId,
+        idx_from: usize,
+        count: usize,
+    ) -> Result<Vec<Post>, ServerError> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        self.read_posts(
+            "SELECT seq, data FROM posts WHERE chat_id = ?1 ORDER BY seq LIMIT ?2 OFFSET ?3",
+            &[sql_id(chat_id), count as i64, idx_from as i64],
+        )
+    }
+
+    fn read_post(&self, chat_id: ChatId, post_id: PostId) -> Result<Option<Post>, ServerError> {
+        let posts = self.read_posts(
+            "SELECT seq, data FROM posts WHERE chat_id = ?1 AND post_id = ?2 ORDER BY seq",
+            &[sql_id(chat_id), sql_id(post_id)],
+        )?;
+        Ok(posts.into_iter().next())
+    }
+
+    fn remove_post(&self, chat_id: ChatId, post_id: PostId) -> Result<bool, ServerError> {
+        let removed = self.conn()?.execute(
+            "DELETE FROM posts WHERE seq IN (
+                SELECT seq FROM posts WHERE chat_id = ?1 AND post_id = ?2 ORDER BY seq LIMIT 1
+            )",
+            params![sql_id(chat_id), sql_id(post_id)],
+        )?;
+        Ok(removed > 0)
+    }
+
+    // the row keeps its seq, so the post keeps its place
+    fn update_post(&self, post: &Post) -> Result<bool, ServerError> {
+        let updated = self.conn()?.execute(
+            "UPDATE posts SET user_id = ?3, text = ?4, data = ?5 WHERE seq IN (
+                SELECT seq FROM posts WHERE chat_id = ?1 AND post_id = ?2 ORDER BY seq LIMIT 1
+            )",
+            params![
+                sql_id(post.chat_id),
+                sql_id(post.id),
+                sql_id(post.user_id),
+                post.text,
+                &encode(post)?[..]
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+
+    // the posts which can't be decoded or belong to another chat are the discrepancies;
+    // SQLite keeps the table consistent, so salvaging only drops them
+    fn verify_chat_posts(&self, chat_id: ChatId, repair: bool) -> Result<usize, ServerError> {
+        let degraded = self.is_degraded(chat_id)?;
+        let broken: Vec<i64> = self
+            .chat_rows(chat_id)?
+            .into_iter()
+            .filter(|(_, post)| post.as_ref().map_or(true, |p| p.chat_id != chat_id))
+            .map(|(seq, _)| seq)
+            .collect();
+        let found = broken.len() + degraded as usize;
+        if repair {
+            if degraded {
+                self.salvage_chat_posts(chat_id)?;
+            } else if !broken.is_empty() {
+                self.remove_rows(&broken)?;
+            }
+        }
+        Ok(found)
+    }
+
+    fn salvage_chat_posts(&self, chat_id: ChatId) -> Result<usize, ServerError> {
+        let (kept, broken): (Vec<_>, Vec<_>) = self
+            .chat_rows(chat_id)?
+            .into_iter()
+            .partition(|(_, post)| post.as_ref().map_or(false, |p| p.chat_id == chat_id));
+        let broken: Vec<i64> = broken.into_iter().map(|(seq, _)| seq).collect();
+        self.remove_rows(&broken)?;
+        info!("salvaged {} posts of chat {}", kept.len(), chat_id);
+        self.set_degraded(chat_id, false)?;
+        Ok(kept.len())
+    }
+
+    fn count_posts_after(
+        &self,
+        chat_id: ChatId,
+        seq: u64,
+        user_id: UserId,
+    ) -> Result<usize, ServerError> {
+        let count: i64 = self.conn()?.query_row(
+            "SELECT COUNT(*) FROM posts WHERE chat_id = ?1 AND seq > ?2 AND user_id NOT IN (?3, ?4)",
+            params![
+                sql_id(chat_id),
+                seq as i64,
+                sql_id(user_id),
+                sql_id(NOT_USER_ID)
+            ],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    // operations with read marks
+
+    fn read_read_mark(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+    ) -> Result<Option<(PostId, u64)>, ServerError> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT post_id, seq FROM read_marks WHERE chat_id = ?1 AND user_id = ?2",
+                params![sql_id(chat_id), sql_id(user_id)],
+                |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+            )
+            .optional()?)
+    }
+
+    // the stored mark is compared by the statement itself
+    fn advance_read_mark(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        post_id: PostId,
+        seq: u64,
+    ) -> Result<bool, ServerError> {
+        let changed = self.conn()?.execute(
+            "INSERT INTO read_marks (chat_id, user_id, post_id, seq) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (chat_id, user_id) DO UPDATE
+             SET post_id = excluded.post_id, seq = excluded.seq WHERE read_marks.seq < excluded.seq",
+            params![sql_id(chat_id), sql_id(user_id), sql_id(post_id), seq as i64],
+        )?;
+        Ok(changed > 0)
+    }
+
+    // operations with mutes
+
+    fn is_chat_muted(&self, chat_id: ChatId, user_id: UserId) -> Result<bool, ServerError> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT 1 FROM mutes WHERE chat_id = ?1 AND user_id = ?2",
+                params![sql_id(chat_id), sql_id(user_id)],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    fn set_chat_muted(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        muted: bool,
+    ) -> Result<(), ServerError> {
+        let sql = if muted {
+            "INSERT OR IGNORE INTO mutes (chat_id, user_id) VALUES (?1, ?2)"
+        } else {
+            "DELETE FROM mutes WHERE chat_id = ?1 AND user_id = ?2"
+        };
+        self.conn()?
+            .execute(sql, params![sql_id(chat_id), sql_id(user_id)])?;
+        Ok(())
+    }
+
+    // operations with bans
+
+    fn is_banned(&self, chat_id: ChatId, user_id: UserId) -> Result<bool, ServerError> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT 1 FROM bans WHERE chat_id = ?1 AND user_id = ?2",
+                params![sql_id(chat_id), sql_id(user_id)],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    fn set_banned(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        banned: bool,
+    ) -> Result<(), ServerError> {
+        let sql = if banned {
+            "INSERT OR IGNORE INTO bans (chat_id, user_id) VALUES (?1, ?2)"
+        } else {
+            "DELETE FROM bans WHERE chat_id = ?1 AND user_id = ?2"
+        };
+        self.conn()?
+            .execute(sql, params![sql_id(chat_id), sql_id(user_id)])?;
+        Ok(())
+    }
+
+    // operations with attachments, chunked like in jammdb to keep the rows small
+
+    fn write_attachment(&self, info: &AttachmentInfo, content: &[u8]) -> Result<bool, ServerError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let written = tx.execute(
+            "INSERT OR IGNORE INTO attachments (id, data) VALUES (?1, ?2)",
+            params![sql_id(info.id), &encode(info)?[..]],
+        )?;
+        if written == 0 {
+            return Ok(false);
+        }
+        for (idx, chunk) in content.chunks(ATTACHMENT_CHUNK_LEN).enumerate() {
+            tx.execute(
+                "INSERT INTO attachment_chunks (attachment_id, idx, data) VALUES (?1, ?2, ?3)",
+                params![sql_id(info.id), idx as i64, chunk],
+            )?;
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn read_attachment_info(
+        &self,
+        id: AttachmentId,
+    ) -> Result<Option<AttachmentInfo>, ServerError> {
+        self.read_one("SELECT data FROM attachments WHERE id = ?1", id)
+    }
+
+    fn read_attachment_chunk(
+        &self,
+        id: AttachmentId,
+        idx: usize,
+    ) -> Result<Option<Vec<u8>>, ServerError> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT data FROM attachment_chunks WHERE attachment_id = ?1 AND idx = ?2",
+                params![sql_id(id), idx as i64],
+                data_column,
+            )
+            .optional()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::PostKind;
+
+    const TEST_DB: &str = "migchat-test-storage-sqlite.db";
+
+    #[test]
+    fn round_trip() {
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let storage = SqliteStorage::new(TEST_DB).unwrap();
+            let user = User {
+                id: u64::MAX,
+                name: String::from("User Name"),
+                short_name: String::from("user"),
+                created: 1,
+            };
+            storage.write_user(user.id, &user).unwrap();
+            let chat = Chat {
+                id: 1 << 63,
+                permanent: true,
+                description: String::from("chat"),
+                users: vec![user.id],
+                created: 2,
+                owner_id: user.id,
+                export_discouraged: false,
+            };
+            storage.write_chat(chat.id, &chat).unwrap();
+            let posts: Vec<Post> = (0..300)
+                .map(|i| Post {
+                    id: 1000 + i,
+                    chat_id: chat.id,
+                    user_id: user.id,
+                    text: format!("post {}", i),
+                    attachments: Vec::new(),
+                    created: i,
+                    author_name: String::new(),
+                    reactions: Vec::new(),
+                    reply_to_post_id: 0,
+                    forwarded_from: None,
+                    idempotency_key: String::new(),
+                    // the rows are numbered from 1
+                    seq: i + 1,
+                    kind: PostKind::Regular as i32,
+                })
+                .collect();
+            for post in &posts {
+                storage.write_post(post).unwrap();
+            }
+            let (_, seq) = storage.locate_post(1100).unwrap().unwrap();
+            assert!(storage.advance_read_mark(chat.id, 7, 1100, seq).unwrap());
+            assert!(!storage
+                .advance_read_mark(chat.id, 7, 1050, seq - 50)
+                .unwrap());
+            storage.close();
+
+            // reopened as is
+            let storage = SqliteStorage::new(TEST_DB).unwrap();
+            assert_eq!(storage.read_user_chats(user.id).unwrap(), vec![chat.id]);
+            assert_eq!(storage.read_user(user.id).unwrap(), Some(user.clone()));
+            let renamed = storage
+                .update_user(user.id, |u| {
+                    u.name = String::from("New Name");
+                    true
+                })
+                .unwrap()
+                .unwrap();
+            assert_eq!(renamed.id, user.id);
+            assert_eq!(storage.read_all_users().unwrap(), vec![renamed]);
+            assert_eq!(storage.find_user_id("user").unwrap(), Some(user.id));
+            assert_eq!(storage.find_user_id("other").unwrap(), None);
+            assert_eq!(storage.read_chat(chat.id).unwrap(), Some(chat.clone()));
+            assert_eq!(storage.chat_posts_count(chat.id).unwrap(), 300);
+            assert_eq!(
+                storage.read_read_mark(chat.id, 7).unwrap(),
+                Some((1100, seq))
+            );
+            assert_eq!(storage.count_posts_after(chat.id, seq, 7).unwrap(), 199);
+            assert_eq!(storage.read_chat_posts(chat.id, 0, 1000).unwrap(), posts);
+            assert_eq!(
+                storage.read_chat_posts(chat.id, 250, 100).unwrap(),
+                posts[250..].to_vec()
+            );
+            assert_eq!(
+                storage.read_chat_posts(chat.id, 255, 3).unwrap(),
+                posts[255..258].to_vec()
+            );
+            assert!(storage
+                .read_chat_posts(chat.id, 300, 10)
+                .unwrap()
+                .is_empty());
+            // the order survives the removal
+            assert!(storage.remove_post(chat.id, 1000 + 256).unwrap());
+            assert_eq!(
+                storage.read_chat_posts(chat.id, 255, 2).unwrap(),
+                vec![posts[255].clone(), posts[257].clone()]
+            );
+            storage.write_post(&posts[256]).unwrap();
+            let rewritten = Post {
+                seq: 301,
+                ..posts[256].clone()
+            };
+            assert_eq!(
+                storage.read_chat_posts(chat.id, 298, 2).unwrap(),
+                vec![posts[299].clone(), rewritten]
+            );
+            // rewritten in place
+            let mut reacted = posts[10].clone();
+            reacted.reactions.push(crate::proto::PostReaction {
+                emoji: String::from(":+1:"),
+                user_ids: vec![chat.owner_id],
+            });
+            assert!(storage.update_post(&reacted).unwrap());
+            assert_eq!(
+                storage.read_chat_posts(chat.id, 10, 1).unwrap(),
+                vec![reacted]
+            );
+            assert!(!storage
+                .update_post(&Post {
+                    id: 1,
+                    ..posts[0].clone()
+                })
+                .unwrap());
+            // the content comes back by chunks
+            let content: Vec<u8> = (0..ATTACHMENT_CHUNK_LEN + 10).map(|i| i as u8).collect();
+            let info = AttachmentInfo {
+                id: u64::MAX,
+                name: String::from("file.bin"),
+                size: content.len() as u64,
+            };
+            assert!(storage.write_attachment(&info, &content).unwrap());
+            assert!(!storage.write_attachment(&info, b"other").unwrap());
+            assert_eq!(storage.read_attachment_info(info.id).unwrap(), Some(info));
+            assert_eq!(
+                storage
+                    .read_attachment_chunk(u64::MAX, 1)
+                    .unwrap()
+                    .as_deref(),
+                Some(&content[ATTACHMENT_CHUNK_LEN..])
+            );
+            assert!(storage
+                .read_attachment_chunk(u64::MAX, 2)
+                .unwrap()
+                .is_none());
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+}
@@ -0,0 +1,418 @@
+// The service manager protocols the server follows when run by systemd:
+// the socket activation (LISTEN_PID, LISTEN_FDS) and the readiness notification (NOTIFY_SOCKET).
+// Nothing changes without the environment variables.
+use super::{InternalError, Listener};
+use log::{debug, warn};
+use std::{fmt, sync::Arc, time::Duration};
+
+// the first fd passed by the service manager, the ones below are stdio
+const LISTEN_FDS_START: i32 = 3;
+const LISTEN_PID: &str = "LISTEN_PID";
+const LISTEN_FDS: &str = "LISTEN_FDS";
+const LISTEN_FDNAMES: &str = "LISTEN_FDNAMES";
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID: &str = "WATCHDOG_PID";
+
+// the variables meant for another process are ignored, e.g. inherited from the parent
+fn for_this_process(pid_var: Option<String>, pid: u32) -> Result<bool, InternalError> {
+    match pid_var {
+        Some(value) => match value.parse::<u32>() {
+            Ok(target) => Ok(target == pid),
+            Err(e) => Err(format!("invalid pid '{}', {}", value, e).into()),
+        },
+        None => Ok(false),
+    }
+}
+
+// the fd of the listening socket passed by the service manager, the only one served
+pub fn listen_fd<F: Fn(&str) -> Option<String>>(
+    var: F,
+    pid: u32,
+) -> Result<Option<i32>, InternalError> {
+    if !for_this_process(var(LISTEN_PID), pid).map_err(|e| format!("{}, {}", LISTEN_PID, e))? {
+        if var(LISTEN_FDS).is_some() {
+            debug!("{} is meant for another process, ignored", LISTEN_FDS);
+        }
+        return Ok(None);
+    }
+    let count = match var(LISTEN_FDS) {
+        Some(value) => value
+            .parse::<u32>()
+            .map_err(|e| format!("invalid {} '{}', {}", LISTEN_FDS, value, e))?,
+        None => return Err(format!("{} is set without {}", LISTEN_PID, LISTEN_FDS).into()),
+    };
+    match count {
+        0 => Ok(None),
+        1 => Ok(Some(LISTEN_FDS_START)),
+        _ => Err(format!("{} sockets are passed, the server listens on one", count).into()),
+    }
+}
+
+// the interval to ping the watchdog at, half of its timeout
+pub fn watchdog_interval<F: Fn(&str) -> Option<String>>(
+    var: F,
+    pid: u32,
+) -> Result<Option<Duration>, InternalError> {
+    let usec = match var(WATCHDOG_USEC) {
+        Some(value) => value
+            .parse::<u64>()
+            .ok()
+            .filter(|usec| *usec > 0)
+            .ok_or_else(|| format!("invalid {} '{}'", WATCHDOG_USEC, value))?,
+        None => return Ok(None),
+    };
+    if let Some(value) = var(WATCHDOG_PID) {
+        if !for_this_process(Some(value), pid).map_err(|e| format!("{}, {}", WATCHDOG_PID, e))? {
+            return Ok(None);
+        }
+    }
+    Ok(Some(Duration::from_micros(usec / 2)))
+}
+
+// the listener passed by the service manager if the server is socket activated
+pub fn inherited_listener() -> Result<Option<Listener>, InternalError> {
+    let fd = listen_fd(|key| std::env::var(key).ok(), std::process::id())?;
+    // the variables are consumed, nobody else is to take the socket
+    for key in &[LISTEN_PID, LISTEN_FDS, LISTEN_FDNAMES] {
+        std::env::remove_var(key);
+    }
+    match fd {
+        Some(fd) => inherit(fd).map(Some),
+        None => Ok(None),
+    }
+}
+
+// takes the listening stream socket of the fd, the fd is left intact if it is not such a socket
+#[cfg(unix)]
+pub fn inherit(fd: i32) -> Result<Listener, InternalError> {
+    use std::os::unix::io::FromRawFd;
+
+    let failed = |what: &str| format!("inherited fd {}, {}", fd, what);
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+        return Err(failed(&std::io::Error::last_os_error().to_string()).into());
+    }
+    if stat.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Err(failed("not a socket").into());
+    }
+    if sys::socket_option(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+        return Err(failed("not a stream socket").into());
+    }
+    if sys::socket_option(fd, libc::SO_ACCEPTCONN)? == 0 {
+        return Err(failed("not listening").into());
+    }
+    let family = sys::socket_family(fd)?;
+    // the service manager leaves it inheritable
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        warn!("{}", failed("is left inheritable"));
+    }
+    match family {
+        libc::AF_INET | libc::AF_INET6 => {
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(Listener::Tcp(tokio::net::TcpListener::from_std(listener)?))
+        }
+        libc::AF_UNIX => {
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(Listener::Unix(tokio::net::UnixListener::from_std(
+                listener,
+            )?))
+        }
+        family => Err(failed(&format!("unsupported address family {}", family)).into()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn inherit(fd: i32) -> Result<Listener, InternalError> {
+    Err(format!(
+        "inherited fd {}, sockets are not inherited on this platform",
+        fd
+    )
+    .into())
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::{io, mem};
+
+    pub fn socket_option(fd: i32, option: libc::c_int) -> io::Result<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let rc = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if rc == 0 {
+            Ok(value)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub fn socket_family(fd: i32) -> io::Result<libc::c_int> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let rc = unsafe {
+            libc::getsockname(
+                fd,
+                &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                &mut len,
+            )
+        };
+        if rc == 0 {
+            Ok(addr.ss_family as libc::c_int)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+// the states reported to the service manager
+#[derive(Clone, Debug, PartialEq)]
+pub enum State {
+    Ready,
+    Stopping,
+    Watchdog,
+    Status(String),
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Ready => write!(f, "READY=1"),
+            State::Stopping => write!(f, "STOPPING=1"),
+            State::Watchdog => write!(f, "WATCHDOG=1"),
+            // a line per state
+            State::Status(text) => write!(f, "STATUS={}", text.replace('\n', " ")),
+        }
+    }
+}
+
+// the datagram carrying the states
+pub fn message(states: &[State]) -> String {
+    states.iter().map(|state| format!("{}\n", state)).collect()
+}
+
+// writes the states to the socket of the service manager
+pub struct Notifier {
+    #[cfg(unix)]
+    socket: std::os::unix::net::UnixDatagram,
+    #[cfg(unix)]
+    addr: std::os::unix::net::SocketAddr,
+}
+
+impl Notifier {
+    // none if the server is not run by a service manager expecting the notifications
+    #[cfg(unix)]
+    pub fn from_env() -> Result<Option<Notifier>, InternalError> {
+        use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+        let path = match std::env::var(NOTIFY_SOCKET) {
+            Ok(path) if !path.is_empty() => path,
+            _ => return Ok(None),
+        };
+        let addr = match path.strip_prefix('@') {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Some(name) => {
+                #[cfg(target_os = "android")]
+                use std::os::android::net::SocketAddrExt;
+                #[cfg(target_os = "linux")]
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)?
+            }
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            Some(_) => return Err(format!("{} '{}' is abstract", NOTIFY_SOCKET, path).into()),
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        Ok(Some(Notifier {
+            socket: UnixDatagram::unbound()?,
+            addr,
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_env() -> Result<Option<Notifier>, InternalError> {
+        Ok(None)
+    }
+
+    // the service keeps running if the manager has not got the states
+    pub fn notify(&self, states: &[State]) {
+        let message = message(states);
+        debug!("notify {}", message.trim_end().replace('\n', ", "));
+        if let Err(e) = self.send(&message) {
+            warn!("failed to notify the service manager, {}", e);
+        }
+    }
+
+    #[cfg(unix)]
+    fn send(&self, message: &str) -> std::io::Result<usize> {
+        self.socket.send_to_addr(message.as_bytes(), &self.addr)
+    }
+
+    #[cfg(not(unix))]
+    fn send(&self, message: &str) -> std::io::Result<usize> {
+        Ok(message.len())
+    }
+}
+
+// pings the watchdog until aborted
+pub async fn watchdog(notifier: Arc<Notifier>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        notifier.notify(&[State::Watchdog]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (String::from(*key), String::from(*value)))
+            .collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    #[test]
+    fn listen_fds_validated() {
+        assert_eq!(listen_fd(env(&[]), 42).unwrap(), None);
+        let one = [(LISTEN_PID, "42"), (LISTEN_FDS, "1")];
+        assert_eq!(listen_fd(env(&one), 42).unwrap(), Some(LISTEN_FDS_START));
+        // the parent's ones
+        assert_eq!(listen_fd(env(&one), 7).unwrap(), None);
+        assert_eq!(listen_fd(env(&[(LISTEN_FDS, "1")]), 42).unwrap(), None);
+        let none = [(LISTEN_PID, "42"), (LISTEN_FDS, "0")];
+        assert_eq!(listen_fd(env(&none), 42).unwrap(), None);
+        for invalid in &[
+            vec![(LISTEN_PID, "42"), (LISTEN_FDS, "2")],
+            vec![(LISTEN_PID, "42"), (LISTEN_FDS, "-1")],
+            vec![(LISTEN_PID, "42")],
+            vec![(LISTEN_PID, "pid"), (LISTEN_FDS, "1")],
+        ] {
+            assert!(listen_fd(env(invalid), 42).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn watchdog_interval_halved() {
+        assert_eq!(watchdog_interval(env(&[]), 42).unwrap(), None);
+        let usec = [(WATCHDOG_USEC, "30000000")];
+        assert_eq!(
+            watchdog_interval(env(&usec), 42).unwrap(),
+            Some(Duration::from_secs(15))
+        );
+        let other = [(WATCHDOG_USEC, "30000000"), (WATCHDOG_PID, "7")];
+        assert_eq!(watchdog_interval(env(&other), 42).unwrap(), None);
+        let own = [(WATCHDOG_USEC, "30000000"), (WATCHDOG_PID, "42")];
+        assert!(watchdog_interval(env(&own), 42).unwrap().is_some());
+        assert!(watchdog_interval(env(&[(WATCHDOG_USEC, "0")]), 42).is_err());
+        assert!(watchdog_interval(env(&[(WATCHDOG_USEC, "soon")]), 42).is_err());
+    }
+
+    #[test]
+    fn message_formatted() {
+        assert_eq!(message(&[State::Ready]), "READY=1\n");
+        assert_eq!(
+            message(&[
+                State::Stopping,
+                State::Status(String::from("draining\nstreams"))
+            ]),
+            "STOPPING=1\nSTATUS=draining streams\n"
+        );
+        assert_eq!(message(&[State::Watchdog]), "WATCHDOG=1\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn notified_over_datagram() {
+        use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+        const SOCKET: &str = "migchat-test-notify.sock";
+        let _ = std::fs::remove_file(SOCKET);
+        let manager = UnixDatagram::bind(SOCKET).unwrap();
+        let notifier = Notifier {
+            socket: UnixDatagram::unbound().unwrap(),
+            addr: SocketAddr::from_pathname(SOCKET).unwrap(),
+        };
+        notifier.notify(&[State::Ready, State::Status(String::from("serving"))]);
+        let mut buf = [0; 64];
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=serving\n");
+        let _ = std::fs::remove_file(SOCKET);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn only_listening_streams_inherited() {
+        use std::os::unix::io::{AsRawFd, IntoRawFd};
+
+        const SOCKET: &str = "migchat-test-inherit.sock";
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(inherit(udp.as_raw_fd()).is_err());
+        let file = std::fs::File::open("Cargo.toml").unwrap();
+        assert!(inherit(file.as_raw_fd()).is_err());
+        let _ = std::fs::remove_file(SOCKET);
+        let unix = std::os::unix::net::UnixListener::bind(SOCKET).unwrap();
+        assert!(matches!(
+            inherit(unix.into_raw_fd()).unwrap(),
+            Listener::Unix(_)
+        ));
+        let _ = std::fs::remove_file(SOCKET);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn served_on_inherited_listener() {
+        use crate::proto::chat_room_service_client::ChatRoomServiceClient;
+        use crate::proto::UserInfo;
+        use crate::proxy::{ProxyConfig, ProxyFilter};
+        use crate::storage::memory::InMemoryStorage;
+        use crate::{ChatRoomImpl, Limits};
+        use std::os::unix::io::IntoRawFd;
+
+        // bound by the service manager
+        let bound = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = bound.local_addr().unwrap();
+        let listener = inherit(bound.into_raw_fd()).unwrap();
+        let chat_room = Arc::new(ChatRoomImpl::with_storage(
+            InMemoryStorage::new(),
+            Limits::default(),
+        ));
+        let (tx_stop, rx_stop) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(crate::serve(
+            chat_room,
+            listener,
+            None,
+            Arc::new(ProxyFilter::new(ProxyConfig::default())),
+            None,
+            async {
+                let _ = rx_stop.await;
+            },
+        ));
+        // already listening, no retries needed
+        let mut client = ChatRoomServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let registered = client
+            .register(tonic::Request::new(UserInfo {
+                name: String::from("User Name"),
+                short_name: String::from("user"),
+            }))
+            .await
+            .unwrap();
+        assert!(registered.into_inner().registration.is_some());
+        drop(client);
+        tx_stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
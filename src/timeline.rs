@@ -0,0 +1,364 @@
+use crate::storage::ChatStorage;
+use crate::{ChatId, InternalError, Post, User, UserId};
+use chrono::{DateTime, TimeZone, Utc};
+use std::{collections::HashMap, fmt::Write};
+
+// posts listed before the boundary
+const LAST_POSTS: usize = 20;
+
+// a point in the chat's history; both kinds are inclusive: the post written
+// with the seq, or created at the second, is the last one seen at the boundary
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Boundary {
+    Seq(u64),
+    // unix time in seconds
+    Time(u64),
+}
+
+impl Boundary {
+    fn includes(self, seq: u64, post: &Post) -> bool {
+        match self {
+            Boundary::Seq(until) => seq <= until,
+            Boundary::Time(until) => post.created <= until,
+        }
+    }
+
+    // either unix seconds or RFC 3339
+    pub fn parse_time(text: &str) -> Result<Self, InternalError> {
+        if let Ok(secs) = text.parse() {
+            return Ok(Boundary::Time(secs));
+        }
+        let time = DateTime::parse_from_rfc3339(text).map_err(|e| {
+            format!(
+                "time '{}' is neither unix seconds nor RFC 3339, {}",
+                text, e
+            )
+        })?;
+        Ok(Boundary::Time(time.timestamp().max(0) as u64))
+    }
+}
+
+// the chat as it was seen at the boundary
+#[derive(Debug, PartialEq)]
+pub struct ChatSnapshot {
+    pub chat_id: ChatId,
+    pub boundary: Boundary,
+    // no membership history is kept, these are the members as of now
+    pub members: Vec<UserId>,
+    // posts written up to the boundary and not removed since
+    pub total_posts: usize,
+    // the last ones before the boundary with their seqs, oldest first
+    pub last_posts: Vec<(u64, Post)>,
+}
+
+// the chat's posts with their seqs in the order of writing
+fn sequenced_posts<S: ChatStorage>(
+    storage: &S,
+    chat_id: ChatId,
+) -> Result<Vec<(u64, Post)>, InternalError> {
+    let count = storage.chat_posts_count(chat_id)?;
+    let mut posts = Vec::with_capacity(count);
+    for post in storage.read_chat_posts(chat_id, 0, count)? {
+        match storage.locate_post(post.id)? {
+            Some((located_chat_id, seq)) if located_chat_id == chat_id => posts.push((seq, post)),
+            _ => return Err(format!("post {} is not indexed", post.id).into()),
+        }
+    }
+    Ok(posts)
+}
+
+pub fn reconstruct<S: ChatStorage>(
+    storage: &S,
+    chat_id: ChatId,
+    boundary: Boundary,
+) -> Result<ChatSnapshot, InternalError> {
+    let chat = storage
+        .read_chat(chat_id)?
+        .ok_or_else(|| format!("chat {} does not exist", chat_id))?;
+    let mut seen: Vec<(u64, Post)> = sequenced_posts(storage, chat_id)?
+        .into_iter()
+        .filter(|(seq, post)| boundary.includes(*seq, post))
+        .collect();
+    let total_posts = seen.len();
+    let last_posts = seen.split_off(total_posts.saturating_sub(LAST_POSTS));
+    Ok(ChatSnapshot {
+        chat_id,
+        boundary,
+        members: chat.users,
+        total_posts,
+        last_posts,
+    })
+}
+
+// the posts seen at `to` but not at `from`, i.e. written after `from` up to `to`
+// inclusive; the boundaries may be given in any order
+pub fn posts_between<S: ChatStorage>(
+    storage: &S,
+    chat_id: ChatId,
+    from: u64,
+    to: u64,
+) -> Result<Vec<(u64, Post)>, InternalError> {
+    let (from, to) = (from.min(to), from.max(to));
+    Ok(sequenced_posts(storage, chat_id)?
+        .into_iter()
+        .filter(|(seq, _)| *seq > from && *seq <= to)
+        .collect())
+}
+
+fn format_time(secs: u64) -> String {
+    Utc.timestamp(secs as i64, 0)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn author(users: &HashMap<UserId, User>, user_id: UserId) -> String {
+    match users.get(&user_id) {
+        Some(user) => format!("{} ({})", user.short_name, user_id),
+        None => format!("unknown ({})", user_id),
+    }
+}
+
+fn render_post(out: &mut String, users: &HashMap<UserId, User>, seq: u64, post: &Post) {
+    let _ = writeln!(
+        out,
+        "  #{} {} {}: {}",
+        seq,
+        format_time(post.created),
+        author(users, post.user_id),
+        post.text
+    );
+}
+
+pub fn render_snapshot(snapshot: &ChatSnapshot, users: &HashMap<UserId, User>) -> String {
+    let mut out = String::new();
+    let boundary = match snapshot.boundary {
+        Boundary::Seq(seq) => format!("seq {}", seq),
+        Boundary::Time(secs) => format_time(secs),
+    };
+    let _ = writeln!(out, "chat {} up to {}", snapshot.chat_id, boundary);
+    let _ = writeln!(out, "members (current, no history is kept):");
+    for user_id in &snapshot.members {
+        let _ = writeln!(out, "  {}", author(users, *user_id));
+    }
+    let _ = writeln!(
+        out,
+        "posts: {} in total, the last {}:",
+        snapshot.total_posts,
+        snapshot.last_posts.len()
+    );
+    for (seq, post) in &snapshot.last_posts {
+        render_post(&mut out, users, *seq, post);
+    }
+    out
+}
+
+pub fn render_diff(
+    from: u64,
+    to: u64,
+    added: &[(u64, Post)],
+    users: &HashMap<UserId, User>,
+) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "posts added after seq {} up to seq {}: {}",
+        from.min(to),
+        from.max(to),
+        added.len()
+    );
+    for (seq, post) in added {
+        render_post(&mut out, users, *seq, post);
+    }
+    let _ = writeln!(out, "members joined / left: unknown, no history is kept");
+    out
+}
+
+// the replay-chat admin command, reads the storage only
+pub fn replay_chat<S: ChatStorage>(
+    storage: &S,
+    chat_id: ChatId,
+    boundary: Boundary,
+    diff_seq: Option<u64>,
+) -> Result<String, InternalError> {
+    let users: HashMap<UserId, User> = storage
+        .read_all_users()?
+        .into_iter()
+        .map(|user| (user.id, user))
+        .collect();
+    let snapshot = reconstruct(storage, chat_id, boundary)?;
+    let mut out = render_snapshot(&snapshot, &users);
+    if let Some(diff_seq) = diff_seq {
+        let until = match boundary {
+            Boundary::Seq(seq) => seq,
+            Boundary::Time(_) => return Err("--diff-seq requires --until-seq".into()),
+        };
+        let added = posts_between(storage, chat_id, until, diff_seq)?;
+        out.push_str(&render_diff(until, diff_seq, &added, &users));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::PostKind;
+    use crate::storage::Storage;
+    use crate::Chat;
+
+    const TEST_DB: &str = "migchat-test-timeline.db";
+    const CHAT_ID: ChatId = 7;
+
+    // user 1 creates the chat, user 2 joins and posts, user 1 leaves;
+    // post i is created at 1000 + 10 * i
+    fn seed(storage: &Storage) {
+        for (id, short_name) in &[(1, "one"), (2, "two")] {
+            storage
+                .write_user(
+                    *id,
+                    &User {
+                        id: *id,
+                        name: short_name.to_uppercase(),
+                        short_name: short_name.to_string(),
+                        created: 0,
+                    },
+                )
+                .unwrap();
+        }
+        storage
+            .write_chat(
+                CHAT_ID,
+                &Chat {
+                    id: CHAT_ID,
+                    users: vec![1],
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        for i in 0..30u64 {
+            let user_id = if i < 5 { 1 } else { 2 };
+            if i == 5 {
+                storage
+                    .update_chat(CHAT_ID, |chat| {
+                        chat.users.push(2);
+                        true
+                    })
+                    .unwrap();
+            }
+            let post = Post {
+                id: 100 + i,
+                chat_id: CHAT_ID,
+                user_id,
+                text: format!("post {}", i),
+                attachments: Vec::new(),
+                created: 1000 + 10 * i,
+                author_name: String::new(),
+                reactions: Vec::new(),
+                reply_to_post_id: 0,
+                forwarded_from: None,
+                idempotency_key: String::new(),
+                seq: 0,
+                kind: PostKind::Regular as i32,
+            };
+            assert!(storage.write_post(&post).unwrap());
+        }
+        storage
+            .update_chat(CHAT_ID, |chat| {
+                chat.users.retain(|u| *u != 1);
+                true
+            })
+            .unwrap();
+        // the removed post is not seen at any boundary
+        assert!(storage.remove_post(CHAT_ID, 103).unwrap());
+    }
+
+    fn seqs(posts: &[(u64, Post)]) -> Vec<u64> {
+        posts.iter().map(|(seq, _)| *seq).collect()
+    }
+
+    #[test]
+    fn boundaries() {
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let storage = Storage::new(TEST_DB).unwrap();
+            seed(&storage);
+            let seq_of = |post_id| storage.locate_post(post_id).unwrap().unwrap().1;
+            let first = seq_of(100);
+            let last = seq_of(129);
+            assert_eq!(last - first, 29);
+
+            // nothing before the first post
+            let before = reconstruct(&storage, CHAT_ID, Boundary::Time(999)).unwrap();
+            assert_eq!(before.total_posts, 0);
+            assert!(before.last_posts.is_empty());
+            // the seq is inclusive
+            let at_first = reconstruct(&storage, CHAT_ID, Boundary::Seq(first)).unwrap();
+            assert_eq!(seqs(&at_first.last_posts), vec![first]);
+            // the removed post is skipped
+            let at_fifth = reconstruct(&storage, CHAT_ID, Boundary::Seq(first + 4)).unwrap();
+            assert_eq!(at_fifth.total_posts, 4);
+            assert_eq!(
+                seqs(&at_fifth.last_posts),
+                vec![first, first + 1, first + 2, first + 4]
+            );
+            // only the last ones are listed
+            let all = reconstruct(&storage, CHAT_ID, Boundary::Seq(u64::MAX)).unwrap();
+            assert_eq!(all.total_posts, 29);
+            assert_eq!(all.last_posts.len(), LAST_POSTS);
+            assert_eq!(all.last_posts.last().unwrap().1.id, 129);
+            assert_eq!(all.members, vec![2]);
+            // the time is inclusive too
+            let at_time = reconstruct(&storage, CHAT_ID, Boundary::Time(1050)).unwrap();
+            assert_eq!(at_time.last_posts.last().unwrap().1.id, 105);
+            let just_before = reconstruct(&storage, CHAT_ID, Boundary::Time(1049)).unwrap();
+            assert_eq!(just_before.last_posts.last().unwrap().1.id, 104);
+            assert!(reconstruct(&storage, CHAT_ID + 1, Boundary::Seq(0)).is_err());
+
+            // the lower boundary is exclusive, the upper one inclusive, in any order
+            let added = posts_between(&storage, CHAT_ID, first + 2, first + 5).unwrap();
+            assert_eq!(seqs(&added), vec![first + 4, first + 5]);
+            assert_eq!(
+                posts_between(&storage, CHAT_ID, first + 5, first + 2).unwrap(),
+                added
+            );
+            assert!(posts_between(&storage, CHAT_ID, last, last)
+                .unwrap()
+                .is_empty());
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+
+    #[test]
+    fn rendering() {
+        const TEST_DB_RENDER: &str = "migchat-test-timeline-render.db";
+        let _ = std::fs::remove_file(TEST_DB_RENDER);
+        {
+            let storage = Storage::new(TEST_DB_RENDER).unwrap();
+            seed(&storage);
+            let first = storage.locate_post(100).unwrap().unwrap().1;
+            let text =
+                replay_chat(&storage, CHAT_ID, Boundary::Seq(first + 5), Some(first + 6)).unwrap();
+            assert!(text.contains(&format!("chat {} up to seq {}", CHAT_ID, first + 5)));
+            assert!(text.contains("  two (2)\n"));
+            assert!(text.contains("posts: 5 in total, the last 5:"));
+            assert!(text.contains(&format!(
+                "  #{} 1970-01-01 00:16:40 one (1): post 0\n",
+                first
+            )));
+            assert!(!text.contains("post 3\n"));
+            assert!(text.contains(&format!(
+                "posts added after seq {} up to seq {}: 1\n  #{} 1970-01-01 00:17:40 two (2): post 6\n",
+                first + 5,
+                first + 6,
+                first + 6
+            )));
+            assert!(replay_chat(&storage, CHAT_ID, Boundary::Time(0), Some(1)).is_err());
+        }
+        let _ = std::fs::remove_file(TEST_DB_RENDER);
+        assert_eq!(Boundary::parse_time("1050").unwrap(), Boundary::Time(1050));
+        assert_eq!(
+            Boundary::parse_time("1970-01-01T00:17:30Z").unwrap(),
+            Boundary::Time(1050)
+        );
+        assert!(Boundary::parse_time("yesterday").is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use super::{ChatId, ChatRoomImpl, UserId};
+use crate::storage::ChatStorage;
+use log::{debug, error};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// repeats of the same user in the same chat within it are ignored
+const DEBOUNCE: Duration = Duration::from_secs(2);
+// the indicator not renewed within it goes off
+const EXPIRE_AFTER: Duration = Duration::from_secs(5);
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// the members of the chat at the moment the user started typing
+pub type Recipients = Arc<Vec<UserId>>;
+
+// who is typing where, kept in memory only
+#[derive(Default)]
+pub struct Typing {
+    // the time of the last accepted notification and those to tell it goes off
+    typing: Mutex<HashMap<(ChatId, UserId), (Instant, Recipients)>>,
+}
+
+impl Typing {
+    // the recipients if the user has started typing, none for the renewal and the repeat
+    pub fn start(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        members: Vec<UserId>,
+        now: Instant,
+    ) -> Option<Recipients> {
+        match self.typing.lock() {
+            Ok(mut typing) => match typing.get_mut(&(chat_id, user_id)) {
+                Some((last, _)) => {
+                    if now.saturating_duration_since(*last) >= DEBOUNCE {
+                        *last = now;
+                    }
+                    None
+                }
+                None => {
+                    let recipients = Arc::new(members);
+                    typing.insert((chat_id, user_id), (now, recipients.clone()));
+                    Some(recipients)
+                }
+            },
+            Err(_) => {
+                error!("failed locking typing users");
+                None
+            }
+        }
+    }
+
+    // the recipients if the user was typing
+    pub fn stop(&self, chat_id: ChatId, user_id: UserId) -> Option<Recipients> {
+        match self.typing.lock() {
+            Ok(mut typing) => typing
+                .remove(&(chat_id, user_id))
+                .map(|(_, recipients)| recipients),
+            Err(_) => {
+                error!("failed locking typing users");
+                None
+            }
+        }
+    }
+
+    // those who have not renewed the indicator stop typing
+    pub fn expire(&self, now: Instant) -> Vec<(ChatId, UserId, Recipients)> {
+        let mut expired = Vec::new();
+        match self.typing.lock() {
+            Ok(mut typing) => typing.retain(|&(chat_id, user_id), (last, recipients)| {
+                if now.saturating_duration_since(*last) < EXPIRE_AFTER {
+                    true
+                } else {
+                    expired.push((chat_id, user_id, recipients.clone()));
+                    false
+                }
+            }),
+            Err(_) => error!("failed locking typing users"),
+        }
+        expired
+    }
+}
+
+// the indicators of the clients gone silent are taken down
+pub async fn run<S: ChatStorage>(chat_room: Arc<ChatRoomImpl<S>>) {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        for (chat_id, user_id, recipients) in chat_room.typing.expire(Instant::now()) {
+            debug!("{} has stopped typing in {}", user_id, chat_id);
+            chat_room.notify_typing(chat_id, user_id, false, recipients);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_debounced() {
+        let typing = Typing::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let expired = |typing: &Typing, secs| {
+            typing
+                .expire(at(secs))
+                .into_iter()
+                .map(|(chat_id, user_id, _)| (chat_id, user_id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            typing.start(10, 1, vec![1, 2], start),
+            Some(Arc::new(vec![1, 2]))
+        );
+        assert!(typing.start(20, 1, vec![1], start).is_some());
+        // the repeat does not renew the indicator, the later one does
+        assert!(typing.start(10, 1, vec![1, 2], at(1)).is_none());
+        assert!(typing.start(20, 1, vec![1], at(3)).is_none());
+        assert_eq!(expired(&typing, 5), vec![(10, 1)]);
+        assert!(expired(&typing, 7).is_empty());
+        assert_eq!(expired(&typing, 8), vec![(20, 1)]);
+        // back again
+        assert!(typing.start(10, 1, vec![1, 2], at(9)).is_some());
+        assert_eq!(typing.stop(10, 1), Some(Arc::new(vec![1, 2])));
+        assert!(typing.stop(10, 1).is_none());
+    }
+}
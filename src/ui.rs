@@ -1,4 +1,17 @@
 mod app;
+mod areas;
+mod clipboard;
+mod colors;
+mod composer;
 mod draw;
-pub use app::{App, State as WidgetState, Widget};
+mod filter;
+mod history;
+mod keys;
+mod plural;
+mod search;
+pub use app::{App, Connection, State as WidgetState, Widget};
+pub use colors::{parse_color, UserColors};
+pub use composer::ComposerLimits;
 pub use draw::draw;
+pub use filter::{load_rules, FilterRule};
+pub use keys::{named_key, Action, KeyBindings};
@@ -1,10 +1,35 @@
-use crate::proto::{self, ChatId, UserId, NOT_USER_ID};
-use crate::Command;
-use log::{error, warn};
-use std::collections::{HashMap, LinkedList};
+use super::areas::{self, Areas};
+use super::clipboard;
+use super::colors::UserColors;
+use super::composer::{self, ComposerLimits, OversizeAction, OversizeChoice};
+use super::filter::{self, FilterRule};
+use super::history::{InputHistory, Recall};
+use super::keys::{Action, Chord, Key, KeyBindings, Lookup};
+use super::plural::{self, Counted, Language};
+use super::search::{SearchState, MAX_SEARCH_PAGES};
+use crate::chat_spec::ChatSpec;
+use crate::client_service::{countdown_secs, TypingThrottle};
+use crate::export::{ExportFormat, Watermark};
+use crate::post_limits::{normalize_line_endings, PostLimits};
+use crate::proto::{self, ChatId, PostId, UserId, NOT_POST_ID, NOT_USER_ID};
+use crate::relay::RelayStats;
+use crate::{Attachment, ChatExport, Command};
+use chrono::Local;
+use crossterm::event::{MouseButton, MouseEvent, MouseEventKind};
+use log::{debug, error, info, warn};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, LinkedList, VecDeque},
+    ops::Range,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::sync::mpsc;
 use tui::widgets::ListState;
 use tui_logger::{TuiWidgetEvent, TuiWidgetState};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 // pub type SharedChats = Arc<Mutex<Vec<proto::Chat>>>;
 // pub type SharedUsers = Arc<Mutex<Vec<proto::User>>>;
@@ -18,6 +43,11 @@ pub enum Widget {
     Posts,
     Log,
     Input,
+    Confirm,
+    Picker,
+    Help,
+    Filter,
+    Results,
 }
 
 pub enum State {
@@ -26,26 +56,55 @@ pub enum State {
     Modal,
 }
 
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Connection {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
 // input text consumer
 #[derive(PartialEq)]
 enum InputResult {
     NewChat, // new chat name
     NewPost, // new post text
     UserInfo,
+    RenameChat(ChatId),    // new description of the chat
+    React(ChatId, PostId), // emoji to react to the post with
+    SendFile(ChatId),      // path of the file to send into the chat
+    Profile,               // new own display names
+    Search(ChatId),        // text to look for in the posts of the chat
+    SearchAll,             // text to look for in the posts of all the chats
+    ExportChat(ChatId),    // path of the file to write the chat history into
 }
 
 pub struct InputMode {
     purpose: InputResult,
     pub title: String,
     pub text: String,
+    // byte offset of the cursor in the text
+    cursor: usize,
+    // decision on too large post
+    pub oversize: Option<OversizeChoice>,
+    // the text is refused on submit, until edited
+    pub rejected: bool,
+    // Up/Down go through the texts submitted before
+    recall: Recall,
+    // the post the new one replies to
+    reply_to: PostId,
 }
 
 impl InputMode {
     pub fn new_chat() -> Self {
         InputMode {
             purpose: InputResult::NewChat,
-            title: "New chat name".to_string(),
+            title: "New chat: name [-perm] [-auto] [@login]".to_string(),
             text: String::with_capacity(64),
+            cursor: 0,
+            oversize: None,
+            rejected: false,
+            recall: Recall::default(),
+            reply_to: NOT_POST_ID,
         }
     }
 
@@ -54,6 +113,113 @@ impl InputMode {
             purpose: InputResult::NewPost,
             title: "Post content".to_string(),
             text: String::with_capacity(512),
+            cursor: 0,
+            oversize: None,
+            rejected: false,
+            recall: Recall::default(),
+            reply_to: NOT_POST_ID,
+        }
+    }
+
+    pub fn reply(post_id: PostId, author: &str) -> Self {
+        InputMode {
+            title: format!("Reply to {}", author),
+            reply_to: post_id,
+            ..InputMode::new_post()
+        }
+    }
+
+    pub fn rename_chat(chat: &proto::Chat) -> Self {
+        let text = format!("{}", ChatSpec::of_chat(chat));
+        InputMode {
+            purpose: InputResult::RenameChat(chat.id),
+            title: "Rename chat: name [-perm]".to_string(),
+            cursor: text.len(),
+            text,
+            oversize: None,
+            rejected: false,
+            recall: Recall::default(),
+            reply_to: NOT_POST_ID,
+        }
+    }
+
+    pub fn react(chat_id: ChatId, post_id: PostId) -> Self {
+        InputMode {
+            purpose: InputResult::React(chat_id, post_id),
+            title: "React: emoji or :shortcode:".to_string(),
+            text: String::with_capacity(16),
+            cursor: 0,
+            oversize: None,
+            rejected: false,
+            recall: Recall::default(),
+            reply_to: NOT_POST_ID,
+        }
+    }
+
+    pub fn send_file(chat_id: ChatId) -> Self {
+        InputMode {
+            purpose: InputResult::SendFile(chat_id),
+            title: "Send file: path".to_string(),
+            text: String::with_capacity(128),
+            cursor: 0,
+            oversize: None,
+            rejected: false,
+            recall: Recall::default(),
+            reply_to: NOT_POST_ID,
+        }
+    }
+
+    pub fn search(chat_id: ChatId) -> Self {
+        InputMode {
+            purpose: InputResult::Search(chat_id),
+            title: "Search posts: text".to_string(),
+            text: String::with_capacity(64),
+            cursor: 0,
+            oversize: None,
+            rejected: false,
+            recall: Recall::default(),
+            reply_to: NOT_POST_ID,
+        }
+    }
+
+    pub fn search_all() -> Self {
+        InputMode {
+            purpose: InputResult::SearchAll,
+            title: "Search all chats: text".to_string(),
+            text: String::with_capacity(64),
+            cursor: 0,
+            oversize: None,
+            rejected: false,
+            recall: Recall::default(),
+            reply_to: NOT_POST_ID,
+        }
+    }
+
+    pub fn export_chat(chat_id: ChatId) -> Self {
+        let text = format!("chat-{}.txt", chat_id);
+        InputMode {
+            purpose: InputResult::ExportChat(chat_id),
+            title: "Export chat: path, .json for JSON lines".to_string(),
+            cursor: text.len(),
+            text,
+            oversize: None,
+            rejected: false,
+            recall: Recall::default(),
+            reply_to: NOT_POST_ID,
+        }
+    }
+
+    pub fn edit_profile(user: &proto::User) -> Self {
+        let text = format!("{}, {}", user.short_name, user.name);
+        InputMode {
+            purpose: InputResult::Profile,
+            title: "Profile: Login, Full Name".to_string(),
+            cursor: text.len(),
+            text,
+            oversize: None,
+            rejected: false,
+            recall: Recall::default(),
+            reply_to: NOT_POST_ID,
         }
     }
 
@@ -62,10 +228,371 @@ impl InputMode {
             purpose: InputResult::UserInfo,
             title: "Login, Full Name".to_string(),
             text: String::with_capacity(512),
+            cursor: 0,
+            oversize: None,
+            rejected: false,
+            recall: Recall::default(),
+            reply_to: NOT_POST_ID,
+        }
+    }
+
+    // the text is public and may be replaced leaving the cursor behind
+    fn cursor(&self) -> usize {
+        let mut at = self.cursor.min(self.text.len());
+        while !self.text.is_char_boundary(at) {
+            at -= 1;
+        }
+        at
+    }
+
+    pub fn set_text(&mut self, text: String) {
+        self.cursor = text.len();
+        self.text = text;
+        self.rejected = false;
+    }
+
+    // returns the range of the inserted text, cut to the room left up to MAX_INPUT_LEN
+    pub fn insert(&mut self, s: &str) -> Range<usize> {
+        let mut len = s.len().min(MAX_INPUT_LEN.saturating_sub(self.text.len()));
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        let s = &s[..len];
+        let at = self.cursor();
+        self.text.insert_str(at, s);
+        self.cursor = at + s.len();
+        at..self.cursor
+    }
+
+    // the char before the cursor
+    pub fn backspace(&mut self) -> bool {
+        let at = self.cursor();
+        match self.text[..at].chars().next_back() {
+            Some(c) => {
+                self.cursor = at - c.len_utf8();
+                self.text.remove(self.cursor);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // the char under the cursor
+    pub fn delete(&mut self) -> bool {
+        let at = self.cursor();
+        if at < self.text.len() {
+            self.text.remove(at);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn move_left(&mut self) {
+        let at = self.cursor();
+        if let Some(c) = self.text[..at].chars().next_back() {
+            self.cursor = at - c.len_utf8();
+        }
+    }
+
+    pub fn move_right(&mut self) {
+        let at = self.cursor();
+        if let Some(c) = self.text[at..].chars().next() {
+            self.cursor = at + c.len_utf8();
+        }
+    }
+
+    // the start of the line of the cursor
+    fn line_start(&self) -> usize {
+        let at = self.cursor();
+        self.text[..at].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    pub fn move_home(&mut self) {
+        self.cursor = self.line_start();
+    }
+
+    pub fn move_end(&mut self) {
+        let at = self.cursor();
+        self.cursor = self.text[at..]
+            .find('\n')
+            .map_or(self.text.len(), |i| at + i);
+    }
+
+    // the whitespace before the cursor and the word before it, as Ctrl+W in the shell
+    pub fn delete_word(&mut self) -> bool {
+        let at = self.cursor();
+        let from = self.text[..at]
+            .trim_end()
+            .trim_end_matches(|c: char| !c.is_whitespace())
+            .len();
+        self.text.replace_range(from..at, "");
+        self.cursor = from;
+        from < at
+    }
+
+    // the line from its start up to the cursor, as Ctrl+U in the shell
+    pub fn clear_line(&mut self) -> bool {
+        let at = self.cursor();
+        let from = self.line_start();
+        self.text.replace_range(from..at, "");
+        self.cursor = from;
+        from < at
+    }
+
+    // the cursor goes before the char at the display column of its line
+    pub fn set_cursor_column(&mut self, column: usize) {
+        let start = self.line_start();
+        let end = self.text[start..]
+            .find('\n')
+            .map_or(self.text.len(), |i| start + i);
+        let mut width = 0;
+        for (i, c) in self.text[start..end].char_indices() {
+            width += c.width().unwrap_or(0);
+            if width > column {
+                self.cursor = start + i;
+                return;
+            }
+        }
+        self.cursor = end;
+    }
+
+    // display width of the line up to the cursor, the wide chars take two columns
+    pub fn cursor_column(&self) -> usize {
+        self.text[self.line_start()..self.cursor()].width()
+    }
+}
+
+// the way the posts in the background chats are told
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Notify {
+    // the bell rings and the title is marked
+    Bell,
+    // the title is marked only
+    Title,
+    Off,
+}
+
+impl FromStr for Notify {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bell" => Ok(Notify::Bell),
+            "title" => Ok(Notify::Title),
+            "off" => Ok(Notify::Off),
+            _ => Err(format!("unknown notify '{}'", s)),
+        }
+    }
+}
+
+const DEF_UNSEND_GRACE: Duration = Duration::from_secs(15);
+// posts arriving later than that after their creation show the time of delivery
+const DEF_LATE_DELIVERY: Duration = Duration::from_secs(5 * 60);
+// posts kept per unknown chat until the chat is received
+const ORPHAN_POSTS_CAPACITY: usize = 256;
+// post text working as the unsend action
+const UNSEND_COMMAND: &str = ":unsend";
+// post text working as the reconnect action
+const RECONNECT_COMMAND: &str = ":reconnect";
+// post text starting with it manages the filters of posts
+const FILTER_COMMAND: &str = ":filter";
+// the shortcodes of the common reactions, the others are sent as typed
+const REACTION_SHORTCODES: [(&str, &str); 6] = [
+    (":+1:", "👍"),
+    (":-1:", "👎"),
+    (":heart:", "❤️"),
+    (":joy:", "😂"),
+    (":tada:", "🎉"),
+    (":eyes:", "👀"),
+];
+// the preview of the post replied to is cut to it, in chars
+const REPLY_PREVIEW_LEN: usize = 60;
+// the input text is cut to it, in bytes, the oversized posts are attached well below it
+const MAX_INPUT_LEN: usize = 256 * 1024;
+// recalled with Up/Down while typing
+const POST_HISTORY_CAPACITY: usize = 20;
+const CHAT_SPEC_HISTORY_CAPACITY: usize = 50;
+// ids of the received posts remembered to drop the repeated ones
+const SEEN_POSTS_CAPACITY: usize = 4096;
+// posts scrolled by PageUp/PageDown
+const POSTS_PAGE: usize = 10;
+// elder posts fetched at once when scrolled above the received ones
+const HISTORY_PAGE: usize = 50;
+
+// ids of the recently received posts, the oldest ones are forgotten first
+struct SeenPosts {
+    ids: HashSet<PostId>,
+    order: VecDeque<PostId>,
+    capacity: usize,
+}
+
+impl SeenPosts {
+    fn new(capacity: usize) -> Self {
+        SeenPosts {
+            ids: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    // false if the id has been seen already
+    fn insert(&mut self, post_id: PostId) -> bool {
+        if !self.ids.insert(post_id) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.order.push_back(post_id);
+        true
+    }
+}
+
+// the post arrived long after its creation, the clocks may differ a bit
+fn is_late(threshold: Duration, created: u64, received_at: u64) -> bool {
+    threshold > Duration::from_secs(0) && received_at.saturating_sub(created) > threshold.as_secs()
+}
+
+// the post of the server, e.g. telling who entered the chat
+fn is_system(post: &proto::Post) -> bool {
+    post.kind() == proto::PostKind::System
+}
+
+// the posts of the others count as unread, the system ones never do
+fn is_unread(post: &proto::Post, user_id: UserId) -> bool {
+    post.user_id != user_id && !is_system(post)
+}
+
+// bytes up to 1 KiB, larger sizes in KiB or MiB with a decimal
+fn get_size_text(size: u64) -> String {
+    const KIB: u64 = 1024;
+    const MIB: u64 = 1024 * KIB;
+    if size < KIB {
+        format!("{} B", size)
+    } else if size < MIB {
+        format!("{:.1} KiB", size as f64 / KIB as f64)
+    } else {
+        format!("{:.1} MiB", size as f64 / MIB as f64)
+    }
+}
+
+// the online users first, then by the short names
+fn user_order(a: &proto::User, b: &proto::User, online: &[UserId]) -> Ordering {
+    let is_offline = |u: &proto::User| !online.contains(&u.id);
+    is_offline(a)
+        .cmp(&is_offline(b))
+        .then_with(|| {
+            a.short_name
+                .to_lowercase()
+                .cmp(&b.short_name.to_lowercase())
+        })
+        .then_with(|| a.id.cmp(&b.id))
+}
+
+// the case is ignored, the empty filter matches everyone
+fn user_matches(user: &proto::User, filter: &str) -> bool {
+    let filter = filter.to_lowercase();
+    user.short_name.to_lowercase().contains(&filter) || user.name.to_lowercase().contains(&filter)
+}
+
+// the index the item selected before has got after reordering
+fn pinned_index<T: PartialEq>(items: impl IntoIterator<Item = T>, sel: Option<T>) -> Option<usize> {
+    let sel = sel?;
+    items.into_iter().position(|item| item == sel)
+}
+
+// the post to forward and the chats to pick from, the own chat of the post is not offered
+pub struct Forwarding {
+    post: proto::Post,
+    pub targets: Vec<ChatId>,
+    pub state: ListState,
+}
+
+impl Forwarding {
+    // the selection stays on the list
+    fn pick(&mut self, step: isize) {
+        let last = self.targets.len().saturating_sub(1) as isize;
+        let cur = self.state.selected().unwrap_or(0) as isize;
+        self.state
+            .select(Some((cur + step).max(0).min(last) as usize));
+    }
+}
+
+// the posts the server has found in all the chats, the newest first
+pub struct FoundPosts {
+    pub query: String,
+    pub posts: Vec<proto::Post>,
+    pub state: ListState,
+}
+
+impl FoundPosts {
+    // the selection stays on the list
+    fn pick(&mut self, step: isize) {
+        let last = self.posts.len().saturating_sub(1) as isize;
+        let cur = self.state.selected().unwrap_or(0) as isize;
+        self.state
+            .select(Some((cur + step).max(0).min(last) as usize));
+    }
+}
+
+// the latest own post which still can be taken back
+pub struct PendingUnsend {
+    pub chat_id: ChatId,
+    pub post_id: PostId,
+    expires: Instant,
+}
+
+impl PendingUnsend {
+    pub fn remaining(&self) -> Duration {
+        self.expires.saturating_duration_since(Instant::now())
+    }
+}
+
+// the backoff of the client while disconnected
+pub struct Reconnect {
+    pub attempt: u32,
+    // None while the attempt is being made
+    at: Option<Instant>,
+    pub remaining_secs: u64,
+}
+
+impl Reconnect {
+    fn update(&mut self, now: Instant) {
+        if let Some(at) = self.at {
+            self.remaining_secs = countdown_secs(at.saturating_duration_since(now));
+        }
+    }
+
+    pub fn text(&self) -> String {
+        match self.at {
+            Some(_) => format!(
+                "reconnecting in {}s, attempt {}",
+                self.remaining_secs, self.attempt
+            ),
+            None => format!("reconnecting, attempt {}", self.attempt),
         }
     }
 }
 
+// own post sent but not confirmed by the server yet
+pub struct PendingPost {
+    pub chat_id: ChatId,
+    pub text: String,
+    // known once accepted, the post is pending until it comes back from the server
+    post_id: Option<PostId>,
+    // given back after every attempt to send it has failed, kept to be sent again
+    failed: Option<proto::Post>,
+}
+
+impl PendingPost {
+    pub fn is_failed(&self) -> bool {
+        self.failed.is_some()
+    }
+}
+
 pub struct ChatInfo {
     // the chat itself
     pub chat: proto::Chat,
@@ -73,16 +600,78 @@ pub struct ChatInfo {
     pub history_len: usize,
     // posts
     pub posts: LinkedList<proto::Post>,
+    // scroll position is kept while other chats are viewed
+    pub posts_state: ListState,
+    // the first post drawn, the clicks are resolved against it
+    pub posts_offset: usize,
+    // posts of others came while the chat was not selected
+    pub unread: usize,
+    // the server failed to read stored posts, the history may be incomplete
+    pub degraded: bool,
+    // posts hidden by the filters, kept to be revealed
+    pub filtered: HashSet<PostId>,
+    // local arrival time of the posts delivered late, unix secs, kept for the session only
+    pub delivered: HashMap<PostId, u64>,
+    // some of the unread posts have been delivered late
+    pub late_unread: bool,
+    // my relation to the chat as the server tells it, my id is not looked up in the users
+    pub membership: proto::Membership,
+    // members typing now as the server tells it, kept for the connection only
+    pub typing: BTreeSet<UserId>,
+    // the last post told read to the server
+    pub last_read: PostId,
+    // creation time of the newest post received, zero if none
+    pub last_post_at: u64,
+    // elder posts are being fetched, not to ask for them twice
+    pub history_requested: bool,
 }
 
 impl ChatInfo {
+    // the chats with the recent posts come first, the named chat is as recent as its creation
+    // until posted to
+    fn activity(&self) -> u64 {
+        if self.last_post_at == 0 && !self.chat.description.is_empty() {
+            self.chat.created
+        } else {
+            self.last_post_at
+        }
+    }
+
+    pub fn is_member(&self) -> bool {
+        self.membership == proto::Membership::Member
+    }
+
     pub fn get_posts_count(&self) -> usize {
         self.posts.len() + self.history_len
     }
 
-    fn insert_history(&mut self, posts: Vec<proto::Post>) {
+    // posts in the list, the elder ones are not there until fetched
+    pub fn get_shown_count(&self, reveal_filtered: bool) -> usize {
+        if reveal_filtered {
+            self.posts.len()
+        } else {
+            self.posts.len().saturating_sub(self.filtered.len())
+        }
+    }
+
+    pub fn find_post(&self, post_id: PostId) -> Option<&proto::Post> {
+        self.posts.iter().find(|p| p.id == post_id)
+    }
+
+    // the seq of the eldest post held, the page of the history ends before it;
+    // none if the server does not number the posts
+    fn history_cursor(&self) -> Option<u64> {
+        self.posts.front().map(|p| p.seq).filter(|seq| *seq != 0)
+    }
+
+    fn insert_history(&mut self, mut posts: Vec<proto::Post>) {
         let cnt = posts.len();
         if cnt > 0 {
+            posts.sort_by_key(|p| p.seq);
+            // those held already, e.g. arrived while the page was on the way
+            if let Some(cursor) = self.history_cursor() {
+                posts.retain(|p| p.seq < cursor);
+            }
             let mut history: LinkedList<proto::Post> = posts.into_iter().collect();
             history.append(&mut self.posts);
             self.posts = history;
@@ -95,8 +684,30 @@ impl ChatInfo {
         }
     }
 
+    // the post delivered out of order takes its place by the seq,
+    // the unnumbered ones are kept in the order of delivery
     fn push(&mut self, post: proto::Post) {
+        let later = self
+            .posts
+            .iter()
+            .rev()
+            .take_while(|p| post.seq != 0 && p.seq > post.seq)
+            .count();
+        let mut tail = self.posts.split_off(self.posts.len() - later);
         self.posts.push_back(post);
+        self.posts.append(&mut tail);
+    }
+
+    fn remove(&mut self, post_id: PostId) {
+        let posts = std::mem::take(&mut self.posts);
+        self.posts = posts.into_iter().filter(|p| p.id != post_id).collect();
+        self.filtered.remove(&post_id);
+        self.delivered.remove(&post_id);
+    }
+
+    // the newest post received so far
+    fn high_water_mark(&self) -> Option<u64> {
+        self.posts.iter().map(|p| p.created).max()
     }
 }
 
@@ -105,15 +716,86 @@ pub struct App {
     pub users: Vec<proto::User>,
     pub online: Vec<UserId>,
     pub users_state: ListState,
-    pub chats: HashMap<ChatId, ChatInfo>,
+    // the lists show the matching entries only, the selection is the index among them
+    pub users_filter: String,
+    pub chats_filter: String,
+    pub chats: BTreeMap<ChatId, ChatInfo>,
+    // the ids of the chats as shown, the selection follows the chat when they are re-sorted
+    chats_order: Vec<ChatId>,
     pub chats_state: ListState,
-    pub posts_state: ListState,
     pub logger_state: TuiWidgetState,
     pub user_description: String,
     pub user: proto::User,
     pub extended_log: bool,
+    pub keys: KeyBindings,
+    // zero disables unsending
+    pub unsend_grace: Duration,
+    // zero disables showing the delivery time
+    pub late_delivery: Duration,
+    // failure of the last request
+    pub notice: Option<String>,
+    pub connection: Connection,
+    pub reconnect: Option<Reconnect>,
+    // the users, the chats and the posts shown are cached ones, not confirmed by the server yet
+    pub stale: bool,
+    pub server_address: String,
+    // counted words follow its plural rules
+    pub language: Language,
+    pub notify: Notify,
+    pub user_colors: UserColors,
+    // the bell is rung by the terminal loop
+    bell: bool,
+    // the chats told of neither by the bell nor by the title
+    muted: HashSet<ChatId>,
+    // events of the server waiting for the UI
+    pub relay_stats: Arc<RelayStats>,
+    pub composer_limits: ComposerLimits,
+    // those of the server, the post refused by them is not sent
+    pub post_limits: PostLimits,
+    // the server takes attachments: files are sent and oversized post can be attached
+    pub attachments: bool,
+    // the invitation dialog offers accepting as the default answer
+    pub accept_invitations: bool,
+    // those of the config are kept when the user's ones are cleared
+    pub config_filters: Vec<FilterRule>,
+    pub filters: Vec<FilterRule>,
+    // the filters added by the user are saved there
+    pub filters_file: Option<PathBuf>,
+    // the filtered posts are shown temporarily
+    pub reveal_filtered: bool,
+    // the matches of the selected chat are highlighted and cycled through
+    pub search: Option<SearchState>,
 
     tx_command: mpsc::Sender<Command>,
+    // beginning of a multi-key sequence typed so far
+    pending_keys: Vec<Chord>,
+    unsend: Option<PendingUnsend>,
+    pending_posts: Vec<PendingPost>,
+    // posts came before their chats
+    orphan_posts: HashMap<ChatId, Vec<proto::Post>>,
+    // replayed backlogs and the races of the streams repeat posts
+    seen_posts: SeenPosts,
+    // the chats whose members were asked for, not to repeat the request
+    members_requested: HashSet<ChatId>,
+    // the first one is being confirmed
+    invitations: VecDeque<proto::Invitation>,
+    // the chat to leave once confirmed, asked before the invitations
+    leaving: Option<ChatId>,
+    // the post waiting for the chat to be forwarded to
+    pub forwarding: Option<Forwarding>,
+    // the results of the search in all the chats being looked through
+    pub found: Option<FoundPosts>,
+    // the user whose dialog is selected once it arrives
+    dialog_with: Option<UserId>,
+    // the first line of the help shown
+    pub help_scroll: u16,
+    // the layout drawn the last time
+    pub areas: Areas,
+    // texts sent to every chat, the login is never recorded
+    post_history: HashMap<ChatId, InputHistory>,
+    chat_spec_history: InputHistory,
+    // not to fill the command channel while typing fast
+    typing: TypingThrottle,
     focused: Widget,
     modal: Widget,
     pub input: Option<InputMode>,
@@ -124,6 +806,7 @@ impl App {
         user: proto::UserInfo,
         tx_command: mpsc::Sender<Command>,
         extended_log: bool,
+        keys: KeyBindings,
     ) -> Self {
         let need_user_info = user.name.is_empty() && user.short_name.is_empty();
         let modal = if need_user_info {
@@ -144,9 +827,11 @@ impl App {
             users: Vec::new(),
             online: Vec::new(),
             users_state: ListState::default(),
-            chats: HashMap::new(),
+            users_filter: String::new(),
+            chats_filter: String::new(),
+            chats: BTreeMap::new(),
+            chats_order: Vec::new(),
             chats_state: ListState::default(),
-            posts_state: ListState::default(),
             logger_state: TuiWidgetState::new(),
             user_description: format!("{}", user),
             user: proto::User {
@@ -156,7 +841,46 @@ impl App {
                 ..Default::default()
             },
             extended_log,
+            keys,
+            unsend_grace: DEF_UNSEND_GRACE,
+            late_delivery: DEF_LATE_DELIVERY,
+            notice: None,
+            connection: Connection::Connecting,
+            reconnect: None,
+            stale: false,
+            server_address: String::new(),
+            language: Language::English,
+            notify: Notify::Off,
+            user_colors: UserColors::default(),
+            bell: false,
+            muted: HashSet::new(),
+            relay_stats: Arc::new(RelayStats::default()),
+            composer_limits: ComposerLimits::default(),
+            post_limits: PostLimits::default(),
+            attachments: false,
+            accept_invitations: false,
+            config_filters: Vec::new(),
+            filters: Vec::new(),
+            filters_file: None,
+            reveal_filtered: false,
+            search: None,
             tx_command,
+            pending_keys: Vec::new(),
+            unsend: None,
+            pending_posts: Vec::new(),
+            orphan_posts: HashMap::new(),
+            seen_posts: SeenPosts::new(SEEN_POSTS_CAPACITY),
+            members_requested: HashSet::new(),
+            invitations: VecDeque::new(),
+            leaving: None,
+            forwarding: None,
+            found: None,
+            dialog_with: None,
+            help_scroll: 0,
+            areas: Areas::default(),
+            post_history: HashMap::new(),
+            chat_spec_history: InputHistory::new(CHAT_SPEC_HISTORY_CAPACITY),
+            typing: TypingThrottle::default(),
             focused: Widget::Chats,
             modal,
             input,
@@ -173,454 +897,5054 @@ impl App {
         }
     }
 
+    pub fn get_help_lines(&self) -> Vec<String> {
+        self.keys.help_lines()
+    }
+
     pub fn on_up(&mut self) {
         match self.modal {
             Widget::Log => {
                 self.logger_state.transition(&TuiWidgetEvent::UpKey);
             }
-            Widget::App => match self.focused {
-                Widget::Users => App::list_previous(&mut self.users_state, self.users.len()),
-                Widget::Chats => {
-                    App::list_previous(&mut self.chats_state, self.chats.len());
-                    // download elder posts if any
-                    if let Some(sel) = self.get_sel_chat() {
-                        if sel.history_len > 0 {
-                            if let Err(e) = self.tx_command.blocking_send(Command::GetHistory(
-                                proto::HistoryParams {
-                                    chat_id: sel.chat.id,
-                                    idx_from: 0,
-                                    count: sel.history_len as u64,
-                                },
-                            )) {
-                                error!("failed creating chat: {}", e);
-                            }
-                        }
-                    }
+            Widget::Input => self.recall_input(true),
+            Widget::Help => self.help_scroll = self.help_scroll.saturating_sub(1),
+            Widget::Picker => {
+                if let Some(forwarding) = self.forwarding.as_mut() {
+                    forwarding.pick(-1);
                 }
-                Widget::Posts => {
-                    let cnt = self
-                        .get_sel_chat()
-                        .map(|c| c.get_posts_count())
-                        .unwrap_or_default();
-                    App::list_previous(&mut self.posts_state, cnt);
+            }
+            Widget::Results => {
+                if let Some(found) = self.found.as_mut() {
+                    found.pick(-1);
+                }
+            }
+            Widget::App | Widget::Filter => match self.focused {
+                Widget::Users => {
+                    let cnt = self.get_users().count();
+                    App::list_previous(&mut self.users_state, cnt);
+                }
+                Widget::Chats => {
+                    let cnt = self.get_chats().count();
+                    App::list_previous(&mut self.chats_state, cnt);
+                    self.on_chat_switched();
                 }
+                Widget::Posts => self.scroll_posts(-1),
                 _ => {}
             },
             _ => {}
         }
     }
 
-    pub fn on_down(&mut self) {
+    pub fn on_page_up(&mut self) {
         match self.modal {
             Widget::Log => {
-                self.logger_state.transition(&TuiWidgetEvent::DownKey);
+                self.logger_state.transition(&TuiWidgetEvent::PrevPageKey);
+            }
+            Widget::App if self.focused == Widget::Posts => {
+                self.scroll_posts(-(POSTS_PAGE as isize))
             }
-            Widget::App => match self.focused {
-                Widget::Chats => {
-                    App::list_next(&mut self.chats_state, self.chats.len());
-                    //download elder posts if any
-                    if let Some(sel) = self.get_sel_chat() {
-                        if sel.history_len > 0 {
-                            if let Err(e) = self.tx_command.blocking_send(Command::GetHistory(
-                                proto::HistoryParams {
-                                    chat_id: sel.chat.id,
-                                    idx_from: 0,
-                                    count: sel.history_len as u64,
-                                },
-                            )) {
-                                error!("failed creating chat: {}", e);
-                            }
-                        }
-                    }
-                }
-                Widget::Users => App::list_next(&mut self.users_state, self.users.len()),
-                Widget::Posts => {
-                    let cnt = self
-                        .get_sel_chat()
-                        .map(|c| c.get_posts_count())
-                        .unwrap_or_default();
-                    App::list_next(&mut self.posts_state, cnt);
-                }
-                _ => {}
-            },
             _ => {}
         }
     }
 
-    pub fn on_right(&mut self) {
+    pub fn on_page_down(&mut self) {
         match self.modal {
             Widget::Log => {
-                self.logger_state.transition(&TuiWidgetEvent::RightKey);
+                self.logger_state.transition(&TuiWidgetEvent::NextPageKey);
             }
-            Widget::App => match self.focused {
-                Widget::Users => self.focused = Widget::Chats,
-                Widget::Chats => self.focused = Widget::Posts,
-                _ => {}
-            },
+            Widget::App if self.focused == Widget::Posts => self.scroll_posts(POSTS_PAGE as isize),
             _ => {}
         }
     }
 
-    pub fn on_left(&mut self) {
+    pub fn on_home(&mut self) {
         match self.modal {
-            Widget::Log => {
-                self.logger_state.transition(&TuiWidgetEvent::LeftKey);
+            Widget::Input => {
+                if let Some(input) = self.input.as_mut() {
+                    input.move_home();
+                }
             }
-            Widget::App => match self.focused {
-                Widget::Chats => self.focused = Widget::Users,
-                Widget::Posts => self.focused = Widget::Chats,
-                _ => {}
-            },
+            Widget::App if self.focused == Widget::Posts => self.scroll_posts(isize::MIN),
             _ => {}
         }
     }
 
-    pub fn on_enter(&mut self) {
+    pub fn on_end(&mut self) {
         match self.modal {
-            Widget::Log => {
-                self.logger_state.transition(&TuiWidgetEvent::FocusKey);
-            }
             Widget::Input => {
-                // accept input:
-                if let Some(input) = &self.input {
-                    match input.purpose {
-                        InputResult::NewChat => {
-                            let mut desired_users = Vec::new();
-                            if let Some(user) = self.get_sel_user() {
-                                desired_users.push(user.id);
-                            }
-                            if let Err(e) = self.tx_command.blocking_send(Command::CreateChat(
-                                proto::ChatInfo {
-                                    user_id: self.user.id,
-                                    permanent: true,
-                                    auto_enter: true,
-                                    description: input.text.clone(),
-                                    desired_users,
-                                },
-                            )) {
-                                error!("failed creating chat: {}", e);
-                            }
-                        }
-                        InputResult::NewPost => {
-                            if let Some(sel) = self.get_sel_chat() {
-                                let chat_id = sel.chat.id;
-                                if let Err(e) =
-                                    self.tx_command.blocking_send(Command::Post(proto::Post {
-                                        id: proto::NOT_POST_ID,
-                                        user_id: self.user.id,
-                                        chat_id,
-                                        text: input.text.clone(),
-                                        ..Default::default()
-                                    }))
-                                {
-                                    error!("failed creating post: {}", e);
-                                }
-                            }
-                        }
-                        InputResult::UserInfo => {
-                            if let Ok(info) = input.text.parse::<proto::UserInfo>() {
-                                self.user_description = format!("{}", &info);
-                                self.user.name = info.name.clone();
-                                self.user.short_name = info.short_name.clone();
-                                if let Err(e) =
-                                    self.tx_command.blocking_send(Command::Register(info))
-                                {
-                                    error!("failed to send command to register: {}", e);
-                                }
-                            } else {
-                                // remaining modal state of input
-                                return;
-                            }
-                        }
-                    }
+                if let Some(input) = self.input.as_mut() {
+                    input.move_end();
                 }
-                self.input = None;
-                // restore previous modal widget:
-                self.modal = Widget::App;
             }
+            Widget::App if self.focused == Widget::Posts => self.scroll_posts(isize::MAX),
             _ => {}
+        }
+    }
+
+    // moves the selected post by the rows, moving above the first one fetches the elder posts
+    fn scroll_posts(&mut self, rows: isize) {
+        let reveal_filtered = self.reveal_filtered;
+        let above_top = match self.get_sel_chat_mut() {
+            Some(sel) => {
+                let cnt = sel.get_shown_count(reveal_filtered);
+                let cur = sel.posts_state.selected().unwrap_or_default() as isize;
+                let target = cur.saturating_add(rows);
+                let last = cnt.saturating_sub(1) as isize;
+                if cnt > 0 {
+                    sel.posts_state
+                        .select(Some(target.max(0).min(last) as usize));
+                }
+                target < 0
+            }
+            None => return,
         };
+        if above_top {
+            self.request_history();
+        } else if rows > 0 {
+            self.mark_sel_read();
+        }
     }
 
-    pub fn on_esc(&mut self) {
+    // the newest match up to the selected post is selected first
+    fn start_search(&mut self, chat_id: ChatId, query: String) {
+        self.search = None;
+        if query.is_empty() || self.get_sel_chat().map(|c| c.chat.id) != Some(chat_id) {
+            return;
+        }
+        self.search = Some(SearchState::new(chat_id, query));
+        self.update_search();
+        let selected = self.get_sel_chat().and_then(|c| c.posts_state.selected());
+        self.select_match(selected.map(|idx| idx + 1), true);
+    }
+
+    // the matches follow the shown posts of the selected chat
+    fn update_search(&mut self) {
+        let sel_chat_id = self.get_sel_chat().map(|c| c.chat.id);
+        match self.search.as_ref() {
+            Some(search) if Some(search.chat_id) == sel_chat_id => {}
+            Some(_) => {
+                self.search = None;
+                return;
+            }
+            None => return,
+        }
+        let posts = self.get_sel_posts();
+        if let Some(search) = self.search.as_mut() {
+            search.update(&posts);
+        }
+    }
+
+    // the match older or newer than the post, none loaded fetches the elder posts
+    fn select_match(&mut self, from: Option<usize>, older: bool) {
+        let found = match self.search.as_ref() {
+            Some(search) => search.next_match(from, older),
+            None => return,
+        };
+        match found {
+            Some(idx) => {
+                self.notice = None;
+                self.focused = Widget::Posts;
+                if let Some(sel) = self.get_sel_chat_mut() {
+                    sel.posts_state.select(Some(idx));
+                }
+            }
+            None => self.search_older(),
+        }
+    }
+
+    // a page at a time up to the limit, the search goes on once the page comes
+    fn search_older(&mut self) {
+        let has_history = self.get_sel_chat().map_or(false, |c| c.history_len > 0);
+        let search = match self.search.as_mut() {
+            Some(search) => search,
+            None => return,
+        };
+        if has_history && search.pages < MAX_SEARCH_PAGES {
+            search.pages += 1;
+            self.notice = Some(format!(
+                "search: looking for '{}' further back",
+                search.query
+            ));
+            self.request_history();
+        } else {
+            self.notice = Some(format!("search: nothing matches '{}'", search.query));
+        }
+    }
+
+    // fetches the page of the elder posts right before the received ones, one page at a time
+    fn request_history(&mut self) {
+        let params = match self.get_sel_chat() {
+            Some(sel) if sel.history_len > 0 && !sel.history_requested => {
+                let count = sel.history_len.min(HISTORY_PAGE);
+                proto::HistoryParams {
+                    chat_id: sel.chat.id,
+                    idx_from: (sel.history_len - count) as u64,
+                    count: count as u64,
+                    before_seq: 0,
+                }
+            }
+            _ => return,
+        };
+        let chat_id = params.chat_id;
+        if let Err(e) = self.tx_command.blocking_send(Command::GetHistory(params)) {
+            error!("failed requesting history: {}", e);
+        } else if let Some(chat) = self.chats.get_mut(&chat_id) {
+            chat.history_requested = true;
+        }
+    }
+
+    pub fn on_down(&mut self) {
         match self.modal {
-            Widget::Input => {
-                if let Some(mode) = &self.input {
-                    if mode.purpose != InputResult::UserInfo {
-                        self.modal = Widget::App
-                    }
+            Widget::Log => {
+                self.logger_state.transition(&TuiWidgetEvent::DownKey);
+            }
+            Widget::Input => self.recall_input(false),
+            Widget::Help => {
+                let last = self.keys.help_lines().len().saturating_sub(1) as u16;
+                self.help_scroll = (self.help_scroll + 1).min(last);
+            }
+            Widget::Picker => {
+                if let Some(forwarding) = self.forwarding.as_mut() {
+                    forwarding.pick(1);
                 }
             }
-            Widget::App => match self.focused {
-                Widget::Users => {
-                    self.users_state.select(None);
+            Widget::Results => {
+                if let Some(found) = self.found.as_mut() {
+                    found.pick(1);
                 }
+            }
+            Widget::App | Widget::Filter => match self.focused {
                 Widget::Chats => {
-                    self.chats_state.select(None);
+                    let cnt = self.get_chats().count();
+                    App::list_next(&mut self.chats_state, cnt);
+                    self.on_chat_switched();
+                }
+                Widget::Users => {
+                    let cnt = self.get_users().count();
+                    App::list_next(&mut self.users_state, cnt);
                 }
                 Widget::Posts => {
-                    self.posts_state.select(None);
+                    let reveal_filtered = self.reveal_filtered;
+                    if let Some(sel) = self.get_sel_chat_mut() {
+                        let cnt = sel.get_shown_count(reveal_filtered);
+                        App::list_next(&mut sel.posts_state, cnt);
+                    }
+                    self.mark_sel_read();
                 }
                 _ => {}
             },
-            _ => {
-                error!("widget {:?} must not be modal", self.modal);
-                self.modal = Widget::App;
+            _ => {}
+        }
+    }
+
+    pub fn on_right(&mut self) {
+        match self.modal {
+            Widget::Log => {
+                self.logger_state.transition(&TuiWidgetEvent::RightKey);
             }
+            Widget::Input => match self.input.as_mut() {
+                Some(InputMode {
+                    oversize: Some(choice),
+                    ..
+                }) => choice.next(),
+                Some(input) => input.move_right(),
+                None => {}
+            },
+            Widget::App => match self.focused {
+                Widget::Users => self.focused = Widget::Chats,
+                Widget::Chats => self.focused = Widget::Posts,
+                _ => {}
+            },
+            _ => {}
         }
     }
 
-    pub fn on_key(&mut self, c: char, ctrl: bool, alt: bool) {
-        // exit in any modal widget
-        if ctrl && c == 'q' {
-            if let Err(e) = self.tx_command.blocking_send(Command::Exit) {
-                error!("failed sending Exit command: {}", e);
+    pub fn on_left(&mut self) {
+        match self.modal {
+            Widget::Log => {
+                self.logger_state.transition(&TuiWidgetEvent::LeftKey);
             }
-            return;
+            Widget::Input => match self.input.as_mut() {
+                Some(InputMode {
+                    oversize: Some(choice),
+                    ..
+                }) => choice.previous(),
+                Some(input) => input.move_left(),
+                None => {}
+            },
+            Widget::App => match self.focused {
+                Widget::Chats => self.focused = Widget::Users,
+                Widget::Posts => self.focused = Widget::Chats,
+                _ => {}
+            },
+            _ => {}
         }
-        if self.modal == Widget::Input {
-            if let Some(input) = self.input.as_mut() {
-                input.text.push(c);
-            } else {
-                error!("input mode is not init properly");
+    }
+
+    pub fn on_enter(&mut self) {
+        match self.modal {
+            Widget::Log => {
+                self.logger_state.transition(&TuiWidgetEvent::FocusKey);
             }
-        } else {
-            match c {
-                ' ' => {
-                    if self.modal == Widget::Log {
-                        self.logger_state.transition(&TuiWidgetEvent::SpaceKey);
+            // the filter is kept applied
+            Widget::Filter => self.close_modal(),
+            Widget::Input => {
+                let choice = self
+                    .input
+                    .as_ref()
+                    .and_then(|i| i.oversize.as_ref())
+                    .map(|c| c.action());
+                if let Some(action) = choice {
+                    self.apply_oversize(action);
+                    return;
+                }
+                self.record_post_text();
+                let unsend = self.input.as_ref().map_or(false, |input| {
+                    input.purpose == InputResult::NewPost && input.text.trim() == UNSEND_COMMAND
+                });
+                if unsend {
+                    self.input = None;
+                    self.close_modal();
+                    self.apply_action(Action::Unsend);
+                    return;
+                }
+                let reconnect = self.input.as_ref().map_or(false, |input| {
+                    input.purpose == InputResult::NewPost && input.text.trim() == RECONNECT_COMMAND
+                });
+                if reconnect {
+                    self.input = None;
+                    self.close_modal();
+                    self.apply_action(Action::ReconnectNow);
+                    return;
+                }
+                let filter_args = self.input.as_ref().and_then(|input| {
+                    let text = input.text.trim();
+                    if input.purpose == InputResult::NewPost && text.starts_with(FILTER_COMMAND) {
+                        Some(String::from(text[FILTER_COMMAND.len()..].trim()))
+                    } else {
+                        None
                     }
+                });
+                if let Some(args) = filter_args {
+                    self.input = None;
+                    self.close_modal();
+                    self.on_filter_command(&args);
+                    return;
                 }
-                '-' => {
-                    if self.modal == Widget::Log {
-                        self.logger_state.transition(&TuiWidgetEvent::MinusKey);
+                let search = self.input.as_ref().and_then(|input| match input.purpose {
+                    InputResult::Search(chat_id) => {
+                        Some((chat_id, String::from(input.text.trim())))
                     }
+                    _ => None,
+                });
+                if let Some((chat_id, query)) = search {
+                    self.input = None;
+                    self.close_modal();
+                    self.start_search(chat_id, query);
+                    return;
                 }
-                '+' => {
-                    if self.modal == Widget::Log {
-                        self.logger_state.transition(&TuiWidgetEvent::PlusKey);
+                // the post the server would refuse is kept to be fixed
+                let refused = self.input.as_ref().and_then(|input| {
+                    if input.purpose == InputResult::NewPost {
+                        let text = normalize_line_endings(&input.text);
+                        self.post_limits.check(&text, 0).err()
+                    } else {
+                        None
+                    }
+                });
+                if let Some(e) = refused {
+                    self.notice = Some(format!("not sent: {}", e));
+                    if let Some(input) = self.input.as_mut() {
+                        input.rejected = true;
                     }
+                    return;
                 }
-                'n' => {
-                    if ctrl {
-                        // create new item
-                        match self.focused {
-                            Widget::Chats => {
-                                self.focused = Widget::Chats;
-                                self.modal = Widget::Input;
-                                // setup input mode:
-                                self.input = Some(InputMode::new_chat());
+                // accept input:
+                if let Some(input) = &self.input {
+                    match input.purpose {
+                        InputResult::NewChat => {
+                            let resolved = input.text.parse::<ChatSpec>().and_then(|spec| {
+                                spec.resolve(self.user.id, |login| {
+                                    self.find_user(login).map(|u| u.id)
+                                })
+                            });
+                            let mut chat_info = match resolved {
+                                Ok(chat_info) => chat_info,
+                                Err(e) => {
+                                    // remaining modal state of input to fix the spec
+                                    self.notice = Some(format!("new chat: {}", e));
+                                    return;
+                                }
+                            };
+                            if let Some(user) = self.get_sel_user() {
+                                if !chat_info.desired_users.contains(&user.id) {
+                                    chat_info.desired_users.push(user.id);
+                                }
                             }
-                            Widget::Posts => {
-                                self.focused = Widget::Posts;
-                                self.modal = Widget::Input;
-                                // setup input mode:
-                                self.input = Some(InputMode::new_post());
+                            self.notice = None;
+                            self.chat_spec_history.record(&input.text);
+                            if let Err(e) = self
+                                .tx_command
+                                .blocking_send(Command::CreateChat(chat_info))
+                            {
+                                error!("failed creating chat: {}", e);
                             }
-                            _ => {}
                         }
-                    }
-                }
-                'p' => {
-                    // create new post
-                    if self.get_sel_chat().is_some() {
-                        self.modal = Widget::Input;
-                        self.input = Some(InputMode::new_post());
-                    }
-                }
-                'i' => {
-                    if alt {
-                        match self.focused {
-                            Widget::Users => {
-                                // invite selected user into selected chat
-                                if let Some(user) = self.get_sel_user() {
-                                    if let Some(sel) = self.get_sel_chat() {
-                                        if let Err(e) = self.tx_command.blocking_send(
-                                            Command::Invite(proto::Invitation {
-                                                chat_id: sel.chat.id,
-                                                from_user_id: self.user.id,
-                                                to_user_id: user.id,
-                                            }),
-                                        ) {
-                                            error!(
-                                                "failed inviting {} to {}: {}",
-                                                user.short_name, sel.chat.description, e
-                                            );
-                                        }
-                                    }
+                        InputResult::NewPost => {
+                            if let Some(sel) = self.get_sel_chat() {
+                                let chat_id = sel.chat.id;
+                                if let Err(e) =
+                                    self.tx_command.blocking_send(Command::Post(proto::Post {
+                                        id: NOT_POST_ID,
+                                        user_id: self.user.id,
+                                        chat_id,
+                                        text: input.text.clone(),
+                                        reply_to_post_id: input.reply_to,
+                                        ..Default::default()
+                                    }))
+                                {
+                                    error!("failed creating post: {}", e);
+                                } else {
+                                    self.pending_posts.push(PendingPost {
+                                        chat_id,
+                                        text: input.text.clone(),
+                                        post_id: None,
+                                        failed: None,
+                                    });
+                                }
+                            }
+                        }
+                        InputResult::RenameChat(chat_id) => {
+                            let update = input
+                                .text
+                                .parse::<ChatSpec>()
+                                .and_then(|spec| spec.update(self.user.id, chat_id));
+                            let update = match update {
+                                Ok(update) => update,
+                                Err(e) => {
+                                    // remaining modal state of input to fix the spec
+                                    self.notice = Some(format!("rename chat: {}", e));
+                                    return;
+                                }
+                            };
+                            self.notice = None;
+                            if let Err(e) =
+                                self.tx_command.blocking_send(Command::UpdateChat(update))
+                            {
+                                error!("failed renaming chat: {}", e);
+                            }
+                        }
+                        InputResult::React(chat_id, post_id) => {
+                            let text = input.text.trim();
+                            let emoji = REACTION_SHORTCODES
+                                .iter()
+                                .find(|(code, _)| *code == text)
+                                .map_or(text, |(_, emoji)| *emoji);
+                            // nothing typed is nothing to react with
+                            if !emoji.is_empty() {
+                                let reaction = proto::Reaction {
+                                    user_id: self.user.id,
+                                    chat_id,
+                                    post_id,
+                                    emoji: String::from(emoji),
+                                };
+                                if let Err(e) =
+                                    self.tx_command.blocking_send(Command::React(reaction))
+                                {
+                                    error!("failed reacting: {}", e);
+                                }
+                            }
+                        }
+                        InputResult::SendFile(chat_id) => {
+                            // nothing typed is nothing to send
+                            let path = input.text.trim();
+                            if !path.is_empty() {
+                                if let Err(e) = self
+                                    .tx_command
+                                    .blocking_send(Command::SendFile(chat_id, PathBuf::from(path)))
+                                {
+                                    error!("failed sending file: {}", e);
+                                }
+                            }
+                        }
+                        InputResult::ExportChat(chat_id) => {
+                            // nothing typed is nowhere to write
+                            let path = input.text.trim();
+                            if !path.is_empty() {
+                                let path = PathBuf::from(path);
+                                let mut authors: HashMap<UserId, String> = self
+                                    .users
+                                    .iter()
+                                    .map(|user| (user.id, user.short_name.clone()))
+                                    .collect();
+                                authors.insert(self.user.id, self.user.short_name.clone());
+                                info!("export: chat {} to {}", chat_id, path.display());
+                                let watermark = Watermark {
+                                    exported_by: format!(
+                                        "{} ({})",
+                                        self.user.short_name, self.user.id
+                                    ),
+                                    chat_id,
+                                    server: self.server_address.clone(),
+                                    exported: Local::now().timestamp() as u64,
+                                };
+                                let export = ChatExport {
+                                    chat_id,
+                                    format: ExportFormat::of_path(&path),
+                                    path,
+                                    watermark,
+                                    authors,
+                                };
+                                if let Err(e) =
+                                    self.tx_command.blocking_send(Command::ExportChat(export))
+                                {
+                                    error!("failed exporting chat: {}", e);
+                                }
+                            }
+                        }
+                        InputResult::Profile => {
+                            let info = match input.text.parse::<proto::UserInfo>() {
+                                Ok(info)
+                                    if !info.name.is_empty() && !info.short_name.is_empty() =>
+                                {
+                                    info
+                                }
+                                _ => {
+                                    // remaining modal state of input to fix the names
+                                    self.notice =
+                                        Some(String::from("profile: expected Login, Full Name"));
+                                    return;
                                 }
+                            };
+                            self.notice = None;
+                            // the own names change once the server confirms them
+                            if let Err(e) = self.tx_command.blocking_send(Command::UpdateUser(info))
+                            {
+                                error!("failed updating profile: {}", e);
                             }
-                            Widget::Chats => {
-                                // also create new post
-                                if self.get_sel_chat().is_some() {
-                                    self.modal = Widget::Input;
-                                    self.input = Some(InputMode::new_post());
+                        }
+                        // started above, the search needs the app mutable
+                        InputResult::Search(_) => {}
+                        InputResult::SearchAll => {
+                            // nothing typed is nothing to look for
+                            let query = input.text.trim();
+                            if !query.is_empty() {
+                                if let Err(e) = self
+                                    .tx_command
+                                    .blocking_send(Command::Search(String::from(query)))
+                                {
+                                    error!("failed searching posts: {}", e);
+                                }
+                            }
+                        }
+                        InputResult::UserInfo => {
+                            if let Ok(info) = input.text.parse::<proto::UserInfo>() {
+                                self.user_description = format!("{}", &info);
+                                self.user.name = info.name.clone();
+                                self.user.short_name = info.short_name.clone();
+                                if let Err(e) =
+                                    self.tx_command.blocking_send(Command::Register(info))
+                                {
+                                    error!("failed to send command to register: {}", e);
                                 }
+                            } else {
+                                // remaining modal state of input
+                                return;
                             }
-                            _ => {}
                         }
                     }
                 }
-                _ => {}
+                self.input = None;
+                // restore previous modal widget:
+                self.close_modal();
             }
-        }
+            Widget::Confirm => {
+                if let Some(chat_id) = self.leaving.take() {
+                    if let Err(e) = self.tx_command.blocking_send(Command::LeaveChat(chat_id)) {
+                        error!("failed leaving chat: {}", e);
+                    }
+                } else if let Some(invitation) = self.invitations.pop_front() {
+                    if let Err(e) = self
+                        .tx_command
+                        .blocking_send(Command::EnterChat(invitation.chat_id))
+                    {
+                        error!("failed entering chat: {}", e);
+                    }
+                }
+                self.close_modal();
+            }
+            Widget::Picker => {
+                if let Some(forwarding) = self.forwarding.take() {
+                    let target = forwarding
+                        .state
+                        .selected()
+                        .and_then(|idx| forwarding.targets.get(idx).copied())
+                        .filter(|chat_id| self.chats.contains_key(chat_id));
+                    if let Some(target_chat) = target {
+                        if let Err(e) = self.tx_command.blocking_send(Command::ForwardPost {
+                            post: forwarding.post,
+                            target_chat,
+                        }) {
+                            error!("failed forwarding post: {}", e);
+                        }
+                    }
+                }
+                self.close_modal();
+            }
+            Widget::Results => {
+                if let Some(found) = self.found.take() {
+                    let post = found
+                        .state
+                        .selected()
+                        .and_then(|idx| found.posts.into_iter().nth(idx));
+                    if let Some(post) = post {
+                        self.show_found_post(post, found.query);
+                    }
+                }
+                self.close_modal();
+            }
+            Widget::App if self.focused == Widget::Users => self.open_dialog(),
+            _ => {}
+        };
     }
 
-    pub fn on_backspace(&mut self) {
-        if let Some(input) = self.input.as_mut() {
-            if !input.text.is_empty() {
-                input.text.pop();
-            }
+    // selects the dialog with the selected user, the missing one is created or entered first
+    fn open_dialog(&mut self) {
+        let user_id = match self.get_sel_user() {
+            Some(user) if user.id != self.user.id => user.id,
+            _ => return,
+        };
+        if self.select_dialog(user_id) {
+            return;
+        }
+        // the server finds the dialog by its members, the existing one is entered
+        let chat_info = proto::ChatInfo {
+            user_id: self.user.id,
+            permanent: true,
+            auto_enter: true,
+            description: String::new(),
+            desired_users: vec![user_id],
+            export_discouraged: false,
+        };
+        if let Err(e) = self
+            .tx_command
+            .blocking_send(Command::CreateChat(chat_info))
+        {
+            error!("failed creating dialog: {}", e);
+        } else {
+            self.dialog_with = Some(user_id);
         }
     }
 
-    pub fn on_tick(&mut self) {}
-
-    pub fn get_sel_chat(&self) -> Option<&ChatInfo> {
-        self.chats_state
-            .selected()
-            .and_then(|idx| self.chats.values().nth(idx))
+    // returns true if the dialog with the user is known and now selected
+    fn select_dialog(&mut self, user_id: UserId) -> bool {
+        let own_id = self.user.id;
+        let found = self.get_all_chats().find(|c| {
+            let users = &c.chat.users;
+            c.chat.description.is_empty()
+                && users.len() == 2
+                && users.contains(&own_id)
+                && users.contains(&user_id)
+        });
+        let chat_id = match found {
+            Some(c) => c.chat.id,
+            None => return false,
+        };
+        let selected = self.select_chat(chat_id);
+        if selected {
+            self.dialog_with = None;
+        }
+        selected
     }
 
-    pub fn get_sel_posts(&self) -> Vec<proto::Post> {
-        if let Some(sel) = self.get_sel_chat() {
-            let mut ret = Vec::with_capacity(sel.posts.len());
-            for p in &sel.posts {
-                ret.push(p.clone())
+    // the chat hidden by the filter is shown
+    fn select_chat(&mut self, chat_id: ChatId) -> bool {
+        if !self.get_chats().any(|c| c.chat.id == chat_id) {
+            self.chats_filter.clear();
+        }
+        match pinned_index(self.get_chats().map(|c| c.chat.id), Some(chat_id)) {
+            Some(idx) => {
+                self.chats_state.select(Some(idx));
+                self.focused = Widget::Posts;
+                self.on_chat_switched();
+                true
             }
-            ret
-        } else {
-            Vec::new()
+            None => false,
         }
     }
 
-    pub fn get_chat(&self, chat_id: ChatId) -> Option<&ChatInfo> {
-        self.chats.get(&chat_id)
+    // the post is looked for in its chat, the elder pages are fetched if it is not loaded
+    fn show_found_post(&mut self, post: proto::Post, query: String) {
+        if !self.select_chat(post.chat_id) {
+            self.notice = Some(String::from("search: no longer available"));
+            return;
+        }
+        self.start_search(post.chat_id, query);
+        let idx = self.get_sel_posts().iter().position(|p| p.id == post.id);
+        if let (Some(idx), Some(sel)) = (idx, self.get_sel_chat_mut()) {
+            sel.posts_state.select(Some(idx));
+        }
     }
 
-    pub fn get_posts_count(&self, chat_id: proto::ChatId) -> usize {
-        self.get_chat(chat_id)
-            .map(|c| c.get_posts_count())
-            .unwrap_or_default()
+    pub fn on_esc(&mut self) {
+        match self.modal {
+            Widget::Input => {
+                if self.input.as_ref().map_or(false, |i| i.oversize.is_some()) {
+                    self.apply_oversize(OversizeAction::Cancel);
+                } else if let Some(mode) = &self.input {
+                    if mode.purpose != InputResult::UserInfo {
+                        self.close_modal();
+                    }
+                }
+            }
+            Widget::Confirm => {
+                // cancelled leaving keeps the chat
+                if self.leaving.take().is_none() {
+                    if let Some(invitation) = self.invitations.pop_front() {
+                        if let Err(e) = self
+                            .tx_command
+                            .blocking_send(Command::DeclineInvitation(invitation))
+                        {
+                            error!("failed declining invitation: {}", e);
+                        }
+                    }
+                }
+                self.close_modal();
+            }
+            Widget::Picker => {
+                self.forwarding = None;
+                self.close_modal();
+            }
+            Widget::Results => {
+                self.found = None;
+                self.close_modal();
+            }
+            Widget::Help => self.close_modal(),
+            Widget::Filter => {
+                self.set_list_filter(self.focused, String::new());
+                self.close_modal();
+            }
+            Widget::App => match self.focused {
+                // the applied filter is cleared first
+                Widget::Users | Widget::Chats if !self.get_list_filter(self.focused).is_empty() => {
+                    self.set_list_filter(self.focused, String::new());
+                }
+                Widget::Users => {
+                    self.users_state.select(None);
+                }
+                Widget::Chats => {
+                    self.chats_state.select(None);
+                }
+                // the search is cancelled first
+                Widget::Posts if self.search.is_some() => self.search = None,
+                Widget::Posts => {
+                    if let Some(sel) = self.get_sel_chat_mut() {
+                        sel.posts_state.select(None);
+                    }
+                }
+                _ => {}
+            },
+            _ => {
+                error!("widget {:?} must not be modal", self.modal);
+                self.close_modal();
+            }
+        }
     }
 
-    pub fn get_sel_user(&self) -> Option<&proto::User> {
-        self.users_state
-            .selected()
-            .and_then(|idx| self.users.get(idx))
+    pub fn on_mouse(&mut self, event: MouseEvent) {
+        match event.kind {
+            MouseEventKind::Down(MouseButton::Left) => self.on_click(event.column, event.row),
+            MouseEventKind::ScrollUp => self.on_wheel(event.column, event.row, false),
+            MouseEventKind::ScrollDown => self.on_wheel(event.column, event.row, true),
+            _ => {}
+        }
     }
 
-    pub fn get_user(&self, user_id: UserId) -> Option<&proto::User> {
-        if self.user.id == user_id {
-            Some(&self.user)
-        } else {
-            self.users.iter().find(|u| u.id == user_id)
+    // focuses the pane under the cursor and selects the item clicked
+    fn on_click(&mut self, column: u16, row: u16) {
+        match self.modal {
+            Widget::Input => {
+                if let (Some(rect), Some(input)) = (self.areas.input, self.input.as_mut()) {
+                    if areas::contains(rect, column, row) {
+                        input.set_cursor_column((column - rect.x) as usize);
+                    }
+                }
+            }
+            Widget::App => {
+                if areas::contains(self.areas.users.rect, column, row) {
+                    self.focused = Widget::Users;
+                    if let Some(idx) = self.areas.users.item_at(column, row) {
+                        self.users_state.select(Some(idx));
+                    }
+                } else if areas::contains(self.areas.chats.rect, column, row) {
+                    self.focused = Widget::Chats;
+                    if let Some(idx) = self.areas.chats.item_at(column, row) {
+                        if self.chats_state.selected() != Some(idx) {
+                            self.chats_state.select(Some(idx));
+                            self.on_chat_switched();
+                        }
+                    }
+                } else if areas::contains(self.areas.posts.rect, column, row) {
+                    self.focused = Widget::Posts;
+                    let idx = self.areas.posts.item_at(column, row);
+                    let reveal_filtered = self.reveal_filtered;
+                    if let Some(sel) = self.get_sel_chat_mut() {
+                        // the pending posts are not selected
+                        if let Some(idx) = idx.filter(|i| *i < sel.get_shown_count(reveal_filtered))
+                        {
+                            sel.posts_state.select(Some(idx));
+                        }
+                    }
+                    self.mark_sel_read();
+                }
+            }
+            _ => {}
         }
     }
 
-    pub fn get_user_description(user: &proto::User) -> String {
-        format!("{}", proto::UserInfo::from(user.clone()))
+    // the log is scrolled under the cursor, the focused list elsewhere
+    fn on_wheel(&mut self, column: u16, row: u16, down: bool) {
+        if areas::contains(self.areas.log, column, row) {
+            let event = if down {
+                TuiWidgetEvent::NextPageKey
+            } else {
+                TuiWidgetEvent::PrevPageKey
+            };
+            self.logger_state.transition(&event);
+            return;
+        }
+        match self.modal {
+            Widget::App
+            | Widget::Log
+            | Widget::Picker
+            | Widget::Help
+            | Widget::Filter
+            | Widget::Results => {
+                if down {
+                    self.on_down();
+                } else {
+                    self.on_up();
+                }
+            }
+            _ => {}
+        }
     }
 
-    fn list_next(state: &mut ListState, count: usize) {
-        if count == 0 {
-            state.select(None);
-        } else {
-            state.select(state.selected().map(|cur| count.min(cur + 1)).or(Some(0)));
+    // shown over the panes only, the help is closed by the same key
+    pub fn on_help(&mut self) {
+        match self.modal {
+            Widget::App => {
+                self.help_scroll = 0;
+                self.modal = Widget::Help;
+            }
+            Widget::Help => self.close_modal(),
+            _ => {}
         }
     }
 
-    fn list_previous(state: &mut ListState, count: usize) {
-        if count == 0 {
-            state.select(None);
+    pub fn on_key(&mut self, c: char, ctrl: bool, alt: bool) {
+        self.on_chord(Chord::new(c, ctrl, alt));
+    }
+
+    // the function keys other than the named ones are bound by the config only
+    pub fn on_function_key(&mut self, n: u8, ctrl: bool, alt: bool) {
+        self.on_chord(Chord::function(n, ctrl, alt));
+    }
+
+    fn on_chord(&mut self, chord: Chord) {
+        if self.modal == Widget::Help {
+            // only the help itself and exit work over the help
+            if let Lookup::Action(action) = self.keys.lookup(Widget::App, &[chord]) {
+                if action == Action::Help || action == Action::Exit {
+                    self.apply_action(action);
+                }
+            }
+            return;
+        }
+        if self.modal == Widget::Filter {
+            // the modified chords still work, e.g. exit
+            match chord.key {
+                Key::Char(c) if chord.is_plain() => {
+                    let mut filter = String::from(self.get_list_filter(self.focused));
+                    filter.push(c);
+                    self.set_list_filter(self.focused, filter);
+                }
+                _ => {
+                    if let Lookup::Action(action) = self.keys.lookup(Widget::App, &[chord]) {
+                        self.apply_action(action);
+                    }
+                }
+            }
+            return;
+        }
+        if self.modal == Widget::Confirm
+            || self.modal == Widget::Picker
+            || self.modal == Widget::Results
+        {
+            // the invitation is to be answered and the chat or the post to be picked first
+            if !chord.is_plain() {
+                if let Lookup::Action(action) = self.keys.lookup(Widget::App, &[chord]) {
+                    self.apply_action(action);
+                }
+            }
+            return;
+        }
+        if self.modal == Widget::Input {
+            // only modified chords work while typing, the line editing first, then e.g. exit
+            if !chord.is_plain() {
+                if let Lookup::Action(action) = self.keys.lookup(Widget::Input, &[chord]) {
+                    self.apply_action(action);
+                    return;
+                }
+            }
+            let c = match chord.key {
+                Key::Char(c) => c,
+                Key::F(_) => return,
+            };
+            if let Some(input) = self.input.as_mut() {
+                // the choice is to be made first
+                if input.oversize.is_none() {
+                    let inserted = input.insert(c.encode_utf8(&mut [0; 4]));
+                    input.recall.reset();
+                    let is_post = input.purpose == InputResult::NewPost;
+                    self.check_oversize(inserted);
+                    if is_post {
+                        self.send_typing();
+                    }
+                }
+            } else {
+                error!("input mode is not init properly");
+            }
+            return;
+        }
+        let context = if self.modal == Widget::Log {
+            Widget::Log
         } else {
-            state.select(
-                state
-                    .selected()
-                    .map(|cur| if cur > 0 { count.min(cur - 1) } else { 0 })
-                    .or(Some(0)),
-            );
+            self.focused
+        };
+        self.pending_keys.push(chord);
+        match self.keys.lookup(context, &self.pending_keys) {
+            Lookup::Action(action) => {
+                self.pending_keys.clear();
+                self.apply_action(action);
+            }
+            Lookup::Pending => {}
+            Lookup::None => {
+                // unknown sequence, the last key may start a new one
+                let restart = self.pending_keys.len() > 1;
+                self.pending_keys.clear();
+                if restart {
+                    self.on_chord(chord);
+                }
+            }
         }
     }
 
-    // chat events handling
+    fn send_typing(&mut self) {
+        let chat_id = match self.get_sel_chat() {
+            Some(sel) => sel.chat.id,
+            None => return,
+        };
+        if self.typing.is_due(chat_id, Instant::now()) {
+            if let Err(e) = self.tx_command.blocking_send(Command::Typing(chat_id)) {
+                error!("failed sending typing: {}", e);
+            }
+        }
+    }
 
-    pub fn on_registered(&mut self, user_id: UserId) {
-        self.user.id = user_id;
+    // the selected chat scrolled down to the last post is read
+    fn mark_sel_read(&mut self) {
+        let reveal_filtered = self.reveal_filtered;
+        let (chat_id, post_id) = match self.get_sel_chat_mut() {
+            Some(sel) if sel.is_member() => {
+                let last = sel.get_shown_count(reveal_filtered).saturating_sub(1);
+                let at_bottom = sel.posts_state.selected().map_or(true, |i| i >= last);
+                match sel.posts.back() {
+                    Some(post) if at_bottom && post.id != sel.last_read => {
+                        sel.last_read = post.id;
+                        (sel.chat.id, post.id)
+                    }
+                    _ => return,
+                }
+            }
+            _ => return,
+        };
+        if let Err(e) = self
+            .tx_command
+            .blocking_send(Command::MarkRead(chat_id, post_id))
+        {
+            error!("failed sending mark read: {}", e);
+        }
     }
 
-    pub fn on_user_info(&mut self, user: proto::User) {
-        if !self.users.iter().any(|u| u.id == user.id) {
-            self.users.push(user);
+    pub fn apply_action(&mut self, action: Action) {
+        match action {
+            Action::Exit => {
+                self.unsend = None;
+                self.mark_sel_read();
+                if let Err(e) = self.tx_command.blocking_send(Command::Exit) {
+                    error!("failed sending Exit command: {}", e);
+                }
+            }
+            Action::LogToggleHidden => {
+                self.logger_state.transition(&TuiWidgetEvent::SpaceKey);
+            }
+            Action::LogLessVerbose => {
+                self.logger_state.transition(&TuiWidgetEvent::MinusKey);
+            }
+            Action::LogMoreVerbose => {
+                self.logger_state.transition(&TuiWidgetEvent::PlusKey);
+            }
+            Action::React => {
+                if let Some(post) = self.get_sel_post() {
+                    self.modal = Widget::Input;
+                    self.input = Some(InputMode::react(post.chat_id, post.id));
+                }
+            }
+            Action::Forward => {
+                if let Some(post) = self.get_sel_post() {
+                    let targets: Vec<ChatId> = self
+                        .get_all_chats()
+                        .filter(|c| c.is_member() && c.chat.id != post.chat_id)
+                        .map(|c| c.chat.id)
+                        .collect();
+                    if targets.is_empty() {
+                        self.notice = Some(String::from("forward: no other chat to forward to"));
+                    } else {
+                        let mut state = ListState::default();
+                        state.select(Some(0));
+                        self.forwarding = Some(Forwarding {
+                            post,
+                            targets,
+                            state,
+                        });
+                        self.modal = Widget::Picker;
+                    }
+                }
+            }
+            Action::Reply => {
+                if let Some(post) = self.get_sel_post() {
+                    if self.unsend.as_ref().map(|u| u.chat_id) != Some(post.chat_id) {
+                        self.unsend = None;
+                    }
+                    let author = self.get_post_author(&post);
+                    self.modal = Widget::Input;
+                    self.input = Some(InputMode::reply(post.id, &author));
+                }
+            }
+            Action::SendFile => {
+                if self.modal == Widget::App && self.attachments {
+                    if let Some(sel) = self.get_sel_chat().filter(|c| c.is_member()) {
+                        self.input = Some(InputMode::send_file(sel.chat.id));
+                        self.modal = Widget::Input;
+                    }
+                }
+            }
+            Action::ExportChat => {
+                if self.modal == Widget::App {
+                    let sel = self
+                        .get_sel_chat()
+                        .map(|sel| (sel.chat.id, sel.chat.export_discouraged));
+                    if let Some((chat_id, discouraged)) = sel {
+                        // advisory, the posts shown are not hidden
+                        if discouraged {
+                            self.notice = Some(String::from(
+                                "export: the owner of the chat does not allow it",
+                            ));
+                        } else {
+                            self.input = Some(InputMode::export_chat(chat_id));
+                            self.modal = Widget::Input;
+                        }
+                    }
+                }
+            }
+            Action::Search => {
+                if self.modal == Widget::App {
+                    if let Some(chat_id) = self.get_sel_chat().map(|sel| sel.chat.id) {
+                        self.input = Some(InputMode::search(chat_id));
+                        self.modal = Widget::Input;
+                    }
+                }
+            }
+            Action::SearchAll => {
+                if self.modal == Widget::App {
+                    self.input = Some(InputMode::search_all());
+                    self.modal = Widget::Input;
+                }
+            }
+            Action::NextMatch | Action::PreviousMatch => {
+                let selected = self.get_sel_chat().and_then(|c| c.posts_state.selected());
+                self.select_match(selected, action == Action::NextMatch);
+            }
+            Action::EditProfile => {
+                if self.modal == Widget::App && self.user.id != NOT_USER_ID {
+                    self.input = Some(InputMode::edit_profile(&self.user));
+                    self.modal = Widget::Input;
+                }
+            }
+            Action::Help => self.on_help(),
+            Action::FilterList => {
+                if self.modal == Widget::App
+                    && (self.focused == Widget::Users || self.focused == Widget::Chats)
+                {
+                    self.modal = Widget::Filter;
+                }
+            }
+            Action::MuteChat => {
+                if let Some(chat_id) = self.get_sel_chat().map(|sel| sel.chat.id) {
+                    let muted = !self.muted.contains(&chat_id);
+                    self.on_muted(chat_id, muted);
+                    self.notice = Some(String::from(if muted {
+                        "chat muted"
+                    } else {
+                        "chat unmuted"
+                    }));
+                    // kept by the server for the other devices and the next runs
+                    if let Err(e) = self
+                        .tx_command
+                        .blocking_send(Command::MuteChat(chat_id, muted))
+                    {
+                        error!("failed sending mute: {}", e);
+                    }
+                }
+            }
+            Action::DeleteWord => {
+                if self.modal == Widget::Input {
+                    self.edit_input(InputMode::delete_word);
+                }
+            }
+            Action::ClearLine => {
+                if self.modal == Widget::Input {
+                    self.edit_input(InputMode::clear_line);
+                }
+            }
+            Action::Paste => {
+                if self.modal == Widget::Input {
+                    match clipboard::get_text() {
+                        Ok(text) => self.on_paste(&text),
+                        Err(e) => warn!("clipboard is not available: {}", e),
+                    }
+                }
+            }
+            Action::CopyPost => {
+                if let Some(post) = self.get_sel_post() {
+                    match clipboard::set_text(&post.text) {
+                        Ok(()) => self.notice = Some(String::from("post copied")),
+                        Err(e) => warn!("clipboard is not available: {}", e),
+                    }
+                }
+            }
+            Action::NewChat => {
+                self.modal = Widget::Input;
+                // setup input mode:
+                self.input = Some(InputMode::new_chat());
+            }
+            Action::NewPost => {
+                if let Some(sel) = self.get_sel_chat().filter(|c| c.is_member()) {
+                    // composing in another chat gives up the unsend
+                    let chat_id = sel.chat.id;
+                    if self.unsend.as_ref().map(|u| u.chat_id) != Some(chat_id) {
+                        self.unsend = None;
+                    }
+                    self.modal = Widget::Input;
+                    self.input = Some(InputMode::new_post());
+                }
+            }
+            Action::Unsend => {
+                if let Some(unsend) = self.unsend.take() {
+                    if let Err(e) =
+                        self.tx_command
+                            .blocking_send(Command::DeletePost(proto::PostReference {
+                                user_id: self.user.id,
+                                chat_id: unsend.chat_id,
+                                post_id: unsend.post_id,
+                            }))
+                    {
+                        error!("failed sending unsend: {}", e);
+                    }
+                }
+            }
+            Action::Resend => {
+                if let Some(chat_id) = self.get_sel_chat().map(|sel| sel.chat.id) {
+                    // sent again after the others, with the same idempotency keys
+                    let (failed, others): (Vec<PendingPost>, Vec<PendingPost>) =
+                        std::mem::take(&mut self.pending_posts)
+                            .into_iter()
+                            .partition(|p| p.chat_id == chat_id && p.is_failed());
+                    self.pending_posts = others;
+                    for mut pending in failed {
+                        if let Some(post) = pending.failed.take() {
+                            match self.tx_command.blocking_send(Command::Post(post)) {
+                                Ok(()) => self.pending_posts.push(pending),
+                                Err(e) => error!("failed resending post: {}", e),
+                            }
+                        }
+                    }
+                }
+            }
+            Action::ReconnectNow => {
+                if self.connection != Connection::Connected {
+                    if let Err(e) = self.tx_command.blocking_send(Command::ReconnectNow) {
+                        error!("failed sending reconnect: {}", e);
+                    }
+                }
+            }
+            Action::LeaveChat => {
+                if self.modal == Widget::App {
+                    if let Some(sel) = self.get_sel_chat() {
+                        if sel.is_member() {
+                            self.leaving = Some(sel.chat.id);
+                            self.modal = Widget::Confirm;
+                        }
+                    }
+                }
+            }
+            Action::RenameChat => {
+                if self.modal == Widget::App {
+                    if let Some(sel) = self.get_sel_chat() {
+                        if sel.is_member() {
+                            self.input = Some(InputMode::rename_chat(&sel.chat));
+                            self.modal = Widget::Input;
+                        }
+                    }
+                }
+            }
+            Action::NextUnread => {
+                // the post being composed goes to the selected chat
+                if self.modal == Widget::App {
+                    self.select_next_unread();
+                }
+            }
+            Action::KickUser => {
+                // only the owner removes the other members
+                if let Some(user) = self.get_sel_user() {
+                    if let Some(sel) = self.get_sel_chat() {
+                        if sel.is_member()
+                            && sel.chat.owner_id == self.user.id
+                            && user.id != self.user.id
+                            && sel.chat.users.contains(&user.id)
+                        {
+                            if let Err(e) = self.tx_command.blocking_send(Command::KickUser(
+                                proto::MemberReference {
+                                    chat_id: sel.chat.id,
+                                    user_id: self.user.id,
+                                    member_id: user.id,
+                                },
+                            )) {
+                                error!(
+                                    "failed kicking {} from {}: {}",
+                                    user.short_name, sel.chat.description, e
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+            Action::Invite => {
+                // invite selected user into selected chat, only its members do
+                if let Some(user) = self.get_sel_user() {
+                    if let Some(sel) = self.get_sel_chat().filter(|c| c.is_member()) {
+                        if let Err(e) =
+                            self.tx_command
+                                .blocking_send(Command::Invite(proto::Invitation {
+                                    chat_id: sel.chat.id,
+                                    from_user_id: self.user.id,
+                                    to_user_id: user.id,
+                                    declined: false,
+                                }))
+                        {
+                            error!(
+                                "failed inviting {} to {}: {}",
+                                user.short_name, sel.chat.description, e
+                            );
+                        }
+                    }
+                }
+            }
         }
     }
 
-    pub fn on_user_entered(&mut self, id: UserId) {
-        self.online.push(id);
+    pub fn on_backspace(&mut self) {
+        if self.modal == Widget::Filter {
+            let mut filter = String::from(self.get_list_filter(self.focused));
+            filter.pop();
+            self.set_list_filter(self.focused, filter);
+            return;
+        }
+        self.edit_input(InputMode::backspace);
     }
 
-    pub fn on_user_gone(&mut self, id: UserId) {
-        self.online.retain(|item| *item != id);
+    pub fn on_delete(&mut self) {
+        self.edit_input(InputMode::delete);
     }
 
-    pub fn on_history(&mut self, chat_id: ChatId, _idx_from: usize, posts: Vec<proto::Post>) {
-        if let Some(chat) = self.chats.get_mut(&chat_id) {
-            chat.insert_history(posts);
-        } else {
-            warn!("get history of unknown chat");
+    // the choice on the oversize is to be made before editing
+    fn edit_input(&mut self, edit: impl FnOnce(&mut InputMode) -> bool) {
+        if let Some(input) = self.input.as_mut() {
+            if input.oversize.is_none() && edit(input) {
+                input.recall.reset();
+                input.rejected = false;
+            }
         }
     }
 
-    pub fn on_chat_updated(&mut self, chat: proto::Chat, history_len: usize) {
-        if let Some(old) = self.chats.get_mut(&chat.id) {
-            old.chat = chat;
+    // pasted text comes at once, its newlines do not submit the post
+    pub fn on_paste(&mut self, text: &str) {
+        if self.modal == Widget::Confirm
+            || self.modal == Widget::Picker
+            || self.modal == Widget::Help
+            || self.modal == Widget::Results
+        {
+            // pasted newlines must not answer the invitation nor pick the chat or the post
+            return;
+        }
+        if self.modal != Widget::Input {
+            for c in text.chars() {
+                if c == '\n' {
+                    self.on_enter();
+                } else {
+                    self.on_key(c, false, false);
+                }
+            }
+            return;
+        }
+        if let Some(input) = self.input.as_mut() {
+            if input.oversize.is_some() {
+                return;
+            }
+            input.recall.reset();
+            input.rejected = false;
+            // the control chars would mess the terminal up
+            let text: String = text
+                .chars()
+                .filter(|c| !c.is_control() || *c == '\n')
+                .collect();
+            if input.purpose == InputResult::NewPost {
+                let inserted = input.insert(&text);
+                self.check_oversize(inserted);
+            } else {
+                // single line inputs
+                input.insert(&text.replace('\n', " "));
+            }
+        }
+    }
+
+    // Up/Down while typing go through the texts submitted to the same input
+    fn recall_input(&mut self, older: bool) {
+        let chat_id = self.get_sel_chat().map(|sel| sel.chat.id);
+        let input = match self.input.as_mut() {
+            Some(input) if input.oversize.is_none() => input,
+            _ => return,
+        };
+        let history = match input.purpose {
+            InputResult::NewChat => &self.chat_spec_history,
+            InputResult::NewPost => match chat_id.and_then(|id| self.post_history.get(&id)) {
+                Some(history) => history,
+                None => return,
+            },
+            InputResult::UserInfo
+            | InputResult::RenameChat(_)
+            | InputResult::React(..)
+            | InputResult::SendFile(_)
+            | InputResult::Profile
+            | InputResult::Search(_)
+            | InputResult::SearchAll
+            | InputResult::ExportChat(_) => return,
+        };
+        let recalled = if older {
+            input.recall.older(history, &input.text)
         } else {
-            self.chats.insert(
-                chat.id,
-                ChatInfo {
-                    chat,
-                    history_len,
-                    posts: LinkedList::new(),
-                },
-            );
+            input.recall.newer(history)
+        };
+        if let Some(text) = recalled {
+            input.set_text(text);
         }
     }
 
-    pub fn on_get_invited(&mut self, invitation: proto::Invitation) {
-        //todo: ask user about invitation
-        if let Some(info) = self.get_chat(invitation.chat_id) {
-            if info.chat.users.iter().any(|u| *u == self.user.id) {
-                warn!("got invitation while being in that chat");
+    // the commands typed in the composer are recalled as well
+    fn record_post_text(&mut self) {
+        let chat_id = match self.get_sel_chat() {
+            Some(sel) => sel.chat.id,
+            None => return,
+        };
+        if let Some(input) = self.input.as_ref() {
+            if input.purpose == InputResult::NewPost {
+                self.post_history
+                    .entry(chat_id)
+                    .or_insert_with(|| InputHistory::new(POST_HISTORY_CAPACITY))
+                    .record(&input.text);
             }
         }
-        // auto enter chat
-        if let Err(e) = self
-            .tx_command
-            .blocking_send(Command::EnterChat(invitation.chat_id))
-        {
-            error!("failed creating post: {}", e);
+    }
+
+    // offers the choice if the inserted text has made the post too large
+    fn check_oversize(&mut self, inserted: Range<usize>) {
+        let limits = self.composer_limits;
+        let attachments = self.attachments;
+        if let Some(input) = self.input.as_mut() {
+            if input.purpose == InputResult::NewPost
+                && input.oversize.is_none()
+                && limits.exceeded_by(&input.text)
+            {
+                let mut before = input.text.clone();
+                before.replace_range(inserted, "");
+                input.oversize = Some(OversizeChoice::new(attachments, before));
+            }
         }
     }
 
-    pub fn on_new_post(&mut self, post: proto::Post) {
-        if let Some(found) = self.chats.get_mut(&post.chat_id) {
-            found.push(post);
-        } else {
-            // chat is not found
-            error!("internal, post from unknown chat was received");
+    fn apply_oversize(&mut self, action: OversizeAction) {
+        let input = match self.input.as_mut() {
+            Some(input) => input,
+            None => return,
+        };
+        let choice = match input.oversize.take() {
+            Some(choice) => choice,
+            None => return,
+        };
+        match action {
+            OversizeAction::Trim => input.set_text(self.composer_limits.trim(&input.text)),
+            OversizeAction::Cancel => input.set_text(choice.before),
+            OversizeAction::Attach => {
+                let content = std::mem::take(&mut input.text);
+                self.input = None;
+                self.close_modal();
+                if let Some(sel) = self.get_sel_chat() {
+                    let file_name = composer::attachment_file_name(&Local::now());
+                    let text = composer::attachment_post_text(&file_name, &content);
+                    if let Err(e) =
+                        self.tx_command
+                            .blocking_send(Command::PostAttachment(Attachment {
+                                chat_id: sel.chat.id,
+                                file_name,
+                                content,
+                                text,
+                            }))
+                    {
+                        error!("failed creating post: {}", e);
+                    }
+                }
+            }
         }
     }
 
-    pub fn on_chat_deleted(&mut self, chat_id: ChatId) {
-        self.chats.remove(&chat_id);
+    pub fn on_tick(&mut self) {
+        let now = Instant::now();
+        self.expire_unsend(now);
+        if let Some(reconnect) = &mut self.reconnect {
+            reconnect.update(now);
+        }
     }
-}
 
-#[test]
-fn test_vec_iter() {
-    let mut v = vec![4; 4];
-    if !v.iter().any(|&n| n == 7) {
-        v.push(7);
+    fn expire_unsend(&mut self, now: Instant) {
+        if self.unsend.as_ref().map(|u| u.expires <= now) == Some(true) {
+            self.unsend = None;
+        }
     }
-    assert_eq!(v.len(), 5);
+
+    fn on_chat_switched(&mut self) {
+        let sel_chat_id = self.get_sel_chat().map(|c| c.chat.id);
+        if self.unsend.as_ref().map(|u| u.chat_id) != sel_chat_id {
+            self.unsend = None;
+        }
+        if self.search.as_ref().map(|s| s.chat_id) != sel_chat_id {
+            self.search = None;
+        }
+        if let Some(sel) = self.get_sel_chat_mut() {
+            sel.unread = 0;
+            sel.late_unread = false;
+        }
+        // the newest of the elder posts, the others are fetched when scrolled to
+        if self
+            .get_sel_chat()
+            .map_or(false, |sel| sel.posts.len() < HISTORY_PAGE)
+        {
+            self.request_history();
+        }
+        // the chat not scrolled yet starts at the newest post
+        let reveal_filtered = self.reveal_filtered;
+        if let Some(sel) = self.get_sel_chat_mut() {
+            if sel.posts_state.selected().is_none() {
+                let cnt = sel.get_shown_count(reveal_filtered);
+                sel.posts_state.select(cnt.checked_sub(1));
+            }
+        }
+        // the names of the members not seen on the users stream
+        if let Some(sel) = self.get_sel_chat() {
+            let chat_id = sel.chat.id;
+            let unknown = sel.chat.users.iter().any(|id| self.get_user(*id).is_none());
+            if unknown && self.members_requested.insert(chat_id) {
+                if let Err(e) = self
+                    .tx_command
+                    .blocking_send(Command::GetChatMembers(chat_id))
+                {
+                    error!("failed requesting chat members: {}", e);
+                }
+            }
+        }
+        self.mark_sel_read();
+    }
+
+    // cycles through the chats with unread posts starting after the selected one
+    fn select_next_unread(&mut self) {
+        let count = self.get_chats().count();
+        let start = self.chats_state.selected().map_or(0, |idx| idx + 1);
+        let found = (0..count)
+            .map(|n| (start + n) % count)
+            .find(|&idx| self.get_chats().nth(idx).map_or(false, |c| c.unread > 0));
+        if let Some(idx) = found {
+            self.chats_state.select(Some(idx));
+            self.on_chat_switched();
+        }
+    }
+
+    pub fn get_pending_unsend(&self) -> Option<&PendingUnsend> {
+        self.unsend.as_ref()
+    }
+
+    pub fn get_sel_chat(&self) -> Option<&ChatInfo> {
+        let idx = self.chats_state.selected()?;
+        self.get_chats().nth(idx)
+    }
+
+    pub fn get_sel_chat_mut(&mut self) -> Option<&mut ChatInfo> {
+        let chat_id = self.get_sel_chat()?.chat.id;
+        self.chats.get_mut(&chat_id)
+    }
+
+    // the chats in the order they are shown, the filtered out are skipped
+    pub fn get_chats(&self) -> impl Iterator<Item = &ChatInfo> {
+        self.get_all_chats().filter(move |c| self.chat_matches(c))
+    }
+
+    fn get_all_chats(&self) -> impl Iterator<Item = &ChatInfo> {
+        self.chats_order
+            .iter()
+            .filter_map(move |chat_id| self.chats.get(chat_id))
+    }
+
+    // by the description or by the names of the members
+    fn chat_matches(&self, chat: &ChatInfo) -> bool {
+        let filter = self.chats_filter.to_lowercase();
+        chat.chat.description.to_lowercase().contains(&filter)
+            || chat
+                .chat
+                .users
+                .iter()
+                .filter(|id| **id != self.user.id)
+                .filter_map(|id| self.get_user(*id))
+                .any(|u| user_matches(u, &filter))
+    }
+
+    // the users in the order they are shown, the filtered out are skipped
+    pub fn get_users(&self) -> impl Iterator<Item = &proto::User> {
+        let filter = &self.users_filter;
+        self.users.iter().filter(move |u| user_matches(u, filter))
+    }
+
+    pub fn get_list_filter(&self, widget: Widget) -> &str {
+        match widget {
+            Widget::Users => &self.users_filter,
+            Widget::Chats => &self.chats_filter,
+            _ => "",
+        }
+    }
+
+    // the selected entry stays selected while it matches, the first match is selected otherwise
+    fn set_list_filter(&mut self, widget: Widget, filter: String) {
+        match widget {
+            Widget::Users => {
+                let sel_user_id = self.get_sel_user().map(|u| u.id);
+                self.users_filter = filter;
+                let sel_idx = pinned_index(self.get_users().map(|u| u.id), sel_user_id);
+                let first = self.get_users().next().map(|_| 0);
+                self.users_state.select(sel_idx.or(first));
+            }
+            Widget::Chats => {
+                let sel_chat_id = self.get_sel_chat().map(|c| c.chat.id);
+                self.chats_filter = filter;
+                let sel_idx = pinned_index(self.get_chats().map(|c| c.chat.id), sel_chat_id);
+                let first = self.get_chats().next().map(|_| 0);
+                self.chats_state.select(sel_idx.or(first));
+                if self.get_sel_chat().map(|c| c.chat.id) != sel_chat_id {
+                    self.on_chat_switched();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // keeps the selected chat selected wherever it moves
+    fn sort_chats(&mut self) {
+        let sel_chat_id = self.get_sel_chat().map(|c| c.chat.id);
+        let chats = &self.chats;
+        let mut order: Vec<ChatId> = chats.keys().copied().collect();
+        order.sort_by_key(|chat_id| (Reverse(chats[chat_id].activity()), *chat_id));
+        self.chats_order = order;
+        let sel_idx = pinned_index(self.get_chats().map(|c| c.chat.id), sel_chat_id);
+        if sel_idx.is_some() {
+            self.chats_state.select(sel_idx);
+        }
+    }
+
+    // keeps the selected user selected wherever one moves
+    fn sort_users(&mut self) {
+        let sel_user_id = self.get_sel_user().map(|u| u.id);
+        let online = &self.online;
+        self.users.sort_by(|a, b| user_order(a, b, online));
+        let sel_idx = pinned_index(self.get_users().map(|u| u.id), sel_user_id);
+        if sel_idx.is_some() {
+            self.users_state.select(sel_idx);
+        }
+    }
+
+    pub fn is_online(&self, user_id: UserId) -> bool {
+        self.online.contains(&user_id)
+    }
+
+    pub fn get_sel_posts(&self) -> Vec<proto::Post> {
+        if let Some(sel) = self.get_sel_chat() {
+            let mut ret = Vec::with_capacity(sel.posts.len());
+            for p in &sel.posts {
+                if self.reveal_filtered || !sel.filtered.contains(&p.id) {
+                    ret.push(p.clone())
+                }
+            }
+            ret
+        } else {
+            Vec::new()
+        }
+    }
+
+    // the post selected in the member chat, the one to react or reply to
+    fn get_sel_post(&self) -> Option<proto::Post> {
+        let idx = self
+            .get_sel_chat()
+            .filter(|c| c.is_member())
+            .and_then(|c| c.posts_state.selected())?;
+        self.get_sel_posts().into_iter().nth(idx)
+    }
+
+    pub fn get_sel_pending_posts(&self) -> Vec<&PendingPost> {
+        match self.get_sel_chat() {
+            Some(sel) => self
+                .pending_posts
+                .iter()
+                .filter(|p| p.chat_id == sel.chat.id)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn get_chat(&self, chat_id: ChatId) -> Option<&ChatInfo> {
+        self.chats.get(&chat_id)
+    }
+
+    pub fn get_posts_count(&self, chat_id: proto::ChatId) -> usize {
+        self.get_chat(chat_id)
+            .map(|c| c.get_posts_count())
+            .unwrap_or_default()
+    }
+
+    pub fn get_sel_user(&self) -> Option<&proto::User> {
+        let idx = self.users_state.selected()?;
+        self.get_users().nth(idx)
+    }
+
+    pub fn get_user(&self, user_id: UserId) -> Option<&proto::User> {
+        if self.user.id == user_id {
+            Some(&self.user)
+        } else {
+            self.users.iter().find(|u| u.id == user_id)
+        }
+    }
+
+    pub fn get_user_description(user: &proto::User) -> String {
+        format!("{}", proto::UserInfo::from(user.clone()))
+    }
+
+    fn list_next(state: &mut ListState, count: usize) {
+        if count == 0 {
+            state.select(None);
+        } else {
+            state.select(state.selected().map(|cur| count.min(cur + 1)).or(Some(0)));
+        }
+    }
+
+    fn list_previous(state: &mut ListState, count: usize) {
+        if count == 0 {
+            state.select(None);
+        } else {
+            state.select(
+                state
+                    .selected()
+                    .map(|cur| if cur > 0 { count.min(cur - 1) } else { 0 })
+                    .or(Some(0)),
+            );
+        }
+    }
+
+    // chat events handling
+
+    pub fn on_registered(&mut self, user_id: UserId) {
+        self.user.id = user_id;
+    }
+
+    pub fn on_connected(&mut self) {
+        info!("connected to the chat room");
+        self.connection = Connection::Connected;
+        self.reconnect = None;
+    }
+
+    pub fn on_stale(&mut self, stale: bool) {
+        self.stale = stale;
+    }
+
+    pub fn on_reconnect_scheduled(&mut self, attempt: u32, next_in_secs: u64) {
+        self.reconnect = Some(Reconnect {
+            attempt,
+            at: Some(Instant::now() + Duration::from_secs(next_in_secs)),
+            remaining_secs: next_in_secs,
+        });
+    }
+
+    pub fn on_reconnect_attempt(&mut self) {
+        if let Some(reconnect) = &mut self.reconnect {
+            reconnect.at = None;
+            reconnect.remaining_secs = 0;
+        }
+    }
+
+    pub fn on_reconnect_failed(&mut self, error: String) {
+        warn!("failed to reconnect, {}", error);
+    }
+
+    // users' presence is resent after reconnect
+    pub fn on_disconnected(&mut self) {
+        warn!("disconnected from the chat room, reconnecting");
+        self.connection = Connection::Disconnected;
+        self.online.clear();
+        self.sort_users();
+        // the typing stream is opened again with the connection
+        for chat in self.chats.values_mut() {
+            chat.typing.clear();
+        }
+    }
+
+    pub fn on_user_info(&mut self, user: proto::User) {
+        // the names of a known user might have been changed
+        if user.id == self.user.id {
+            self.user_description = App::get_user_description(&user);
+            self.user.name = user.name.clone();
+            self.user.short_name = user.short_name.clone();
+        }
+        match self.users.iter_mut().find(|u| u.id == user.id) {
+            Some(known) => *known = user,
+            None => self.users.push(user),
+        }
+        self.sort_users();
+    }
+
+    pub fn on_chat_members(&mut self, chat_id: ChatId, users: Vec<proto::User>) {
+        debug!("chat {} has {} members", chat_id, users.len());
+        for user in users.into_iter().filter(|u| u.id != self.user.id) {
+            self.on_user_info(user);
+        }
+    }
+
+    pub fn on_user_entered(&mut self, id: UserId) {
+        if !self.online.contains(&id) {
+            self.online.push(id);
+            self.sort_users();
+        }
+    }
+
+    pub fn on_user_gone(&mut self, id: UserId) {
+        self.online.retain(|item| *item != id);
+        self.sort_users();
+    }
+
+    pub fn on_history(&mut self, chat_id: ChatId, _idx_from: usize, posts: Vec<proto::Post>) {
+        self.on_history_at(chat_id, posts, Local::now().timestamp() as u64);
+        if self.get_sel_chat().map(|c| c.chat.id) == Some(chat_id) {
+            self.mark_sel_read();
+        }
+    }
+
+    fn on_history_at(&mut self, chat_id: ChatId, posts: Vec<proto::Post>, now: u64) {
+        let filtered = self.filtered_ids(posts.iter());
+        let late_delivery = self.late_delivery;
+        let reveal_filtered = self.reveal_filtered;
+        if let Some(chat) = self.chats.get_mut(&chat_id) {
+            chat.history_requested = false;
+            let shown = chat.get_shown_count(reveal_filtered);
+            // the history is old, only the posts missed meanwhile are just delivered
+            if let Some(mark) = chat.high_water_mark() {
+                for post in posts.iter().filter(|p| p.created > mark) {
+                    if is_late(late_delivery, post.created, now) {
+                        chat.delivered.insert(post.id, now);
+                    }
+                }
+            }
+            chat.filtered.extend(filtered);
+            chat.insert_history(posts);
+            // the selected post keeps selected above the elder ones
+            let prepended = chat.get_shown_count(reveal_filtered).saturating_sub(shown);
+            if let Some(idx) = chat.posts_state.selected() {
+                chat.posts_state.select(Some(idx + prepended));
+            }
+        } else {
+            warn!("get history of unknown chat");
+        }
+        if self.search.as_ref().map(|s| s.chat_id) == Some(chat_id) {
+            // the search waiting for the elder posts goes on
+            let waiting = self.search.as_ref().map_or(false, |s| s.matches.is_empty());
+            self.update_search();
+            if waiting {
+                self.select_match(None, true);
+            }
+        }
+    }
+
+    pub fn on_chat_updated(&mut self, chat: proto::Chat, history_len: usize) {
+        self.update_chat(chat, history_len);
+        self.sort_chats();
+        // either the response to creating the dialog or the chats stream brings it first
+        if let Some(user_id) = self.dialog_with {
+            self.select_dialog(user_id);
+        }
+    }
+
+    fn update_chat(&mut self, chat: proto::Chat, history_len: usize) {
+        if let Some(old) = self.chats.get_mut(&chat.id) {
+            if old.chat != chat {
+                old.chat = chat;
+            } else {
+                debug!("chat {} is repeated, kept as is", chat.id);
+            }
+        } else {
+            let chat_id = chat.id;
+            let posts: LinkedList<proto::Post> = self
+                .orphan_posts
+                .remove(&chat_id)
+                .map(|mut posts| {
+                    posts.sort_by_key(|p| p.seq);
+                    posts.into_iter().collect()
+                })
+                .unwrap_or_default();
+            let filtered = self.filtered_ids(posts.iter());
+            let unread = posts
+                .iter()
+                .filter(|p| is_unread(p, self.user.id) && !filtered.contains(&p.id))
+                .count();
+            // the posts came just before the chat
+            let now = Local::now().timestamp() as u64;
+            let delivered: HashMap<PostId, u64> = posts
+                .iter()
+                .filter(|p| is_late(self.late_delivery, p.created, now))
+                .map(|p| (p.id, now))
+                .collect();
+            let late_unread = posts.iter().any(|p| {
+                delivered.contains_key(&p.id)
+                    && is_unread(p, self.user.id)
+                    && !filtered.contains(&p.id)
+            });
+            let last_post_at = posts.iter().map(|p| p.created).max().unwrap_or_default();
+            self.chats.insert(
+                chat_id,
+                ChatInfo {
+                    chat,
+                    history_len,
+                    posts,
+                    posts_state: ListState::default(),
+                    posts_offset: 0,
+                    unread,
+                    degraded: false,
+                    filtered,
+                    delivered,
+                    late_unread,
+                    membership: proto::Membership::None,
+                    typing: BTreeSet::new(),
+                    last_read: NOT_POST_ID,
+                    last_post_at,
+                    history_requested: false,
+                },
+            );
+        }
+    }
+
+    pub fn on_get_invited(&mut self, invitation: proto::Invitation) {
+        if invitation.declined {
+            let text = format!(
+                "{} declined your invitation to {}",
+                self.get_user_name(invitation.to_user_id),
+                self.get_chat_description(invitation.chat_id)
+            );
+            info!("{}", text);
+            self.notice = Some(text);
+            return;
+        }
+        if let Some(info) = self.get_chat(invitation.chat_id) {
+            if info.is_member() {
+                warn!("got invitation while being in that chat");
+                return;
+            }
+        }
+        if self
+            .invitations
+            .iter()
+            .any(|i| i.chat_id == invitation.chat_id)
+        {
+            info!("repeated invitation to chat {}", invitation.chat_id);
+            return;
+        }
+        self.invitations.push_back(invitation);
+        if self.modal == Widget::App {
+            self.modal = Widget::Confirm;
+        }
+    }
+
+    // the invitation being confirmed
+    pub fn get_invitation(&self) -> Option<&proto::Invitation> {
+        if self.modal == Widget::Confirm && self.leaving.is_none() {
+            self.invitations.front()
+        } else {
+            None
+        }
+    }
+
+    // the chat being confirmed to leave
+    pub fn get_pending_leave(&self) -> Option<ChatId> {
+        if self.modal == Widget::Confirm {
+            self.leaving
+        } else {
+            None
+        }
+    }
+
+    // users are referred by their logins
+    pub fn find_user(&self, login: &str) -> Option<&proto::User> {
+        self.users.iter().find(|u| u.short_name == login)
+    }
+
+    // the live name is preferred, the one the post has been stamped with is shown if it differs
+    pub fn get_post_author(&self, post: &proto::Post) -> String {
+        match self.get_user(post.user_id) {
+            Some(user) if user.id == self.user.id => String::from("me"),
+            Some(user) if !post.author_name.is_empty() && post.author_name != user.short_name => {
+                format!("{} (formerly {})", user.short_name, post.author_name)
+            }
+            Some(user) => user.short_name.clone(),
+            None if !post.author_name.is_empty() => post.author_name.clone(),
+            None => format!("{}", post.user_id),
+        }
+    }
+
+    pub fn get_user_name(&self, user_id: UserId) -> String {
+        self.users
+            .iter()
+            .find(|u| u.id == user_id)
+            .map(App::get_user_description)
+            .unwrap_or_else(|| format!("user {}", user_id))
+    }
+
+    // marked by the chats with the posts not seen yet
+    pub fn get_title(&self) -> String {
+        let news = self
+            .chats
+            .values()
+            .filter(|c| c.unread > 0 && !self.muted.contains(&c.chat.id))
+            .count();
+        if self.notify == Notify::Off || news == 0 {
+            self.title.clone()
+        } else {
+            format!(
+                "{} ({})",
+                self.title,
+                self.plural(news, Counted::ChatsWithNews)
+            )
+        }
+    }
+
+    pub fn is_muted(&self, chat_id: ChatId) -> bool {
+        self.muted.contains(&chat_id)
+    }
+
+    // true once per the posts rung for
+    pub fn take_bell(&mut self) -> bool {
+        std::mem::take(&mut self.bell)
+    }
+
+    pub fn plural(&self, count: usize, counted: Counted) -> String {
+        plural::plural(self.language, count, counted)
+    }
+
+    // the dialog is named after the counterparts, all the members are the counterparts
+    // of the one who is not a member
+    pub fn get_chat_title(&self, chat: &ChatInfo) -> String {
+        if !chat.chat.description.is_empty() {
+            return chat.chat.description.clone();
+        }
+        chat.chat
+            .users
+            .iter()
+            .filter(|&&u| !chat.is_member() || u != self.user.id)
+            .filter_map(|&u| self.get_user(u))
+            .map(|user| user.short_name.clone())
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    pub fn get_chat_description(&self, chat_id: ChatId) -> String {
+        self.get_chat(chat_id)
+            .map(|c| c.chat.description.clone())
+            .unwrap_or_else(|| format!("chat {}", chat_id))
+    }
+
+    // queued invitations are presented one after another
+    fn close_modal(&mut self) {
+        self.modal = if self.leaving.is_none() && self.invitations.is_empty() {
+            Widget::App
+        } else {
+            Widget::Confirm
+        };
+    }
+
+    pub fn on_new_post(&mut self, post: proto::Post) {
+        self.on_new_post_at(post, Local::now().timestamp() as u64)
+    }
+
+    fn on_new_post_at(&mut self, post: proto::Post, now: u64) {
+        if !self.seen_posts.insert(post.id) {
+            // the post comes again when its reactions change
+            self.on_post_updated(post);
+            return;
+        }
+        // own post has been delivered
+        self.pending_posts.retain(|p| p.post_id != Some(post.id));
+        let sel_chat_id = self.get_sel_chat().map(|c| c.chat.id);
+        let filtered = self.is_filtered(&post);
+        let late = is_late(self.late_delivery, post.created, now);
+        let reveal_filtered = self.reveal_filtered;
+        if let Some(found) = self.chats.get_mut(&post.chat_id) {
+            // the chat scrolled to the newest post follows the new ones
+            let last = found.get_shown_count(reveal_filtered).checked_sub(1);
+            let at_bottom = last.is_some() && found.posts_state.selected() == last;
+            if late {
+                found.delivered.insert(post.id, now);
+            }
+            // the post ends typing it
+            found.typing.remove(&post.user_id);
+            if filtered {
+                found.filtered.insert(post.id);
+            } else if is_unread(&post, self.user.id) && sel_chat_id != Some(post.chat_id) {
+                found.unread += 1;
+                found.late_unread |= late;
+                self.bell |= self.notify == Notify::Bell && !self.muted.contains(&post.chat_id);
+            }
+            let resort = post.created > found.last_post_at;
+            if resort {
+                found.last_post_at = post.created;
+            }
+            found.push(post);
+            if at_bottom {
+                let last = found.get_shown_count(reveal_filtered).checked_sub(1);
+                found.posts_state.select(last);
+            }
+            if resort {
+                self.sort_chats();
+            }
+            if sel_chat_id == Some(post.chat_id) {
+                self.update_search();
+            }
+        } else {
+            // the chat is expected to come soon
+            let posts = self.orphan_posts.entry(post.chat_id).or_default();
+            if posts.len() >= ORPHAN_POSTS_CAPACITY {
+                warn!("too many posts from unknown chat {}", post.chat_id);
+                posts.remove(0);
+            }
+            posts.push(post);
+        }
+    }
+
+    // only the reactions change, the post keeps its place and the local state
+    fn on_post_updated(&mut self, post: proto::Post) {
+        let found = self
+            .chats
+            .get_mut(&post.chat_id)
+            .and_then(|chat| chat.posts.iter_mut().find(|p| p.id == post.id));
+        match found {
+            Some(found) => found.reactions = post.reactions,
+            None => debug!(
+                "post {} of chat {} is repeated, dropped",
+                post.id, post.chat_id
+            ),
+        }
+    }
+
+    // e.g. "↳ other: the beginning of the post", none if the post is not a reply
+    pub fn get_reply_preview(&self, post: &proto::Post) -> Option<String> {
+        if post.reply_to_post_id == NOT_POST_ID {
+            return None;
+        }
+        let original = self
+            .chats
+            .get(&post.chat_id)
+            .and_then(|chat| chat.find_post(post.reply_to_post_id));
+        Some(match original {
+            Some(original) => {
+                let text = original.text.trim_end();
+                let line = text.lines().next().unwrap_or_default();
+                let mut preview: String = line.chars().take(REPLY_PREVIEW_LEN).collect();
+                if preview.len() < text.len() {
+                    preview.push('…');
+                }
+                format!("↳ {}: {}", self.get_post_author(original), preview)
+            }
+            // the history is not fetched yet or the post is gone
+            None => String::from("↳ the post is not loaded"),
+        })
+    }
+
+    // the author of the post forwarded, none if the post is not forwarded
+    pub fn get_forwarded_text(&self, post: &proto::Post) -> Option<String> {
+        post.forwarded_from.as_ref().map(|origin| {
+            let author = self.get_post_author(&proto::Post {
+                user_id: origin.user_id,
+                author_name: origin.author_name.clone(),
+                ..Default::default()
+            });
+            format!("forwarded from {}", author)
+        })
+    }
+
+    // e.g. "📎 notes.txt (1.5 KiB)", a line per attachment
+    pub fn get_attachments_text(&self, post: &proto::Post) -> Vec<String> {
+        post.attachments
+            .iter()
+            .map(|a| format!("📎 {} ({})", a.name, get_size_text(a.size)))
+            .collect()
+    }
+
+    // e.g. "👍 2  🎉 1", none if nobody has reacted
+    pub fn get_reactions_text(&self, post: &proto::Post) -> Option<String> {
+        let counts: Vec<String> = post
+            .reactions
+            .iter()
+            .filter(|r| !r.user_ids.is_empty())
+            .map(|r| format!("{} {}", r.emoji, r.user_ids.len()))
+            .collect();
+        if counts.is_empty() {
+            None
+        } else {
+            Some(counts.join("  "))
+        }
+    }
+
+    fn is_filtered(&self, post: &proto::Post) -> bool {
+        let author = self.get_user(post.user_id);
+        self.config_filters
+            .iter()
+            .chain(self.filters.iter())
+            .any(|rule| rule.matches(post, author))
+    }
+
+    fn filtered_ids<'a>(&self, posts: impl Iterator<Item = &'a proto::Post>) -> HashSet<PostId> {
+        posts
+            .filter(|p| self.is_filtered(p))
+            .map(|p| p.id)
+            .collect()
+    }
+
+    // the received posts are filtered again, the unread ones stay counted
+    fn refilter(&mut self) {
+        let filtered: Vec<(ChatId, HashSet<PostId>)> = self
+            .chats
+            .values()
+            .map(|info| (info.chat.id, self.filtered_ids(info.posts.iter())))
+            .collect();
+        for (chat_id, filtered) in filtered {
+            if let Some(info) = self.chats.get_mut(&chat_id) {
+                info.filtered = filtered;
+            }
+        }
+        // the list has been changed under the scroll position
+        let reveal_filtered = self.reveal_filtered;
+        if let Some(sel) = self.get_sel_chat_mut() {
+            let cnt = sel.get_shown_count(reveal_filtered);
+            if sel.posts_state.selected().map_or(false, |idx| idx >= cnt) {
+                sel.posts_state.select(None);
+            }
+        }
+        self.update_search();
+    }
+
+    pub fn set_filters(&mut self, config_filters: Vec<FilterRule>, filters: Vec<FilterRule>) {
+        self.config_filters = config_filters;
+        self.filters = filters;
+        self.refilter();
+    }
+
+    // add <rule> | show | clear
+    fn on_filter_command(&mut self, args: &str) {
+        let (command, rule) = match args.find(char::is_whitespace) {
+            Some(idx) => (&args[..idx], args[idx..].trim()),
+            None => (args, ""),
+        };
+        match command {
+            "add" => match rule.parse::<FilterRule>() {
+                Ok(rule) => {
+                    info!("posts matching {} are filtered", rule);
+                    self.filters.push(rule);
+                    self.refilter();
+                    self.save_filters();
+                }
+                Err(e) => self.notice = Some(format!("filter: {}", e)),
+            },
+            "show" => {
+                self.reveal_filtered = !self.reveal_filtered;
+                self.refilter();
+            }
+            "clear" => {
+                self.filters.clear();
+                self.refilter();
+                self.save_filters();
+            }
+            _ => {
+                self.notice = Some(format!(
+                    "filter: use {} add <rule> | show | clear",
+                    FILTER_COMMAND
+                ))
+            }
+        }
+    }
+
+    fn save_filters(&mut self) {
+        let failed = self.filters_file.as_ref().and_then(|file| {
+            filter::save_rules(file, &self.filters)
+                .err()
+                .map(|e| format!("failed saving filters to {}, {}", file.display(), e))
+        });
+        if let Some(text) = failed {
+            self.on_notice(text);
+        }
+    }
+
+    pub fn on_chat_degraded(&mut self, chat_id: ChatId, degraded: bool) {
+        if let Some(chat) = self.chats.get_mut(&chat_id) {
+            chat.degraded = degraded;
+        } else {
+            warn!("state of unknown chat {}", chat_id);
+        }
+    }
+
+    pub fn on_membership(&mut self, chat_id: ChatId, membership: proto::Membership) {
+        if let Some(chat) = self.chats.get_mut(&chat_id) {
+            chat.membership = membership;
+        } else {
+            warn!("membership in unknown chat {}", chat_id);
+        }
+    }
+
+    pub fn on_muted(&mut self, chat_id: ChatId, muted: bool) {
+        if muted {
+            self.muted.insert(chat_id);
+        } else {
+            self.muted.remove(&chat_id);
+        }
+    }
+
+    // the server keeps the read position over the sessions, the local count is more recent
+    pub fn on_read_position(&mut self, chat_id: ChatId, post_id: PostId, unread: usize) {
+        let sel_chat_id = self.get_sel_chat().map(|c| c.chat.id);
+        if let Some(chat) = self.chats.get_mut(&chat_id) {
+            if sel_chat_id != Some(chat_id) && chat.posts.is_empty() && chat.unread == 0 {
+                chat.unread = unread;
+            }
+            if chat.last_read == NOT_POST_ID {
+                chat.last_read = post_id;
+            }
+        } else {
+            warn!("read position in unknown chat {}", chat_id);
+        }
+    }
+
+    pub fn on_typing(&mut self, chat_id: ChatId, user_id: UserId, typing: bool) {
+        if let Some(chat) = self.chats.get_mut(&chat_id) {
+            if typing {
+                chat.typing.insert(user_id);
+            } else {
+                chat.typing.remove(&user_id);
+            }
+        } else {
+            debug!("typing in unknown chat {}", chat_id);
+        }
+    }
+
+    // e.g. "alice, bob are typing", none if nobody is
+    pub fn get_typing_text(&self, chat: &ChatInfo) -> Option<String> {
+        let names: Vec<String> = chat
+            .typing
+            .iter()
+            .filter(|&&user_id| user_id != self.user.id)
+            .map(|&user_id| {
+                self.get_user(user_id)
+                    .map(|u| u.short_name.clone())
+                    .unwrap_or_else(|| format!("{}", user_id))
+            })
+            .collect();
+        match names.len() {
+            0 => None,
+            1 => Some(format!("{} is typing…", names[0])),
+            _ => Some(format!("{} are typing…", names.join(", "))),
+        }
+    }
+
+    // named chats remain visible to non-members, the server sends the update then
+    pub fn on_chat_left(&mut self, chat_id: ChatId) {
+        let user_id = self.user.id;
+        let unnamed = match self.chats.get_mut(&chat_id) {
+            Some(info) => {
+                info.chat.users.retain(|&u| u != user_id);
+                info.membership = proto::Membership::None;
+                info.chat.description.is_empty()
+            }
+            None => false,
+        };
+        if unnamed {
+            self.on_chat_deleted(chat_id);
+        }
+    }
+
+    pub fn on_chat_deleted(&mut self, chat_id: ChatId) {
+        self.chats.remove(&chat_id);
+        self.muted.remove(&chat_id);
+        self.sort_chats();
+        self.invitations.retain(|i| i.chat_id != chat_id);
+        if self.leaving == Some(chat_id) {
+            self.leaving = None;
+        }
+        if self.modal == Widget::Confirm {
+            self.close_modal();
+        }
+        self.orphan_posts.remove(&chat_id);
+        if self.unsend.as_ref().map(|u| u.chat_id) == Some(chat_id) {
+            self.unsend = None;
+        }
+    }
+
+    // posts of a chat are accepted in the order they were sent
+    fn first_unaccepted_post(&self, chat_id: ChatId) -> Option<usize> {
+        self.pending_posts
+            .iter()
+            .position(|p| p.chat_id == chat_id && p.post_id.is_none() && p.failed.is_none())
+    }
+
+    // only the latest own post is undoable
+    pub fn on_post_accepted(&mut self, chat_id: ChatId, post_id: PostId) {
+        self.notice = None;
+        if let Some(idx) = self.first_unaccepted_post(chat_id) {
+            let delivered = self
+                .chats
+                .get(&chat_id)
+                .map(|c| c.posts.iter().any(|p| p.id == post_id))
+                .unwrap_or(false);
+            if delivered {
+                // the post has come back before the acknowledge
+                self.pending_posts.remove(idx);
+            } else {
+                self.pending_posts[idx].post_id = Some(post_id);
+            }
+        }
+        self.unsend = if self.unsend_grace > Duration::from_secs(0) {
+            Some(PendingUnsend {
+                chat_id,
+                post_id,
+                expires: Instant::now() + self.unsend_grace,
+            })
+        } else {
+            None
+        };
+    }
+
+    pub fn on_post_deleted(&mut self, chat_id: ChatId, post_id: PostId) {
+        if let Some(chat) = self.chats.get_mut(&chat_id) {
+            chat.remove(post_id);
+        }
+    }
+
+    pub fn on_post_failed(&mut self, chat_id: ChatId, text: String) {
+        if let Some(idx) = self.first_unaccepted_post(chat_id) {
+            self.pending_posts.remove(idx);
+        }
+        self.on_notice(text);
+    }
+
+    // the post is shown as failed until sent again
+    pub fn on_command_failed(&mut self, command: Command, text: String) {
+        if let Command::Post(post) = command {
+            if let Some(idx) = self.first_unaccepted_post(post.chat_id) {
+                self.pending_posts[idx].failed = Some(post);
+            }
+        }
+        self.on_notice(text);
+    }
+
+    // the results are not to interrupt typing or answering the invitation
+    pub fn on_search_results(&mut self, query: String, posts: Vec<proto::Post>) {
+        if posts.is_empty() {
+            self.notice = Some(format!("search: nothing matches '{}'", query));
+            return;
+        }
+        if self.modal != Widget::App && self.modal != Widget::Results {
+            self.notice = Some(format!(
+                "search: {} of '{}' dismissed",
+                self.plural(posts.len(), Counted::Matches),
+                query
+            ));
+            return;
+        }
+        self.notice = None;
+        let mut state = ListState::default();
+        state.select(Some(0));
+        self.found = Some(FoundPosts {
+            query,
+            posts,
+            state,
+        });
+        self.modal = Widget::Results;
+    }
+
+    pub fn on_notice(&mut self, text: String) {
+        warn!("{}", text);
+        self.notice = Some(text);
+    }
+
+    // text dump of the state visible to the user, two equal dumps mean the same screen
+    pub fn state_dump(&self) -> String {
+        let mut lines = vec![
+            format!("user: {} {}", self.user.id, self.user_description),
+            format!("connection: {:?}", self.connection),
+            format!("focused: {:?}, modal: {:?}", self.focused, self.modal),
+            format!("notice: {:?}", self.notice),
+            format!(
+                "invitations: {:?}",
+                self.invitations
+                    .iter()
+                    .map(|i| (i.chat_id, i.from_user_id))
+                    .collect::<Vec<_>>()
+            ),
+        ];
+        for user in &self.users {
+            lines.push(format!(
+                "user {}: {}, online: {}",
+                user.id,
+                App::get_user_description(user),
+                self.online.contains(&user.id)
+            ));
+        }
+        lines.push(format!(
+            "selected user: {:?}",
+            self.get_sel_user().map(|u| u.id)
+        ));
+        for info in self.chats.values() {
+            lines.push(format!(
+                "chat {}: {:?}, users: {:?}, history: {}, unread: {}, degraded: {}, membership: {:?}, selected post: {:?}",
+                info.chat.id,
+                info.chat.description,
+                info.chat.users,
+                info.history_len,
+                info.unread,
+                info.degraded,
+                info.membership,
+                info.posts_state.selected()
+            ));
+            for post in &info.posts {
+                lines.push(format!(
+                    "  post {} by {}: {:?}",
+                    post.id, post.user_id, post.text
+                ));
+            }
+        }
+        lines.push(format!(
+            "selected chat: {:?}",
+            self.get_sel_chat().map(|c| c.chat.id)
+        ));
+        lines.push(format!(
+            "filters: {}, revealed: {}",
+            self.config_filters.len() + self.filters.len(),
+            self.reveal_filtered
+        ));
+        for pending in &self.pending_posts {
+            lines.push(format!(
+                "pending post in {}: {:?}, id: {:?}, failed: {}",
+                pending.chat_id,
+                pending.text,
+                pending.post_id,
+                pending.is_failed()
+            ));
+        }
+        lines.push(format!(
+            "unsend: {:?}",
+            self.unsend.as_ref().map(|u| (u.chat_id, u.post_id))
+        ));
+        if let Some(input) = &self.input {
+            lines.push(format!(
+                "input {:?}: {:?}, oversize: {:?}",
+                input.title,
+                input.text,
+                input.oversize.as_ref().map(|c| c.action())
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+#[test]
+fn test_vec_iter() {
+    let mut v = vec![4; 4];
+    if !v.iter().any(|&n| n == 7) {
+        v.push(7);
+    }
+    assert_eq!(v.len(), 5);
+}
+
+#[cfg(test)]
+fn test_app() -> (App, mpsc::Receiver<Command>) {
+    let (tx_command, mut rx_command) = mpsc::channel(16);
+    let mut app = App::new(
+        proto::UserInfo {
+            name: String::from("User Name"),
+            short_name: String::from("user"),
+        },
+        tx_command,
+        false,
+        KeyBindings::default(),
+    );
+    assert!(matches!(
+        rx_command.blocking_recv(),
+        Some(Command::Register(_))
+    ));
+    app.on_registered(1);
+    app.on_chat_updated(
+        proto::Chat {
+            id: 10,
+            description: String::from("chat"),
+            users: vec![1],
+            ..Default::default()
+        },
+        0,
+    );
+    app.on_membership(10, proto::Membership::Member);
+    app.chats_state.select(Some(0));
+    app.on_user_info(proto::User {
+        id: 2,
+        short_name: String::from("other"),
+        ..Default::default()
+    });
+    app.users_state.select(Some(0));
+    (app, rx_command)
+}
+
+#[test]
+fn test_default_bindings_dispatch() {
+    let (mut app, rx_command) = test_app();
+    let input_purpose = |app: &App| {
+        app.input
+            .as_ref()
+            .map(|i| i.purpose == InputResult::NewPost)
+    };
+    let reset = |app: &mut App| {
+        app.modal = Widget::App;
+        app.input = None;
+    };
+    // 'p' creates a post from any pane
+    for &focused in &[Widget::Users, Widget::Chats, Widget::Posts] {
+        app.focused = focused;
+        app.on_key('p', false, false);
+        assert_eq!(input_purpose(&app), Some(true));
+        reset(&mut app);
+    }
+    // ctrl+n depends on the pane
+    app.focused = Widget::Chats;
+    app.on_key('n', true, false);
+    assert_eq!(input_purpose(&app), Some(false));
+    reset(&mut app);
+    app.focused = Widget::Posts;
+    app.on_key('n', true, false);
+    assert_eq!(input_purpose(&app), Some(true));
+    reset(&mut app);
+    app.focused = Widget::Users;
+    app.on_key('n', true, false);
+    assert!(app.input.is_none());
+    // alt+i depends on the pane
+    app.focused = Widget::Chats;
+    app.on_key('i', false, true);
+    assert_eq!(input_purpose(&app), Some(true));
+    reset(&mut app);
+    app.focused = Widget::Users;
+    app.on_key('i', false, true);
+    assert!(app.input.is_none());
+    // log keys do not leak into the other actions
+    app.modal = Widget::Log;
+    for &c in &[' ', '-', '+'] {
+        app.on_key(c, false, false);
+        assert!(app.input.is_none());
+    }
+    reset(&mut app);
+    // typed text is not dispatched, exit works while typing
+    app.on_key('p', false, false);
+    app.on_key('p', false, false);
+    app.on_key('n', true, false);
+    assert_eq!(app.input.as_ref().map(|i| i.text.as_str()), Some("pn"));
+    app.on_key('q', true, false);
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 2);
+    assert!(matches!(
+        &commands[0],
+        Command::Invite(proto::Invitation {
+            chat_id: 10,
+            from_user_id: 1,
+            to_user_id: 2,
+            declined: false,
+        })
+    ));
+    assert!(matches!(&commands[1], Command::Exit));
+}
+
+#[cfg(test)]
+fn collect_commands(app: App, mut rx_command: mpsc::Receiver<Command>) -> Vec<Command> {
+    drop(app);
+    let mut commands = Vec::new();
+    while let Some(command) = rx_command.blocking_recv() {
+        // sent along with the typed posts and the scrolling, checked on their own
+        if !matches!(command, Command::Typing(_) | Command::MarkRead(..)) {
+            commands.push(command);
+        }
+    }
+    commands
+}
+
+#[test]
+fn test_unsend_confirmed_post() {
+    let (mut app, rx_command) = test_app();
+    app.on_post_accepted(10, 100);
+    assert_eq!(app.get_pending_unsend().map(|u| u.post_id), Some(100));
+    app.on_key('z', true, false);
+    assert!(app.get_pending_unsend().is_none());
+    // nothing more to unsend
+    app.on_key('z', true, false);
+    // the same by the command typed as a post
+    app.on_post_accepted(10, 101);
+    app.on_key('p', false, false);
+    for c in UNSEND_COMMAND.chars() {
+        app.on_key(c, false, false);
+    }
+    app.on_enter();
+    assert!(app.input.is_none());
+    assert!(app.get_pending_unsend().is_none());
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 2);
+    assert!(matches!(
+        &commands[0],
+        Command::DeletePost(proto::PostReference {
+            user_id: 1,
+            chat_id: 10,
+            post_id: 100
+        })
+    ));
+    assert!(matches!(
+        &commands[1],
+        Command::DeletePost(proto::PostReference { post_id: 101, .. })
+    ));
+}
+
+#[test]
+fn test_unsend_timeout() {
+    let (mut app, rx_command) = test_app();
+    app.on_post_accepted(10, 100);
+    app.expire_unsend(Instant::now() + app.unsend_grace / 2);
+    assert!(app.get_pending_unsend().is_some());
+    app.expire_unsend(Instant::now() + app.unsend_grace);
+    assert!(app.get_pending_unsend().is_none());
+    app.on_key('z', true, false);
+    // disabled unsending
+    app.unsend_grace = Duration::from_secs(0);
+    app.on_post_accepted(10, 101);
+    assert!(app.get_pending_unsend().is_none());
+    assert!(collect_commands(app, rx_command).is_empty());
+}
+
+#[test]
+fn test_unsend_cancelled_by_chat_switch() {
+    let (mut app, rx_command) = test_app();
+    app.on_chat_updated(
+        proto::Chat {
+            id: 11,
+            description: String::from("another chat"),
+            users: vec![1],
+            ..Default::default()
+        },
+        0,
+    );
+    app.on_membership(11, proto::Membership::Member);
+    app.focused = Widget::Chats;
+    app.chats_state.select(Some(0));
+    let first = app.get_sel_chat().unwrap().chat.id;
+    // composing in the same chat keeps the unsend
+    app.on_post_accepted(first, 100);
+    app.on_key('p', false, false);
+    assert!(app.get_pending_unsend().is_some());
+    app.on_esc();
+    // switching to another chat drops it
+    app.on_down();
+    assert_ne!(app.get_sel_chat().unwrap().chat.id, first);
+    assert!(app.get_pending_unsend().is_none());
+    // composing in another chat drops it too
+    app.on_post_accepted(first, 101);
+    app.on_key('p', false, false);
+    assert!(app.get_pending_unsend().is_none());
+    app.on_esc();
+    // as well as exit
+    app.on_post_accepted(first, 102);
+    app.on_key('q', true, false);
+    assert!(app.get_pending_unsend().is_none());
+    app.on_key('z', true, false);
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 1);
+    assert!(matches!(&commands[0], Command::Exit));
+}
+
+#[test]
+fn test_unsend_rapid_double_post() {
+    let (mut app, rx_command) = test_app();
+    app.on_post_accepted(10, 100);
+    app.on_post_accepted(10, 101);
+    app.on_key('z', true, false);
+    app.on_key('z', true, false);
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 1);
+    assert!(matches!(
+        &commands[0],
+        Command::DeletePost(proto::PostReference { post_id: 101, .. })
+    ));
+}
+
+#[test]
+fn test_unsend_result() {
+    let (mut app, _rx_command) = test_app();
+    for id in 100..103 {
+        app.on_new_post(proto::Post {
+            id,
+            chat_id: 10,
+            user_id: 1,
+            ..Default::default()
+        });
+    }
+    app.on_post_deleted(10, 101);
+    // replayed by the resumed stream
+    app.on_new_post(proto::Post {
+        id: 102,
+        chat_id: 10,
+        user_id: 1,
+        ..Default::default()
+    });
+    let ids: Vec<PostId> = app.get_sel_posts().iter().map(|p| p.id).collect();
+    assert_eq!(ids, vec![100, 102]);
+    // failure is surfaced until the next accepted post
+    app.on_notice(String::from("unsend: no longer available"));
+    assert_eq!(app.notice.as_deref(), Some("unsend: no longer available"));
+    app.on_post_accepted(10, 103);
+    assert!(app.notice.is_none());
+}
+
+#[test]
+fn test_reconnect_countdown() {
+    let (mut app, rx_command) = test_app();
+    app.on_disconnected();
+    app.on_reconnect_scheduled(6, 42);
+    let reconnect = app.reconnect.as_mut().unwrap();
+    assert_eq!(reconnect.text(), "reconnecting in 42s, attempt 6");
+    let at = reconnect.at.unwrap();
+    reconnect.update(at - Duration::from_millis(41_500));
+    assert_eq!(reconnect.remaining_secs, 42);
+    reconnect.update(at - Duration::from_millis(1_500));
+    assert_eq!(reconnect.text(), "reconnecting in 2s, attempt 6");
+    reconnect.update(at + Duration::from_secs(5));
+    assert_eq!(reconnect.remaining_secs, 0);
+    app.on_reconnect_attempt();
+    assert_eq!(
+        app.reconnect.as_ref().unwrap().text(),
+        "reconnecting, attempt 6"
+    );
+    // the key and the command typed as a post skip the delay
+    app.on_key('r', false, false);
+    send_post(&mut app, RECONNECT_COMMAND);
+    assert!(app.input.is_none());
+    app.on_connected();
+    assert!(app.reconnect.is_none());
+    // nothing to skip while connected
+    app.on_key('r', false, false);
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 2);
+    assert!(commands
+        .iter()
+        .all(|command| matches!(command, Command::ReconnectNow)));
+}
+
+#[test]
+fn test_connection_state() {
+    let (mut app, _rx_command) = test_app();
+    assert_eq!(app.connection, Connection::Connecting);
+    app.on_user_entered(2);
+    app.on_connected();
+    assert_eq!(app.connection, Connection::Connected);
+    app.on_disconnected();
+    assert_eq!(app.connection, Connection::Disconnected);
+    // presence is resent on reconnect
+    assert!(app.online.is_empty());
+    app.on_connected();
+    assert_eq!(app.connection, Connection::Connected);
+}
+
+#[cfg(test)]
+fn send_post(app: &mut App, text: &str) {
+    app.on_key('p', false, false);
+    for c in text.chars() {
+        app.on_key(c, false, false);
+    }
+    app.on_enter();
+}
+
+#[test]
+fn test_pending_posts() {
+    let (mut app, _rx_command) = test_app();
+    let pending_texts = |app: &App| {
+        app.get_sel_pending_posts()
+            .iter()
+            .map(|p| p.text.clone())
+            .collect::<Vec<String>>()
+    };
+    // posting works while disconnected
+    app.on_disconnected();
+    send_post(&mut app, "first");
+    send_post(&mut app, "second");
+    send_post(&mut app, "third");
+    assert_eq!(pending_texts(&app), vec!["first", "second", "third"]);
+    app.on_connected();
+    // acknowledged, then delivered
+    app.on_post_accepted(10, 100);
+    assert_eq!(pending_texts(&app).len(), 3);
+    app.on_new_post(proto::Post {
+        id: 100,
+        chat_id: 10,
+        user_id: 1,
+        text: String::from("first"),
+        ..Default::default()
+    });
+    assert_eq!(pending_texts(&app), vec!["second", "third"]);
+    // delivered, then acknowledged
+    app.on_new_post(proto::Post {
+        id: 101,
+        chat_id: 10,
+        user_id: 1,
+        text: String::from("second"),
+        ..Default::default()
+    });
+    app.on_post_accepted(10, 101);
+    assert_eq!(pending_texts(&app), vec!["third"]);
+    // rejected
+    app.on_post_failed(10, String::from("post: not permitted"));
+    assert!(pending_texts(&app).is_empty());
+    assert_eq!(app.notice.as_deref(), Some("post: not permitted"));
+    assert_eq!(app.get_sel_posts().len(), 2);
+}
+
+#[test]
+fn test_failed_post_resent() {
+    let (mut app, rx_command) = test_app();
+    let pending = |app: &App| {
+        app.get_sel_pending_posts()
+            .iter()
+            .map(|p| format!("{}{}", p.text, if p.is_failed() { " failed" } else { "" }))
+            .collect::<Vec<String>>()
+    };
+    send_post(&mut app, "first");
+    send_post(&mut app, "second");
+    // given back by the service with the key of its attempts
+    let failed = proto::Post {
+        chat_id: 10,
+        user_id: 1,
+        text: String::from("first"),
+        idempotency_key: String::from("k1"),
+        ..Default::default()
+    };
+    app.on_command_failed(
+        Command::Post(failed),
+        String::from("request: server is unavailable"),
+    );
+    assert_eq!(pending(&app), vec!["first failed", "second"]);
+    assert_eq!(
+        app.notice.as_deref(),
+        Some("request: server is unavailable")
+    );
+    // the second one is accepted meanwhile
+    app.on_post_accepted(10, 101);
+    app.apply_action(Action::Resend);
+    assert_eq!(pending(&app), vec!["second", "first"]);
+    app.on_post_accepted(10, 102);
+    // nothing failed to resend
+    app.apply_action(Action::Resend);
+    let commands = collect_commands(app, rx_command);
+    let posts: Vec<(&str, &str)> = commands
+        .iter()
+        .filter_map(|command| match command {
+            Command::Post(post) => Some((post.text.as_str(), post.idempotency_key.as_str())),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(posts, vec![("first", ""), ("second", ""), ("first", "k1")]);
+}
+
+#[test]
+fn test_oversize_on_typing() {
+    let (mut app, _rx_command) = test_app();
+    app.composer_limits = ComposerLimits {
+        max_lines: 50,
+        max_bytes: 4,
+    };
+    app.on_key('p', false, false);
+    for c in "abcd".chars() {
+        app.on_key(c, false, false);
+    }
+    assert!(app.input.as_ref().unwrap().oversize.is_none());
+    app.on_key('e', false, false);
+    let input = app.input.as_ref().unwrap();
+    assert_eq!(input.text, "abcde");
+    assert_eq!(input.oversize.as_ref().unwrap().before, "abcd");
+    // typing is blocked until the choice is made
+    app.on_key('f', false, false);
+    app.on_backspace();
+    assert_eq!(app.input.as_ref().unwrap().text, "abcde");
+    // the default choice trims
+    app.on_enter();
+    let input = app.input.as_ref().unwrap();
+    assert_eq!(input.text, "abcd");
+    assert!(input.oversize.is_none());
+}
+
+#[test]
+fn test_oversize_on_paste() {
+    let (mut app, rx_command) = test_app();
+    app.composer_limits = ComposerLimits {
+        max_lines: 2,
+        max_bytes: 4096,
+    };
+    app.on_key('p', false, false);
+    app.on_paste("1\n2");
+    assert_eq!(app.input.as_ref().unwrap().text, "1\n2");
+    assert!(app.input.as_ref().unwrap().oversize.is_none());
+    app.on_paste("\n3\n4");
+    let choice = app.input.as_ref().unwrap().oversize.as_ref().unwrap();
+    assert_eq!(choice.before, "1\n2");
+    // no attachments: trim / cancel only
+    assert_eq!(
+        choice.options,
+        vec![OversizeAction::Trim, OversizeAction::Cancel]
+    );
+    // right to cancel restores the content before the paste
+    app.on_right();
+    app.on_right();
+    app.on_enter();
+    assert_eq!(app.input.as_ref().unwrap().text, "1\n2");
+    // esc cancels as well
+    app.on_paste("\n3");
+    app.on_esc();
+    assert_eq!(app.input.as_ref().unwrap().text, "1\n2");
+    assert!(app.input.is_some());
+    // the post is sent as is
+    app.on_enter();
+    assert!(app.input.is_none());
+    let commands = collect_commands(app, rx_command);
+    assert!(matches!(
+        &commands[..],
+        [Command::Post(proto::Post { text, .. })] if text == "1\n2"
+    ));
+}
+
+#[test]
+fn test_oversize_as_attachment() {
+    let (mut app, rx_command) = test_app();
+    app.attachments = true;
+    app.composer_limits = ComposerLimits {
+        max_lines: 1,
+        max_bytes: 4096,
+    };
+    app.on_key('p', false, false);
+    app.on_paste("line 1\nline 2");
+    let choice = app.input.as_ref().unwrap().oversize.as_ref().unwrap();
+    assert_eq!(choice.options.len(), 3);
+    app.on_right();
+    app.on_left();
+    app.on_right();
+    app.on_enter();
+    assert!(app.input.is_none());
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 1);
+    match &commands[0] {
+        Command::PostAttachment(attachment) => {
+            assert_eq!(attachment.chat_id, 10);
+            assert_eq!(attachment.content, "line 1\nline 2");
+            assert_eq!(
+                attachment.text,
+                composer::attachment_post_text(&attachment.file_name, &attachment.content)
+            );
+        }
+        _ => panic!("attachment is expected"),
+    }
+}
+
+#[test]
+fn test_post_limits() {
+    let (mut app, rx_command) = test_app();
+    app.post_limits = PostLimits {
+        max_bytes: 8,
+        max_attachments: 1,
+    };
+    // refused at once, the input stays to be fixed
+    app.on_key('p', false, false);
+    app.on_paste(" \n ");
+    app.on_enter();
+    assert_eq!(app.notice.as_deref(), Some("not sent: post is empty"));
+    assert!(app.input.as_ref().unwrap().rejected);
+    app.input
+        .as_mut()
+        .unwrap()
+        .set_text(String::from("123456789"));
+    assert!(!app.input.as_ref().unwrap().rejected);
+    app.on_enter();
+    assert_eq!(
+        app.notice.as_deref(),
+        Some("not sent: post of 9 bytes exceeds 8 bytes")
+    );
+    assert!(app.input.as_ref().unwrap().rejected);
+    // the size at the limit is sent
+    app.on_backspace();
+    assert!(!app.input.as_ref().unwrap().rejected);
+    app.on_enter();
+    assert!(app.input.is_none());
+    let commands = collect_commands(app, rx_command);
+    assert!(matches!(
+        &commands[..],
+        [Command::Post(proto::Post { text, .. })] if text == "12345678"
+    ));
+}
+
+#[test]
+fn test_chats_order() {
+    let (mut app, _rx_command) = test_app();
+    let ids = |app: &App| app.get_chats().map(|c| c.chat.id).collect::<Vec<ChatId>>();
+    let sel_chat_id = |app: &App| app.get_sel_chat().map(|c| c.chat.id);
+    let chat = |id, description: &str, created| proto::Chat {
+        id,
+        description: String::from(description),
+        users: vec![1, 2],
+        created,
+        ..Default::default()
+    };
+    let post = |id, chat_id, created| proto::Post {
+        id,
+        chat_id,
+        user_id: 2,
+        text: String::from("text"),
+        created,
+        ..Default::default()
+    };
+    // the named chats go by their creation, the dialogs not posted to come last
+    app.on_chat_updated(chat(30, "older", 100), 0);
+    app.on_chat_updated(chat(20, "newer", 200), 0);
+    app.on_chat_updated(chat(40, "", 300), 0);
+    assert_eq!(ids(&app), vec![20, 30, 10, 40]);
+    assert_eq!(sel_chat_id(&app), Some(10));
+    // the chat posted to moves up, the selection follows its chat
+    app.on_new_post(post(1, 40, 400));
+    assert_eq!(ids(&app), vec![40, 20, 30, 10]);
+    assert_eq!(sel_chat_id(&app), Some(10));
+    // the older post keeps the place
+    app.on_new_post(post(2, 40, 50));
+    assert_eq!(ids(&app), vec![40, 20, 30, 10]);
+    // the posts came before their chat
+    app.on_new_post(post(3, 50, 500));
+    app.on_chat_updated(chat(50, "", 0), 0);
+    assert_eq!(ids(&app), vec![50, 40, 20, 30, 10]);
+    assert_eq!(sel_chat_id(&app), Some(10));
+    // moving goes by the shown order
+    app.focused = Widget::Chats;
+    app.on_up();
+    assert_eq!(sel_chat_id(&app), Some(30));
+    // the chat above the selected one is gone
+    app.on_chat_deleted(40);
+    assert_eq!(ids(&app), vec![50, 20, 30, 10]);
+    assert_eq!(sel_chat_id(&app), Some(30));
+    // the repeated chat keeps the order
+    app.on_chat_updated(chat(20, "newer", 200), 0);
+    assert_eq!(ids(&app), vec![50, 20, 30, 10]);
+    assert_eq!(sel_chat_id(&app), Some(30));
+}
+
+#[test]
+fn test_open_dialog() {
+    let (mut app, rx_command) = test_app();
+    let sel_chat_id = |app: &App| app.get_sel_chat().map(|c| c.chat.id);
+    let dialog = |id, users| proto::Chat {
+        id,
+        users,
+        ..Default::default()
+    };
+    app.focused = Widget::Users;
+    app.on_enter();
+    // another chat of the same members is no dialog
+    app.on_chat_updated(
+        proto::Chat {
+            id: 30,
+            description: String::from("other"),
+            users: vec![1, 2],
+            ..Default::default()
+        },
+        0,
+    );
+    assert_eq!(sel_chat_id(&app), Some(10));
+    app.on_chat_updated(dialog(20, vec![1, 2]), 0);
+    assert_eq!(sel_chat_id(&app), Some(20));
+    assert_eq!(app.focused, Widget::Posts);
+    // the known dialog is selected at once
+    app.chats_state.select(Some(0));
+    app.focused = Widget::Users;
+    app.on_enter();
+    assert_eq!(sel_chat_id(&app), Some(20));
+    assert_eq!(app.focused, Widget::Posts);
+    // the existing dialog comes by the chats stream rather than the response
+    app.on_user_info(proto::User {
+        id: 3,
+        short_name: String::from("third"),
+        ..Default::default()
+    });
+    app.users_state.select(Some(1));
+    app.focused = Widget::Users;
+    app.on_enter();
+    app.on_chat_updated(dialog(5, vec![1, 3]), 0);
+    assert_eq!(sel_chat_id(&app), Some(5));
+    // selected once
+    app.chats_state.select(Some(1));
+    app.on_chat_updated(dialog(5, vec![1, 3]), 0);
+    assert_eq!(sel_chat_id(&app), Some(10));
+    let commands = collect_commands(app, rx_command);
+    let desired: Vec<Vec<UserId>> = commands
+        .iter()
+        .map(|command| match command {
+            Command::CreateChat(info) => {
+                assert!(info.description.is_empty());
+                assert!(info.auto_enter);
+                info.desired_users.clone()
+            }
+            _ => panic!("unexpected command"),
+        })
+        .collect();
+    assert_eq!(desired, vec![vec![2], vec![3]]);
+}
+
+#[test]
+fn test_edit_profile() {
+    let (mut app, rx_command) = test_app();
+    app.focused = Widget::Chats;
+    app.on_key('p', true, false);
+    assert_eq!(app.input.as_ref().unwrap().text, "user, User Name");
+    // both names are required
+    app.input.as_mut().unwrap().text = String::from("renamed");
+    app.on_enter();
+    assert!(app.input.is_some());
+    assert!(app.notice.is_some());
+    app.input.as_mut().unwrap().text = String::from("renamed, New Name");
+    app.on_enter();
+    assert!(app.input.is_none());
+    // the names come back by the users stream, replacing the known ones
+    app.on_user_info(proto::User {
+        id: 1,
+        name: String::from("New Name"),
+        short_name: String::from("renamed"),
+        ..Default::default()
+    });
+    app.on_user_info(proto::User {
+        id: 2,
+        short_name: String::from("another"),
+        ..Default::default()
+    });
+    assert_eq!(app.user_description, "renamed (New Name)");
+    assert_eq!(app.user.short_name, "renamed");
+    assert_eq!(app.users.len(), 2);
+    assert!(app.users.iter().any(|u| u.short_name == "another"));
+    assert!(!app.users.iter().any(|u| u.short_name == "other"));
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 1);
+    assert!(matches!(
+        &commands[0],
+        Command::UpdateUser(info) if info.short_name == "renamed" && info.name == "New Name"
+    ));
+}
+
+#[test]
+fn test_send_file() {
+    let (mut app, rx_command) = test_app();
+    app.focused = Widget::Chats;
+    // the server takes no attachments
+    app.on_key('f', true, false);
+    assert!(app.input.is_none());
+    app.attachments = true;
+    app.on_key('f', true, false);
+    for c in " notes.txt ".chars() {
+        app.on_key(c, false, false);
+    }
+    app.on_enter();
+    assert!(app.input.is_none());
+    // the received attachments are listed by their names and sizes
+    app.on_new_post(proto::Post {
+        id: 100,
+        chat_id: 10,
+        user_id: 2,
+        attachments: vec![
+            proto::AttachmentInfo {
+                id: 5,
+                name: String::from("notes.txt"),
+                size: 1536,
+            },
+            proto::AttachmentInfo {
+                id: 6,
+                name: String::from("photo.jpg"),
+                size: 3 * 1024 * 1024,
+            },
+        ],
+        ..Default::default()
+    });
+    assert_eq!(
+        app.get_attachments_text(&app.get_sel_posts()[0]),
+        vec!["📎 notes.txt (1.5 KiB)", "📎 photo.jpg (3.0 MiB)"]
+    );
+    assert_eq!(get_size_text(1023), "1023 B");
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 1);
+    assert!(matches!(
+        &commands[0],
+        Command::SendFile(10, path) if path.as_path() == std::path::Path::new("notes.txt")
+    ));
+}
+
+#[test]
+fn test_posts_of_unknown_chat() {
+    let (mut app, _rx_command) = test_app();
+    let post = |id| proto::Post {
+        id,
+        chat_id: 11,
+        user_id: 2,
+        ..Default::default()
+    };
+    app.on_new_post(post(1));
+    app.on_new_post(post(2));
+    assert!(app.get_chat(11).is_none());
+    app.on_chat_updated(
+        proto::Chat {
+            id: 11,
+            description: String::from("another chat"),
+            users: vec![1, 2],
+            ..Default::default()
+        },
+        0,
+    );
+    let ids: Vec<PostId> = app
+        .get_chat(11)
+        .unwrap()
+        .posts
+        .iter()
+        .map(|p| p.id)
+        .collect();
+    assert_eq!(ids, vec![1, 2]);
+    // the buffer is bounded
+    for id in 100..ORPHAN_POSTS_CAPACITY as PostId + 101 {
+        app.on_new_post(proto::Post {
+            chat_id: 12,
+            ..post(id)
+        });
+    }
+    assert_eq!(app.orphan_posts[&12].len(), ORPHAN_POSTS_CAPACITY);
+    assert_eq!(app.orphan_posts[&12][0].id, 101);
+    app.on_chat_deleted(12);
+    assert!(app.orphan_posts.is_empty());
+}
+
+#[test]
+fn test_repeated_posts_dropped() {
+    let (mut app, _rx_command) = test_app();
+    let post = |id, chat_id| proto::Post {
+        id,
+        chat_id,
+        user_id: 2,
+        text: format!("post {}", id),
+        ..Default::default()
+    };
+    app.on_new_post(post(1, 10));
+    app.on_new_post(post(1, 10));
+    app.on_new_post(post(2, 10));
+    let ids: Vec<PostId> = app.get_sel_posts().iter().map(|p| p.id).collect();
+    assert_eq!(ids, vec![1, 2]);
+    assert_eq!(app.get_chat(10).unwrap().unread, 0);
+    // before the chat too
+    app.on_new_post(post(3, 11));
+    app.on_new_post(post(3, 11));
+    assert_eq!(app.orphan_posts[&11].len(), 1);
+    // the same chat again
+    let chat = app.get_chat(10).unwrap().chat.clone();
+    app.on_chat_updated(chat.clone(), 0);
+    assert_eq!(app.get_chat(10).unwrap().chat, chat);
+    assert_eq!(app.get_chat(10).unwrap().posts.len(), 2);
+
+    // the oldest ids are forgotten
+    let mut seen = SeenPosts::new(2);
+    assert!(seen.insert(1));
+    assert!(!seen.insert(1));
+    assert!(seen.insert(2));
+    assert!(seen.insert(3));
+    assert!(seen.insert(1));
+    assert!(!seen.insert(3));
+}
+
+#[test]
+fn test_posts_ordered_by_seq() {
+    let (mut app, _rx_command) = test_app();
+    let post = |id, seq| proto::Post {
+        id,
+        chat_id: 10,
+        user_id: 2,
+        text: format!("post {}", id),
+        seq,
+        ..Default::default()
+    };
+    let ids = |app: &App| -> Vec<PostId> {
+        app.get_chat(10)
+            .unwrap()
+            .posts
+            .iter()
+            .map(|p| p.id)
+            .collect()
+    };
+    // the missed posts replayed after the newer one
+    app.on_new_post(post(5, 15));
+    app.on_new_post(post(3, 13));
+    app.on_new_post(post(4, 14));
+    app.on_new_post(post(6, 16));
+    assert_eq!(ids(&app), vec![3, 4, 5, 6]);
+    // the page overlapping the posts held is cut at the eldest one
+    app.on_history(
+        10,
+        0,
+        vec![post(4, 14), post(1, 11), post(3, 13), post(2, 12)],
+    );
+    assert_eq!(ids(&app), vec![1, 2, 3, 4, 5, 6]);
+    // the unnumbered ones keep the order of delivery
+    app.on_new_post(post(8, 0));
+    app.on_new_post(post(7, 0));
+    assert_eq!(ids(&app), vec![1, 2, 3, 4, 5, 6, 8, 7]);
+}
+
+#[test]
+fn test_posts_scroll_per_chat() {
+    let (mut app, _rx_command) = test_app();
+    app.on_chat_updated(
+        proto::Chat {
+            id: 11,
+            description: String::from("another chat"),
+            users: vec![1],
+            ..Default::default()
+        },
+        0,
+    );
+    for (id, chat_id) in &[(1, 10), (2, 10), (3, 11)] {
+        app.on_new_post(proto::Post {
+            id: *id,
+            chat_id: *chat_id,
+            ..Default::default()
+        });
+    }
+    app.chats_state.select(Some(0));
+    let first = app.get_sel_chat().unwrap().chat.id;
+    app.focused = Widget::Posts;
+    app.on_down();
+    app.on_down();
+    let scrolled = app.get_sel_chat().unwrap().posts_state.selected();
+    assert!(scrolled.is_some());
+    // another chat has its own position, starting at the newest post
+    app.focused = Widget::Chats;
+    app.on_down();
+    assert_ne!(app.get_sel_chat().unwrap().chat.id, first);
+    assert_eq!(app.get_sel_chat().unwrap().posts_state.selected(), Some(0));
+    // the position is restored on return
+    app.on_up();
+    assert_eq!(app.get_sel_chat().unwrap().chat.id, first);
+    assert_eq!(app.get_sel_chat().unwrap().posts_state.selected(), scrolled);
+}
+
+#[test]
+fn test_unread_counters() {
+    let (mut app, _rx_command) = test_app();
+    let chat = |id, description: &str| proto::Chat {
+        id,
+        description: String::from(description),
+        users: vec![1, 2],
+        ..Default::default()
+    };
+    let post = |id, chat_id, user_id| proto::Post {
+        id,
+        chat_id,
+        user_id,
+        text: String::from("text"),
+        ..Default::default()
+    };
+    let unread = |app: &App, chat_id| app.get_chat(chat_id).map(|c| c.unread);
+    app.on_chat_updated(chat(20, "second"), 0);
+    app.on_chat_updated(chat(30, "third"), 0);
+    // chat 10 is selected
+    app.on_new_post(post(1, 10, 2));
+    assert_eq!(unread(&app, 10), Some(0));
+    app.on_new_post(post(2, 20, 2));
+    app.on_new_post(post(3, 20, 2));
+    assert_eq!(unread(&app, 20), Some(2));
+    // own posts are read
+    app.on_new_post(post(4, 20, 1));
+    assert_eq!(unread(&app, 20), Some(2));
+    // chat update keeps the counter
+    app.on_chat_updated(chat(20, "renamed"), 0);
+    assert_eq!(unread(&app, 20), Some(2));
+    // posts came before their chat
+    app.on_new_post(post(5, 40, 2));
+    app.on_new_post(post(6, 40, 1));
+    app.on_chat_updated(chat(40, "fourth"), 0);
+    assert_eq!(unread(&app, 40), Some(1));
+    // jumps to the chats with unread posts resetting them
+    app.on_key('u', true, false);
+    assert_eq!(app.get_sel_chat().map(|c| c.chat.id), Some(20));
+    assert_eq!(unread(&app, 20), Some(0));
+    app.on_key('u', true, false);
+    assert_eq!(app.get_sel_chat().map(|c| c.chat.id), Some(40));
+    assert_eq!(unread(&app, 40), Some(0));
+    app.on_key('u', true, false);
+    assert_eq!(app.get_sel_chat().map(|c| c.chat.id), Some(40));
+    // selecting the chat resets it
+    app.on_new_post(post(7, 10, 2));
+    assert_eq!(unread(&app, 10), Some(1));
+    app.focused = Widget::Chats;
+    for _ in 0..3 {
+        app.on_up();
+    }
+    assert_eq!(app.get_sel_chat().map(|c| c.chat.id), Some(10));
+    assert_eq!(unread(&app, 10), Some(0));
+    // the target of the post being composed is not switched
+    app.on_new_post(post(8, 30, 2));
+    app.on_key('p', false, false);
+    app.on_key('u', true, false);
+    assert_eq!(app.get_sel_chat().map(|c| c.chat.id), Some(10));
+    assert_eq!(unread(&app, 30), Some(1));
+}
+
+#[test]
+fn test_system_posts_not_unread() {
+    let (mut app, _rx_command) = test_app();
+    app.notify = Notify::Bell;
+    let notice = |id, chat_id, text: &str| proto::Post {
+        id,
+        chat_id,
+        user_id: NOT_USER_ID,
+        text: String::from(text),
+        kind: proto::PostKind::System as i32,
+        ..Default::default()
+    };
+    let chat = |id| proto::Chat {
+        id,
+        description: String::from("club"),
+        users: vec![1, 2],
+        ..Default::default()
+    };
+    let unread = |app: &App, chat_id| app.get_chat(chat_id).map(|c| c.unread);
+    app.on_chat_updated(chat(20), 0);
+    app.on_new_post(notice(1, 20, "bob entered the chat"));
+    assert_eq!(unread(&app, 20), Some(0));
+    assert!(!app.take_bell());
+    // the kind tells them apart rather than the author
+    app.on_new_post(proto::Post {
+        kind: proto::PostKind::Regular as i32,
+        ..notice(2, 20, "hi")
+    });
+    assert_eq!(unread(&app, 20), Some(1));
+    assert!(app.take_bell());
+    // nor are those came before their chat
+    app.on_new_post(notice(3, 30, "bob left the chat"));
+    app.on_chat_updated(chat(30), 0);
+    assert_eq!(unread(&app, 30), Some(0));
+    // shown among the posts anyway
+    let texts: Vec<&str> = app.chats[&30]
+        .posts
+        .iter()
+        .map(|p| p.text.as_str())
+        .collect();
+    assert_eq!(texts, vec!["bob left the chat"]);
+}
+
+#[test]
+fn test_late_delivery() {
+    let (mut app, _rx_command) = test_app();
+    const NOW: u64 = 1_600_000_000;
+    let post = |id, chat_id, user_id, created| proto::Post {
+        id,
+        chat_id,
+        user_id,
+        text: String::from("text"),
+        created,
+        ..Default::default()
+    };
+    let late = |app: &App, chat_id| app.get_chat(chat_id).map(|c| c.late_unread);
+    let delivered = |app: &App, chat_id, post_id| {
+        app.get_chat(chat_id)
+            .and_then(|c| c.delivered.get(&post_id).copied())
+    };
+    // the threshold itself is in time
+    assert!(!is_late(DEF_LATE_DELIVERY, NOW - 300, NOW));
+    assert!(is_late(DEF_LATE_DELIVERY, NOW - 301, NOW));
+    // created in the future by the clock of the server
+    assert!(!is_late(DEF_LATE_DELIVERY, NOW + 60, NOW));
+    assert!(!is_late(Duration::from_secs(0), 0, NOW));
+
+    app.on_chat_updated(
+        proto::Chat {
+            id: 20,
+            description: String::from("second"),
+            users: vec![1, 2],
+            ..Default::default()
+        },
+        0,
+    );
+    // chat 10 is selected
+    app.on_new_post_at(post(1, 10, 2, NOW - 3600), NOW);
+    assert_eq!(delivered(&app, 10, 1), Some(NOW));
+    assert_eq!(late(&app, 10), Some(false));
+    app.on_new_post_at(post(2, 20, 2, NOW - 10), NOW);
+    assert_eq!(delivered(&app, 20, 2), None);
+    assert_eq!(late(&app, 20), Some(false));
+    // own posts are read
+    app.on_new_post_at(post(3, 20, 1, NOW - 3600), NOW);
+    assert_eq!(delivered(&app, 20, 3), Some(NOW));
+    assert_eq!(late(&app, 20), Some(false));
+    app.on_new_post_at(post(4, 20, 2, NOW - 3600), NOW);
+    assert_eq!(late(&app, 20), Some(true));
+    // the chat posted to recently comes first, the selected one stays selected
+    let ids: Vec<ChatId> = app.get_chats().map(|c| c.chat.id).collect();
+    assert_eq!(ids, vec![20, 10]);
+    assert_eq!(app.get_sel_chat().map(|c| c.chat.id), Some(10));
+    // reading the chat clears the marker, the delivery time stays
+    app.on_key('u', true, false);
+    assert_eq!(app.get_sel_chat().map(|c| c.chat.id), Some(20));
+    assert_eq!(late(&app, 20), Some(false));
+    assert_eq!(delivered(&app, 20, 4), Some(NOW));
+    app.on_post_deleted(20, 4);
+    assert_eq!(delivered(&app, 20, 4), None);
+
+    // only the history newer than the received posts has been just delivered
+    app.on_chat_updated(
+        proto::Chat {
+            id: 30,
+            description: String::from("third"),
+            users: vec![1, 2],
+            ..Default::default()
+        },
+        0,
+    );
+    app.on_history_at(30, vec![post(5, 30, 2, NOW - 7200)], NOW);
+    assert_eq!(delivered(&app, 30, 5), None);
+    app.on_new_post_at(post(6, 30, 2, NOW - 5400), NOW);
+    app.on_history_at(
+        30,
+        vec![post(7, 30, 2, NOW - 9000), post(8, 30, 2, NOW - 1800)],
+        NOW,
+    );
+    assert_eq!(delivered(&app, 30, 7), None);
+    assert_eq!(delivered(&app, 30, 8), Some(NOW));
+
+    app.late_delivery = Duration::from_secs(0);
+    app.on_new_post_at(post(9, 10, 2, NOW - 3600), NOW);
+    assert_eq!(delivered(&app, 10, 9), None);
+    assert_eq!(late(&app, 10), Some(false));
+}
+
+#[test]
+fn test_degraded_chat() {
+    let (mut app, _rx_command) = test_app();
+    let chat = proto::Chat {
+        id: 20,
+        description: String::from("damaged"),
+        users: vec![1, 2],
+        ..Default::default()
+    };
+    app.on_chat_updated(chat.clone(), 0);
+    assert_eq!(app.get_chat(20).map(|c| c.degraded), Some(false));
+    app.on_chat_degraded(20, true);
+    assert_eq!(app.get_chat(20).map(|c| c.degraded), Some(true));
+    // a chat update does not reset the state, the server reports it separately
+    app.on_chat_updated(chat, 0);
+    assert_eq!(app.get_chat(20).map(|c| c.degraded), Some(true));
+    app.on_chat_degraded(20, false);
+    assert_eq!(app.get_chat(20).map(|c| c.degraded), Some(false));
+    // unknown chats are ignored
+    app.on_chat_degraded(30, true);
+    assert!(app.get_chat(30).is_none());
+}
+
+#[test]
+fn test_invitations_queue() {
+    let (mut app, rx_command) = test_app();
+    let invitation = |chat_id, from_user_id| proto::Invitation {
+        chat_id,
+        from_user_id,
+        to_user_id: 1,
+        declined: false,
+    };
+    app.on_get_invited(invitation(20, 2));
+    app.on_get_invited(invitation(30, 3));
+    // repeated invitation is not queued again
+    app.on_get_invited(invitation(20, 3));
+    assert_eq!(app.get_invitation().map(|i| i.chat_id), Some(20));
+    // plain keys do not answer
+    app.on_key('p', false, false);
+    app.on_paste("text\n");
+    assert_eq!(app.get_invitation().map(|i| i.chat_id), Some(20));
+    app.on_enter();
+    assert_eq!(app.get_invitation().map(|i| i.chat_id), Some(30));
+    app.on_esc();
+    assert!(app.get_invitation().is_none());
+    assert!(matches!(app.get_state(Widget::Confirm), State::Normal));
+    // the invitation waits for the input to be closed
+    app.on_key('p', false, false);
+    app.on_get_invited(invitation(40, 2));
+    assert!(app.get_invitation().is_none());
+    app.on_esc();
+    assert_eq!(app.get_invitation().map(|i| i.chat_id), Some(40));
+    // the chat has gone
+    app.on_chat_deleted(40);
+    assert!(app.get_invitation().is_none());
+    // own invitation has been declined
+    app.on_get_invited(proto::Invitation {
+        chat_id: 50,
+        from_user_id: 1,
+        to_user_id: 2,
+        declined: true,
+    });
+    assert!(app.get_invitation().is_none());
+    assert_eq!(
+        app.notice.as_deref(),
+        Some("other declined your invitation to chat 50")
+    );
+    let answers: Vec<_> = collect_commands(app, rx_command)
+        .into_iter()
+        .filter_map(|c| match c {
+            Command::EnterChat(chat_id) => Some((chat_id, true)),
+            Command::DeclineInvitation(i) => Some((i.chat_id, false)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(answers, vec![(20, true), (30, false)]);
+}
+
+#[test]
+fn test_new_chat_spec() {
+    let (mut app, rx_command) = test_app();
+    app.users_state.select(None);
+    app.on_user_info(proto::User {
+        id: 3,
+        short_name: String::from("third"),
+        ..Default::default()
+    });
+    app.focused = Widget::Chats;
+    app.on_key('n', true, false);
+    app.on_paste("talk -perm @unknown");
+    app.on_enter();
+    // the input remains to fix the spec
+    assert_eq!(
+        app.notice.as_deref(),
+        Some("new chat: unknown user: '@unknown'")
+    );
+    assert!(app.input.is_some());
+    for _ in 0.."unknown".len() {
+        app.on_backspace();
+    }
+    app.on_paste("third");
+    app.on_enter();
+    assert!(app.input.is_none());
+    assert!(app.notice.is_none());
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 1);
+    match &commands[0] {
+        Command::CreateChat(info) => {
+            assert_eq!(info.description, "talk");
+            assert!(!info.permanent);
+            assert!(info.auto_enter);
+            assert_eq!(info.desired_users, vec![3]);
+        }
+        _ => panic!("chat is not created"),
+    }
+}
+
+#[test]
+fn test_leave_chat() {
+    let (mut app, rx_command) = test_app();
+    app.on_chat_updated(
+        proto::Chat {
+            id: 20,
+            users: vec![1, 2],
+            ..Default::default()
+        },
+        0,
+    );
+    app.on_membership(20, proto::Membership::Member);
+    app.focused = Widget::Chats;
+    // cancelled
+    app.on_key('l', true, false);
+    assert_eq!(app.get_pending_leave(), Some(10));
+    app.on_esc();
+    assert!(app.get_pending_leave().is_none());
+    // the invitation waits for the answer on leaving
+    app.on_key('l', true, false);
+    app.on_get_invited(proto::Invitation {
+        chat_id: 30,
+        from_user_id: 2,
+        to_user_id: 1,
+        declined: false,
+    });
+    assert!(app.get_invitation().is_none());
+    app.on_enter();
+    assert!(app.get_pending_leave().is_none());
+    assert_eq!(app.get_invitation().map(|i| i.chat_id), Some(30));
+    app.on_enter();
+    // the named chat remains
+    app.on_chat_left(10);
+    assert_eq!(
+        app.get_chat(10).map(|c| c.chat.users.is_empty()),
+        Some(true)
+    );
+    // not a member anymore
+    app.on_key('l', true, false);
+    assert!(app.get_pending_leave().is_none());
+    // the dialog is gone
+    app.on_chat_left(20);
+    assert!(app.get_chat(20).is_none());
+    let commands: Vec<_> = collect_commands(app, rx_command)
+        .into_iter()
+        .filter(|c| matches!(c, Command::LeaveChat(_) | Command::EnterChat(_)))
+        .map(|c| match c {
+            Command::LeaveChat(chat_id) => (chat_id, false),
+            Command::EnterChat(chat_id) => (chat_id, true),
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_eq!(commands, vec![(10, false), (30, true)]);
+}
+
+#[test]
+fn test_filtered_posts() {
+    let (mut app, rx_command) = test_app();
+    let post = |id, chat_id, user_id, text: &str| proto::Post {
+        id,
+        chat_id,
+        user_id,
+        text: String::from(text),
+        ..Default::default()
+    };
+    let shown = |app: &App| {
+        app.get_sel_posts()
+            .iter()
+            .map(|p| p.id)
+            .collect::<Vec<PostId>>()
+    };
+    app.on_user_info(proto::User {
+        id: 7,
+        short_name: String::from("ci-bot"),
+        ..Default::default()
+    });
+    app.on_chat_updated(
+        proto::Chat {
+            id: 20,
+            description: String::from("second"),
+            users: vec![1, 7],
+            ..Default::default()
+        },
+        0,
+    );
+    send_post(&mut app, ":filter add author=ci-bot");
+    send_post(&mut app, r#":filter add text~"nightly build" chat=10"#);
+    assert_eq!(app.filters.len(), 2);
+    // a typo is reported, the rule is not added
+    send_post(&mut app, ":filter add author");
+    assert!(app.notice.is_some());
+    assert_eq!(app.filters.len(), 2);
+    app.on_new_post(post(1, 10, 7, "deployed"));
+    app.on_new_post(post(2, 10, 2, "nightly build failed"));
+    app.on_new_post(post(3, 10, 2, "hello"));
+    assert_eq!(shown(&app), vec![3]);
+    assert_eq!(app.get_sel_chat().unwrap().filtered.len(), 2);
+    // filtered posts are not unread, the text rule is scoped to chat 10
+    app.on_new_post(post(4, 20, 7, "deployed"));
+    app.on_new_post(post(5, 20, 2, "nightly build failed"));
+    assert_eq!(app.get_chat(20).map(|c| c.unread), Some(1));
+    // as well as in the history
+    app.on_history(10, 0, vec![post(6, 10, 7, "old")]);
+    assert_eq!(shown(&app), vec![3]);
+    // revealed temporarily
+    send_post(&mut app, ":filter show");
+    assert_eq!(shown(&app), vec![6, 1, 2, 3]);
+    send_post(&mut app, ":filter show");
+    assert_eq!(shown(&app), vec![3]);
+    // the posts are back once the filters are cleared
+    send_post(&mut app, ":filter clear");
+    assert!(app.filters.is_empty());
+    assert_eq!(shown(&app), vec![6, 1, 2, 3]);
+    // the commands are not posted
+    assert!(collect_commands(app, rx_command)
+        .iter()
+        .all(|c| !matches!(c, Command::Post(_))));
+}
+
+#[test]
+fn test_input_history() {
+    let (mut app, _rx_command) = test_app();
+    let input_text = |app: &App| app.input.as_ref().map(|i| i.text.clone());
+    send_post(&mut app, "first");
+    send_post(&mut app, "second");
+    send_post(&mut app, "second");
+    // the text being typed is back past the newest one
+    app.on_key('p', false, false);
+    for c in "draft".chars() {
+        app.on_key(c, false, false);
+    }
+    app.on_up();
+    assert_eq!(input_text(&app).as_deref(), Some("second"));
+    // the repeated post is kept once
+    app.on_up();
+    assert_eq!(input_text(&app).as_deref(), Some("first"));
+    app.on_up();
+    assert_eq!(input_text(&app).as_deref(), Some("first"));
+    app.on_down();
+    app.on_down();
+    assert_eq!(input_text(&app).as_deref(), Some("draft"));
+    app.on_esc();
+    // every chat has its own history
+    app.on_chat_updated(
+        proto::Chat {
+            id: 11,
+            description: String::from("another"),
+            users: vec![1],
+            ..Default::default()
+        },
+        0,
+    );
+    app.on_membership(11, proto::Membership::Member);
+    app.chats_state.select(Some(1));
+    app.on_key('p', false, false);
+    app.on_up();
+    assert_eq!(input_text(&app).as_deref(), Some(""));
+    app.on_esc();
+    // the login is never recorded
+    app.input = Some(InputMode::new_user_info());
+    app.modal = Widget::Input;
+    for c in "login, Full Name".chars() {
+        app.on_key(c, false, false);
+    }
+    app.on_enter();
+    assert!(app.post_history.get(&11).is_none());
+    assert_eq!(app.post_history.len(), 1);
+}
+
+#[test]
+fn test_chat_members_requested() {
+    let (mut app, rx_command) = test_app();
+    app.on_chat_updated(
+        proto::Chat {
+            id: 20,
+            description: String::from("second"),
+            users: vec![1, 3, 4],
+            ..Default::default()
+        },
+        0,
+    );
+    app.focused = Widget::Chats;
+    app.on_down();
+    assert_eq!(app.get_sel_chat().map(|c| c.chat.id), Some(20));
+    let member = |id, short_name: &str| proto::User {
+        id,
+        short_name: String::from(short_name),
+        ..Default::default()
+    };
+    app.on_chat_members(
+        20,
+        vec![member(1, "user"), member(3, "third"), member(4, "fourth")],
+    );
+    assert_eq!(app.get_user_name(3), "third");
+    assert!(app.get_user(4).is_some());
+    // the own user is not listed among the others
+    assert_eq!(app.users.len(), 3);
+    // the members are known now, the request is not repeated
+    app.on_up();
+    app.on_down();
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 1);
+    assert!(matches!(commands[0], Command::GetChatMembers(20)));
+}
+
+#[test]
+fn test_rename_chat() {
+    let (mut app, rx_command) = test_app();
+    app.focused = Widget::Chats;
+    app.on_key('e', true, false);
+    assert_eq!(
+        app.input.as_ref().map(|i| i.text.as_str()),
+        Some("chat -perm")
+    );
+    // the other options are not for the existing chat
+    app.input.as_mut().unwrap().text = String::from("renamed @other");
+    app.on_enter();
+    assert!(app.notice.is_some());
+    assert_eq!(app.modal, Widget::Input);
+    app.input.as_mut().unwrap().text = String::from("renamed +perm");
+    app.on_enter();
+    assert!(app.notice.is_none());
+    assert!(app.input.is_none());
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 1);
+    match &commands[0] {
+        Command::UpdateChat(update) => {
+            assert_eq!(
+                (update.chat_id, update.user_id, update.description.as_str()),
+                (10, 1, "renamed")
+            );
+            assert!(update.permanent);
+        }
+        _ => panic!("chat is not renamed"),
+    }
+}
+
+#[test]
+fn test_kick_user() {
+    let (mut app, rx_command) = test_app();
+    app.focused = Widget::Users;
+    // not the owner of the selected chat
+    app.on_key('k', false, true);
+    app.on_chat_updated(
+        proto::Chat {
+            id: 20,
+            description: String::from("owned"),
+            users: vec![1, 2],
+            owner_id: 1,
+            ..Default::default()
+        },
+        0,
+    );
+    app.on_membership(20, proto::Membership::Member);
+    app.chats_state.select(Some(1));
+    app.on_key('k', false, true);
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 1);
+    match &commands[0] {
+        Command::KickUser(member_ref) => assert_eq!(
+            (member_ref.chat_id, member_ref.user_id, member_ref.member_id),
+            (20, 1, 2)
+        ),
+        _ => panic!("user is not kicked"),
+    }
+}
+
+#[test]
+fn test_membership_affordances() {
+    let (mut app, rx_command) = test_app();
+    app.on_chat_updated(
+        proto::Chat {
+            id: 20,
+            description: String::from("owned"),
+            users: vec![3],
+            owner_id: 3,
+            ..Default::default()
+        },
+        0,
+    );
+    app.chats_state.select(Some(1));
+    // the ids in the users are not looked at
+    app.on_membership(20, proto::Membership::Invited);
+    app.focused = Widget::Chats;
+    for &(c, ctrl, alt) in &[('p', false, false), ('l', true, false), ('e', true, false)] {
+        app.on_key(c, ctrl, alt);
+        assert_eq!(app.modal, Widget::App);
+    }
+    app.focused = Widget::Users;
+    app.on_key('i', false, true);
+    // accepted, the server tells the chat has been entered
+    app.on_get_invited(proto::Invitation {
+        chat_id: 20,
+        from_user_id: 3,
+        to_user_id: 1,
+        declined: false,
+    });
+    app.on_enter();
+    app.on_membership(20, proto::Membership::Member);
+    app.on_get_invited(proto::Invitation {
+        chat_id: 20,
+        from_user_id: 3,
+        to_user_id: 1,
+        declined: false,
+    });
+    assert!(app.get_invitation().is_none());
+    app.on_key('i', false, true);
+    app.focused = Widget::Chats;
+    app.on_key('l', true, false);
+    assert_eq!(app.get_pending_leave(), Some(20));
+    app.on_esc();
+    // kicked
+    app.on_membership(20, proto::Membership::None);
+    app.on_key('l', true, false);
+    assert!(app.get_pending_leave().is_none());
+    app.on_key('p', false, false);
+    assert!(app.input.is_none());
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 2);
+    assert!(matches!(commands[0], Command::EnterChat(20)));
+    assert!(matches!(
+        &commands[1],
+        Command::Invite(proto::Invitation {
+            chat_id: 20,
+            to_user_id: 2,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_post_author() {
+    let (mut app, _rx_command) = test_app();
+    let post = |user_id, author_name: &str| proto::Post {
+        user_id,
+        author_name: String::from(author_name),
+        ..Default::default()
+    };
+    // the posts stored before the stamping are named by the live lookup
+    assert_eq!(app.get_post_author(&post(2, "")), "other");
+    assert_eq!(app.get_post_author(&post(3, "")), "3");
+    assert_eq!(app.get_post_author(&post(1, "user")), "me");
+    assert_eq!(app.get_post_author(&post(2, "other")), "other");
+    // renamed since
+    if let Some(user) = app.users.iter_mut().find(|u| u.id == 2) {
+        user.short_name = String::from("renamed");
+    }
+    assert_eq!(
+        app.get_post_author(&post(2, "other")),
+        "renamed (formerly other)"
+    );
+    // the author is not known yet
+    assert_eq!(app.get_post_author(&post(3, "third")), "third");
+}
+
+#[test]
+fn test_typing() {
+    let (mut app, mut rx_command) = test_app();
+    app.on_key('p', false, false);
+    for c in "abc".chars() {
+        app.on_key(c, false, false);
+    }
+    // told once for the keys typed in a row, never for the chat name
+    app.on_esc();
+    app.on_key('n', true, false);
+    app.on_key('x', false, false);
+    let chat = app.get_chat(10).unwrap();
+    assert!(app.get_typing_text(chat).is_none());
+    app.on_typing(10, 2, true);
+    app.on_typing(10, 1, true);
+    app.on_typing(99, 2, true);
+    let chat = app.get_chat(10).unwrap();
+    assert_eq!(
+        app.get_typing_text(chat).as_deref(),
+        Some("other is typing…")
+    );
+    app.on_new_post(proto::Post {
+        id: 100,
+        chat_id: 10,
+        user_id: 2,
+        text: String::from("done"),
+        ..Default::default()
+    });
+    let chat = app.get_chat(10).unwrap();
+    assert!(app.get_typing_text(chat).is_none());
+    app.on_typing(10, 2, true);
+    app.on_disconnected();
+    assert!(app.get_chat(10).unwrap().typing.is_empty());
+    drop(app);
+    let mut commands = Vec::new();
+    while let Some(command) = rx_command.blocking_recv() {
+        commands.push(command);
+    }
+    assert_eq!(commands.len(), 1);
+    assert!(matches!(commands[0], Command::Typing(10)));
+}
+
+#[test]
+fn test_scroll_history() {
+    let (mut app, rx_command) = test_app();
+    let post = |id| proto::Post {
+        id,
+        chat_id: 20,
+        user_id: 2,
+        text: format!("post {}", id),
+        ..Default::default()
+    };
+    let sel = |app: &App| app.get_sel_chat().unwrap().posts_state.selected();
+    // 70 elder posts are on the server, the newer ones come by the stream
+    app.on_chat_updated(
+        proto::Chat {
+            id: 20,
+            description: String::from("long"),
+            users: vec![1, 2],
+            ..Default::default()
+        },
+        70,
+    );
+    app.on_membership(20, proto::Membership::Member);
+    for id in 71..=130 {
+        app.on_new_post(post(id));
+    }
+    app.chats_state.select(Some(1));
+    app.on_chat_switched();
+    // the chat starts at the newest post
+    assert_eq!(sel(&app), Some(59));
+    app.focused = Widget::Posts;
+    app.on_page_up();
+    assert_eq!(sel(&app), Some(49));
+    // above the first post the elder ones are fetched, once at a time
+    app.on_home();
+    assert_eq!(sel(&app), Some(0));
+    app.on_up();
+    app.on_page_up();
+    app.on_history(20, 20, (21..=70).map(post).collect());
+    assert_eq!(sel(&app), Some(50));
+    assert_eq!(app.get_sel_posts()[50].id, 71);
+    app.on_home();
+    app.on_history(20, 0, (1..=20).map(post).collect());
+    assert_eq!(sel(&app), Some(20));
+    // nothing elder is left
+    app.on_home();
+    assert_eq!(sel(&app), Some(0));
+    // the newest post selected follows the new ones
+    app.on_end();
+    assert_eq!(sel(&app), Some(129));
+    app.on_new_post(post(131));
+    assert_eq!(sel(&app), Some(130));
+    // scrolled up it does not
+    app.on_page_up();
+    assert_eq!(sel(&app), Some(120));
+    app.on_new_post(post(132));
+    assert_eq!(sel(&app), Some(120));
+    app.on_page_down();
+    app.on_page_down();
+    assert_eq!(sel(&app), Some(131));
+    let commands = collect_commands(app, rx_command);
+    let pages: Vec<(u64, u64)> = commands
+        .iter()
+        .map(|command| match command {
+            Command::GetHistory(params) => (params.idx_from, params.count),
+            _ => panic!("unexpected command"),
+        })
+        .collect();
+    assert_eq!(pages, vec![(20, 50), (0, 20)]);
+}
+
+#[test]
+fn test_read_position() {
+    let (mut app, mut rx_command) = test_app();
+    app.on_chat_updated(
+        proto::Chat {
+            id: 20,
+            users: vec![1, 2],
+            ..Default::default()
+        },
+        0,
+    );
+    app.on_membership(20, proto::Membership::Member);
+    app.on_read_position(20, 5, 3);
+    app.on_read_position(99, 5, 3);
+    assert_eq!(app.get_chat(20).unwrap().unread, 3);
+    assert_eq!(app.get_chat(20).unwrap().last_read, 5);
+    app.on_new_post(proto::Post {
+        id: 6,
+        chat_id: 20,
+        user_id: 2,
+        text: String::from("unread"),
+        ..Default::default()
+    });
+    // the local count is kept over the one told on reconnection
+    app.on_read_position(20, 5, 3);
+    assert_eq!(app.get_chat(20).unwrap().unread, 4);
+    app.chats_state.select(Some(1));
+    app.on_chat_switched();
+    app.on_chat_switched();
+    app.on_new_post(proto::Post {
+        id: 7,
+        chat_id: 20,
+        user_id: 2,
+        text: String::from("seen"),
+        ..Default::default()
+    });
+    app.apply_action(Action::Exit);
+    drop(app);
+    let mut commands = Vec::new();
+    while let Some(command) = rx_command.blocking_recv() {
+        commands.push(command);
+    }
+    assert_eq!(commands.len(), 3);
+    assert!(matches!(commands[0], Command::MarkRead(20, 6)));
+    assert!(matches!(commands[1], Command::MarkRead(20, 7)));
+    assert!(matches!(commands[2], Command::Exit));
+}
+
+#[test]
+fn test_reactions() {
+    let (mut app, rx_command) = test_app();
+    let post = |reactions| proto::Post {
+        id: 100,
+        chat_id: 10,
+        user_id: 2,
+        text: String::from("vote"),
+        reactions,
+        ..Default::default()
+    };
+    app.on_new_post(post(Vec::new()));
+    assert!(app.get_reactions_text(&app.get_sel_posts()[0]).is_none());
+    // nothing to react to without the selected post
+    app.focused = Widget::Posts;
+    app.on_key('r', false, false);
+    assert!(app.input.is_none());
+    app.get_sel_chat_mut().unwrap().posts_state.select(Some(0));
+    app.on_key('r', false, false);
+    for c in ":+1:".chars() {
+        app.on_key(c, false, false);
+    }
+    app.on_enter();
+    assert!(app.input.is_none());
+    // the repeated post brings the reactions, it is neither added nor unread
+    app.on_new_post(post(vec![
+        proto::PostReaction {
+            emoji: String::from("👍"),
+            user_ids: vec![1, 2],
+        },
+        proto::PostReaction {
+            emoji: String::from("🎉"),
+            user_ids: vec![2],
+        },
+    ]));
+    let posts = app.get_sel_posts();
+    assert_eq!(posts.len(), 1);
+    assert_eq!(
+        app.get_reactions_text(&posts[0]).as_deref(),
+        Some("👍 2  🎉 1")
+    );
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 1);
+    assert!(matches!(
+        &commands[0],
+        Command::React(proto::Reaction { chat_id: 10, post_id: 100, emoji, .. }) if emoji == "👍"
+    ));
+}
+
+#[test]
+fn test_replies() {
+    let (mut app, rx_command) = test_app();
+    let post = |id, reply_to_post_id, text: &str| proto::Post {
+        id,
+        chat_id: 10,
+        user_id: 2,
+        text: String::from(text),
+        reply_to_post_id,
+        ..Default::default()
+    };
+    let long_text = "x".repeat(REPLY_PREVIEW_LEN + 10);
+    app.on_new_post(post(100, NOT_POST_ID, &long_text));
+    app.on_new_post(post(101, 100, "short"));
+    app.on_new_post(post(102, 50, "to the older one"));
+    let posts = app.get_sel_posts();
+    assert!(app.get_reply_preview(&posts[0]).is_none());
+    assert_eq!(
+        app.get_reply_preview(&posts[1]),
+        Some(format!("↳ other: {}…", "x".repeat(REPLY_PREVIEW_LEN)))
+    );
+    assert_eq!(
+        app.get_reply_preview(&posts[2]).as_deref(),
+        Some("↳ the post is not loaded")
+    );
+    // resolved once the history brings it
+    app.on_history(10, 0, vec![post(50, NOT_POST_ID, "old\nsecond line")]);
+    let posts = app.get_sel_posts();
+    assert_eq!(
+        app.get_reply_preview(&posts[3]).as_deref(),
+        Some("↳ other: old…")
+    );
+    app.focused = Widget::Posts;
+    app.get_sel_chat_mut().unwrap().posts_state.select(Some(1));
+    app.on_key('r', true, false);
+    assert_eq!(app.input.as_ref().unwrap().title, "Reply to other");
+    for c in "agreed".chars() {
+        app.on_key(c, false, false);
+    }
+    app.on_enter();
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 1);
+    assert!(matches!(
+        &commands[0],
+        Command::Post(proto::Post { reply_to_post_id: 100, text, .. }) if text == "agreed"
+    ));
+}
+
+#[test]
+fn test_forward() {
+    let (mut app, rx_command) = test_app();
+    for (chat_id, membership) in [
+        (20, proto::Membership::Member),
+        (30, proto::Membership::None),
+    ]
+    .iter()
+    {
+        app.on_chat_updated(
+            proto::Chat {
+                id: *chat_id,
+                users: vec![1, 2],
+                ..Default::default()
+            },
+            0,
+        );
+        app.on_membership(*chat_id, *membership);
+    }
+    assert_eq!(app.get_chat_title(app.get_chat(20).unwrap()), "other");
+    app.on_new_post(proto::Post {
+        id: 100,
+        chat_id: 10,
+        user_id: 2,
+        text: String::from("news"),
+        ..Default::default()
+    });
+    app.focused = Widget::Posts;
+    app.on_key('f', false, false);
+    assert!(app.forwarding.is_none());
+    app.get_sel_chat_mut().unwrap().posts_state.select(Some(0));
+    app.on_key('f', false, false);
+    // neither the chat of the post nor the one the user is not a member of
+    assert_eq!(app.forwarding.as_ref().unwrap().targets, vec![20]);
+    assert!(matches!(app.get_state(Widget::Picker), State::Modal));
+    app.on_paste("\n");
+    app.on_down();
+    assert_eq!(app.forwarding.as_ref().unwrap().state.selected(), Some(0));
+    app.on_enter();
+    assert!(app.forwarding.is_none());
+    assert!(matches!(app.get_state(Widget::Picker), State::Normal));
+    // cancelled
+    app.on_key('f', false, false);
+    app.on_esc();
+    assert!(app.forwarding.is_none());
+    let forwarded = proto::Post {
+        forwarded_from: Some(proto::ForwardedFrom {
+            user_id: 2,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    assert_eq!(
+        app.get_forwarded_text(&forwarded).as_deref(),
+        Some("forwarded from other")
+    );
+    let commands = collect_commands(app, rx_command);
+    assert_eq!(commands.len(), 1);
+    assert!(matches!(
+        &commands[0],
+        Command::ForwardPost { post, target_chat: 20 } if post.id == 100
+    ));
+}
+
+#[test]
+fn test_input_editing() {
+    let mut input = InputMode::new_post();
+    input.insert("héllo wörld");
+    assert_eq!(input.cursor_column(), 11);
+    // back over the multibyte chars
+    for _ in 0..4 {
+        input.move_left();
+    }
+    input.insert("🎉");
+    assert_eq!(input.text, "héllo w🎉örld");
+    assert_eq!(input.cursor_column(), 9);
+    assert!(input.delete());
+    assert_eq!(input.text, "héllo w🎉rld");
+    assert!(input.backspace());
+    assert!(input.backspace());
+    assert_eq!(input.text, "héllo rld");
+    input.move_home();
+    assert!(!input.backspace());
+    assert!(input.delete());
+    input.move_right();
+    input.insert("E");
+    assert_eq!(input.text, "éEllo rld");
+    input.move_end();
+    assert!(!input.delete());
+    input.insert("  ");
+    // the trailing spaces go with the word
+    assert!(input.delete_word());
+    assert_eq!(input.text, "éEllo ");
+    assert!(input.delete_word());
+    assert_eq!(input.text, "");
+    assert!(!input.delete_word());
+    // the line is cleared up to the cursor only
+    input.insert("first\nsecond");
+    input.move_left();
+    assert!(input.clear_line());
+    assert_eq!(input.text, "first\nd");
+    assert_eq!(input.cursor_column(), 0);
+    input.move_left();
+    input.move_home();
+    assert!(!input.clear_line());
+    input.move_end();
+    assert!(input.clear_line());
+    assert_eq!(input.text, "\nd");
+    // the recalled text puts the cursor past its end
+    input.set_text(String::from("recalled"));
+    input.insert("!");
+    assert_eq!(input.text, "recalled!");
+}
+
+#[test]
+fn test_input_cursor_keys() {
+    let (mut app, _rx_command) = test_app();
+    app.on_key('p', false, false);
+    app.on_paste("one two");
+    app.on_left();
+    app.on_left();
+    app.on_key('X', false, false);
+    assert_eq!(app.input.as_ref().unwrap().text, "one tXwo");
+    app.on_home();
+    app.on_delete();
+    app.on_end();
+    app.on_backspace();
+    assert_eq!(app.input.as_ref().unwrap().text, "ne tXw");
+    app.on_key('w', true, false);
+    assert_eq!(app.input.as_ref().unwrap().text, "ne ");
+    app.on_key('u', true, false);
+    assert_eq!(app.input.as_ref().unwrap().text, "");
+    // the prefilled text is edited at its end
+    let mut input = InputMode::edit_profile(&proto::User {
+        short_name: String::from("login"),
+        name: String::from("Имя"),
+        ..Default::default()
+    });
+    assert!(input.backspace());
+    assert_eq!(input.text, "login, Им");
+    assert_eq!(input.cursor_column(), 9);
+}
+
+#[test]
+fn test_paste_filtered() {
+    let (mut app, _rx_command) = test_app();
+    app.on_key('p', false, false);
+    app.on_paste("one\x1b[2J\ttwo\nthree");
+    app.on_left();
+    app.on_paste("\x07!");
+    assert_eq!(app.input.as_ref().unwrap().text, "one[2Jtwo\nthre!e");
+    // the single line inputs get no newlines
+    app.input = Some(InputMode::new_chat());
+    app.on_paste("new\nchat\r\n");
+    assert_eq!(app.input.as_ref().unwrap().text, "new chat ");
+    // the input is cut to its max length on a char boundary
+    let mut input = InputMode::new_post();
+    input.insert(&"a".repeat(MAX_INPUT_LEN - 1));
+    assert_eq!(input.insert("éa"), MAX_INPUT_LEN - 1..MAX_INPUT_LEN - 1);
+    assert_eq!(input.insert("ab"), MAX_INPUT_LEN - 1..MAX_INPUT_LEN);
+    assert_eq!(input.text.len(), MAX_INPUT_LEN);
+}
+
+#[test]
+fn test_help() {
+    use super::keys::{named_key, NAMED_KEYS};
+    use crossterm::event::KeyCode;
+
+    let (mut app, _rx_command) = test_app();
+    let lines = app.get_help_lines();
+    assert!(lines.iter().any(|l| l.contains("F1")));
+    assert!(lines
+        .iter()
+        .any(|l| l.contains('?') && l.contains(Action::Help.description())));
+    app.on_key('?', false, false);
+    assert!(matches!(app.get_state(Widget::Help), State::Modal));
+    // every named key is handled over the help, only F1 and Esc close it
+    for key in NAMED_KEYS.iter() {
+        if key.code != KeyCode::F(1) && key.code != KeyCode::Esc {
+            (key.handler)(&mut app);
+        }
+    }
+    app.on_key('p', false, false);
+    app.on_paste("text\n");
+    assert!(matches!(app.get_state(Widget::Help), State::Modal));
+    assert!(app.input.is_none());
+    app.help_scroll = 0;
+    app.on_down();
+    app.on_down();
+    app.on_up();
+    assert_eq!(app.help_scroll, 1);
+    (named_key(KeyCode::F(1)).unwrap().handler)(&mut app);
+    assert!(matches!(app.get_state(Widget::Help), State::Normal));
+    (named_key(KeyCode::F(1)).unwrap().handler)(&mut app);
+    assert_eq!(app.help_scroll, 0);
+    (named_key(KeyCode::Esc).unwrap().handler)(&mut app);
+    assert!(matches!(app.get_state(Widget::Help), State::Normal));
+    // typed as is while typing
+    app.on_key('p', false, false);
+    app.on_key('?', false, false);
+    assert_eq!(app.input.as_ref().unwrap().text, "?");
+    app.on_help();
+    assert!(matches!(app.get_state(Widget::Input), State::Modal));
+}
+
+#[test]
+fn test_mouse() {
+    use super::areas::ListArea;
+    use crossterm::event::KeyModifiers;
+    use tui::layout::Rect;
+
+    let mouse = |kind, column, row| MouseEvent {
+        kind,
+        column,
+        row,
+        modifiers: KeyModifiers::NONE,
+    };
+    let click = |column, row| mouse(MouseEventKind::Down(MouseButton::Left), column, row);
+    let (mut app, _rx_command) = test_app();
+    for id in 100..102 {
+        app.on_new_post(proto::Post {
+            id,
+            chat_id: 10,
+            user_id: 1,
+            ..Default::default()
+        });
+    }
+    // the third post is the pending one
+    app.areas = Areas {
+        users: ListArea::new(Rect::new(1, 4, 10, 5), vec![1; app.users.len()], None, 0),
+        chats: ListArea::new(Rect::new(13, 4, 10, 5), vec![2], None, 0),
+        posts: ListArea::new(Rect::new(25, 4, 40, 5), vec![2, 2, 1], None, 0),
+        log: Rect::new(0, 12, 70, 5),
+        input: None,
+    };
+    app.focused = Widget::Users;
+    app.on_mouse(click(30, 6));
+    assert_eq!(app.focused, Widget::Posts);
+    assert_eq!(app.get_sel_chat().unwrap().posts_state.selected(), Some(1));
+    app.on_mouse(click(30, 8));
+    assert_eq!(app.get_sel_chat().unwrap().posts_state.selected(), Some(1));
+    app.on_mouse(click(30, 4));
+    assert_eq!(app.get_sel_chat().unwrap().posts_state.selected(), Some(0));
+    // the wheel scrolls the focused list, the log under the cursor
+    app.on_mouse(mouse(MouseEventKind::ScrollDown, 30, 14));
+    assert_eq!(app.get_sel_chat().unwrap().posts_state.selected(), Some(0));
+    app.on_mouse(mouse(MouseEventKind::ScrollDown, 2, 5));
+    assert_eq!(app.get_sel_chat().unwrap().posts_state.selected(), Some(1));
+    // click to focus, the border and outside are not the panes
+    app.on_mouse(click(15, 5));
+    assert_eq!(app.focused, Widget::Chats);
+    assert_eq!(app.chats_state.selected(), Some(0));
+    app.on_mouse(click(12, 5));
+    assert_eq!(app.focused, Widget::Chats);
+    app.on_mouse(click(2, 4));
+    assert_eq!(app.focused, Widget::Users);
+    assert_eq!(app.users_state.selected(), Some(0));
+    // the click in the input moves the cursor
+    app.on_key('p', false, false);
+    app.on_paste("héllo");
+    app.areas.input = Some(Rect::new(5, 10, 30, 1));
+    app.on_mouse(click(7, 10));
+    app.on_key('X', false, false);
+    assert_eq!(app.input.as_ref().unwrap().text, "héXllo");
+    app.on_mouse(click(40, 10));
+    app.on_mouse(click(30, 10));
+    app.on_key('!', false, false);
+    assert_eq!(app.input.as_ref().unwrap().text, "héXllo!");
+    // the panes under the popup are not clicked
+    app.on_mouse(click(30, 4));
+    assert_eq!(app.focused, Widget::Users);
+}
+
+#[test]
+fn test_notify() {
+    let (mut app, _rx_command) = test_app();
+    app.on_chat_updated(
+        proto::Chat {
+            id: 20,
+            description: String::from("second"),
+            users: vec![1, 2],
+            ..Default::default()
+        },
+        0,
+    );
+    app.on_membership(20, proto::Membership::Member);
+    let post = |id, chat_id, user_id| proto::Post {
+        id,
+        chat_id,
+        user_id,
+        created: id,
+        ..Default::default()
+    };
+    let select = |app: &mut App, chat_id| {
+        let idx = app.get_chats().position(|c| c.chat.id == chat_id);
+        app.chats_state.select(idx);
+        app.on_chat_switched();
+    };
+    // off by default
+    app.on_new_post(post(100, 20, 2));
+    assert!(!app.take_bell());
+    assert_eq!(app.get_title(), app.title);
+    app.notify = Notify::Bell;
+    app.on_new_post(post(101, 20, 2));
+    assert!(app.take_bell());
+    assert!(!app.take_bell());
+    assert_eq!(
+        app.get_title(),
+        format!("{} (1 chat with new posts)", app.title)
+    );
+    // neither own posts nor those of the selected chat
+    app.on_new_post(post(102, 20, 1));
+    app.on_new_post(post(103, 10, 2));
+    assert!(!app.take_bell());
+    // the marker is cleared once the chat is selected
+    select(&mut app, 20);
+    assert_eq!(app.get_title(), app.title);
+    // the muted chat is told of by nothing
+    app.focused = Widget::Chats;
+    app.on_key('m', false, false);
+    assert!(app.is_muted(20));
+    select(&mut app, 10);
+    app.on_new_post(post(104, 20, 2));
+    assert!(!app.take_bell());
+    assert_eq!(app.get_title(), app.title);
+    select(&mut app, 20);
+    app.on_key('m', false, false);
+    assert!(!app.is_muted(20));
+    // the title is marked only
+    select(&mut app, 10);
+    app.notify = Notify::Title;
+    app.on_new_post(post(105, 20, 2));
+    assert!(!app.take_bell());
+    assert_ne!(app.get_title(), app.title);
+    assert_eq!("Bell".parse::<Notify>(), Ok(Notify::Bell));
+    assert!("loud".parse::<Notify>().is_err());
+}
+
+#[test]
+fn test_mute_kept_by_server() {
+    let (mut app, rx_command) = test_app();
+    // muted on another device or in the former run
+    app.on_muted(10, true);
+    assert!(app.is_muted(10));
+    app.focused = Widget::Chats;
+    app.on_key('m', false, false);
+    assert!(!app.is_muted(10));
+    app.on_key('m', false, false);
+    assert!(app.is_muted(10));
+    app.on_muted(10, false);
+    assert!(!app.is_muted(10));
+    let commands = collect_commands(app, rx_command);
+    assert!(matches!(
+        commands[..],
+        [Command::MuteChat(10, false), Command::MuteChat(10, true)]
+    ));
+}
+
+#[test]
+fn test_users_order() {
+    let user = |id, short_name: &str| proto::User {
+        id,
+        short_name: String::from(short_name),
+        ..Default::default()
+    };
+    let online = [3];
+    // the online first, the names compared regardless of the case
+    assert_eq!(
+        user_order(&user(3, "zed"), &user(2, "amy"), &online),
+        Ordering::Less
+    );
+    assert_eq!(
+        user_order(&user(2, "Bob"), &user(4, "alice"), &online),
+        Ordering::Greater
+    );
+    // the same names go by the ids
+    assert_eq!(
+        user_order(&user(2, "amy"), &user(4, "amy"), &online),
+        Ordering::Less
+    );
+    // the selection goes with its item
+    assert_eq!(pinned_index(vec![5, 7, 9], Some(9)), Some(2));
+    assert_eq!(pinned_index(vec![5, 7], Some(9)), None);
+    assert_eq!(pinned_index(vec![5, 7], None), None);
+}
+
+#[test]
+fn test_users_resorted() {
+    let (mut app, _rx_command) = test_app();
+    let names = |app: &App| {
+        app.users
+            .iter()
+            .map(|u| u.short_name.as_str())
+            .collect::<Vec<&str>>()
+    };
+    let sel_user_id = |app: &App| app.get_sel_user().map(|u| u.id);
+    app.on_user_info(proto::User {
+        id: 3,
+        short_name: String::from("Alice"),
+        ..Default::default()
+    });
+    assert_eq!(names(&app), vec!["Alice", "other"]);
+    assert_eq!(sel_user_id(&app), Some(2));
+    // the online move up, the selection follows the user
+    app.on_user_entered(2);
+    assert_eq!(names(&app), vec!["other", "Alice"]);
+    assert_eq!(sel_user_id(&app), Some(2));
+    assert!(app.is_online(2));
+    // entered twice, gone once
+    app.on_user_entered(2);
+    app.on_user_gone(2);
+    assert!(!app.is_online(2));
+    assert_eq!(names(&app), vec!["Alice", "other"]);
+    assert_eq!(sel_user_id(&app), Some(2));
+    app.on_user_entered(3);
+    app.on_user_entered(2);
+    assert_eq!(names(&app), vec!["Alice", "other"]);
+    // nobody is online after the disconnection
+    app.on_user_gone(3);
+    app.on_disconnected();
+    assert_eq!(names(&app), vec!["Alice", "other"]);
+    assert_eq!(sel_user_id(&app), Some(2));
+}
+
+#[test]
+fn test_list_filter() {
+    let (mut app, _rx_command) = test_app();
+    let user = |id, short_name: &str, name: &str| proto::User {
+        id,
+        short_name: String::from(short_name),
+        name: String::from(name),
+        ..Default::default()
+    };
+    assert!(user_matches(&user(3, "Alice", "Alice Smith"), "smi"));
+    assert!(user_matches(&user(3, "Alice", ""), "aLI"));
+    assert!(user_matches(&user(3, "Alice", ""), ""));
+    assert!(!user_matches(&user(3, "Alice", ""), "bob"));
+    app.on_user_info(user(3, "Alice", "Alice Smith"));
+    app.on_user_info(user(4, "bob", "Robert"));
+    app.on_chat_updated(
+        proto::Chat {
+            id: 20,
+            description: String::from("team"),
+            users: vec![1, 4],
+            ..Default::default()
+        },
+        0,
+    );
+    let sel_user_id = |app: &App| app.get_sel_user().map(|u| u.id);
+    let sel_chat_id = |app: &App| app.get_sel_chat().map(|c| c.chat.id);
+    // no filter, the index goes over the whole list
+    assert_eq!(app.get_users().count(), 3);
+    app.users_state.select(Some(1));
+    assert_eq!(sel_user_id(&app), Some(4));
+    // the typed text narrows the list, the selected user is still shown
+    app.focused = Widget::Users;
+    app.on_key('/', false, false);
+    assert!(matches!(app.get_state(Widget::Filter), State::Modal));
+    for c in "OB".chars() {
+        app.on_key(c, false, false);
+    }
+    assert_eq!(app.users_filter, "OB");
+    assert_eq!(app.get_users().map(|u| u.id).collect::<Vec<_>>(), vec![4]);
+    assert_eq!(app.users_state.selected(), Some(0));
+    assert_eq!(sel_user_id(&app), Some(4));
+    // the selection moves within the shown ones only
+    app.on_down();
+    assert_eq!(sel_user_id(&app), Some(4));
+    // the user filtered out gives the selection to the first match
+    app.on_backspace();
+    app.on_backspace();
+    app.on_key('h', false, false);
+    assert_eq!(
+        app.get_users().map(|u| u.id).collect::<Vec<_>>(),
+        vec![3, 2]
+    );
+    assert_eq!(sel_user_id(&app), Some(3));
+    // enter keeps the filter, esc clears it and the selection stays
+    app.on_enter();
+    assert!(matches!(app.get_state(Widget::Filter), State::Normal));
+    assert_eq!(app.users_filter, "h");
+    app.on_down();
+    assert_eq!(sel_user_id(&app), Some(2));
+    app.on_esc();
+    assert!(app.users_filter.is_empty());
+    assert_eq!(app.users_state.selected(), Some(2));
+    assert_eq!(sel_user_id(&app), Some(2));
+    // the chats match by the description or by the names of the members
+    app.focused = Widget::Chats;
+    assert_eq!(sel_chat_id(&app), Some(10));
+    app.on_key('/', false, false);
+    app.on_key('r', false, false);
+    app.on_key('o', false, false);
+    assert_eq!(
+        app.get_chats().map(|c| c.chat.id).collect::<Vec<_>>(),
+        vec![20]
+    );
+    assert_eq!(app.chats_state.selected(), Some(0));
+    assert_eq!(sel_chat_id(&app), Some(20));
+    app.on_esc();
+    assert!(app.chats_filter.is_empty());
+    assert_eq!(sel_chat_id(&app), Some(20));
+    // the own names match no chat
+    app.set_list_filter(Widget::Chats, String::from("user"));
+    assert_eq!(app.get_chats().count(), 0);
+    assert_eq!(sel_chat_id(&app), None);
+}
+
+#[test]
+fn test_search() {
+    let (mut app, rx_command) = test_app();
+    let post = |id, chat_id, text: &str| proto::Post {
+        id,
+        chat_id,
+        user_id: 2,
+        text: String::from(text),
+        ..Default::default()
+    };
+    let sel = |app: &App| app.get_sel_chat().unwrap().posts_state.selected();
+    for (id, text) in [
+        (1, "deployed"),
+        (2, "hello"),
+        (3, "Redeploy please"),
+        (4, "bye"),
+    ]
+    .iter()
+    {
+        app.on_new_post(post(*id, 10, text));
+    }
+    app.focused = Widget::Posts;
+    app.on_key('f', true, false);
+    for c in "DEPLOY".chars() {
+        app.on_key(c, false, false);
+    }
+    app.on_enter();
+    assert!(app.input.is_none());
+    assert_eq!(app.search.as_ref().unwrap().matches, vec![0, 2]);
+    // the newest match first, n goes up and N down, both wrap
+    assert_eq!(sel(&app), Some(2));
+    app.on_key('n', false, false);
+    assert_eq!(sel(&app), Some(0));
+    app.on_key('n', false, false);
+    assert_eq!(sel(&app), Some(2));
+    app.on_key('N', false, false);
+    assert_eq!(sel(&app), Some(0));
+    // the new posts are searched too
+    app.on_new_post(post(5, 10, "deploy again"));
+    assert_eq!(app.search.as_ref().unwrap().matches, vec![0, 2, 4]);
+    app.on_key('N', false, false);
+    assert_eq!(sel(&app), Some(2));
+    // esc cancels the search, the selection stays
+    app.on_esc();
+    assert!(app.search.is_none());
+    assert_eq!(sel(&app), Some(2));
+    // 120 elder posts are on the server, the first page is fetched with the chat
+    app.on_chat_updated(
+        proto::Chat {
+            id: 20,
+            description: String::from("long"),
+            users: vec![1, 2],
+            ..Default::default()
+        },
+        120,
+    );
+    app.on_membership(20, proto::Membership::Member);
+    for id in 121..=130 {
+        app.on_new_post(post(id, 20, "nothing"));
+    }
+    app.chats_state.select(Some(1));
+    app.on_chat_switched();
+    assert_eq!(app.get_sel_chat().unwrap().chat.id, 20);
+    app.on_key('f', true, false);
+    for c in "needle".chars() {
+        app.on_key(c, false, false);
+    }
+    app.on_enter();
+    assert!(app.notice.is_some());
+    // no match in the page, the next one is fetched, the match there is selected
+    app.on_history(20, 70, (71..=120).map(|id| post(id, 20, "hay")).collect());
+    assert!(app.search.as_ref().unwrap().matches.is_empty());
+    app.on_history(
+        20,
+        20,
+        (21..=70)
+            .map(|id| post(id, 20, if id == 42 { "a Needle" } else { "hay" }))
+            .collect(),
+    );
+    assert_eq!(app.search.as_ref().unwrap().matches, vec![21]);
+    assert_eq!(app.get_sel_posts()[sel(&app).unwrap()].id, 42);
+    assert!(app.notice.is_none());
+    // the pages run out
+    app.on_key('f', true, false);
+    for c in "absent".chars() {
+        app.on_key(c, false, false);
+    }
+    app.on_enter();
+    app.on_history(20, 0, (1..=20).map(|id| post(id, 20, "hay")).collect());
+    assert!(app.notice.as_ref().unwrap().contains("nothing matches"));
+    // switching the chat ends the search
+    app.chats_state.select(Some(0));
+    app.on_chat_switched();
+    assert!(app.search.is_none());
+    let pages: Vec<(u64, u64)> = collect_commands(app, rx_command)
+        .iter()
+        .filter_map(|command| match command {
+            Command::GetHistory(params) => Some((params.idx_from, params.count)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(pages, vec![(70, 50), (20, 50), (0, 20)]);
+}
+
+#[test]
+fn test_search_all() {
+    let (mut app, rx_command) = test_app();
+    let post = |id, chat_id, text: &str| proto::Post {
+        id,
+        chat_id,
+        user_id: 2,
+        text: String::from(text),
+        ..Default::default()
+    };
+    app.on_chat_updated(
+        proto::Chat {
+            id: 20,
+            description: String::from("ops"),
+            users: vec![1, 2],
+            ..Default::default()
+        },
+        0,
+    );
+    app.on_membership(20, proto::Membership::Member);
+    app.on_new_post(post(200, 20, "Deploy here"));
+    app.on_new_post(post(201, 20, "bye"));
+    app.on_key('f', false, true);
+    for c in "deploy".chars() {
+        app.on_key(c, false, false);
+    }
+    app.on_enter();
+    assert!(app.input.is_none());
+    assert_eq!(app.modal, Widget::App);
+    app.on_search_results(String::from("deploy"), Vec::new());
+    assert!(app.notice.as_ref().unwrap().contains("nothing matches"));
+    assert_eq!(app.modal, Widget::App);
+    let found = vec![post(200, 20, "Deploy here"), post(5, 10, "old deploy")];
+    app.on_search_results(String::from("deploy"), found.clone());
+    assert_eq!(app.modal, Widget::Results);
+    assert_eq!(app.found.as_ref().unwrap().state.selected(), Some(0));
+    // the selection stays on the list, the plain keys are not typed anywhere
+    app.on_down();
+    app.on_down();
+    assert_eq!(app.found.as_ref().unwrap().state.selected(), Some(1));
+    app.on_up();
+    app.on_key('x', false, false);
+    assert_eq!(app.modal, Widget::Results);
+    // the chat of the post is selected along with the post
+    app.on_enter();
+    assert!(app.found.is_none());
+    assert_eq!(app.modal, Widget::App);
+    assert_eq!(app.focused, Widget::Posts);
+    assert_eq!(app.get_sel_chat().unwrap().chat.id, 20);
+    assert_eq!(app.get_sel_post().unwrap().id, 200);
+    assert_eq!(app.search.as_ref().unwrap().query, "deploy");
+    // esc dismisses the results
+    app.on_search_results(String::from("deploy"), found.clone());
+    app.on_esc();
+    assert!(app.found.is_none());
+    assert_eq!(app.modal, Widget::App);
+    // the results do not interrupt typing
+    app.on_key('p', false, false);
+    app.on_search_results(String::from("deploy"), found);
+    assert_eq!(app.modal, Widget::Input);
+    assert!(app.found.is_none());
+    assert!(app.notice.as_ref().unwrap().contains("dismissed"));
+    let searched: Vec<String> = collect_commands(app, rx_command)
+        .into_iter()
+        .filter_map(|command| match command {
+            Command::Search(query) => Some(query),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(searched, vec![String::from("deploy")]);
+}
+
+#[test]
+fn test_export_chat() {
+    let (mut app, rx_command) = test_app();
+    app.on_key('s', true, false);
+    assert_eq!(app.input.as_ref().unwrap().text, "chat-10.txt");
+    // the default name is exported as text, the json by the extension
+    app.on_enter();
+    app.on_key('s', true, false);
+    app.on_key('u', true, false);
+    for c in "log.JSON".chars() {
+        app.on_key(c, false, false);
+    }
+    app.on_enter();
+    // nothing typed exports nothing
+    app.on_key('s', true, false);
+    app.on_key('u', true, false);
+    app.on_enter();
+    assert!(app.input.is_none());
+    // the owner discourages the export
+    app.on_chat_updated(
+        proto::Chat {
+            id: 10,
+            description: String::from("chat"),
+            users: vec![1],
+            export_discouraged: true,
+            ..Default::default()
+        },
+        0,
+    );
+    app.on_key('s', true, false);
+    assert!(app.input.is_none());
+    assert_eq!(app.modal, Widget::App);
+    assert!(app.notice.as_ref().unwrap().contains("does not allow"));
+    let exports: Vec<ChatExport> = collect_commands(app, rx_command)
+        .into_iter()
+        .filter_map(|command| match command {
+            Command::ExportChat(export) => Some(export),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(exports.len(), 2);
+    assert_eq!(exports[0].path, PathBuf::from("chat-10.txt"));
+    assert_eq!(exports[0].format, ExportFormat::Text);
+    assert_eq!(exports[1].path, PathBuf::from("log.JSON"));
+    assert_eq!(exports[1].format, ExportFormat::Json);
+    assert_eq!(exports[1].chat_id, 10);
+    assert_eq!(exports[1].authors[&1], "user");
+    assert_eq!(exports[1].authors[&2], "other");
+    let watermark = &exports[1].watermark;
+    assert_eq!(watermark.exported_by, "user (1)");
+    assert_eq!(watermark.chat_id, 10);
+    assert!(watermark.exported > 0);
 }
@@ -0,0 +1,147 @@
+use tui::layout::Rect;
+
+// the list as drawn the last time, the clicks are resolved against it
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct ListArea {
+    // inside the borders
+    pub rect: Rect,
+    // the first item shown
+    pub offset: usize,
+    // rows of every item
+    pub heights: Vec<usize>,
+}
+
+// the layout drawn the last time
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct Areas {
+    pub users: ListArea,
+    pub chats: ListArea,
+    pub posts: ListArea,
+    pub log: Rect,
+    // the line of the input popup, none while the oversize is decided
+    pub input: Option<Rect>,
+}
+
+pub fn contains(rect: Rect, column: u16, row: u16) -> bool {
+    column >= rect.x && column - rect.x < rect.width && row >= rect.y && row - rect.y < rect.height
+}
+
+impl ListArea {
+    // the offset is the one of the previous draw, tui moves it to keep the selection shown
+    pub fn new(rect: Rect, heights: Vec<usize>, selected: Option<usize>, offset: usize) -> Self {
+        let offset = list_offset(&heights, selected, offset, rect.height as usize);
+        ListArea {
+            rect,
+            offset,
+            heights,
+        }
+    }
+
+    // none below the last item
+    pub fn item_at(&self, column: u16, row: u16) -> Option<usize> {
+        if !contains(self.rect, column, row) {
+            return None;
+        }
+        let mut rows = (row - self.rect.y) as usize;
+        for (idx, height) in self.heights.iter().enumerate().skip(self.offset) {
+            if rows < *height {
+                return Some(idx);
+            }
+            rows -= height;
+        }
+        None
+    }
+}
+
+// the same first item tui shows, ListState keeps its offset private
+fn list_offset(
+    heights: &[usize],
+    selected: Option<usize>,
+    offset: usize,
+    max_height: usize,
+) -> usize {
+    if heights.is_empty() {
+        return 0;
+    }
+    let mut start = offset.min(heights.len() - 1);
+    let mut end = start;
+    let mut height = 0;
+    for item in heights.iter().skip(start) {
+        if height + item > max_height {
+            break;
+        }
+        height += item;
+        end += 1;
+    }
+    let selected = selected.unwrap_or(0).min(heights.len() - 1);
+    while selected >= end {
+        height = height.saturating_add(heights[end]);
+        end += 1;
+        while height > max_height {
+            height = height.saturating_sub(heights[start]);
+            start += 1;
+        }
+    }
+    while selected < start {
+        start -= 1;
+        height = height.saturating_add(heights[start]);
+        while height > max_height {
+            end -= 1;
+            height = height.saturating_sub(heights[end]);
+        }
+    }
+    start
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn item_under_position() {
+        // 3 rows high at (10, 5), the items of 1, 2 and 1 rows
+        let list = ListArea::new(Rect::new(10, 5, 20, 3), vec![1, 2, 1], None, 0);
+        assert_eq!(list.offset, 0);
+        assert_eq!(list.item_at(10, 5), Some(0));
+        assert_eq!(list.item_at(29, 6), Some(1));
+        assert_eq!(list.item_at(15, 7), Some(1));
+        // outside
+        assert_eq!(list.item_at(30, 6), None);
+        assert_eq!(list.item_at(9, 6), None);
+        assert_eq!(list.item_at(15, 8), None);
+        // below the last item
+        let list = ListArea::new(Rect::new(0, 0, 20, 10), vec![2, 2], Some(1), 0);
+        assert_eq!(list.item_at(0, 3), Some(1));
+        assert_eq!(list.item_at(0, 4), None);
+    }
+
+    #[test]
+    fn offset_follows_selection() {
+        let rect = Rect::new(0, 0, 20, 4);
+        // the selection at the bottom scrolls the list down
+        let list = ListArea::new(rect, vec![2, 2, 2, 2], Some(3), 0);
+        assert_eq!(list.offset, 2);
+        assert_eq!(list.item_at(0, 0), Some(2));
+        assert_eq!(list.item_at(0, 3), Some(3));
+        // the selection shown keeps the offset
+        let list = ListArea::new(rect, vec![2, 2, 2, 2], Some(2), list.offset);
+        assert_eq!(list.offset, 2);
+        // the selection above scrolls up
+        let list = ListArea::new(rect, vec![2, 2, 2, 2], Some(0), list.offset);
+        assert_eq!(list.offset, 0);
+        // no items
+        let list = ListArea::new(rect, Vec::new(), None, 3);
+        assert_eq!(list.offset, 0);
+        assert_eq!(list.item_at(0, 0), None);
+    }
+
+    #[test]
+    fn contained() {
+        let rect = Rect::new(2, 3, 4, 1);
+        assert!(contains(rect, 2, 3));
+        assert!(contains(rect, 5, 3));
+        assert!(!contains(rect, 6, 3));
+        assert!(!contains(rect, 2, 4));
+        assert!(!contains(Rect::default(), 0, 0));
+    }
+}
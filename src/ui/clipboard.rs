@@ -0,0 +1,10 @@
+use arboard::Clipboard;
+
+// a fresh handle per use, e.g. the clipboard might be gone with the X session
+pub fn get_text() -> Result<String, arboard::Error> {
+    Clipboard::new()?.get_text()
+}
+
+pub fn set_text(text: &str) -> Result<(), arboard::Error> {
+    Clipboard::new()?.set_text(text.to_string())
+}
@@ -0,0 +1,130 @@
+use crate::proto::UserId;
+use tui::style::Color;
+
+// readable on both the dark and the light terminals, white and black are left for the text
+const DEF_PALETTE: [Color; 8] = [
+    Color::Cyan,
+    Color::Green,
+    Color::Yellow,
+    Color::Magenta,
+    Color::Blue,
+    Color::Red,
+    Color::LightCyan,
+    Color::LightMagenta,
+];
+
+// the color of every author, none is the style of the widget
+pub struct UserColors {
+    palette: Vec<Color>,
+}
+
+impl Default for UserColors {
+    fn default() -> Self {
+        UserColors::new(DEF_PALETTE.to_vec())
+    }
+}
+
+impl UserColors {
+    // the empty palette disables the colors
+    pub fn new(palette: Vec<Color>) -> Self {
+        UserColors { palette }
+    }
+
+    pub fn disabled() -> Self {
+        UserColors::new(Vec::new())
+    }
+
+    // the same in every run, the hasher of std is not guaranteed to be
+    pub fn of(&self, user_id: UserId) -> Option<Color> {
+        if self.palette.is_empty() {
+            return None;
+        }
+        let idx = (mix(user_id) % self.palette.len() as u64) as usize;
+        Some(self.palette[idx])
+    }
+}
+
+// the finalizer of splitmix64, the close ids get distant colors
+fn mix(id: UserId) -> u64 {
+    let mut x = id;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+// accepts the names as "light_blue" or "dark gray", and "#rrggbb"
+pub fn parse_color(s: &str) -> Result<Color, String> {
+    let name = s
+        .trim()
+        .to_lowercase()
+        .replace(|c: char| c == ' ' || c == '-', "_");
+    let color = match name.as_str() {
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" | "grey" => Color::Gray,
+        "dark_gray" | "dark_grey" => Color::DarkGray,
+        "light_red" => Color::LightRed,
+        "light_green" => Color::LightGreen,
+        "light_yellow" => Color::LightYellow,
+        "light_blue" => Color::LightBlue,
+        "light_magenta" => Color::LightMagenta,
+        "light_cyan" => Color::LightCyan,
+        "white" => Color::White,
+        _ => match name.strip_prefix('#') {
+            Some(hex) if hex.len() == 6 => {
+                let rgb =
+                    u32::from_str_radix(hex, 16).map_err(|_| format!("invalid color '{}'", s))?;
+                Color::Rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+            }
+            _ => return Err(format!("unknown color '{}'", s)),
+        },
+    };
+    Ok(color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_colors() {
+        let colors = UserColors::default();
+        // the fixed mixing, not the random state of a hasher
+        assert_eq!(mix(0), 0);
+        assert_eq!(mix(1), 0x5692_161d_100b_05e5);
+        for id in &[1, 2, 42, u64::MAX] {
+            assert_eq!(colors.of(*id), colors.of(*id));
+        }
+        // the sequential ids are spread over the palette
+        let used: Vec<Color> = (1..=64).filter_map(|id| colors.of(id)).collect();
+        for color in DEF_PALETTE.iter() {
+            assert!(used.contains(color), "{:?}", color);
+        }
+    }
+
+    #[test]
+    fn palette_bounds() {
+        let palette = vec![Color::Red, Color::Green, Color::Blue];
+        let colors = UserColors::new(palette.clone());
+        for id in (0..1000).chain(u64::MAX - 1000..=u64::MAX) {
+            assert!(palette.contains(&colors.of(id).unwrap()));
+        }
+        assert_eq!(UserColors::new(vec![Color::Red]).of(7), Some(Color::Red));
+        assert_eq!(UserColors::disabled().of(7), None);
+    }
+
+    #[test]
+    fn parsed_colors() {
+        assert_eq!(parse_color("Light Blue"), Ok(Color::LightBlue));
+        assert_eq!(parse_color("dark-gray"), Ok(Color::DarkGray));
+        assert_eq!(parse_color("#ff8000"), Ok(Color::Rgb(255, 128, 0)));
+        assert!(parse_color("#ff80").is_err());
+        assert!(parse_color("#gg8000").is_err());
+        assert!(parse_color("purple").is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use chrono::{DateTime, TimeZone};
+use std::fmt::Display;
+
+const DEF_MAX_LINES: usize = 50;
+const DEF_MAX_BYTES: usize = 4096;
+
+// post content exceeding the limits requires the user's decision
+#[derive(Clone, Copy, Debug)]
+pub struct ComposerLimits {
+    pub max_lines: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for ComposerLimits {
+    fn default() -> Self {
+        ComposerLimits {
+            max_lines: DEF_MAX_LINES,
+            max_bytes: DEF_MAX_BYTES,
+        }
+    }
+}
+
+impl ComposerLimits {
+    pub fn exceeded_by(&self, text: &str) -> bool {
+        text.lines().count() > self.max_lines || text.len() > self.max_bytes
+    }
+
+    // cuts the text to the limits keeping whole chars
+    pub fn trim(&self, text: &str) -> String {
+        let mut trimmed = text
+            .split('\n')
+            .take(self.max_lines)
+            .collect::<Vec<&str>>()
+            .join("\n");
+        if trimmed.len() > self.max_bytes {
+            let mut end = self.max_bytes;
+            while !trimmed.is_char_boundary(end) {
+                end -= 1;
+            }
+            trimmed.truncate(end);
+        }
+        trimmed
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum OversizeAction {
+    Trim,
+    Attach,
+    Cancel,
+}
+
+impl OversizeAction {
+    pub fn label(&self) -> &'static str {
+        match self {
+            OversizeAction::Trim => "trim",
+            OversizeAction::Attach => "send as attachment",
+            OversizeAction::Cancel => "cancel",
+        }
+    }
+}
+
+// selector shown in the composer once its content gets too large
+pub struct OversizeChoice {
+    pub options: Vec<OversizeAction>,
+    pub selected: usize,
+    // composer content before the input exceeding the limits, restored on cancel
+    pub before: String,
+}
+
+impl OversizeChoice {
+    pub fn new(attachments: bool, before: String) -> Self {
+        let options = if attachments {
+            vec![
+                OversizeAction::Trim,
+                OversizeAction::Attach,
+                OversizeAction::Cancel,
+            ]
+        } else {
+            vec![OversizeAction::Trim, OversizeAction::Cancel]
+        };
+        OversizeChoice {
+            options,
+            selected: 0,
+            before,
+        }
+    }
+
+    pub fn next(&mut self) {
+        self.selected = (self.selected + 1).min(self.options.len() - 1);
+    }
+
+    pub fn previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn action(&self) -> OversizeAction {
+        self.options[self.selected]
+    }
+}
+
+pub fn attachment_file_name<Tz: TimeZone>(now: &DateTime<Tz>) -> String
+where
+    Tz::Offset: Display,
+{
+    format!("paste-{}.txt", now.format("%Y%m%d-%H%M%S"))
+}
+
+// short post referencing the uploaded content
+pub fn attachment_post_text(file_name: &str, content: &str) -> String {
+    format!(
+        "[attachment: {}, {} lines, {} bytes]",
+        file_name,
+        content.lines().count(),
+        content.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn limits() {
+        let limits = ComposerLimits {
+            max_lines: 2,
+            max_bytes: 8,
+        };
+        assert!(!limits.exceeded_by("a\nb"));
+        assert!(limits.exceeded_by("a\nb\nc"));
+        assert!(!limits.exceeded_by("12345678"));
+        assert!(limits.exceeded_by("123456789"));
+        assert_eq!(limits.trim("a\nb\nc"), "a\nb");
+        assert_eq!(limits.trim("123456789"), "12345678");
+        // multibyte char is not split
+        assert_eq!(limits.trim("1234567ж"), "1234567");
+    }
+
+    #[test]
+    fn choice_navigation() {
+        let mut choice = OversizeChoice::new(true, String::new());
+        assert_eq!(choice.action(), OversizeAction::Trim);
+        choice.previous();
+        assert_eq!(choice.action(), OversizeAction::Trim);
+        choice.next();
+        assert_eq!(choice.action(), OversizeAction::Attach);
+        choice.next();
+        choice.next();
+        assert_eq!(choice.action(), OversizeAction::Cancel);
+        // attachments are unavailable
+        let mut choice = OversizeChoice::new(false, String::new());
+        assert_eq!(choice.options.len(), 2);
+        choice.next();
+        assert_eq!(choice.action(), OversizeAction::Cancel);
+    }
+
+    #[test]
+    fn attachment_post() {
+        let name = attachment_file_name(&Utc.ymd(2021, 4, 5).and_hms(13, 7, 9));
+        assert_eq!(name, "paste-20210405-130709.txt");
+        assert_eq!(
+            attachment_post_text(&name, "line 1\nline 2\n"),
+            "[attachment: paste-20210405-130709.txt, 2 lines, 14 bytes]"
+        );
+    }
+}
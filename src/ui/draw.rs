@@ -1,4 +1,8 @@
-use super::{App, Widget, WidgetState};
+use super::areas::ListArea;
+use super::plural::Counted;
+use super::search;
+use super::{Action, App, Connection, Widget, WidgetState};
+use crate::proto;
 use chrono::{Local, TimeZone};
 use tui::{
     backend::Backend,
@@ -9,6 +13,7 @@ use tui::{
     Frame,
 };
 use tui_logger::{TuiLoggerSmartWidget, TuiLoggerWidget};
+use unicode_width::UnicodeWidthStr;
 
 fn get_style(state: WidgetState) -> Style {
     match state {
@@ -18,12 +23,61 @@ fn get_style(state: WidgetState) -> Style {
     }
 }
 
+// the own name is underlined, the others are colored by the authors
+fn author_style(app: &App, user_id: proto::UserId, style: Style) -> Style {
+    if user_id == app.user.id {
+        style.add_modifier(Modifier::UNDERLINED)
+    } else {
+        match app.user_colors.of(user_id) {
+            Some(color) => style.fg(color),
+            None => style,
+        }
+    }
+}
+
+// the filter being typed or applied follows the name of the list
+fn list_title(app: &App, widget: Widget, name: &str) -> String {
+    let filter = app.get_list_filter(widget);
+    let typing = matches!(app.get_state(Widget::Filter), WidgetState::Modal)
+        && matches!(app.get_state(widget), WidgetState::Focused);
+    if typing || !filter.is_empty() {
+        format!("{} /{}", name, filter)
+    } else {
+        String::from(name)
+    }
+}
+
+// the parts matching the search are reversed
+fn highlight_matches<'a>(text: &str, query: Option<&str>, style: Style) -> Spans<'a> {
+    let ranges = query.map_or_else(Vec::new, |query| search::match_ranges(text, query));
+    let mut spans = Vec::with_capacity(ranges.len() * 2 + 1);
+    let mut from = 0;
+    for range in ranges {
+        if range.start > from {
+            spans.push(Span::styled(String::from(&text[from..range.start]), style));
+        }
+        spans.push(Span::styled(
+            String::from(&text[range.clone()]),
+            style.add_modifier(Modifier::REVERSED),
+        ));
+        from = range.end;
+    }
+    if from < text.len() || spans.is_empty() {
+        spans.push(Span::styled(String::from(&text[from..]), style));
+    }
+    Spans::from(spans)
+}
+
 fn get_timestamp_text(ts: u64) -> String {
     let tmp = Local.timestamp(ts as i64, 0);
     // /let tmp = chrono::DateTime::from_utc(NaiveDateTime::from_timestamp(ts as i64, 0), chrono::TimeZone::from_offset(offset: &Self::Offset));
     format!("{}", tmp.format("%d.%m.%Y %H:%M"))
 }
 
+fn get_time_text(ts: u64) -> String {
+    format!("{}", Local.timestamp(ts as i64, 0).format("%H:%M"))
+}
+
 pub fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     //
     // styles
@@ -37,6 +91,7 @@ pub fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     let posts_style = get_style(app.get_state(Widget::Posts));
     let log_style = get_style(app.get_state(Widget::Log));
     let input_style = get_style(app.get_state(Widget::Input));
+    let confirm_style = get_style(app.get_state(Widget::Confirm));
     //
     // layout
     //
@@ -55,8 +110,53 @@ pub fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     //
     let block = Block::default()
         .borders(Borders::ALL)
-        .title(Span::styled(&app.title, caption_style));
-    let paragraph = Paragraph::new(app.user_description.as_str())
+        .title(Span::styled(app.get_title(), caption_style));
+    let (connection, connection_color) = match app.connection {
+        Connection::Connecting => ("connecting to", Color::Yellow),
+        Connection::Connected => ("connected to", Color::Green),
+        Connection::Disconnected => ("disconnected from", Color::Red),
+    };
+    let mut header = vec![Span::raw(app.user_description.as_str())];
+    if let Some(keys) = app.keys.keys_text(Action::EditProfile) {
+        header.push(Span::styled(
+            format!(" [{}: edit]", keys),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+    header.push(Span::raw(" | "));
+    header.push(Span::styled(
+        format!("{} {}", connection, app.server_address),
+        Style::default().fg(connection_color),
+    ));
+    if app.stale {
+        header.push(Span::raw(" | "));
+        header.push(Span::styled("cached", Style::default().fg(Color::Yellow)));
+    }
+    if let Some(reconnect) = &app.reconnect {
+        header.push(Span::raw(" | "));
+        header.push(Span::styled(
+            reconnect.text(),
+            Style::default().fg(Color::Yellow),
+        ));
+    }
+    if let Some(pending) = app.relay_stats.stalled_pending() {
+        header.push(Span::raw(" | "));
+        header.push(Span::styled(
+            format!(
+                "UI lagging: {}",
+                app.plural(pending, Counted::PendingEvents)
+            ),
+            Style::default().fg(Color::Red),
+        ));
+    }
+    if let Some(notice) = &app.notice {
+        header.push(Span::raw(" | "));
+        header.push(Span::styled(
+            notice.as_str(),
+            Style::default().fg(Color::Yellow),
+        ));
+    }
+    let paragraph = Paragraph::new(Spans::from(header))
         .block(block)
         .wrap(Wrap { trim: true });
     f.render_widget(paragraph, rows[0]);
@@ -76,12 +176,34 @@ pub fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     //
     // Iterate through all elements in the `items` app and append some debug text to it.
     let users: Vec<ListItem> = app
-        .users
-        .iter()
-        .map(|u| ListItem::new(App::get_user_description(u)))
+        .get_users()
+        .map(|u| {
+            let presence = if app.is_online(u.id) {
+                Span::styled("● ", users_style.fg(Color::Green))
+            } else {
+                Span::styled("○ ", users_style)
+            };
+            ListItem::new(Spans::from(vec![
+                presence,
+                Span::styled(
+                    App::get_user_description(u),
+                    author_style(app, u.id, users_style),
+                ),
+            ]))
+        })
         .collect();
+    let users_block =
+        Block::default()
+            .borders(Borders::ALL)
+            .title(list_title(app, Widget::Users, "users"));
+    app.areas.users = ListArea::new(
+        users_block.inner(columns[0]),
+        users.iter().map(ListItem::height).collect(),
+        app.users_state.selected(),
+        app.areas.users.offset,
+    );
     let users = List::new(users)
-        .block(Block::default().borders(Borders::ALL).title("users"))
+        .block(users_block)
         .style(users_style)
         .highlight_symbol("> ")
         .highlight_style(selected_style);
@@ -90,28 +212,11 @@ pub fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     // chats
     //
     let chats: Vec<ListItem> = app
-        .chats
-        .values()
+        .get_chats()
         .map(|c| {
             let is_dialog = c.chat.description.is_empty();
             // 1st line: chat description
-            let chat_desc = if !is_dialog {
-                c.chat.description.clone()
-            } else {
-                // empty header means dialog chat, its name is a countepart's name
-                let mut tmp = String::new();
-                for u in &c.chat.users {
-                    if *u != app.user.id {
-                        if let Some(user) = app.get_user(*u) {
-                            if !tmp.is_empty() {
-                                tmp.push_str(", ");
-                            }
-                            tmp.push_str(&user.short_name);
-                        }
-                    }
-                }
-                tmp
-            };
+            let chat_desc = app.get_chat_title(c);
             // add posts count to desc
             let posts_count = app.get_posts_count(c.chat.id);
             let chat_header = if posts_count > 0 {
@@ -119,7 +224,28 @@ pub fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
             } else {
                 chat_desc
             };
-            let mut lines = vec![Spans::from(Span::styled(chat_header, chats_style))];
+            let mut header = vec![Span::styled(chat_header, chats_style)];
+            if app.is_muted(c.chat.id) {
+                header.push(Span::styled(" (muted)", chats_style));
+            } else if c.unread > 0 {
+                header.push(Span::styled(
+                    format!(" ({})", app.plural(c.unread, Counted::NewPosts)),
+                    chats_style.add_modifier(Modifier::BOLD),
+                ));
+            }
+            if c.late_unread {
+                header.push(Span::styled(" (late delivery)", chats_style));
+            }
+            let relation = match c.membership {
+                proto::Membership::Member => None,
+                proto::Membership::Spectator => Some(" (spectator)"),
+                proto::Membership::Invited => Some(" (invited)"),
+                proto::Membership::None => Some(" (not a member)"),
+            };
+            if let Some(relation) = relation {
+                header.push(Span::styled(relation, chats_style));
+            }
+            let mut lines = vec![Spans::from(header)];
             // 2nd line: chat members or 'private'
             let users = if !is_dialog {
                 let mut tmp = String::from("(");
@@ -150,8 +276,18 @@ pub fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
             ListItem::new(lines).style(chats_style)
         })
         .collect();
+    let chats_block =
+        Block::default()
+            .borders(Borders::ALL)
+            .title(list_title(app, Widget::Chats, "select chat"));
+    app.areas.chats = ListArea::new(
+        chats_block.inner(columns[1]),
+        chats.iter().map(ListItem::height).collect(),
+        app.chats_state.selected(),
+        app.areas.chats.offset,
+    );
     let chats = List::new(chats)
-        .block(Block::default().borders(Borders::ALL).title("select chat"))
+        .block(chats_block)
         .style(chats_style)
         .highlight_symbol("> ")
         .highlight_style(selected_style);
@@ -160,51 +296,187 @@ pub fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     // selected chat content
     //
     let displayed_posts = app.get_sel_posts();
-    let content: Vec<ListItem> = displayed_posts
+    let delivered = app.get_sel_chat().map(|c| &c.delivered);
+    let query = app.search.as_ref().map(|s| s.query.as_str());
+    let mut content: Vec<ListItem> = displayed_posts
         .iter()
         .map(|post| {
-            let mut author_info: String = app
-                .get_user(post.user_id)
-                .map(|u| {
-                    if u.id == app.user.id {
-                        String::from("me")
-                    } else {
-                        u.short_name.clone()
-                    }
-                })
-                .unwrap_or_else(|| format!("{}", post.user_id));
+            // the notice of the server has no author, it stands apart from the talk
+            if post.kind() == proto::PostKind::System {
+                let width = (columns[2].width - 4) as usize;
+                let lines: Vec<Spans> = textwrap::wrap(post.text.trim_end_matches('\n'), width)
+                    .into_iter()
+                    .map(|line| {
+                        let pad = width.saturating_sub(line.width()) / 2;
+                        Spans::from(Span::styled(
+                            format!("{}{}", " ".repeat(pad), line),
+                            posts_style.add_modifier(Modifier::ITALIC),
+                        ))
+                    })
+                    .collect();
+                return ListItem::new(lines);
+            }
+            let mut author_info = app.get_post_author(post);
             author_info.push_str(&format!(" ({})", get_timestamp_text(post.created)));
-            let mut lines = vec![Spans::from(Span::styled(
+            if let Some(at) = delivered.and_then(|d| d.get(&post.id)) {
+                author_info.push_str(&format!(" (delivered {})", get_time_text(*at)));
+            }
+            let mut lines = Vec::new();
+            if let Some(preview) = app.get_reply_preview(post) {
+                lines.push(Spans::from(Span::styled(
+                    preview,
+                    Style::default()
+                        .fg(Color::DarkGray)
+                        .add_modifier(Modifier::ITALIC),
+                )));
+            }
+            lines.push(Spans::from(Span::styled(
                 author_info,
-                selected_style.add_modifier(Modifier::BOLD),
-            ))];
+                author_style(
+                    app,
+                    post.user_id,
+                    selected_style.add_modifier(Modifier::BOLD),
+                ),
+            )));
+            if let Some(forwarded) = app.get_forwarded_text(post) {
+                lines.push(Spans::from(Span::styled(
+                    forwarded,
+                    Style::default()
+                        .fg(Color::DarkGray)
+                        .add_modifier(Modifier::ITALIC),
+                )));
+            }
             for wrapped_text in textwrap::wrap(
                 post.text.trim_end_matches('\n'),
                 (columns[2].width - 4) as usize, // width - left("|> ") - right("|")
             ) {
-                lines.push(Spans::from(Span::styled(wrapped_text, posts_style)));
+                lines.push(highlight_matches(&wrapped_text, query, posts_style));
+            }
+            for attachment in app.get_attachments_text(post) {
+                lines.push(Spans::from(Span::styled(
+                    attachment,
+                    posts_style.add_modifier(Modifier::UNDERLINED),
+                )));
+            }
+            if let Some(reactions) = app.get_reactions_text(post) {
+                lines.push(Spans::from(Span::styled(
+                    reactions,
+                    Style::default().fg(Color::DarkGray),
+                )));
             }
             ListItem::new(lines)
         })
         .collect();
+    // own posts not confirmed by the server yet
+    let pending_style = Style::default().fg(Color::DarkGray);
+    let failed_style = Style::default().fg(Color::Red);
+    let pending_texts: Vec<(String, bool)> = app
+        .get_sel_pending_posts()
+        .iter()
+        .map(|p| (p.text.clone(), p.is_failed()))
+        .collect();
+    for (text, failed) in &pending_texts {
+        let (caption, caption_style) = if *failed {
+            ("me (failed)", failed_style)
+        } else {
+            ("me (pending)", pending_style)
+        };
+        let mut lines = vec![Spans::from(Span::styled(
+            caption,
+            caption_style.add_modifier(Modifier::BOLD),
+        ))];
+        for wrapped_text in
+            textwrap::wrap(text.trim_end_matches('\n'), (columns[2].width - 4) as usize)
+        {
+            lines.push(Spans::from(Span::styled(wrapped_text, pending_style)));
+        }
+        content.push(ListItem::new(lines));
+    }
     let posts_title = if let Some(sel) = app.get_sel_chat() {
-        format!(
+        let mut title = format!(
             "{} ({})",
             sel.chat.description.clone(),
             sel.get_posts_count()
-        )
+        );
+        if !sel.filtered.is_empty() {
+            if app.reveal_filtered {
+                title.push_str(&format!(" ({} filtered, shown)", sel.filtered.len()));
+            } else {
+                title.push_str(&format!(" ({} filtered)", sel.filtered.len()));
+            }
+        }
+        if let Some(search) = app.search.as_ref() {
+            title.push_str(&format!(
+                " [search '{}': {}]",
+                search.query,
+                app.plural(search.matches.len(), Counted::Matches)
+            ));
+        }
+        if sel.degraded {
+            title.push_str(" - history unavailable for this chat");
+        }
+        if pending_texts.iter().any(|(_, failed)| *failed) {
+            if let Some(keys) = app.keys.keys_text(Action::Resend) {
+                title.push_str(&format!(" [{}: resend failed]", keys));
+            }
+        }
+        // the last own post still can be taken back
+        if let Some(unsend) = app.get_pending_unsend() {
+            if unsend.chat_id == sel.chat.id {
+                if let Some(keys) = app.keys.keys_text(Action::Unsend) {
+                    title.push_str(&format!(
+                        " [{}: unsend, {}s]",
+                        keys,
+                        unsend.remaining().as_secs()
+                    ));
+                }
+            }
+        }
+        title
     } else {
         String::from("No chat selected")
     };
+    let posts_block = Block::default().borders(Borders::ALL).title(posts_title);
+    let mut posts_area = posts_block.inner(columns[2]);
+    f.render_widget(posts_block, columns[2]);
+    // the members typing now are told right under the title
+    if let Some(typing) = app.get_sel_chat().and_then(|sel| app.get_typing_text(sel)) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(1), Constraint::Min(0)].as_ref())
+            .split(posts_area);
+        let typing_style = Style::default()
+            .fg(Color::DarkGray)
+            .add_modifier(Modifier::ITALIC);
+        f.render_widget(Paragraph::new(Span::styled(typing, typing_style)), rows[0]);
+        posts_area = rows[1];
+    }
+    let posts_heights = content.iter().map(ListItem::height).collect();
+    app.areas.posts = match app.get_sel_chat_mut() {
+        Some(sel) => {
+            let area = ListArea::new(
+                posts_area,
+                posts_heights,
+                sel.posts_state.selected(),
+                sel.posts_offset,
+            );
+            sel.posts_offset = area.offset;
+            area
+        }
+        None => ListArea::new(posts_area, posts_heights, None, 0),
+    };
     let content = List::new(content)
-        .block(Block::default().borders(Borders::ALL).title(posts_title))
         .style(posts_style)
         .highlight_symbol("> ")
         .highlight_style(selected_style);
-    f.render_stateful_widget(content, columns[2], &mut app.posts_state);
+    match app.get_sel_chat_mut() {
+        Some(sel) => f.render_stateful_widget(content, posts_area, &mut sel.posts_state),
+        None => f.render_widget(content, posts_area),
+    }
     //
     // logger
     //
+    app.areas.log = rows[2];
     if app.extended_log {
         let tui_sm = TuiLoggerSmartWidget::default()
             .border_style(log_style)
@@ -229,26 +501,229 @@ pub fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     //
     // input
     //
+    app.areas.input = None;
     if let Some(input) = &app.input {
-        let block = Paragraph::new(input.text.as_ref())
-            .style(input_style)
-            .block(
+        if let Some(choice) = &input.oversize {
+            // too large post, the decision is required
+            let mut options = Vec::new();
+            for (idx, option) in choice.options.iter().enumerate() {
+                if idx > 0 {
+                    options.push(Span::raw("   "));
+                }
+                let style = if idx == choice.selected {
+                    selected_style.add_modifier(Modifier::REVERSED)
+                } else {
+                    input_style
+                };
+                options.push(Span::styled(option.label(), style));
+            }
+            let text = vec![
+                Spans::from(Span::styled(
+                    format!(
+                        "The post is too large: {}, {}",
+                        app.plural(input.text.lines().count(), Counted::Lines),
+                        app.plural(input.text.len(), Counted::Bytes)
+                    ),
+                    input_style,
+                )),
+                Spans::from(options),
+            ];
+            let block = Paragraph::new(text).style(input_style).block(
                 Block::default()
                     .borders(Borders::ALL)
                     .style(input_style)
                     .title(input.title.as_str()),
             );
-        //let area = Rect::new(columns[1].left() + 5, columns[1].top() + 5, 60, 3);
-        let area = centered_rect(60, 3, f.size());
-        f.render_widget(Clear, area); //this clears out the background
+            let area = centered_rect(60, 4, f.size());
+            f.render_widget(Clear, area);
+            f.render_widget(block, area);
+        } else {
+            // the refused post is to be fixed
+            let border_style = if input.rejected {
+                input_style.fg(Color::Red)
+            } else {
+                input_style
+            };
+            let block = Paragraph::new(input.text.as_ref())
+                .style(input_style)
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .style(input_style)
+                        .border_style(border_style)
+                        .title(input.title.as_str()),
+                );
+            //let area = Rect::new(columns[1].left() + 5, columns[1].top() + 5, 60, 3);
+            let area = centered_rect(60, 3, f.size());
+            f.render_widget(Clear, area); //this clears out the background
+            f.render_widget(block, area);
+            app.areas.input = Some(Rect::new(
+                area.x + 1,
+                area.y + 1,
+                area.width.saturating_sub(2),
+                1,
+            ));
+            // Make the cursor visible and ask tui-rs to put it at the specified coordinates after rendering
+            f.set_cursor(
+                // Put cursor at its display column, kept inside the borders
+                area.x + 1 + (input.cursor_column() as u16).min(area.width.saturating_sub(3)),
+                // Move one line down, from the border to the input line
+                area.y + 1,
+            )
+        }
+    }
+    //
+    // chat to forward to
+    //
+    if let Some(forwarding) = &app.forwarding {
+        let picker_style = get_style(app.get_state(Widget::Picker));
+        let items: Vec<ListItem> = forwarding
+            .targets
+            .iter()
+            .map(|chat_id| {
+                let title = app
+                    .get_chat(*chat_id)
+                    .map(|c| app.get_chat_title(c))
+                    .unwrap_or_else(|| format!("chat {}", chat_id));
+                ListItem::new(Span::styled(title, picker_style))
+            })
+            .collect();
+        let height = (items.len() as u16 + 2).min(f.size().height);
+        let list = List::new(items)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .style(picker_style)
+                    .title("Forward to"),
+            )
+            .highlight_symbol("> ")
+            .highlight_style(selected_style);
+        let area = centered_rect(60, height, f.size());
+        f.render_widget(Clear, area);
+        if let Some(forwarding) = app.forwarding.as_mut() {
+            f.render_stateful_widget(list, area, &mut forwarding.state);
+        }
+    }
+    //
+    // posts found in all the chats
+    //
+    if let Some(found) = &app.found {
+        let results_style = get_style(app.get_state(Widget::Results));
+        let items: Vec<ListItem> = found
+            .posts
+            .iter()
+            .map(|post| {
+                let chat = app
+                    .get_chat(post.chat_id)
+                    .map(|c| app.get_chat_title(c))
+                    .unwrap_or_else(|| format!("chat {}", post.chat_id));
+                let mut spans = vec![
+                    Span::styled(format!("{} ", chat), results_style),
+                    Span::styled(
+                        app.get_post_author(post),
+                        author_style(app, post.user_id, results_style),
+                    ),
+                    Span::styled(": ", results_style),
+                ];
+                let text = post.text.lines().next().unwrap_or_default();
+                spans.extend(highlight_matches(text, Some(&found.query), results_style).0);
+                ListItem::new(Spans::from(spans))
+            })
+            .collect();
+        let height = (items.len() as u16 + 2).min(f.size().height);
+        let list = List::new(items)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .style(results_style)
+                    .title(format!(
+                        "Search results for '{}': {}",
+                        found.query,
+                        app.plural(found.posts.len(), Counted::Matches)
+                    )),
+            )
+            .highlight_symbol("> ")
+            .highlight_style(selected_style);
+        let area = centered_rect(80, height, f.size());
+        f.render_widget(Clear, area);
+        if let Some(found) = app.found.as_mut() {
+            f.render_stateful_widget(list, area, &mut found.state);
+        }
+    }
+    //
+    // leaving
+    //
+    if let Some(chat_id) = app.get_pending_leave() {
+        let text = vec![
+            Spans::from(Span::styled(
+                format!("Leave {}?", app.get_chat_description(chat_id)),
+                confirm_style,
+            )),
+            Spans::from(Span::styled("Enter: leave, Esc: cancel", selected_style)),
+        ];
+        let block = Paragraph::new(text).style(confirm_style).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .style(confirm_style)
+                .title("Leave chat"),
+        );
+        let area = centered_rect(60, 4, f.size());
+        f.render_widget(Clear, area);
+        f.render_widget(block, area);
+    }
+    //
+    // invitation
+    //
+    if let Some(invitation) = app.get_invitation() {
+        let answers = if app.accept_invitations {
+            "Enter: accept (default), Esc: decline"
+        } else {
+            "Enter: accept, Esc: decline"
+        };
+        let text = vec![
+            Spans::from(Span::styled(
+                format!(
+                    "{} invites you to {}",
+                    app.get_user_name(invitation.from_user_id),
+                    app.get_chat_description(invitation.chat_id)
+                ),
+                confirm_style,
+            )),
+            Spans::from(Span::styled(answers, selected_style)),
+        ];
+        let block = Paragraph::new(text).style(confirm_style).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .style(confirm_style)
+                .title("Invitation"),
+        );
+        let area = centered_rect(60, 4, f.size());
+        f.render_widget(Clear, area);
+        f.render_widget(block, area);
+    }
+    //
+    // help
+    //
+    if let WidgetState::Modal = app.get_state(Widget::Help) {
+        let help_style = get_style(WidgetState::Modal);
+        let lines = app.get_help_lines();
+        let text: Vec<Spans> = lines
+            .iter()
+            .map(|l| Spans::from(Span::styled(l.as_str(), help_style)))
+            .collect();
+        let height = (lines.len() as u16 + 2).min(f.size().height);
+        let block = Paragraph::new(text)
+            .style(help_style)
+            .scroll((app.help_scroll, 0))
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .style(help_style)
+                    .title("Keys, Esc: close"),
+            );
+        let area = centered_rect(76.min(f.size().width), height, f.size());
+        f.render_widget(Clear, area);
         f.render_widget(block, area);
-        // Make the cursor visible and ask tui-rs to put it at the specified coordinates after rendering
-        f.set_cursor(
-            // Put cursor past the end of the input text
-            area.x + input.text.len() as u16 + 1,
-            // Move one line down, from the border to the input line
-            area.y + 1,
-        )
     }
 }
 
@@ -0,0 +1,287 @@
+use crate::proto::{self, ChatId};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    str::FromStr,
+};
+
+const AUTHOR_KEY: &str = "author";
+const TEXT_KEY: &str = "text";
+const CHAT_KEY: &str = "chat";
+// lines of the filters file starting with it are skipped
+const COMMENT: char = '#';
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum TextMatch {
+    // text~"..."
+    Contains(String),
+    // text^"..."
+    Prefix(String),
+}
+
+// hides the posts matching all of its conditions, i.e.
+// author=ci-bot text~"nightly build" chat=12
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct FilterRule {
+    // short name or id of the author
+    pub author: Option<String>,
+    pub text: Option<TextMatch>,
+    // all the chats if not set
+    pub chat_id: Option<ChatId>,
+}
+
+impl FilterRule {
+    // the author is unknown until its info is received, then only its id and the name
+    // the post has been stamped with are matched
+    pub fn matches(&self, post: &proto::Post, author: Option<&proto::User>) -> bool {
+        if self.chat_id.map_or(false, |id| id != post.chat_id) {
+            return false;
+        }
+        if let Some(name) = &self.author {
+            let by_id = post.user_id.to_string() == *name;
+            let by_name = author.map_or(false, |u| u.short_name == *name)
+                || (!post.author_name.is_empty() && post.author_name == *name);
+            if !by_id && !by_name {
+                return false;
+            }
+        }
+        match &self.text {
+            Some(TextMatch::Contains(s)) => post.text.contains(s.as_str()),
+            Some(TextMatch::Prefix(s)) => post.text.starts_with(s.as_str()),
+            None => true,
+        }
+    }
+}
+
+// splits "key<op>value" terms, the value may be quoted to contain spaces
+fn terms(s: &str) -> Result<Vec<(String, char, String)>, String> {
+    let mut terms = Vec::new();
+    let mut chars = s.trim().chars().peekable();
+    while chars.peek().is_some() {
+        let mut key = String::new();
+        let op = loop {
+            match chars.next() {
+                Some(c) if c == '=' || c == '~' || c == '^' => break c,
+                Some(c) if c.is_whitespace() => return Err(format!("'{}' has no value", key)),
+                Some(c) => key.push(c),
+                None => return Err(format!("'{}' has no value", key)),
+            }
+        };
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => value.push(c),
+                    None => return Err(format!("unterminated quote of '{}'", key)),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek().filter(|c| !c.is_whitespace()) {
+                value.push(c);
+                chars.next();
+            }
+        }
+        if value.is_empty() {
+            return Err(format!("'{}' has empty value", key));
+        }
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        terms.push((key, op, value));
+    }
+    Ok(terms)
+}
+
+impl FromStr for FilterRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rule = FilterRule::default();
+        for (key, op, value) in terms(s)? {
+            match (key.as_str(), op) {
+                (AUTHOR_KEY, '=') if rule.author.is_none() => rule.author = Some(value),
+                (TEXT_KEY, '~') if rule.text.is_none() => {
+                    rule.text = Some(TextMatch::Contains(value))
+                }
+                (TEXT_KEY, '^') if rule.text.is_none() => {
+                    rule.text = Some(TextMatch::Prefix(value))
+                }
+                (CHAT_KEY, '=') if rule.chat_id.is_none() => {
+                    let id = value
+                        .parse()
+                        .map_err(|_| format!("invalid chat id '{}'", value))?;
+                    rule.chat_id = Some(id);
+                }
+                _ => return Err(format!("unexpected '{}{}{}'", key, op, value)),
+            }
+        }
+        // the chat alone would hide it entirely
+        if rule.author.is_none() && rule.text.is_none() {
+            return Err(String::from("neither author nor text to match"));
+        }
+        Ok(rule)
+    }
+}
+
+// parsed back to the same rule
+impl fmt::Display for FilterRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut terms = Vec::new();
+        if let Some(author) = &self.author {
+            if author.contains(char::is_whitespace) {
+                terms.push(format!("{}=\"{}\"", AUTHOR_KEY, author));
+            } else {
+                terms.push(format!("{}={}", AUTHOR_KEY, author));
+            }
+        }
+        match &self.text {
+            Some(TextMatch::Contains(s)) => terms.push(format!("{}~\"{}\"", TEXT_KEY, s)),
+            Some(TextMatch::Prefix(s)) => terms.push(format!("{}^\"{}\"", TEXT_KEY, s)),
+            None => {}
+        }
+        if let Some(id) = self.chat_id {
+            terms.push(format!("{}={}", CHAT_KEY, id));
+        }
+        write!(f, "{}", terms.join(" "))
+    }
+}
+
+// one rule per line, the missing file has no rules
+pub fn load_rules(path: &Path) -> Result<Vec<FilterRule>, String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut rules = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(COMMENT) {
+            continue;
+        }
+        rules.push(line.parse().map_err(|e| format!("line {}: {}", n + 1, e))?);
+    }
+    Ok(rules)
+}
+
+// replaces the file entirely
+pub fn save_rules(path: &Path, rules: &[FilterRule]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    for rule in rules {
+        writeln!(file, "{}", rule)?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_FILE: &str = "migchat-test-filters.txt";
+
+    fn post(chat_id: ChatId, user_id: proto::UserId, text: &str) -> proto::Post {
+        proto::Post {
+            chat_id,
+            user_id,
+            text: String::from(text),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rule: FilterRule = "author=ci-bot".parse().unwrap();
+        assert_eq!(rule.author.as_deref(), Some("ci-bot"));
+        let rule: FilterRule = r#"text~"nightly build"  chat=12"#.parse().unwrap();
+        assert_eq!(
+            rule,
+            FilterRule {
+                author: None,
+                text: Some(TextMatch::Contains(String::from("nightly build"))),
+                chat_id: Some(12),
+            }
+        );
+        let rule: FilterRule = r#"author="CI bot" text^"[ci]""#.parse().unwrap();
+        assert_eq!(rule.text, Some(TextMatch::Prefix(String::from("[ci]"))));
+        // written back to the same
+        assert_eq!(rule.to_string().parse::<FilterRule>().unwrap(), rule);
+        for invalid in &[
+            "",
+            "chat=12",
+            "author",
+            "author=",
+            "author=a author=b",
+            r#"text~"open"#,
+            "text=ci",
+            "chat=x author=a",
+            "who=a",
+        ] {
+            assert!(invalid.parse::<FilterRule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn match_rules() {
+        let bot = proto::User {
+            id: 7,
+            short_name: String::from("ci-bot"),
+            ..Default::default()
+        };
+        let by_name: FilterRule = "author=ci-bot".parse().unwrap();
+        assert!(by_name.matches(&post(1, 7, "anything"), Some(&bot)));
+        assert!(!by_name.matches(&post(1, 8, "anything"), None));
+        // the author is not known yet
+        assert!(!by_name.matches(&post(1, 7, "anything"), None));
+        let by_id: FilterRule = "author=7".parse().unwrap();
+        assert!(by_id.matches(&post(1, 7, "anything"), None));
+        // the name the post has been stamped with matches after the rename too
+        let stamped = proto::Post {
+            author_name: String::from("ci-bot"),
+            ..post(1, 7, "anything")
+        };
+        let renamed = proto::User {
+            short_name: String::from("builder"),
+            ..bot.clone()
+        };
+        assert!(by_name.matches(&stamped, Some(&renamed)));
+        assert!(by_name.matches(&stamped, None));
+        let by_new_name: FilterRule = "author=builder".parse().unwrap();
+        assert!(by_new_name.matches(&stamped, Some(&renamed)));
+        // all the conditions are to match
+        let rule: FilterRule = r#"author=ci-bot text~"nightly" chat=1"#.parse().unwrap();
+        assert!(rule.matches(&post(1, 7, "the nightly build"), Some(&bot)));
+        assert!(!rule.matches(&post(2, 7, "the nightly build"), Some(&bot)));
+        assert!(!rule.matches(&post(1, 7, "the daily build"), Some(&bot)));
+        assert!(!rule.matches(&post(1, 8, "the nightly build"), None));
+        let prefix: FilterRule = r#"text^"nightly""#.parse().unwrap();
+        assert!(prefix.matches(&post(3, 8, "nightly build"), None));
+        assert!(!prefix.matches(&post(3, 8, "the nightly build"), None));
+    }
+
+    #[test]
+    fn rules_persisted() {
+        let path = Path::new(TEST_FILE);
+        let _ = fs::remove_file(path);
+        assert!(load_rules(path).unwrap().is_empty());
+        let rules: Vec<FilterRule> = vec![
+            "author=ci-bot".parse().unwrap(),
+            r#"text~"nightly build" chat=3"#.parse().unwrap(),
+        ];
+        save_rules(path, &rules).unwrap();
+        assert_eq!(load_rules(path).unwrap(), rules);
+        // comments and blank lines are skipped, invalid rules are reported
+        fs::write(path, "# noisy bots\n\nauthor=ci-bot\nchat=3\n").unwrap();
+        assert_eq!(
+            load_rules(path).unwrap_err(),
+            "line 4: neither author nor text to match"
+        );
+        let _ = fs::remove_file(path);
+    }
+}
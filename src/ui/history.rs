@@ -0,0 +1,100 @@
+use std::collections::VecDeque;
+
+// texts submitted to an input, the newest last
+pub struct InputHistory {
+    entries: VecDeque<String>,
+    capacity: usize,
+}
+
+impl InputHistory {
+    pub fn new(capacity: usize) -> Self {
+        InputHistory {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    // repeating the last entry keeps a single one
+    pub fn record(&mut self, text: &str) {
+        if text.trim().is_empty() || self.entries.back().map(String::as_str) == Some(text) {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(String::from(text));
+    }
+}
+
+// the position of Up/Down in the history, the text typed before is stashed meanwhile
+#[derive(Default)]
+pub struct Recall {
+    index: Option<usize>,
+    stash: String,
+}
+
+impl Recall {
+    // the text to show instead of the current one, if any
+    pub fn older(&mut self, history: &InputHistory, text: &str) -> Option<String> {
+        let index = match self.index {
+            Some(0) => return None,
+            Some(index) => index - 1,
+            None if history.entries.is_empty() => return None,
+            None => {
+                self.stash = String::from(text);
+                history.entries.len() - 1
+            }
+        };
+        self.index = Some(index);
+        history.entries.get(index).cloned()
+    }
+
+    // past the newest entry the stashed text is back
+    pub fn newer(&mut self, history: &InputHistory) -> Option<String> {
+        let index = self.index? + 1;
+        if index < history.entries.len() {
+            self.index = Some(index);
+            history.entries.get(index).cloned()
+        } else {
+            self.index = None;
+            Some(std::mem::take(&mut self.stash))
+        }
+    }
+
+    // the edited text is the one typed now
+    pub fn reset(&mut self) {
+        self.index = None;
+        self.stash.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_collapsed() {
+        let mut history = InputHistory::new(3);
+        for text in &["a", "a", "b", " ", "a", "c", "c"] {
+            history.record(text);
+        }
+        assert_eq!(history.entries, vec!["b", "a", "c"]);
+    }
+
+    #[test]
+    fn recall_restores_stash() {
+        let mut history = InputHistory::new(10);
+        history.record("first");
+        history.record("second");
+        let mut recall = Recall::default();
+        assert_eq!(recall.newer(&history), None);
+        assert_eq!(recall.older(&history, "typing").as_deref(), Some("second"));
+        assert_eq!(recall.older(&history, "second").as_deref(), Some("first"));
+        assert_eq!(recall.older(&history, "first"), None);
+        assert_eq!(recall.newer(&history).as_deref(), Some("second"));
+        assert_eq!(recall.newer(&history).as_deref(), Some("typing"));
+        assert_eq!(recall.newer(&history), None);
+        // nothing to recall
+        assert_eq!(Recall::default().older(&InputHistory::new(10), "x"), None);
+    }
+}
@@ -0,0 +1,829 @@
+use super::{App, Widget};
+use crossterm::event::KeyCode;
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+// the keys the terminals pass with no char are bound by the function keys only
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum Key {
+    Char(char),
+    F(u8),
+}
+
+// F1..F24, the terminals pass no more
+const MAX_FUNCTION_KEY: u8 = 24;
+
+// single key press with its modifiers
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct Chord {
+    pub key: Key,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+impl Chord {
+    pub fn new(key: char, ctrl: bool, alt: bool) -> Self {
+        Chord {
+            key: Key::Char(key),
+            ctrl,
+            alt,
+        }
+    }
+
+    pub fn function(n: u8, ctrl: bool, alt: bool) -> Self {
+        Chord {
+            key: Key::F(n),
+            ctrl,
+            alt,
+        }
+    }
+
+    // chord might be a part of typed text
+    pub fn is_plain(&self) -> bool {
+        !self.ctrl && !self.alt && matches!(self.key, Key::Char(_))
+    }
+}
+
+impl Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.ctrl {
+            write!(f, "ctrl+")?;
+        }
+        if self.alt {
+            write!(f, "alt+")?;
+        }
+        match self.key {
+            Key::Char(' ') => write!(f, "space"),
+            Key::Char(c) => write!(f, "{}", c),
+            Key::F(n) => write!(f, "F{}", n),
+        }
+    }
+}
+
+fn strip_modifier<'a>(s: &'a str, modifier: &str) -> Option<&'a str> {
+    if s.len() > modifier.len()
+        && s.is_char_boundary(modifier.len())
+        && s[..modifier.len()].eq_ignore_ascii_case(modifier)
+    {
+        Some(&s[modifier.len()..])
+    } else {
+        None
+    }
+}
+
+impl FromStr for Chord {
+    type Err = KeyBindingsError;
+
+    // accepts "p", "ctrl+n", "alt+i", "ctrl+alt+x", "space", "+", "F2"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chord = Chord::new(' ', false, false);
+        let mut rest = s.trim();
+        loop {
+            if let Some(tail) = strip_modifier(rest, "ctrl+") {
+                chord.ctrl = true;
+                rest = tail;
+            } else if let Some(tail) = strip_modifier(rest, "alt+") {
+                chord.alt = true;
+                rest = tail;
+            } else {
+                break;
+            }
+        }
+        let mut chars = rest.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => {
+                chord.key = Key::Char(c);
+                Ok(chord)
+            }
+            _ if rest.eq_ignore_ascii_case("space") => Ok(chord),
+            (Some('F'), Some(_)) | (Some('f'), Some(_)) => match rest[1..].parse::<u8>() {
+                Ok(n) if (1..=MAX_FUNCTION_KEY).contains(&n) => {
+                    chord.key = Key::F(n);
+                    Ok(chord)
+                }
+                _ => Err(KeyBindingsError {
+                    text: format!("invalid key chord '{}'", s),
+                }),
+            },
+            _ => Err(KeyBindingsError {
+                text: format!("invalid key chord '{}'", s),
+            }),
+        }
+    }
+}
+
+// parses space separated sequence of chords, e.g. "g i"
+pub fn parse_sequence(s: &str) -> Result<Vec<Chord>, KeyBindingsError> {
+    let keys = s
+        .split_whitespace()
+        .map(|chord| chord.parse::<Chord>())
+        .collect::<Result<Vec<Chord>, KeyBindingsError>>()?;
+    if keys.is_empty() {
+        Err(KeyBindingsError {
+            text: String::from("empty key sequence"),
+        })
+    } else {
+        Ok(keys)
+    }
+}
+
+fn sequence_text(keys: &[Chord]) -> String {
+    keys.iter()
+        .map(|k| k.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum Action {
+    Exit,
+    NewChat,
+    NewPost,
+    Invite,
+    KickUser,
+    LogToggleHidden,
+    LogLessVerbose,
+    LogMoreVerbose,
+    Unsend,
+    NextUnread,
+    LeaveChat,
+    RenameChat,
+    ReconnectNow,
+    React,
+    Reply,
+    Forward,
+    SendFile,
+    EditProfile,
+    Paste,
+    CopyPost,
+    Help,
+    DeleteWord,
+    ClearLine,
+    MuteChat,
+    FilterList,
+    Search,
+    NextMatch,
+    PreviousMatch,
+    SearchAll,
+    ExportChat,
+    Resend,
+}
+
+const ACTIONS: [Action; 31] = [
+    Action::Exit,
+    Action::NewChat,
+    Action::NewPost,
+    Action::Invite,
+    Action::KickUser,
+    Action::LogToggleHidden,
+    Action::LogLessVerbose,
+    Action::LogMoreVerbose,
+    Action::Unsend,
+    Action::NextUnread,
+    Action::LeaveChat,
+    Action::RenameChat,
+    Action::ReconnectNow,
+    Action::React,
+    Action::Reply,
+    Action::Forward,
+    Action::SendFile,
+    Action::EditProfile,
+    Action::Paste,
+    Action::CopyPost,
+    Action::Help,
+    Action::DeleteWord,
+    Action::ClearLine,
+    Action::MuteChat,
+    Action::FilterList,
+    Action::Search,
+    Action::NextMatch,
+    Action::PreviousMatch,
+    Action::SearchAll,
+    Action::ExportChat,
+    Action::Resend,
+];
+
+impl Action {
+    // name used in the config and by the command palette
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Exit => "exit",
+            Action::NewChat => "new_chat",
+            Action::NewPost => "new_post",
+            Action::Invite => "invite",
+            Action::KickUser => "kick_user",
+            Action::LogToggleHidden => "log_toggle_hidden",
+            Action::LogLessVerbose => "log_less_verbose",
+            Action::LogMoreVerbose => "log_more_verbose",
+            Action::Unsend => "unsend",
+            Action::NextUnread => "next_unread",
+            Action::LeaveChat => "leave_chat",
+            Action::RenameChat => "rename_chat",
+            Action::ReconnectNow => "reconnect_now",
+            Action::React => "react",
+            Action::Reply => "reply",
+            Action::Forward => "forward",
+            Action::SendFile => "send_file",
+            Action::EditProfile => "edit_profile",
+            Action::Paste => "paste",
+            Action::CopyPost => "copy_post",
+            Action::Help => "help",
+            Action::DeleteWord => "delete_word",
+            Action::ClearLine => "clear_line",
+            Action::MuteChat => "mute_chat",
+            Action::FilterList => "filter_list",
+            Action::Search => "search",
+            Action::NextMatch => "next_match",
+            Action::PreviousMatch => "previous_match",
+            Action::SearchAll => "search_all",
+            Action::ExportChat => "export_chat",
+            Action::Resend => "resend",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Action::Exit => "exit the application",
+            Action::NewChat => "create new chat",
+            Action::NewPost => "write new post into selected chat",
+            Action::Invite => "invite selected user into selected chat",
+            Action::KickUser => "remove selected user from own selected chat",
+            Action::LogToggleHidden => "hide / show selected log target",
+            Action::LogLessVerbose => "decrease log level",
+            Action::LogMoreVerbose => "increase log level",
+            Action::Unsend => "take back the last own post",
+            Action::NextUnread => "select the next chat with unread posts",
+            Action::LeaveChat => "leave selected chat",
+            Action::RenameChat => "change description of selected chat",
+            Action::ReconnectNow => "reconnect immediately while disconnected",
+            Action::React => "react to selected post with emoji",
+            Action::Reply => "reply to selected post",
+            Action::Forward => "forward selected post to another chat",
+            Action::SendFile => "send a file into selected chat",
+            Action::EditProfile => "change own display names",
+            Action::Paste => "paste the clipboard into the input",
+            Action::CopyPost => "copy text of selected post to the clipboard",
+            Action::Help => "show / hide this help",
+            Action::DeleteWord => "delete the word before the cursor",
+            Action::ClearLine => "delete the line up to the cursor",
+            Action::MuteChat => "mute / unmute notifications of selected chat",
+            Action::FilterList => "show only the entries of focused list matching typed text",
+            Action::Search => "search the loaded posts of selected chat",
+            Action::NextMatch => "select the next older post found",
+            Action::PreviousMatch => "select the next newer post found",
+            Action::SearchAll => "search the posts of all own chats on server",
+            Action::ExportChat => "write history of selected chat into file",
+            Action::Resend => "send again own posts of selected chat failed to send",
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = KeyBindingsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ACTIONS
+            .iter()
+            .find(|a| a.name() == s)
+            .copied()
+            .ok_or_else(|| KeyBindingsError {
+                text: format!("unknown action '{}'", s),
+            })
+    }
+}
+
+// Widget::App is the global context, its bindings work in any pane
+pub fn context_name(context: Widget) -> &'static str {
+    match context {
+        Widget::App => "global",
+        Widget::Users => "users",
+        Widget::Chats => "chats",
+        Widget::Posts => "posts",
+        Widget::Log => "log",
+        Widget::Input => "input",
+        Widget::Confirm => "confirm",
+        Widget::Picker => "picker",
+        Widget::Help => "help",
+        Widget::Filter => "filter",
+        Widget::Results => "results",
+    }
+}
+
+// the contexts of the bindings in the order of the help
+const HELP_CONTEXTS: [Widget; 6] = [
+    Widget::App,
+    Widget::Users,
+    Widget::Chats,
+    Widget::Posts,
+    Widget::Log,
+    Widget::Input,
+];
+
+// key with no char, the client routes it to the app by the table, the help lists it
+pub struct NamedKey {
+    pub code: KeyCode,
+    pub name: &'static str,
+    pub handler: fn(&mut App),
+    pub description: &'static str,
+}
+
+pub static NAMED_KEYS: [NamedKey; 13] = [
+    NamedKey {
+        code: KeyCode::F(1),
+        name: "F1",
+        handler: App::on_help,
+        description: "show / hide this help",
+    },
+    NamedKey {
+        code: KeyCode::Esc,
+        name: "esc",
+        handler: App::on_esc,
+        description: "close the popup, cancel the input, clear the filter or the selection",
+    },
+    NamedKey {
+        code: KeyCode::Enter,
+        name: "enter",
+        handler: App::on_enter,
+        description: "submit the input, open the dialog with selected user, keep the filter",
+    },
+    NamedKey {
+        code: KeyCode::Up,
+        name: "up",
+        handler: App::on_up,
+        description: "previous item, older text while typing",
+    },
+    NamedKey {
+        code: KeyCode::Down,
+        name: "down",
+        handler: App::on_down,
+        description: "next item, newer text while typing",
+    },
+    NamedKey {
+        code: KeyCode::Left,
+        name: "left",
+        handler: App::on_left,
+        description: "previous pane, cursor left while typing",
+    },
+    NamedKey {
+        code: KeyCode::Right,
+        name: "right",
+        handler: App::on_right,
+        description: "next pane, cursor right while typing",
+    },
+    NamedKey {
+        code: KeyCode::PageUp,
+        name: "pgup",
+        handler: App::on_page_up,
+        description: "page up the posts or the log",
+    },
+    NamedKey {
+        code: KeyCode::PageDown,
+        name: "pgdn",
+        handler: App::on_page_down,
+        description: "page down the posts or the log",
+    },
+    NamedKey {
+        code: KeyCode::Home,
+        name: "home",
+        handler: App::on_home,
+        description: "first post, start of the line while typing",
+    },
+    NamedKey {
+        code: KeyCode::End,
+        name: "end",
+        handler: App::on_end,
+        description: "last post, end of the line while typing",
+    },
+    NamedKey {
+        code: KeyCode::Backspace,
+        name: "backspace",
+        handler: App::on_backspace,
+        description: "delete the char before the cursor",
+    },
+    NamedKey {
+        code: KeyCode::Delete,
+        name: "del",
+        handler: App::on_delete,
+        description: "delete the char under the cursor",
+    },
+];
+
+pub fn named_key(code: KeyCode) -> Option<&'static NamedKey> {
+    NAMED_KEYS.iter().find(|k| k.code == code)
+}
+
+fn parse_context(s: &str) -> Result<Widget, KeyBindingsError> {
+    match s.trim() {
+        "global" => Ok(Widget::App),
+        "users" => Ok(Widget::Users),
+        "chats" => Ok(Widget::Chats),
+        "posts" => Ok(Widget::Posts),
+        "log" => Ok(Widget::Log),
+        "input" => Ok(Widget::Input),
+        _ => Err(KeyBindingsError {
+            text: format!("unknown key context '{}'", s),
+        }),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Binding {
+    pub context: Widget,
+    pub keys: Vec<Chord>,
+    pub action: Action,
+}
+
+impl Binding {
+    // accepts "[context/]keys", e.g. "chats/ctrl+n" or "ctrl+q" for the global context
+    fn parse(spec: &str, action: Action) -> Result<Self, KeyBindingsError> {
+        let (context, keys) = match spec.find('/') {
+            Some(pos) => match parse_context(&spec[..pos]) {
+                Ok(context) => (context, &spec[pos + 1..]),
+                // "/" itself might be a key
+                Err(_) => (Widget::App, spec),
+            },
+            None => (Widget::App, spec),
+        };
+        Ok(Binding {
+            context,
+            keys: parse_sequence(keys)?,
+            action,
+        })
+    }
+}
+
+// the spec parsed back by Binding::parse()
+impl Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.context != Widget::App {
+            write!(f, "{}/", context_name(self.context))?;
+        }
+        write!(f, "{}", sequence_text(&self.keys))
+    }
+}
+
+pub enum Lookup {
+    // complete sequence is bound to the action
+    Action(Action),
+    // sequence is a beginning of some binding, wait for more keys
+    Pending,
+    None,
+}
+
+pub struct KeyBindings {
+    bindings: Vec<Binding>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        let binding = |context, keys: &str, action| Binding {
+            context,
+            keys: parse_sequence(keys).expect("valid default key"),
+            action,
+        };
+        KeyBindings {
+            bindings: vec![
+                binding(Widget::App, "ctrl+q", Action::Exit),
+                binding(Widget::App, "p", Action::NewPost),
+                binding(Widget::App, "ctrl+z", Action::Unsend),
+                binding(Widget::App, "ctrl+u", Action::NextUnread),
+                binding(Widget::App, "r", Action::ReconnectNow),
+                binding(Widget::App, "ctrl+p", Action::EditProfile),
+                binding(Widget::App, "ctrl+v", Action::Paste),
+                binding(Widget::App, "?", Action::Help),
+                binding(Widget::App, "alt+f", Action::SearchAll),
+                binding(Widget::Chats, "ctrl+n", Action::NewChat),
+                binding(Widget::Chats, "alt+i", Action::NewPost),
+                binding(Widget::Chats, "ctrl+l", Action::LeaveChat),
+                binding(Widget::Chats, "ctrl+e", Action::RenameChat),
+                binding(Widget::Chats, "ctrl+f", Action::SendFile),
+                binding(Widget::Chats, "m", Action::MuteChat),
+                binding(Widget::Chats, "/", Action::FilterList),
+                binding(Widget::Chats, "ctrl+s", Action::ExportChat),
+                binding(Widget::Posts, "ctrl+n", Action::NewPost),
+                binding(Widget::Posts, "r", Action::React),
+                binding(Widget::Posts, "ctrl+r", Action::Reply),
+                binding(Widget::Posts, "f", Action::Forward),
+                binding(Widget::Posts, "ctrl+f", Action::Search),
+                binding(Widget::Posts, "n", Action::NextMatch),
+                binding(Widget::Posts, "N", Action::PreviousMatch),
+                binding(Widget::Posts, "y", Action::CopyPost),
+                binding(Widget::Posts, "alt+r", Action::Resend),
+                binding(Widget::Users, "alt+i", Action::Invite),
+                binding(Widget::Users, "alt+k", Action::KickUser),
+                binding(Widget::Users, "/", Action::FilterList),
+                binding(Widget::Log, "space", Action::LogToggleHidden),
+                binding(Widget::Log, "-", Action::LogLessVerbose),
+                binding(Widget::Log, "+", Action::LogMoreVerbose),
+                binding(Widget::Input, "ctrl+w", Action::DeleteWord),
+                binding(Widget::Input, "ctrl+u", Action::ClearLine),
+            ],
+        }
+    }
+}
+
+impl KeyBindings {
+    /// Creates default bindings replacing ones of the overridden actions.
+    /// Each override is an action name with the list of its "[context/]keys" specs,
+    /// an empty list unbinds the action.
+    pub fn with_overrides(overrides: &[(String, Vec<String>)]) -> Result<Self, KeyBindingsError> {
+        let mut keys = KeyBindings::default();
+        for (name, specs) in overrides {
+            let action = name.parse::<Action>()?;
+            keys.bindings.retain(|b| b.action != action);
+            for spec in specs {
+                let binding = Binding::parse(spec, action).map_err(|e| KeyBindingsError {
+                    text: format!("keys.{} = \"{}\": {}", name, spec, e),
+                })?;
+                keys.bindings.push(binding);
+            }
+        }
+        keys.check_conflicts()?;
+        Ok(keys)
+    }
+
+    // two actions cannot share a sequence or its beginning in the same context
+    fn check_conflicts(&self) -> Result<(), KeyBindingsError> {
+        for (i, a) in self.bindings.iter().enumerate() {
+            for b in self.bindings.iter().skip(i + 1) {
+                if a.context != b.context || a.action == b.action {
+                    continue;
+                }
+                if a.keys.starts_with(&b.keys) || b.keys.starts_with(&a.keys) {
+                    return Err(KeyBindingsError {
+                        text: format!(
+                            "'{}' and '{}' in {} context are bound to both {} and {}",
+                            sequence_text(&a.keys),
+                            sequence_text(&b.keys),
+                            context_name(a.context),
+                            a.action.name(),
+                            b.action.name()
+                        ),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the pane's own bindings take precedence over the global ones
+    pub fn lookup(&self, context: Widget, keys: &[Chord]) -> Lookup {
+        let contexts = if context == Widget::App {
+            vec![Widget::App]
+        } else {
+            vec![context, Widget::App]
+        };
+        for ctx in contexts {
+            let mut pending = false;
+            for b in self.bindings.iter().filter(|b| b.context == ctx) {
+                if b.keys == keys {
+                    return Lookup::Action(b.action);
+                }
+                if b.keys.starts_with(keys) {
+                    pending = true;
+                }
+            }
+            if pending {
+                return Lookup::Pending;
+            }
+        }
+        Lookup::None
+    }
+
+    // keys of the action's first binding, e.g. for hints
+    pub fn keys_text(&self, action: Action) -> Option<String> {
+        self.bindings
+            .iter()
+            .find(|b| b.action == action)
+            .map(|b| sequence_text(&b.keys))
+    }
+
+    // lines of the help overlay, the named keys and then the bindings grouped by context
+    pub fn help_lines(&self) -> Vec<String> {
+        let named = NAMED_KEYS
+            .iter()
+            .map(|k| format!("{:<8}{:<12}{}", "", k.name, k.description));
+        let bound = HELP_CONTEXTS.iter().flat_map(|context| {
+            self.bindings
+                .iter()
+                .filter(move |b| b.context == *context)
+                .map(|b| {
+                    format!(
+                        "{:<8}{:<12}{}",
+                        context_name(b.context),
+                        sequence_text(&b.keys),
+                        b.action.description()
+                    )
+                })
+        });
+        named.chain(bound).collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct KeyBindingsError {
+    text: String,
+}
+
+impl Error for KeyBindingsError {}
+
+impl Display for KeyBindingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(items: &[(&str, &[&str])]) -> Vec<(String, Vec<String>)> {
+        items
+            .iter()
+            .map(|(name, specs)| {
+                (
+                    name.to_string(),
+                    specs.iter().map(|s| s.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn action_of(keys: &KeyBindings, context: Widget, spec: &str) -> Option<Action> {
+        match keys.lookup(context, &parse_sequence(spec).unwrap()) {
+            Lookup::Action(action) => Some(action),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parse_chord() {
+        assert_eq!("p".parse::<Chord>().unwrap(), Chord::new('p', false, false));
+        assert_eq!(
+            "ctrl+n".parse::<Chord>().unwrap(),
+            Chord::new('n', true, false)
+        );
+        assert_eq!(
+            "Alt+i".parse::<Chord>().unwrap(),
+            Chord::new('i', false, true)
+        );
+        assert_eq!(
+            "ctrl+alt+x".parse::<Chord>().unwrap(),
+            Chord::new('x', true, true)
+        );
+        assert_eq!("+".parse::<Chord>().unwrap(), Chord::new('+', false, false));
+        assert_eq!(
+            "ctrl++".parse::<Chord>().unwrap(),
+            Chord::new('+', true, false)
+        );
+        assert_eq!(
+            "space".parse::<Chord>().unwrap(),
+            Chord::new(' ', false, false)
+        );
+        assert!("ctrl+".parse::<Chord>().is_err());
+        assert!("shift+n".parse::<Chord>().is_err());
+        assert!("".parse::<Chord>().is_err());
+        assert_eq!(
+            parse_sequence("g i").unwrap(),
+            vec![Chord::new('g', false, false), Chord::new('i', false, false)]
+        );
+        assert!(parse_sequence("  ").is_err());
+        assert_eq!(
+            "F2".parse::<Chord>().unwrap(),
+            Chord::function(2, false, false)
+        );
+        assert_eq!(
+            "ctrl+f12".parse::<Chord>().unwrap(),
+            Chord::function(12, true, false)
+        );
+        assert_eq!("f".parse::<Chord>().unwrap(), Chord::new('f', false, false));
+        assert!("F0".parse::<Chord>().is_err());
+        assert!("F25".parse::<Chord>().is_err());
+        assert!("Fx".parse::<Chord>().is_err());
+        assert!(!Chord::function(2, false, false).is_plain());
+        for spec in &["p", "ctrl+n", "alt+i", "ctrl+alt+x", "space", "-", "F2"] {
+            assert_eq!(spec.parse::<Chord>().unwrap().to_string(), *spec);
+        }
+    }
+
+    #[test]
+    fn override_precedence() {
+        let keys =
+            KeyBindings::with_overrides(&overrides(&[("new_chat", &["chats/g c", "ctrl+g"])]))
+                .unwrap();
+        // defaults of the overridden action are gone
+        assert_eq!(action_of(&keys, Widget::Chats, "ctrl+n"), None);
+        assert_eq!(
+            action_of(&keys, Widget::Chats, "g c"),
+            Some(Action::NewChat)
+        );
+        assert!(matches!(
+            keys.lookup(Widget::Chats, &parse_sequence("g").unwrap()),
+            Lookup::Pending
+        ));
+        // global binding works in any pane
+        assert_eq!(
+            action_of(&keys, Widget::Users, "ctrl+g"),
+            Some(Action::NewChat)
+        );
+        // other defaults remain
+        assert_eq!(
+            action_of(&keys, Widget::Posts, "ctrl+n"),
+            Some(Action::NewPost)
+        );
+        // pane binding shadows the global one
+        let keys = KeyBindings::with_overrides(&overrides(&[("invite", &["users/p"])])).unwrap();
+        assert_eq!(action_of(&keys, Widget::Users, "p"), Some(Action::Invite));
+        assert_eq!(action_of(&keys, Widget::Chats, "p"), Some(Action::NewPost));
+        // unbound action
+        let keys = KeyBindings::with_overrides(&overrides(&[("exit", &[])])).unwrap();
+        assert_eq!(action_of(&keys, Widget::App, "ctrl+q"), None);
+    }
+
+    #[test]
+    fn conflicts() {
+        let err = KeyBindings::with_overrides(&overrides(&[("new_chat", &["chats/alt+i"])]))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("new_post"));
+        assert!(err.contains("new_chat"));
+        assert!(err.contains("chats"));
+        // beginning of the sequence is bound already
+        assert!(KeyBindings::with_overrides(&overrides(&[("exit", &["p q"])])).is_err());
+        // the same keys in different contexts are fine
+        assert!(
+            KeyBindings::with_overrides(&overrides(&[("new_chat", &["users/ctrl+n"])])).is_ok()
+        );
+        assert!(KeyBindings::with_overrides(&overrides(&[("unknown", &["x"])])).is_err());
+        assert!(KeyBindings::with_overrides(&overrides(&[("exit", &["nowhere/x"])])).is_err());
+        // the offending entry is named
+        let err = KeyBindings::with_overrides(&overrides(&[("invite", &["users/alt+ii"])]))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.starts_with("keys.invite = \"users/alt+ii\""));
+        assert!(err.contains("'alt+ii'"));
+    }
+
+    #[test]
+    fn defaults_round_trip() {
+        let defaults = KeyBindings::default();
+        let mut specs: Vec<(String, Vec<String>)> = Vec::new();
+        for b in &defaults.bindings {
+            match specs.iter_mut().find(|(name, _)| name == b.action.name()) {
+                Some((_, list)) => list.push(b.to_string()),
+                None => specs.push((b.action.name().to_string(), vec![b.to_string()])),
+            }
+        }
+        let keys = KeyBindings::with_overrides(&specs).unwrap();
+        assert_eq!(keys.bindings.len(), defaults.bindings.len());
+        for b in &defaults.bindings {
+            assert!(keys.bindings.contains(b), "{}", b);
+        }
+        let keys = KeyBindings::with_overrides(&overrides(&[("invite", &["users/F2"])])).unwrap();
+        assert_eq!(action_of(&keys, Widget::Users, "F2"), Some(Action::Invite));
+    }
+
+    #[test]
+    fn table_routes() {
+        // the help lists no binding shadowed by another one
+        let keys = KeyBindings::default();
+        for b in &keys.bindings {
+            assert_eq!(
+                action_of(&keys, b.context, &sequence_text(&b.keys)),
+                Some(b.action),
+                "{}",
+                sequence_text(&b.keys)
+            );
+        }
+        for action in ACTIONS.iter() {
+            assert!(keys.keys_text(*action).is_some(), "{}", action.name());
+        }
+        for key in NAMED_KEYS.iter() {
+            assert_eq!(named_key(key.code).unwrap().name, key.name);
+        }
+        assert!(named_key(KeyCode::Tab).is_none());
+    }
+
+    #[test]
+    fn help_reflects_override() {
+        let defaults = KeyBindings::default().help_lines();
+        assert!(defaults
+            .iter()
+            .any(|l| l.contains("ctrl+n") && l.contains(Action::NewChat.description())));
+        let keys =
+            KeyBindings::with_overrides(&overrides(&[("new_chat", &["chats/ctrl+b"])])).unwrap();
+        let lines = keys.help_lines();
+        assert_eq!(lines.len(), defaults.len());
+        assert!(lines
+            .iter()
+            .any(|l| l.contains("ctrl+b") && l.contains(Action::NewChat.description())));
+        assert!(!lines
+            .iter()
+            .any(|l| l.contains("ctrl+n") && l.contains(Action::NewChat.description())));
+    }
+}
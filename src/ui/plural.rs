@@ -0,0 +1,177 @@
+use std::str::FromStr;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Language {
+    English,
+    Russian,
+}
+
+impl FromStr for Language {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "en" | "english" => Ok(Language::English),
+            "ru" | "russian" => Ok(Language::Russian),
+            _ => Err(format!("unknown language '{}'", s)),
+        }
+    }
+}
+
+// grammatical number required by the count
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PluralForm {
+    One,
+    Few,
+    Many,
+}
+
+impl Language {
+    pub fn plural_form(self, count: usize) -> PluralForm {
+        match self {
+            // 1 line, 0/2/5 lines
+            Language::English => {
+                if count == 1 {
+                    PluralForm::One
+                } else {
+                    PluralForm::Many
+                }
+            }
+            // 1/21/101 строка, 2-4/22-24 строки, 0/5-20/25-30/111-114 строк
+            Language::Russian => {
+                let units = count % 10;
+                let tens = count % 100;
+                if units == 1 && tens != 11 {
+                    PluralForm::One
+                } else if (2..=4).contains(&units) && !(12..=14).contains(&tens) {
+                    PluralForm::Few
+                } else {
+                    PluralForm::Many
+                }
+            }
+        }
+    }
+}
+
+// the counted things shown by the UI
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Counted {
+    NewPosts,
+    PendingEvents,
+    Lines,
+    Bytes,
+    ChatsWithNews,
+    Matches,
+}
+
+// one, few and many forms; English has no "few" form, it is never chosen
+fn forms(language: Language, counted: Counted) -> [&'static str; 3] {
+    match (language, counted) {
+        (Language::English, Counted::NewPosts) => ["new", "new", "new"],
+        (Language::English, Counted::PendingEvents) => {
+            ["event pending", "events pending", "events pending"]
+        }
+        (Language::English, Counted::Lines) => ["line", "lines", "lines"],
+        (Language::English, Counted::Bytes) => ["byte", "bytes", "bytes"],
+        (Language::English, Counted::ChatsWithNews) => [
+            "chat with new posts",
+            "chats with new posts",
+            "chats with new posts",
+        ],
+        (Language::English, Counted::Matches) => ["match", "matches", "matches"],
+        (Language::Russian, Counted::NewPosts) => ["новое", "новых", "новых"],
+        (Language::Russian, Counted::PendingEvents) => [
+            "событие в очереди",
+            "события в очереди",
+            "событий в очереди",
+        ],
+        (Language::Russian, Counted::Lines) => ["строка", "строки", "строк"],
+        (Language::Russian, Counted::Bytes) => ["байт", "байта", "байт"],
+        (Language::Russian, Counted::ChatsWithNews) => {
+            ["чат с новыми", "чата с новыми", "чатов с новыми"]
+        }
+        (Language::Russian, Counted::Matches) => ["совпадение", "совпадения", "совпадений"],
+    }
+}
+
+// the count followed by the matching form, e.g. "3 lines"
+pub fn plural(language: Language, count: usize, counted: Counted) -> String {
+    let form = match language.plural_form(count) {
+        PluralForm::One => 0,
+        PluralForm::Few => 1,
+        PluralForm::Many => 2,
+    };
+    format!("{} {}", count, forms(language, counted)[form])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn russian_rule() {
+        let form = |count| Language::Russian.plural_form(count);
+        for count in &[1, 21, 31, 101, 1001] {
+            assert_eq!(form(*count), PluralForm::One, "{}", count);
+        }
+        for count in &[2, 3, 4, 22, 23, 24, 102, 1004] {
+            assert_eq!(form(*count), PluralForm::Few, "{}", count);
+        }
+        for count in (5..=20).chain(vec![0, 25, 30, 100, 111, 112, 114, 211]) {
+            assert_eq!(form(count), PluralForm::Many, "{}", count);
+        }
+        assert_eq!(plural(Language::Russian, 21, Counted::Lines), "21 строка");
+        assert_eq!(plural(Language::Russian, 22, Counted::Bytes), "22 байта");
+        assert_eq!(plural(Language::Russian, 111, Counted::Lines), "111 строк");
+    }
+
+    #[test]
+    fn english_rule() {
+        let form = |count| Language::English.plural_form(count);
+        assert_eq!(form(1), PluralForm::One);
+        for count in &[0, 2, 4, 5, 11, 21, 101] {
+            assert_eq!(form(*count), PluralForm::Many, "{}", count);
+        }
+        assert_eq!(plural(Language::English, 1, Counted::Lines), "1 line");
+        assert_eq!(
+            plural(Language::English, 21, Counted::PendingEvents),
+            "21 events pending"
+        );
+        assert_eq!("RU".parse::<Language>(), Ok(Language::Russian));
+        assert!("de".parse::<Language>().is_err());
+    }
+
+    // counted words are not to be formatted by hand in the UI
+    #[test]
+    fn no_hand_formatted_counts() {
+        let sources = [
+            ("app.rs", include_str!("app.rs")),
+            ("draw.rs", include_str!("draw.rs")),
+        ];
+        let words = [
+            "new",
+            "unread",
+            "event",
+            "events",
+            "line",
+            "lines",
+            "byte",
+            "bytes",
+            "post",
+            "posts",
+            "more",
+            "invitations",
+        ];
+        for (file, source) in sources.iter() {
+            for word in words.iter() {
+                let literal = format!("{{}} {}", word);
+                assert!(
+                    !source.contains(&literal),
+                    "{} formats \"{}\" by hand",
+                    file,
+                    literal
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use crate::proto::{self, ChatId};
+use std::ops::Range;
+
+// the elder pages fetched looking for the first match, not to load the whole history
+pub const MAX_SEARCH_PAGES: usize = 5;
+
+// the query searched in the selected chat
+pub struct SearchState {
+    pub chat_id: ChatId,
+    pub query: String,
+    // indices of the shown posts matching the query, the oldest first
+    pub matches: Vec<usize>,
+    // the elder pages fetched for this search so far
+    pub pages: usize,
+}
+
+impl SearchState {
+    pub fn new(chat_id: ChatId, query: String) -> Self {
+        SearchState {
+            chat_id,
+            query,
+            matches: Vec::new(),
+            pages: 0,
+        }
+    }
+
+    // the posts as shown, called whenever they change
+    pub fn update(&mut self, posts: &[proto::Post]) {
+        let query = &self.query;
+        self.matches = posts
+            .iter()
+            .enumerate()
+            .filter(|(_, post)| !match_ranges(&post.text, query).is_empty())
+            .map(|(idx, _)| idx)
+            .collect();
+    }
+
+    // the match above or below the selected post, the search wraps around
+    pub fn next_match(&self, selected: Option<usize>, older: bool) -> Option<usize> {
+        let (first, last) = (self.matches.first()?, self.matches.last()?);
+        let found = match (selected, older) {
+            (None, _) => None,
+            (Some(sel), true) => self.matches.iter().rev().find(|idx| **idx < sel),
+            (Some(sel), false) => self.matches.iter().find(|idx| **idx > sel),
+        };
+        Some(*found.unwrap_or(if older { last } else { first }))
+    }
+
+    pub fn is_match(&self, idx: usize) -> bool {
+        self.matches.binary_search(&idx).is_ok()
+    }
+}
+
+// the byte ranges of the text matching the query regardless of the case
+pub fn match_ranges(text: &str, query: &str) -> Vec<Range<usize>> {
+    let query: Vec<char> = query.chars().flat_map(char::to_lowercase).collect();
+    let mut ranges = Vec::new();
+    if query.is_empty() {
+        return ranges;
+    }
+    let mut from = 0;
+    for (start, _) in text.char_indices() {
+        // the matches do not overlap
+        if start < from {
+            continue;
+        }
+        let mut lowered = Vec::with_capacity(query.len());
+        let mut end = start;
+        for c in text[start..].chars() {
+            if lowered.len() >= query.len() {
+                break;
+            }
+            lowered.extend(c.to_lowercase());
+            end += c.len_utf8();
+        }
+        if lowered == query {
+            ranges.push(start..end);
+            from = end;
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_ignore_case() {
+        assert_eq!(match_ranges("Hello hello", "HELLO"), vec![0..5, 6..11]);
+        assert_eq!(match_ranges("aaaa", "aa"), vec![0..2, 2..4]);
+        assert_eq!(match_ranges("Привет, мир", "ПРИ"), vec![0..6]);
+        assert_eq!(match_ranges("ÄÖ ä", "ä"), vec![0..2, 5..7]);
+        assert!(match_ranges("hello", "").is_empty());
+        assert!(match_ranges("hello", "world").is_empty());
+        assert!(match_ranges("he", "hello").is_empty());
+    }
+
+    #[test]
+    fn matches_cycle() {
+        let post = |text: &str| proto::Post {
+            text: String::from(text),
+            ..Default::default()
+        };
+        let mut search = SearchState::new(10, String::from("deploy"));
+        assert_eq!(search.next_match(Some(2), true), None);
+        search.update(&[
+            post("Deployed"),
+            post("hello"),
+            post("redeploy please"),
+            post("bye"),
+        ]);
+        assert_eq!(search.matches, vec![0, 2]);
+        assert!(search.is_match(2));
+        assert!(!search.is_match(1));
+        // the older ones go up, wrapping to the newest
+        assert_eq!(search.next_match(Some(3), true), Some(2));
+        assert_eq!(search.next_match(Some(2), true), Some(0));
+        assert_eq!(search.next_match(Some(0), true), Some(2));
+        // the newer ones go down, wrapping to the oldest
+        assert_eq!(search.next_match(Some(0), false), Some(2));
+        assert_eq!(search.next_match(Some(2), false), Some(0));
+        // nothing selected starts from the bottom or the top
+        assert_eq!(search.next_match(None, true), Some(2));
+        assert_eq!(search.next_match(None, false), Some(0));
+    }
+}
@@ -0,0 +1,178 @@
+use super::{ChatId, ChatRoomImpl, InternalError, ServerError};
+use crate::storage::ChatStorage;
+use log::{error, info, warn};
+use std::{
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+// the last verified chat, the next cycle continues from it
+const CURSOR_KEY: &str = "verifier.cursor";
+const DEF_STARTUP_DELAY_SECS: u64 = 600;
+const DEF_INTERVAL_SECS: u64 = 24 * 60 * 60;
+const DEF_SLICE: usize = 1024;
+const DEF_BATCH: usize = 32;
+const DEF_PAUSE_MS: u64 = 50;
+
+// background verification of the data consistency
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifierConfig {
+    // the first cycle starts after the delay
+    pub startup_delay: Duration,
+    // delay between cycles
+    pub interval: Duration,
+    // max count of chats verified within a single cycle
+    pub slice: usize,
+    // chats verified in a row before the pause
+    pub batch: usize,
+    // gives way to the foreground requests between batches
+    pub pause: Duration,
+    // removes found discrepancies, otherwise just reports them
+    pub auto_repair: bool,
+}
+
+impl Default for VerifierConfig {
+    fn default() -> Self {
+        VerifierConfig {
+            startup_delay: Duration::from_secs(DEF_STARTUP_DELAY_SECS),
+            interval: Duration::from_secs(DEF_INTERVAL_SECS),
+            slice: DEF_SLICE,
+            batch: DEF_BATCH,
+            pause: Duration::from_millis(DEF_PAUSE_MS),
+            auto_repair: false,
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct VerifierStats {
+    pub cycles: AtomicU64,
+    pub checked: AtomicU64,
+    pub drift: AtomicU64,
+    pub repaired: AtomicU64,
+}
+
+// the config is read anew every cycle, the startup delay is taken once
+pub async fn run<S: ChatStorage>(chat_room: Arc<ChatRoomImpl<S>>) {
+    let config = chat_room.tunables().verifier;
+    info!("use verifier config: {:?}", config);
+    let stats = VerifierStats::default();
+    tokio::time::sleep(config.startup_delay).await;
+    loop {
+        let config = chat_room.tunables().verifier;
+        match verify_slice(&chat_room, &config, &stats).await {
+            Ok(_) => info!("verification cycle completed, {:?}", stats),
+            Err(e) => error!("verification cycle failed, {}", e),
+        }
+        tokio::time::sleep(config.interval).await;
+    }
+}
+
+// verifies the next slice of chats; the cursor returns to the beginning once all chats
+// are verified, so a full pass over a large storage takes several cycles
+pub async fn verify_slice<S: ChatStorage>(
+    chat_room: &ChatRoomImpl<S>,
+    config: &VerifierConfig,
+    stats: &VerifierStats,
+) -> Result<(), InternalError> {
+    let storage = &chat_room.storage;
+    let mut cursor = read_cursor(storage)?;
+    let mut remaining = config.slice;
+    while remaining > 0 {
+        let count = config.batch.min(remaining);
+        let chats = storage.read_chats_after(cursor, count)?;
+        for chat in &chats {
+            let degraded = storage.is_degraded(chat.id)?;
+            let found = storage.verify_chat_posts(chat.id, config.auto_repair)?;
+            stats.checked.fetch_add(1, Ordering::Relaxed);
+            if found > 0 {
+                stats.drift.fetch_add(found as u64, Ordering::Relaxed);
+                if config.auto_repair {
+                    stats.repaired.fetch_add(found as u64, Ordering::Relaxed);
+                    warn!("chat {}: repaired {} broken posts", chat.id, found);
+                } else {
+                    warn!("chat {}: found {} broken posts", chat.id, found);
+                }
+            }
+            // the members are to know whether the history is available
+            if storage.is_degraded(chat.id)? != degraded {
+                chat_room.notify_chat_updated(chat.clone());
+            }
+        }
+        if chats.len() < count {
+            // the pass is complete
+            cursor = None;
+            break;
+        }
+        remaining -= chats.len();
+        cursor = chats.last().map(|chat| chat.id);
+        write_cursor(storage, cursor)?;
+        tokio::time::sleep(config.pause).await;
+    }
+    write_cursor(storage, cursor)?;
+    stats.cycles.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+fn read_cursor<S: ChatStorage>(storage: &S) -> Result<Option<ChatId>, ServerError> {
+    Ok(storage
+        .read_meta(CURSOR_KEY)?
+        .and_then(|bin| bin.as_slice().try_into().ok())
+        .map(ChatId::from_le_bytes))
+}
+
+fn write_cursor<S: ChatStorage>(storage: &S, cursor: Option<ChatId>) -> Result<(), ServerError> {
+    match cursor {
+        Some(id) => storage.write_meta(CURSOR_KEY, &id.to_le_bytes()),
+        None => storage.write_meta(CURSOR_KEY, &[]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Fixture;
+    use crate::Limits;
+
+    const TEST_DB: &str = "migchat-test-verifier.db";
+
+    #[tokio::test]
+    async fn full_pass_takes_several_cycles() {
+        let _ = std::fs::remove_file(TEST_DB);
+        {
+            let chat_room = ChatRoomImpl::new(TEST_DB, Limits::default()).unwrap();
+            let storage = &chat_room.storage;
+            Fixture::new(1528)
+                .users(3)
+                .chats(10, |c| c.posts(0..5))
+                .seed(storage)
+                .unwrap();
+            let config = VerifierConfig {
+                slice: 3,
+                batch: 2,
+                pause: Duration::from_millis(1),
+                ..Default::default()
+            };
+            let stats = VerifierStats::default();
+            for cycle in 1..=3 {
+                verify_slice(&chat_room, &config, &stats).await.unwrap();
+                assert_eq!(stats.checked.load(Ordering::Relaxed), cycle * 3);
+                assert!(read_cursor(storage).unwrap().is_some());
+            }
+            // the last chat completes the pass
+            verify_slice(&chat_room, &config, &stats).await.unwrap();
+            assert_eq!(stats.checked.load(Ordering::Relaxed), 10);
+            assert_eq!(read_cursor(storage).unwrap(), None);
+            // the next pass starts from the beginning
+            verify_slice(&chat_room, &config, &stats).await.unwrap();
+            assert_eq!(stats.checked.load(Ordering::Relaxed), 13);
+            assert_eq!(stats.cycles.load(Ordering::Relaxed), 5);
+            assert_eq!(stats.drift.load(Ordering::Relaxed), 0);
+        }
+        let _ = std::fs::remove_file(TEST_DB);
+    }
+}